@@ -5,11 +5,12 @@ use mistralrs_core::{
     get_auto_device_map_params, get_model_dtype, initialize_logging, paged_attn_supported,
     parse_isq_value, Constraint, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata,
     DeviceMapSetting, DrySamplingParams, IsqType, Loader, LoaderBuilder, MemoryGpuConfig,
-    MistralRs, MistralRsBuilder, ModelSelected, NormalRequest, PagedAttentionConfig, Request,
-    RequestMessage, Response, SamplingParams, SchedulerConfig, TokenSource, Usage,
+    MistralRs, MistralRsBuilder, ModelSelected, NormalRequest, PagedAttentionConfig,
+    PromptChunksize, Request, RequestMessage, Response, SamplingParams, SchedulerConfig,
+    TokenSource, Usage,
 };
+use std::fmt::Display;
 use std::sync::Arc;
-use std::{fmt::Display, num::NonZeroUsize};
 use tokio::sync::mpsc::channel;
 use tracing::{info, warn};
 
@@ -61,6 +62,15 @@ fn run_bench(
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        penalty_scope: Default::default(),
+        stream_top_k_logprobs: None,
+        reasoning_budget: None,
+        token_healing: false,
+        return_entropy: false,
+        temperature_order: Default::default(),
+        penalty_decay: None,
+        eos_separates_documents: false,
+        repetition_loop: None,
         max_len: Some(n_gen),
         stop_toks: None,
         logits_bias: None,
@@ -84,6 +94,10 @@ fn run_bench(
         logits_processors: None,
         return_raw_logits: false,
         web_search_options: None,
+        tool_call_trigger: None,
+        stream_raw_bytes: false,
+        stream_flush_policy: Default::default(),
+        kv_cache_limit: None,
     });
 
     let mut usages = Vec::new();
@@ -228,6 +242,15 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        penalty_scope: Default::default(),
+        stream_top_k_logprobs: None,
+        reasoning_budget: None,
+        token_healing: false,
+        return_entropy: false,
+        temperature_order: Default::default(),
+        penalty_decay: None,
+        eos_separates_documents: false,
+        repetition_loop: None,
         max_len: Some(5),
         stop_toks: None,
         logits_bias: None,
@@ -255,6 +278,10 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         logits_processors: None,
         return_raw_logits: false,
         web_search_options: None,
+        tool_call_trigger: None,
+        stream_raw_bytes: false,
+        stream_flush_policy: Default::default(),
+        kv_cache_limit: None,
     });
 
     sender
@@ -336,8 +363,9 @@ struct Args {
     paged_attn: bool,
 
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
+    /// Use `auto` to choose it from the free device memory at load time (plain models only).
     #[arg(long = "prompt-batchsize")]
-    prompt_chunksize: Option<usize>,
+    prompt_chunksize: Option<PromptChunksize>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -348,14 +376,6 @@ fn main() -> anyhow::Result<()> {
 
     let use_flash_attn = mistralrs_core::using_flash_attn();
 
-    let prompt_chunksize = match args.prompt_chunksize {
-        Some(0) => {
-            anyhow::bail!("`prompt_chunksize` must be a strictly positive integer, got 0.",)
-        }
-        Some(x) => Some(NonZeroUsize::new(x).unwrap()),
-        None => None,
-    };
-
     let dtype = get_model_dtype(&args.model)?;
     let auto_device_map_params = get_auto_device_map_params(&args.model)?;
 
@@ -363,7 +383,7 @@ fn main() -> anyhow::Result<()> {
 
     let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
         .with_use_flash_attn(use_flash_attn)
-        .with_prompt_chunksize(prompt_chunksize)
+        .with_prompt_chunksize(args.prompt_chunksize)
         .build()?;
     let model_name = loader.get_id();
 
@@ -3,15 +3,54 @@
 #[cfg(feature = "metal")]
 use std::sync::atomic::AtomicUsize;
 
-use crate::{pipeline::text_models_inputs_processor::FlashParams, MemoryUsage};
+use crate::{
+    layers_utils::{fixed_order_softmax_last_dim, is_differentiable},
+    pipeline::text_models_inputs_processor::FlashParams,
+    MemoryUsage,
+};
 
-use candle_core::{Device, Result, Tensor};
-use mistralrs_quant::MatMul;
+use candle_core::{DType, Device, Result, Tensor, TensorId, D};
+use mistralrs_quant::{get_strict_determinism, op_trace, MatMul};
+use std::cell::{Cell, RefCell};
 
 #[cfg(feature = "metal")]
 /// Initial, sentinel value is usize::MAX
 static METAL_VERSION_CACHE: AtomicUsize = AtomicUsize::new(usize::MAX);
 
+thread_local! {
+    static PREFILL_DTYPE: Cell<Option<DType>> = const { Cell::new(None) };
+    static SPLIT_KV: Cell<Option<(usize, SplitKvAccumulation)>> = const { Cell::new(None) };
+    static FULLY_MASKED_ROWS: RefCell<Option<(TensorId, Option<Tensor>)>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with prefill attention on this thread computed in `dtype`, casting the output back to the
+/// model dtype. Decode steps are left in the model dtype. Each pipeline passes its own
+/// `NormalSpecificConfig::prefill_dtype`, so models loaded side by side do not affect each other.
+pub(crate) fn with_prefill_dtype<T>(dtype: Option<DType>, f: impl FnOnce() -> T) -> T {
+    let prev = PREFILL_DTYPE.replace(dtype);
+    let res = f();
+    PREFILL_DTYPE.set(prev);
+    res
+}
+
+/// Run `f` with the eager attention path on this thread processing the KV in segments of
+/// `segment_len` tokens, bounding the working set to one segment of scores, and combining them with
+/// `accumulation`. Like the prefill dtype, this is set per pipeline from
+/// `NormalSpecificConfig::split_kv_segment_len` and `NormalSpecificConfig::split_kv_accumulation`.
+pub(crate) fn with_split_kv<T>(
+    segment_len: Option<usize>,
+    accumulation: SplitKvAccumulation,
+    f: impl FnOnce() -> T,
+) -> T {
+    let split_kv = segment_len
+        .filter(|len| *len > 0)
+        .map(|len| (len, accumulation));
+    let prev = SPLIT_KV.replace(split_kv);
+    let res = f();
+    SPLIT_KV.set(prev);
+    res
+}
+
 #[cfg(feature = "flash-attn")]
 fn flash_attn(
     q: &Tensor,
@@ -209,6 +248,58 @@ fn maybe_synchronize(device: &Device) -> Result<()> {
     Ok(())
 }
 
+/// The query rows of a `(.., seq_len, kv_len)` mask which are `-inf` at every key, such as a padded
+/// position that attends to nothing, as a `(.., seq_len, 1)` flag. `None` if there are none. Models
+/// pass the same mask to every layer, so the last result is cached by tensor id and the reduction runs
+/// once per forward pass rather than once per layer.
+fn fully_masked_rows(mask: &Tensor) -> Result<Option<Tensor>> {
+    // Boolean/integer masks select positions rather than adding a bias.
+    if !mask.dtype().is_float() {
+        return Ok(None);
+    }
+    let cached = FULLY_MASKED_ROWS.with_borrow(|cached| {
+        cached
+            .as_ref()
+            .filter(|(id, _)| *id == mask.id())
+            .map(|(_, flag)| flag.clone())
+    });
+    if let Some(flag) = cached {
+        return Ok(flag);
+    }
+    let flag = mask.max_keepdim(D::Minus1)?.eq(f64::NEG_INFINITY)?;
+    let flag = (flag.max_all()?.to_scalar::<u8>()? > 0).then_some(flag);
+    FULLY_MASKED_ROWS.set(Some((mask.id(), flag.clone())));
+    Ok(flag)
+}
+
+/// Zero the attention output of the query rows flagged by [`fully_masked_rows`]. The softmax of such
+/// a row is `NaN` (from `-inf - -inf`) in the naive, split-KV and cuBLASLt paths, which would otherwise
+/// spread through the rest of the model; instead these queries get a zero output on every backend.
+fn zero_fully_masked_rows(out: &Tensor, fully_masked: &Tensor) -> Result<Tensor> {
+    // A `(bs, seq_len, kv_len)` mask is shared by all heads.
+    let fully_masked = if fully_masked.rank() == 3 && out.rank() == 4 {
+        fully_masked.unsqueeze(1)?
+    } else {
+        fully_masked.clone()
+    };
+    fully_masked
+        .broadcast_as(out.shape())?
+        .where_cond(&out.zeros_like()?, out)
+}
+
+/// Softmax over the last dim, in place unless the forward pass must be differentiable. With strict
+/// determinism, the normalizing sum is taken in a fixed order.
+fn softmax_last_dim(mut att: Tensor) -> Result<Tensor> {
+    if get_strict_determinism() {
+        fixed_order_softmax_last_dim(&att)
+    } else if is_differentiable() {
+        candle_nn::ops::softmax(&att, D::Minus1)
+    } else {
+        candle_nn::ops::inplace_softmax_last_dim(&mut att)?;
+        Ok(att)
+    }
+}
+
 /// Computes softmax(QK^T*sqrt(d_k))V
 fn naive_sdpa(
     q: &Tensor,
@@ -220,7 +311,11 @@ fn naive_sdpa(
     maybe_synchronize(q.device())?;
 
     // Use faster softmax if mask is rank 2 or it's rank 3
-    if mask.is_some_and(|mask| mask.rank() == 2 || mask.rank() == 3) && supports_attn_softmax()? {
+    if mask.is_some_and(|mask| mask.rank() == 2 || mask.rank() == 3)
+        && !is_differentiable()
+        && !get_strict_determinism()
+        && supports_attn_softmax()?
+    {
         let mask = match mask {
             Some(mask) if mask.rank() == 3 || mask.rank() == 2 => mask.clone(),
             _ => candle_core::bail!("unsupported mask {mask:?}"),
@@ -247,8 +342,7 @@ fn naive_sdpa(
             att = (att * softcap as f64)?;
         }
 
-        att = att.broadcast_add(mask)?;
-        candle_nn::ops::inplace_softmax_last_dim(&mut att)?;
+        att = softmax_last_dim(att.broadcast_add(mask)?)?;
 
         MatMul.matmul(&att, v)
     } else {
@@ -259,17 +353,154 @@ fn naive_sdpa(
             att = (att * softcap as f64)?;
         }
 
-        candle_nn::ops::inplace_softmax_last_dim(&mut att)?;
+        att = softmax_last_dim(att)?;
         MatMul.matmul(&att, v)
     }
 }
 
+/// How split-KV attention accumulates the online softmax over the KV segments.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SplitKvAccumulation {
+    /// Upcast to f32 for the whole computation.
+    #[default]
+    F32,
+    /// Accumulate in the input dtype. Cheapest, but in bf16 the running sums lose precision as the KV grows.
+    Native,
+    /// Accumulate in the input dtype with Kahan (compensated) summation, carrying the rounding error of
+    /// each update in a second accumulator. Costs a few extra elementwise ops per segment.
+    Kahan,
+}
+
+/// Add `x` to `sum` with Kahan summation, where `comp` holds the rounding error of the previous updates.
+/// Returns the new `(sum, comp)`.
+fn kahan_add(sum: &Tensor, comp: &Tensor, x: &Tensor) -> Result<(Tensor, Tensor)> {
+    let y = (x - comp)?;
+    let t = (sum + &y)?;
+    let comp = ((&t - sum)? - y)?;
+    Ok((t, comp))
+}
+
+/// Computes softmax(QK^T*sqrt(d_k))V with the KV split into segments of `segment_len` tokens.
+///
+/// The segments are processed sequentially with an online softmax: a running row max, normalizer and
+/// output are rescaled as each segment is added. Only one segment of attention scores is live at a time.
+fn split_kv_sdpa(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    sdpa_params: &SdpaParams,
+    segment_len: usize,
+    accumulation: SplitKvAccumulation,
+) -> Result<Tensor> {
+    let (b_sz, n_attn_heads, seq_len, _) = q.dims4()?;
+    let (_, _, kv_len, v_head_dim) = v.dims4()?;
+    let dtype = q.dtype();
+    let acc_dtype = match accumulation {
+        SplitKvAccumulation::F32 => DType::F32,
+        SplitKvAccumulation::Native | SplitKvAccumulation::Kahan => dtype,
+    };
+    let q = q.to_dtype(acc_dtype)?;
+
+    // A finite initial max keeps `exp(s - max)` at 0 rather than NaN for fully masked segments. It has
+    // to be finite in the accumulation dtype too, and `f32::MIN / 2` overflows f16.
+    let init_max = match acc_dtype {
+        DType::F16 => f32::from(half::f16::MIN) / 2.,
+        _ => f32::MIN / 2.,
+    };
+    let mut row_max = Tensor::full(init_max, (b_sz, n_attn_heads, seq_len, 1), q.device())?
+        .to_dtype(acc_dtype)?;
+    let mut row_sum = Tensor::zeros((b_sz, n_attn_heads, seq_len, 1), acc_dtype, q.device())?;
+    let mut acc = Tensor::zeros(
+        (b_sz, n_attn_heads, seq_len, v_head_dim),
+        acc_dtype,
+        q.device(),
+    )?;
+    // Kahan compensation terms, only updated for `SplitKvAccumulation::Kahan`.
+    let mut row_sum_comp = row_sum.zeros_like()?;
+    let mut acc_comp = acc.zeros_like()?;
+
+    for start in (0..kv_len).step_by(segment_len) {
+        let len = segment_len.min(kv_len - start);
+        let k_seg = k.narrow(2, start, len)?.to_dtype(acc_dtype)?;
+        let v_seg = v.narrow(2, start, len)?.to_dtype(acc_dtype)?;
+
+        let mut scores =
+            MatMul.matmul_affine_mul(&q, &k_seg.t()?, sdpa_params.softmax_scale.into())?;
+        if let Some(softcap) = sdpa_params.softcap {
+            scores = ((scores / softcap as f64)?.tanh()? * softcap as f64)?;
+        }
+        if let Some(mask) = mask {
+            let mask_seg = mask.narrow(D::Minus1, start, len)?.to_dtype(acc_dtype)?;
+            scores = scores.broadcast_add(&mask_seg)?;
+        }
+
+        let new_max = row_max.maximum(&scores.max_keepdim(D::Minus1)?)?;
+        let probs = scores.broadcast_sub(&new_max)?.exp()?;
+        let correction = (row_max - &new_max)?.exp()?;
+
+        let seg_sum = probs.sum_keepdim(D::Minus1)?;
+        let seg_acc = MatMul.matmul(&probs, &v_seg)?;
+        row_sum = (row_sum * &correction)?;
+        acc = acc.broadcast_mul(&correction)?;
+        if accumulation == SplitKvAccumulation::Kahan {
+            (row_sum, row_sum_comp) =
+                kahan_add(&row_sum, &(row_sum_comp * &correction)?, &seg_sum)?;
+            (acc, acc_comp) = kahan_add(&acc, &acc_comp.broadcast_mul(&correction)?, &seg_acc)?;
+        } else {
+            row_sum = (row_sum + seg_sum)?;
+            acc = (acc + seg_acc)?;
+        }
+        row_max = new_max;
+    }
+
+    if accumulation == SplitKvAccumulation::Kahan {
+        // Fold the compensation back in at full precision for the final normalization.
+        let row_sum = (row_sum.to_dtype(DType::F32)? - row_sum_comp.to_dtype(DType::F32)?)?;
+        let acc = (acc.to_dtype(DType::F32)? - acc_comp.to_dtype(DType::F32)?)?;
+        return acc.broadcast_div(&row_sum)?.to_dtype(dtype);
+    }
+    acc.broadcast_div(&row_sum)?.to_dtype(dtype)
+}
+
 pub struct SdpaParams {
     pub n_kv_groups: usize,
     pub use_flash_attn: bool,
     pub softcap: Option<f32>,
     pub softmax_scale: f32,
     pub sliding_window: Option<usize>,
+    /// Scale applied to the output of each attention head, shape `(n_attn_heads,)`. `None` is uniform.
+    pub head_scales: Option<Tensor>,
+}
+
+impl SdpaParams {
+    /// Scale the output of each attention head, for example to silence or amplify sink heads.
+    pub fn set_head_scales(
+        &mut self,
+        head_scales: &[f32],
+        n_attn_heads: usize,
+        device: &Device,
+    ) -> Result<()> {
+        if head_scales.len() != n_attn_heads {
+            candle_core::bail!(
+                "Expected {n_attn_heads} attention head scales, got {}.",
+                head_scales.len()
+            );
+        }
+        self.head_scales = Some(Tensor::new(head_scales, device)?);
+        Ok(())
+    }
+}
+
+/// Multiply the output of each head by its scale, where `dim` is the head dimension of `xs`.
+pub(crate) fn apply_head_scales(xs: &Tensor, head_scales: &Tensor, dim: usize) -> Result<Tensor> {
+    let mut shape = vec![1; xs.rank()];
+    shape[dim] = head_scales.elem_count();
+    let head_scales = head_scales
+        .to_device(xs.device())?
+        .to_dtype(xs.dtype())?
+        .reshape(shape)?;
+    xs.broadcast_mul(&head_scales)
 }
 
 pub struct Sdpa;
@@ -286,7 +517,9 @@ impl Sdpa {
     /// 1) If `use_flash_attn == true` (CUDA), use a flash attention V2 kernel
     /// 2) If decoding and using a Metal device, use a fused kkernel
     /// 2) Otherwise, use the "naive" SDPA implementation (with optimized mask+softmax+scale application)
-    #[allow(unused_variables, clippy::too_many_arguments)]
+    ///
+    /// Query rows which are masked at every key produce a zero output rather than `NaN`.
+    #[allow(clippy::too_many_arguments)]
     pub fn run_attention(
         &self,
         q: &Tensor,
@@ -295,11 +528,97 @@ impl Sdpa {
         mask: Option<&Tensor>,
         flash_params: Option<&FlashParams>,
         sdpa_params: &SdpaParams,
+    ) -> Result<Tensor> {
+        let prefill_dtype = PREFILL_DTYPE.with(Cell::get);
+        let fully_masked = match mask {
+            Some(mask) => fully_masked_rows(mask)?,
+            None => None,
+        };
+        let out = op_trace::run_op(|| {
+            let mut out = self.run_attention_in_prefill_dtype(
+                q,
+                k,
+                v,
+                mask,
+                flash_params,
+                sdpa_params,
+                prefill_dtype,
+            )?;
+            if let Some(fully_masked) = &fully_masked {
+                out = zero_fully_masked_rows(&out, fully_masked)?;
+            }
+            match &sdpa_params.head_scales {
+                Some(head_scales) => apply_head_scales(&out, head_scales, 1),
+                None => Ok(out),
+            }
+        })?;
+        op_trace::record("attention", &[q, k, v], &[&out]);
+        Ok(out)
+    }
+
+    /// For prefill steps (more than one query token), run attention in `prefill_dtype` and cast the
+    /// output back to the dtype of `q`. Long prompts accumulate enough f16/bf16 error in the scores and
+    /// softmax to shift the first sampled token, and this trades prefill speed for accuracy.
+    ///
+    /// Flash attention has no f32 kernel, so it is left in the model dtype. With strict determinism,
+    /// every step runs in f32 without flash attention.
+    #[allow(clippy::too_many_arguments)]
+    fn run_attention_in_prefill_dtype(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        mask: Option<&Tensor>,
+        flash_params: Option<&FlashParams>,
+        sdpa_params: &SdpaParams,
+        prefill_dtype: Option<DType>,
+    ) -> Result<Tensor> {
+        let is_prefill = q.dim(2)? > 1;
+        let strict = get_strict_determinism();
+        let uses_flash_attn = sdpa_params.use_flash_attn && q.device().is_cuda() && !strict;
+        let upcast = if strict {
+            Some(DType::F32)
+        } else {
+            prefill_dtype.filter(|_| is_prefill && !uses_flash_attn)
+        }
+        .filter(|dtype| *dtype != q.dtype());
+        let Some(dtype) = upcast else {
+            return self.run_attention_unscaled(q, k, v, mask, flash_params, sdpa_params);
+        };
+
+        // Boolean/integer masks select positions rather than adding a bias, so keep their dtype.
+        let mask = match mask {
+            Some(mask) if mask.dtype().is_float() => Some(mask.to_dtype(dtype)?),
+            Some(mask) => Some(mask.clone()),
+            None => None,
+        };
+        self.run_attention_unscaled(
+            &q.to_dtype(dtype)?,
+            &k.to_dtype(dtype)?,
+            &v.to_dtype(dtype)?,
+            mask.as_ref(),
+            flash_params,
+            sdpa_params,
+        )?
+        .to_dtype(q.dtype())
+    }
+
+    #[allow(unused_variables, clippy::too_many_arguments)]
+    fn run_attention_unscaled(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        mask: Option<&Tensor>,
+        flash_params: Option<&FlashParams>,
+        sdpa_params: &SdpaParams,
     ) -> Result<Tensor> {
         let (b_sz, n_attn_heads, seq_len, head_dim) = q.dims4()?;
         let (_, _, _, k_head_dim) = k.dims4()?;
         let (_, _, _, v_head_dim) = v.dims4()?;
-        if sdpa_params.use_flash_attn && q.device().is_cuda() {
+        // Strict determinism takes the same composed path as differentiable forward passes.
+        let differentiable = is_differentiable() || get_strict_determinism();
+        if sdpa_params.use_flash_attn && q.device().is_cuda() && !differentiable {
             // flash-attn expects (b_sz, seq_len, nheads, head_dim)
             let q = q.transpose(1, 2)?;
             let k = k.transpose(1, 2)?;
@@ -325,6 +644,7 @@ impl Sdpa {
             && all_head_dims_match
             && valid_head_dims.contains(&head_dim)
             && can_use_mask
+            && !differentiable
         {
             let mask = match mask {
                 Some(mask) => Some(mask.broadcast_as(tgt_mask_shape)?),
@@ -344,6 +664,16 @@ impl Sdpa {
         let k = repeat_kv(k.clone(), sdpa_params.n_kv_groups)?;
         let v = repeat_kv(v.clone(), sdpa_params.n_kv_groups)?;
 
+        if differentiable {
+            return naive_sdpa(q, &k, &v, mask, sdpa_params);
+        }
+
+        if let Some((segment_len, accumulation)) = SPLIT_KV.with(Cell::get) {
+            if k.dim(2)? > segment_len {
+                return split_kv_sdpa(q, &k, &v, mask, sdpa_params, segment_len, accumulation);
+            }
+        }
+
         if mask.is_some_and(|x| x.rank() == 2) || mistralrs_quant::distributed::use_nccl() {
             return naive_sdpa(q, &k, &v, mask, sdpa_params);
         }
@@ -420,3 +750,453 @@ impl Sdpa {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, IndexOp, Tensor};
+
+    use super::{naive_sdpa, split_kv_sdpa, Sdpa, SdpaParams, SplitKvAccumulation};
+
+    #[test]
+    fn zero_head_scale_silences_head() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (n_heads, seq_len, head_dim) = (4, 5, 8);
+        let q = Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?;
+        let k = Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?;
+        let v = Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?;
+        let params = || SdpaParams {
+            n_kv_groups: 1,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: 1. / (head_dim as f32).sqrt(),
+            sliding_window: None,
+            head_scales: None,
+        };
+
+        let uniform = Sdpa.run_attention(&q, &k, &v, None, None, &params())?;
+        let mut scaled_params = params();
+        scaled_params.set_head_scales(&[1., 0., 1., 1.], n_heads, &dev)?;
+        let scaled = Sdpa.run_attention(&q, &k, &v, None, None, &scaled_params)?;
+
+        let silenced = scaled
+            .i((.., 1))?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert_eq!(silenced, 0.);
+        for head in [0, 2, 3] {
+            let diff = (scaled.i((.., head))? - uniform.i((.., head))?)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert_eq!(diff, 0.);
+        }
+
+        assert!(params().set_head_scales(&[1., 0.], n_heads, &dev).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fully_masked_query_row_is_zero() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (n_heads, seq_len, head_dim) = (2, 4, 8);
+        let q = Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?;
+        let k = Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?;
+        let v = Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?;
+        // Causal, with the first query row (a padded position) masked everywhere.
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| {
+                (0..seq_len).map(move |j| {
+                    if i == 0 || j > i {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        let mask = Tensor::from_vec(mask, (seq_len, seq_len), &dev)?;
+        let params = SdpaParams {
+            n_kv_groups: 1,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: 1. / (head_dim as f32).sqrt(),
+            sliding_window: None,
+            head_scales: None,
+        };
+
+        let out = Sdpa.run_attention(&q, &k, &v, Some(&mask), None, &params)?;
+        let values = out.flatten_all()?.to_vec1::<f32>()?;
+        assert!(values.iter().all(|x| x.is_finite()));
+        assert_eq!(
+            out.i((.., .., 0))?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?,
+            0.
+        );
+        // The other rows are untouched.
+        assert!(
+            out.i((.., .., 1..))?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?
+                > 0.
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn split_kv_matches_full_attention() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (n_heads, seq_len, kv_len, head_dim) = (2, 4, 100, 16);
+        let q = Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?;
+        let k = Tensor::randn(0f32, 1., (1, n_heads, kv_len, head_dim), &dev)?;
+        let v = Tensor::randn(0f32, 1., (1, n_heads, kv_len, head_dim), &dev)?;
+        // The queries are the last `seq_len` positions, attending causally over the whole KV.
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| {
+                (0..kv_len).map(move |j| {
+                    if j > kv_len - seq_len + i {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        let mask = Tensor::from_vec(mask, (seq_len, kv_len), &dev)?;
+
+        for (softcap, mask) in [(None, None), (None, Some(&mask)), (Some(20.), None)] {
+            let params = SdpaParams {
+                n_kv_groups: 1,
+                use_flash_attn: false,
+                softcap,
+                softmax_scale: 1. / (head_dim as f32).sqrt(),
+                sliding_window: None,
+                head_scales: None,
+            };
+            let full = naive_sdpa(&q, &k, &v, mask, &params)?;
+            for segment_len in [16, 33, 100] {
+                let split = split_kv_sdpa(
+                    &q,
+                    &k,
+                    &v,
+                    mask,
+                    &params,
+                    segment_len,
+                    SplitKvAccumulation::F32,
+                )?;
+                let diff = (&split - &full)?
+                    .abs()?
+                    .flatten_all()?
+                    .max(0)?
+                    .to_scalar::<f32>()?;
+                assert!(diff < 1e-5, "segment {segment_len}: {diff}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Uniform values in `[-bound, bound)` from a fixed seed, so accuracy comparisons are reproducible.
+    fn seeded_uniform(
+        bound: f32,
+        shape: (usize, usize, usize, usize),
+        seed: u64,
+    ) -> candle_core::Result<Tensor> {
+        use rand::{Rng, SeedableRng};
+        use rand_isaac::Isaac64Rng;
+
+        let mut rng = Isaac64Rng::seed_from_u64(seed);
+        let (a, b, c, d) = shape;
+        let data = (0..a * b * c * d)
+            .map(|_| rng.random_range(-bound..bound))
+            .collect::<Vec<f32>>();
+        Tensor::from_vec(data, shape, &Device::Cpu)
+    }
+
+    /// Split-KV attention inputs in `dtype` over a KV of `kv_len` tokens, and the f32 reference output,
+    /// which sees the same rounded inputs so only the accumulation error is measured.
+    fn split_kv_case(
+        dtype: candle_core::DType,
+        kv_len: usize,
+    ) -> candle_core::Result<(Tensor, Tensor, Tensor, SdpaParams, Tensor)> {
+        use candle_core::DType;
+
+        let (n_heads, seq_len, head_dim) = (2, 2, 16);
+        let q = seeded_uniform(0.2, (1, n_heads, seq_len, head_dim), 0)?.to_dtype(dtype)?;
+        let k = seeded_uniform(1.7, (1, n_heads, kv_len, head_dim), 1)?.to_dtype(dtype)?;
+        let v = seeded_uniform(1.7, (1, n_heads, kv_len, head_dim), 2)?.to_dtype(dtype)?;
+        let params = SdpaParams {
+            n_kv_groups: 1,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: 1. / (head_dim as f32).sqrt(),
+            sliding_window: None,
+            head_scales: None,
+        };
+        let reference = naive_sdpa(
+            &q.to_dtype(DType::F32)?,
+            &k.to_dtype(DType::F32)?,
+            &v.to_dtype(DType::F32)?,
+            None,
+            &params,
+        )?;
+        Ok((q, k, v, params, reference))
+    }
+
+    fn max_abs_error(out: &Tensor, reference: &Tensor) -> candle_core::Result<f32> {
+        (out.to_dtype(candle_core::DType::F32)? - reference)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()
+    }
+
+    #[test]
+    fn kahan_split_kv_is_more_accurate_than_native_bf16() -> candle_core::Result<()> {
+        let (q, k, v, params, reference) = split_kv_case(candle_core::DType::BF16, 4096)?;
+        let error = |accumulation| -> candle_core::Result<f32> {
+            let out = split_kv_sdpa(&q, &k, &v, None, &params, 3, accumulation)?;
+            max_abs_error(&out, &reference)
+        };
+
+        let native = error(SplitKvAccumulation::Native)?;
+        let kahan = error(SplitKvAccumulation::Kahan)?;
+        let f32_accum = error(SplitKvAccumulation::F32)?;
+        assert!(kahan < native, "kahan {kahan} >= native {native}");
+        assert!(
+            kahan < 4. * f32_accum.max(1e-3),
+            "kahan {kahan}, f32 {f32_accum}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn f16_split_kv_accumulation_stays_finite() -> candle_core::Result<()> {
+        use candle_core::DType;
+
+        let (q, k, v, params, _) = split_kv_case(DType::F16, 1024)?;
+        // Mask out a left-padded prefix, so the first segments are fully masked.
+        let (seq_len, kv_len) = (q.dim(2)?, k.dim(2)?);
+        let mask = (0..seq_len * kv_len)
+            .map(|i| {
+                if i % kv_len < 250 {
+                    f32::NEG_INFINITY
+                } else {
+                    0.
+                }
+            })
+            .collect::<Vec<_>>();
+        let mask = Tensor::from_vec(mask, (seq_len, kv_len), &Device::Cpu)?;
+        let reference = naive_sdpa(
+            &q.to_dtype(DType::F32)?,
+            &k.to_dtype(DType::F32)?,
+            &v.to_dtype(DType::F32)?,
+            Some(&mask),
+            &params,
+        )?;
+        let mask = mask.to_dtype(DType::F16)?;
+        for accumulation in [
+            SplitKvAccumulation::F32,
+            SplitKvAccumulation::Native,
+            SplitKvAccumulation::Kahan,
+        ] {
+            let out = split_kv_sdpa(&q, &k, &v, Some(&mask), &params, 100, accumulation)?;
+            let error = max_abs_error(&out, &reference)?;
+            assert!(error < 1e-2, "{accumulation:?}: {error}");
+        }
+        Ok(())
+    }
+
+    /// Accuracy and speed of each accumulation on a long bf16 KV. Run with
+    /// `cargo test --release -p mistralrs-core split_kv_accumulation_tradeoff -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn split_kv_accumulation_tradeoff() -> candle_core::Result<()> {
+        use std::time::Instant;
+
+        const RUNS: u32 = 10;
+        let (q, k, v, params, reference) = split_kv_case(candle_core::DType::BF16, 32768)?;
+        for accumulation in [
+            SplitKvAccumulation::F32,
+            SplitKvAccumulation::Native,
+            SplitKvAccumulation::Kahan,
+        ] {
+            let mut out = split_kv_sdpa(&q, &k, &v, None, &params, 512, accumulation)?;
+            let start = Instant::now();
+            for _ in 0..RUNS {
+                out = split_kv_sdpa(&q, &k, &v, None, &params, 512, accumulation)?;
+            }
+            println!(
+                "{accumulation:?}: max error {:.2e}, {:.2}ms per call",
+                max_abs_error(&out, &reference)?,
+                start.elapsed().as_secs_f64() * 1000. / f64::from(RUNS),
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn f32_prefill_keeps_first_token_of_long_prompt() -> candle_core::Result<()> {
+        use candle_core::DType;
+
+        let dev = Device::Cpu;
+        let (n_heads, seq_len, head_dim, vocab) = (2, 1024, 64, 512);
+        let q =
+            Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?.to_dtype(DType::F16)?;
+        let k =
+            Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?.to_dtype(DType::F16)?;
+        let v =
+            Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?.to_dtype(DType::F16)?;
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_vec(mask, (seq_len, seq_len), &dev)?;
+        let params = SdpaParams {
+            n_kv_groups: 1,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: 1. / (head_dim as f32).sqrt(),
+            sliding_window: None,
+            head_scales: None,
+        };
+        let lm_head = Tensor::randn(0f32, 1., (n_heads * head_dim, vocab), &dev)?;
+
+        // Project the last position to logits and pick the greedy token, returning the attention output too.
+        let first_token = |out: &Tensor| -> candle_core::Result<(u32, Tensor)> {
+            let last = out
+                .i((.., .., seq_len - 1, ..))?
+                .to_dtype(DType::F32)?
+                .flatten_all()?;
+            let token = last
+                .unsqueeze(0)?
+                .matmul(&lm_head)?
+                .squeeze(0)?
+                .argmax(0)?
+                .to_scalar::<u32>()?;
+            Ok((token, last))
+        };
+
+        // The reference sees the same f16 inputs and rounds its output to f16, like the model would.
+        let reference = Sdpa.run_attention_in_prefill_dtype(
+            &q.to_dtype(DType::F32)?,
+            &k.to_dtype(DType::F32)?,
+            &v.to_dtype(DType::F32)?,
+            Some(&mask),
+            None,
+            &params,
+            None,
+        )?;
+        let (reference_token, reference) = first_token(&reference.to_dtype(DType::F16)?)?;
+
+        let f16_mask = mask.to_dtype(DType::F16)?;
+        let run = |prefill_dtype| {
+            Sdpa.run_attention_in_prefill_dtype(
+                &q,
+                &k,
+                &v,
+                Some(&f16_mask),
+                None,
+                &params,
+                prefill_dtype,
+            )
+        };
+        let f16_out = run(None)?;
+        let f32_out = run(Some(DType::F32))?;
+        assert_eq!(f32_out.dtype(), DType::F16);
+
+        let (f16_token, f16_last) = first_token(&f16_out)?;
+        let (f32_token, f32_last) = first_token(&f32_out)?;
+        let error = |last: &Tensor| -> candle_core::Result<f32> {
+            (last - &reference)?.abs()?.max(0)?.to_scalar::<f32>()
+        };
+        let (f16_error, f32_error) = (error(&f16_last)?, error(&f32_last)?);
+        assert_eq!(f32_token, reference_token);
+        assert!(
+            f32_error <= f16_error,
+            "f32 prefill error {f32_error} > f16 prefill error {f16_error} (tokens {f32_token}, {f16_token})"
+        );
+        Ok(())
+    }
+    #[test]
+    fn prefill_dtype_is_scoped_to_the_caller() -> candle_core::Result<()> {
+        use candle_core::DType;
+
+        let dev = Device::Cpu;
+        let (n_heads, seq_len, head_dim) = (2, 64, 16);
+        let qkv = || -> candle_core::Result<Tensor> {
+            Tensor::randn(0f32, 4., (1, n_heads, seq_len, head_dim), &dev)?.to_dtype(DType::F16)
+        };
+        let (q, k, v) = (qkv()?, qkv()?, qkv()?);
+        let params = SdpaParams {
+            n_kv_groups: 1,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: 1. / (head_dim as f32).sqrt(),
+            sliding_window: None,
+            head_scales: None,
+        };
+        let attend = || Sdpa.run_attention(&q, &k, &v, None, None, &params);
+        let same = |a: &Tensor, b: &Tensor| -> candle_core::Result<bool> {
+            Ok(a.to_dtype(DType::F32)?
+                .eq(&b.to_dtype(DType::F32)?)?
+                .flatten_all()?
+                .min(0)?
+                .to_scalar::<u8>()?
+                == 1)
+        };
+
+        let in_f16 = Sdpa.run_attention_in_prefill_dtype(&q, &k, &v, None, None, &params, None)?;
+        let in_f32 =
+            Sdpa.run_attention_in_prefill_dtype(&q, &k, &v, None, None, &params, Some(DType::F32))?;
+        assert!(!same(&in_f16, &in_f32)?);
+
+        // Only the scoped call on this thread sees the dtype, another pipeline's thread does not.
+        super::with_prefill_dtype(Some(DType::F32), || -> candle_core::Result<()> {
+            assert!(same(&attend()?, &in_f32)?);
+            let other_thread = std::thread::scope(|s| s.spawn(attend).join().unwrap())?;
+            assert!(same(&other_thread, &in_f16)?);
+            Ok(())
+        })?;
+        assert!(same(&attend()?, &in_f16)?);
+        Ok(())
+    }
+
+    #[test]
+    fn split_kv_options_select_split_attention() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (n_heads, seq_len, head_dim) = (2, 64, 16);
+        let qkv = || Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev);
+        let (q, k, v) = (qkv()?, qkv()?, qkv()?);
+        let params = SdpaParams {
+            n_kv_groups: 1,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: 1. / (head_dim as f32).sqrt(),
+            sliding_window: None,
+            head_scales: None,
+        };
+        let attend = || Sdpa.run_attention(&q, &k, &v, None, None, &params);
+        let split = split_kv_sdpa(&q, &k, &v, None, &params, 16, SplitKvAccumulation::F32)?;
+        let split_kahan = split_kv_sdpa(&q, &k, &v, None, &params, 16, SplitKvAccumulation::Kahan)?;
+        let scoped = |accumulation| {
+            super::with_split_kv(Some(16), accumulation, || -> candle_core::Result<_> {
+                attend()?.flatten_all()?.to_vec1::<f32>()
+            })
+        };
+        assert_eq!(
+            scoped(SplitKvAccumulation::Kahan)?,
+            split_kahan.flatten_all()?.to_vec1::<f32>()?
+        );
+        assert_eq!(
+            scoped(SplitKvAccumulation::F32)?,
+            split.flatten_all()?.to_vec1::<f32>()?
+        );
+        Ok(())
+    }
+}
@@ -94,7 +94,7 @@ impl DeviceMapSetting {
                             nm_device: device.clone(),
                         }));
                     } else {
-                        let layers = topology
+                        let mut layers = topology
                             .0
                             .iter()
                             .map(|layer| {
@@ -104,6 +104,10 @@ impl DeviceMapSetting {
                                     .unwrap_or(device.clone())
                             })
                             .collect::<Vec<_>>();
+                        // Layers past the end of the topology stay on the main device.
+                        if layers.len() < model_layers {
+                            layers.resize(model_layers, device.clone());
+                        }
 
                         info!("Loading model according to the following repeating layer mappings based on topology:");
                         for (i, dev) in layers.iter().enumerate() {
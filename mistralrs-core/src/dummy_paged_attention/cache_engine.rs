@@ -1,17 +1,70 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
 use candle_core::{DType, Device, Result, Tensor};
 
-use super::config::ModelConfigLike;
+use super::{config::ModelConfigLike, PagedCacheType};
 
 #[derive(Clone, Debug)]
 pub struct CacheConfig {
     pub block_size: usize,
     pub num_gpu_blocks: usize,
     pub num_cpu_blocks: usize,
+    pub cache_type: PagedCacheType,
+    /// Per-layer overrides of `cache_type`, one entry per layer.
+    pub layer_cache_types: Vec<Option<PagedCacheType>>,
+    /// Number of blocks currently allocated from each pool. Shared with the [`super::BlockEngine`].
+    pub usage: BlockUsage,
+}
+
+impl CacheConfig {
+    /// Storage type of the KV cache blocks of this layer.
+    pub fn layer_cache_type(&self, layer: usize) -> PagedCacheType {
+        self.layer_cache_types
+            .get(layer)
+            .copied()
+            .flatten()
+            .unwrap_or(self.cache_type)
+    }
+
+    /// Snapshot of the current block usage.
+    pub fn stats(&self) -> PagedCacheStats {
+        let used_gpu_blocks = self.usage.gpu.load(Ordering::Relaxed);
+        let used_cpu_blocks = self.usage.cpu.load(Ordering::Relaxed);
+        PagedCacheStats {
+            block_size: self.block_size,
+            total_gpu_blocks: self.num_gpu_blocks,
+            used_gpu_blocks,
+            free_gpu_blocks: self.num_gpu_blocks.saturating_sub(used_gpu_blocks),
+            total_cpu_blocks: self.num_cpu_blocks,
+            used_cpu_blocks,
+            free_cpu_blocks: self.num_cpu_blocks.saturating_sub(used_cpu_blocks),
+        }
+    }
+}
+
+/// Counters of the allocated GPU and CPU blocks, updated by the block allocators.
+#[derive(Clone, Debug, Default)]
+pub struct BlockUsage {
+    pub(crate) gpu: Arc<AtomicUsize>,
+    pub(crate) cpu: Arc<AtomicUsize>,
+}
+
+/// Usage of the PagedAttention KV cache blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PagedCacheStats {
+    pub block_size: usize,
+    pub total_gpu_blocks: usize,
+    pub used_gpu_blocks: usize,
+    pub free_gpu_blocks: usize,
+    pub total_cpu_blocks: usize,
+    pub used_cpu_blocks: usize,
+    pub free_cpu_blocks: usize,
 }
 
 pub type KVCache = (Tensor, Tensor);
@@ -13,7 +13,9 @@ pub const _PAD_SLOT_ID: i64 = -1;
 
 pub use block_engine::{BlockEngine, BlockTables, LogicalTokenBlock};
 pub use block_engine_sequence::BlockEngineSequence;
-pub use cache_engine::{CacheConfig, CacheEngine};
+pub use cache_engine::{BlockUsage, CacheConfig, CacheEngine, PagedCacheStats};
+use std::str::FromStr;
+
 use candle_core::{DType, Device};
 pub use config::{ModelConfigLike, ModelConfigMetadata};
 pub use layers::PagedAttention;
@@ -29,6 +31,7 @@ pub struct PagedAttentionConfig {
     pub(crate) block_size: Option<usize>,
     pub(crate) mem_cpu: usize,
     pub(crate) mem_gpu: MemoryGpuConfig,
+    pub(crate) cache_type: PagedCacheType,
 }
 
 impl PagedAttentionConfig {
@@ -39,6 +42,37 @@ impl PagedAttentionConfig {
     ) -> anyhow::Result<Self> {
         anyhow::bail!("PagedAttention is only supported for CUDA, compile with feature `cuda`.")
     }
+
+    /// Set the storage type of the KV cache blocks.
+    pub fn with_cache_type(mut self, cache_type: PagedCacheType) -> Self {
+        self.cache_type = cache_type;
+        self
+    }
+}
+
+/// Storage type of the PagedAttention KV cache blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
+pub enum PagedCacheType {
+    /// Store keys and values in the model activation dtype.
+    #[default]
+    Auto,
+    /// Store keys and values as int8 with one scale per token and head, kept inside the block.
+    /// Values are dequantized when the blocks are read for attention.
+    I8,
+}
+
+impl FromStr for PagedCacheType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "i8" | "int8" => Ok(Self::I8),
+            other => Err(format!(
+                "Unknown PagedAttention cache type `{other}`, expected `auto` or `i8`."
+            )),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -61,6 +95,8 @@ pub fn calculate_cache_config(
     _mem_gpu: MemoryGpuConfig,
     _mem_cpu: usize,
     _block_size: Option<usize>,
+    _cache_type: PagedCacheType,
+    _layer_cache_types: &[Option<PagedCacheType>],
     _dtype: DType,
     _config: &dyn ModelConfigLike,
     _device: &Device,
@@ -1,9 +1,12 @@
 use crate::{
     pipeline::NormalCache,
-    request::{DetokenizationRequest, NormalRequest, SearchContextSize, TokenizationRequest},
+    request::{
+        check_token_ids, DetokenizationRequest, NormalRequest, SearchContextSize,
+        TokenizationRequest,
+    },
     search::{self, SearchFunctionParameters, SearchResult},
     sequence::SeqStepType,
-    tools::{ToolCallingMatcher, ToolChoice},
+    tools::{ToolCallDetector, ToolCallingMatcher, ToolChoice},
     MessageContent, RequestMessage, Response, ResponseOk,
 };
 use candle_core::Tensor;
@@ -215,10 +218,54 @@ impl Engine {
             Request::TerminateAllSeqsNextStep => {
                 TERMINATE_ALL_NEXT_STEP.store(true, Ordering::SeqCst)
             }
+            Request::Drain => self.drain().await,
         }
     }
 
+    /// Finish every in-flight and queued sequence with its partial output. New requests, such as
+    /// the follow-up requests of web search tasks, are rejected until the drain is done.
+    async fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        let mut seqs = get_mut_arcmutex!(self.scheduler).drain();
+        seqs.extend(
+            self.admission
+                .lock()
+                .unwrap()
+                .drain()
+                .into_iter()
+                .map(|seq| Arc::new(std::sync::Mutex::new(seq))),
+        );
+        let mut guards = seqs
+            .iter()
+            .map(|seq| seq.lock().unwrap())
+            .collect::<Vec<_>>();
+        let mut guards_mut = guards.iter_mut().map(|seq| &mut **seq).collect::<Vec<_>>();
+        get_mut_arcmutex!(self.pipeline)
+            .drain(&mut guards_mut, &mut get_mut_arcmutex!(self.prefix_cacher))
+            .await;
+        self.draining.store(false, Ordering::SeqCst);
+    }
+
     async fn add_request(&self, request: NormalRequest) {
+        if self.draining.load(Ordering::SeqCst) {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "The engine is shutting down and does not accept new requests.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        // Checked before tokenization, which is itself costly for very long inputs.
+        if let Err(e) = self.input_limits.check(&request.messages) {
+            request
+                .response
+                .send(Response::ValidationError(e.into()))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
         let is_chat = matches!(
             request.messages,
             RequestMessage::Chat(_) | RequestMessage::VisionChat { .. }
@@ -328,6 +375,14 @@ impl Engine {
                         .expect("Expected receiver.");
                     return;
                 };
+                if let Err(e) = check_token_ids(&it, tokenizer.get_vocab_size(true)) {
+                    request
+                        .response
+                        .send(Response::ValidationError(e.into()))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
                 let prompt = tokenizer
                     .decode(&it, false)
                     .map_err(|e| anyhow::Error::msg(e.to_string()));
@@ -370,6 +425,30 @@ impl Engine {
                 warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt_tokens.len());
             }
         }
+        // Token healing: drop the last prompt token, the first generated token must then start with its text.
+        let healing = if request.sampling_params.token_healing && prompt_tokens.len() > 1 {
+            let Some(tok_env) = get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .tok_env
+                .clone()
+            else {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Token healing requires the pipeline to have a tokenizer".into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            };
+            let removed_tok = prompt_tokens
+                .pop()
+                .expect("Prompt has more than one token.");
+            Some((tok_env, removed_tok))
+        } else {
+            None
+        };
+
         let prefill_cache = handle_seq_error!(
             get_mut_arcmutex!(self.prefix_cacher).search_for_matching_cache(
                 &prompt_tokens,
@@ -460,14 +539,31 @@ impl Engine {
             }
         };
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+        let mut group = SequenceGroup::new(
             request.sampling_params.n_choices,
             request.is_streaming,
             is_chat,
             best_of,
-        )));
+        );
+        group.stream_raw_bytes = request.stream_raw_bytes;
+        group.stream_flush_policy = request.stream_flush_policy;
+        let group = Arc::new(tokio::sync::Mutex::new(group));
 
         let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
+        let document_separators = if request.sampling_params.eos_separates_documents {
+            get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .eos_tok
+                .clone()
+        } else {
+            Vec::new()
+        };
+        let reasoning_end_tok = match (&tokenizer, request.sampling_params.reasoning_budget) {
+            (Some(tokenizer), Some(_)) => get_mut_arcmutex!(self.pipeline)
+                .get_chat_template()
+                .and_then(|template| template.reasoning_end_tok(tokenizer)),
+            _ => None,
+        };
 
         let sampler = Sampler::new(
             Some(request.sampling_params.temperature.unwrap_or(1.0)),
@@ -475,12 +571,33 @@ impl Engine {
             tokenizer,
             request.sampling_params.frequency_penalty,
             request.sampling_params.presence_penalty,
+            request.sampling_params.penalty_scope,
             request.sampling_params.dry_params,
             topk,
             topp,
             minp,
             request.logits_processors.unwrap_or_default(),
-        );
+        )
+        .map(|sampler| {
+            sampler
+                .with_stream_top_k_logprobs(request.sampling_params.stream_top_k_logprobs)
+                .with_entropy(request.sampling_params.return_entropy)
+                .with_temperature_order(request.sampling_params.temperature_order)
+                .with_penalty_decay(request.sampling_params.penalty_decay)
+                .with_document_separators(document_separators)
+        })
+        .and_then(|sampler| {
+            sampler.with_reasoning_budget(
+                request.sampling_params.reasoning_budget,
+                reasoning_end_tok.as_deref(),
+            )
+        })
+        .map(|sampler| match &healing {
+            Some((tok_env, removed_tok)) => {
+                sampler.with_token_healing(tok_env.tok_trie(), *removed_tok)
+            }
+            None => sampler,
+        });
         let sampler = handle_seq_error!(sampler, request.response);
 
         if request.sampling_params.n_choices == 0 {
@@ -493,6 +610,38 @@ impl Engine {
                 .expect("Expected receiver.");
             return;
         }
+        if request
+            .sampling_params
+            .repetition_loop
+            .is_some_and(|params| params.max_pattern_len == 0 || params.min_repeats < 2)
+        {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Repetition loop detection needs a pattern length of at least 1 and at least 2 repeats.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        let admitted = {
+            let scheduler = get_mut_arcmutex!(self.scheduler);
+            let live = scheduler.waiting_len() + scheduler.running_len();
+            self.admission
+                .lock()
+                .unwrap()
+                .check(live, request.sampling_params.n_choices)
+        };
+        if let Err(e) = admitted {
+            request
+                .response
+                .send(Response::ValidationError(e.into()))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+
+        let mut seqs = Vec::with_capacity(request.sampling_params.n_choices);
 
         // Add sequences
         for response_index in 0..request.sampling_params.n_choices {
@@ -631,6 +780,16 @@ impl Engine {
                 seq_preallocated_cache,
                 request.return_raw_logits,
                 eos_toks,
+                self.content_filter.clone(),
+                request.tool_call_trigger.clone().map(ToolCallDetector::new),
+            )
+            .with_kv_cache_limit(request.kv_cache_limit)
+            .with_repetition_loop(request.sampling_params.repetition_loop)
+            .with_healed_prefix(
+                healing
+                    .as_ref()
+                    .map(|(tok_env, removed_tok)| tok_env.tok_trie().decode(&[*removed_tok]))
+                    .unwrap_or_default(),
             );
             self.logger.add_new_sequence();
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
@@ -645,7 +804,13 @@ impl Engine {
                 seq
             };
             *get_mut_arcmutex!(self.id) += 1;
-            get_mut_arcmutex!(self.scheduler).add_seq(seq);
+            seqs.push(seq);
+        }
+
+        let mut scheduler = get_mut_arcmutex!(self.scheduler);
+        let live = scheduler.waiting_len() + scheduler.running_len();
+        for seq in self.admission.lock().unwrap().submit(live, seqs) {
+            scheduler.add_seq(seq);
         }
     }
 
@@ -8,8 +8,9 @@ use crate::{
     },
     prefix_cacher::PrefixCacheManagerV2,
     response::CompletionChoice,
-    scheduler::{Scheduler, SchedulerOutput},
-    sequence::{SeqStepType, StopReason},
+    sampler::SamplingRng,
+    scheduler::{ConcurrencyLimit, Scheduler, SchedulerOutput, SeqAdmission},
+    sequence::{ContentFilter, SeqStepType, Sequence, StopReason},
     CompletionResponse, SchedulerConfig, DEBUG,
 };
 use interprocess::local_socket::{traits::Listener, ListenerOptions};
@@ -36,7 +37,7 @@ use tokio::{
 use crate::{
     get_mut_arcmutex, handle_pipeline_forward_error,
     pipeline::Pipeline,
-    request::Request,
+    request::{InputLimits, Request},
     response::{ChatCompletionResponse, Choice, ResponseMessage},
     sequence::{SequenceRecognizer, SequenceState},
     Constraint,
@@ -79,6 +80,11 @@ pub struct Engine {
     throughput_logging_enabled: bool,
     logger: IntervalLogger,
     handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    content_filter: Option<ContentFilter>,
+    rng: SamplingRng,
+    draining: AtomicBool,
+    input_limits: InputLimits,
+    admission: std::sync::Mutex<SeqAdmission<Sequence>>,
 }
 
 impl Drop for Engine {
@@ -89,6 +95,26 @@ impl Drop for Engine {
     }
 }
 
+/// Prime `prefix_cacher` with `prompts`. Priming only speeds up later requests, so a failure is
+/// logged and the engine starts with whatever was primed before it.
+fn prime_prefix_cache(
+    pipeline: &mut dyn Pipeline,
+    prompts: Vec<Vec<u32>>,
+    prefix_cacher: &mut PrefixCacheManagerV2,
+) {
+    tracing::info!("Priming the prefix cache with {} prompts.", prompts.len());
+    if let Err(e) = pipeline.prime_prefix_cache(prompts, prefix_cacher) {
+        tracing::warn!(
+            "Failed to prime the prefix cache, continuing with the prompts primed so far: {e}"
+        );
+    }
+}
+
+/// The number of tokens evicted from the KV caches of `seqs` to keep them within their limits.
+fn total_kv_cache_evicted_toks(seqs: &[&mut Sequence]) -> usize {
+    seqs.iter().map(|seq| seq.kv_cache_evicted_toks()).sum()
+}
+
 impl Engine {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -102,6 +128,11 @@ impl Engine {
         disable_eos_stop: bool,
         throughput_logging_enabled: bool,
         search_embedding_model: Option<BertEmbeddingModel>,
+        content_filter: Option<ContentFilter>,
+        sampling_rng: Option<SamplingRng>,
+        input_limits: InputLimits,
+        prefix_cache_primes: Vec<Vec<u32>>,
+        concurrency_limit: Option<ConcurrencyLimit>,
     ) -> anyhow::Result<Self> {
         no_kv_cache |= get_mut_arcmutex!(pipeline).get_metadata().no_kv_cache;
 
@@ -117,6 +148,15 @@ impl Engine {
             None => None,
         };
 
+        let mut prefix_cacher = PrefixCacheManagerV2::new(prefix_cache_n, no_prefix_cache);
+        if !no_prefix_cache && !prefix_cache_primes.is_empty() {
+            prime_prefix_cache(
+                &mut *get_mut_arcmutex!(pipeline),
+                prefix_cache_primes,
+                &mut prefix_cacher,
+            );
+        }
+
         Ok(Self {
             rx: Arc::new(Mutex::new(rx)),
             pipeline,
@@ -125,24 +165,33 @@ impl Engine {
             id: Arc::new(Mutex::new(0)),
             truncate_sequence,
             no_kv_cache,
-            prefix_cacher: Arc::new(Mutex::new(PrefixCacheManagerV2::new(
-                prefix_cache_n,
-                no_prefix_cache,
-            ))),
+            prefix_cacher: Arc::new(Mutex::new(prefix_cacher)),
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
             throughput_logging_enabled,
             logger: IntervalLogger::new(Duration::from_secs(5)),
             handles: Arc::new(Mutex::new(Vec::new())),
+            content_filter,
+            rng: sampling_rng.unwrap_or_else(|| {
+                Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(SEED)))
+            }),
+            draining: AtomicBool::new(false),
+            input_limits,
+            admission: std::sync::Mutex::new(SeqAdmission::new(concurrency_limit)),
         })
     }
 
+    /// The prefix cacher used by this engine, to read its statistics while the engine runs.
+    pub(crate) fn prefix_cacher(&self) -> Arc<Mutex<PrefixCacheManagerV2>> {
+        self.prefix_cacher.clone()
+    }
+
     pub async fn run(self: Arc<Self>) {
         if self.throughput_logging_enabled {
             self.logger.enable_logging();
         }
 
-        let rng = Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(SEED)));
+        let rng = self.rng.clone();
         let mut last_completion_ids: Vec<usize> = vec![];
         'lp: loop {
             if matches!(
@@ -170,6 +219,10 @@ impl Engine {
 
             let run_start = Instant::now();
             let mut scheduler = get_mut_arcmutex!(self.scheduler);
+            let live = scheduler.waiting_len() + scheduler.running_len();
+            for seq in self.admission.lock().unwrap().release(live) {
+                scheduler.add_seq(seq);
+            }
             let scheduled = scheduler.schedule();
 
             match scheduled {
@@ -179,6 +232,7 @@ impl Engine {
                     if !scheduled.completion.is_empty() {
                         let current_completion_ids: Vec<usize> =
                             scheduled.completion.iter().map(|seq| *seq.id()).collect();
+                        let evicted_before = total_kv_cache_evicted_toks(&scheduled.completion);
                         let res = {
                             let mut pipeline = get_mut_arcmutex!(self.pipeline);
                             let pre_op = if !self.no_kv_cache
@@ -230,7 +284,15 @@ impl Engine {
 
                         self.logger.add_tokens_processed(scheduled.completion.len());
 
-                        last_completion_ids = current_completion_ids;
+                        // Evicting from the sequence caches leaves the model cache stale, so copy
+                        // them in again on the next step.
+                        last_completion_ids = if total_kv_cache_evicted_toks(&scheduled.completion)
+                            == evicted_before
+                        {
+                            current_completion_ids
+                        } else {
+                            Vec::new()
+                        };
                     }
 
                     if !scheduled.prompt.is_empty() {
@@ -493,3 +555,157 @@ impl Engine {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{any::Any, sync::Arc};
+
+    use candle_core::{Device, Tensor};
+    use mistralrs_quant::IsqType;
+    use tokenizers::Tokenizer;
+
+    use crate::{
+        device_map::DeviceMapper,
+        pipeline::{
+            chat_template::ChatTemplate, AnyMoePipelineMixin, CacheManagerMixin, EitherCache,
+            ForwardInputsResult, GeneralMetadata, IsqPipelineMixin, KvCache, MetadataMixin,
+            ModelCategory, Pipeline, PreProcessingMixin,
+        },
+        prefix_cacher::PrefixCacheManagerV2,
+        sampler::SamplingRng,
+        sequence::Sequence,
+    };
+
+    /// A pipeline whose prefill stores each prompt token as the key and value, failing on `fail_tok`.
+    struct Primer {
+        fail_tok: u32,
+    }
+
+    impl MetadataMixin for Primer {
+        fn device(&self) -> Device {
+            Device::Cpu
+        }
+        fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+            None
+        }
+        fn name(&self) -> String {
+            "primer".to_string()
+        }
+        fn reset_non_granular_state(&self) {}
+        fn get_metadata(&self) -> Arc<GeneralMetadata> {
+            unreachable!()
+        }
+        fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
+            None
+        }
+    }
+
+    impl PreProcessingMixin for Primer {
+        fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+            None
+        }
+        fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+            None
+        }
+    }
+
+    impl IsqPipelineMixin for Primer {
+        fn re_isq_model(&mut self, _dtype: IsqType) -> anyhow::Result<()> {
+            unreachable!()
+        }
+    }
+
+    impl CacheManagerMixin for Primer {
+        fn clone_in_cache(&self, _seqs: &mut [&mut Sequence]) {
+            unreachable!()
+        }
+        fn clone_out_cache(&self, _seqs: &mut [&mut Sequence]) {
+            unreachable!()
+        }
+        fn set_none_cache(
+            &self,
+            _seqs: &mut [&mut Sequence],
+            _reset_non_granular: bool,
+            _modify_draft_cache: bool,
+            _load_preallocated_cache: bool,
+        ) {
+            unreachable!()
+        }
+        fn cache(&self) -> &EitherCache {
+            unreachable!()
+        }
+    }
+
+    impl AnyMoePipelineMixin for Primer {}
+
+    #[async_trait::async_trait]
+    impl Pipeline for Primer {
+        fn forward_inputs(
+            &mut self,
+            _inputs: Box<dyn Any>,
+            _return_raw_logits: bool,
+            _return_logprobs: Option<usize>,
+        ) -> candle_core::Result<ForwardInputsResult> {
+            unreachable!()
+        }
+        async fn sample_causal_gen(
+            &self,
+            _seqs: &mut [&mut Sequence],
+            _logits: Vec<Tensor>,
+            _prefix_cacher: &mut PrefixCacheManagerV2,
+            _disable_eos_stop: bool,
+            _rng: SamplingRng,
+        ) -> candle_core::Result<()> {
+            unreachable!()
+        }
+        fn category(&self) -> ModelCategory {
+            ModelCategory::Text
+        }
+        fn prime_prefix_cache(
+            &mut self,
+            prompts: Vec<Vec<u32>>,
+            prefix_cacher: &mut PrefixCacheManagerV2,
+        ) -> candle_core::Result<()> {
+            prefix_cacher.prime(prompts, |prompt| {
+                if prompt.contains(&self.fail_tok) {
+                    candle_core::bail!("prefill failed");
+                }
+                let data = Tensor::new(prompt, &Device::Cpu)?
+                    .to_dtype(candle_core::DType::F32)?
+                    .reshape((1, 1, prompt.len(), 1))?;
+                let mut cache = KvCache::new_normal(2, 64, 16);
+                cache.append(&data, &data)?;
+                Ok(vec![Some(cache)])
+            })
+        }
+    }
+
+    #[test]
+    fn failed_priming_keeps_earlier_prompts() -> candle_core::Result<()> {
+        let mut pipeline = Primer { fail_tok: 99 };
+        let mut prefix_cacher = PrefixCacheManagerV2::new(16, false);
+        let primed = vec![1, 2, 3, 4];
+        super::prime_prefix_cache(
+            &mut pipeline,
+            vec![primed.clone(), vec![], vec![5, 99, 6], vec![7, 8]],
+            &mut prefix_cacher,
+        );
+
+        let request = [primed.clone(), vec![42]].concat();
+        let matching = prefix_cacher
+            .search_for_matching_cache(&request, false)?
+            .expect("primed prefix should match");
+        assert_eq!(matching.offset, primed.len() - 1);
+        let k = matching.normal[0].as_ref().unwrap().k()?.unwrap();
+        assert_eq!(k.flatten_all()?.to_vec1::<f32>()?, vec![1., 2., 3.]);
+
+        // Priming stops at the failing prompt, and the engine still starts.
+        assert!(prefix_cacher
+            .search_for_matching_cache(&[5, 99, 6, 42], false)?
+            .is_none());
+        assert!(prefix_cacher
+            .search_for_matching_cache(&[7, 8, 42], false)?
+            .is_none());
+        Ok(())
+    }
+}
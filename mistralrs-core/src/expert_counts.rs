@@ -0,0 +1,59 @@
+//! Opt-in count of the tokens routed to each expert of the MoE layers, for diagnosing load imbalance.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+/// Counts how many tokens each MoE layer routed to each of its experts. Clones share the counts.
+///
+/// Counting is off until [`ExpertCounter::enable`] is called, and then only adds up the routing decisions
+/// already made by the layer.
+#[derive(Clone, Default)]
+pub struct ExpertCounter {
+    enabled: Arc<AtomicBool>,
+    /// Indexed by layer, then expert.
+    counts: Arc<Mutex<Vec<Vec<usize>>>>,
+}
+
+impl ExpertCounter {
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Add the number of tokens routed to each expert of `layer_idx`.
+    pub(crate) fn record(
+        &self,
+        layer_idx: usize,
+        tokens_per_expert: impl ExactSizeIterator<Item = usize>,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut counts = self.counts.lock().unwrap();
+        if counts.len() <= layer_idx {
+            counts.resize(layer_idx + 1, Vec::new());
+        }
+        let layer = &mut counts[layer_idx];
+        if layer.len() < tokens_per_expert.len() {
+            layer.resize(tokens_per_expert.len(), 0);
+        }
+        for (count, n_tokens) in layer.iter_mut().zip(tokens_per_expert) {
+            *count += n_tokens;
+        }
+    }
+
+    /// Take the counts recorded since the last call, indexed by layer then expert. Layers which have not
+    /// routed any tokens are empty.
+    pub fn take(&self) -> Vec<Vec<usize>> {
+        std::mem::take(&mut *self.counts.lock().unwrap())
+    }
+}
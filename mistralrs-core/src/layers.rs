@@ -1,6 +1,14 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use std::{f32::consts::PI, ops::Mul, str::FromStr, sync::Arc};
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::HashMap,
+    f32::consts::PI,
+    ops::Mul,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
 
 use candle_core::{
     quantized::{QMatMul, QTensor},
@@ -12,9 +20,10 @@ use candle_nn::{
 use float8::F8E4M3;
 use half::{bf16, f16};
 use mistralrs_quant::{
-    AfqLayer, ColumnParallelLayer, QuantMethod, QuantizedConfig, RowParallelLayer,
-    ShardedVarBuilder,
+    get_strict_determinism, op_trace, AfqLayer, ColumnParallelLayer, QuantMethod, QuantizedConfig,
+    RowParallelLayer, ShardedVarBuilder,
 };
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
 pub use crate::attention::Sdpa;
@@ -23,6 +32,7 @@ pub use crate::layers_utils::repeat_kv;
 use crate::{
     amoe::{AnyMoeTrainableLayer, MlpLayer},
     gguf::Content,
+    layers_utils::{fixed_order_sum_keepdim, is_differentiable},
     models::llama,
     ops::SplitOp,
     vision_models::{
@@ -145,19 +155,107 @@ pub fn linear_b(
 pub struct RmsNorm {
     eps: f64,
     weight: Tensor,
+    force_f32_accumulation: bool,
+}
+
+thread_local! {
+    static F32_NORM_WEIGHTS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether RmsNorms built on this thread keep their weights in f32.
+fn f32_norm_weights() -> bool {
+    F32_NORM_WEIGHTS.with(Cell::get)
+}
+
+/// Run `f`, typically a model load, with the RmsNorm weights it loads kept in f32 even when the
+/// model is f16/bf16.
+pub(crate) fn with_f32_norm_weights<T>(f32_weights: bool, f: impl FnOnce() -> T) -> T {
+    let prev = F32_NORM_WEIGHTS.replace(f32_weights);
+    let res = f();
+    F32_NORM_WEIGHTS.set(prev);
+    res
+}
+
+/// Names under which checkpoints store the norm weight, tried in order.
+pub const NORM_WEIGHT_ALIASES: &[&str] = &["weight", "scale", "gamma"];
+
+/// The first of [`NORM_WEIGHT_ALIASES`] present in `vb`. Defaults to `weight`, so that a missing weight
+/// is reported under its usual name.
+fn norm_weight_name(vb: &ShardedVarBuilder) -> &'static str {
+    NORM_WEIGHT_ALIASES
+        .iter()
+        .find(|name| vb.contains_tensor(name))
+        .copied()
+        .unwrap_or(NORM_WEIGHT_ALIASES[0])
 }
 
 impl RmsNorm {
     pub fn new(size: usize, eps: f64, vb: ShardedVarBuilder) -> Result<Self> {
-        let w = vb.get(size, "weight")?;
-        Ok(Self { eps, weight: w })
+        if f32_norm_weights() {
+            return Self::new_f32(size, eps, vb);
+        }
+        let w = vb.get(size, norm_weight_name(&vb))?;
+        Ok(Self {
+            eps,
+            weight: w,
+            force_f32_accumulation: false,
+        })
+    }
+
+    /// Load the weight stored under `name` rather than one of [`NORM_WEIGHT_ALIASES`].
+    pub fn new_named(size: usize, eps: f64, vb: ShardedVarBuilder, name: &str) -> Result<Self> {
+        let vb = if f32_norm_weights() {
+            vb.set_dtype(DType::F32)
+        } else {
+            vb
+        };
+        let w = vb.get(size, name)?;
+        Ok(Self {
+            eps,
+            weight: w,
+            force_f32_accumulation: false,
+        })
+    }
+
+    /// With `force_f32_accumulation`, inputs of another dtype are normalized in f32 as in
+    /// [`F32RmsNorm`], while the weight stays in the model dtype.
+    pub fn new_with_opts(
+        size: usize,
+        eps: f64,
+        vb: ShardedVarBuilder,
+        force_f32_accumulation: bool,
+    ) -> Result<Self> {
+        Ok(Self {
+            force_f32_accumulation,
+            ..Self::new(size, eps, vb)?
+        })
+    }
+
+    /// Load the weight in f32 regardless of the model dtype. The input is upcast for the norm and the
+    /// output cast back to the input dtype.
+    pub fn new_f32(size: usize, eps: f64, vb: ShardedVarBuilder) -> Result<Self> {
+        let w = vb.set_dtype(DType::F32).get(size, norm_weight_name(&vb))?;
+        Ok(Self {
+            eps,
+            weight: w,
+            force_f32_accumulation: false,
+        })
     }
 
     /// Gemma uses weight + 1.0
     pub fn new_gemma(size: usize, eps: f64, vb: ShardedVarBuilder) -> Result<Self> {
-        let w = vb.get(size, "weight")?;
+        let vb = if f32_norm_weights() {
+            vb.set_dtype(DType::F32)
+        } else {
+            vb
+        };
+        let w = vb.get(size, norm_weight_name(&vb))?;
         let w = (w + 1.0)?;
-        Ok(Self { eps, weight: w })
+        Ok(Self {
+            eps,
+            weight: w,
+            force_f32_accumulation: false,
+        })
     }
 
     /// Gemma uses weight + 1.0. Undo for UQFF generation.
@@ -165,21 +263,76 @@ impl RmsNorm {
         Ok(Self {
             eps: self.eps,
             weight: (&self.weight - 1.0)?,
+            force_f32_accumulation: self.force_f32_accumulation,
         })
     }
 
     pub fn from_w(w: Tensor, eps: f64) -> Result<Self> {
-        Ok(Self { eps, weight: w })
+        Ok(Self {
+            eps,
+            weight: w,
+            force_f32_accumulation: false,
+        })
     }
 
     pub fn weight(&self) -> &Tensor {
         &self.weight
     }
+
+    /// Compute `x + residual` and its norm, returning `(sum, normed)`. On CUDA and Metal both come
+    /// from one kernel, which saves reading the sum back for the norm. Elsewhere, and whenever
+    /// [`Module::forward`] would not use the fused norm kernel, this adds and then normalizes.
+    pub fn forward_residual(&self, x: &Tensor, residual: &Tensor) -> Result<(Tensor, Tensor)> {
+        let fused = mistralrs_quant::rms_norm::has_fused_add_rms_norm(x.device())
+            && x.dtype() == self.weight.dtype()
+            && residual.dtype() == x.dtype()
+            && !(self.force_f32_accumulation && x.dtype() != DType::F32)
+            && !get_strict_determinism()
+            && !is_differentiable();
+        if !fused {
+            let sum = (x + residual)?;
+            let normed = self.forward(&sum)?;
+            return Ok((sum, normed));
+        }
+        let (sum, normed) = mistralrs_quant::rms_norm::fused_add_rms_norm(
+            x,
+            residual,
+            &self.weight,
+            self.eps as f32,
+        )?;
+        op_trace::record("rms_norm", &[&sum], &[&normed]);
+        Ok((sum, normed))
+    }
 }
 
 impl Module for RmsNorm {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
-        candle_nn::ops::rms_norm(&x.contiguous()?, &self.weight, self.eps as f32)
+        // Strict determinism also avoids the fused kernel, whose reduction order is device specific.
+        let out = if (self.force_f32_accumulation && x.dtype() != DType::F32)
+            || get_strict_determinism()
+        {
+            let xs = x.to_dtype(DType::F32)?;
+            let var = if get_strict_determinism() {
+                (fixed_order_sum_keepdim(&xs.sqr()?)? / xs.dim(D::Minus1)? as f64)?
+            } else {
+                xs.powf(2.)?.mean_keepdim(D::Minus1)?
+            };
+            let xs = xs.broadcast_mul(&(&var + self.eps)?.recip()?.sqrt()?)?;
+            xs.to_dtype(x.dtype())?
+                .broadcast_mul(&self.weight.to_dtype(x.dtype())?)?
+        } else if is_differentiable() {
+            let xs = x.to_dtype(self.weight.dtype())?;
+            candle_nn::ops::rms_norm_slow(&xs, &self.weight, self.eps as f32)?
+                .to_dtype(x.dtype())?
+        } else if x.dtype() == self.weight.dtype() {
+            candle_nn::ops::rms_norm(&x.contiguous()?, &self.weight, self.eps as f32)?
+        } else {
+            // The weight was kept in a higher precision than the model, so normalize in its dtype.
+            let xs = x.to_dtype(self.weight.dtype())?.contiguous()?;
+            candle_nn::ops::rms_norm(&xs, &self.weight, self.eps as f32)?.to_dtype(x.dtype())?
+        };
+        op_trace::record("rms_norm", &[x], &[&out]);
+        Ok(out)
     }
 }
 
@@ -192,7 +345,7 @@ pub struct F32RmsNorm {
 impl F32RmsNorm {
     pub fn new(size: usize, eps: f64, vb: ShardedVarBuilder) -> Result<Self> {
         Ok(Self {
-            w: vb.get((size,), "weight")?,
+            w: vb.get((size,), norm_weight_name(&vb))?,
             eps,
         })
     }
@@ -235,13 +388,88 @@ impl QRmsNorm {
 /// RoPE supporting LongRope
 #[derive(Debug, Clone)]
 pub struct PhiRotaryEmbedding {
-    short_sin: Tensor,
-    short_cos: Tensor,
-    long_cos: Option<Tensor>,
-    long_sin: Option<Tensor>,
+    short: PhiRopeTable,
+    long: Option<PhiRopeTable>,
     original_max_position_embeddings: usize,
 }
 
+/// Precomputed sin/cos for one set of inverse frequencies, built up to a capped length and extended
+/// on demand.
+#[derive(Debug, Clone)]
+struct PhiRopeTable {
+    inv_freq: Tensor,
+    scaling_factor: f64,
+    max_position_embeddings: usize,
+    dtype: DType,
+    /// (sin, cos) for the positions built so far, shared between clones.
+    built: Arc<RwLock<(Tensor, Tensor)>>,
+}
+
+impl PhiRopeTable {
+    fn new(
+        inv_freq: Tensor,
+        scaling_factor: f64,
+        max_position_embeddings: usize,
+        max_cached_positions: Option<usize>,
+        dtype: DType,
+    ) -> Result<Self> {
+        let len = max_cached_positions.map_or(max_position_embeddings, |cap| {
+            cap.clamp(1, max_position_embeddings)
+        });
+        let built = Self::build(&inv_freq, scaling_factor, 0, len, dtype)?;
+        Ok(Self {
+            inv_freq,
+            scaling_factor,
+            max_position_embeddings,
+            dtype,
+            built: Arc::new(RwLock::new(built)),
+        })
+    }
+
+    /// Compute (sin, cos) for positions `start..start + len`, scaled before casting to `dtype`.
+    fn build(
+        inv_freq: &Tensor,
+        scaling_factor: f64,
+        start: usize,
+        len: usize,
+        dtype: DType,
+    ) -> Result<(Tensor, Tensor)> {
+        let (cos, sin) = build_rotary_tables(inv_freq, start, len, DType::F32)?;
+        Ok((
+            sin.mul(scaling_factor)?.to_dtype(dtype)?,
+            cos.mul(scaling_factor)?.to_dtype(dtype)?,
+        ))
+    }
+
+    /// Get (sin, cos) covering at least `len` positions, extending the table as required.
+    fn get(&self, len: usize) -> Result<(Tensor, Tensor)> {
+        {
+            let built = self.built.read().unwrap();
+            if built.0.dim(0)? >= len {
+                return Ok(built.clone());
+            }
+        }
+        let mut built = self.built.write().unwrap();
+        let cur_len = built.0.dim(0)?;
+        if cur_len >= len {
+            return Ok(built.clone());
+        }
+        // Grow geometrically to amortize extension during decoding.
+        let new_len = (cur_len * 2).min(self.max_position_embeddings).max(len);
+        let (sin_ext, cos_ext) = Self::build(
+            &self.inv_freq,
+            self.scaling_factor,
+            cur_len,
+            new_len - cur_len,
+            self.dtype,
+        )?;
+        let sin = Tensor::cat(&[&built.0, &sin_ext], 0)?;
+        let cos = Tensor::cat(&[&built.1, &cos_ext], 0)?;
+        *built = (sin.clone(), cos.clone());
+        Ok((sin, cos))
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ScaledRopeType {
@@ -300,11 +528,13 @@ pub struct PhiRopeConfig {
 }
 
 impl PhiRotaryEmbedding {
+    #[allow(clippy::too_many_arguments)]
     fn new_classic_scaled(
         short_factor: &[f64],
         long_factor: &[f64],
         scaling_type: &ScaledRopeType,
         cfg: &PhiRopeConfig,
+        max_cached_positions: Option<usize>,
         dtype: DType,
         dev: &Device,
     ) -> Result<Self> {
@@ -342,34 +572,34 @@ impl PhiRotaryEmbedding {
             })
             .collect::<Vec<_>>();
         let inv_freq_len = inv_freq_long.len();
-
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-
-        // Calculate sin,cos for long
         let inv_freq_long = Tensor::from_vec(inv_freq_long, (1, inv_freq_len), dev)?;
-        let freqs_long = t.matmul(&inv_freq_long)?;
-        let long_sin = freqs_long.sin()?.mul(scaling_factor)?.to_dtype(dtype)?;
-        let long_cos = freqs_long.cos()?.mul(scaling_factor)?.to_dtype(dtype)?;
-
-        // Calculate sin,cos for short
-        let inv_freq_short =
-            Tensor::from_vec(inv_freq_short, (1, inv_freq_len), dev)?.to_dtype(DType::F32)?;
-        let freqs_short = t.matmul(&inv_freq_short)?;
-        let short_sin = freqs_short.sin()?.mul(scaling_factor)?.to_dtype(dtype)?;
-        let short_cos = freqs_short.cos()?.mul(scaling_factor)?.to_dtype(dtype)?;
+        let inv_freq_short = Tensor::from_vec(inv_freq_short, (1, inv_freq_len), dev)?;
 
         Ok(Self {
-            short_cos,
-            short_sin,
-            long_cos: Some(long_cos),
-            long_sin: Some(long_sin),
+            short: PhiRopeTable::new(
+                inv_freq_short,
+                scaling_factor,
+                max_seq_len,
+                max_cached_positions,
+                dtype,
+            )?,
+            long: Some(PhiRopeTable::new(
+                inv_freq_long,
+                scaling_factor,
+                max_seq_len,
+                max_cached_positions,
+                dtype,
+            )?),
             original_max_position_embeddings: cfg.original_max_position_embeddings,
         })
     }
 
-    fn new_unscaled(cfg: &PhiRopeConfig, dtype: DType, dev: &Device) -> Result<Self> {
+    fn new_unscaled(
+        cfg: &PhiRopeConfig,
+        max_cached_positions: Option<usize>,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Self> {
         let max_seq_len = cfg.max_position_embeddings;
         let dim = (cfg.head_dim as f64 * cfg.partial_rotary_factor.unwrap_or(1.)) as usize;
 
@@ -379,17 +609,9 @@ impl PhiRotaryEmbedding {
             .collect();
         let inv_freq_len = inv_freq.len();
         let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        let sin = freqs.sin()?.to_dtype(dtype)?;
-        let cos = freqs.cos()?.to_dtype(dtype)?;
         Ok(Self {
-            short_cos: cos,
-            short_sin: sin,
-            long_cos: None,
-            long_sin: None,
+            short: PhiRopeTable::new(inv_freq, 1.0, max_seq_len, max_cached_positions, dtype)?,
+            long: None,
             original_max_position_embeddings: cfg.original_max_position_embeddings,
         })
     }
@@ -402,6 +624,7 @@ impl PhiRotaryEmbedding {
         long_mscale: f64,
         short_mscale: f64,
         cfg: &PhiRopeConfig,
+        max_cached_positions: Option<usize>,
         dtype: DType,
         dev: &Device,
     ) -> Result<Self> {
@@ -437,12 +660,6 @@ impl PhiRotaryEmbedding {
             .collect();
         let inv_freq_len_short = inv_freq_short.len();
         let inv_freq_short = Tensor::from_vec(inv_freq_short, (1, inv_freq_len_short), dev)?;
-        let t_short = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs_short = t_short.matmul(&inv_freq_short)?;
-        let sin_short = (freqs_short.sin()?.to_dtype(dtype)? * short_mscale)?;
-        let cos_short = (freqs_short.cos()?.to_dtype(dtype)? * short_mscale)?;
 
         // Long cos/sin
         let inv_freq_long: Vec<_> = (0..dim)
@@ -454,22 +671,37 @@ impl PhiRotaryEmbedding {
             .collect();
         let inv_freq_len_long = inv_freq_long.len();
         let inv_freq_long = Tensor::from_vec(inv_freq_long, (1, inv_freq_len_long), dev)?;
-        let t_long = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs_long = t_long.matmul(&inv_freq_long)?;
-        let sin_long = (freqs_long.sin()?.to_dtype(dtype)? * long_mscale)?;
-        let cos_long = (freqs_long.cos()?.to_dtype(dtype)? * long_mscale)?;
         Ok(Self {
-            short_cos: cos_short,
-            short_sin: sin_short,
-            long_cos: Some(cos_long),
-            long_sin: Some(sin_long),
+            short: PhiRopeTable::new(
+                inv_freq_short,
+                short_mscale,
+                max_seq_len,
+                max_cached_positions,
+                dtype,
+            )?,
+            long: Some(PhiRopeTable::new(
+                inv_freq_long,
+                long_mscale,
+                max_seq_len,
+                max_cached_positions,
+                dtype,
+            )?),
             original_max_position_embeddings: cfg.original_max_position_embeddings,
         })
     }
 
     pub fn new(dtype: DType, cfg: impl Into<PhiRopeConfig>, dev: &Device) -> Result<Self> {
+        Self::new_with_max_cached_positions(dtype, cfg, None, dev)
+    }
+
+    /// Like [`PhiRotaryEmbedding::new`], but only precompute sin/cos for the first
+    /// `max_cached_positions` positions. Longer tables are built when a longer sequence is seen.
+    pub fn new_with_max_cached_positions(
+        dtype: DType,
+        cfg: impl Into<PhiRopeConfig>,
+        max_cached_positions: Option<usize>,
+        dev: &Device,
+    ) -> Result<Self> {
         let cfg: PhiRopeConfig = cfg.into();
 
         match &cfg.rope_scaling {
@@ -477,9 +709,15 @@ impl PhiRotaryEmbedding {
                 short_factor,
                 long_factor,
                 scaling_type,
-            }) => {
-                Self::new_classic_scaled(short_factor, long_factor, scaling_type, &cfg, dtype, dev)
-            }
+            }) => Self::new_classic_scaled(
+                short_factor,
+                long_factor,
+                scaling_type,
+                &cfg,
+                max_cached_positions,
+                dtype,
+                dev,
+            ),
 
             Some(PhiRopeScalingConfig::Scaled {
                 short_factor,
@@ -494,27 +732,29 @@ impl PhiRotaryEmbedding {
                 *long_mscale,
                 *short_mscale,
                 &cfg,
+                max_cached_positions,
                 dtype,
                 dev,
             ),
 
-            None => Self::new_unscaled(&cfg, dtype, dev),
+            None => Self::new_unscaled(&cfg, max_cached_positions, dtype, dev),
         }
     }
 
-    /// Returns (sin, cos) taking into account LongRope
-    fn get_long_or_short_sin_cos(&self, position_ids: &[usize]) -> (&Tensor, &Tensor) {
-        if self.long_cos.is_none() {
-            return (&self.short_sin, &self.short_cos);
-        }
+    /// Returns (sin, cos) covering at least `len` positions, taking into account LongRope
+    fn get_long_or_short_sin_cos(
+        &self,
+        position_ids: &[usize],
+        len: usize,
+    ) -> Result<(Tensor, Tensor)> {
+        let Some(long) = &self.long else {
+            return self.short.get(len);
+        };
         let seq_len = position_ids.iter().max().unwrap() + 1;
         if seq_len > self.original_max_position_embeddings {
-            (
-                self.long_sin.as_ref().unwrap(),
-                self.long_cos.as_ref().unwrap(),
-            )
+            long.get(len)
         } else {
-            (&self.short_sin, &self.short_cos)
+            self.short.get(len)
         }
     }
 
@@ -525,8 +765,9 @@ impl PhiRotaryEmbedding {
         seqlen_offsets: &[usize],
         position_ids: &[usize],
     ) -> Result<(Tensor, Tensor)> {
-        let (sin, cos) = self.get_long_or_short_sin_cos(position_ids);
         let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let len = seqlen_offsets.iter().max().copied().unwrap_or(0) + seq_len;
+        let (sin, cos) = self.get_long_or_short_sin_cos(position_ids, len)?;
 
         let rot_dim = cos.dim(D::Minus1)? * 2;
 
@@ -681,17 +922,13 @@ impl Llama3RotaryEmbedding {
                 let inv_freq_len = inv_freq.len();
                 let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
 
-                let t = Tensor::arange(0u32, cfg.max_position_embeddings as u32, dev)?
-                    .to_dtype(DType::F32)?
-                    .reshape((cfg.max_position_embeddings, 1))?;
-                let freqs = t.matmul(&inv_freq)?;
-                let sin = freqs.sin()?.to_dtype(dtype)?;
-                let cos = freqs.cos()?.to_dtype(dtype)?;
-                Ok(Self(RotaryEmbedding {
-                    sin,
-                    cos,
+                Ok(Self(RotaryEmbedding::from_inv_freq(
+                    inv_freq,
+                    cfg.max_position_embeddings,
                     is_gpt_neox,
-                }))
+                    dtype,
+                    lazy_rope_tables(),
+                )?))
             }
         }
     }
@@ -741,17 +978,13 @@ impl Llama3RotaryEmbedding {
                 let inv_freq_len = inv_freq.len();
                 let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
 
-                let t = Tensor::arange(0u32, cfg.max_position_embeddings as u32, dev)?
-                    .to_dtype(DType::F32)?
-                    .reshape((cfg.max_position_embeddings, 1))?;
-                let freqs = t.matmul(&inv_freq)?;
-                let sin = freqs.sin()?.to_dtype(dtype)?;
-                let cos = freqs.cos()?.to_dtype(dtype)?;
-                Ok(Self(RotaryEmbedding {
-                    sin,
-                    cos,
+                Ok(Self(RotaryEmbedding::from_inv_freq(
+                    inv_freq,
+                    cfg.max_position_embeddings,
                     is_gpt_neox,
-                }))
+                    dtype,
+                    lazy_rope_tables(),
+                )?))
             }
         }
     }
@@ -818,17 +1051,13 @@ impl Llama3RotaryEmbedding {
                 let inv_freq_len = inv_freq.len();
                 let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
 
-                let t = Tensor::arange(0u32, cfg.max_position_embeddings as u32, dev)?
-                    .to_dtype(DType::F32)?
-                    .reshape((cfg.max_position_embeddings, 1))?;
-                let freqs = t.matmul(&inv_freq)?;
-                let sin = freqs.sin()?.to_dtype(dtype)?;
-                let cos = freqs.cos()?.to_dtype(dtype)?;
-                Ok(Self(RotaryEmbedding {
-                    sin,
-                    cos,
+                Ok(Self(RotaryEmbedding::from_inv_freq(
+                    inv_freq,
+                    cfg.max_position_embeddings,
                     is_gpt_neox,
-                }))
+                    dtype,
+                    lazy_rope_tables(),
+                )?))
             }
             Some(MLlamaRopeScaling {
                 rope_type: other, ..
@@ -848,6 +1077,15 @@ impl Llama3RotaryEmbedding {
     ) -> Result<(Tensor, Tensor)> {
         self.0.forward(q, k, seqlen_offsets)
     }
+
+    /// See [`RotaryEmbedding::padded`].
+    pub fn padded(&self, head_dim: usize) -> Result<Self> {
+        Ok(Self(self.0.padded(head_dim)?))
+    }
+
+    pub fn is_gpt_neox(&self) -> bool {
+        self.0.is_gpt_neox
+    }
 }
 
 // https://github.com/huggingface/transformers/blob/f2c388e3f946862f657acc1e21b272ec946fc66c/src/transformers/models/qwen2_vl/modeling_qwen2_vl.py#L107
@@ -1345,19 +1583,20 @@ impl Phi4MMRotaryEmbedding {
         }
     }
 
-    /// Returns (sin, cos) taking into account LongRope
-    fn get_long_or_short_sin_cos(&self, position_ids: &[usize]) -> (&Tensor, &Tensor) {
-        if self.long_cos.is_none() {
-            return (&self.short_sin, &self.short_cos);
-        }
+    /// Returns (sin, cos) covering at least `len` positions, taking into account LongRope
+    fn get_long_or_short_sin_cos(
+        &self,
+        position_ids: &[usize],
+        len: usize,
+    ) -> Result<(Tensor, Tensor)> {
+        let Some(long) = &self.long else {
+            return self.short.get(len);
+        };
         let seq_len = position_ids.iter().max().unwrap() + 1;
         if seq_len > self.original_max_position_embeddings {
-            (
-                self.long_sin.as_ref().unwrap(),
-                self.long_cos.as_ref().unwrap(),
-            )
+            long.get(len)
         } else {
-            (&self.short_sin, &self.short_cos)
+            self.short.get(len)
         }
     }
 
@@ -1449,17 +1688,13 @@ impl Gemma3RotaryEmbedding {
         let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
         let inv_freq = (inv_freq / factor)?;
 
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        let sin = freqs.sin()?.to_dtype(dtype)?;
-        let cos = freqs.cos()?.to_dtype(dtype)?;
-        Ok(Self(RotaryEmbedding {
-            cos,
-            sin,
+        Ok(Self(RotaryEmbedding::from_inv_freq(
+            inv_freq,
+            max_seq_len,
             is_gpt_neox,
-        }))
+            dtype,
+            lazy_rope_tables(),
+        )?))
     }
 
     pub fn new(
@@ -1529,9 +1764,14 @@ impl QLinear {
         }
     }
 
+    /// The bias may be f32, f16 or bf16. Reduced precision biases are added in their own dtype.
     pub fn from_qparts(w: QTensor, b: Option<Tensor>) -> Self {
         if let Some(ref b) = b {
-            assert_eq!(b.dtype(), DType::F32);
+            assert!(
+                matches!(b.dtype(), DType::F32 | DType::F16 | DType::BF16),
+                "QLinear bias must be f32, f16 or bf16, got {:?}",
+                b.dtype()
+            );
         }
         Self {
             inner: QMatMul::QTensor(Arc::new(w)),
@@ -1560,6 +1800,17 @@ impl QLinear {
         matches!(self.inner, QMatMul::QTensor(_))
     }
 
+    /// Forward without looking at the bias. [`Module::forward`] uses this when there is none, so
+    /// the bias `Option` is checked once per call.
+    pub fn forward_no_bias(&self, xs: &Tensor) -> Result<Tensor> {
+        let out = if self.is_quant() {
+            self.inner.forward(&xs.to_dtype(DType::F32)?)?
+        } else {
+            self.inner.forward(xs)?
+        };
+        out.to_dtype(self.dtype)
+    }
+
     pub fn bias(&self) -> Option<&Tensor> {
         self.bias.as_ref()
     }
@@ -1571,27 +1822,258 @@ impl QLinear {
 
 impl Module for QLinear {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let Some(bias) = &self.bias else {
+            return self.forward_no_bias(xs);
+        };
         let xs = if self.is_quant() {
-            xs.to_dtype(DType::F32)?
+            Cow::Owned(xs.to_dtype(DType::F32)?)
         } else {
-            xs.clone()
+            Cow::Borrowed(xs)
         };
-        if let Some(bias) = &self.bias {
-            self.inner
-                .forward(&xs)?
+        let out = self.inner.forward(&xs)?;
+        if bias.dtype() == DType::F32 {
+            out.broadcast_add(bias)?.to_dtype(self.dtype)
+        } else {
+            out.to_dtype(bias.dtype())?
                 .broadcast_add(bias)?
                 .to_dtype(self.dtype)
+        }
+    }
+}
+
+/// An activation which is kept on the CPU until it is needed again on its original device.
+pub(crate) struct OffloadedActivation {
+    xs: Tensor,
+    device: Device,
+}
+
+impl OffloadedActivation {
+    /// Take `xs`, moving it to the CPU if `offload` is set. The device copy is freed once no other
+    /// handle to it remains.
+    pub(crate) fn new(xs: Tensor, offload: bool) -> Result<Self> {
+        let device = xs.device().clone();
+        let xs = if offload {
+            xs.to_device(&Device::Cpu)?
         } else {
-            self.inner.forward(&xs)?.to_dtype(self.dtype)
+            xs
+        };
+        Ok(Self { xs, device })
+    }
+
+    /// Bring the activation back to the device it was offloaded from.
+    pub(crate) fn restore(self) -> Result<Tensor> {
+        self.xs.to_device(&self.device)
+    }
+}
+
+thread_local! {
+    static SHARE_ROPE_TABLES: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether rotary tables built on this thread are shared with identically configured models.
+fn share_rope_tables() -> bool {
+    SHARE_ROPE_TABLES.with(Cell::get)
+}
+
+/// Run `f`, typically a model load, sharing the rotary tables it builds with identically configured
+/// models, such as the members of an ensemble, rather than building them per model. Shared tables are
+/// kept for the lifetime of the process.
+pub(crate) fn with_shared_rope_tables<T>(share: bool, f: impl FnOnce() -> T) -> T {
+    let prev = SHARE_ROPE_TABLES.replace(share);
+    let res = f();
+    SHARE_ROPE_TABLES.set(prev);
+    res
+}
+
+thread_local! {
+    static LAZY_ROPE_TABLES: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether rotary tables built on this thread are built on first use rather than at load.
+fn lazy_rope_tables() -> bool {
+    LAZY_ROPE_TABLES.with(Cell::get)
+}
+
+/// Run `f`, typically a model load, with the rotary tables it builds deferred to the first forward
+/// and only built up to the longest position seen so far.
+pub(crate) fn with_lazy_rope_tables<T>(lazy: bool, f: impl FnOnce() -> T) -> T {
+    let prev = LAZY_ROPE_TABLES.replace(lazy);
+    let res = f();
+    LAZY_ROPE_TABLES.set(prev);
+    res
+}
+
+/// Identifies rotary tables. The inverse frequencies capture the base, the rotated dimension and any
+/// scaling.
+#[derive(PartialEq, Eq, Hash)]
+struct RotaryTablesKey {
+    inv_freq: Vec<u32>,
+    max_position_embeddings: usize,
+    dtype: &'static str,
+    device: String,
+    lazy: bool,
+}
+
+static SHARED_ROTARY_TABLES: Lazy<Mutex<HashMap<RotaryTablesKey, RotaryTables>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone)]
+enum RotaryTables {
+    Eager {
+        cos: Tensor,
+        sin: Tensor,
+    },
+    Lazy {
+        inv_freq: Tensor,
+        max_position_embeddings: usize,
+        dtype: DType,
+        /// (cos, sin) for the positions built so far, shared between clones.
+        built: Arc<RwLock<Option<(Tensor, Tensor)>>>,
+    },
+}
+
+/// Compute (cos, sin) for positions `start..start + len`.
+fn build_rotary_tables(
+    inv_freq: &Tensor,
+    start: usize,
+    len: usize,
+    dtype: DType,
+) -> Result<(Tensor, Tensor)> {
+    let t = Tensor::arange(start as u32, (start + len) as u32, inv_freq.device())?
+        .to_dtype(DType::F32)?
+        .reshape((len, 1))?;
+    let freqs = t.matmul(inv_freq)?;
+    Ok((freqs.cos()?.to_dtype(dtype)?, freqs.sin()?.to_dtype(dtype)?))
+}
+
+impl RotaryTables {
+    /// Extend the tables to `half_dim` frequencies. The extra frequencies are zero, so their
+    /// rotation is the identity.
+    fn padded(&self, half_dim: usize) -> Result<Self> {
+        match self {
+            Self::Eager { cos, sin } => {
+                let (positions, cur) = cos.dims2()?;
+                let ones = Tensor::ones((positions, half_dim - cur), cos.dtype(), cos.device())?;
+                Ok(Self::Eager {
+                    cos: Tensor::cat(&[cos, &ones], 1)?,
+                    sin: sin.pad_with_zeros(1, 0, half_dim - cur)?,
+                })
+            }
+            Self::Lazy {
+                inv_freq,
+                max_position_embeddings,
+                dtype,
+                built: _,
+            } => Ok(Self::Lazy {
+                inv_freq: inv_freq.pad_with_zeros(1, 0, half_dim - inv_freq.dim(1)?)?,
+                max_position_embeddings: *max_position_embeddings,
+                dtype: *dtype,
+                built: Arc::new(RwLock::new(None)),
+            }),
+        }
+    }
+
+    /// Get (cos, sin) covering at least `len` positions, extending lazily built tables as required.
+    fn get(&self, len: usize) -> Result<(Tensor, Tensor)> {
+        match self {
+            Self::Eager { cos, sin } => Ok((cos.clone(), sin.clone())),
+            Self::Lazy {
+                inv_freq,
+                max_position_embeddings,
+                dtype,
+                built,
+            } => {
+                if let Some((cos, sin)) = &*built.read().unwrap() {
+                    if cos.dim(0)? >= len {
+                        return Ok((cos.clone(), sin.clone()));
+                    }
+                }
+                let mut built = built.write().unwrap();
+                let cur_len = match &*built {
+                    Some((cos, _)) => cos.dim(0)?,
+                    None => 0,
+                };
+                if cur_len >= len {
+                    let (cos, sin) = built.as_ref().unwrap();
+                    return Ok((cos.clone(), sin.clone()));
+                }
+                // Grow geometrically to amortize extension during decoding.
+                let new_len = (cur_len * 2).min(*max_position_embeddings).max(len);
+                let (cos_ext, sin_ext) =
+                    build_rotary_tables(inv_freq, cur_len, new_len - cur_len, *dtype)?;
+                let (cos, sin) = match built.take() {
+                    Some((cos, sin)) => (
+                        Tensor::cat(&[cos, cos_ext], 0)?,
+                        Tensor::cat(&[sin, sin_ext], 0)?,
+                    ),
+                    None => (cos_ext, sin_ext),
+                };
+                *built = Some((cos.clone(), sin.clone()));
+                Ok((cos, sin))
+            }
+        }
+    }
+}
+
+fn partial_ntk_inv_freq(
+    base: f32,
+    scaled_base: f32,
+    cutoff_dim: usize,
+    head_dim: usize,
+) -> Vec<f32> {
+    (0..head_dim)
+        .step_by(2)
+        .map(|i| {
+            let base = if i >= cutoff_dim { scaled_base } else { base };
+            1f32 / base.powf(i as f32 / head_dim as f32)
+        })
+        .collect()
+}
+
+/// The type of a `rope_scaling` config. Only dynamic NTK scaling is supported by [`RotaryEmbedding`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RopeScalingType {
+    #[serde(rename = "dynamic")]
+    Dynamic,
+    #[serde(other)]
+    Unsupported,
+}
+
+/// `rope_scaling` config, as in `{"type": "dynamic", "factor": 2.0}`. Newer configs name the type
+/// `rope_type`, and some set both keys.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RopeScalingConfig {
+    pub rope_type: Option<RopeScalingType>,
+    #[serde(rename = "type")]
+    pub scaling_type: Option<RopeScalingType>,
+    pub factor: Option<f32>,
+    /// Defaults to the model's `max_position_embeddings`.
+    pub original_max_position_embeddings: Option<usize>,
+}
+
+impl RopeScalingConfig {
+    /// The scaling factor, if this config selects dynamic NTK scaling and sets one.
+    pub fn dynamic_ntk_factor(&self) -> Option<f32> {
+        match self.rope_type.or(self.scaling_type) {
+            Some(RopeScalingType::Dynamic) => self.factor,
+            _ => None,
         }
     }
 }
 
+/// Dynamic NTK scaling, see [`RotaryEmbedding::new_dynamic_ntk`].
+#[derive(Debug, Clone)]
+struct DynamicNtk {
+    original_max_position_embeddings: usize,
+    /// Tables for sequences longer than `original_max_position_embeddings`.
+    long: RotaryTables,
+}
+
 #[derive(Debug, Clone)]
 pub struct RotaryEmbedding {
-    cos: Tensor,
-    sin: Tensor,
+    tables: RotaryTables,
     is_gpt_neox: bool,
+    dynamic_ntk: Option<DynamicNtk>,
 }
 
 impl RotaryEmbedding {
@@ -1609,18 +2091,13 @@ impl RotaryEmbedding {
             .collect();
         let inv_freq_len = inv_freq.len();
         let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), device)?;
-        let t = Tensor::arange(0u32, max_position_embeddings as u32, device)?
-            .to_dtype(DType::F32)?
-            .reshape((max_position_embeddings, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        let sin = freqs.sin()?.to_dtype(dtype)?;
-        let cos = freqs.cos()?.to_dtype(dtype)?;
-
-        Ok(Self {
-            cos,
-            sin,
+        Self::from_inv_freq(
+            inv_freq,
+            max_position_embeddings,
             is_gpt_neox,
-        })
+            dtype,
+            lazy_rope_tables(),
+        )
     }
 
     pub fn new_partial(
@@ -1637,17 +2114,156 @@ impl RotaryEmbedding {
             .collect();
         let inv_freq_len = inv_freq.len();
         let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), device)?;
-        let t = Tensor::arange(0u32, max_position_embeddings as u32, device)?
-            .to_dtype(DType::F32)?
-            .reshape((max_position_embeddings, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        let sin = freqs.sin()?.to_dtype(dtype)?;
-        let cos = freqs.cos()?.to_dtype(dtype)?;
+        Self::from_inv_freq(
+            inv_freq,
+            max_position_embeddings,
+            is_gpt_neox,
+            dtype,
+            lazy_rope_tables(),
+        )
+    }
+
+    /// Partial NTK scaling with a hard cutoff: the dimensions at or above `cutoff_dim` (the lower
+    /// frequencies) use `scaled_base` and the dimensions below it keep `base`. Unlike the llama3
+    /// scaling, there is no smoothing between the two bands.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_partial_ntk(
+        base: f32,
+        scaled_base: f32,
+        cutoff_dim: usize,
+        head_dim: usize,
+        max_position_embeddings: usize,
+        device: &Device,
+        is_gpt_neox: bool,
+        dtype: DType,
+    ) -> Result<Self> {
+        if cutoff_dim > head_dim {
+            candle_core::bail!(
+                "RoPE cutoff dimension {cutoff_dim} is larger than the head dimension {head_dim}."
+            );
+        }
+        let inv_freq = partial_ntk_inv_freq(base, scaled_base, cutoff_dim, head_dim);
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), device)?;
+        Self::from_inv_freq(
+            inv_freq,
+            max_position_embeddings,
+            is_gpt_neox,
+            dtype,
+            lazy_rope_tables(),
+        )
+    }
 
+    /// Dynamic NTK scaling: sequences up to `original_max_position_embeddings` use the same tables as
+    /// [`RotaryEmbedding::new`]. Longer sequences use the base scaled for `len = factor *
+    /// original_max_position_embeddings` positions, by `((factor * len / original_max_position_embeddings)
+    /// - (factor - 1)) ^ (dim / (dim - 2))`. As in vLLM, this fixed length lets both tables be built
+    /// here instead of for every sequence length.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_dynamic_ntk(
+        base: f32,
+        factor: f32,
+        original_max_position_embeddings: usize,
+        head_dim: usize,
+        max_position_embeddings: usize,
+        device: &Device,
+        is_gpt_neox: bool,
+        dtype: DType,
+    ) -> Result<Self> {
+        let mut rope = Self::new(
+            base,
+            head_dim,
+            max_position_embeddings.min(original_max_position_embeddings),
+            device,
+            is_gpt_neox,
+            dtype,
+        )?;
+        let scaled_len = (factor * original_max_position_embeddings as f32) as usize;
+        let dim = head_dim as f32;
+        let scale =
+            factor * scaled_len as f32 / original_max_position_embeddings as f32 - (factor - 1.);
+        let long = Self::new(
+            base * scale.powf(dim / (dim - 2.)),
+            head_dim,
+            max_position_embeddings.max(scaled_len),
+            device,
+            is_gpt_neox,
+            dtype,
+        )?;
+        rope.dynamic_ntk = Some(DynamicNtk {
+            original_max_position_embeddings,
+            long: long.tables,
+        });
+        Ok(rope)
+    }
+
+    /// `inv_freq` has shape `(1, rot_dim / 2)`. If `lazy`, no tables are built until the first forward.
+    fn from_inv_freq(
+        inv_freq: Tensor,
+        max_position_embeddings: usize,
+        is_gpt_neox: bool,
+        dtype: DType,
+        lazy: bool,
+    ) -> Result<Self> {
+        let key = if share_rope_tables() {
+            Some(RotaryTablesKey {
+                inv_freq: inv_freq
+                    .flatten_all()?
+                    .to_vec1::<f32>()?
+                    .into_iter()
+                    .map(f32::to_bits)
+                    .collect(),
+                max_position_embeddings,
+                dtype: dtype.as_str(),
+                device: format!("{:?}", inv_freq.device().location()),
+                lazy,
+            })
+        } else {
+            None
+        };
+        // Holding the lock while building ensures concurrent loads build the tables only once.
+        let mut shared = key.as_ref().map(|_| SHARED_ROTARY_TABLES.lock().unwrap());
+        if let (Some(shared), Some(key)) = (&shared, &key) {
+            if let Some(tables) = shared.get(key) {
+                return Ok(Self {
+                    tables: tables.clone(),
+                    is_gpt_neox,
+                    dynamic_ntk: None,
+                });
+            }
+        }
+        let tables = if lazy {
+            RotaryTables::Lazy {
+                inv_freq,
+                max_position_embeddings,
+                dtype,
+                built: Arc::new(RwLock::new(None)),
+            }
+        } else {
+            let (cos, sin) = build_rotary_tables(&inv_freq, 0, max_position_embeddings, dtype)?;
+            RotaryTables::Eager { cos, sin }
+        };
+        if let (Some(shared), Some(key)) = (&mut shared, key) {
+            shared.insert(key, tables.clone());
+        }
         Ok(Self {
-            cos,
-            sin,
+            tables,
             is_gpt_neox,
+            dynamic_ntk: None,
+        })
+    }
+
+    /// The same embedding for heads zero-padded to `head_dim`. With the GPT-NeoX layout each half of
+    /// a head is padded separately, otherwise the padding follows the real dims. Only the real dims
+    /// are rotated.
+    pub fn padded(&self, head_dim: usize) -> Result<Self> {
+        if self.dynamic_ntk.is_some() {
+            candle_core::bail!("Padding the head dim is not supported with dynamic NTK scaling.");
+        }
+        Ok(Self {
+            tables: self.tables.padded(head_dim / 2)?,
+            is_gpt_neox: self.is_gpt_neox,
+            dynamic_ntk: None,
         })
     }
 
@@ -1656,28 +2272,48 @@ impl RotaryEmbedding {
         q: &Tensor,
         k: &Tensor,
         seqlen_offsets: &[usize],
+    ) -> Result<(Tensor, Tensor)> {
+        let (q_embed, k_embed) = op_trace::run_op(|| self.forward_inner(q, k, seqlen_offsets))?;
+        op_trace::record("rope", &[q, k], &[&q_embed, &k_embed]);
+        Ok((q_embed, k_embed))
+    }
+
+    fn forward_inner(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        seqlen_offsets: &[usize],
     ) -> Result<(Tensor, Tensor)> {
         let (b_sz, qh, seq_len, n_embd) = q.dims4()?;
         let (_b_sz, kh, _seq_len, __n_embd) = k.dims4()?;
 
-        let rope = if self.is_gpt_neox {
-            candle_nn::rotary_emb::rope
-        } else {
-            candle_nn::rotary_emb::rope_i
+        let max_offset = seqlen_offsets.iter().copied().max().unwrap_or(0);
+        let len = max_offset + seq_len;
+        let (cos_table, sin_table) = match &self.dynamic_ntk {
+            Some(ntk) if len > ntk.original_max_position_embeddings => ntk.long.get(len)?,
+            _ => self.tables.get(len)?,
+        };
+
+        let differentiable = is_differentiable();
+        let rope = match (self.is_gpt_neox, differentiable) {
+            (true, false) => candle_nn::rotary_emb::rope,
+            (false, false) => candle_nn::rotary_emb::rope_i,
+            (true, true) => candle_nn::rotary_emb::rope_slow,
+            (false, true) => candle_nn::rotary_emb::rope_i_slow,
         };
 
-        if cfg!(feature = "cuda") && qh == kh {
+        if cfg!(feature = "cuda") && qh == kh && !differentiable {
             let (cos, sin) = if seqlen_offsets.len() == 1 {
                 (
-                    self.cos.narrow(0, seqlen_offsets[0], seq_len)?,
-                    self.sin.narrow(0, seqlen_offsets[0], seq_len)?,
+                    cos_table.narrow(0, seqlen_offsets[0], seq_len)?,
+                    sin_table.narrow(0, seqlen_offsets[0], seq_len)?,
                 )
             } else {
                 let mut cos_s = Vec::new();
                 let mut sin_s = Vec::new();
                 for offset in seqlen_offsets {
-                    cos_s.push(self.cos.narrow(0, *offset, seq_len)?);
-                    sin_s.push(self.sin.narrow(0, *offset, seq_len)?);
+                    cos_s.push(cos_table.narrow(0, *offset, seq_len)?);
+                    sin_s.push(sin_table.narrow(0, *offset, seq_len)?);
                 }
                 (Tensor::cat(&cos_s, 0)?, Tensor::cat(&sin_s, 0)?)
             };
@@ -1703,8 +2339,8 @@ impl RotaryEmbedding {
             }
             Ok((q, k))
         } else if seqlen_offsets.len() == 1 {
-            let cos = self.cos.narrow(0, seqlen_offsets[0], seq_len)?;
-            let sin = self.sin.narrow(0, seqlen_offsets[0], seq_len)?;
+            let cos = cos_table.narrow(0, seqlen_offsets[0], seq_len)?;
+            let sin = sin_table.narrow(0, seqlen_offsets[0], seq_len)?;
             let q_embed = rope(&q.contiguous()?, &cos, &sin)?;
             let k_embed = rope(&k.contiguous()?, &cos, &sin)?;
             Ok((q_embed, k_embed))
@@ -1712,8 +2348,8 @@ impl RotaryEmbedding {
             let mut q_embeds = Vec::new();
             let mut k_embeds = Vec::new();
             for (i, offset) in seqlen_offsets.iter().enumerate() {
-                let cos = self.cos.narrow(0, *offset, seq_len)?;
-                let sin = self.sin.narrow(0, *offset, seq_len)?;
+                let cos = cos_table.narrow(0, *offset, seq_len)?;
+                let sin = sin_table.narrow(0, *offset, seq_len)?;
                 let q_embed = rope(&q.i(i)?.unsqueeze(0)?.contiguous()?, &cos, &sin)?;
                 let k_embed = rope(&k.i(i)?.unsqueeze(0)?.contiguous()?, &cos, &sin)?;
                 q_embeds.push(q_embed);
@@ -1746,6 +2382,7 @@ pub enum Activation {
     #[serde(alias = "gelu_pytorch_tanh")]
     GeluPytorchTanh,
     QuickGelu,
+    Tanh,
 }
 
 impl Module for Activation {
@@ -1767,6 +2404,7 @@ impl Module for Activation {
             &Self::LeakyRelu(negative_slope) => candle_nn::ops::leaky_relu(xs, negative_slope),
             Self::GeluPytorchTanh => xs.gelu(),
             Self::QuickGelu => xs * candle_nn::ops::sigmoid(&(xs * 1.702f64)?),
+            Self::Tanh => xs.tanh(),
         }
     }
 }
@@ -1791,6 +2429,7 @@ impl TryInto<candle_nn::Activation> for Activation {
             Self::LeakyRelu(x) => Ok(candle_nn::Activation::LeakyRelu(x)),
             Self::GeluPytorchTanh => Ok(candle_nn::Activation::GeluPytorchTanh),
             Self::QuickGelu => candle_core::bail!("No mapping to candle_nn for QuickGelu"),
+            Self::Tanh => candle_core::bail!("No mapping to candle_nn for Tanh"),
         }
     }
 }
@@ -1814,9 +2453,13 @@ impl Default for Conv3dConfig {
     }
 }
 
+/// A 3D convolution without bias, computed as one 2D convolution per temporal plane of the kernel.
+/// The scalar `padding`, `stride` and `dilation` apply to the temporal axis as well as the spatial ones.
 pub struct Conv3dNoBias {
-    conv2d_1: Conv2d,
-    conv2d_2: Conv2d,
+    planes: Vec<Conv2d>,
+    padding: usize,
+    stride: usize,
+    dilation: usize,
 }
 
 impl Conv3dNoBias {
@@ -1841,29 +2484,68 @@ impl Conv3dNoBias {
         // Split on temporal dimension
         // https://github.com/pytorch/pytorch/issues/139066
 
-        let w1 = ws.i((.., .., 0, .., ..))?;
-        let w2 = ws.i((.., .., 1, .., ..))?;
-
-        let cfg = Conv2dConfig {
+        let conv2d_cfg = Conv2dConfig {
             padding: cfg.padding,
             stride: cfg.stride,
             dilation: cfg.dilation,
             groups: cfg.groups,
         };
+        let planes = (0..kernel_sizes[0])
+            .map(|t| {
+                let w = ws.i((.., .., t, .., ..))?.contiguous()?;
+                Ok(Conv2d::new(w, None, conv2d_cfg))
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         Ok(Self {
-            conv2d_1: Conv2d::new(w1.contiguous()?, None, cfg),
-            conv2d_2: Conv2d::new(w2.contiguous()?, None, cfg),
+            planes,
+            padding: cfg.padding,
+            stride: cfg.stride,
+            dilation: cfg.dilation,
         })
     }
 }
 
 impl Module for Conv3dNoBias {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
-        let xs1 = xs.i((.., .., 0, .., ..))?;
-        let xs2 = xs.i((.., .., 1, .., ..))?;
+        // Fast path: a two-plane kernel over exactly two frames gives a single output frame.
+        if let [conv2d_1, conv2d_2] = &self.planes[..] {
+            if self.padding == 0 && xs.dim(2)? == 2 {
+                let xs1 = xs.i((.., .., 0, .., ..))?;
+                let xs2 = xs.i((.., .., 1, .., ..))?;
 
-        (self.conv2d_1.forward(&xs1)? + self.conv2d_2.forward(&xs2)?)?.unsqueeze(2)
+                return (conv2d_1.forward(&xs1)? + conv2d_2.forward(&xs2)?)?.unsqueeze(2);
+            }
+        }
+
+        let xs = if self.padding > 0 {
+            xs.pad_with_zeros(2, self.padding, self.padding)?
+        } else {
+            xs.clone()
+        };
+        let t_in = xs.dim(2)?;
+        let span = self.dilation * (self.planes.len() - 1) + 1;
+        if t_in < span {
+            candle_core::bail!(
+                "Conv3dNoBias: {t_in} frames (after padding) is less than the temporal kernel span {span}"
+            );
+        }
+        let t_out = (t_in - span) / self.stride + 1;
+
+        let mut frames = Vec::with_capacity(t_out);
+        for t in 0..t_out {
+            let mut frame: Option<Tensor> = None;
+            for (k, conv2d) in self.planes.iter().enumerate() {
+                let plane = xs.i((.., .., t * self.stride + k * self.dilation, .., ..))?;
+                let out = conv2d.forward(&plane)?;
+                frame = Some(match frame {
+                    Some(frame) => (frame + out)?,
+                    None => out,
+                });
+            }
+            frames.push(frame.expect("temporal kernel size must be at least 1"));
+        }
+        Tensor::stack(&frames, 2)
     }
 }
 
@@ -2061,7 +2743,8 @@ impl MlpLayer for Mlp {
         let mut res = if matches!(
             self.act,
             Activation::Gelu | Activation::Silu | Activation::Relu
-        ) {
+        ) && !is_differentiable()
+        {
             MatMul.qmethod_matmul(
                 &candle_nn::ops::mul_and_act(&lhs, &rhs, self.act.try_into()?)?,
                 &*self.down,
@@ -2236,3 +2919,673 @@ impl Module for ScaledEmbedding {
         xs.apply(&self.embedding)? * self.scale
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{
+        partial_ntk_inv_freq, with_f32_norm_weights, with_lazy_rope_tables,
+        with_shared_rope_tables, Activation, F32RmsNorm, PhiRopeConfig, PhiRopeScalingConfig,
+        PhiRotaryEmbedding, RmsNorm, RopeScalingConfig, RotaryEmbedding, RotaryTables,
+        ScaledRopeType,
+    };
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> candle_core::Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    }
+
+    #[test]
+    fn lazy_rope_matches_eager() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (head_dim, max_pos, n_heads) = (16, 64, 2);
+        let inv_freq: Vec<_> = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1f32 / 10000f32.powf(i as f32 / head_dim as f32))
+            .collect();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, head_dim / 2), &dev)?;
+
+        for is_gpt_neox in [true, false] {
+            let eager = RotaryEmbedding::from_inv_freq(
+                inv_freq.clone(),
+                max_pos,
+                is_gpt_neox,
+                DType::F32,
+                false,
+            )?;
+            let lazy = RotaryEmbedding::from_inv_freq(
+                inv_freq.clone(),
+                max_pos,
+                is_gpt_neox,
+                DType::F32,
+                true,
+            )?;
+
+            // Prefill, then decode steps and a batched step which force the lazy tables to extend.
+            for (seq_len, offsets) in [
+                (5, vec![0]),
+                (1, vec![5]),
+                (1, vec![9]),
+                (3, vec![2, 20]),
+                (4, vec![max_pos - 4]),
+            ] {
+                let b_sz = offsets.len();
+                let q = Tensor::randn(0f32, 1., (b_sz, n_heads, seq_len, head_dim), &dev)?;
+                let k = Tensor::randn(0f32, 1., (b_sz, n_heads, seq_len, head_dim), &dev)?;
+                let (q_eager, k_eager) = eager.forward(&q, &k, &offsets)?;
+                let (q_lazy, k_lazy) = lazy.forward(&q, &k, &offsets)?;
+                assert!(max_abs_diff(&q_eager, &q_lazy)? < 1e-6);
+                assert!(max_abs_diff(&k_eager, &k_lazy)? < 1e-6);
+            }
+
+            let (cos_eager, sin_eager) = eager.tables.get(max_pos)?;
+            let (cos_lazy, sin_lazy) = lazy.tables.get(max_pos)?;
+            assert_eq!(cos_lazy.dims(), cos_eager.dims());
+            assert!(max_abs_diff(&cos_eager, &cos_lazy)? < 1e-6);
+            assert!(max_abs_diff(&sin_eager, &sin_lazy)? < 1e-6);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn partial_ntk_uses_hard_cutoff() -> candle_core::Result<()> {
+        // head_dim 8, cutoff 4: dims 0 and 2 keep base 10000, dims 4 and 6 use 40000.
+        let reference = [
+            1.,
+            1. / 10000f32.powf(0.25),
+            1. / 40000f32.powf(0.5),
+            1. / 40000f32.powf(0.75),
+        ];
+        let inv_freq = partial_ntk_inv_freq(10000., 40000., 4, 8);
+        assert_eq!(inv_freq.len(), reference.len());
+        for (got, expected) in inv_freq.iter().zip(reference) {
+            assert!(
+                (got - expected).abs() <= expected * 1e-6,
+                "{got} != {expected}"
+            );
+        }
+        assert!((inv_freq[2] - 0.005).abs() < 1e-8);
+
+        // The cutoff at either end recovers unscaled and fully scaled RoPE.
+        let unscaled: Vec<_> = (0..8)
+            .step_by(2)
+            .map(|i| 1f32 / 10000f32.powf(i as f32 / 8.))
+            .collect();
+        assert_eq!(partial_ntk_inv_freq(10000., 40000., 8, 8), unscaled);
+        assert_eq!(
+            partial_ntk_inv_freq(40000., 40000., 0, 8),
+            partial_ntk_inv_freq(10000., 40000., 0, 8)
+        );
+
+        let dev = Device::Cpu;
+        let rope =
+            RotaryEmbedding::new_partial_ntk(10000., 40000., 4, 8, 16, &dev, true, DType::F32)?;
+        let (cos, _sin) = rope.tables.get(16)?;
+        let cos_pos_1 = cos.get(1)?.to_vec1::<f32>()?;
+        for (got, freq) in cos_pos_1.iter().zip(reference) {
+            assert!((got - freq.cos()).abs() < 1e-6);
+        }
+        assert!(RotaryEmbedding::new_partial_ntk(
+            10000.,
+            40000.,
+            10,
+            8,
+            16,
+            &dev,
+            true,
+            DType::F32
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dynamic_ntk_only_rescales_long_sequences() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (head_dim, original_max_pos, n_heads) = (16, 32, 2);
+        let fixed = RotaryEmbedding::new(10000., head_dim, 128, &dev, true, DType::F32)?;
+        let dynamic = RotaryEmbedding::new_dynamic_ntk(
+            10000.,
+            2.,
+            original_max_pos,
+            head_dim,
+            128,
+            &dev,
+            true,
+            DType::F32,
+        )?;
+
+        // Up to the original length, the output is bit-identical to a fixed base.
+        for (seq_len, offsets) in [(5, vec![0]), (1, vec![31]), (3, vec![4, 29])] {
+            let b_sz = offsets.len();
+            let q = Tensor::randn(0f32, 1., (b_sz, n_heads, seq_len, head_dim), &dev)?;
+            let k = Tensor::randn(0f32, 1., (b_sz, n_heads, seq_len, head_dim), &dev)?;
+            let (q_fixed, k_fixed) = fixed.forward(&q, &k, &offsets)?;
+            let (q_dynamic, k_dynamic) = dynamic.forward(&q, &k, &offsets)?;
+            assert_eq!(max_abs_diff(&q_fixed, &q_dynamic)?, 0.);
+            assert_eq!(max_abs_diff(&k_fixed, &k_dynamic)?, 0.);
+        }
+
+        // Beyond it, the base is rescaled for factor * 32 = 64 positions, by (2 * 64 / 32 - 1) ^ (16 / 14),
+        // whatever the current length.
+        let scaled_base = 10000. * 3f32.powf(16. / 14.);
+        let scaled = RotaryEmbedding::new(scaled_base, head_dim, 128, &dev, true, DType::F32)?;
+        let q = Tensor::randn(0f32, 1., (1, n_heads, 1, head_dim), &dev)?;
+        let k = Tensor::randn(0f32, 1., (1, n_heads, 1, head_dim), &dev)?;
+        let (q_dynamic, _) = dynamic.forward(&q, &k, &[63])?;
+        let (q_scaled, _) = scaled.forward(&q, &k, &[63])?;
+        let (q_fixed, _) = fixed.forward(&q, &k, &[63])?;
+        assert!(max_abs_diff(&q_dynamic, &q_scaled)? < 1e-4);
+        assert!(max_abs_diff(&q_dynamic, &q_fixed)? > 1e-2);
+        let (q_dynamic, _) = dynamic.forward(&q, &k, &[99])?;
+        let (q_scaled, _) = scaled.forward(&q, &k, &[99])?;
+        assert!(max_abs_diff(&q_dynamic, &q_scaled)? < 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn rope_scaling_config_accepts_either_type_key() -> serde_json::Result<()> {
+        let both: RopeScalingConfig =
+            serde_json::from_str(r#"{"type": "dynamic", "rope_type": "dynamic", "factor": 2.0}"#)?;
+        assert_eq!(both.dynamic_ntk_factor(), Some(2.));
+        let legacy: RopeScalingConfig =
+            serde_json::from_str(r#"{"type": "dynamic", "factor": 4.0}"#)?;
+        assert_eq!(legacy.dynamic_ntk_factor(), Some(4.));
+        // Other scaling types may omit the factor.
+        let other: RopeScalingConfig = serde_json::from_str(r#"{"rope_type": "default"}"#)?;
+        assert_eq!(other.dynamic_ntk_factor(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn forward_residual_matches_add_then_norm() -> candle_core::Result<()> {
+        use candle_core::Module;
+
+        let dev = Device::Cpu;
+        let norm = RmsNorm::from_w(Tensor::randn(0f32, 1., 16, &dev)?, 1e-5)?;
+        let x = Tensor::randn(0f32, 1., (2, 3, 16), &dev)?;
+        let residual = Tensor::randn(0f32, 1., (2, 3, 16), &dev)?;
+
+        let (sum, normed) = norm.forward_residual(&x, &residual)?;
+        let expected_sum = (&x + &residual)?;
+        assert_eq!(max_abs_diff(&sum, &expected_sum)?, 0.);
+        assert_eq!(max_abs_diff(&normed, &norm.forward(&expected_sum)?)?, 0.);
+        Ok(())
+    }
+
+    /// Decode-step time of the residual add and post-attention norm, fused and as two ops. Run with
+    /// `cargo test --release -p mistralrs-core --features cuda forward_residual_decode_speed -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn forward_residual_decode_speed() -> candle_core::Result<()> {
+        use candle_core::Module;
+        use std::time::Instant;
+
+        const RUNS: u32 = 1000;
+        #[cfg(feature = "cuda")]
+        let dev = Device::new_cuda(0)?;
+        #[cfg(all(feature = "metal", not(feature = "cuda")))]
+        let dev = Device::new_metal(0)?;
+        #[cfg(not(any(feature = "cuda", feature = "metal")))]
+        let dev = Device::Cpu;
+
+        let hidden = 4096;
+        let norm = RmsNorm::from_w(
+            Tensor::randn(0f32, 1., hidden, &dev)?.to_dtype(DType::BF16)?,
+            1e-5,
+        )?;
+        let x = Tensor::randn(0f32, 1., (1, 1, hidden), &dev)?.to_dtype(DType::BF16)?;
+        let residual = Tensor::randn(0f32, 1., (1, 1, hidden), &dev)?.to_dtype(DType::BF16)?;
+
+        let time = |f: &dyn Fn() -> candle_core::Result<(Tensor, Tensor)>| {
+            f()?;
+            dev.synchronize()?;
+            let start = Instant::now();
+            for _ in 0..RUNS {
+                f()?;
+            }
+            dev.synchronize()?;
+            Ok::<_, candle_core::Error>(start.elapsed().as_secs_f64() * 1e6 / f64::from(RUNS))
+        };
+        let fused = time(&|| norm.forward_residual(&x, &residual))?;
+        let unfused = time(&|| {
+            let sum = (&x + &residual)?;
+            let normed = norm.forward(&sum)?;
+            Ok((sum, normed))
+        })?;
+        println!("{dev:?}: forward_residual {fused:.2}us, add then norm {unfused:.2}us per call");
+        Ok(())
+    }
+
+    #[test]
+    fn partial_phi_rope_rotates_leading_channels() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (head_dim, n_heads, seq_len) = (20, 2, 5);
+        let cfg = |head_dim, partial_rotary_factor| PhiRopeConfig {
+            rope_scaling: None,
+            max_position_embeddings: 32,
+            original_max_position_embeddings: 32,
+            rope_theta: 10000.,
+            head_dim,
+            partial_rotary_factor,
+        };
+        let partial = PhiRotaryEmbedding::new(DType::F32, cfg(head_dim, Some(0.4)), &dev)?;
+        // The rotated slice sees the same frequencies as a full RoPE over `rotary_dim` channels.
+        let rotary_dim = 8;
+        let reference = PhiRotaryEmbedding::new(DType::F32, cfg(rotary_dim, None), &dev)?;
+
+        let offsets = [3];
+        let position_ids = [3 + seq_len - 1];
+        let q = Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?;
+        let k = Tensor::randn(0f32, 1., (1, n_heads, seq_len, head_dim), &dev)?;
+        let (q_out, k_out) = partial.forward(&q, &k, &offsets, &position_ids)?;
+        let (q_ref, k_ref) = reference.forward(
+            &q.narrow(3, 0, rotary_dim)?.contiguous()?,
+            &k.narrow(3, 0, rotary_dim)?.contiguous()?,
+            &offsets,
+            &position_ids,
+        )?;
+
+        assert!(max_abs_diff(&q_out.narrow(3, 0, rotary_dim)?, &q_ref)? < 1e-6);
+        assert!(max_abs_diff(&k_out.narrow(3, 0, rotary_dim)?, &k_ref)? < 1e-6);
+        let pass_dim = head_dim - rotary_dim;
+        assert_eq!(
+            max_abs_diff(
+                &q_out.narrow(3, rotary_dim, pass_dim)?,
+                &q.narrow(3, rotary_dim, pass_dim)?
+            )?,
+            0.
+        );
+        assert_eq!(
+            max_abs_diff(
+                &k_out.narrow(3, rotary_dim, pass_dim)?,
+                &k.narrow(3, rotary_dim, pass_dim)?
+            )?,
+            0.
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn capped_phi_rope_matches_uncapped() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (head_dim, n_heads) = (8, 2);
+        let cfg = || PhiRopeConfig {
+            rope_scaling: Some(PhiRopeScalingConfig::Classic {
+                short_factor: vec![1.0, 1.5, 2.0, 2.5],
+                long_factor: vec![3.0, 4.0, 5.0, 6.0],
+                scaling_type: ScaledRopeType::Su,
+            }),
+            max_position_embeddings: 64,
+            original_max_position_embeddings: 16,
+            rope_theta: 10000.,
+            head_dim,
+            partial_rotary_factor: None,
+        };
+        let full = PhiRotaryEmbedding::new(DType::F32, cfg(), &dev)?;
+        let capped =
+            PhiRotaryEmbedding::new_with_max_cached_positions(DType::F32, cfg(), Some(4), &dev)?;
+        assert_eq!(capped.short.get(0)?.0.dim(0)?, 4);
+
+        // Extend the short table, then cross over to the long one at the original length.
+        for (seq_len, offsets) in [
+            (3, vec![0]),
+            (1, vec![3]),
+            (1, vec![14]),
+            (1, vec![15]),
+            (2, vec![5, 30]),
+            (4, vec![60]),
+        ] {
+            let b_sz = offsets.len();
+            let position_ids: Vec<_> = offsets.iter().map(|o| o + seq_len - 1).collect();
+            let q = Tensor::randn(0f32, 1., (b_sz, n_heads, seq_len, head_dim), &dev)?;
+            let k = Tensor::randn(0f32, 1., (b_sz, n_heads, seq_len, head_dim), &dev)?;
+            let (q_full, k_full) = full.forward(&q, &k, &offsets, &position_ids)?;
+            let (q_capped, k_capped) = capped.forward(&q, &k, &offsets, &position_ids)?;
+            assert!(max_abs_diff(&q_full, &q_capped)? < 1e-6);
+            assert!(max_abs_diff(&k_full, &k_capped)? < 1e-6);
+        }
+        assert_eq!(capped.long.as_ref().unwrap().get(0)?.0.dim(0)?, 64);
+        assert!(capped.short.get(0)?.0.dim(0)? < 64);
+        Ok(())
+    }
+
+    #[test]
+    fn norm_weight_found_under_alias() -> candle_core::Result<()> {
+        use std::collections::HashMap;
+
+        use candle_core::Module;
+        use mistralrs_quant::ShardedSafeTensors;
+
+        let dev = Device::Cpu;
+        let w = Tensor::new(&[1f32, 2., 3., 4.], &dev)?;
+        let vb = |name: &str| {
+            ShardedSafeTensors::wrap(
+                Box::new(HashMap::from([(name.to_string(), w.clone())])),
+                DType::F32,
+                dev.clone(),
+            )
+        };
+        let x = Tensor::randn(0f32, 1., (2, 4), &dev)?;
+        let expected = RmsNorm::from_w(w.clone(), 1e-6)?.forward(&x)?;
+
+        for name in ["scale", "gamma"] {
+            let norm = RmsNorm::new(4, 1e-6, vb(name))?;
+            assert_eq!(max_abs_diff(&norm.forward(&x)?, &expected)?, 0.);
+            let f32_norm = F32RmsNorm::new(4, 1e-6, vb(name))?;
+            assert_eq!(f32_norm.weight().to_vec1::<f32>()?, vec![1., 2., 3., 4.]);
+        }
+        let named = RmsNorm::new_named(4, 1e-6, vb("ln_w"), "ln_w")?;
+        assert_eq!(max_abs_diff(&named.forward(&x)?, &expected)?, 0.);
+
+        // A missing weight is reported under the default name.
+        let err = RmsNorm::new(4, 1e-6, vb("bias")).unwrap_err().to_string();
+        assert!(err.contains("weight"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn f32_accumulation_matches_f32_rms_norm() -> candle_core::Result<()> {
+        use std::collections::HashMap;
+
+        use candle_core::Module;
+        use mistralrs_quant::ShardedSafeTensors;
+
+        let dev = Device::Cpu;
+        let size = 64;
+        let w = Tensor::rand(0.5f32, 1.5, size, &dev)?;
+        let vb = ShardedSafeTensors::wrap(
+            Box::new(HashMap::from([("weight".to_string(), w)])),
+            DType::BF16,
+            dev.clone(),
+        );
+        let x = Tensor::randn(0f32, 4., (3, size), &dev)?.to_dtype(DType::BF16)?;
+
+        let upcast = RmsNorm::new_with_opts(size, 1e-6, vb.clone(), true)?;
+        let reference = F32RmsNorm::new(size, 1e-6, vb.clone())?;
+        let out = upcast.forward(&x)?;
+        assert_eq!(out.dtype(), DType::BF16);
+        let diff = max_abs_diff(
+            &out.to_dtype(DType::F32)?,
+            &reference.forward(&x)?.to_dtype(DType::F32)?,
+        )?;
+        assert!(diff < 1e-2, "{diff}");
+
+        // Off by default, the norm runs at the input dtype.
+        assert!(!RmsNorm::new(size, 1e-6, vb)?.force_f32_accumulation);
+        Ok(())
+    }
+
+    #[test]
+    fn f32_norm_weights_option_loads_f32_weights() -> candle_core::Result<()> {
+        use std::collections::HashMap;
+
+        use mistralrs_quant::ShardedSafeTensors;
+
+        let dev = Device::Cpu;
+        let vb = ShardedSafeTensors::wrap(
+            Box::new(HashMap::from([(
+                "weight".to_string(),
+                Tensor::rand(0.5f32, 1.5, 8, &dev)?,
+            )])),
+            DType::BF16,
+            dev.clone(),
+        );
+
+        let norm = with_f32_norm_weights(true, || RmsNorm::new(8, 1e-6, vb.clone()))?;
+        assert_eq!(norm.weight.dtype(), DType::F32);
+        let gemma = with_f32_norm_weights(true, || RmsNorm::new_gemma(8, 1e-6, vb.clone()))?;
+        assert_eq!(gemma.weight.dtype(), DType::F32);
+        // Outside the option, the weights are loaded in the model dtype.
+        assert_eq!(RmsNorm::new(8, 1e-6, vb)?.weight.dtype(), DType::BF16);
+        Ok(())
+    }
+
+    #[test]
+    fn tanh_activation_round_trips() -> candle_core::Result<()> {
+        use candle_core::Module;
+
+        let act: Activation = serde_json::from_str("\"tanh\"").unwrap();
+        assert_eq!(act, Activation::Tanh);
+        assert_eq!(serde_json::to_string(&act).unwrap(), "\"tanh\"");
+
+        let xs = Tensor::new(&[0f32, 1., -2.], &Device::Cpu)?;
+        let out = act.forward(&xs)?.to_vec1::<f32>()?;
+        for (out, expected) in out.iter().zip([0f32, 0.761_594_2, -0.964_027_6]) {
+            assert!((out - expected).abs() < 1e-6, "{out} != {expected}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lazy_rope_option_defers_tables() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let load = || RotaryEmbedding::new(10000., 16, 48, &dev, true, DType::F32);
+        let lazy = with_lazy_rope_tables(true, load)?;
+        let RotaryTables::Lazy { built, .. } = &lazy.tables else {
+            panic!("expected lazily built tables");
+        };
+        assert!(built.read().unwrap().is_none());
+
+        // Outside the option, the tables are built at load.
+        assert!(matches!(load()?.tables, RotaryTables::Eager { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn identical_rope_configs_share_tables() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let load = |base: f32| RotaryEmbedding::new(base, 16, 48, &dev, true, DType::F32);
+        let (first, second, other) =
+            with_shared_rope_tables(true, || -> candle_core::Result<_> {
+                Ok((load(12345.)?, load(12345.)?, load(54321.)?))
+            })?;
+
+        let (cos_first, sin_first) = first.tables.get(48)?;
+        let (cos_second, sin_second) = second.tables.get(48)?;
+        let (cos_other, _) = other.tables.get(48)?;
+        assert_eq!(cos_first.id(), cos_second.id());
+        assert_eq!(sin_first.id(), sin_second.id());
+        assert_ne!(cos_first.id(), cos_other.id());
+
+        // Without sharing, each model builds its own tables.
+        let unshared = load(12345.)?;
+        assert_ne!(unshared.tables.get(48)?.0.id(), cos_first.id());
+        Ok(())
+    }
+
+    #[test]
+    fn qlinear_accepts_f16_bias() -> candle_core::Result<()> {
+        use candle_core::{
+            quantized::{GgmlDType, QTensor},
+            Module,
+        };
+
+        use super::QLinear;
+
+        let dev = Device::Cpu;
+        let w = Tensor::randn(0f32, 1f32, (4, 32), &dev)?;
+        let b = Tensor::new(&[0.5f32, -1.25, 2.0, 0.125], &dev)?;
+        let xs = Tensor::randn(0f32, 1f32, (2, 32), &dev)?;
+
+        let f32_bias =
+            QLinear::from_qparts(QTensor::quantize(&w, GgmlDType::Q8_0)?, Some(b.clone()));
+        let f16_bias = QLinear::from_qparts(
+            QTensor::quantize(&w, GgmlDType::Q8_0)?,
+            Some(b.to_dtype(DType::F16)?),
+        );
+        let expected = f32_bias.forward(&xs)?;
+        let out = f16_bias.forward(&xs)?;
+        assert_eq!(out.dtype(), DType::F32);
+        // Only the f16 rounding of the sum separates the two.
+        assert!(max_abs_diff(&out, &expected)? < 5e-2);
+        Ok(())
+    }
+
+    /// Per-call overhead of biasless `QLinear`s in a 128-expert MoE layer at decode, against the
+    /// previous forward which cloned the input and checked the bias on every call. Run with
+    /// `cargo test --release -p mistralrs-core qlinear_moe_forward_overhead -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn qlinear_moe_forward_overhead() -> candle_core::Result<()> {
+        use std::time::Instant;
+
+        use candle_core::Module;
+
+        use super::QLinear;
+
+        const RUNS: u32 = 200;
+        const N_EXPERTS: usize = 128;
+        const TOP_K: usize = 8;
+        let dev = Device::Cpu;
+        let (hidden, intermediate) = (64, 128);
+        let linear = |out_dim, in_dim| -> candle_core::Result<QLinear> {
+            Ok(QLinear::from_parts(
+                Tensor::randn(0f32, 1., (out_dim, in_dim), &dev)?,
+                None,
+            ))
+        };
+        let experts = (0..N_EXPERTS)
+            .map(|_| {
+                Ok((
+                    linear(intermediate, hidden)?,
+                    linear(intermediate, hidden)?,
+                    linear(hidden, intermediate)?,
+                ))
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let xs = Tensor::randn(0f32, 1., (1, hidden), &dev)?;
+
+        let previous = |linear: &QLinear, xs: &Tensor| -> candle_core::Result<Tensor> {
+            let xs = xs.clone();
+            let out = linear.inner_ref().forward(&xs)?;
+            match linear.bias() {
+                Some(bias) => out.broadcast_add(bias)?.to_dtype(linear.dtype),
+                None => out.to_dtype(linear.dtype),
+            }
+        };
+        let forward = |linear: &QLinear, xs: &Tensor| linear.forward(xs);
+        for (name, run) in [
+            (
+                "forward",
+                &forward as &dyn Fn(&QLinear, &Tensor) -> candle_core::Result<Tensor>,
+            ),
+            ("clone and bias check", &previous),
+        ] {
+            let start = Instant::now();
+            for step in 0..RUNS as usize {
+                for (gate, up, down) in experts.iter().cycle().skip(step).take(TOP_K) {
+                    let act = (run(gate, &xs)?.silu()? * run(up, &xs)?)?;
+                    run(down, &act)?;
+                }
+            }
+            println!(
+                "{name}: {:.1}us per MoE layer",
+                start.elapsed().as_secs_f64() * 1e6 / f64::from(RUNS),
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn conv3d_matches_reference_for_temporal_kernels() -> candle_core::Result<()> {
+        use std::collections::HashMap;
+
+        use candle_core::Module;
+        use mistralrs_quant::ShardedSafeTensors;
+
+        use super::{Conv3dConfig, Conv3dNoBias};
+
+        let dev = Device::Cpu;
+        let (c_in, c_out, t_in, size, k) = (2, 3, 5, 4, 2);
+        let xs = Tensor::randn(0f32, 1., (1, c_in, t_in, size, size), &dev)?;
+        let x = xs.flatten_all()?.to_vec1::<f32>()?;
+        for kt in [2, 3, 4] {
+            let w = Tensor::randn(0f32, 1., (c_out, c_in, kt, k, k), &dev)?;
+            let wv = w.flatten_all()?.to_vec1::<f32>()?;
+            let vb = ShardedSafeTensors::wrap(
+                Box::new(HashMap::from([("weight".to_string(), w)])),
+                DType::F32,
+                dev.clone(),
+            );
+            let conv = Conv3dNoBias::new(c_in, c_out, [kt, k, k], Conv3dConfig::default(), vb)?;
+            let out = conv.forward(&xs)?;
+
+            // Direct Conv3d with stride 1 and no padding.
+            let (t_out, s_out) = (t_in - kt + 1, size - k + 1);
+            let mut expected = vec![0f32; c_out * t_out * s_out * s_out];
+            for o in 0..c_out {
+                for t in 0..t_out {
+                    for y in 0..s_out {
+                        for z in 0..s_out {
+                            let mut acc = 0.;
+                            for c in 0..c_in {
+                                for dt in 0..kt {
+                                    for dy in 0..k {
+                                        for dz in 0..k {
+                                            let xi = (((c * t_in) + t + dt) * size + y + dy) * size
+                                                + z
+                                                + dz;
+                                            let wi = (((o * c_in + c) * kt + dt) * k + dy) * k + dz;
+                                            acc += x[xi] * wv[wi];
+                                        }
+                                    }
+                                }
+                            }
+                            expected[((o * t_out + t) * s_out + y) * s_out + z] = acc;
+                        }
+                    }
+                }
+            }
+            let expected = Tensor::from_vec(expected, (1, c_out, t_out, s_out, s_out), &dev)?;
+            assert_eq!(out.dims(), expected.dims());
+            assert!(max_abs_diff(&out, &expected)? < 1e-4, "kt = {kt}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn f32_norm_weight_is_more_accurate_than_f16() -> candle_core::Result<()> {
+        use std::collections::HashMap;
+
+        use candle_core::Module;
+        use mistralrs_quant::ShardedSafeTensors;
+
+        let dev = Device::Cpu;
+        let size = 256;
+        // Weights just above 1 lose their fractional part in f16.
+        let w = (Tensor::rand(0f32, 1., size, &dev)? * 1e-3)?.affine(1., 1.)?;
+        let vb = ShardedSafeTensors::wrap(
+            Box::new(HashMap::from([("weight".to_string(), w.clone())])),
+            DType::F16,
+            dev.clone(),
+        );
+        let f32_norm = RmsNorm::new_f32(size, 1e-6, vb.clone())?;
+        let f16_norm = RmsNorm::new(size, 1e-6, vb)?;
+        assert_eq!(f32_norm.weight().dtype(), DType::F32);
+        assert_eq!(f16_norm.weight().dtype(), DType::F16);
+
+        // A stress input with a wide dynamic range.
+        let x = (Tensor::randn(0f32, 1., (4, size), &dev)?
+            * Tensor::arange(0f32, size as f32, &dev)?
+                .affine(0.05, -4.)?
+                .exp()?)?
+        .to_dtype(DType::F16)?;
+        let reference = RmsNorm::from_w(w, 1e-6)?.forward(&x.to_dtype(DType::F32)?)?;
+        let mean_error = |norm: &RmsNorm| -> candle_core::Result<f32> {
+            let out = norm.forward(&x)?;
+            assert_eq!(out.dtype(), DType::F16);
+            (out.to_dtype(DType::F32)? - &reference)?
+                .abs()?
+                .mean_all()?
+                .to_scalar::<f32>()
+        };
+
+        let (f32_error, f16_error) = (mean_error(&f32_norm)?, mean_error(&f16_norm)?);
+        assert!(
+            f32_error < f16_error,
+            "f32 weight error {f32_error} >= f16 weight error {f16_error}"
+        );
+        Ok(())
+    }
+}
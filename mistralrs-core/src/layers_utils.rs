@@ -1,4 +1,6 @@
-use candle_core::{Result, Tensor};
+use std::cell::Cell;
+
+use candle_core::{Result, Tensor, D};
 
 pub fn repeat_kv(x: Tensor, n_rep: usize) -> Result<Tensor> {
     if n_rep == 1 {
@@ -8,3 +10,82 @@ pub fn repeat_kv(x: Tensor, n_rep: usize) -> Result<Tensor> {
         Tensor::cat(&vec![&x; n_rep], 2)?.reshape((b_sz, n_kv_head * n_rep, seq_len, head_dim))
     }
 }
+
+thread_local! {
+    static DIFFERENTIABLE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether forward passes on this thread must be differentiable. Layers then use composed tensor ops
+/// instead of fused or in-place kernels, which have no backward pass.
+pub(crate) fn is_differentiable() -> bool {
+    DIFFERENTIABLE.with(Cell::get)
+}
+
+/// Run `f` with differentiable forward passes on this thread, see [`is_differentiable`].
+pub(crate) fn with_differentiable<T>(f: impl FnOnce() -> T) -> T {
+    let prev = DIFFERENTIABLE.replace(true);
+    let res = f();
+    DIFFERENTIABLE.set(prev);
+    res
+}
+
+/// Sum over the last dim, keeping it, by adding the two halves until one element is left. Odd lengths
+/// are padded with a zero. Each step is an elementwise add, which rounds the same on every backend, so
+/// unlike `sum_keepdim` the result does not depend on the device's reduction order. Used under strict
+/// determinism.
+pub(crate) fn fixed_order_sum_keepdim(xs: &Tensor) -> Result<Tensor> {
+    let mut xs = xs.clone();
+    let mut len = xs.dim(D::Minus1)?;
+    if len == 0 {
+        return xs.sum_keepdim(D::Minus1);
+    }
+    while len > 1 {
+        if len % 2 == 1 {
+            xs = xs.pad_with_zeros(D::Minus1, 0, 1)?;
+            len += 1;
+        }
+        len /= 2;
+        xs = (xs.narrow(D::Minus1, 0, len)? + xs.narrow(D::Minus1, len, len)?)?;
+    }
+    Ok(xs)
+}
+
+/// Softmax over the last dim, with the normalizing sum taken by [`fixed_order_sum_keepdim`].
+pub(crate) fn fixed_order_softmax_last_dim(xs: &Tensor) -> Result<Tensor> {
+    let exp = xs.broadcast_sub(&xs.max_keepdim(D::Minus1)?)?.exp()?;
+    exp.broadcast_div(&fixed_order_sum_keepdim(&exp)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor, D};
+
+    use super::{fixed_order_softmax_last_dim, fixed_order_sum_keepdim};
+
+    #[test]
+    fn fixed_order_sum_adds_halves() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        // Summed left to right in f32 the first row is 1, the halves give (1e8 - 1e8) + (1 + 1).
+        let xs = Tensor::new(&[[1e8f32, 1., -1e8, 1.], [1., 2., 3., 4.]], &dev)?;
+        let sum = fixed_order_sum_keepdim(&xs)?;
+        assert_eq!(sum.dims(), [2, 1]);
+        assert_eq!(sum.flatten_all()?.to_vec1::<f32>()?, [2., 10.]);
+
+        // An odd length is padded with a zero: (1 + 3) + (2 + 0).
+        let odd = Tensor::new(&[1f32, 2., 3.], &dev)?;
+        assert_eq!(fixed_order_sum_keepdim(&odd)?.to_vec1::<f32>()?, [6.]);
+        Ok(())
+    }
+
+    #[test]
+    fn fixed_order_softmax_matches_softmax() -> candle_core::Result<()> {
+        let xs = Tensor::randn(0f32, 4., (3, 37), &Device::Cpu)?;
+        let diff = (fixed_order_softmax_last_dim(&xs)? - candle_nn::ops::softmax(&xs, D::Minus1)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-6, "{diff}");
+        Ok(())
+    }
+}
@@ -8,8 +8,10 @@ use hf_hub::Cache;
 pub use lora::Ordering;
 pub use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
+use prefix_cacher::PrefixCacheManagerV2;
 #[cfg(feature = "pyo3_macros")]
 use pyo3::exceptions::PyValueError;
+use rand::RngCore;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::sync::OnceLock;
@@ -33,9 +35,11 @@ use tracing::warn;
 mod cuda;
 mod device_map;
 mod engine;
+mod expert_counts;
 mod lora;
 mod model_loader;
 mod ops;
+mod qkv_capture;
 pub use model_loader::{
     get_auto_device_map_params, get_model_dtype, get_tgt_non_granular_index, LoaderBuilder,
 };
@@ -76,39 +80,53 @@ mod vision_models;
 mod xlora_models;
 
 pub use amoe::{AnyMoeConfig, AnyMoeExpertType};
+pub use attention::SplitKvAccumulation;
 pub use device_map::{
     DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, LayerDeviceMapper,
 };
+pub use expert_counts::ExpertCounter;
 pub use gguf::{GGUFArchitecture, GGUF_MULTI_FILE_DELIMITER};
-pub use mistralrs_quant::{IsqType, MULTI_LORA_DELIMITER};
-pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig};
+pub use mistralrs_quant::{
+    get_matmul_precision, get_strict_determinism, get_use_matmul_via_bf16, get_use_matmul_via_f16,
+    op_trace, set_matmul_precision, set_strict_determinism, set_use_matmul_via_bf16,
+    set_use_matmul_via_f16, IsqType, MatMulPrecision, QuantInfo, MULTI_LORA_DELIMITER,
+};
+pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig, PagedCacheStats, PagedCacheType};
 pub use pipeline::{
     chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline,
-    AutoDeviceMapParams, DiffusionGenerationParams, DiffusionLoader, DiffusionLoaderBuilder,
-    DiffusionLoaderType, DiffusionSpecificConfig, GGMLLoader, GGMLLoaderBuilder,
-    GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader,
-    Idefics2Loader, IsqOrganization, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader,
-    LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoader,
-    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader, Phi3Loader,
-    Phi3VLoader, Qwen2Loader, SpeculativeConfig, SpeculativeLoader, SpeculativePipeline,
-    Starcoder2Loader, TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType,
-    VisionPromptPrefixer, VisionSpecificConfig, UQFF_MULTI_FILE_DELIMITER,
+    AutoDeviceMapParams, ConfigValidationError, DiffusionGenerationParams, DiffusionLoader,
+    DiffusionLoaderBuilder, DiffusionLoaderType, DiffusionSpecificConfig, GGMLLoader,
+    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig,
+    GemmaLoader, GradientTarget, Idefics2Loader, IsqOrganization, LLaVALoader, LLaVANextLoader,
+    LlamaLoader, Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths,
+    NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, NormalizedConfig,
+    Phi2Loader, Phi3Loader, Phi3VLoader, PromptChunksize, PromptLookupConfig, PromptLookupLoader,
+    PromptLookupPipeline, Qwen2Loader, ResourceEstimate, SpeculativeConfig, SpeculativeLoader,
+    SpeculativePipeline, Starcoder2Loader, TokenSource, VisionLoader, VisionLoaderBuilder,
+    VisionLoaderType, VisionPromptPrefixer, VisionSpecificConfig, WeightSource,
+    UQFF_MULTI_FILE_DELIMITER,
 };
+pub use prefix_cacher::PrefixCacheStats;
+pub use qkv_capture::{CapturedQkv, QkvCapture};
 pub use request::{
     ApproximateUserLocation, Constraint, DetokenizationRequest, ImageGenerationResponseFormat,
-    LlguidanceGrammar, MessageContent, NormalRequest, Request, RequestMessage, TokenizationRequest,
+    InputLimits, KvCacheLimit, KvCacheOverflowPolicy, LlguidanceGrammar, MessageContent,
+    NormalRequest, Request, RequestMessage, StreamFlushPolicy, TokenizationRequest,
     WebSearchOptions, WebSearchUserLocation,
 };
 pub use response::*;
 pub use sampler::{
-    CustomLogitsProcessor, DrySamplingParams, SamplingParams, StopTokens, TopLogprob,
+    CustomLogitsProcessor, DrySamplingParams, PenaltyScope, RepetitionLoopParams, SamplingParams,
+    SamplingRng, StopTokens, TemperatureOrder, TemperatureProcessor, TopLogprob,
 };
-pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
+pub use scheduler::{ConcurrencyLimit, ConcurrencyPolicy, DefaultSchedulerMethod, SchedulerConfig};
+pub use sequence::ContentFilter;
 use serde::Serialize;
 use tokio::runtime::Runtime;
 use toml_selector::{TomlLoaderArgs, TomlSelector};
 pub use tools::{
-    CalledFunction, Function, Tool, ToolCallResponse, ToolCallType, ToolChoice, ToolType,
+    CalledFunction, Function, Tool, ToolCallDetector, ToolCallEvent, ToolCallResponse,
+    ToolCallTrigger, ToolCallType, ToolChoice, ToolType,
 };
 pub use topology::{LayerTopology, Topology};
 pub use utils::debug::initialize_logging;
@@ -145,8 +163,12 @@ pub struct MistralRs {
     engine_id: usize,
     category: ModelCategory,
     config: MistralRsConfig,
+    prefix_cacher: PrefixCacherSlot,
 }
 
+/// The prefix cacher of the running engine, set once the engine has been created.
+type PrefixCacherSlot = Arc<RwLock<Option<Arc<tokio::sync::Mutex<PrefixCacheManagerV2>>>>>;
+
 #[derive(Clone)]
 struct RebootState {
     pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>>,
@@ -158,17 +180,37 @@ struct RebootState {
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
     search_embedding_model: Option<BertEmbeddingModel>,
+    content_filter: Option<ContentFilter>,
+    sampling_rng: Option<SamplingRng>,
+    input_limits: InputLimits,
+    prefix_cache_primes: Vec<Vec<u32>>,
+    concurrency_limit: Option<ConcurrencyLimit>,
 }
 
 #[derive(Debug)]
 pub enum MistralRsError {
     EnginePoisoned,
     SenderPoisoned,
+    /// A forward step expected PagedAttention input metadata, but the inputs did not carry any.
+    /// This usually means the scheduler config is not set up for PagedAttention.
+    PagedAttentionMetadataMissing,
+    /// A forward step got PagedAttention input metadata, but the pipeline has no cache engine.
+    PagedAttentionCacheEngineMissing,
 }
 
 impl std::fmt::Display for MistralRsError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", &self)
+        match self {
+            Self::PagedAttentionMetadataMissing => write!(
+                f,
+                "Forward step expected a PagedAttention input metadata. This was not provided, please ensure that the scheduler config is correctly configured for PagedAttention (use `SchedulerConfig::PagedAttentionMeta` when the pipeline has a cache engine)."
+            ),
+            Self::PagedAttentionCacheEngineMissing => write!(
+                f,
+                "Forward step got a PagedAttention input metadata but there is no cache engine. Either load the pipeline with a `PagedAttentionConfig` or use `SchedulerConfig::DefaultScheduler`."
+            ),
+            _ => write!(f, "{:?}", &self),
+        }
     }
 }
 
@@ -195,6 +237,11 @@ pub struct MistralRsBuilder {
     disable_eos_stop: Option<bool>,
     throughput_logging_enabled: bool,
     search_embedding_model: Option<BertEmbeddingModel>,
+    content_filter: Option<ContentFilter>,
+    sampling_rng: Option<SamplingRng>,
+    input_limits: InputLimits,
+    prefix_cache_primes: Vec<Vec<u32>>,
+    concurrency_limit: Option<ConcurrencyLimit>,
 }
 
 impl MistralRsBuilder {
@@ -215,6 +262,11 @@ impl MistralRsBuilder {
             disable_eos_stop: None,
             throughput_logging_enabled: throughput_logging,
             search_embedding_model,
+            content_filter: None,
+            sampling_rng: None,
+            input_limits: InputLimits::default(),
+            prefix_cache_primes: Vec::new(),
+            concurrency_limit: None,
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -245,6 +297,49 @@ impl MistralRsBuilder {
         self.disable_eos_stop = Some(disable_eos_stop);
         self
     }
+    /// Register a filter checked on the decoded output at every decode step. When it returns
+    /// `true`, the sequence finishes with the `content_filter` reason and the offending tail is trimmed.
+    pub fn with_content_filter(mut self, content_filter: ContentFilter) -> Self {
+        self.content_filter = Some(content_filter);
+        self
+    }
+    /// Use a custom random number generator for sampling instead of the default `Isaac64Rng`.
+    /// It is shared by all sequences and kept across engine reboots.
+    pub fn with_sampling_rng(mut self, rng: impl RngCore + Send + 'static) -> Self {
+        self.sampling_rng = Some(Arc::new(std::sync::Mutex::new(rng)));
+        self
+    }
+    /// Reject requests whose raw text has more than `max_input_chars` characters, before tokenizing them.
+    pub fn with_max_input_chars(mut self, max_input_chars: usize) -> Self {
+        self.input_limits.max_input_chars = Some(max_input_chars);
+        self
+    }
+    /// Reject requests whose raw text has more than `max_input_bytes` bytes, before tokenizing them.
+    pub fn with_max_input_bytes(mut self, max_input_bytes: usize) -> Self {
+        self.input_limits.max_input_bytes = Some(max_input_bytes);
+        self
+    }
+
+    /// Prefill these prompts, such as known system prompts, at startup and store them in the prefix
+    /// cache so that the first requests starting with them skip that part of the prefill.
+    pub fn with_prefix_cache_primes(mut self, prompts: Vec<Vec<u32>>) -> Self {
+        self.prefix_cache_primes = prompts;
+        self
+    }
+
+    /// Bound the number of live sequences, and so the number of KV caches, to `max_concurrent_seqs`.
+    /// Requests beyond it are queued or rejected according to `policy`.
+    pub fn with_max_concurrent_seqs(
+        mut self,
+        max_concurrent_seqs: usize,
+        policy: ConcurrencyPolicy,
+    ) -> Self {
+        self.concurrency_limit = Some(ConcurrencyLimit {
+            max_concurrent_seqs,
+            policy,
+        });
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -273,6 +368,11 @@ impl MistralRs {
             disable_eos_stop,
             throughput_logging_enabled,
             search_embedding_model,
+            content_filter,
+            sampling_rng,
+            input_limits,
+            prefix_cache_primes,
+            concurrency_limit,
         } = config;
 
         let category = pipeline.try_lock().unwrap().category();
@@ -296,6 +396,11 @@ impl MistralRs {
             disable_eos_stop,
             throughput_logging_enabled,
             search_embedding_model: search_embedding_model.clone(),
+            content_filter: content_filter.clone(),
+            sampling_rng: sampling_rng.clone(),
+            input_limits,
+            prefix_cache_primes: prefix_cache_primes.clone(),
+            concurrency_limit,
         };
 
         let (tx, rx) = channel(10_000);
@@ -311,6 +416,8 @@ impl MistralRs {
             category: category.clone(),
         };
 
+        let prefix_cacher: PrefixCacherSlot = Arc::new(RwLock::new(None));
+        let engine_prefix_cacher = prefix_cacher.clone();
         let engine_handler = thread::spawn(move || {
             let rt = Runtime::new().unwrap();
             rt.block_on(async move {
@@ -325,8 +432,14 @@ impl MistralRs {
                     disable_eos_stop,
                     throughput_logging_enabled,
                     search_embedding_model,
+                    content_filter,
+                    sampling_rng,
+                    input_limits,
+                    prefix_cache_primes,
+                    concurrency_limit,
                 )
                 .expect("Engine creation failed.");
+                *engine_prefix_cacher.write().unwrap() = Some(engine.prefix_cacher());
                 Arc::new(engine).run().await;
             });
         });
@@ -386,6 +499,7 @@ impl MistralRs {
                                 Request::TerminateAllSeqsNextStep => {
                                     Request::TerminateAllSeqsNextStep
                                 }
+                                Request::Drain => Request::Drain,
                             };
 
                             request_sender.send(req).await.unwrap();
@@ -431,6 +545,10 @@ impl MistralRs {
                     logits_processors: None,
                     return_raw_logits: false,
                     web_search_options: None,
+                    tool_call_trigger: None,
+                    stream_raw_bytes: false,
+                    stream_flush_policy: Default::default(),
+                    kv_cache_limit: None,
                 });
                 info!("Beginning dummy run.");
                 let start = Instant::now();
@@ -446,6 +564,10 @@ impl MistralRs {
                     warn!("Dummy run failed!");
                 }
             });
+            // The dummy run should not show up in the prefix cache statistics.
+            if let Some(prefix_cacher) = prefix_cacher.read().unwrap().as_ref() {
+                get_mut_arcmutex!(prefix_cacher).reset_stats();
+            }
         }
 
         Arc::new(Self {
@@ -462,6 +584,7 @@ impl MistralRs {
             engine_handler: RwLock::new(engine_handler),
             category,
             config,
+            prefix_cacher,
         })
     }
 
@@ -484,6 +607,7 @@ impl MistralRs {
             Ok(())
         } else {
             // critical section. A panic here could lead to poisoned locks
+            let engine_prefix_cacher = self.prefix_cacher.clone();
             let new_engine_handler = thread::spawn(move || {
                 let rt = Runtime::new().unwrap();
                 rt.block_on(async move {
@@ -498,8 +622,14 @@ impl MistralRs {
                         reboot_state.disable_eos_stop,
                         reboot_state.throughput_logging_enabled,
                         reboot_state.search_embedding_model,
+                        reboot_state.content_filter,
+                        reboot_state.sampling_rng,
+                        reboot_state.input_limits,
+                        reboot_state.prefix_cache_primes,
+                        reboot_state.concurrency_limit,
                     )
                     .expect("Engine creation failed");
+                    *engine_prefix_cacher.write().unwrap() = Some(engine.prefix_cacher());
                     Arc::new(engine).run().await;
                 });
             });
@@ -594,4 +724,23 @@ impl MistralRs {
     pub fn config(&self) -> &MistralRsConfig {
         &self.config
     }
+
+    /// Hit, miss and eviction counts of the prefix cache, or `None` if the engine has not been
+    /// created yet. A rebooted engine starts with a new prefix cache and zeroed counts.
+    pub fn prefix_cache_stats(&self) -> Option<PrefixCacheStats> {
+        let prefix_cacher = self.engine_prefix_cacher()?;
+        let stats = get_mut_arcmutex!(prefix_cacher).stats();
+        Some(stats)
+    }
+
+    /// Zero the prefix cache counters, for example between benchmark runs.
+    pub fn reset_prefix_cache_stats(&self) {
+        if let Some(prefix_cacher) = self.engine_prefix_cacher() {
+            get_mut_arcmutex!(prefix_cacher).reset_stats();
+        }
+    }
+
+    fn engine_prefix_cacher(&self) -> Option<Arc<tokio::sync::Mutex<PrefixCacheManagerV2>>> {
+        self.prefix_cacher.read().ok()?.clone()
+    }
 }
@@ -1,6 +1,5 @@
 use std::{
     fs::{self, File},
-    num::NonZeroUsize,
     path::PathBuf,
     str::FromStr,
 };
@@ -12,8 +11,8 @@ use crate::{
     pipeline::{GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, NormalSpecificConfig},
     toml_selector::get_toml_selected_model_device_map_params,
     AutoDeviceMapParams, DiffusionLoaderBuilder, DiffusionSpecificConfig, GGUFSpecificConfig,
-    Loader, ModelDType, ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, Topology,
-    VisionLoaderBuilder, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
+    Loader, ModelDType, ModelSelected, NormalLoaderBuilder, PromptChunksize, TomlLoaderArgs,
+    TomlSelector, Topology, VisionLoaderBuilder, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
     UQFF_MULTI_FILE_DELIMITER,
 };
 
@@ -24,7 +23,7 @@ pub struct LoaderBuilder {
     chat_template: Option<String>,
     jinja_explicit: Option<String>,
     use_flash_attn: bool,
-    prompt_chunksize: Option<NonZeroUsize>,
+    prompt_chunksize: Option<PromptChunksize>,
 }
 
 impl LoaderBuilder {
@@ -55,7 +54,7 @@ impl LoaderBuilder {
         self.use_flash_attn = use_flash_attn;
         self
     }
-    pub fn with_prompt_chunksize(mut self, prompt_chunksize: Option<NonZeroUsize>) -> Self {
+    pub fn with_prompt_chunksize(mut self, prompt_chunksize: Option<PromptChunksize>) -> Self {
         self.prompt_chunksize = prompt_chunksize;
         self
     }
@@ -212,6 +211,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             topology,
             organization,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             imatrix,
             calibration_file,
@@ -225,6 +225,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 topology: Topology::from_option_path(topology)?,
                 organization: organization.unwrap_or_default(),
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.split(UQFF_MULTI_FILE_DELIMITER)
                         .map(PathBuf::from_str)
@@ -234,6 +235,22 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 imatrix,
                 calibration_file,
                 hf_cache_path,
+                activation_override: None,
+                prefill_dtype: None,
+                split_kv_segment_len: None,
+                split_kv_accumulation: Default::default(),
+                embedding_dtype: None,
+                lm_head_dtype: None,
+                f32_norm_weights: false,
+                isq_overrides: Vec::new(),
+                pruned_heads: Default::default(),
+                attention_head_scales: Default::default(),
+                head_dim_alignment: None,
+                max_cached_rope_positions: None,
+                offload_activations: false,
+                share_rope_tables: false,
+                lazy_rope_tables: false,
+                shard_download_retries: 0,
             },
             args.chat_template,
             tokenizer_json,
@@ -252,6 +269,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             dtype: _,
             topology,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             max_seq_len: _,
             max_batch_size: _,
@@ -263,6 +281,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.split(UQFF_MULTI_FILE_DELIMITER)
                         .map(PathBuf::from_str)
@@ -272,6 +291,22 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                activation_override: None,
+                prefill_dtype: None,
+                split_kv_segment_len: None,
+                split_kv_accumulation: Default::default(),
+                embedding_dtype: None,
+                lm_head_dtype: None,
+                f32_norm_weights: false,
+                isq_overrides: Vec::new(),
+                pruned_heads: Default::default(),
+                attention_head_scales: Default::default(),
+                head_dim_alignment: None,
+                max_cached_rope_positions: None,
+                offload_activations: false,
+                share_rope_tables: false,
+                lazy_rope_tables: false,
+                shard_download_retries: 0,
             },
             args.chat_template,
             tokenizer_json,
@@ -297,6 +332,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             dtype: _,
             topology,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             max_seq_len: _,
             max_batch_size: _,
@@ -308,6 +344,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.split(UQFF_MULTI_FILE_DELIMITER)
                         .map(PathBuf::from_str)
@@ -317,6 +354,22 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                activation_override: None,
+                prefill_dtype: None,
+                split_kv_segment_len: None,
+                split_kv_accumulation: Default::default(),
+                embedding_dtype: None,
+                lm_head_dtype: None,
+                f32_norm_weights: false,
+                isq_overrides: Vec::new(),
+                pruned_heads: Default::default(),
+                attention_head_scales: Default::default(),
+                head_dim_alignment: None,
+                max_cached_rope_positions: None,
+                offload_activations: false,
+                share_rope_tables: false,
+                lazy_rope_tables: false,
+                shard_download_retries: 0,
             },
             args.chat_template,
             tokenizer_json,
@@ -346,7 +399,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 .map(ToOwned::to_owned)
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.no_kv_cache,
@@ -371,7 +424,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 .map(ToOwned::to_owned)
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.no_kv_cache,
@@ -404,7 +457,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 .map(ToOwned::to_owned)
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.no_kv_cache,
@@ -429,7 +482,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
         } => GGMLLoaderBuilder::new(
             GGMLSpecificConfig {
                 gqa,
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -455,7 +508,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
         } => GGMLLoaderBuilder::new(
             GGMLSpecificConfig {
                 gqa,
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -489,7 +542,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
         } => GGMLLoaderBuilder::new(
             GGMLSpecificConfig {
                 gqa,
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -515,6 +568,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             dtype: _,
             topology,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             max_edge,
             calibration_file,
@@ -527,9 +581,10 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
         } => VisionLoaderBuilder::new(
             VisionSpecificConfig {
                 use_flash_attn,
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.split(UQFF_MULTI_FILE_DELIMITER)
                         .map(PathBuf::from_str)
@@ -540,6 +595,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 calibration_file,
                 imatrix,
                 hf_cache_path,
+                shard_download_retries: 0,
             },
             args.chat_template,
             tokenizer_json,
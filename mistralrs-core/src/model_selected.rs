@@ -63,7 +63,11 @@ pub enum ModelSelected {
         #[arg(short, long)]
         write_uqff: Option<PathBuf>,
 
-        /// UQFF path to load from. If provided, this takes precedence over applying ISQ. Specify multiple files using a semicolon delimiter (;)
+        /// Maximum size in MB of each UQFF shard written with `write_uqff`. Defaults to 10 GB.
+        #[arg(long)]
+        uqff_shard_size_mb: Option<usize>,
+
+        /// UQFF path to load from. If provided, this takes precedence over applying ISQ. Specify multiple files using a semicolon delimiter (;) or a `*` wildcard.
         #[arg(short, long)]
         from_uqff: Option<String>,
 
@@ -129,7 +133,11 @@ pub enum ModelSelected {
         #[arg(short, long)]
         write_uqff: Option<PathBuf>,
 
-        /// UQFF path to load from. If provided, this takes precedence over applying ISQ. Specify multiple files using a semicolon delimiter (;).
+        /// Maximum size in MB of each UQFF shard written with `write_uqff`. Defaults to 10 GB.
+        #[arg(long)]
+        uqff_shard_size_mb: Option<usize>,
+
+        /// UQFF path to load from. If provided, this takes precedence over applying ISQ. Specify multiple files using a semicolon delimiter (;) or a `*` wildcard.
         #[arg(short, long)]
         from_uqff: Option<String>,
 
@@ -176,7 +184,11 @@ pub enum ModelSelected {
         #[arg(short, long)]
         write_uqff: Option<PathBuf>,
 
-        /// UQFF path to load from. If provided, this takes precedence over applying ISQ. Specify multiple files using a semicolon delimiter (;).
+        /// Maximum size in MB of each UQFF shard written with `write_uqff`. Defaults to 10 GB.
+        #[arg(long)]
+        uqff_shard_size_mb: Option<usize>,
+
+        /// UQFF path to load from. If provided, this takes precedence over applying ISQ. Specify multiple files using a semicolon delimiter (;) or a `*` wildcard.
         #[arg(short, long)]
         from_uqff: Option<String>,
 
@@ -486,7 +498,11 @@ pub enum ModelSelected {
         #[arg(short, long)]
         write_uqff: Option<PathBuf>,
 
-        /// UQFF path to load from. If provided, this takes precedence over applying ISQ. Specify multiple files using a semicolon delimiter (;).
+        /// Maximum size in MB of each UQFF shard written with `write_uqff`. Defaults to 10 GB.
+        #[arg(long)]
+        uqff_shard_size_mb: Option<usize>,
+
+        /// UQFF path to load from. If provided, this takes precedence over applying ISQ. Specify multiple files using a semicolon delimiter (;) or a `*` wildcard.
         #[arg(short, long)]
         from_uqff: Option<String>,
 
@@ -248,6 +248,7 @@ impl Attention {
                 softcap: None,
                 softmax_scale: cfg.softmax_scale(),
                 sliding_window: None,
+                head_scales: None,
             },
         })
     }
@@ -801,11 +802,10 @@ impl DecoderLayer {
             metadata,
             flash_params,
         )?;
-        let xs = (xs + residual)?;
-        let residual = &xs;
-        let xs = self
-            .moe_or_mlp
-            .forward(&xs.apply(&self.post_attention_layernorm)?)?;
+        let (residual, xs) = self
+            .post_attention_layernorm
+            .forward_residual(&xs, residual)?;
+        let xs = self.moe_or_mlp.forward(&xs)?;
         residual + xs
     }
 }
@@ -1,10 +1,9 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use candle_core::{Device, Result, Tensor};
+use candle_core::{DType, Device, Result, Tensor, D};
 use candle_nn::{Embedding, Module};
 use mistralrs_quant::{
-    ColumnParallelLayer, QuantMethod, QuantizedConfig, ReplicatedLayer, RowParallelLayer,
-    ShardedVarBuilder,
+    QuantMethod, QuantizedConfig, ReplicatedLayer, RowParallelLayer, ShardedVarBuilder,
 };
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, sync::Arc};
@@ -16,7 +15,7 @@ use crate::{
     get_delta_from_lora_ab,
     layers::{
         embedding, Activation, CausalMasker, Llama3RopeConfig, Llama3RotaryEmbedding, MatMul, Mlp,
-        RmsNorm, Sdpa,
+        OffloadedActivation, RmsNorm, Sdpa,
     },
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
@@ -24,7 +23,9 @@ use crate::{
         extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
         EitherCache, IsqModel, KvCache, NormalCache, NormalLoadingMetadata, NormalModel,
+        SharedEmbeddings,
     },
+    qkv_capture::QkvCapture,
     serde_default_fn,
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
 };
@@ -50,6 +51,73 @@ pub struct Config {
     pub quantization_config: Option<QuantizedConfig>,
     #[serde(default = "word_emb_default")]
     pub tie_word_embeddings: bool,
+    /// Granite: attention softmax scale, replacing `1/sqrt(head_dim)`.
+    #[serde(default)]
+    pub attention_multiplier: Option<f64>,
+    /// Granite: scale applied to the input embeddings.
+    #[serde(default)]
+    pub embedding_multiplier: Option<f64>,
+    /// Granite: scale applied to the attention and MLP outputs before each residual add.
+    #[serde(default)]
+    pub residual_multiplier: Option<f64>,
+    /// Granite: the final logits are divided by this.
+    #[serde(default)]
+    pub logits_scaling: Option<f64>,
+}
+
+/// Granite-style scaling factors. These are all identity for Llama checkpoints.
+#[derive(Debug, Clone, Copy)]
+struct Multipliers {
+    embedding: f64,
+    residual: f64,
+    logits_scaling: f64,
+}
+
+impl Multipliers {
+    fn new(cfg: &Config) -> Self {
+        Self {
+            embedding: cfg.embedding_multiplier.unwrap_or(1.),
+            residual: cfg.residual_multiplier.unwrap_or(1.),
+            logits_scaling: cfg.logits_scaling.unwrap_or(1.),
+        }
+    }
+
+    fn softmax_scale(cfg: &Config) -> f32 {
+        match cfg.attention_multiplier {
+            Some(attention_multiplier) => attention_multiplier as f32,
+            None => 1.0 / ((cfg.hidden_size / cfg.num_attention_heads) as f32).sqrt(),
+        }
+    }
+
+    fn scale_embeddings(&self, xs: Tensor) -> Result<Tensor> {
+        if self.embedding == 1. {
+            Ok(xs)
+        } else {
+            xs * self.embedding
+        }
+    }
+
+    /// `xs * residual_multiplier`, the branch output before it is added to the residual.
+    fn scale_residual_branch(&self, xs: Tensor) -> Result<Tensor> {
+        if self.residual == 1. {
+            Ok(xs)
+        } else {
+            xs * self.residual
+        }
+    }
+
+    /// `residual + xs * residual_multiplier`
+    fn residual_add(&self, xs: Tensor, residual: &Tensor) -> Result<Tensor> {
+        self.scale_residual_branch(xs)? + residual
+    }
+
+    fn scale_logits(&self, logits: Tensor) -> Result<Tensor> {
+        if self.logits_scaling == 1. {
+            Ok(logits)
+        } else {
+            logits / self.logits_scaling
+        }
+    }
 }
 
 struct CausalSelfAttention {
@@ -64,6 +132,8 @@ struct CausalSelfAttention {
     max_seq_len: usize,
     paged_attn: Option<PagedAttention>,
     sdpa_params: SdpaParams,
+    layer_idx: usize,
+    qkv_capture: QkvCapture,
 }
 
 impl CausalSelfAttention {
@@ -112,6 +182,7 @@ impl CausalSelfAttention {
         };
 
         let (q, k) = self.rotary_emb.forward(&q, &k, seqlen_offsets)?;
+        self.qkv_capture.record(self.layer_idx, &q, &k, &v)?;
 
         let mut y = match &self.paged_attn {
             Some(paged_attn) => match metadata {
@@ -174,46 +245,83 @@ impl CausalSelfAttention {
         Ok(res)
     }
 
+    /// Remove the query heads `heads` and the key/value heads left without any query head, slicing
+    /// the projections to match.
+    fn prune_heads(&mut self, heads: &[usize]) -> Result<()> {
+        let layer_idx = self.layer_idx;
+        if let Some(head) = heads.iter().find(|&&h| h >= self.num_attention_heads) {
+            candle_core::bail!(
+                "Cannot prune attention head {head} of layer {layer_idx}, which has {} heads.",
+                self.num_attention_heads
+            );
+        }
+        let kept_q = (0..self.num_attention_heads)
+            .filter(|h| !heads.contains(h))
+            .collect::<Vec<_>>();
+        if kept_q.is_empty() {
+            candle_core::bail!("Cannot prune every attention head of layer {layer_idx}.");
+        }
+        // Query heads sharing a key/value head must be kept or pruned together.
+        let n_rep = self.num_attention_heads / self.num_key_value_heads;
+        let mut kept_kv = Vec::new();
+        for kv_head in 0..self.num_key_value_heads {
+            let group = kv_head * n_rep..(kv_head + 1) * n_rep;
+            match group.clone().filter(|h| kept_q.contains(h)).count() {
+                0 => (),
+                n if n == n_rep => kept_kv.push(kv_head),
+                _ => candle_core::bail!(
+                    "Attention heads {group:?} of layer {layer_idx} share a key/value head, so they must be pruned together."
+                ),
+            }
+        }
+
+        self.q_proj = prune_projection(&self.q_proj, &kept_q, self.head_dim, 0)?;
+        self.k_proj = prune_projection(&self.k_proj, &kept_kv, self.head_dim, 0)?;
+        self.v_proj = prune_projection(&self.v_proj, &kept_kv, self.head_dim, 0)?;
+        self.o_proj = prune_projection(&self.o_proj, &kept_q, self.head_dim, 1)?;
+        self.num_attention_heads = kept_q.len();
+        self.num_key_value_heads = kept_kv.len();
+        Ok(())
+    }
+
+    /// Zero-pad every head to `head_dim` dims by padding the rows of the projections, so that
+    /// attention and the KV cache run on the padded heads. `rope` must be padded to match.
+    fn pad_heads(&mut self, head_dim: usize, rope: Arc<Llama3RotaryEmbedding>) -> Result<()> {
+        let split = rope.is_gpt_neox();
+        let (real, n_q, n_kv) = (
+            self.head_dim,
+            self.num_attention_heads,
+            self.num_key_value_heads,
+        );
+        self.q_proj = pad_projection(&self.q_proj, n_q, real, head_dim, split, 0)?;
+        self.k_proj = pad_projection(&self.k_proj, n_kv, real, head_dim, split, 0)?;
+        self.v_proj = pad_projection(&self.v_proj, n_kv, real, head_dim, false, 0)?;
+        self.o_proj = pad_projection(&self.o_proj, n_q, real, head_dim, false, 1)?;
+        self.head_dim = head_dim;
+        self.rotary_emb = rope;
+        Ok(())
+    }
+
     fn load(
         vb: ShardedVarBuilder,
         cfg: &Config,
         rope: Arc<Llama3RotaryEmbedding>,
         paged_attn: Option<PagedAttention>,
         comm: &Arc<mistralrs_quant::Comm>,
+        layer_idx: usize,
+        qkv_capture: QkvCapture,
     ) -> Result<Self> {
         let size_in = cfg.hidden_size;
         let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
-        let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
-        let q_proj = ColumnParallelLayer::new(
+        let [q_proj, k_proj, v_proj] = mistralrs_quant::qkv_column_parallel_layers(
             size_in,
-            size_q,
-            &cfg.quantization_config,
-            false,
-            comm,
-            vb.pp("q_proj"),
-        )?;
-        let kv_shard = mistralrs_quant::compute_kv_shard(
-            cfg.num_key_value_heads,
             cfg.hidden_size / cfg.num_attention_heads,
-            comm,
-        );
-        let k_proj = ColumnParallelLayer::new_with_shard(
-            size_in,
-            size_kv,
-            &cfg.quantization_config,
-            false,
-            comm,
-            kv_shard,
-            vb.pp("k_proj"),
-        )?;
-        let v_proj = ColumnParallelLayer::new_with_shard(
-            size_in,
-            size_kv,
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
             &cfg.quantization_config,
             false,
             comm,
-            kv_shard,
-            vb.pp("v_proj"),
+            vb.clone(),
         )?;
         let o_proj = RowParallelLayer::new(
             size_q,
@@ -242,44 +350,127 @@ impl CausalSelfAttention {
                 ),
                 use_flash_attn: cfg.use_flash_attn,
                 softcap: None,
-                softmax_scale: 1.0 / ((cfg.hidden_size / cfg.num_attention_heads) as f32).sqrt(),
+                softmax_scale: Multipliers::softmax_scale(cfg),
                 sliding_window: None,
+                head_scales: None,
             },
+            layer_idx,
+            qkv_capture,
         })
     }
 }
 
+/// Keep the rows (`dim == 0`) or columns (`dim == 1`) of an unquantized projection belonging to `heads`.
+fn prune_projection(
+    layer: &Arc<dyn QuantMethod>,
+    heads: &[usize],
+    head_dim: usize,
+    dim: usize,
+) -> Result<Arc<dyn QuantMethod>> {
+    let Some((w, b)) = layer.unquant_weight_bias() else {
+        candle_core::bail!("Attention heads can only be pruned from unquantized projections.");
+    };
+    let idx = heads
+        .iter()
+        .flat_map(|h| (h * head_dim..(h + 1) * head_dim).map(|i| i as u32))
+        .collect::<Vec<_>>();
+    let idx = Tensor::new(idx.as_slice(), w.device())?;
+    // The output projection's bias is over the hidden size, so it is kept whole.
+    let b = match b {
+        Some(b) if dim == 0 => Some(b.index_select(&idx.to_device(b.device())?, 0)?),
+        b => b,
+    };
+    ReplicatedLayer::from_linear(candle_nn::Linear::new(w.index_select(&idx, dim)?, b))
+}
+
+/// Zero-pad each head of an unquantized projection from `head_dim` to `padded` rows (`dim == 0`) or
+/// columns (`dim == 1`). With `split_halves`, each half of a head is padded separately, matching
+/// the GPT-NeoX RoPE layout.
+fn pad_projection(
+    layer: &Arc<dyn QuantMethod>,
+    n_heads: usize,
+    head_dim: usize,
+    padded: usize,
+    split_halves: bool,
+    dim: usize,
+) -> Result<Arc<dyn QuantMethod>> {
+    let Some((w, b)) = layer.unquant_weight_bias() else {
+        candle_core::bail!("The head dim can only be padded for unquantized projections.");
+    };
+    // Index `n_heads * head_dim` selects the zero appended to the weight.
+    let zero = (n_heads * head_dim) as u32;
+    let idx = (0..n_heads)
+        .flat_map(|h| {
+            (0..padded).map(move |p| {
+                let src = if split_halves {
+                    let (half, padded_half) = (head_dim / 2, padded / 2);
+                    let (offset, p) = if p < padded_half {
+                        (0, p)
+                    } else {
+                        (half, p - padded_half)
+                    };
+                    (p < half).then_some(offset + p)
+                } else {
+                    (p < head_dim).then_some(p)
+                };
+                src.map_or(zero, |i| (h * head_dim + i) as u32)
+            })
+        })
+        .collect::<Vec<_>>();
+    let idx = Tensor::new(idx.as_slice(), w.device())?;
+    let w = w.pad_with_zeros(dim, 0, 1)?.index_select(&idx, dim)?;
+    // The output projection's bias is over the hidden size, so it is kept whole.
+    let b = match b {
+        Some(b) if dim == 0 => Some(
+            b.pad_with_zeros(0, 0, 1)?
+                .index_select(&idx.to_device(b.device())?, 0)?,
+        ),
+        b => b,
+    };
+    ReplicatedLayer::from_linear(candle_nn::Linear::new(w, b))
+}
+
 struct Block {
     rms_1: RmsNorm,
     attn: CausalSelfAttention,
     rms_2: RmsNorm,
     mlp: Box<dyn MlpLayer>,
+    multipliers: Multipliers,
 }
 
 impl Block {
     #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
-        x: &Tensor,
+        x: Tensor,
         attention_mask: &Option<Tensor>,
         seqlen_offsets: &[usize],
         kv_cache: &mut KvCache,
         metadata: Option<((Tensor, Tensor), &PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
+        offload_activations: bool,
     ) -> Result<Tensor> {
-        let residual = x;
-        let x = self.rms_1.forward(x)?;
-        let x = (self.attn.forward(
-            &x,
+        let normed = self.rms_1.forward(&x)?;
+        let residual = OffloadedActivation::new(x, offload_activations)?;
+        let attn_out = self.attn.forward(
+            &normed,
             attention_mask,
             seqlen_offsets,
             kv_cache,
             metadata,
             flash_params,
-        )? + residual)?;
-        let residual = &x;
-        let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
-        Ok(x)
+        )?;
+        drop(normed);
+        let attn_out = self.multipliers.scale_residual_branch(attn_out)?;
+        let (x, normed) = self
+            .rms_2
+            .forward_residual(&attn_out, &residual.restore()?)?;
+        drop(attn_out);
+
+        let residual = OffloadedActivation::new(x, offload_activations)?;
+        let mlp_out = self.mlp.forward(&normed)?;
+        drop(normed);
+        self.multipliers.residual_add(mlp_out, &residual.restore()?)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -292,6 +483,7 @@ impl Block {
         rope: Arc<Llama3RotaryEmbedding>,
         paged_attn: Option<PagedAttention>,
         comm: &Arc<mistralrs_quant::Comm>,
+        qkv_capture: QkvCapture,
     ) -> Result<Self> {
         let attn = CausalSelfAttention::load(
             mapper.set_device(layer_idx, vb.pp("self_attn"), loading_isq),
@@ -299,6 +491,8 @@ impl Block {
             rope,
             paged_attn,
             comm,
+            layer_idx,
+            qkv_capture,
         )?;
         let mlp = Mlp::new(
             mapper.set_device(layer_idx, vb.pp("mlp"), loading_isq),
@@ -323,6 +517,7 @@ impl Block {
             attn,
             rms_2,
             mlp: Box::new(mlp),
+            multipliers: Multipliers::new(cfg),
         })
     }
 }
@@ -336,6 +531,13 @@ pub struct Llama {
     device: Device,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
     cfg: ModelConfigMetadata,
+    multipliers: Multipliers,
+    offload_activations: bool,
+    qkv_capture: QkvCapture,
+    /// The dtype of the decoder layers, which the embeddings are cast to.
+    dtype: DType,
+    /// The dtype of an unquantized LM head, if it differs from `dtype`.
+    lm_head_dtype: Option<DType>,
 }
 
 impl Llama {
@@ -365,6 +567,48 @@ impl Llama {
         is_gptx: bool,
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
+    ) -> Result<Self> {
+        Self::new_maybe_shared(
+            cfg,
+            vb_m,
+            vb_lm_head,
+            is_gptx,
+            normal_loading_metadata,
+            attention_mechanism,
+            None,
+        )
+    }
+
+    /// Load a draft model which references `shared`, the target model's input embedding and LM head,
+    /// and only loads its own decoder layers and norms.
+    pub fn new_with_shared_embeddings(
+        cfg: &Config,
+        vb: ShardedVarBuilder,
+        is_gptx: bool,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+        shared: SharedEmbeddings,
+    ) -> Result<Self> {
+        shared.validate(cfg.vocab_size, cfg.hidden_size)?;
+        Self::new_maybe_shared(
+            cfg,
+            vb.pp("model"),
+            vb.pp("lm_head"),
+            is_gptx,
+            normal_loading_metadata,
+            attention_mechanism,
+            Some(shared),
+        )
+    }
+
+    fn new_maybe_shared(
+        cfg: &Config,
+        vb_m: ShardedVarBuilder,
+        vb_lm_head: ShardedVarBuilder,
+        is_gptx: bool,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+        shared: Option<SharedEmbeddings>,
     ) -> Result<Self> {
         if let Some(ref quant_cfg) = &cfg.quantization_config {
             tracing::info!(
@@ -375,26 +619,43 @@ impl Llama {
         }
         let mapper = normal_loading_metadata.mapper;
 
-        let wte = embedding(
-            cfg.vocab_size,
-            cfg.hidden_size,
-            mapper.set_nm_device(vb_m.pp("embed_tokens"), false),
-            &cfg.quantization_config,
-        )?;
-        let lm_head = if !cfg.tie_word_embeddings {
-            ReplicatedLayer::new(
-                cfg.hidden_size,
-                cfg.vocab_size,
-                &None,
-                false,
-                mapper.set_nm_device(vb_lm_head, normal_loading_metadata.loading_isq),
-            )?
-        } else {
-            ReplicatedLayer::from_linear(candle_nn::Linear::new(
-                mapper.cast_nm_device(wte.embeddings(), normal_loading_metadata.loading_isq)?,
-                None,
-            ))?
+        let (wte, lm_head) = match shared {
+            Some(SharedEmbeddings {
+                embed_tokens,
+                lm_head,
+            }) => (embed_tokens, lm_head),
+            None => {
+                let wte = embedding(
+                    cfg.vocab_size,
+                    cfg.hidden_size,
+                    mapper.set_nm_device(vb_m.pp("embed_tokens"), false),
+                    &cfg.quantization_config,
+                )?;
+                let lm_head = if !cfg.tie_word_embeddings {
+                    ReplicatedLayer::new(
+                        cfg.hidden_size,
+                        cfg.vocab_size,
+                        &None,
+                        false,
+                        mapper.set_nm_device(vb_lm_head, normal_loading_metadata.loading_isq),
+                    )?
+                } else {
+                    ReplicatedLayer::from_linear(candle_nn::Linear::new(
+                        mapper.cast_nm_device(
+                            wte.embeddings(),
+                            normal_loading_metadata.loading_isq,
+                        )?,
+                        None,
+                    ))?
+                };
+                (wte, lm_head)
+            }
         };
+        // A shared LM head may be in a different dtype than this model.
+        let lm_head_dtype = lm_head
+            .unquant_weight_bias()
+            .map(|(w, _)| w.dtype())
+            .filter(|dtype| *dtype != vb_m.dtype());
         let ln_f = RmsNorm::new(
             cfg.hidden_size,
             cfg.rms_norm_eps,
@@ -416,6 +677,7 @@ impl Llama {
                 )?),
             );
         }
+        let qkv_capture = QkvCapture::default();
         let blocks: Vec<_> = NiceProgressBar::<_, 'b'>(
             0..cfg.num_hidden_layers,
             "Loading repeating layers",
@@ -447,6 +709,7 @@ impl Llama {
                 rotary_emb,
                 paged_attn,
                 &comm,
+                qkv_capture.clone(),
             )
             .expect("Failed to load block.")
         })
@@ -474,6 +737,11 @@ impl Llama {
                 v_head_dim: cfg.hidden_size / cfg.num_attention_heads,
             },
             mapper,
+            multipliers: Multipliers::new(cfg),
+            offload_activations: false,
+            qkv_capture,
+            dtype: vb_m.dtype(),
+            lm_head_dtype,
         })
     }
 
@@ -509,7 +777,38 @@ impl Llama {
         metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
-        let mut x = input_embeds;
+        let mut x = self.hidden_states(
+            input_ids,
+            input_embeds,
+            seqlen_offsets,
+            metadata,
+            flash_params,
+            None,
+        )?;
+        if let Some(t) = self.lm_head.quantized_act_type().or(self.lm_head_dtype) {
+            x = x.to_dtype(t)?;
+        }
+        let xs = self
+            .multipliers
+            .scale_logits(MatMul.qmethod_matmul(&x, &*self.lm_head)?)?;
+        extract_logits(&xs, context_lens)
+    }
+
+    /// Run the decoder layers and the final norm. If `layer_outputs` is given, the output of each decoder
+    /// layer (before the final norm) is pushed to it.
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        input_embeds: Tensor,
+        seqlen_offsets: &[usize],
+        metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+        mut layer_outputs: Option<&mut Vec<Tensor>>,
+    ) -> Result<Tensor> {
+        let mut x = self
+            .multipliers
+            .scale_embeddings(input_embeds)?
+            .to_dtype(self.dtype)?;
         let cache = &mut self.kv_cache.normal().0;
         let mask = CausalMasker.make_causal_mask_matrix(
             input_ids,
@@ -529,24 +828,24 @@ impl Llama {
         });
         for (block_idx, block) in self.blocks.iter().enumerate() {
             x = self.mapper.map(x, block_idx)?;
+            let block_mask = mask.clone().map(|m| m.to_device(x.device()).unwrap());
             x = block.forward(
-                &x,
-                &mask.clone().map(|m| m.to_device(x.device()).unwrap()),
+                x,
+                &block_mask,
                 seqlen_offsets,
                 &mut cache[block_idx],
                 metadata
                     .as_ref()
                     .map(|(kv_cache, metadata)| (kv_cache[block_idx].clone(), *metadata)),
                 flash_params,
+                self.offload_activations,
             )?;
+            if let Some(layer_outputs) = layer_outputs.as_mut() {
+                layer_outputs.push(x.to_device(&self.device)?);
+            }
         }
         let x = x.to_device(&self.device)?;
-        let mut x = self.ln_f.forward(&x)?;
-        if let Some(t) = self.lm_head.quantized_act_type() {
-            x = x.to_dtype(t)?;
-        }
-        let xs = MatMul.qmethod_matmul(&x, &*self.lm_head)?;
-        extract_logits(&xs, context_lens)
+        self.ln_f.forward(&x)
     }
 
     pub fn residual_tensors_m(&self, uvb_m: UnVarBuilder) -> Vec<(String, Tensor)> {
@@ -663,6 +962,172 @@ impl NormalModel for Llama {
     fn config(&self) -> &ModelConfigMetadata {
         &self.cfg
     }
+    fn final_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.hidden_states(
+            input_ids,
+            self.wte.forward(input_ids)?,
+            seqlen_offsets,
+            None,
+            flash_params,
+            None,
+        )
+    }
+    fn output_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        flash_params: &FlashParams,
+    ) -> Result<Vec<Tensor>> {
+        let mut layer_outputs = Vec::with_capacity(self.blocks.len());
+        self.hidden_states(
+            input_ids,
+            self.wte.forward(input_ids)?,
+            seqlen_offsets,
+            None,
+            flash_params,
+            Some(&mut layer_outputs),
+        )?;
+        Ok(layer_outputs)
+    }
+    fn input_embeddings(&self, input_ids: &Tensor) -> Result<Tensor> {
+        self.get_input_embeddings(input_ids)
+    }
+    fn forward_input_embeds(
+        &self,
+        input_ids: &Tensor,
+        input_embeds: &Tensor,
+        seqlen_offsets: &[usize],
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let (bs, seq_len) = input_ids.dims2()?;
+        self.forward_embeds(
+            input_ids,
+            input_embeds.clone(),
+            seqlen_offsets,
+            vec![(0, seq_len); bs],
+            None,
+            flash_params,
+        )
+    }
+    fn qkv_capture(&self) -> Option<&QkvCapture> {
+        Some(&self.qkv_capture)
+    }
+    fn shared_embeddings(&self) -> Option<SharedEmbeddings> {
+        Some(SharedEmbeddings {
+            embed_tokens: self.wte.clone(),
+            lm_head: self.lm_head.clone(),
+        })
+    }
+    fn set_boundary_dtypes(
+        &mut self,
+        embedding_dtype: Option<DType>,
+        lm_head_dtype: Option<DType>,
+    ) -> Result<()> {
+        if let Some(dtype) = embedding_dtype {
+            self.wte = Embedding::new(self.wte.embeddings().to_dtype(dtype)?, self.cfg.hidden_size);
+        }
+        if let Some(dtype) = lm_head_dtype {
+            let Some((w, b)) = self.lm_head.unquant_weight_bias() else {
+                candle_core::bail!("The LM head is quantized, so it cannot be run in {dtype:?}.");
+            };
+            self.lm_head = ReplicatedLayer::from_linear(candle_nn::Linear::new(
+                w.to_dtype(dtype)?,
+                b.map(|b| b.to_dtype(dtype)).transpose()?,
+            ))?;
+            self.lm_head_dtype = Some(dtype).filter(|dtype| *dtype != self.dtype);
+        }
+        Ok(())
+    }
+    fn prune_attention_heads(&mut self, pruned_heads: &HashMap<usize, Vec<usize>>) -> Result<()> {
+        for (&layer_idx, heads) in pruned_heads {
+            let Some(block) = self.blocks.get_mut(layer_idx) else {
+                candle_core::bail!(
+                    "Cannot prune attention heads of layer {layer_idx}, the model has {} layers.",
+                    self.cfg.num_layers
+                );
+            };
+            if self.mapper.get_comm_for(layer_idx)?.world_size() > 1 {
+                candle_core::bail!(
+                    "Pruning attention heads is not supported with tensor parallelism."
+                );
+            }
+            block.attn.prune_heads(heads)?;
+        }
+        Ok(())
+    }
+    fn pad_head_dim(&mut self, alignment: usize) -> Result<()> {
+        let head_dim = self.cfg.k_head_dim;
+        let padded = head_dim.next_multiple_of(alignment);
+        if padded == head_dim {
+            return Ok(());
+        }
+        if head_dim % 2 != 0 {
+            candle_core::bail!("Cannot pad the odd head dim {head_dim}.");
+        }
+        // Layers on the same device share their RoPE, so pad each one once.
+        let mut ropes = HashMap::new();
+        for (layer_idx, block) in self.blocks.iter_mut().enumerate() {
+            if self.mapper.get_comm_for(layer_idx)?.world_size() > 1 {
+                candle_core::bail!(
+                    "Padding the head dim is not supported with tensor parallelism."
+                );
+            }
+            let key = Arc::as_ptr(&block.attn.rotary_emb) as usize;
+            let rope = match ropes.get(&key) {
+                Some(rope) => Arc::clone(rope),
+                None => {
+                    let rope = Arc::new(block.attn.rotary_emb.padded(padded)?);
+                    ropes.insert(key, rope.clone());
+                    rope
+                }
+            };
+            block.attn.pad_heads(padded, rope)?;
+        }
+        self.cfg.k_head_dim = padded;
+        self.cfg.v_head_dim = padded;
+        Ok(())
+    }
+    fn set_offload_activations(&mut self, offload: bool) -> Result<()> {
+        self.offload_activations = offload;
+        Ok(())
+    }
+    fn set_attention_head_scales(&mut self, head_scales: &HashMap<usize, Vec<f32>>) -> Result<()> {
+        for (&layer_idx, scales) in head_scales {
+            let Some(block) = self.blocks.get_mut(layer_idx) else {
+                candle_core::bail!(
+                    "Cannot scale attention heads of layer {layer_idx}, the model has {} layers.",
+                    self.cfg.num_layers
+                );
+            };
+            if self.mapper.get_comm_for(layer_idx)?.world_size() > 1 {
+                candle_core::bail!(
+                    "Scaling attention heads is not supported with tensor parallelism."
+                );
+            }
+            let device = self
+                .mapper
+                .device_for(layer_idx, false)
+                .unwrap_or(&self.device);
+            let attn = &mut block.attn;
+            attn.sdpa_params
+                .set_head_scales(scales, attn.num_attention_heads, device)?;
+        }
+        Ok(())
+    }
+    fn logit_lens(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let mut x = self.ln_f.forward(&hidden_states.to_device(&self.device)?)?;
+        if let Some(t) = self.lm_head.quantized_act_type().or(self.lm_head_dtype) {
+            x = x.to_dtype(t)?;
+        }
+        self.multipliers
+            .scale_logits(MatMul.qmethod_matmul(&x, &*self.lm_head)?)?
+            .argmax(D::Minus1)
+    }
 }
 
 impl AnyMoeBaseModelMixin for Llama {
@@ -784,3 +1249,81 @@ impl AnyMoeBaseModelMixin for Llama {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{Config, Multipliers};
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn granite_multipliers_apply_at_each_stage() -> candle_core::Result<()> {
+        let cfg: Config = serde_json::from_str(
+            r#"{
+                "hidden_act": "silu",
+                "hidden_size": 64,
+                "intermediate_size": 128,
+                "vocab_size": 32,
+                "num_hidden_layers": 1,
+                "num_attention_heads": 4,
+                "num_key_value_heads": 4,
+                "rms_norm_eps": 1e-5,
+                "rope_theta": 10000.0,
+                "max_position_embeddings": 128,
+                "rope_scaling": null,
+                "quantization_config": null,
+                "attention_multiplier": 0.015625,
+                "embedding_multiplier": 12.0,
+                "residual_multiplier": 0.22,
+                "logits_scaling": 8.0
+            }"#,
+        )
+        .map_err(candle_core::Error::msg)?;
+        let multipliers = Multipliers::new(&cfg);
+        let dev = Device::Cpu;
+        let xs = Tensor::randn(0f32, 1., (2, 3, 64), &dev)?;
+        let residual = Tensor::randn(0f32, 1., (2, 3, 64), &dev)?;
+
+        assert_eq!(Multipliers::softmax_scale(&cfg), 0.015625);
+        assert!(max_diff(&multipliers.scale_embeddings(xs.clone())?, &(&xs * 12.)?) < 1e-6);
+        assert!(
+            max_diff(
+                &multipliers.residual_add(xs.clone(), &residual)?,
+                &((&xs * 0.22)? + &residual)?
+            ) < 1e-6
+        );
+        assert!(max_diff(&multipliers.scale_logits(xs.clone())?, &(&xs / 8.)?) < 1e-6);
+
+        // Without the Granite fields everything is identity.
+        let llama = Config {
+            hidden_size: 64,
+            num_attention_heads: 4,
+            ..Default::default()
+        };
+        let identity = Multipliers::new(&llama);
+        assert_eq!(Multipliers::softmax_scale(&llama), 1. / 16f32.sqrt());
+        assert_eq!(max_diff(&identity.scale_embeddings(xs.clone())?, &xs), 0.);
+        assert_eq!(
+            max_diff(
+                &identity.residual_add(xs.clone(), &residual)?,
+                &(&xs + &residual)?
+            ),
+            0.
+        );
+        assert_eq!(max_diff(&identity.scale_logits(xs.clone())?, &xs), 0.);
+        Ok(())
+    }
+}
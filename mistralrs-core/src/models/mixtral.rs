@@ -15,6 +15,7 @@ use crate::{
     amoe::AnyMoeBaseModelMixin,
     attention::SdpaParams,
     device_map::DeviceMapper,
+    expert_counts::ExpertCounter,
     layers::{self, Activation, CausalMasker, MatMul, RmsNorm, RotaryEmbedding, Sdpa},
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
@@ -135,6 +136,7 @@ impl Attention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: cfg.sliding_window,
+                head_scales: None,
             },
         })
     }
@@ -309,15 +311,53 @@ impl Module for BlockSparseTop2MLP {
     }
 }
 
+/// Select the `top_k` experts with the highest routing weights for one token, renormalizing the
+/// selected weights to sum to 1.
+pub(crate) fn select_experts(routing_weights: &[f32], top_k: usize) -> Vec<(usize, f32)> {
+    let mut dst = (0..routing_weights.len()).collect::<Vec<usize>>();
+    dst.sort_by(|&i, &j| routing_weights[j].total_cmp(&routing_weights[i]));
+    dst.truncate(top_k);
+    let sum_routing_weights: f32 = dst.iter().map(|&i| routing_weights[i]).sum();
+    dst.into_iter()
+        .map(|i| (i, routing_weights[i] / sum_routing_weights))
+        .collect()
+}
+
+/// Route each token (row of `routing_weights`) to its `top_k` experts, returning the rows and renormalized
+/// routing weights assigned to each expert.
+fn route_tokens(
+    routing_weights: &[Vec<f32>],
+    num_experts: usize,
+    top_k: usize,
+) -> (Vec<Vec<u32>>, Vec<Vec<f32>>) {
+    let mut top_x = vec![vec![]; num_experts];
+    let mut selected_rws = vec![vec![]; num_experts];
+    for (row_idx, rw) in routing_weights.iter().enumerate() {
+        for (expert_idx, routing_weight) in select_experts(rw, top_k) {
+            top_x[expert_idx].push(row_idx as u32);
+            selected_rws[expert_idx].push(routing_weight);
+        }
+    }
+    (top_x, selected_rws)
+}
+
 #[derive(Clone)]
 struct SparseMoeBlock {
     gate: Arc<dyn QuantMethod>,
     experts: Vec<BlockSparseTop2MLP>,
     num_experts_per_tok: usize,
+    layer_idx: usize,
+    expert_counter: ExpertCounter,
 }
 
 impl SparseMoeBlock {
-    fn new(cfg: &Config, vb: ShardedVarBuilder, comm: &Arc<mistralrs_quant::Comm>) -> Result<Self> {
+    fn new(
+        cfg: &Config,
+        vb: ShardedVarBuilder,
+        comm: &Arc<mistralrs_quant::Comm>,
+        layer_idx: usize,
+        expert_counter: ExpertCounter,
+    ) -> Result<Self> {
         let gate = mistralrs_quant::linear_no_bias(
             cfg.hidden_size,
             cfg.num_local_experts,
@@ -334,6 +374,8 @@ impl SparseMoeBlock {
             gate,
             experts,
             num_experts_per_tok: cfg.num_experts_per_tok,
+            layer_idx,
+            expert_counter,
         })
     }
 }
@@ -361,24 +403,13 @@ impl Module for SparseMoeBlock {
 
         // routing_weights, selected_experts = torch.topk(routing_weights, self.top_k, dim=-1)
         // top_x contains the row indexes to evaluate for each expert.
-        let mut top_x = vec![vec![]; self.experts.len()];
-        let mut selected_rws = vec![vec![]; self.experts.len()];
-        for (row_idx, rw) in routing_weights.iter().enumerate() {
-            let mut dst = (0..rw.len() as u32).collect::<Vec<u32>>();
-            dst.sort_by(|&i, &j| rw[j as usize].total_cmp(&rw[i as usize]));
-            let mut sum_routing_weights = 0f32;
-            for &expert_idx in dst.iter().take(self.num_experts_per_tok) {
-                let expert_idx = expert_idx as usize;
-                let routing_weight = rw[expert_idx];
-                sum_routing_weights += routing_weight;
-                top_x[expert_idx].push(row_idx as u32);
-            }
-            for &expert_idx in dst.iter().take(self.num_experts_per_tok) {
-                let expert_idx = expert_idx as usize;
-                let routing_weight = rw[expert_idx];
-                selected_rws[expert_idx].push(routing_weight / sum_routing_weights)
-            }
-        }
+        let (top_x, selected_rws) = route_tokens(
+            &routing_weights,
+            self.experts.len(),
+            self.num_experts_per_tok,
+        );
+        self.expert_counter
+            .record(self.layer_idx, top_x.iter().map(Vec::len));
 
         // routing_weights /= routing_weights.sum(dim=-1, keepdim=True)
         // expert_mask = torch.nn.functional.one_hot(selected_experts, num_classes=self.num_experts).permute(2, 1, 0)
@@ -425,6 +456,7 @@ impl DecoderLayer {
         loading_isq: bool,
         paged_attn: Option<PagedAttention>,
         comm: &Arc<mistralrs_quant::Comm>,
+        expert_counter: ExpertCounter,
     ) -> Result<Self> {
         let self_attn = Attention::new(
             rotary_emb,
@@ -437,6 +469,8 @@ impl DecoderLayer {
             cfg,
             mapper.set_device(layer_idx, vb.pp("block_sparse_moe"), loading_isq),
             comm,
+            layer_idx,
+            expert_counter,
         )?;
         let input_layernorm = RmsNorm::new(
             cfg.hidden_size,
@@ -476,10 +510,10 @@ impl DecoderLayer {
             metadata,
             flash_params,
         )?;
-        let xs = (xs + residual)?;
-        let residual = &xs;
+        let (residual, xs) = self
+            .post_attention_layernorm
+            .forward_residual(&xs, residual)?;
         let xs = xs
-            .apply(&self.post_attention_layernorm)?
             .apply(&self.block_sparse_moe)?
             .to_dtype(residual.dtype())?;
         residual + xs
@@ -497,6 +531,7 @@ pub struct Model {
     max_seq_len: usize,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
     cfg: ModelConfigMetadata,
+    expert_counter: ExpertCounter,
 }
 
 impl Model {
@@ -541,6 +576,7 @@ impl Model {
                 )?),
             );
         }
+        let expert_counter = ExpertCounter::default();
         let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
         let vb_l = vb_m.pp("layers");
         for layer_idx in NiceProgressBar::<_, 'b'>(
@@ -571,6 +607,7 @@ impl Model {
                 normal_loading_metadata.loading_isq,
                 paged_attn,
                 &comm,
+                expert_counter.clone(),
             )?;
             layers.push(layer)
         }
@@ -621,6 +658,7 @@ impl Model {
                 v_head_dim: cfg.hidden_size / cfg.num_attention_heads,
             },
             mapper,
+            expert_counter,
         })
     }
 
@@ -770,6 +808,56 @@ impl NormalModel for Model {
     fn config(&self) -> &ModelConfigMetadata {
         &self.cfg
     }
+    fn expert_counter(&self) -> Option<&ExpertCounter> {
+        Some(&self.expert_counter)
+    }
 }
 
 impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use super::{route_tokens, select_experts};
+    use crate::expert_counts::ExpertCounter;
+
+    #[test]
+    fn top_k_routing_renormalizes() {
+        let routing_weights = [0.1f32, 0.4, 0.2, 0.3];
+
+        let top1 = select_experts(&routing_weights, 1);
+        assert_eq!(top1.len(), 1);
+        assert_eq!(top1[0].0, 1);
+        assert!((top1[0].1 - 1.).abs() < 1e-6);
+
+        let top2 = select_experts(&routing_weights, 2);
+        assert_eq!(top2.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![1, 3]);
+        assert!((top2[0].1 - 0.4 / 0.7).abs() < 1e-6);
+        assert!((top2[1].1 - 0.3 / 0.7).abs() < 1e-6);
+        assert!((top2.iter().map(|(_, w)| w).sum::<f32>() - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn expert_counts_match_routing() {
+        // Top-2 of 4 experts: tokens go to {1, 3}, {0, 1}, {1, 2} and {3, 0}.
+        let routing_weights = vec![
+            vec![0.1f32, 0.4, 0.2, 0.3],
+            vec![0.5, 0.3, 0.1, 0.1],
+            vec![0.05, 0.6, 0.3, 0.05],
+            vec![0.3, 0.1, 0.2, 0.4],
+        ];
+        let counter = ExpertCounter::default();
+        let (top_x, _) = route_tokens(&routing_weights, 4, 2);
+        counter.record(0, top_x.iter().map(Vec::len));
+        assert!(counter.take().is_empty(), "counting is opt-in");
+
+        counter.enable();
+        counter.record(0, top_x.iter().map(Vec::len));
+        let (top_x, _) = route_tokens(&routing_weights[..1], 4, 1);
+        counter.record(2, top_x.iter().map(Vec::len));
+        assert_eq!(
+            counter.take(),
+            vec![vec![2, 3, 1, 2], vec![], vec![0, 1, 0, 0]]
+        );
+        assert!(counter.take().is_empty());
+    }
+}
@@ -54,6 +54,10 @@ pub struct Config {
     #[serde(default = "word_emb_default")]
     pub tie_word_embeddings: bool,
     pub partial_rotary_factor: Option<f64>,
+    /// Only precompute the RoPE sin/cos tables for this many positions, extending them when a
+    /// longer sequence is seen.
+    #[serde(default)]
+    pub max_cached_rope_positions: Option<usize>,
 }
 
 impl From<Config> for PhiRopeConfig {
@@ -96,14 +100,14 @@ impl Attention {
         let num_heads = cfg.num_attention_heads;
         let num_kv_heads = cfg.num_key_value_heads;
         let head_dim = cfg.head_dim();
-        let op_size = num_heads * head_dim + 2 * num_kv_heads * head_dim;
 
         // No TP here.
-        let qkv_proj = mistralrs_quant::linear_no_bias(
+        let qkv_proj = mistralrs_quant::fused_qkv_linear_no_bias(
             cfg.hidden_size,
-            op_size,
+            num_heads * head_dim,
+            num_kv_heads * head_dim,
             &cfg.quantization_config,
-            vb.pp("qkv_proj"),
+            vb.clone(),
         )?;
 
         let o_proj = mistralrs_quant::linear_no_bias(
@@ -127,6 +131,7 @@ impl Attention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: cfg.sliding_window,
+                head_scales: None,
             },
         })
     }
@@ -407,11 +412,10 @@ impl DecoderLayer {
             metadata,
             flash_params,
         )?;
-        let xs = (xs + residual)?;
-        let residual = &xs;
-        let xs = self
-            .mlp
-            .forward(&xs.apply(&self.post_attention_layernorm)?)?;
+        let (residual, xs) = self
+            .post_attention_layernorm
+            .forward_residual(&xs, residual)?;
+        let xs = self.mlp.forward(&xs)?;
         residual + xs
     }
 }
@@ -460,7 +464,12 @@ impl Model {
                 .unwrap_or(&normal_loading_metadata.real_device);
             ropes.insert(
                 device.location(),
-                Arc::new(PhiRotaryEmbedding::new(vb.dtype(), cfg.clone(), device)?),
+                Arc::new(PhiRotaryEmbedding::new_with_max_cached_positions(
+                    vb.dtype(),
+                    cfg.clone(),
+                    cfg.max_cached_rope_positions,
+                    device,
+                )?),
             );
         }
         let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
@@ -31,6 +31,7 @@ use crate::{
 };
 
 serde_default_fn!(bool, word_emb_default, false);
+serde_default_fn!(usize, num_experts_per_tok_default, 2);
 
 // https://huggingface.co/microsoft/Phi-3-mini-4k-instruct/blob/main/config.json
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Default)]
@@ -53,9 +54,13 @@ pub struct Config {
     pub(crate) lm_head_bias: bool,
     pub(crate) attention_bias: bool,
     pub(crate) num_local_experts: usize,
+    #[serde(default = "num_experts_per_tok_default")]
+    pub(crate) num_experts_per_tok: usize,
     pub(crate) router_jitter_noise: f64,
     #[serde(default = "word_emb_default")]
     pub(crate) tie_word_embeddings: bool,
+    #[serde(default)]
+    pub(crate) max_cached_rope_positions: Option<usize>,
 }
 
 impl From<Config> for PhiRopeConfig {
@@ -162,6 +167,7 @@ impl Attention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: cfg.sliding_window,
+                head_scales: None,
             },
         })
     }
@@ -341,11 +347,52 @@ impl Mlp {
     }
 }
 
+/// SparseMixer routing, generalized from top-2 to `top_k` experts: each round selects the best
+/// remaining expert and masks it out for the following rounds.
+fn sparsemixer(scores: &Tensor, jitter_eps: f64, top_k: usize) -> Result<(Tensor, Tensor)> {
+    let mut masked_scores = scores.clone();
+    let mut multipliers = Vec::with_capacity(top_k);
+    let mut selected = Vec::with_capacity(top_k);
+    for _ in 0..top_k {
+        // Compute mask for sparsity
+        let selected_experts = masked_scores.argmax_keepdim(D::Minus1)?;
+        let mask_logits_threshold = masked_scores.gather(&selected_experts, D::Minus1)?;
+        let factor = scores.abs()?.broadcast_minimum(&mask_logits_threshold)?;
+        let mask_logits_threshold = mask_logits_threshold
+            .broadcast_sub(scores)?
+            .broadcast_div(&factor)?
+            .gt(2. * jitter_eps)?;
+
+        // Apply mask
+        let masked_gates = masked_fill(&masked_scores, &mask_logits_threshold, f64::NEG_INFINITY)?;
+
+        // Compute scores
+        let masked_gates = candle_nn::ops::softmax_last_dim(&masked_gates)?;
+        multipliers.push(masked_gates.gather(&selected_experts, D::Minus1)?);
+
+        // Mask out the selected expert for the following rounds
+        masked_scores = masked_scores.scatter_add(
+            &selected_experts
+                .broadcast_as(scores.shape())?
+                .contiguous()?,
+            &(scores.ones_like()? * f64::NEG_INFINITY)?,
+            D::Minus1,
+        )?;
+        selected.push(selected_experts);
+    }
+
+    Ok((
+        Tensor::cat(&multipliers, D::Minus1)?,
+        Tensor::cat(&selected, D::Minus1)?,
+    ))
+}
+
 struct MoeMlp {
     gate: candle_nn::Linear,
     experts: Vec<Mlp>,
     router_jitter_noise: f64,
     num_experts: usize,
+    num_experts_per_tok: usize,
 }
 
 impl MoeMlp {
@@ -373,56 +420,10 @@ impl MoeMlp {
             experts,
             router_jitter_noise: cfg.router_jitter_noise,
             num_experts,
+            num_experts_per_tok: cfg.num_experts_per_tok,
         })
     }
 
-    fn sparsemixer(&self, scores: &Tensor, jitter_eps: f64) -> Result<(Tensor, Tensor)> {
-        // Compute mask for sparsity
-        let selected_experts = scores.argmax_keepdim(D::Minus1)?;
-        let mask_logits_threshold = scores.gather(&selected_experts, D::Minus1)?;
-        let factor = scores.abs()?.broadcast_minimum(&mask_logits_threshold)?;
-        let mask_logits_threshold = mask_logits_threshold
-            .broadcast_sub(scores)?
-            .broadcast_div(&factor)?
-            .gt(2. * jitter_eps)?;
-
-        // Apply mask
-        let masked_gates = masked_fill(scores, &mask_logits_threshold, f64::NEG_INFINITY)?;
-
-        // Compute scores
-        let masked_gates = candle_nn::ops::softmax_last_dim(&masked_gates)?;
-        let multiplier = masked_gates.gather(&selected_experts, D::Minus1)?;
-
-        // Mask out first expert
-        let masked_scores = scores.scatter_add(
-            &selected_experts
-                .broadcast_as(scores.shape())?
-                .contiguous()?,
-            &(scores.ones_like()? * f64::NEG_INFINITY)?,
-            D::Minus1,
-        )?;
-
-        // Compute mask for sparsity
-        let selected_experts_top2 = masked_scores.argmax_keepdim(D::Minus1)?;
-        let mask_logits_threshold = masked_scores.gather(&selected_experts_top2, D::Minus1)?;
-        let factor = scores.abs()?.broadcast_minimum(&mask_logits_threshold)?;
-        let mask_logits_threshold = mask_logits_threshold
-            .broadcast_sub(scores)?
-            .broadcast_div(&factor)?
-            .gt(2. * jitter_eps)?;
-
-        // Apply mask
-        let masked_gates_top2 =
-            masked_fill(&masked_scores, &mask_logits_threshold, f64::NEG_INFINITY)?;
-        let masked_gates_top2 = candle_nn::ops::softmax_last_dim(&masked_gates_top2)?;
-        let multiplier_top2 = masked_gates_top2.gather(&selected_experts_top2, D::Minus1)?;
-
-        let multiplier = Tensor::cat(&[multiplier, multiplier_top2], D::Minus1)?;
-        let selected_experts = Tensor::cat(&[selected_experts, selected_experts_top2], D::Minus1)?;
-
-        Ok((multiplier, selected_experts))
-    }
-
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let (bs, seq, hidden) = xs.dims3()?;
         let xs = xs.reshape(((), hidden))?;
@@ -435,9 +436,10 @@ impl MoeMlp {
             .gate
             .forward(&xs.to_device(xs_dev)?)?
             .to_device(&Device::Cpu)?;
-        let (routing_weights, selected_experts) = self.sparsemixer(
+        let (routing_weights, selected_experts) = sparsemixer(
             &router_logits.to_device(&Device::Cpu)?,
             self.router_jitter_noise,
+            self.num_experts_per_tok,
         )?;
 
         let mut final_hidden_states = Tensor::zeros((bs * seq, hidden), xs.dtype(), xs.device())?;
@@ -616,7 +618,12 @@ impl Model {
                 .unwrap_or(&normal_loading_metadata.real_device);
             ropes.insert(
                 device.location(),
-                Arc::new(PhiRotaryEmbedding::new(vb.dtype(), cfg.clone(), device)?),
+                Arc::new(PhiRotaryEmbedding::new_with_max_cached_positions(
+                    vb.dtype(),
+                    cfg.clone(),
+                    cfg.max_cached_rope_positions,
+                    device,
+                )?),
             );
         }
         let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
@@ -890,3 +897,27 @@ impl NormalModel for Model {
 }
 
 impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::sparsemixer;
+
+    #[test]
+    fn sparsemixer_selects_num_experts_per_tok() -> candle_core::Result<()> {
+        let scores = Tensor::new(&[[0.1f32, 2.0, -1.0, 1.5, 0.5]], &Device::Cpu)?;
+
+        for (top_k, expected) in [(1, vec![1u32]), (2, vec![1, 3]), (3, vec![1, 3, 4])] {
+            let (multipliers, selected) = sparsemixer(&scores, 0.01, top_k)?;
+            assert_eq!(selected.to_vec2::<u32>()?, vec![expected]);
+            assert_eq!(multipliers.dims(), &[1, top_k]);
+            assert!(multipliers
+                .flatten_all()?
+                .to_vec1::<f32>()?
+                .iter()
+                .all(|m| *m > 0. && *m <= 1.));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,861 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+// This implementation is based on:
+// https://huggingface.co/microsoft/Phi-3-small-8k-instruct/blob/main/modeling_phi3_small.py
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::LayerNorm;
+use mistralrs_quant::{QuantMethod, QuantizedConfig, ReplicatedLayer, ShardedVarBuilder};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    amoe::AnyMoeBaseModelMixin,
+    attention::SdpaParams,
+    device_map::DeviceMapper,
+    layers::{
+        embedding, layer_norm, Activation, CausalMasker, MatMul, PhiRopeConfig,
+        PhiRopeScalingConfig, PhiRotaryEmbedding, Sdpa,
+    },
+    layers_masker::PastKvLenCache,
+    paged_attention::{AttentionImplementation, ModelConfigMetadata},
+    pipeline::{
+        extract_logits,
+        text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
+        EitherCache, IsqModel, KvCache, NormalCache, NormalLoadingMetadata, NormalModel,
+    },
+    utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
+};
+
+// https://huggingface.co/microsoft/Phi-3-small-8k-instruct/blob/main/config.json
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Default)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub ff_intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub layer_norm_epsilon: f64,
+    pub rope_embedding_base: f64,
+    pub rope_scaling: Option<PhiRopeScalingConfig>,
+    pub max_position_embeddings: usize,
+    pub original_max_position_embeddings: usize,
+    pub gegelu_limit: f64,
+    pub mup_attn_multiplier: f64,
+    pub mup_embedding_multiplier: f64,
+    pub mup_use_scaling: bool,
+    pub mup_width_multiplier: f64,
+    pub blocksparse_block_size: usize,
+    pub blocksparse_num_local_blocks: usize,
+    pub blocksparse_vert_stride: usize,
+    pub blocksparse_homo_head_pattern: bool,
+    pub dense_attention_every_n_layers: Option<usize>,
+    pub use_flash_attn: bool,
+    pub quantization_config: Option<QuantizedConfig>,
+    pub tie_word_embeddings: bool,
+}
+
+impl From<Config> for PhiRopeConfig {
+    fn from(val: Config) -> Self {
+        PhiRopeConfig {
+            rope_scaling: val.rope_scaling,
+            max_position_embeddings: val.max_position_embeddings,
+            original_max_position_embeddings: val.original_max_position_embeddings,
+            rope_theta: val.rope_embedding_base,
+            head_dim: val.hidden_size / val.num_attention_heads,
+            partial_rotary_factor: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+
+    pub fn blocksparse(&self) -> BlocksparseConfig {
+        BlocksparseConfig {
+            block_size: self.blocksparse_block_size,
+            num_local_blocks: self.blocksparse_num_local_blocks,
+            vert_stride: self.blocksparse_vert_stride,
+            homo_head_pattern: self.blocksparse_homo_head_pattern,
+        }
+    }
+
+    /// Whether layer `layer_idx` uses the blocksparse pattern. Every `dense_attention_every_n_layers`-th
+    /// layer attends densely.
+    pub fn is_blocksparse_layer(&self, layer_idx: usize) -> bool {
+        self.dense_attention_every_n_layers
+            .is_none_or(|n| (layer_idx + 1) % n != 0)
+    }
+}
+
+/// Blocksparse attention: keys are grouped in blocks of `block_size` and a query attends to the
+/// `num_local_blocks` blocks ending at its own, plus every `vert_stride`-th block before them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlocksparseConfig {
+    pub block_size: usize,
+    pub num_local_blocks: usize,
+    pub vert_stride: usize,
+    /// Use the same vertical blocks for every head instead of shifting them per head.
+    pub homo_head_pattern: bool,
+}
+
+impl BlocksparseConfig {
+    /// Whether key block `k_block` is a vertical block of head `head`.
+    fn is_vertical(&self, num_heads: usize, head: usize, k_block: usize) -> bool {
+        let head_offset = if self.homo_head_pattern {
+            0
+        } else {
+            (self.vert_stride / num_heads).max(1) * head
+        };
+        (k_block + head_offset + 1) % self.vert_stride == 0
+    }
+}
+
+/// The block layout of a [`BlocksparseConfig`]. The vertical blocks repeat every `vert_stride` blocks, so
+/// they are built once for each head and indexed by the key block when building a mask.
+pub struct BlocksparseLayout {
+    cfg: BlocksparseConfig,
+    /// Whether a key block is vertical, with shape `(pattern_heads, vert_stride)`.
+    vertical: Tensor,
+}
+
+impl BlocksparseLayout {
+    pub fn new(cfg: BlocksparseConfig, num_heads: usize, device: &Device) -> Result<Self> {
+        let pattern_heads = if cfg.homo_head_pattern { 1 } else { num_heads };
+        let vertical = (0..pattern_heads)
+            .flat_map(|head| {
+                (0..cfg.vert_stride)
+                    .map(move |k_block| u8::from(cfg.is_vertical(num_heads, head, k_block)))
+            })
+            .collect::<Vec<_>>();
+        Ok(Self {
+            cfg,
+            vertical: Tensor::from_vec(vertical, (pattern_heads, cfg.vert_stride), device)?,
+        })
+    }
+
+    /// Additive attention mask for the last `q_len` of `kv_len` positions, with shape `(q_len, kv_len)`
+    /// for a homogeneous pattern and `(num_heads, q_len, kv_len)` otherwise. This also applies the
+    /// causal mask.
+    pub fn mask(&self, q_len: usize, kv_len: usize, dtype: DType) -> Result<Tensor> {
+        let device = self.vertical.device();
+        let past_len = kv_len - q_len;
+        let block = |pos: usize| (pos / self.cfg.block_size) as u32;
+
+        let q_pos = Tensor::arange(past_len as u32, kv_len as u32, device)?.unsqueeze(1)?;
+        let k_pos = Tensor::arange(0u32, kv_len as u32, device)?.unsqueeze(0)?;
+        let q_block = Tensor::from_vec(
+            (past_len..kv_len).map(block).collect::<Vec<_>>(),
+            (q_len, 1),
+            device,
+        )?;
+        // A key block is local if it is one of the `num_local_blocks` blocks ending at the query block.
+        let local_end = Tensor::from_vec(
+            (0..kv_len)
+                .map(|pos| block(pos) + self.cfg.num_local_blocks as u32)
+                .collect::<Vec<_>>(),
+            (1, kv_len),
+            device,
+        )?;
+        let vertical_idx = Tensor::from_vec(
+            (0..kv_len)
+                .map(|pos| block(pos) % self.cfg.vert_stride as u32)
+                .collect::<Vec<_>>(),
+            kv_len,
+            device,
+        )?;
+
+        let local = local_end.broadcast_gt(&q_block)?;
+        let causal = k_pos.broadcast_le(&q_pos)?;
+        let vertical = self.vertical.index_select(&vertical_idx, 1)?.unsqueeze(1)?;
+        let attends = vertical.broadcast_maximum(&local)?.broadcast_mul(&causal)?;
+
+        let zero = Tensor::zeros((), dtype, device)?.broadcast_as(attends.shape())?;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?
+            .to_dtype(dtype)?
+            .broadcast_as(attends.shape())?;
+        let mask = attends.where_cond(&zero, &neg_inf)?;
+        if self.cfg.homo_head_pattern {
+            mask.squeeze(0)
+        } else {
+            Ok(mask)
+        }
+    }
+}
+
+struct Attention {
+    query_key_value: Arc<dyn QuantMethod>,
+    dense: Arc<dyn QuantMethod>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    rotary_emb: Arc<PhiRotaryEmbedding>,
+    sdpa_params: SdpaParams,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Arc<PhiRotaryEmbedding>,
+        cfg: &Config,
+        vb: ShardedVarBuilder,
+        blocksparse: bool,
+    ) -> Result<Self> {
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let head_dim = cfg.head_dim();
+
+        // No TP here.
+        let query_key_value = mistralrs_quant::linear(
+            cfg.hidden_size,
+            (num_heads + 2 * num_kv_heads) * head_dim,
+            &cfg.quantization_config,
+            vb.pp("query_key_value"),
+        )?;
+        let dense = mistralrs_quant::linear(
+            num_heads * head_dim,
+            cfg.hidden_size,
+            &cfg.quantization_config,
+            vb.pp("dense"),
+        )?;
+
+        let softmax_scale = if cfg.mup_use_scaling {
+            cfg.mup_attn_multiplier as f32 / head_dim as f32
+        } else {
+            1.0 / (head_dim as f32).sqrt()
+        };
+        Ok(Self {
+            query_key_value,
+            dense,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            rotary_emb,
+            sdpa_params: SdpaParams {
+                n_kv_groups: num_heads / num_kv_heads,
+                // Flash attention only applies a causal mask, not the blocksparse one.
+                use_flash_attn: cfg.use_flash_attn && !blocksparse,
+                softcap: None,
+                softmax_scale,
+                sliding_window: None,
+                head_scales: None,
+            },
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        position_ids: &[usize],
+        kv_cache: &mut KvCache,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.query_key_value.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let mut qkv = MatMul.qmethod_matmul(&xs, &*self.query_key_value)?;
+        if self.query_key_value.quantized_act_type().is_some() {
+            qkv = qkv.to_dtype(original_dtype)?;
+        }
+        // Each KV head is stored after the query heads that share it.
+        let q_per_kv = self.num_heads / self.num_kv_heads;
+        let qkv = qkv.reshape((b_sz, q_len, self.num_kv_heads, q_per_kv + 2, self.head_dim))?;
+        let q = qkv
+            .narrow(3, 0, q_per_kv)?
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = qkv
+            .narrow(3, q_per_kv, 1)?
+            .squeeze(3)?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = qkv
+            .narrow(3, q_per_kv + 1, 1)?
+            .squeeze(3)?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (q, k) = self
+            .rotary_emb
+            .forward(&q, &k, seqlen_offsets, position_ids)?;
+
+        let (k, v) = kv_cache.append(&k, &v)?;
+        let mut attn_output = Sdpa.run_attention(
+            &q,
+            &k,
+            &v,
+            attention_mask,
+            Some(flash_params),
+            &self.sdpa_params,
+        )?;
+
+        if let Some(t) = self.query_key_value.quantized_act_type() {
+            attn_output = attn_output.to_dtype(t)?;
+        }
+        let attn_output = attn_output.transpose(1, 2)?.reshape((b_sz, q_len, ()))?;
+        let mut res = MatMul.qmethod_matmul(&attn_output, &*self.dense)?;
+        if self.query_key_value.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+struct Mlp {
+    up_proj: Arc<dyn QuantMethod>,
+    down_proj: Arc<dyn QuantMethod>,
+    gegelu_limit: f64,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: ShardedVarBuilder) -> Result<Self> {
+        // No TP here.
+        let up_proj = mistralrs_quant::linear(
+            cfg.hidden_size,
+            2 * cfg.ff_intermediate_size,
+            &cfg.quantization_config,
+            vb.pp("up_proj"),
+        )?;
+        let down_proj = mistralrs_quant::linear(
+            cfg.ff_intermediate_size,
+            cfg.hidden_size,
+            &cfg.quantization_config,
+            vb.pp("down_proj"),
+        )?;
+        Ok(Self {
+            up_proj,
+            down_proj,
+            gegelu_limit: cfg.gegelu_limit,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.up_proj.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        // GeGELU: the even features are gated by the odd ones, both clamped to `gegelu_limit`.
+        let up_states = MatMul.qmethod_matmul(&xs, &*self.up_proj)?;
+        let (b_sz, seq_len, _) = up_states.dims3()?;
+        let up_states = up_states.reshape((b_sz, seq_len, (), 2))?;
+        let gelu = up_states
+            .narrow(D::Minus1, 0, 1)?
+            .squeeze(D::Minus1)?
+            .minimum(self.gegelu_limit)?;
+        let linear = up_states
+            .narrow(D::Minus1, 1, 1)?
+            .squeeze(D::Minus1)?
+            .clamp(-self.gegelu_limit, self.gegelu_limit)?;
+        let up_states = (gelu.apply(&Activation::QuickGelu)? * (linear + 1.)?)?;
+        let mut res = MatMul.qmethod_matmul(&up_states, &*self.down_proj)?;
+        if self.up_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    input_layernorm: LayerNorm,
+    post_attention_layernorm: LayerNorm,
+    blocksparse: bool,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<PhiRotaryEmbedding>,
+        cfg: &Config,
+        vb: ShardedVarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let blocksparse = cfg.is_blocksparse_layer(layer_idx);
+        let self_attn = Attention::new(
+            rotary_emb,
+            cfg,
+            mapper.set_device(layer_idx, vb.pp("self_attn"), loading_isq),
+            blocksparse,
+        )?;
+        let mlp = Mlp::new(cfg, mapper.set_device(layer_idx, vb.pp("mlp"), loading_isq))?;
+        let input_layernorm = layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_device(layer_idx, vb.pp("input_layernorm"), false),
+        )?;
+        let post_attention_layernorm = layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_device(layer_idx, vb.pp("post_attention_layernorm"), false),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+            blocksparse,
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        position_ids: &[usize],
+        kv_cache: &mut KvCache,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(
+            &xs,
+            attention_mask,
+            seqlen_offsets,
+            position_ids,
+            kv_cache,
+            flash_params,
+        )?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = self
+            .mlp
+            .forward(&xs.apply(&self.post_attention_layernorm)?)?;
+        residual + xs
+    }
+}
+
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    mup_embedding_multiplier: f64,
+    layers: Vec<DecoderLayer>,
+    final_layernorm: LayerNorm,
+    lm_head: Arc<dyn QuantMethod>,
+    mup_width_multiplier: f64,
+    blocksparse: Option<BlocksparseLayout>,
+    device: Device,
+    cache: EitherCache,
+    max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+    cfg: ModelConfigMetadata,
+}
+
+impl Model {
+    pub fn new(
+        cfg: &Config,
+        vb: ShardedVarBuilder,
+        _is_gptx: bool,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Self> {
+        if let Some(ref quant_cfg) = &cfg.quantization_config {
+            tracing::info!(
+                "Using {} quantization: {}.",
+                quant_cfg.name(),
+                quant_cfg.get_bits_name(&vb)
+            );
+        }
+        if matches!(attention_mechanism, AttentionImplementation::PagedAttention) {
+            candle_core::bail!("Phi-3-small does not support PagedAttention.");
+        }
+        let mapper = normal_loading_metadata.mapper;
+        let vb_m = vb.pp("model");
+
+        let embed_tokens = embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_m.pp("embed_tokens"), false),
+            &cfg.quantization_config,
+        )?;
+        let mut ropes = HashMap::new();
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let device = mapper
+                .device_for(layer_idx, false)
+                .unwrap_or(&normal_loading_metadata.real_device);
+            ropes.insert(
+                device.location(),
+                Arc::new(PhiRotaryEmbedding::new(vb.dtype(), cfg.clone(), device)?),
+            );
+        }
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..cfg.num_hidden_layers,
+            "Loading repeating layers",
+            &normal_loading_metadata.multi_progress,
+        ) {
+            let device = mapper
+                .device_for(layer_idx, false)
+                .unwrap_or(&normal_loading_metadata.real_device);
+            let rotary_emb = ropes
+                .get(&device.location())
+                .expect("No RoPE for device location!")
+                .clone();
+            let layer = DecoderLayer::new(
+                rotary_emb,
+                cfg,
+                vb_l.pp(layer_idx),
+                &*mapper,
+                layer_idx,
+                normal_loading_metadata.loading_isq,
+            )?;
+            layers.push(layer)
+        }
+        let final_layernorm = layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_nm_device(vb_m.pp("final_layernorm"), false),
+        )?;
+        let lm_head = if !cfg.tie_word_embeddings {
+            ReplicatedLayer::new(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                &None,
+                false,
+                mapper.set_nm_device(vb.pp("lm_head"), normal_loading_metadata.loading_isq),
+            )?
+        } else {
+            ReplicatedLayer::from_linear(candle_nn::Linear::new(
+                mapper.cast_nm_device(
+                    embed_tokens.embeddings(),
+                    normal_loading_metadata.loading_isq,
+                )?,
+                None,
+            ))?
+        };
+        let blocksparse = if layers.iter().any(|layer| layer.blocksparse) {
+            Some(BlocksparseLayout::new(
+                cfg.blocksparse(),
+                cfg.num_attention_heads,
+                &normal_loading_metadata.real_device,
+            )?)
+        } else {
+            None
+        };
+        Ok(Self {
+            embed_tokens,
+            mup_embedding_multiplier: cfg.mup_embedding_multiplier,
+            layers,
+            final_layernorm,
+            lm_head,
+            mup_width_multiplier: cfg.mup_width_multiplier,
+            blocksparse,
+            device: normal_loading_metadata.real_device,
+            cache: EitherCache::Normal(NormalCache::new(
+                cfg.num_hidden_layers,
+                cfg.max_position_embeddings,
+            )),
+            max_seq_len: cfg.max_position_embeddings,
+            cfg: ModelConfigMetadata {
+                max_seq_len: cfg.max_position_embeddings,
+                num_layers: cfg.num_hidden_layers,
+                hidden_size: cfg.hidden_size,
+                num_attn_heads: cfg.num_attention_heads,
+                num_kv_heads: cfg.num_key_value_heads,
+                sliding_window: None,
+                k_head_dim: cfg.head_dim(),
+                v_head_dim: cfg.head_dim(),
+            },
+            mapper,
+        })
+    }
+
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        position_ids: &[usize],
+        context_lens: Vec<(usize, usize)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        if self.mup_embedding_multiplier > 0. {
+            xs = (xs * self.mup_embedding_multiplier)?;
+        }
+        let cache = &mut self.cache.normal().0;
+        let attention_mask = CausalMasker.make_causal_mask_matrix(
+            input_ids,
+            &*cache as &dyn PastKvLenCache,
+            xs.dtype(),
+            self.cfg.num_attn_heads,
+        )?;
+        // Unlike the causal mask, this is also needed when decoding a single token. It is built once
+        // per forward pass and shared by all blocksparse layers.
+        let blocksparse_mask = match &self.blocksparse {
+            Some(layout) => {
+                let (b_sz, q_len) = input_ids.dims2()?;
+                let kv_len = (&*cache as &dyn PastKvLenCache).get_past_kv_len()? + q_len;
+                let mask = layout.mask(q_len, kv_len, xs.dtype())?;
+                let mask = if mask.rank() == 3 {
+                    mask.unsqueeze(0)?
+                        .broadcast_as((b_sz, self.cfg.num_attn_heads, q_len, kv_len))?
+                        .contiguous()?
+                } else {
+                    mask
+                };
+                Some(mask)
+            }
+            None => None,
+        };
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            let mask = if layer.blocksparse {
+                &blocksparse_mask
+            } else {
+                &attention_mask
+            };
+            xs = layer.forward(
+                &xs,
+                mask.as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                seqlen_offsets,
+                position_ids,
+                &mut cache[i],
+                flash_params,
+            )?
+        }
+        let xs = xs.to_device(&self.device)?;
+        let mut xs = xs.apply(&self.final_layernorm)?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let logits = (MatMul.qmethod_matmul(&xs, &*self.lm_head)? / self.mup_width_multiplier)?;
+        extract_logits(&logits, context_lens)
+    }
+}
+
+impl IsqModel for Model {
+    fn get_layers(
+        &mut self,
+    ) -> (
+        Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+        &dyn DeviceMapper,
+    ) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((&mut layer.self_attn.query_key_value, Some(i)));
+            tensors.push((&mut layer.self_attn.dense, Some(i)));
+            tensors.push((&mut layer.mlp.up_proj, Some(i)));
+            tensors.push((&mut layer.mlp.down_proj, Some(i)));
+        }
+        (tensors, &*self.mapper)
+    }
+
+    fn residual_tensors(&self) -> Vec<(String, Tensor)> {
+        let uvb = UnVarBuilder::new();
+
+        let uvb_m = uvb.pp("model");
+        uvb_m.pp("embed_tokens").add(&self.embed_tokens);
+        uvb_m.pp("final_layernorm").add(&self.final_layernorm);
+
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            let uvb_l = uvb_m.pp("layers").pp(layer_idx);
+            uvb_l.pp("input_layernorm").add(&layer.input_layernorm);
+            uvb_l
+                .pp("post_attention_layernorm")
+                .add(&layer.post_attention_layernorm);
+        }
+
+        uvb.to_safetensors()
+    }
+}
+
+impl NormalModel for Model {
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        context_lens: Vec<(usize, usize)>,
+        position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward(
+            input_ids,
+            seqlen_offsets,
+            &position_ids,
+            context_lens,
+            flash_params,
+        )
+    }
+    fn xlora_forward(
+        &self,
+        _input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        _seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        _no_kv_cache: bool,
+        _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _flash_params: &FlashParams,
+        _flash_params_full: &FlashParams,
+    ) -> Result<Tensor> {
+        unimplemented!()
+    }
+    fn cache(&self) -> &EitherCache {
+        &self.cache
+    }
+    fn cache_mut(&mut self) -> &mut EitherCache {
+        &mut self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        false
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+    fn config(&self) -> &ModelConfigMetadata {
+        &self.cfg
+    }
+}
+
+impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, IndexOp, Tensor, D};
+
+    use super::{BlocksparseConfig, BlocksparseLayout};
+    use crate::{attention::SdpaParams, layers::Sdpa};
+
+    const SEQ_LEN: usize = 8;
+
+    /// Blocks of 2 positions, 2 local blocks and every 2nd block as a vertical block.
+    fn blocksparse(homo_head_pattern: bool) -> BlocksparseConfig {
+        BlocksparseConfig {
+            block_size: 2,
+            num_local_blocks: 2,
+            vert_stride: 2,
+            homo_head_pattern,
+        }
+    }
+
+    fn pattern(mask: &Tensor) -> candle_core::Result<Vec<String>> {
+        Ok(mask
+            .to_vec2::<f32>()?
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|x| if x.is_finite() { '1' } else { '0' })
+                    .collect()
+            })
+            .collect())
+    }
+
+    #[test]
+    fn blocksparse_mask_matches_reference_pattern() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let layout = BlocksparseLayout::new(blocksparse(true), 2, &dev)?;
+        let mask = layout.mask(SEQ_LEN, SEQ_LEN, DType::F32)?;
+        // Block 3 sees the local blocks 2 and 3 and the vertical block 1, but not block 0.
+        let reference = [
+            "10000000", "11000000", "11100000", "11110000", "00111000", "00111100", "00111110",
+            "00111111",
+        ];
+        assert_eq!(pattern(&mask)?, reference);
+
+        // Decoding the last token only uses the last row.
+        let last = layout.mask(1, SEQ_LEN, DType::F32)?;
+        assert_eq!(pattern(&last)?, [reference[SEQ_LEN - 1]]);
+
+        // The second head shifts its vertical blocks to blocks 0 and 2.
+        let per_head = BlocksparseLayout::new(blocksparse(false), 2, &dev)?.mask(
+            SEQ_LEN,
+            SEQ_LEN,
+            DType::F32,
+        )?;
+        assert_eq!(pattern(&per_head.get(0)?)?, reference);
+        assert_eq!(pattern(&per_head.get(1)?)?[4], "11111000");
+        assert_eq!(pattern(&per_head.get(1)?)?[6], "11001110");
+
+        // The masked dense attention only mixes the values of the attended positions.
+        let q = Tensor::randn(0f32, 1., (1, 1, SEQ_LEN, 4), &dev)?;
+        let k = Tensor::randn(0f32, 1., (1, 1, SEQ_LEN, 4), &dev)?;
+        let v = Tensor::randn(0f32, 1., (1, 1, SEQ_LEN, 4), &dev)?;
+        let params = SdpaParams {
+            n_kv_groups: 1,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: 0.5,
+            sliding_window: None,
+            head_scales: None,
+        };
+        let out = Sdpa.run_attention(&q, &k, &v, Some(&mask), None, &params)?;
+        for (i, row) in reference.iter().enumerate() {
+            let attended = row
+                .char_indices()
+                .filter(|(_, c)| *c == '1')
+                .map(|(j, _)| j as u32)
+                .collect::<Vec<_>>();
+            let attended = Tensor::new(attended, &dev)?;
+            let (k, v) = (k.i((0, 0))?, v.i((0, 0))?);
+            let scores = (q
+                .i((0, 0, i))?
+                .unsqueeze(0)?
+                .matmul(&k.index_select(&attended, 0)?.t()?)?
+                * 0.5)?;
+            let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+            let expected = probs.matmul(&v.index_select(&attended, 0)?)?.squeeze(0)?;
+            let diff = (out.i((0, 0, i))? - expected)?
+                .abs()?
+                .max(D::Minus1)?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-5, "row {i}: {diff}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn blocksparse_mask_matches_dense_layout_for_chunks() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (num_heads, kv_len) = (4, 70);
+        for homo_head_pattern in [true, false] {
+            let cfg = BlocksparseConfig {
+                block_size: 4,
+                num_local_blocks: 3,
+                vert_stride: 5,
+                homo_head_pattern,
+            };
+            let layout = BlocksparseLayout::new(cfg, num_heads, &dev)?;
+            // A prompt chunk after a cached prefix, and a single decoded token.
+            for q_len in [13, 1] {
+                let mask = layout.mask(q_len, kv_len, DType::F32)?;
+                let mask = if homo_head_pattern {
+                    mask.unsqueeze(0)?
+                } else {
+                    mask
+                };
+                let mask = mask.to_vec3::<f32>()?;
+                for (head, rows) in mask.iter().enumerate() {
+                    for (row, q_pos) in rows.iter().zip(kv_len - q_len..) {
+                        for (k_pos, x) in row.iter().enumerate() {
+                            let (q_block, k_block) = (q_pos / 4, k_pos / 4);
+                            let attends = k_pos <= q_pos
+                                && (q_block - k_block < 3
+                                    || cfg.is_vertical(num_heads, head, k_block));
+                            assert_eq!(x.is_finite(), attends, "head {head}, {q_pos} -> {k_pos}");
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -302,6 +302,7 @@ impl ModelConfig::FromGGML for ModelWeights {
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                     sliding_window: None,
+                    head_scales: None,
                 },
                 dtype,
             })
@@ -625,6 +626,7 @@ impl ModelConfig::FromGGUF for ModelWeights {
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                     sliding_window: None,
+                    head_scales: None,
                 },
                 dtype,
             })
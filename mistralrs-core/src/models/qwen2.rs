@@ -12,7 +12,10 @@ use crate::{
     attention::SdpaParams,
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
-    layers::{embedding, Activation, CausalMasker, MatMul, Mlp, RmsNorm, RotaryEmbedding, Sdpa},
+    layers::{
+        embedding, Activation, CausalMasker, MatMul, Mlp, RmsNorm, RopeScalingConfig,
+        RotaryEmbedding, Sdpa,
+    },
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
@@ -37,6 +40,10 @@ pub struct Config {
     pub num_key_value_heads: usize,
     pub max_position_embeddings: usize,
     pub sliding_window: Option<usize>,
+    #[serde(default)]
+    pub use_sliding_window: bool,
+    #[serde(default)]
+    pub max_window_layers: Option<usize>,
     pub rope_theta: f64,
     pub rms_norm_eps: f64,
     pub hidden_act: Activation,
@@ -45,6 +52,16 @@ pub struct Config {
     pub quantization_config: Option<QuantizedConfig>,
     #[serde(default = "word_emb_default")]
     pub tie_word_embeddings: bool,
+    pub rope_scaling: Option<RopeScalingConfig>,
+}
+
+impl Config {
+    /// The sliding window of layer `layer_idx`. If `use_sliding_window` is set, the first `max_window_layers`
+    /// layers use full attention and the rest use the window, as in the reference implementation.
+    pub fn layer_sliding_window(&self, layer_idx: usize) -> Option<usize> {
+        self.sliding_window
+            .filter(|_| self.use_sliding_window && layer_idx >= self.max_window_layers.unwrap_or(0))
+    }
 }
 
 struct Attention {
@@ -65,6 +82,7 @@ impl Attention {
         rotary_emb: Arc<RotaryEmbedding>,
         cfg: &Config,
         vb: ShardedVarBuilder,
+        layer_idx: usize,
         paged_attn: Option<PagedAttention>,
         comm: &Arc<mistralrs_quant::Comm>,
     ) -> Result<Self> {
@@ -130,7 +148,8 @@ impl Attention {
                 use_flash_attn: cfg.use_flash_attn,
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
-                sliding_window: cfg.sliding_window,
+                sliding_window: cfg.layer_sliding_window(layer_idx),
+                head_scales: None,
             },
         })
     }
@@ -266,6 +285,7 @@ impl DecoderLayer {
             rotary_emb,
             cfg,
             mapper.set_device(layer_idx, vb.pp("self_attn"), loading_isq),
+            layer_idx,
             paged_attn,
             comm,
         )?;
@@ -315,11 +335,10 @@ impl DecoderLayer {
             metadata,
             flash_params,
         )?;
-        let xs = (xs + residual)?;
-        let residual = &xs;
-        let xs = self
-            .mlp
-            .forward(&xs.apply(&self.post_attention_layernorm)?)?;
+        let (residual, xs) = self
+            .post_attention_layernorm
+            .forward_residual(&xs, residual)?;
+        let xs = self.mlp.forward(&xs)?;
         residual + xs
     }
 }
@@ -364,22 +383,44 @@ impl Model {
         let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
         let head_dim = cfg.hidden_size / cfg.num_attention_heads;
 
+        let dynamic_ntk_factor = cfg
+            .rope_scaling
+            .as_ref()
+            .and_then(RopeScalingConfig::dynamic_ntk_factor);
+        if cfg.rope_scaling.is_some() && dynamic_ntk_factor.is_none() {
+            tracing::warn!(
+                "Only dynamic NTK `rope_scaling` with a `factor` is supported, using unscaled RoPE."
+            );
+        }
         let mut ropes = HashMap::new();
         for layer_idx in 0..cfg.num_hidden_layers {
             let device = mapper
                 .device_for(layer_idx, false)
                 .unwrap_or(&normal_loading_metadata.real_device);
-            ropes.insert(
-                device.location(),
-                Arc::new(RotaryEmbedding::new(
+            let rope = match dynamic_ntk_factor {
+                Some(factor) => RotaryEmbedding::new_dynamic_ntk(
                     cfg.rope_theta as f32,
+                    factor,
+                    cfg.rope_scaling
+                        .as_ref()
+                        .and_then(|s| s.original_max_position_embeddings)
+                        .unwrap_or(cfg.max_position_embeddings),
                     head_dim,
                     cfg.max_position_embeddings,
                     device,
                     is_gptx,
                     vb_m.dtype(),
-                )?),
-            );
+                )?,
+                None => RotaryEmbedding::new(
+                    cfg.rope_theta as f32,
+                    head_dim,
+                    cfg.max_position_embeddings,
+                    device,
+                    is_gptx,
+                    vb_m.dtype(),
+                )?,
+            };
+            ropes.insert(device.location(), Arc::new(rope));
         }
 
         let vb_l = vb_m.pp("layers");
@@ -441,7 +482,7 @@ impl Model {
             layers,
             norm,
             lm_head,
-            sliding_window: cfg.sliding_window,
+            sliding_window: cfg.sliding_window.filter(|_| cfg.use_sliding_window),
             device: normal_loading_metadata.real_device,
             cache: EitherCache::Normal(NormalCache::new(
                 cfg.num_hidden_layers,
@@ -455,7 +496,8 @@ impl Model {
                 num_attn_heads: cfg.num_attention_heads / mapper.get_comm_for(0)?.world_size(),
                 num_kv_heads: (cfg.num_key_value_heads / mapper.get_comm_for(0)?.world_size())
                     .max(1),
-                sliding_window: cfg.sliding_window,
+                // Only set if every layer uses the window.
+                sliding_window: cfg.layer_sliding_window(0),
                 k_head_dim: cfg.hidden_size / cfg.num_attention_heads,
                 v_head_dim: cfg.hidden_size / cfg.num_attention_heads,
             },
@@ -497,28 +539,35 @@ impl Model {
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let cache = &mut self.cache.normal().0;
-        let attention_mask = CausalMasker.make_sliding_window_causal_mask_matrix(
-            input_ids,
-            metadata
-                .as_ref()
-                .map(|(_, _)| &seqlen_offsets as &dyn PastKvLenCache)
-                .unwrap_or(cache as &dyn PastKvLenCache),
-            self.sliding_window,
-            xs.dtype(),
-            self.cfg.num_attn_heads,
-        )?;
-        let attention_mask = attention_mask.filter(|_| {
-            metadata
-                .as_ref()
-                .map(|(_, meta)| meta.is_first_prompt_chunk)
-                .unwrap_or(true)
-        });
+        let make_mask = |sliding_window: Option<usize>| -> Result<Option<Tensor>> {
+            let mask = CausalMasker.make_sliding_window_causal_mask_matrix(
+                input_ids,
+                metadata
+                    .as_ref()
+                    .map(|(_, _)| &seqlen_offsets as &dyn PastKvLenCache)
+                    .unwrap_or(&*cache as &dyn PastKvLenCache),
+                sliding_window,
+                xs.dtype(),
+                self.cfg.num_attn_heads,
+            )?;
+            Ok(mask.filter(|_| {
+                metadata
+                    .as_ref()
+                    .map(|(_, meta)| meta.is_first_prompt_chunk)
+                    .unwrap_or(true)
+            }))
+        };
+        let attention_mask = make_mask(None)?;
+        let sliding_attention_mask = match self.sliding_window {
+            Some(sliding_window) => make_mask(Some(sliding_window))?,
+            None => None,
+        };
         for (i, layer) in self.layers.iter().enumerate() {
             xs = self.mapper.map(xs, i)?;
+            let mask = self.layer_attention_mask(i, &attention_mask, &sliding_attention_mask);
             xs = layer.forward(
                 &xs,
-                attention_mask
-                    .as_ref()
+                mask.as_ref()
                     .map(|m| m.to_device(xs.device()).unwrap())
                     .as_ref(),
                 seqlen_offsets,
@@ -537,6 +586,26 @@ impl Model {
         extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
     }
 
+    /// The mask of layer `layer_idx`: the sliding window mask if the layer uses the window, the
+    /// full causal mask otherwise.
+    fn layer_attention_mask<'a>(
+        &self,
+        layer_idx: usize,
+        attention_mask: &'a Option<Tensor>,
+        sliding_attention_mask: &'a Option<Tensor>,
+    ) -> &'a Option<Tensor> {
+        if self.layers[layer_idx]
+            .self_attn
+            .sdpa_params
+            .sliding_window
+            .is_some()
+        {
+            sliding_attention_mask
+        } else {
+            attention_mask
+        }
+    }
+
     pub fn embed_dtype(&self) -> DType {
         self.embed_tokens.embeddings().dtype()
     }
@@ -776,3 +845,121 @@ impl AnyMoeBaseModelMixin for Model {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{Config, Model};
+    use crate::{
+        paged_attention::AttentionImplementation,
+        pipeline::{NormalLoadingMetadata, WeightSource},
+        DeviceMapSetting,
+    };
+
+    const CONFIG: &str = r#"{"vocab_size": 32, "hidden_size": 16, "intermediate_size": 32,
+        "num_hidden_layers": 4, "num_attention_heads": 2, "num_key_value_heads": 2,
+        "max_position_embeddings": 64, "sliding_window": 8, "rope_theta": 10000.0,
+        "rms_norm_eps": 1e-6, "hidden_act": "silu", "quantization_config": null,
+        "rope_scaling": null"#;
+
+    fn layer_windows(extra: &str) -> Vec<Option<usize>> {
+        let cfg: Config = serde_json::from_str(&format!("{CONFIG}{extra}}}")).unwrap();
+        (0..cfg.num_hidden_layers)
+            .map(|i| cfg.layer_sliding_window(i))
+            .collect()
+    }
+
+    #[test]
+    fn max_window_layers_selects_the_windowed_layers() {
+        // The first `max_window_layers` layers use full attention.
+        assert_eq!(
+            layer_windows(r#", "use_sliding_window": true, "max_window_layers": 2"#),
+            [None, None, Some(8), Some(8)]
+        );
+        assert_eq!(
+            layer_windows(r#", "use_sliding_window": true"#),
+            [Some(8); 4]
+        );
+        // The window is ignored unless `use_sliding_window` is set, as in most Qwen2 checkpoints.
+        assert_eq!(
+            layer_windows(r#", "use_sliding_window": false, "max_window_layers": 2"#),
+            [None; 4]
+        );
+        assert_eq!(layer_windows(""), [None; 4]);
+    }
+
+    /// A model for `CONFIG` with random weights.
+    fn tiny_model(extra: &str) -> anyhow::Result<Model> {
+        let cfg: Config = serde_json::from_str(&format!(
+            r#"{CONFIG}, "tie_word_embeddings": true{extra}}}"#
+        ))?;
+        let dev = Device::Cpu;
+        let mut shapes = vec![
+            ("model.embed_tokens.weight".to_string(), vec![32, 16]),
+            ("model.norm.weight".to_string(), vec![16]),
+        ];
+        for i in 0..cfg.num_hidden_layers {
+            let layer = format!("model.layers.{i}");
+            for proj in ["q_proj", "k_proj", "v_proj"] {
+                shapes.push((format!("{layer}.self_attn.{proj}.weight"), vec![16, 16]));
+                shapes.push((format!("{layer}.self_attn.{proj}.bias"), vec![16]));
+            }
+            shapes.push((format!("{layer}.self_attn.o_proj.weight"), vec![16, 16]));
+            shapes.push((format!("{layer}.mlp.gate_proj.weight"), vec![32, 16]));
+            shapes.push((format!("{layer}.mlp.up_proj.weight"), vec![32, 16]));
+            shapes.push((format!("{layer}.mlp.down_proj.weight"), vec![16, 32]));
+            shapes.push((format!("{layer}.input_layernorm.weight"), vec![16]));
+            shapes.push((format!("{layer}.post_attention_layernorm.weight"), vec![16]));
+        }
+        let weights = shapes
+            .into_iter()
+            .map(|(name, shape)| Ok((name, Tensor::randn(0f32, 1., shape, &dev)?)))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let buffer = safetensors::tensor::serialize(weights.iter().map(|(n, t)| (n, t)), &None)?;
+        let vb = WeightSource::SafetensorsBuffers(vec![buffer]).into_var_builder(
+            DType::F32,
+            &dev,
+            true,
+        )?;
+        Ok(Model::new(
+            &cfg,
+            vb,
+            true,
+            NormalLoadingMetadata {
+                mapper: DeviceMapSetting::dummy().into_mapper(cfg.num_hidden_layers, &dev, None)?,
+                loading_isq: false,
+                real_device: dev.clone(),
+                multi_progress: std::sync::Arc::new(indicatif::MultiProgress::new()),
+            },
+            AttentionImplementation::Eager,
+        )?)
+    }
+
+    #[test]
+    fn layers_attend_with_their_own_mask() -> anyhow::Result<()> {
+        let full = Some(Tensor::zeros((4, 4), DType::F32, &Device::Cpu)?);
+        let sliding = Some(Tensor::zeros((4, 4), DType::F32, &Device::Cpu)?);
+        let masks = |model: &Model| {
+            (0..model.layers.len())
+                .map(|i| {
+                    let mask = model.layer_attention_mask(i, &full, &sliding).as_ref();
+                    mask.map(|mask| mask.id())
+                })
+                .collect::<Vec<_>>()
+        };
+        let (full_id, sliding_id) = (
+            full.as_ref().map(Tensor::id),
+            sliding.as_ref().map(Tensor::id),
+        );
+
+        let model = tiny_model(r#", "use_sliding_window": true, "max_window_layers": 2"#)?;
+        assert_eq!(masks(&model), [full_id, full_id, sliding_id, sliding_id]);
+        assert_eq!(model.sliding_window, Some(8));
+
+        let model = tiny_model(r#", "use_sliding_window": false, "max_window_layers": 2"#)?;
+        assert_eq!(masks(&model), [full_id; 4]);
+        assert_eq!(model.sliding_window, None);
+        Ok(())
+    }
+}
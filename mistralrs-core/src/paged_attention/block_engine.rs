@@ -3,10 +3,13 @@ use std::{
     hash::Hash,
     marker::PhantomData,
     ops::Deref,
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
-use super::block_engine_sequence::BlockEngineSequence;
+use super::{block_engine_sequence::BlockEngineSequence, BlockUsage};
 
 pub struct LogicalTokenBlock {
     tokens: Vec<usize>,
@@ -103,6 +106,8 @@ impl Deref for GPUAllocatorWrapper {
 
 struct Allocator<T> {
     free_blocks: BlockTable,
+    num_blocks: usize,
+    used: Arc<AtomicUsize>,
     _ghost: PhantomData<T>,
 }
 
@@ -110,9 +115,17 @@ impl<T> Allocator<T> {
     fn allocate(&mut self) -> Arc<PhysicalTokenBlock> {
         let block = self.free_blocks.pop().unwrap();
         block.deref_mut().refcount = 1;
+        self.update_usage();
         block
     }
 
+    fn update_usage(&self) {
+        self.used.store(
+            self.num_blocks.saturating_sub(self.free_blocks.len()),
+            Ordering::Relaxed,
+        );
+    }
+
     fn free_block(&mut self, block: Arc<PhysicalTokenBlock>) {
         if block.deref_mut().refcount == 0 {
             panic!(
@@ -123,12 +136,13 @@ impl<T> Allocator<T> {
         block.deref_mut().refcount -= 1;
         if block.deref_mut().refcount == 0 {
             self.free_blocks.push(block);
+            self.update_usage();
         }
     }
 }
 
 impl Allocator<GPUAllocator> {
-    fn new(block_size: usize, num_blocks: usize) -> Self {
+    fn new(block_size: usize, num_blocks: usize, used: Arc<AtomicUsize>) -> Self {
         let mut free_blocks = Vec::new();
         for id in 0..num_blocks {
             free_blocks.push(Arc::new(PhysicalTokenBlock(Mutex::new(
@@ -140,8 +154,11 @@ impl Allocator<GPUAllocator> {
                 },
             ))))
         }
+        used.store(0, Ordering::Relaxed);
         Allocator {
             free_blocks,
+            num_blocks,
+            used,
             _ghost: PhantomData,
         }
     }
@@ -152,7 +169,7 @@ impl Allocator<GPUAllocator> {
 }
 
 impl Allocator<CPUAllocator> {
-    fn new(block_size: usize, num_blocks: usize) -> Self {
+    fn new(block_size: usize, num_blocks: usize, used: Arc<AtomicUsize>) -> Self {
         let mut free_blocks = Vec::new();
         for id in 0..num_blocks {
             free_blocks.push(Arc::new(PhysicalTokenBlock(Mutex::new(
@@ -164,8 +181,11 @@ impl Allocator<CPUAllocator> {
                 },
             ))))
         }
+        used.store(0, Ordering::Relaxed);
         Allocator {
             free_blocks,
+            num_blocks,
+            used,
             _ghost: PhantomData,
         }
     }
@@ -194,11 +214,16 @@ pub type BlockTables = HashMap<usize, BlockTable>;
 
 impl BlockEngine {
     #[must_use]
-    pub fn new(block_size: usize, num_gpu_blocks: usize, num_cpu_blocks: usize) -> Self {
+    pub fn new(
+        block_size: usize,
+        num_gpu_blocks: usize,
+        num_cpu_blocks: usize,
+        usage: BlockUsage,
+    ) -> Self {
         Self {
             num_gpu_blocks,
-            gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks),
-            cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks),
+            gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks, usage.gpu),
+            cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks, usage.cpu),
             block_tables: HashMap::new(),
         }
     }
@@ -376,3 +401,61 @@ impl BlockEngine {
             .collect::<HashMap<_, _>>()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockEngine, BlockEngineSequence, BlockUsage};
+    use crate::paged_attention::CacheConfig;
+    use crate::PagedCacheType;
+
+    struct MockSeq {
+        id: usize,
+        len: usize,
+        block_size: usize,
+    }
+
+    impl BlockEngineSequence for MockSeq {
+        fn blocks_to_add_new_tok(&self) -> usize {
+            usize::from(self.len % self.block_size == 0)
+        }
+        fn get_id(&self) -> usize {
+            self.id
+        }
+        fn get_logical_token_blocks(&self) -> usize {
+            self.len.div_ceil(self.block_size)
+        }
+    }
+
+    #[test]
+    fn used_blocks_track_prefilled_sequences() {
+        let cache_config = CacheConfig {
+            block_size: 16,
+            num_gpu_blocks: 64,
+            num_cpu_blocks: 8,
+            cache_type: PagedCacheType::Auto,
+            layer_cache_types: Vec::new(),
+            usage: BlockUsage::default(),
+        };
+        let mut engine = BlockEngine::new(
+            cache_config.block_size,
+            cache_config.num_gpu_blocks,
+            cache_config.num_cpu_blocks,
+            cache_config.usage.clone(),
+        );
+
+        let seq = MockSeq {
+            id: 0,
+            len: 100,
+            block_size: 16,
+        };
+        engine.allocate(&seq);
+        let stats = cache_config.stats();
+        assert_eq!(stats.used_gpu_blocks, 100usize.div_ceil(16));
+        assert_eq!(stats.free_gpu_blocks, 64 - 100usize.div_ceil(16));
+        assert_eq!(stats.used_cpu_blocks, 0);
+        assert_eq!(stats.free_cpu_blocks, 8);
+
+        engine.free_sequence(0);
+        assert_eq!(cache_config.stats().used_gpu_blocks, 0);
+    }
+}
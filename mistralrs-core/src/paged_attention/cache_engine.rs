@@ -1,18 +1,71 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
 use candle_core::{DType, Device, Result, Tensor};
 use mistralrs_paged_attn::{copy_blocks, swap_blocks};
 
-use super::config::ModelConfigLike;
+use super::{config::ModelConfigLike, PagedCacheType, INT8_SCALE_PAD};
 
 #[derive(Clone, Debug)]
 pub struct CacheConfig {
     pub block_size: usize,
     pub num_gpu_blocks: usize,
     pub num_cpu_blocks: usize,
+    pub cache_type: PagedCacheType,
+    /// Per-layer overrides of `cache_type`, one entry per layer.
+    pub layer_cache_types: Vec<Option<PagedCacheType>>,
+    /// Number of blocks currently allocated from each pool. Shared with the [`super::BlockEngine`].
+    pub usage: BlockUsage,
+}
+
+impl CacheConfig {
+    /// Storage type of the KV cache blocks of this layer.
+    pub fn layer_cache_type(&self, layer: usize) -> PagedCacheType {
+        self.layer_cache_types
+            .get(layer)
+            .copied()
+            .flatten()
+            .unwrap_or(self.cache_type)
+    }
+
+    /// Snapshot of the current block usage.
+    pub fn stats(&self) -> PagedCacheStats {
+        let used_gpu_blocks = self.usage.gpu.load(Ordering::Relaxed);
+        let used_cpu_blocks = self.usage.cpu.load(Ordering::Relaxed);
+        PagedCacheStats {
+            block_size: self.block_size,
+            total_gpu_blocks: self.num_gpu_blocks,
+            used_gpu_blocks,
+            free_gpu_blocks: self.num_gpu_blocks.saturating_sub(used_gpu_blocks),
+            total_cpu_blocks: self.num_cpu_blocks,
+            used_cpu_blocks,
+            free_cpu_blocks: self.num_cpu_blocks.saturating_sub(used_cpu_blocks),
+        }
+    }
+}
+
+/// Counters of the allocated GPU and CPU blocks, updated by the block allocators.
+#[derive(Clone, Debug, Default)]
+pub struct BlockUsage {
+    pub(crate) gpu: Arc<AtomicUsize>,
+    pub(crate) cpu: Arc<AtomicUsize>,
+}
+
+/// Usage of the PagedAttention KV cache blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PagedCacheStats {
+    pub block_size: usize,
+    pub total_gpu_blocks: usize,
+    pub used_gpu_blocks: usize,
+    pub free_gpu_blocks: usize,
+    pub total_cpu_blocks: usize,
+    pub used_cpu_blocks: usize,
+    pub free_cpu_blocks: usize,
 }
 
 pub type KVCache = (Tensor, Tensor);
@@ -59,17 +112,20 @@ impl CacheEngine {
         device: &Device,
         layer_devices: Vec<Option<Device>>,
     ) -> Result<Vec<KVCache>> {
-        let key_block_shape =
-            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
         let mut gpu_cache = Vec::new();
 
-        for device in layer_devices
+        for (layer, device) in layer_devices
             .iter()
             .take(model_config.num_layers())
             .map(|x| x.as_ref().unwrap_or(device))
+            .enumerate()
         {
+            let cache_type = cache_config.layer_cache_type(layer);
+            let key_block_shape =
+                Self::calculate_key_block_shape(model_config, dtype, cache_config, cache_type);
+            let value_block_shape =
+                Self::calculate_value_block_shape(model_config, cache_config, cache_type);
+            let dtype = Self::storage_dtype(cache_type, dtype);
             let key_blocks = unsafe {
                 Tensor::empty(
                     (
@@ -106,12 +162,14 @@ impl CacheEngine {
         dtype: DType,
         device: &Device,
     ) -> Result<Vec<KVCache>> {
-        let key_block_shape =
-            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
         let mut cpu_cache = Vec::new();
-        for _ in 0..model_config.num_layers() {
+        for layer in 0..model_config.num_layers() {
+            let cache_type = cache_config.layer_cache_type(layer);
+            let key_block_shape =
+                Self::calculate_key_block_shape(model_config, dtype, cache_config, cache_type);
+            let value_block_shape =
+                Self::calculate_value_block_shape(model_config, cache_config, cache_type);
+            let dtype = Self::storage_dtype(cache_type, dtype);
             let key_blocks = unsafe {
                 Tensor::empty(
                     (
@@ -144,31 +202,49 @@ impl CacheEngine {
 }
 
 impl CacheEngine {
+    /// For int8 caches, `x` is still derived from `dtype` so that dequantized blocks can be handed
+    /// to the attention kernel without relayout.
     fn calculate_key_block_shape(
         model_config: &dyn ModelConfigLike,
         dtype: DType,
-        block_size: usize,
+        cache_config: &CacheConfig,
+        cache_type: PagedCacheType,
     ) -> (usize, usize, usize, usize) {
         let element_size = dtype.size_in_bytes();
         let x = 16 / element_size;
         (
             model_config.num_kv_heads(),
-            model_config.k_head_dim() / x,
-            block_size,
+            (model_config.k_head_dim() + Self::head_dim_pad(cache_type)) / x,
+            cache_config.block_size,
             x,
         )
     }
 
     fn calculate_value_block_shape(
         model_config: &dyn ModelConfigLike,
-        block_size: usize,
+        cache_config: &CacheConfig,
+        cache_type: PagedCacheType,
     ) -> (usize, usize, usize) {
         (
             model_config.num_kv_heads(),
-            model_config.v_head_dim(),
-            block_size,
+            model_config.v_head_dim() + Self::head_dim_pad(cache_type),
+            cache_config.block_size,
         )
     }
+
+    fn head_dim_pad(cache_type: PagedCacheType) -> usize {
+        match cache_type {
+            PagedCacheType::Auto => 0,
+            PagedCacheType::I8 => INT8_SCALE_PAD,
+        }
+    }
+
+    fn storage_dtype(cache_type: PagedCacheType, dtype: DType) -> DType {
+        match cache_type {
+            PagedCacheType::Auto => dtype,
+            PagedCacheType::I8 => DType::U8,
+        }
+    }
 }
 
 impl CacheEngine {
@@ -220,14 +296,139 @@ impl CacheEngine {
 
     pub fn copy(&self, src_to_dst: HashMap<usize, Vec<usize>>) -> Result<()> {
         let mut gpu_cache = self.get_kv_cache();
-        #[allow(clippy::map_identity)]
-        let caches: (Vec<&mut Tensor>, Vec<&mut Tensor>) =
-            gpu_cache.iter_mut().map(|(a, b)| (a, b)).unzip();
-        let (key_caches, value_caches) = caches;
+        // `copy_blocks` expects every cache to have the layout of the first one, so layers with
+        // different storage types are copied separately.
+        #[allow(clippy::type_complexity)]
+        let mut groups: Vec<(DType, Vec<&mut Tensor>, Vec<&mut Tensor>)> = Vec::new();
+        for (key_cache, value_cache) in gpu_cache.iter_mut() {
+            let dtype = key_cache.dtype();
+            match groups.iter_mut().find(|(ty, _, _)| *ty == dtype) {
+                Some((_, key_caches, value_caches)) => {
+                    key_caches.push(key_cache);
+                    value_caches.push(value_cache);
+                }
+                None => groups.push((dtype, vec![key_cache], vec![value_cache])),
+            }
+        }
+
+        for (_, key_caches, value_caches) in groups {
+            // NOTE(EricLBuehler): This may synchronize the CPU and GPU
+            copy_blocks(key_caches, value_caches, src_to_dst.clone())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, IndexOp, Tensor, D};
+
+    use super::{BlockUsage, CacheConfig, CacheEngine};
+    use crate::paged_attention::{
+        layers::int8_cache::{gather_dequantized, quantize_kv},
+        ModelConfigMetadata, PagedCacheType,
+    };
 
-        // NOTE(EricLBuehler): This may synchronize the CPU and GPU
-        copy_blocks(key_caches, value_caches, src_to_dst)?;
+    fn attention(q: &Tensor, k: &Tensor, v: &Tensor) -> candle_core::Result<Tensor> {
+        // q: [num_heads, head_size], k/v: [num_heads, head_size, num_tokens]
+        let scale = 1. / (q.dim(1)? as f64).sqrt();
+        let att = (q.unsqueeze(1)?.matmul(k)? * scale)?;
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        att.matmul(&v.transpose(1, 2)?.contiguous()?)?.squeeze(1)
+    }
+
+    #[test]
+    fn mixed_layer_cache_types() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (num_heads, head_size, block_size) = (2, 64, 16);
+        let model_config = ModelConfigMetadata {
+            max_seq_len: 128,
+            num_layers: 3,
+            hidden_size: num_heads * head_size,
+            num_kv_heads: num_heads,
+            num_attn_heads: num_heads,
+            sliding_window: None,
+            k_head_dim: head_size,
+            v_head_dim: head_size,
+        };
+        // Keep the first layer in f16 and store the rest as int8.
+        let cache_config = CacheConfig {
+            block_size,
+            num_gpu_blocks: 1,
+            num_cpu_blocks: 1,
+            cache_type: PagedCacheType::I8,
+            layer_cache_types: vec![Some(PagedCacheType::Auto)],
+            usage: BlockUsage::default(),
+        };
+        let engine = CacheEngine::new(
+            &model_config,
+            &cache_config,
+            DType::F16,
+            &dev,
+            vec![None; 3],
+        )?;
+        let x = 16 / DType::F16.size_in_bytes();
+
+        let k = (Tensor::randn(0f32, 1., (block_size, num_heads, head_size), &dev)? * 3.)?;
+        let v = Tensor::randn(0f32, 1., (block_size, num_heads, head_size), &dev)?;
+        let q = Tensor::randn(0f32, 1., (num_heads, head_size), &dev)?;
+        let reference = attention(
+            &q,
+            &k.permute((1, 2, 0))?.contiguous()?,
+            &v.permute((1, 2, 0))?,
+        )?;
+
+        let gpu_cache = engine.get_kv_cache();
+        for (layer, (key_cache, value_cache)) in gpu_cache.iter().enumerate() {
+            let expected = if layer == 0 { DType::F16 } else { DType::U8 };
+            assert_eq!(key_cache.dtype(), expected, "layer {layer}");
+            assert_eq!(value_cache.dtype(), expected, "layer {layer}");
 
+            // Store the keys and values in a single block, in this layer's format.
+            let (k_stored, v_stored) = if expected == DType::U8 {
+                (quantize_kv(&k)?, quantize_kv(&v)?)
+            } else {
+                (k.to_dtype(DType::F16)?, v.to_dtype(DType::F16)?)
+            };
+            let stored_head_size = k_stored.dim(D::Minus1)?;
+            let key_block = k_stored
+                .reshape((block_size, num_heads, stored_head_size / x, x))?
+                .permute((1, 2, 0, 3))?
+                .unsqueeze(0)?;
+            let value_block = v_stored.permute((1, 2, 0))?.unsqueeze(0)?;
+            assert_eq!(key_block.dims(), key_cache.dims(), "layer {layer}");
+            assert_eq!(value_block.dims(), value_cache.dims(), "layer {layer}");
+
+            let (key_block, value_block) = if expected == DType::U8 {
+                let block_tables = Tensor::new(&[[0u32]], &dev)?;
+                #[allow(clippy::cast_possible_truncation)]
+                let context_lens = Tensor::new(&[block_size as u32], &dev)?;
+                let (k, v, _) = gather_dequantized(
+                    &key_block,
+                    &value_block,
+                    &block_tables,
+                    &context_lens,
+                    DType::F16,
+                )?;
+                (k, v)
+            } else {
+                (key_block, value_block)
+            };
+            let k_read = key_block
+                .i(0)?
+                .permute((0, 1, 3, 2))?
+                .reshape((num_heads, head_size, block_size))?
+                .to_dtype(DType::F32)?;
+            let v_read = value_block.i(0)?.to_dtype(DType::F32)?;
+
+            let max_diff = (attention(&q, &k_read, &v_read)? - &reference)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(max_diff < 5e-2, "layer {layer}: max diff {max_diff}");
+        }
         Ok(())
     }
 }
@@ -0,0 +1,245 @@
+//! Int8 storage for the PagedAttention KV cache.
+//!
+//! Each `(token, head)` vector of size `head_size` is stored as `head_size + INT8_SCALE_PAD` bytes:
+//! the values offset by 128, followed by one byte encoding the scale and zero padding which keeps
+//! the key cache `x` grouping aligned. The scale is stored as a log-code with 16 steps per octave,
+//! which is rounded up so that quantized values do not clip. The largest code encodes a scale of
+//! about 15.3, so vectors with an absolute maximum above roughly 1946 are clipped to that range.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Result, Tensor, D};
+
+/// Number of bytes appended to each quantized head vector.
+pub(crate) const INT8_SCALE_PAD: usize = 16;
+
+const ZERO_POINT: f64 = 128.;
+const QMAX: f64 = 127.;
+const SCALE_CODE_STEPS: f64 = 16.;
+const SCALE_CODE_BIAS: f64 = 192.;
+
+/// Decode scale codes of any shape into f32 scales.
+fn decode_scale(codes: &Tensor) -> Result<Tensor> {
+    let step = std::f64::consts::LN_2 / SCALE_CODE_STEPS;
+    codes
+        .to_dtype(DType::F32)?
+        .affine(step, -SCALE_CODE_BIAS * step)?
+        .exp()
+}
+
+/// Quantize `[num_tokens, num_heads, head_size]` into `[num_tokens, num_heads, head_size + INT8_SCALE_PAD]` u8.
+pub(crate) fn quantize_kv(xs: &Tensor) -> Result<Tensor> {
+    let (num_tokens, num_heads, _) = xs.dims3()?;
+    let xs = xs.to_dtype(DType::F32)?;
+    let absmax = xs
+        .abs()?
+        .max_keepdim(D::Minus1)?
+        .maximum(f32::MIN_POSITIVE)?;
+    let codes = (absmax / QMAX)?
+        .log()?
+        .affine(SCALE_CODE_STEPS / std::f64::consts::LN_2, SCALE_CODE_BIAS)?
+        .ceil()?
+        .clamp(0f32, 255f32)?;
+    let quantized = xs
+        .broadcast_div(&decode_scale(&codes)?)?
+        .round()?
+        .clamp(-QMAX, QMAX)?
+        .affine(1., ZERO_POINT)?;
+    let padding = Tensor::zeros(
+        (num_tokens, num_heads, INT8_SCALE_PAD - 1),
+        DType::F32,
+        xs.device(),
+    )?;
+    Tensor::cat(&[quantized, codes, padding], D::Minus1)?.to_dtype(DType::U8)
+}
+
+/// Dequantize int8 key cache blocks of shape `[num_blocks, num_heads, (head_size + INT8_SCALE_PAD) / x, block_size, x]`
+/// into `[num_blocks, num_heads, head_size / x, block_size, x]`.
+pub(crate) fn dequantize_key_blocks(blocks: &Tensor, dtype: DType) -> Result<Tensor> {
+    let (_, _, padded_groups, _, x) = blocks.dims5()?;
+    let groups = padded_groups - INT8_SCALE_PAD / x;
+    let scale = decode_scale(&blocks.narrow(2, groups, 1)?.narrow(4, 0, 1)?)?;
+    blocks
+        .narrow(2, 0, groups)?
+        .to_dtype(DType::F32)?
+        .affine(1., -ZERO_POINT)?
+        .broadcast_mul(&scale)?
+        .to_dtype(dtype)
+}
+
+/// Dequantize int8 value cache blocks of shape `[num_blocks, num_heads, head_size + INT8_SCALE_PAD, block_size]`
+/// into `[num_blocks, num_heads, head_size, block_size]`.
+pub(crate) fn dequantize_value_blocks(blocks: &Tensor, dtype: DType) -> Result<Tensor> {
+    let (_, _, padded_head_size, _) = blocks.dims4()?;
+    let head_size = padded_head_size - INT8_SCALE_PAD;
+    let scale = decode_scale(&blocks.narrow(2, head_size, 1)?)?;
+    blocks
+        .narrow(2, 0, head_size)?
+        .to_dtype(DType::F32)?
+        .affine(1., -ZERO_POINT)?
+        .broadcast_mul(&scale)?
+        .to_dtype(dtype)
+}
+
+/// Gather and dequantize the blocks referenced by `block_tables`, returning the dequantized caches
+/// along with block tables indexing into them. Only the distinct blocks which hold one of the
+/// `context_lens` tokens are dequantized; the padding entries of the returned tables point at the
+/// first gathered block and are never read.
+pub(crate) fn gather_dequantized(
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    dtype: DType,
+) -> Result<(Tensor, Tensor, Tensor)> {
+    let (num_seqs, max_num_blocks) = block_tables.dims2()?;
+    let block_size = value_cache.dim(3)?;
+    let tables = block_tables.to_vec2::<u32>()?;
+    let context_lens = context_lens.to_vec1::<u32>()?;
+
+    let mut live_blocks = Vec::new();
+    let mut remapped = HashMap::new();
+    let mut new_tables = Vec::with_capacity(num_seqs * max_num_blocks);
+    for (table, context_len) in tables.iter().zip(context_lens) {
+        let used = (context_len as usize).div_ceil(block_size);
+        for (i, block) in table.iter().enumerate() {
+            let index = if i < used {
+                *remapped.entry(*block).or_insert_with(|| {
+                    live_blocks.push(*block);
+                    live_blocks.len() - 1
+                })
+            } else {
+                0
+            };
+            #[allow(clippy::cast_possible_truncation)]
+            new_tables.push(index as u32);
+        }
+    }
+    if live_blocks.is_empty() {
+        live_blocks.push(0);
+    }
+
+    let device = block_tables.device();
+    let num_live = live_blocks.len();
+    let live_blocks = Tensor::from_vec(live_blocks, num_live, device)?;
+    let key_cache = dequantize_key_blocks(&key_cache.index_select(&live_blocks, 0)?, dtype)?;
+    let value_cache = dequantize_value_blocks(&value_cache.index_select(&live_blocks, 0)?, dtype)?;
+    let block_tables = Tensor::from_vec(new_tables, (num_seqs, max_num_blocks), device)?;
+    Ok((key_cache, value_cache, block_tables))
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, IndexOp, Tensor, D};
+
+    use super::{
+        dequantize_key_blocks, dequantize_value_blocks, gather_dequantized, quantize_kv,
+        INT8_SCALE_PAD,
+    };
+
+    /// Store `[num_tokens, num_heads, head_size]` in a single block using the paged layout.
+    fn to_key_block(xs: &Tensor, x: usize) -> candle_core::Result<Tensor> {
+        let (num_tokens, num_heads, head_size) = xs.dims3()?;
+        xs.reshape((num_tokens, num_heads, head_size / x, x))?
+            .permute((1, 2, 0, 3))?
+            .unsqueeze(0)
+    }
+
+    fn to_value_block(xs: &Tensor) -> candle_core::Result<Tensor> {
+        xs.permute((1, 2, 0))?.unsqueeze(0)
+    }
+
+    fn attention(q: &Tensor, k: &Tensor, v: &Tensor) -> candle_core::Result<Tensor> {
+        // q: [num_heads, head_size], k/v: [num_heads, head_size, num_tokens]
+        let scale = 1. / (q.dim(1)? as f64).sqrt();
+        let att = (q.unsqueeze(1)?.matmul(k)? * scale)?;
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        att.matmul(&v.transpose(1, 2)?.contiguous()?)?.squeeze(1)
+    }
+
+    #[test]
+    fn int8_paged_attention_matches_f16() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (num_tokens, num_heads, head_size) = (32, 4, 64);
+        let x = 16 / DType::F16.size_in_bytes();
+
+        let k = (Tensor::randn(0f32, 1., (num_tokens, num_heads, head_size), &dev)? * 3.)?;
+        let v = Tensor::randn(0f32, 1., (num_tokens, num_heads, head_size), &dev)?;
+        let q = Tensor::randn(0f32, 1., (num_heads, head_size), &dev)?;
+
+        let k_f16 = to_key_block(&k.to_dtype(DType::F16)?, x)?;
+        let v_f16 = to_value_block(&v.to_dtype(DType::F16)?)?;
+
+        let k_q = to_key_block(&quantize_kv(&k)?, x)?;
+        let v_q = to_value_block(&quantize_kv(&v)?)?;
+        assert_eq!(k_q.dim(2)?, (head_size + INT8_SCALE_PAD) / x);
+        let k_i8 = dequantize_key_blocks(&k_q, DType::F16)?;
+        let v_i8 = dequantize_value_blocks(&v_q, DType::F16)?;
+        assert_eq!(k_i8.dims(), k_f16.dims());
+        assert_eq!(v_i8.dims(), v_f16.dims());
+
+        // Back to [num_heads, head_size, num_tokens] for the reference attention
+        let unblock_k = |b: &Tensor| -> candle_core::Result<Tensor> {
+            b.i(0)?
+                .permute((0, 1, 3, 2))?
+                .reshape((num_heads, head_size, num_tokens))?
+                .to_dtype(DType::F32)
+        };
+        let unblock_v =
+            |b: &Tensor| -> candle_core::Result<Tensor> { b.i(0)?.to_dtype(DType::F32) };
+
+        let out_f16 = attention(&q, &unblock_k(&k_f16)?, &unblock_v(&v_f16)?)?;
+        let out_i8 = attention(&q, &unblock_k(&k_i8)?, &unblock_v(&v_i8)?)?;
+
+        let max_diff = (out_f16 - out_i8)?
+            .abs()?
+            .max_keepdim(D::Minus1)?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(max_diff < 5e-2, "max diff {max_diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn gather_dequantizes_only_live_blocks() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (num_blocks, num_heads, head_size, block_size) = (6, 2, 16, 4);
+        let x = 16 / DType::F16.size_in_bytes();
+
+        let blocks = (0..num_blocks)
+            .map(|_| {
+                let kv = Tensor::randn(0f32, 1., (block_size, num_heads, head_size), &dev)?;
+                let q = quantize_kv(&kv)?;
+                Ok((to_key_block(&q, x)?, to_value_block(&q)?))
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let key_cache = Tensor::cat(&blocks.iter().map(|(k, _)| k).collect::<Vec<_>>(), 0)?;
+        let value_cache = Tensor::cat(&blocks.iter().map(|(_, v)| v).collect::<Vec<_>>(), 0)?;
+
+        // The sequences share block 4, and the trailing entries of the second one are padding.
+        let block_tables = Tensor::new(&[[4u32, 2, 5], [4, 0, 0]], &dev)?;
+        let context_lens = Tensor::new(&[10u32, 3], &dev)?;
+        let (k, v, tables) = gather_dequantized(
+            &key_cache,
+            &value_cache,
+            &block_tables,
+            &context_lens,
+            DType::F32,
+        )?;
+        assert_eq!(k.dim(0)?, 3);
+        assert_eq!(v.dim(0)?, 3);
+        assert_eq!(tables.to_vec2::<u32>()?, vec![vec![0, 1, 2], vec![0, 0, 0]]);
+
+        for (gathered, block) in [4, 2, 5].into_iter().enumerate() {
+            let expected = dequantize_value_blocks(&value_cache.i(block..block + 1)?, DType::F32)?;
+            let diff = (v.i(gathered..gathered + 1)? - expected)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert_eq!(diff, 0.);
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+pub(crate) mod int8_cache;
 pub mod paged_attention;
 
+pub(crate) use int8_cache::INT8_SCALE_PAD;
 pub use paged_attention::PagedAttention;
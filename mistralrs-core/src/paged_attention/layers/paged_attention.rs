@@ -1,9 +1,13 @@
-use candle_core::{Device, Result, Tensor};
+use std::borrow::Cow;
+
+use candle_core::{DType, Device, Result, Tensor};
 
 use mistralrs_paged_attn::{paged_attention, reshape_and_cache};
 
+use super::int8_cache::{gather_dequantized, quantize_kv};
+
 use crate::{
-    attention::SdpaParams,
+    attention::{apply_head_scales, SdpaParams},
     layers::Sdpa,
     pipeline::text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
 };
@@ -116,7 +120,14 @@ impl PagedAttention {
         // key_cache: &mut Tensor,   // [num_blocks, num_heads, head_size/x, block_size, x] 48,32,16,16,8
         // value_cache: &mut Tensor, // [num_blocks, num_heads, head_size, block_size] 48,32,128,16
         // slot_mapping: Tensor,     // [num_tokens]
+        // An int8 cache is allocated as u8 blocks: quantize on write, dequantize on read.
+        let int8_cache = key_cache.as_ref().is_some_and(|kc| kc.dtype() == DType::U8);
         if key_cache.as_ref().is_some_and(|_| value_cache.is_some()) {
+            let (key, value) = if int8_cache {
+                (quantize_kv(&key)?, quantize_kv(&value)?)
+            } else {
+                (key, value)
+            };
             reshape_and_cache(
                 &key,
                 &value,
@@ -145,17 +156,38 @@ impl PagedAttention {
         //  input_metadata: metadata for paged attention.
         //
         //  alibi_slopes: shape = [num_heads]
+        let (key_cache, value_cache, block_tables) = if int8_cache {
+            let (k, v, b) = gather_dequantized(
+                key_cache.as_ref().unwrap(),
+                value_cache.as_ref().unwrap(),
+                block_tables,
+                context_lens,
+                query.dtype(),
+            )?;
+            (k, v, Cow::Owned(b))
+        } else {
+            (
+                key_cache.unwrap(),
+                value_cache.unwrap(),
+                Cow::Borrowed(block_tables),
+            )
+        };
         #[allow(clippy::cast_possible_truncation)]
-        paged_attention(
+        let out = paged_attention(
             &query,
-            key_cache.as_ref().unwrap(),
-            value_cache.as_ref().unwrap(),
-            block_tables,
+            &key_cache,
+            &value_cache,
+            &block_tables,
             context_lens,
             alibi_slopes.as_ref(),
             input_metadata.max_context_len.unwrap(),
             sdpa_params.softmax_scale,
             sdpa_params.softcap.unwrap_or(1.0f32),
-        )
+        )?;
+        // out: [num_generation_tokens, num_heads, head_size]
+        match &sdpa_params.head_scales {
+            Some(head_scales) => apply_head_scales(&out, head_scales, 1),
+            None => Ok(out),
+        }
     }
 }
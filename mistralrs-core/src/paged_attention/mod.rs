@@ -13,10 +13,13 @@ pub const _PAD_SLOT_ID: i64 = -1;
 
 pub use block_engine::{BlockEngine, BlockTables, LogicalTokenBlock};
 pub use block_engine_sequence::BlockEngineSequence;
-pub use cache_engine::{CacheConfig, CacheEngine};
+pub use cache_engine::{BlockUsage, CacheConfig, CacheEngine, PagedCacheStats};
+use std::str::FromStr;
+
 use candle_core::{DType, Device};
 pub use config::{ModelConfigLike, ModelConfigMetadata};
 pub use layers::PagedAttention;
+pub(crate) use layers::INT8_SCALE_PAD;
 pub use scheduler::{
     PagedAttentionScheduler, PagedAttentionSchedulerConfig, PagedAttentionSchedulerOutput,
 };
@@ -32,6 +35,7 @@ pub struct PagedAttentionConfig {
     pub(crate) block_size: Option<usize>,
     pub(crate) mem_cpu: usize,
     pub(crate) mem_gpu: MemoryGpuConfig,
+    pub(crate) cache_type: PagedCacheType,
 }
 
 impl PagedAttentionConfig {
@@ -44,8 +48,40 @@ impl PagedAttentionConfig {
             block_size,
             mem_cpu,
             mem_gpu,
+            cache_type: PagedCacheType::default(),
         })
     }
+
+    /// Set the storage type of the KV cache blocks.
+    pub fn with_cache_type(mut self, cache_type: PagedCacheType) -> Self {
+        self.cache_type = cache_type;
+        self
+    }
+}
+
+/// Storage type of the PagedAttention KV cache blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
+pub enum PagedCacheType {
+    /// Store keys and values in the model activation dtype.
+    #[default]
+    Auto,
+    /// Store keys and values as int8 with one scale per token and head, kept inside the block.
+    /// Values are dequantized when the blocks are read for attention.
+    I8,
+}
+
+impl FromStr for PagedCacheType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "i8" | "int8" => Ok(Self::I8),
+            other => Err(format!(
+                "Unknown PagedAttention cache type `{other}`, expected `auto` or `i8`."
+            )),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -68,34 +104,28 @@ const SUPPORTED_BLOCK_SIZE: &[usize] = &[8, 16, 32];
 const SIZE_IN_MB: usize = 1024 * 1024;
 
 macro_rules! mb_to_blocks {
-    ($mb_size:expr, $dtype_size:expr, $block_size:expr, $config:expr) => {
-        $mb_size
-            / $dtype_size
-            / $block_size
-            / $config.num_kv_heads()
-            / ($config.k_head_dim().max($config.v_head_dim()))
-            / $config.num_layers()
-            / 2
+    ($mb_size:expr, $layers_size:expr, $block_size:expr, $config:expr) => {
+        $mb_size / $layers_size / $block_size / $config.num_kv_heads() / 2
     };
 }
 
 macro_rules! ctxt_to_blocks {
-    ($context_len:expr, $dtype_size:expr, $block_size:expr, $config:expr) => {
-        $context_len
-            * $dtype_size
-            * $config.num_kv_heads()
-            * ($config.k_head_dim().max($config.v_head_dim()))
-            * $config.num_layers()
-            * 2
+    ($context_len:expr, $layers_size:expr, $config:expr) => {
+        $context_len * $layers_size * $config.num_kv_heads() * 2
     };
 }
 
 /// Memory values are in MBs or a percentage in [0,1]. Specify block size or the default is 32.
+///
+/// `layer_cache_types` overrides `cache_type` for individual layers, indexed by layer. Layers past its end
+/// or set to `None` use `cache_type`.
 #[allow(clippy::too_many_arguments)]
 pub fn calculate_cache_config(
     mem_gpu: MemoryGpuConfig,
     mem_cpu: usize,
     block_size: Option<usize>,
+    cache_type: PagedCacheType,
+    layer_cache_types: &[Option<PagedCacheType>],
     dtype: DType,
     config: &dyn ModelConfigLike,
     device: &Device,
@@ -106,7 +136,20 @@ pub fn calculate_cache_config(
     if !SUPPORTED_BLOCK_SIZE.contains(&block_size) {
         anyhow::bail!("Block size must be in {SUPPORTED_BLOCK_SIZE:?}, got {block_size}");
     }
-    let dtype_size = dtype.size_in_bytes();
+    let layer_cache_types = (0..config.num_layers())
+        .map(|i| layer_cache_types.get(i).copied().flatten())
+        .collect::<Vec<_>>();
+    // Bytes of one key or value head vector, summed over the layers.
+    let layers_size = layer_cache_types
+        .iter()
+        .map(|ty| {
+            let (dtype_size, head_dim_pad) = match ty.unwrap_or(cache_type) {
+                PagedCacheType::Auto => (dtype.size_in_bytes(), 0),
+                PagedCacheType::I8 => (DType::U8.size_in_bytes(), INT8_SCALE_PAD),
+            };
+            dtype_size * (config.k_head_dim().max(config.v_head_dim()) + head_dim_pad)
+        })
+        .sum::<usize>();
 
     let mut min_mem_gpu = usize::MAX;
     for dev in layer_devices {
@@ -122,7 +165,7 @@ pub fn calculate_cache_config(
                 (total * f - used) as usize
             }
             MemoryGpuConfig::ContextSize(toks) => {
-                ctxt_to_blocks!(toks, dtype_size, block_size, config) / SIZE_IN_MB
+                ctxt_to_blocks!(toks, layers_size, config) / SIZE_IN_MB
             }
         };
         min_mem_gpu = min_mem_gpu.min(mem_gpu);
@@ -130,12 +173,12 @@ pub fn calculate_cache_config(
 
     // // Cap at kv cache for max seq len
     // let mem_for_toks =
-    //     ctxt_to_blocks!(config.max_seq_len(), dtype_size, block_size, config) / SIZE_IN_MB;
+    //     ctxt_to_blocks!(config.max_seq_len(), layers_size, config) / SIZE_IN_MB;
     // let mem_gpu = min_mem_gpu.min(mem_for_toks);
     let mem_gpu = min_mem_gpu;
 
-    let num_gpu_blocks = mb_to_blocks!(mem_gpu * SIZE_IN_MB, dtype_size, block_size, config);
-    let num_cpu_blocks = mb_to_blocks!(mem_cpu * SIZE_IN_MB, dtype_size, block_size, config);
+    let num_gpu_blocks = mb_to_blocks!(mem_gpu * SIZE_IN_MB, layers_size, block_size, config);
+    let num_cpu_blocks = mb_to_blocks!(mem_cpu * SIZE_IN_MB, layers_size, block_size, config);
     if num_gpu_blocks == 0 {
         anyhow::bail!("Num GPU blocks is 0. This means there is not enough memory. Either reduce the memory amount/utilization/context size or disable PagedAttention.");
     }
@@ -143,10 +186,25 @@ pub fn calculate_cache_config(
     if !silent {
         info!("Allocating {mem_gpu} MB for PagedAttention KV cache per GPU");
         info!("Using PagedAttention with block size {block_size} and {num_gpu_blocks} GPU blocks: available context length is {} tokens", num_gpu_blocks*block_size);
+        let num_i8_layers = layer_cache_types
+            .iter()
+            .filter(|ty| ty.unwrap_or(cache_type) == PagedCacheType::I8)
+            .count();
+        if num_i8_layers == layer_cache_types.len() {
+            info!("PagedAttention KV cache blocks are stored as int8.");
+        } else if num_i8_layers > 0 {
+            info!(
+                "PagedAttention KV cache blocks are stored as int8 for {num_i8_layers} of {} layers.",
+                layer_cache_types.len()
+            );
+        }
     }
     Ok(CacheConfig {
         block_size,
         num_gpu_blocks,
         num_cpu_blocks,
+        cache_type,
+        layer_cache_types,
+        usage: BlockUsage::default(),
     })
 }
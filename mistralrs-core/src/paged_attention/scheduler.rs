@@ -58,6 +58,7 @@ impl PagedAttentionScheduler {
                 cache_config.block_size,
                 cache_config.num_gpu_blocks,
                 cache_config.num_cpu_blocks,
+                cache_config.usage.clone(),
             ),
             block_size: cache_config.block_size,
         }
@@ -360,6 +361,18 @@ impl Scheduler for PagedAttentionScheduler {
     fn free_finished_sequence_groups(&mut self) {
         self.free_finished_sequence_groups()
     }
+    fn drain(&mut self) -> Vec<Arc<Mutex<Sequence>>> {
+        let seqs = self
+            .running
+            .drain(..)
+            .chain(self.swapped_out.drain(..))
+            .chain(self.waiting.drain(..))
+            .collect::<Vec<_>>();
+        for seq in &seqs {
+            self._free(get_mut_arcmutex!(seq).get_id());
+        }
+        seqs
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         Some(&mut self.block_engine)
     }
@@ -13,9 +13,8 @@ use either::Either;
 use image::DynamicImage;
 use indexmap::IndexMap;
 use indicatif::MultiProgress;
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{IsqType, QuantInfo};
 use rand::{rng, seq::SliceRandom};
-use rand_isaac::Isaac64Rng;
 use tracing::{info, warn};
 
 use crate::{
@@ -23,7 +22,7 @@ use crate::{
     device_map::DeviceMapper,
     get_mut_arcmutex,
     prefix_cacher::PrefixCacheManagerV2,
-    sampler::Sampler,
+    sampler::{Sampler, SamplingRng},
     sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer},
     utils::progress::NiceProgressBar,
     DeviceMapSetting, Loader, ModelCategory, ModelKind, ModelPaths, PagedAttentionConfig, Pipeline,
@@ -243,6 +242,9 @@ impl MetadataMixin for AnyMoePipeline {
     fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
         None
     }
+    fn quant_manifest(&self) -> Vec<(String, QuantInfo)> {
+        get_mut_arcmutex!(self.target).quant_manifest()
+    }
 }
 
 #[async_trait::async_trait]
@@ -251,8 +253,9 @@ impl Pipeline for AnyMoePipeline {
         &mut self,
         inputs: Box<dyn Any>,
         _return_raw_logits: bool,
+        return_logprobs: Option<usize>,
     ) -> Result<ForwardInputsResult, candle_core::Error> {
-        get_mut_arcmutex!(self.target).forward_inputs(inputs, false)
+        get_mut_arcmutex!(self.target).forward_inputs(inputs, false, return_logprobs)
     }
 
     async fn sample_causal_gen(
@@ -261,7 +264,7 @@ impl Pipeline for AnyMoePipeline {
         logits: Vec<Tensor>,
         prefix_cacher: &mut PrefixCacheManagerV2,
         disable_eos_stop: bool,
-        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
     ) -> Result<(), candle_core::Error> {
         get_mut_arcmutex!(self.target)
             .sample_causal_gen(seqs, logits, prefix_cacher, disable_eos_stop, rng)
@@ -371,6 +374,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             tokenizer.clone(),
             None,
             None,
+            Default::default(),
             None,
             -1,
             0.0,
@@ -479,7 +483,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
                 // === PREPARE AND RUN MODEL ==
 
                 // Run the model, ignoring the logits
-                let _ = target.forward_inputs(inputs.unwrap().inputs, false)?;
+                let _ = target.forward_inputs(inputs.unwrap().inputs, false, None)?;
 
                 // Clear the KV cache
                 target.set_none_cache(&mut input_seqs, true, true, false);
@@ -591,5 +595,7 @@ fn new_dummy_seq(
         None,
         false,
         eos_toks,
+        None,
+        None,
     )
 }
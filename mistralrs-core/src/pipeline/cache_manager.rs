@@ -2,7 +2,12 @@ use std::sync::{Arc, Mutex, MutexGuard};
 
 use candle_core::{Result, Tensor, D};
 
-use crate::{get_mut_arcmutex, sequence::Sequence};
+use crate::{
+    get_mut_arcmutex,
+    layers_utils::is_differentiable,
+    request::{KvCacheLimit, KvCacheOverflowPolicy},
+    sequence::Sequence,
+};
 
 use super::{CacheManagerMixin, MetadataMixin};
 
@@ -46,6 +51,47 @@ impl EitherCache {
             Self::Full(_) => panic!("Got full cache, expected normal cache."),
         }
     }
+
+    /// Run `f` on an empty cache, restoring the previous cache afterwards. Like `set_none_cache`,
+    /// this also clears the X-LoRA cache, so a cacheless forward pass leaves no trace.
+    pub(crate) fn with_empty_cache<T>(&self, f: impl FnOnce() -> T) -> T {
+        match self {
+            Self::Full(full) => {
+                let saved = full.lock().clone();
+                let saved_xlora = full
+                    .is_xlora()
+                    .then(|| (full.xlora_lock().clone(), full.get_scalings_cache().clone()));
+                for layer in &mut *full.lock() {
+                    *layer = None;
+                }
+                if full.is_xlora() {
+                    for layer in &mut *full.xlora_lock() {
+                        *layer = None;
+                    }
+                }
+
+                let res = f();
+
+                *full.lock() = saved;
+                if let Some((xlora, scalings)) = saved_xlora {
+                    *full.xlora_lock() = xlora;
+                    *full.get_scalings_cache() = scalings;
+                }
+                res
+            }
+            Self::Normal(normal) => {
+                let saved = normal.lock().unwrap().clone();
+                for layer in &mut normal.lock().unwrap().0 {
+                    layer.reset();
+                }
+
+                let res = f();
+
+                *normal.lock().unwrap() = saved;
+                res
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -142,6 +188,24 @@ impl SingleCache {
         self.current_seq_len += seq_len;
         Ok(())
     }
+
+    /// Keep the first `sink` and the last `window` tokens, dropping the ones in between. Returns the
+    /// number of evicted tokens.
+    pub fn evict_middle(&mut self, sink: usize, window: usize) -> Result<usize> {
+        if self.current_seq_len <= sink + window {
+            return Ok(0);
+        }
+        if let (Some(ad), true) = (self.all_data.as_ref(), window > 0) {
+            // Copy the window out first, as it may overlap with where it is moved to.
+            let recent = ad
+                .narrow(self.dim, self.current_seq_len - window, window)?
+                .copy()?;
+            ad.slice_set(&recent, self.dim, sink)?;
+        }
+        let evicted = self.current_seq_len - sink - window;
+        self.current_seq_len = sink + window;
+        Ok(evicted)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -332,6 +396,19 @@ impl KvCache {
         let k = k.contiguous()?;
         let v = v.contiguous()?;
         let (out_k, out_v) = match self {
+            Self::Normal { k: kc, v: vc } if is_differentiable() => {
+                // The cache is written in place, so return the new keys and values themselves to keep
+                // them in the graph.
+                let (past_k, past_v) = (kc.current_data()?, vc.current_data()?);
+                kc.append(&k)?;
+                vc.append(&v)?;
+                let dim = kc.dim;
+                let cat = |past: Option<Tensor>, new: &Tensor| match past {
+                    Some(past) => Tensor::cat(&[&past, new], dim),
+                    None => Ok(new.clone()),
+                };
+                (Some(cat(past_k, &k)?), Some(cat(past_v, &v)?))
+            }
             Self::Normal { k: kc, v: vc } => {
                 kc.append(&k)?;
                 vc.append(&v)?;
@@ -407,6 +484,18 @@ impl KvCache {
     pub fn is_rotating(&self) -> bool {
         matches!(self, Self::Rotating { .. })
     }
+
+    /// Keep the first `sink` and the last `window` tokens, see [`SingleCache::evict_middle`]. Rotating
+    /// caches are already bounded by their window and are left untouched.
+    pub fn evict_middle(&mut self, sink: usize, window: usize) -> Result<usize> {
+        match self {
+            Self::Normal { k, v } => {
+                v.evict_middle(sink, window)?;
+                k.evict_middle(sink, window)
+            }
+            Self::Rotating { .. } => Ok(0),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -474,8 +563,67 @@ impl NormalCache {
     }
 }
 
+/// Fail or evict tokens if the layers hold more than `limit` tokens, returning the number of evicted
+/// tokens. Only the non-rotating layers count towards the limit.
+fn enforce_layers_limit(mut layers: Vec<&mut KvCache>, limit: KvCacheLimit) -> Result<usize> {
+    let Some(len) = layers
+        .iter()
+        .find(|layer| !layer.is_rotating())
+        .map(|layer| layer.current_seq_len())
+    else {
+        return Ok(0);
+    };
+    if len <= limit.max_tokens {
+        return Ok(0);
+    }
+
+    match limit.overflow {
+        KvCacheOverflowPolicy::Error => candle_core::bail!(
+            "kv-cache: {len} tokens exceed the limit of {} tokens",
+            limit.max_tokens
+        ),
+        KvCacheOverflowPolicy::EvictSinkWindow { sink_tokens } => {
+            let sink = sink_tokens.min(limit.max_tokens);
+            for layer in layers.iter_mut() {
+                layer.evict_middle(sink, limit.max_tokens - sink)?;
+            }
+            Ok(len - limit.max_tokens)
+        }
+    }
+}
+
 pub struct NormalCacheManager;
 
+impl NormalCacheManager {
+    /// Apply the KV cache limit of each sequence to its own cache, which must already hold the
+    /// output of [`CacheManager::clone_out_cache`]. Returns the index and error of every sequence
+    /// whose cache went past its limit with [`KvCacheOverflowPolicy::Error`]; the other sequences
+    /// are unaffected.
+    ///
+    /// Evicting leaves the model cache stale, so the next step must clone the sequence caches in
+    /// again.
+    pub fn enforce_limits<T: CacheManagerMixin + ?Sized>(
+        &self,
+        pipeline: &T,
+        seqs: &mut [&mut Sequence],
+    ) -> Vec<(usize, candle_core::Error)> {
+        if !matches!(pipeline.cache(), EitherCache::Normal(_)) {
+            return Vec::new();
+        }
+        let mut failed = Vec::new();
+        for (i, seq) in seqs.iter_mut().enumerate() {
+            let Some(limit) = seq.kv_cache_limit() else {
+                continue;
+            };
+            match enforce_layers_limit(seq.normal_cache().iter_mut().flatten().collect(), limit) {
+                Ok(evicted) => seq.add_kv_cache_evicted_toks(evicted),
+                Err(e) => failed.push((i, e)),
+            }
+        }
+        failed
+    }
+}
+
 impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCacheManager {
     fn clone_in_cache(
         &self,
@@ -1106,3 +1254,218 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for FullCach
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Result, Tensor};
+
+    use std::sync::{Arc, Mutex};
+
+    use super::{Cache, EitherCache, KvCache, NormalCache, NormalCacheManager};
+    use crate::{
+        pipeline::CacheManagerMixin,
+        request::{KvCacheLimit, KvCacheOverflowPolicy},
+        sampler::Sampler,
+        sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer},
+    };
+
+    /// A single layer cache holding `len` tokens, where the keys and values of token `i` are `i`.
+    fn layer_with(len: usize) -> Result<KvCache> {
+        let mut layer = KvCache::new_normal(2, 4096, NormalCache::CACHE_GROW_SIZE);
+        let toks = Tensor::arange(0f32, len as f32, &Device::Cpu)?.reshape((1, 1, len, 1))?;
+        layer.append(&toks, &toks)?;
+        Ok(layer)
+    }
+
+    fn cache_with(len: usize) -> Result<NormalCache> {
+        Ok(NormalCache(vec![layer_with(len)?]))
+    }
+
+    fn retained(layer: &KvCache) -> Result<(Vec<f32>, Vec<f32>)> {
+        let k = layer.k()?.unwrap().flatten_all()?.to_vec1()?;
+        let v = layer.v()?.unwrap().flatten_all()?.to_vec1()?;
+        Ok((k, v))
+    }
+
+    /// A pipeline with a single layer normal cache, of which only the cache kind is looked at.
+    struct NormalCachePipeline(EitherCache);
+
+    impl CacheManagerMixin for NormalCachePipeline {
+        fn clone_in_cache(&self, _: &mut [&mut Sequence]) {
+            unreachable!()
+        }
+        fn clone_out_cache(&self, _: &mut [&mut Sequence]) {
+            unreachable!()
+        }
+        fn set_none_cache(&self, _: &mut [&mut Sequence], _: bool, _: bool, _: bool) {
+            unreachable!()
+        }
+        fn cache(&self) -> &EitherCache {
+            &self.0
+        }
+    }
+
+    fn pipeline() -> NormalCachePipeline {
+        NormalCachePipeline(EitherCache::Normal(NormalCache::new(1, 4096)))
+    }
+
+    /// A sequence whose single layer cache holds `len` tokens as in [`layer_with`], under `limit`.
+    fn seq_with(id: usize, len: usize, limit: KvCacheLimit) -> Result<Sequence> {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            Default::default(),
+            None,
+            -1,
+            0.0,
+            0.0,
+            vec![],
+        )
+        .unwrap();
+        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+            1, false, false, None,
+        )));
+        let mut seq = Sequence::new_waiting(
+            vec![1, 2],
+            "prompt".to_string(),
+            id,
+            0,
+            1,
+            tx,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            false,
+            false,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            SeqStepType::PromptAndDecode,
+            None,
+            None,
+            false,
+            vec![],
+            None,
+            None,
+        )
+        .with_kv_cache_limit(Some(limit));
+        *seq.normal_cache() = vec![Some(layer_with(len)?)];
+        Ok(seq)
+    }
+
+    fn seq_layer(seq: &mut Sequence) -> &KvCache {
+        seq.normal_cache()[0].as_ref().unwrap()
+    }
+
+    #[test]
+    fn limit_error_fails_past_the_limit() -> Result<()> {
+        let limit = KvCacheLimit {
+            max_tokens: 8,
+            overflow: KvCacheOverflowPolicy::Error,
+        };
+        let (mut at_limit, mut past_limit) = (seq_with(0, 8, limit)?, seq_with(1, 9, limit)?);
+        let failed =
+            NormalCacheManager.enforce_limits(&pipeline(), &mut [&mut at_limit, &mut past_limit]);
+        assert_eq!(failed.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [1]);
+        assert!(failed[0].1.to_string().contains("9 tokens"));
+        assert_eq!(retained(seq_layer(&mut at_limit))?.0.len(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn limit_evict_sink_window_keeps_sinks_and_recent_tokens() -> Result<()> {
+        let limit = KvCacheLimit {
+            max_tokens: 6,
+            overflow: KvCacheOverflowPolicy::EvictSinkWindow { sink_tokens: 2 },
+        };
+        let pipeline = pipeline();
+        let mut seq = seq_with(0, 10, limit)?;
+        assert!(NormalCacheManager
+            .enforce_limits(&pipeline, &mut [&mut seq])
+            .is_empty());
+        assert_eq!(seq.kv_cache_evicted_toks(), 4);
+        let expected = vec![0., 1., 6., 7., 8., 9.];
+        assert_eq!(retained(seq_layer(&mut seq))?, (expected.clone(), expected));
+
+        // New tokens are appended after the window, and the oldest non-sink token is evicted next.
+        let tok = Tensor::new(10f32, &Device::Cpu)?.reshape((1, 1, 1, 1))?;
+        seq.normal_cache()[0].as_mut().unwrap().append(&tok, &tok)?;
+        assert!(NormalCacheManager
+            .enforce_limits(&pipeline, &mut [&mut seq])
+            .is_empty());
+        assert_eq!(seq.kv_cache_evicted_toks(), 5);
+        let expected = vec![0., 1., 7., 8., 9., 10.];
+        assert_eq!(retained(seq_layer(&mut seq))?, (expected.clone(), expected));
+        Ok(())
+    }
+
+    #[test]
+    fn limits_apply_per_sequence_in_a_batch() -> Result<()> {
+        let error = |max_tokens| KvCacheLimit {
+            max_tokens,
+            overflow: KvCacheOverflowPolicy::Error,
+        };
+        let evict = |max_tokens| KvCacheLimit {
+            max_tokens,
+            overflow: KvCacheOverflowPolicy::EvictSinkWindow { sink_tokens: 2 },
+        };
+        // The same 10 tokens are within the first sequence's limits and past the second's.
+        let all = (0..10).map(|i| i as f32).collect::<Vec<_>>();
+        for (within, past) in [(error(16), error(8)), (evict(16), evict(6))] {
+            let mut under = seq_with(0, 10, within)?;
+            let mut over = seq_with(1, 10, past)?;
+            let failed =
+                NormalCacheManager.enforce_limits(&pipeline(), &mut [&mut under, &mut over]);
+
+            assert_eq!(under.kv_cache_evicted_toks(), 0);
+            assert_eq!(retained(seq_layer(&mut under))?.0, all);
+            match past.overflow {
+                KvCacheOverflowPolicy::Error => {
+                    assert_eq!(failed.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [1]);
+                    assert_eq!(retained(seq_layer(&mut over))?.0, all);
+                }
+                KvCacheOverflowPolicy::EvictSinkWindow { .. } => {
+                    assert!(failed.is_empty());
+                    assert_eq!(over.kv_cache_evicted_toks(), 4);
+                    assert_eq!(retained(seq_layer(&mut over))?.0, [0., 1., 6., 7., 8., 9.]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn with_empty_cache_restores_the_cache() -> Result<()> {
+        let normal = EitherCache::Normal(Arc::new(Mutex::new(cache_with(4)?)));
+        let seen = normal.with_empty_cache(|| normal.normal().0[0].k())?;
+        assert!(seen.is_none());
+        assert_eq!(retained(&normal.normal().0[0])?.0, vec![0., 1., 2., 3.]);
+
+        let kv = Tensor::zeros((1, 1, 4, 1), candle_core::DType::F32, &Device::Cpu)?;
+        let full = EitherCache::Full(Cache::new(1, true));
+        full.full().lock()[0] = Some((kv.clone(), kv.clone()));
+        full.full().xlora_lock()[0] = Some((kv.clone(), kv));
+        let seen = full.with_empty_cache(|| {
+            (
+                full.full().lock()[0].is_some(),
+                full.full().xlora_lock()[0].is_some(),
+            )
+        });
+        assert_eq!(seen, (false, false));
+        assert!(full.full().lock()[0].is_some());
+        assert!(full.full().xlora_lock()[0].is_some());
+        Ok(())
+    }
+}
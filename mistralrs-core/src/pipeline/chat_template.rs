@@ -9,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 use tracing::info;
 
-use crate::{MessageContent, Tool};
+use crate::{MessageContent, SamplingParams, Tool};
 
 const SUPPORTED_ALTERNATE_EOS: &[&str] = &[
     "<|im_end|>",      // Handle ChatML case
@@ -63,6 +63,8 @@ pub struct ChatTemplate {
     legacy: Option<bool>,
     model_max_length: Option<f64>,
     pub pad_token: Option<BeginEndUnkPadTok>,
+    /// Token which ends the reasoning span of reasoning models. Detected from the chat template if unset.
+    pub reasoning_end_token: Option<String>,
     sp_model_kwargs: Option<HashMap<String, String>>,
     spaces_between_special_tokens: Option<bool>,
     tokenizer_class: Option<String>,
@@ -96,6 +98,35 @@ impl ChatTemplate {
             Either::Right(ref added) => Some(added.content.clone()),
         }
     }
+
+    /// The token which ends the reasoning span, such as `</think>`. This is `reasoning_end_token` if set,
+    /// otherwise the first non-special added token of `tokenizer` which closes a tag that the chat
+    /// template both opens and closes. Special tokens like `</s>` are control tokens, not markup.
+    pub fn reasoning_end_tok(&self, tokenizer: &Tokenizer) -> Option<String> {
+        if let Some(tok) = &self.reasoning_end_token {
+            return Some(tok.clone());
+        }
+        let template = match &self.chat_template.as_ref()?.0 {
+            Either::Left(template) => template.clone(),
+            Either::Right(templates) => templates
+                .iter()
+                .filter_map(|t| t.get("template"))
+                .join("\n"),
+        };
+        tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .sorted_by_key(|(id, _)| *id)
+            .filter(|(_, tok)| !tok.special)
+            .map(|(_, tok)| tok.content)
+            .find(|tok| {
+                tok.strip_prefix("</").is_some_and(|tag| {
+                    tag.ends_with('>')
+                        && template.contains(tok.as_str())
+                        && template.contains(&format!("<{tag}"))
+                })
+            })
+    }
 }
 
 pub fn calculate_eos_tokens(
@@ -172,6 +203,36 @@ pub fn calculate_eos_tokens(
     eos_toks
 }
 
+/// Override the resolved BOS token of the chat template and the EOS token ids used for stopping.
+/// The ids must be within the tokenizer vocabulary.
+pub(crate) fn apply_special_token_overrides(
+    chat_template: &mut ChatTemplate,
+    eos_toks: &mut Vec<u32>,
+    bos: Option<u32>,
+    eos: Option<&[u32]>,
+    tokenizer: &Tokenizer,
+) -> Result<()> {
+    let vocab_size = tokenizer.get_vocab_size(true);
+    for id in bos.iter().chain(eos.into_iter().flatten()) {
+        if *id as usize >= vocab_size {
+            anyhow::bail!("Special token id {id} is out of range for vocab size {vocab_size}.");
+        }
+    }
+
+    if let Some(bos) = bos {
+        let Some(bos_tok) = tokenizer.id_to_token(bos) else {
+            anyhow::bail!("Unable to find the BOS token for id {bos}.");
+        };
+        info!("Overriding bos_tok = {bos_tok:?}");
+        chat_template.bos_token = Some(BeginEndUnkPadTok(Either::Left(bos_tok)));
+    }
+    if let Some(eos) = eos {
+        info!("Overriding eos_toks = {eos:?}");
+        *eos_toks = eos.to_vec();
+    }
+    Ok(())
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct GenerationConfig {
@@ -179,6 +240,46 @@ pub struct GenerationConfig {
     bos_token_id: Either<u32, Vec<u32>>,
     #[serde(with = "either::serde_untagged")]
     eos_token_id: Either<u32, Vec<u32>>,
+    #[serde(flatten)]
+    sampling: GenerationSamplingConfig,
+}
+
+impl GenerationConfig {
+    pub fn sampling(&self) -> &GenerationSamplingConfig {
+        &self.sampling
+    }
+}
+
+/// The sampling fields of `generation_config.json`. Each is `None` unless the model sets it.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GenerationSamplingConfig {
+    pub do_sample: Option<bool>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    /// Hugging Face's multiplicative repetition penalty. The sampler has no equivalent, so this is not
+    /// part of [`GenerationSamplingConfig::recommended_sampling`] and is only reported for frontends.
+    pub repetition_penalty: Option<f32>,
+}
+
+impl GenerationSamplingConfig {
+    /// The sampling params suggested by the model.
+    ///
+    /// Fields the model does not set are left as `None`, so that the usual request defaults apply
+    /// (a temperature of 1.0 and no top-k or top-p). If `do_sample` is `false`, greedy decoding is
+    /// recommended and the other fields are ignored. Everything which is not a sampling field, such
+    /// as stop tokens and the maximum length, is left unset.
+    pub fn recommended_sampling(&self) -> SamplingParams {
+        if self.do_sample == Some(false) {
+            return SamplingParams::deterministic();
+        }
+        SamplingParams {
+            temperature: self.temperature,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            ..SamplingParams::deterministic()
+        }
+    }
 }
 
 fn tojson(value: Value, kwargs: Kwargs) -> Result<Value, Error> {
@@ -221,6 +322,12 @@ fn strftime_now(fmt: String) -> Result<String, minijinja::Error> {
     Ok(date_string)
 }
 
+/// Check that a Jinja chat template compiles, without rendering it.
+pub(crate) fn validate_chat_template(template: &str) -> Result<()> {
+    Environment::new().template_from_str(template)?;
+    Ok(())
+}
+
 pub fn apply_chat_template_to(
     messages: Vec<IndexMap<String, MessageContent>>,
     add_generation_prompt: bool,
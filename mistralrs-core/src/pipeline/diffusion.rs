@@ -10,6 +10,7 @@ use crate::diffusion_models::processor::{DiffusionProcessor, ModelInputs};
 use crate::paged_attention::AttentionImplementation;
 use crate::pipeline::ChatTemplate;
 use crate::prefix_cacher::PrefixCacheManagerV2;
+use crate::sampler::SamplingRng;
 use crate::sequence::Sequence;
 use crate::utils::varbuilder_utils::DeviceForLoadTensor;
 use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
@@ -20,7 +21,6 @@ use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use image::{DynamicImage, RgbImage};
 use indicatif::MultiProgress;
 use mistralrs_quant::IsqType;
-use rand_isaac::Isaac64Rng;
 use std::any::Any;
 use std::io;
 use std::sync::Arc;
@@ -228,6 +228,7 @@ impl Loader for DiffusionLoader {
                 cache_engine: None,
                 prompt_chunksize: None,
                 model_metadata: None,
+                generation_sampling: Default::default(),
             }),
             dummy_cache: EitherCache::Full(Cache::new(0, false)),
         })))
@@ -301,6 +302,7 @@ impl Pipeline for DiffusionPipeline {
         &mut self,
         inputs: Box<dyn Any>,
         return_raw_logits: bool,
+        _return_logprobs: Option<usize>,
     ) -> candle_core::Result<ForwardInputsResult> {
         assert!(!return_raw_logits);
 
@@ -328,7 +330,7 @@ impl Pipeline for DiffusionPipeline {
         _logits: Vec<Tensor>,
         _prefix_cacher: &mut PrefixCacheManagerV2,
         _disable_eos_stop: bool,
-        _srng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        _srng: SamplingRng,
     ) -> Result<(), candle_core::Error> {
         candle_core::bail!("`sample_causal_gen` is incompatible with `DiffusionPipeline`");
     }
@@ -16,6 +16,7 @@ use crate::pipeline::inputs_processor::DEFAULT_PROMPT_CHUNK_SIZE;
 use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManagerV2;
+use crate::sampler::SamplingRng;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::model_config as ModelConfig;
@@ -34,7 +35,6 @@ use candle_core::quantized::ggml_file;
 use candle_core::{Device, Tensor};
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use mistralrs_quant::IsqType;
-use rand_isaac::Isaac64Rng;
 use std::any::Any;
 use std::fs;
 use std::num::{NonZero, NonZeroUsize};
@@ -368,6 +368,10 @@ impl Loader for GGMLLoader {
             Model::Llama(ref model) => model.cache.normal().0.len(),
             Model::XLoraLlama(ref model) => model.cache.full().lock().len(),
         };
+        let generation_sampling = gen_conf
+            .as_ref()
+            .map(|gen_conf| gen_conf.sampling().clone())
+            .unwrap_or_default();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
         Ok(Arc::new(Mutex::new(GGMLPipeline {
             model,
@@ -396,6 +400,7 @@ impl Loader for GGMLLoader {
                 cache_engine: None,
                 prompt_chunksize: Some(NonZero::new(prompt_chunksize).unwrap()),
                 model_metadata: None,
+                generation_sampling,
             }),
         })))
     }
@@ -420,7 +425,8 @@ impl Loader for GGMLLoader {
             self.quantized_model_id,
             Some(vec![self.quantized_filename.as_ref().unwrap().clone()]),
             silent,
-            false // Never loading UQFF
+            false, // Never loading UQFF
+            0
         );
         self.load_model_from_path(
             &paths?,
@@ -523,7 +529,10 @@ impl Pipeline for GGMLPipeline {
         &mut self,
         inputs: Box<dyn Any>,
         return_raw_logits: bool,
+        return_logprobs: Option<usize>,
     ) -> Result<ForwardInputsResult, candle_core::Error> {
+        let inputs: ModelInputs = *inputs.downcast().expect("Downcast failed.");
+        inputs.check_consistency()?;
         let ModelInputs {
             input_ids,
             input_ids_full,
@@ -534,27 +543,36 @@ impl Pipeline for GGMLPipeline {
             paged_attn_meta: _, // NOTE(EricLBuehler): ignore it for ggml
             flash_meta,         // NOTE(EricLBuehler): ignore it for ggml dequant into f32
             flash_meta_full,    // NOTE(EricLBuehler): ignore it for ggml dequant into f32
-        } = *inputs.downcast().expect("Downcast failed.");
-        let logits = match self.model {
-            Model::Llama(ref model) => {
-                model.forward(&input_ids, &seqlen_offsets, context_lens, None)?
-            }
-            Model::XLoraLlama(ref model) => model.forward(
-                &input_ids,
-                input_ids_full.as_ref().unwrap_or(&input_ids),
-                &seqlen_offsets,
-                seqlen_offsets_full.as_ref().unwrap_or(&seqlen_offsets),
-                self.no_kv_cache,
-                &self.non_granular_state,
-                context_lens,
-                &flash_meta,
-                flash_meta_full.as_ref().unwrap_or(&flash_meta),
-            )?,
+            use_cache,
+        } = inputs;
+        let no_kv_cache = self.no_kv_cache || !use_cache;
+        let forward = || -> candle_core::Result<Tensor> {
+            Ok(match self.model {
+                Model::Llama(ref model) => {
+                    model.forward(&input_ids, &seqlen_offsets, context_lens, None)?
+                }
+                Model::XLoraLlama(ref model) => model.forward(
+                    &input_ids,
+                    input_ids_full.as_ref().unwrap_or(&input_ids),
+                    &seqlen_offsets,
+                    seqlen_offsets_full.as_ref().unwrap_or(&seqlen_offsets),
+                    no_kv_cache,
+                    &self.non_granular_state,
+                    context_lens,
+                    &flash_meta,
+                    flash_meta_full.as_ref().unwrap_or(&flash_meta),
+                )?,
+            })
+        };
+        let logits = if use_cache || self.no_kv_cache {
+            forward()?
+        } else {
+            self.cache().with_empty_cache(forward)?
         };
         if return_raw_logits {
             Ok(ForwardInputsResult::RawLogits { logits })
         } else {
-            Ok(ForwardInputsResult::CausalGeneration { logits })
+            ForwardInputsResult::CausalGeneration { logits }.with_top_logprobs(return_logprobs)
         }
     }
     async fn sample_causal_gen(
@@ -563,7 +581,7 @@ impl Pipeline for GGMLPipeline {
         logits: Vec<Tensor>,
         prefix_cacher: &mut PrefixCacheManagerV2,
         disable_eos_stop: bool,
-        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
     ) -> Result<(), candle_core::Error> {
         sample_and_add_toks(self, seqs, logits, prefix_cacher, disable_eos_stop, rng).await
     }
@@ -1,5 +1,6 @@
 use super::cache_manager::{FullCacheManager, NormalCacheManager};
 use super::llg::build_tok_env;
+use super::pair_paged_attn_meta;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
     CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, PrettyName, QuantizationKind,
@@ -25,6 +26,7 @@ use crate::pipeline::loaders::DeviceMappedModelLoader;
 use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::ChatTemplate;
 use crate::prefix_cacher::PrefixCacheManagerV2;
+use crate::sampler::SamplingRng;
 use crate::sequence::Sequence;
 use crate::utils::gguf_metadata::{ContentConfig, GgufDeviceMapLoaderInner};
 use crate::utils::model_config as ModelConfig;
@@ -48,7 +50,6 @@ use candle_core::{Device, Tensor};
 use either::Either;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use mistralrs_quant::IsqType;
-use rand_isaac::Isaac64Rng;
 use std::any::Any;
 use std::fs;
 use std::num::{NonZero, NonZeroUsize};
@@ -468,6 +469,13 @@ impl Loader for GGUFLoader {
                 paged_attn_config.mem_gpu,
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
+                paged_attn_config.cache_type,
+                &self
+                    .config
+                    .topology
+                    .as_ref()
+                    .map(Topology::kv_cache_types)
+                    .unwrap_or_default(),
                 internal_dtype,
                 model_config,
                 device,
@@ -532,6 +540,11 @@ impl Loader for GGUFLoader {
             chat_template.unk_token = Some(BeginEndUnkPadTok(Either::Left(unk.unwrap())));
         }
 
+        let generation_sampling = gen_conf
+            .as_ref()
+            .map(|gen_conf| gen_conf.sampling().clone())
+            .unwrap_or_default();
+
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
         Ok(Arc::new(Mutex::new(GGUFPipeline {
             model,
@@ -563,6 +576,7 @@ impl Loader for GGUFLoader {
                 cache_engine,
                 prompt_chunksize: Some(NonZero::new(prompt_chunksize).unwrap()),
                 model_metadata: Some(Arc::new(model_config_metadata)),
+                generation_sampling,
             }),
             mapper: pipeline_mapper,
         })))
@@ -684,7 +698,10 @@ impl Pipeline for GGUFPipeline {
         &mut self,
         inputs: Box<dyn Any>,
         return_raw_logits: bool,
+        return_logprobs: Option<usize>,
     ) -> Result<ForwardInputsResult, candle_core::Error> {
+        let inputs: ModelInputs = *inputs.downcast().expect("Downcast failed.");
+        inputs.check_consistency()?;
         let ModelInputs {
             input_ids,
             input_ids_full,
@@ -695,63 +712,68 @@ impl Pipeline for GGUFPipeline {
             paged_attn_meta,
             flash_meta,
             flash_meta_full,
-        } = *inputs.downcast().expect("Downcast failed.");
+            use_cache,
+        } = inputs;
         let metadata = self.get_metadata();
-        let paged_attn_meta = match (&metadata.cache_engine, &paged_attn_meta) {
-            (Some(engine), Some(meta)) => Some((engine.get_kv_cache().clone(), meta)),
-            (Some(_), None) => {
-                // This can happen if Rust-side user code is wrong
-                candle_core::bail!("Forward step expected a PagedAttention input metadata. This was not provided, please ensure that the scheduler config is correctly configured for PagedAttention.")
-            }
-            (None, Some(_)) => {
-                // This should never happen but we handle it anyway
-                candle_core::bail!("Forward step got a PagedAttention input metadata but there is no cache engine. Please raise an issue.")
-            }
-            (None, None) => None,
+        if !use_cache && paged_attn_meta.is_some() {
+            candle_core::bail!(
+                "A forward pass without the KV cache is not supported with PagedAttention."
+            );
+        }
+        let paged_attn_meta =
+            pair_paged_attn_meta(metadata.cache_engine.as_ref(), paged_attn_meta.as_ref())?
+                .map(|(engine, meta)| (engine.get_kv_cache().clone(), meta));
+        let no_kv_cache = self.no_kv_cache || !use_cache;
+        let forward = || -> candle_core::Result<Tensor> {
+            Ok(match self.model {
+                Model::Llama(ref model) => {
+                    model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
+                }
+                Model::Phi2(ref model) => {
+                    model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
+                }
+                Model::XLoraLlama(ref model) => model.forward(
+                    &input_ids,
+                    input_ids_full.as_ref().unwrap_or(&input_ids),
+                    &seqlen_offsets,
+                    seqlen_offsets_full.as_ref().unwrap_or(&seqlen_offsets),
+                    no_kv_cache,
+                    &self.non_granular_state,
+                    context_lens,
+                    &flash_meta,
+                    flash_meta_full.as_ref().unwrap_or(&flash_meta),
+                )?,
+                Model::Phi3(ref model) => {
+                    model.forward(&input_ids, &seqlen_offsets, paged_attn_meta)?
+                }
+                Model::XLoraPhi3(ref model) => model.forward(
+                    &input_ids,
+                    input_ids_full.as_ref().unwrap_or(&input_ids),
+                    &seqlen_offsets,
+                    seqlen_offsets_full.as_ref().unwrap_or(&seqlen_offsets),
+                    no_kv_cache,
+                    &self.non_granular_state,
+                    context_lens,
+                    &flash_meta,
+                    flash_meta_full.as_ref().unwrap_or(&flash_meta),
+                )?,
+                Model::Starcoder2(ref model) => {
+                    model.forward(&input_ids, &seqlen_offsets, paged_attn_meta)?
+                }
+                Model::Qwen2(ref model) => {
+                    model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
+                }
+            })
         };
-        let logits = match self.model {
-            Model::Llama(ref model) => {
-                model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
-            }
-            Model::Phi2(ref model) => {
-                model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
-            }
-            Model::XLoraLlama(ref model) => model.forward(
-                &input_ids,
-                input_ids_full.as_ref().unwrap_or(&input_ids),
-                &seqlen_offsets,
-                seqlen_offsets_full.as_ref().unwrap_or(&seqlen_offsets),
-                self.no_kv_cache,
-                &self.non_granular_state,
-                context_lens,
-                &flash_meta,
-                flash_meta_full.as_ref().unwrap_or(&flash_meta),
-            )?,
-            Model::Phi3(ref model) => {
-                model.forward(&input_ids, &seqlen_offsets, paged_attn_meta)?
-            }
-            Model::XLoraPhi3(ref model) => model.forward(
-                &input_ids,
-                input_ids_full.as_ref().unwrap_or(&input_ids),
-                &seqlen_offsets,
-                seqlen_offsets_full.as_ref().unwrap_or(&seqlen_offsets),
-                self.no_kv_cache,
-                &self.non_granular_state,
-                context_lens,
-                &flash_meta,
-                flash_meta_full.as_ref().unwrap_or(&flash_meta),
-            )?,
-            Model::Starcoder2(ref model) => {
-                model.forward(&input_ids, &seqlen_offsets, paged_attn_meta)?
-            }
-            Model::Qwen2(ref model) => {
-                model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
-            }
+        let logits = if use_cache || self.no_kv_cache {
+            forward()?
+        } else {
+            self.cache().with_empty_cache(forward)?
         };
         if return_raw_logits {
             Ok(ForwardInputsResult::RawLogits { logits })
         } else {
-            Ok(ForwardInputsResult::CausalGeneration { logits })
+            ForwardInputsResult::CausalGeneration { logits }.with_top_logprobs(return_logprobs)
         }
     }
     async fn sample_causal_gen(
@@ -760,7 +782,7 @@ impl Pipeline for GGUFPipeline {
         logits: Vec<Tensor>,
         prefix_cacher: &mut PrefixCacheManagerV2,
         disable_eos_stop: bool,
-        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
     ) -> Result<(), candle_core::Error> {
         sample_and_add_toks(self, seqs, logits, prefix_cacher, disable_eos_stop, rng).await
     }
@@ -0,0 +1,104 @@
+use candle_core::{Context, DType, IndexOp, Result, Tensor, Var};
+
+use crate::layers_utils::with_differentiable;
+
+/// The scalar differentiated by [`super::Pipeline::input_embedding_gradients`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GradientTarget {
+    /// The logit of `token` at `position`.
+    Logit { position: usize, token: u32 },
+    /// The mean cross-entropy of predicting each token from the ones before it.
+    Loss,
+}
+
+impl GradientTarget {
+    /// The target computed from the `(seq_len, vocab)` logits of `tokens`.
+    fn scalar(&self, logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+        let (seq_len, vocab) = logits.dims2()?;
+        match *self {
+            Self::Logit { position, token } => {
+                if position >= seq_len || token as usize >= vocab {
+                    candle_core::bail!(
+                        "Logit of token {token} at position {position} is out of range for {seq_len} positions and a vocab of {vocab}."
+                    );
+                }
+                logits.i((position, token as usize))
+            }
+            Self::Loss => {
+                if seq_len < 2 {
+                    candle_core::bail!("The loss requires at least two tokens.");
+                }
+                let targets = Tensor::new(&tokens[1..], logits.device())?;
+                candle_nn::loss::cross_entropy(
+                    &logits.narrow(0, 0, seq_len - 1)?.to_dtype(DType::F32)?,
+                    &targets,
+                )
+            }
+        }
+    }
+}
+
+/// Compute the gradient of `target` with respect to `input_embeds`, the `(1, seq_len, hidden_size)`
+/// input embeddings of `tokens`. Returns a tensor of shape `(seq_len, hidden_size)`.
+///
+/// - `forward(input_embeds)` runs the model on the embeddings and returns the logits for every position
+///   with shape `(1, seq_len, vocab)`. It is called with differentiable forward passes enabled.
+pub(crate) fn input_embedding_gradients(
+    input_embeds: &Tensor,
+    tokens: &[u32],
+    target: GradientTarget,
+    forward: impl FnOnce(&Tensor) -> Result<Tensor>,
+) -> Result<Tensor> {
+    // The embeddings must be a leaf of the graph, rather than the output of the embedding lookup.
+    let input_embeds = Var::from_tensor(&input_embeds.detach())?;
+    let logits = with_differentiable(|| forward(input_embeds.as_tensor()))?;
+    let grads = target.scalar(&logits.squeeze(0)?, tokens)?.backward()?;
+    grads
+        .get(input_embeds.as_tensor())
+        .context("The target does not depend on the input embeddings.")?
+        .squeeze(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, IndexOp, Result, Tensor};
+
+    use super::{input_embedding_gradients, GradientTarget};
+
+    #[test]
+    fn linear_model_gradient_is_the_weight_row() -> Result<()> {
+        let dev = Device::Cpu;
+        // The logits are `embeds @ w`, so the gradient of logit `t` at `p` is column `t` of `w` at row `p`.
+        let w = Tensor::arange(0f32, 12., &dev)?.reshape((3, 4))?;
+        let embeds = Tensor::ones((1, 2, 3), candle_core::DType::F32, &dev)?;
+        let grad = input_embedding_gradients(
+            &embeds,
+            &[0, 1],
+            GradientTarget::Logit {
+                position: 1,
+                token: 2,
+            },
+            |embeds| embeds.broadcast_matmul(&w),
+        )?;
+        assert_eq!(grad.dims(), &[2, 3]);
+        assert_eq!(grad.i(0)?.to_vec1::<f32>()?, [0., 0., 0.]);
+        assert_eq!(
+            grad.i(1)?.to_vec1::<f32>()?,
+            w.i((.., 2))?.to_vec1::<f32>()?
+        );
+
+        let err = input_embedding_gradients(
+            &embeds,
+            &[0, 1],
+            GradientTarget::Logit {
+                position: 2,
+                token: 0,
+            },
+            |embeds| embeds.broadcast_matmul(&w),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("out of range"), "{err}");
+        Ok(())
+    }
+}
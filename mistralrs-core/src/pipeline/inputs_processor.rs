@@ -1,6 +1,6 @@
 #![allow(clippy::cast_possible_truncation)]
 
-use std::{any::Any, num::NonZeroUsize, sync::Arc};
+use std::{any::Any, num::NonZeroUsize, str::FromStr, sync::Arc};
 
 use anyhow::Result;
 use candle_core::Device;
@@ -11,6 +11,73 @@ use crate::{device_map::DeviceMapper, sequence::Sequence};
 
 pub const DEFAULT_PROMPT_CHUNK_SIZE: usize = 1024;
 
+/// The smallest prompt chunk size chosen by [`PromptChunksize::Auto`].
+const MIN_AUTO_PROMPT_CHUNK_SIZE: usize = 64;
+
+/// How long prompts are split into chunks for prefill.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PromptChunksize {
+    /// Process prompts in chunks of this many tokens.
+    Fixed(NonZeroUsize),
+    /// Choose the chunk size at load time from the device memory left after the weights and the
+    /// model's activation size.
+    Auto,
+}
+
+impl From<NonZeroUsize> for PromptChunksize {
+    fn from(size: NonZeroUsize) -> Self {
+        Self::Fixed(size)
+    }
+}
+
+impl FromStr for PromptChunksize {
+    type Err = anyhow::Error;
+
+    /// Parse `auto` or a strictly positive number of tokens.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+        s.parse::<usize>()
+            .ok()
+            .and_then(NonZeroUsize::new)
+            .map(Self::Fixed)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "`prompt_chunksize` must be `auto` or a strictly positive integer, got `{s}`."
+                )
+            })
+    }
+}
+
+impl PromptChunksize {
+    /// The chunk size for models which only support [`PromptChunksize::Fixed`].
+    pub fn fixed_only(chunksize: Option<Self>) -> Result<Option<NonZeroUsize>> {
+        match chunksize {
+            Some(Self::Fixed(size)) => Ok(Some(size)),
+            Some(Self::Auto) => {
+                anyhow::bail!("An automatic prompt chunk size is only supported for plain models.")
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// The largest power of two chunk size, between [`MIN_AUTO_PROMPT_CHUNK_SIZE`] and `max_seq_len`, whose
+/// activations take at most `budget_in_bytes`. `act_size_in_bytes` gives the peak activation size for a
+/// chunk size.
+pub(crate) fn auto_prompt_chunksize(
+    budget_in_bytes: usize,
+    max_seq_len: usize,
+    act_size_in_bytes: impl Fn(usize) -> Result<usize>,
+) -> Result<usize> {
+    let mut chunksize = MIN_AUTO_PROMPT_CHUNK_SIZE;
+    while chunksize * 2 <= max_seq_len && act_size_in_bytes(chunksize * 2)? <= budget_in_bytes {
+        chunksize *= 2;
+    }
+    Ok(chunksize)
+}
+
 #[derive(PartialEq)]
 pub enum InputsProcessorType {
     Text,
@@ -638,6 +705,51 @@ pub mod text_models_inputs_processor {
         pub paged_attn_meta: Option<PagedAttentionInputMetadata>,
         pub flash_meta: FlashParams,
         pub flash_meta_full: Option<FlashParams>,
+        /// When false, the forward pass runs on an empty KV cache and leaves the pipeline's cache
+        /// untouched, even if the pipeline was loaded with the KV cache enabled. The inputs must
+        /// then cover the whole sequence.
+        pub use_cache: bool,
+    }
+
+    impl ModelInputs {
+        /// Check that `seqlen_offsets`, `context_lens` and `position_ids` have one entry per
+        /// sequence of `input_ids` and that each context window lies within the sequence length.
+        /// Inputs built by hand can get this wrong, which otherwise fails deep in the model.
+        pub(crate) fn check_consistency(&self) -> candle_core::Result<()> {
+            let (batch, seq_len) = self.input_ids.dims2()?;
+            let per_seq = [
+                ("seqlen_offsets", self.seqlen_offsets.len()),
+                ("context_lens", self.context_lens.len()),
+                ("position_ids", self.position_ids.len()),
+            ];
+            for (name, len) in per_seq {
+                if len != batch {
+                    candle_core::bail!(
+                        "Inconsistent model inputs: `{name}` has {len} entries but `input_ids` has a batch size of {batch}."
+                    );
+                }
+            }
+            if let (Some(full), Some(offsets_full)) =
+                (&self.input_ids_full, &self.seqlen_offsets_full)
+            {
+                let (batch_full, _) = full.dims2()?;
+                if offsets_full.len() != batch_full {
+                    candle_core::bail!(
+                        "Inconsistent model inputs: `seqlen_offsets_full` has {} entries but `input_ids_full` has a batch size of {batch_full}.",
+                        offsets_full.len()
+                    );
+                }
+            }
+            for (i, &(start, len)) in self.context_lens.iter().enumerate() {
+                if start + len > seq_len {
+                    candle_core::bail!(
+                        "Inconsistent model inputs: `context_lens[{i}]` covers tokens {start}..{} but `input_ids` has a sequence length of {seq_len}.",
+                        start + len
+                    );
+                }
+            }
+            Ok(())
+        }
     }
 
     pub struct TextInputsProcessor;
@@ -722,6 +834,7 @@ pub mod text_models_inputs_processor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: Some(flash_meta_full),
+                            use_cache: !no_kv_cache,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -767,6 +880,7 @@ pub mod text_models_inputs_processor {
                             paged_attn_meta,
                             flash_meta: flash_meta.clone(),
                             flash_meta_full: Some(flash_meta),
+                            use_cache: !no_kv_cache,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -812,6 +926,7 @@ pub mod text_models_inputs_processor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: None,
+                            use_cache: !no_kv_cache,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -858,6 +973,7 @@ pub mod text_models_inputs_processor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: None,
+                            use_cache: !no_kv_cache,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -873,3 +989,91 @@ pub mod text_models_inputs_processor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::{
+        auto_prompt_chunksize,
+        text_models_inputs_processor::{make_prompt_chunk, InputMetadata, ModelInputs},
+    };
+
+    fn two_seq_inputs() -> anyhow::Result<ModelInputs> {
+        let InputMetadata {
+            input,
+            positions,
+            context_lens,
+            position_ids,
+            paged_attn_meta,
+            flash_meta,
+        } = make_prompt_chunk(
+            0,
+            vec![vec![1u32, 2, 3], vec![4, 5]],
+            &[0, 1],
+            &Device::Cpu,
+            None,
+            false,
+            None,
+            None,
+        )?;
+        Ok(ModelInputs {
+            input_ids: input,
+            input_ids_full: None,
+            seqlen_offsets: positions,
+            seqlen_offsets_full: None,
+            context_lens,
+            position_ids,
+            paged_attn_meta,
+            flash_meta,
+            flash_meta_full: None,
+            use_cache: true,
+        })
+    }
+
+    #[test]
+    fn model_inputs_consistency() -> anyhow::Result<()> {
+        two_seq_inputs()?.check_consistency()?;
+
+        let mut inputs = two_seq_inputs()?;
+        inputs.context_lens.pop();
+        let err = inputs.check_consistency().unwrap_err().to_string();
+        assert!(err.contains("`context_lens` has 1 entries"), "{err}");
+
+        let mut inputs = two_seq_inputs()?;
+        inputs.seqlen_offsets.push(0);
+        let err = inputs.check_consistency().unwrap_err().to_string();
+        assert!(err.contains("`seqlen_offsets` has 3 entries"), "{err}");
+
+        let mut inputs = two_seq_inputs()?;
+        inputs.position_ids.clear();
+        let err = inputs.check_consistency().unwrap_err().to_string();
+        assert!(err.contains("`position_ids` has 0 entries"), "{err}");
+
+        let mut inputs = two_seq_inputs()?;
+        inputs.context_lens[1] = (2, 2);
+        let err = inputs.check_consistency().unwrap_err().to_string();
+        assert!(
+            err.contains("`context_lens[1]` covers tokens 2..4"),
+            "{err}"
+        );
+
+        let mut inputs = two_seq_inputs()?;
+        inputs.input_ids_full = Some(inputs.input_ids.clone());
+        inputs.seqlen_offsets_full = Some(vec![0]);
+        let err = inputs.check_consistency().unwrap_err().to_string();
+        assert!(err.contains("`seqlen_offsets_full` has 1 entries"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn auto_prompt_chunksize_fits_the_budget() -> anyhow::Result<()> {
+        // Attention scores for 32 heads in f16, as for a typical 7B model.
+        let act_size = |chunksize: usize| Ok(32 * chunksize * chunksize * 2);
+        assert_eq!(auto_prompt_chunksize(1 << 30, 32768, act_size)?, 4096);
+        assert_eq!(auto_prompt_chunksize(1 << 30, 2048, act_size)?, 2048);
+        // Never below the minimum, even without memory to spare.
+        assert_eq!(auto_prompt_chunksize(0, 32768, act_size)?, 64);
+        Ok(())
+    }
+}
@@ -3,7 +3,7 @@ use std::{
     collections::{HashMap, HashSet},
     env,
     fs::File,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
     sync::{atomic::AtomicUsize, Arc},
     time::Instant,
@@ -15,8 +15,8 @@ use indicatif::{MultiProgress, ParallelProgressIterator, ProgressBar, ProgressSt
 use itertools::Itertools;
 use mistralrs_quant::{
     AfqLayer, CollectedImatrixData, ColumnParallelLayer, DistributedKind, FP8Linear, GgufMatMul,
-    HqqLayer, IsqType, QuantMethod, QuantizeOntoGuard, QuantizedSerde, QuantizedSerdeType,
-    ReplicatedLayer, RowParallelLayer, UnquantLinear,
+    HqqLayer, IsqType, QuantInfo, QuantMethod, QuantizeOntoGuard, QuantizedSerde,
+    QuantizedSerdeType, ReplicatedLayer, RowParallelLayer, UnquantLinear,
 };
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use regex::Regex;
@@ -31,6 +31,91 @@ pub(crate) const UQFF_RESIDUAL_SAFETENSORS: &str = "residual.safetensors";
 const MAX_UQFF_SIZE_BYTES: usize = 10 * 1024 * 1024 * 1024;
 pub const UQFF_MULTI_FILE_DELIMITER: &str = ";";
 
+/// Write the serialized ISQ tensors to `serialized`, or, if they are larger than `max_shard_bytes`,
+/// to `{stem}-{i}.uqff` shards next to it. A tensor larger than `max_shard_bytes` gets a shard of its own.
+/// Returns the written files.
+pub(crate) fn write_uqff_shards(
+    values: Vec<(String, Tensor)>,
+    serialized: &Path,
+    max_shard_bytes: usize,
+) -> candle_core::Result<Vec<PathBuf>> {
+    let mut shards: Vec<Vec<(String, Tensor)>> = vec![Vec::new()];
+    let mut shard_bytes = 0;
+    for (name, tensor) in values {
+        let bytes = tensor.elem_count() * tensor.dtype().size_in_bytes();
+        if shard_bytes + bytes > max_shard_bytes && !shards.last().unwrap().is_empty() {
+            shards.push(Vec::new());
+            shard_bytes = 0;
+        }
+        shard_bytes += bytes;
+        shards.last_mut().unwrap().push((name, tensor));
+    }
+
+    if shards.len() == 1 {
+        info!("Writing to `{}`", serialized.display());
+        safetensors::serialize_to_file(shards.pop().unwrap(), &None, serialized)?;
+        return Ok(vec![serialized.to_path_buf()]);
+    }
+
+    let parent = serialized
+        .parent()
+        .context("Target UQFF path must have a filename!")?;
+    let file_stem = serialized
+        .file_stem()
+        .context("Target UQFF path must have a file stem!")?
+        .to_string_lossy()
+        .to_string();
+    let mut files = Vec::new();
+    for (i, shard) in shards.into_iter().enumerate() {
+        let name = parent.join(format!("{file_stem}-{i}.uqff"));
+        info!("Writing shard {i} to `{}`", name.display());
+        safetensors::serialize_to_file(shard, &None, &name)?;
+        files.push(name);
+    }
+    Ok(files)
+}
+
+/// Expand a UQFF file name containing `*` wildcards against the files of the model repository,
+/// in sorted order. Names without a wildcard are returned as is.
+pub(crate) fn expand_uqff_pattern(
+    pattern: &str,
+    files: impl Iterator<Item = String>,
+) -> Result<Vec<String>> {
+    if !pattern.contains('*') {
+        return Ok(vec![pattern.to_string()]);
+    }
+    let re = Regex::new(&format!(
+        "^{}$",
+        regex::escape(pattern).replace(r"\*", "[^/]*")
+    ))?;
+    let matches = files
+        .filter(|f| re.is_match(f))
+        .sorted()
+        .collect::<Vec<_>>();
+    if matches.is_empty() {
+        anyhow::bail!("No UQFF files match `{pattern}`.");
+    }
+    Ok(matches)
+}
+
+/// Check that no ISQ tensor appears in more than one UQFF shard, as loading would silently keep only one.
+fn check_uqff_shard_keys(artifacts: &[PathBuf]) -> candle_core::Result<()> {
+    let mut seen: HashMap<String, &PathBuf> = HashMap::new();
+    for path in artifacts {
+        let shard = unsafe { candle_core::safetensors::MmapedSafetensors::new(path)? };
+        for (name, _) in shard.tensors() {
+            if let Some(other) = seen.insert(name.clone(), path) {
+                candle_core::bail!(
+                    "UQFF tensor `{name}` is in both `{}` and `{}`.",
+                    other.display(),
+                    path.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Parse ISQ value.
 ///
 /// If the provided value is a valid integer (one of 2,3,4,5,6,8), the best quantization type will be chosen.
@@ -154,6 +239,8 @@ pub struct UqffFullSer<'a> {
     pub config: String,
     pub processor_filename: &'a Option<PathBuf>,
     pub preprocessor_filename: &'a Option<PathBuf>,
+    /// Maximum size in MB of each written UQFF shard. Defaults to 10 GB.
+    pub shard_size_mb: Option<usize>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -259,6 +346,18 @@ pub trait IsqModel {
         candle_core::bail!("This model does not support quantizing with an imatrix.");
     }
 
+    /// The quantization of each ISQ layer, in the order of [`get_layers`]. Layers are named by their
+    /// imatrix name if the model provides one, otherwise by their index (as in a UQFF file).
+    fn quant_manifest(&mut self) -> Vec<(String, QuantInfo)> {
+        let names = self.imatrix_names().unwrap_or_default();
+        self.get_layers()
+            .0
+            .into_iter()
+            .enumerate()
+            .map(|(i, (layer, _))| (isq_layer_name(&names, i), layer.quant_info()))
+            .collect()
+    }
+
     /// Residual tensors for generating a UQFF file. Counterpart to [`get_layers`].
     fn residual_tensors(&self) -> Vec<(String, Tensor)>;
 
@@ -269,6 +368,9 @@ pub trait IsqModel {
 
     /// Quantize the model in-situ.
     ///
+    /// The ISQ type of each layer is taken from the first of `isq_overrides` matching its name (as in
+    /// [`quant_manifest`]), then from its layer in the `topology`, and finally from `dtype`.
+    ///
     /// This function will also create a UQFF file, or, if the model supports it (residual tensors are returned),
     /// a full serialization is created.
     #[allow(clippy::too_many_arguments)]
@@ -277,6 +379,7 @@ pub trait IsqModel {
         dtype: Option<IsqType>,
         device: Device,
         topology: Option<&Topology>,
+        isq_overrides: &[(Regex, IsqType)],
         silent: bool,
         imatrix_source: Option<ImatrixDataSource<'_>>,
         organization: IsqOrganization,
@@ -379,11 +482,34 @@ pub trait IsqModel {
                 }
             };
 
+            // Residual tensors are not modified by ISQ, so collect them first to detect tied layers.
+            let residual = write_artifacts.map(|_| match organization {
+                IsqOrganization::Default => self.residual_tensors(),
+                IsqOrganization::MoeExpertsOnly => self
+                    .residual_tensors_moe_experts_only()
+                    .unwrap_or(self.residual_tensors()),
+            });
+
+            // Only the default organization follows the order of the imatrix names.
+            let names = match organization {
+                IsqOrganization::Default if !isq_overrides.is_empty() => {
+                    self.imatrix_names().unwrap_or_default()
+                }
+                _ => Vec::new(),
+            };
+
             let (mut tensors, mapper) = match organization {
                 IsqOrganization::Default => self.get_layers(),
                 IsqOrganization::MoeExpertsOnly => self.get_layers_moe_experts_only(),
             };
 
+            // Compare against the source weights, before ISQ replaces the tied layers.
+            let tied = residual
+                .as_ref()
+                .map(|residual| tied_residual_layers(&tensors, residual))
+                .transpose()?
+                .unwrap_or_default();
+
             let imatrix_to_weight: Vec<Option<Vec<f32>>> =
                 if let Some(mut imatrix_to_weight) = imatrix_to_weight {
                     let ordered_keys = imatrix_to_weight
@@ -406,7 +532,7 @@ pub trait IsqModel {
                 for layer in topology.0.iter().flatten() {
                     if let LayerTopology {
                         isq: Some(isq_dtype),
-                        device: _,
+                        ..
                     } = layer
                     {
                         dtypes.insert(isq_dtype);
@@ -432,7 +558,8 @@ pub trait IsqModel {
             });
 
             let mut devices_and_dtypes = Vec::new();
-            for (_, layer_num) in &tensors {
+            let mut override_matched = vec![false; isq_overrides.len()];
+            for (i, (_, layer_num)) in tensors.iter().enumerate() {
                 let device = if let Some(ref layers) = layers {
                     if let Some(layer) = layer_num {
                         layers
@@ -452,7 +579,19 @@ pub trait IsqModel {
                 } else {
                     device.clone()
                 };
-                let dtype = if let Some(ref layers) = layers {
+                let mut overridden = None;
+                if !isq_overrides.is_empty() {
+                    let name = isq_layer_name(&names, i);
+                    for ((regex, isq), matched) in isq_overrides.iter().zip(&mut override_matched) {
+                        if regex.is_match(&name) {
+                            *matched = true;
+                            overridden = overridden.or(Some(*isq));
+                        }
+                    }
+                }
+                let dtype = if overridden.is_some() {
+                    overridden
+                } else if let Some(ref layers) = layers {
                     if let Some(layer) = layer_num {
                         layers.get(*layer).cloned().map(|x| x.0).unwrap_or(dtype)
                     } else {
@@ -463,6 +602,16 @@ pub trait IsqModel {
                 };
                 devices_and_dtypes.push((device, dtype));
             }
+            // A pattern which matches nothing is most likely a typo, or uses the wrong naming scheme.
+            if let Some(((regex, _), _)) = isq_overrides
+                .iter()
+                .zip(&override_matched)
+                .find(|(_, matched)| !**matched)
+            {
+                candle_core::bail!(
+                    "ISQ override `{regex}` matches no layer. Layers are named by their GGUF tensor name (`blk.0.attn_q.weight`) if the model has one and the organization is `default`, otherwise by their index."
+                );
+            }
 
             let t_start = Instant::now();
 
@@ -543,12 +692,27 @@ pub trait IsqModel {
                 }
             });
 
-            if let Some(serialized) = write_artifacts {
+            if let (Some(serialized), Some(residual)) = (write_artifacts, residual) {
                 info!(
                     "Serializing {total_tensors} ISQ tensors to `{}`.",
                     serialized.display()
                 );
 
+                let mut tied = tied;
+                tied.retain(|layer, name| {
+                    // A quantized layer no longer shares the residual tensor's data.
+                    if tensors[*layer].0.unquant_weight_bias().is_none() {
+                        warn!(
+                            "ISQ layer {layer} is tied to residual tensor `{name}` but was quantized, storing it separately."
+                        );
+                        return false;
+                    }
+                    info!(
+                        "ISQ layer {layer} is tied to residual tensor `{name}`, storing it once."
+                    );
+                    true
+                });
+
                 if serialized.extension().is_none_or(|ext| ext != "uqff") {
                     candle_core::bail!("UQFF output path extension must be `.uqff`",);
                 }
@@ -576,7 +740,9 @@ pub trait IsqModel {
                         tensors
                             .par_iter()
                             .enumerate()
-                            .filter(|(_, (layer, _))| layer.isq_serde_supported())
+                            .filter(|(i, (layer, _))| {
+                                layer.isq_serde_supported() && !tied.contains_key(i)
+                            })
                             .map(|(i, (layer, _))| {
                                 Ok((
                                     i.to_string(),
@@ -589,7 +755,9 @@ pub trait IsqModel {
                             .par_iter()
                             .enumerate()
                             .progress_with(bar)
-                            .filter(|(_, (layer, _))| layer.isq_serde_supported())
+                            .filter(|(i, (layer, _))| {
+                                layer.isq_serde_supported() && !tied.contains_key(i)
+                            })
                             .map(|(i, (layer, _))| {
                                 Ok((
                                     i.to_string(),
@@ -607,38 +775,10 @@ pub trait IsqModel {
 
                 std::fs::create_dir_all(parent)?;
 
-                let file_stem = serialized
-                    .file_stem()
-                    .context("Target UQFF path must have a file stem!")?
-                    .to_string_lossy()
-                    .to_string();
-
-                let size_estimate_bytes = quantized_values
-                    .iter()
-                    .map(|(_, x)| x.elem_count() * x.dtype().size_in_bytes())
-                    .sum::<usize>();
-                let n_files = size_estimate_bytes.div_ceil(MAX_UQFF_SIZE_BYTES);
-
-                if n_files == 1 {
-                    info!("Writing to `{}`", serialized.display());
-                    safetensors::serialize_to_file(quantized_values, &None, serialized)?;
-                } else {
-                    let chunksize = quantized_values.len() / n_files;
-                    let quantized_values_chunks = quantized_values.into_iter().chunks(chunksize);
-                    for (i, chunk) in quantized_values_chunks.into_iter().enumerate() {
-                        let mut name = parent.to_path_buf();
-                        name.push(format!("{file_stem}-{i}.uqff"));
-                        info!("Writing shard {i} to `{}`", name.display());
-                        safetensors::serialize_to_file(chunk, &None, &name)?;
-                    }
-                }
-
-                let residual = match organization {
-                    IsqOrganization::Default => self.residual_tensors(),
-                    IsqOrganization::MoeExpertsOnly => self
-                        .residual_tensors_moe_experts_only()
-                        .unwrap_or(self.residual_tensors()),
-                };
+                let shard_size_bytes = full_ser
+                    .shard_size_mb
+                    .map_or(MAX_UQFF_SIZE_BYTES, |mb| mb * 1024 * 1024);
+                write_uqff_shards(quantized_values, serialized, shard_size_bytes)?;
 
                 let residual_out = parent.join(UQFF_RESIDUAL_SAFETENSORS);
                 let config_out = parent.join("config.json");
@@ -663,6 +803,7 @@ pub trait IsqModel {
                     config,
                     processor_filename,
                     preprocessor_filename,
+                    shard_size_mb: _,
                 } = full_ser;
 
                 info!("Serializing configuration to `{}`.", config_out.display());
@@ -765,6 +906,9 @@ pub trait IsqModel {
             comms.push(mapper.get_comm_for(layer_num.unwrap_or(0))?)
         }
 
+        if artifacts.len() > 1 {
+            check_uqff_shard_keys(artifacts)?;
+        }
         let artifacts = unsafe { candle_core::safetensors::MmapedSafetensors::multi(artifacts)? };
 
         let artifact_isqs = artifacts
@@ -779,7 +923,15 @@ pub trait IsqModel {
             })
             .collect::<HashMap<_, _>>();
 
-        if artifact_isqs.len() != total_tensors {
+        // Layers tied to a residual tensor are not serialized, and already hold their weights.
+        let n_tied = tensors
+            .iter()
+            .enumerate()
+            .filter(|(i, (layer, _))| {
+                !artifact_isqs.contains_key(i) && layer.unquant_weight_bias().is_some()
+            })
+            .count();
+        if artifact_isqs.len() + n_tied != total_tensors {
             candle_core::bail!(
                 "Number of artifacts ({}) does not match the number of ISQ layers ({total_tensors})",
                 artifact_isqs.len(),
@@ -952,6 +1104,48 @@ pub trait IsqModel {
     }
 }
 
+/// ISQ layers whose unquantized weight is one of the residual tensors (e.g. an `lm_head` tied to
+/// the embeddings), mapped to that tensor's name. These are only stored in the residual tensors,
+/// and the tie is made again when the model is loaded.
+#[allow(clippy::type_complexity)]
+fn tied_residual_layers(
+    layers: &[(&mut Arc<dyn QuantMethod>, Option<usize>)],
+    residual: &[(String, Tensor)],
+) -> candle_core::Result<HashMap<usize, String>> {
+    let mut tied = HashMap::new();
+    for (i, (layer, _)) in layers.iter().enumerate() {
+        let Some((weight, None)) = layer.unquant_weight_bias() else {
+            continue;
+        };
+        for (name, tensor) in residual {
+            if weight.id() == tensor.id() || same_data(&weight, tensor)? {
+                tied.insert(i, name.clone());
+                break;
+            }
+        }
+    }
+    Ok(tied)
+}
+
+/// The name of ISQ layer `i`: its imatrix name if the model provides one, otherwise its index (as in a
+/// UQFF file). Models without imatrix names, and the MoQE organization, only have index names.
+fn isq_layer_name(names: &[Option<String>], i: usize) -> String {
+    names
+        .get(i)
+        .cloned()
+        .flatten()
+        .unwrap_or_else(|| i.to_string())
+}
+
+/// Whether two tensors hold the same values, even if one is a copy of the other on another device.
+fn same_data(a: &Tensor, b: &Tensor) -> candle_core::Result<bool> {
+    if a.shape() != b.shape() || a.dtype() != b.dtype() {
+        return Ok(false);
+    }
+    let eq = a.eq(&b.to_device(a.device())?)?;
+    Ok(eq.flatten_all()?.min(0)?.to_scalar::<u8>()? == 1)
+}
+
 /// Trait for loading models with ISQ.
 pub(crate) trait IsqModelLoader {
     /// Regex to match layers which will have standard ISQ applied.
@@ -968,3 +1162,500 @@ pub(crate) trait IsqModelLoader {
         self.isq_layer_regexes(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, sync::Arc};
+
+    use candle_core::{DType, Device, Tensor};
+    use indicatif::MultiProgress;
+    use mistralrs_quant::{
+        DummyLayer, IsqType, QuantInfo, QuantMethod, QuantizedSerde, ReplicatedLayer,
+    };
+    use regex::Regex;
+    use tokenizers::{models::wordlevel::WordLevel, Tokenizer};
+
+    use super::{
+        expand_uqff_pattern, write_uqff_shards, IsqModel, IsqOrganization, UqffFullSer,
+        UQFF_RESIDUAL_SAFETENSORS,
+    };
+    use crate::{device_map::DeviceMapper, topology::LayerTopology, DeviceMapSetting, Topology};
+
+    /// An embedding with a tied `lm_head` and one other ISQ layer.
+    struct TiedModel {
+        embed: Tensor,
+        lm_head: Arc<dyn QuantMethod>,
+        proj: Arc<dyn QuantMethod>,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+    }
+
+    impl TiedModel {
+        fn new(embed: Tensor, proj: Arc<dyn QuantMethod>) -> anyhow::Result<Self> {
+            let lm_head =
+                ReplicatedLayer::from_linear(candle_nn::Linear::new(embed.clone(), None))?;
+            Ok(Self {
+                embed,
+                lm_head,
+                proj,
+                mapper: DeviceMapSetting::dummy().into_mapper(1, &Device::Cpu, None)?,
+            })
+        }
+    }
+
+    impl IsqModel for TiedModel {
+        fn get_layers(
+            &mut self,
+        ) -> (
+            Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+            &dyn DeviceMapper,
+        ) {
+            (
+                vec![(&mut self.lm_head, None), (&mut self.proj, Some(0))],
+                &*self.mapper,
+            )
+        }
+
+        fn residual_tensors(&self) -> Vec<(String, Tensor)> {
+            vec![("embed_tokens.weight".to_string(), self.embed.clone())]
+        }
+    }
+
+    #[test]
+    fn uqff_stores_tied_embeddings_once() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let (vocab, hidden) = (64, 32);
+        let dir = std::env::temp_dir().join(format!("mistralrs-tied-uqff-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let uqff = dir.join("model.uqff");
+
+        let embed = Tensor::randn(0f32, 1., (vocab, hidden), &dev)?;
+        let proj = ReplicatedLayer::from_linear(candle_nn::Linear::new(
+            Tensor::randn(0f32, 1., (hidden, hidden), &dev)?,
+            None,
+        ))?;
+        let mut model = TiedModel::new(embed, proj)?;
+
+        // Only the decoder layer is quantized, the tied head keeps sharing the embedding.
+        let topology = Topology(vec![Some(LayerTopology {
+            isq: Some(IsqType::Q8_0),
+            device: None,
+            kv_cache: None,
+        })]);
+        let tokenizer = Tokenizer::new(WordLevel::default());
+        model.quantize(
+            None,
+            dev.clone(),
+            Some(&topology),
+            &[],
+            true,
+            None,
+            IsqOrganization::Default,
+            Some(&uqff),
+            UqffFullSer {
+                tokenizer: &tokenizer,
+                template_filename: &None,
+                generation_config: None,
+                config: "{}".to_string(),
+                processor_filename: &None,
+                preprocessor_filename: &None,
+                shard_size_mb: None,
+            },
+            Arc::new(MultiProgress::new()),
+        )?;
+
+        // The head is not serialized again alongside the embedding.
+        let artifacts = candle_core::safetensors::load(&uqff, &dev)?;
+        assert_eq!(artifacts.keys().collect::<Vec<_>>(), vec!["1"]);
+        let embed_bytes = vocab * hidden * DType::F32.size_in_bytes();
+        assert!((std::fs::metadata(&uqff)?.len() as usize) < embed_bytes);
+
+        // Reload: the tie is made again from the residual embedding, the other layer from UQFF.
+        let residual = candle_core::safetensors::load(dir.join(UQFF_RESIDUAL_SAFETENSORS), &dev)?;
+        let embed = residual["embed_tokens.weight"].clone();
+        let mut reloaded = TiedModel::new(embed.clone(), Arc::new(DummyLayer))?;
+        reloaded.load_from_artifacts(dev.clone(), None, true, &[uqff])?;
+        std::fs::remove_dir_all(&dir)?;
+
+        let (head_weight, _) = reloaded.lm_head.unquant_weight_bias().unwrap();
+        assert_eq!(head_weight.id(), embed.id());
+        assert_eq!(reloaded.proj.name(), model.proj.name());
+        Ok(())
+    }
+
+    #[test]
+    fn uqff_stores_quantized_tied_head_separately() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let (vocab, hidden) = (64, 32);
+        let dir = std::env::temp_dir().join(format!(
+            "mistralrs-quantized-tied-uqff-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let uqff = dir.join("model.uqff");
+
+        let embed = Tensor::randn(0f32, 1., (vocab, hidden), &dev)?;
+        let proj = ReplicatedLayer::from_linear(candle_nn::Linear::new(
+            Tensor::randn(0f32, 1., (hidden, hidden), &dev)?,
+            None,
+        ))?;
+        let mut model = TiedModel::new(embed, proj)?;
+
+        // The tied head is quantized too, so it no longer matches the embedding.
+        let tokenizer = Tokenizer::new(WordLevel::default());
+        model.quantize(
+            Some(IsqType::Q8_0),
+            dev.clone(),
+            None,
+            &[],
+            true,
+            None,
+            IsqOrganization::Default,
+            Some(&uqff),
+            UqffFullSer {
+                tokenizer: &tokenizer,
+                template_filename: &None,
+                generation_config: None,
+                config: "{}".to_string(),
+                processor_filename: &None,
+                preprocessor_filename: &None,
+                shard_size_mb: None,
+            },
+            Arc::new(MultiProgress::new()),
+        )?;
+
+        let artifacts = candle_core::safetensors::load(&uqff, &dev)?;
+        std::fs::remove_dir_all(&dir)?;
+        let mut keys = artifacts.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["0", "1"]);
+        Ok(())
+    }
+
+    /// A stack of decoder layers with one ISQ layer each.
+    struct StackModel {
+        layers: Vec<Arc<dyn QuantMethod>>,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+    }
+
+    impl IsqModel for StackModel {
+        fn get_layers(
+            &mut self,
+        ) -> (
+            Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+            &dyn DeviceMapper,
+        ) {
+            (
+                self.layers
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(i, layer)| (layer, Some(i)))
+                    .collect(),
+                &*self.mapper,
+            )
+        }
+
+        fn residual_tensors(&self) -> Vec<(String, Tensor)> {
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn quant_manifest_reflects_topology() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let hidden = 256;
+        let layers = (0..3)
+            .map(|_| {
+                Ok(ReplicatedLayer::from_linear(candle_nn::Linear::new(
+                    Tensor::randn(0f32, 1., (hidden, hidden), &dev)?,
+                    None,
+                ))?)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut model = StackModel {
+            layers,
+            mapper: DeviceMapSetting::dummy().into_mapper(3, &dev, None)?,
+        };
+
+        // The last layer is left unquantized.
+        let topology = Topology(
+            [Some(IsqType::Q4K), Some(IsqType::HQQ4), None]
+                .into_iter()
+                .map(|isq| {
+                    Some(LayerTopology {
+                        isq,
+                        device: None,
+                        kv_cache: None,
+                    })
+                })
+                .collect(),
+        );
+        let tokenizer = Tokenizer::new(WordLevel::default());
+        model.quantize(
+            None,
+            dev.clone(),
+            Some(&topology),
+            &[],
+            true,
+            None,
+            IsqOrganization::Default,
+            None,
+            UqffFullSer {
+                tokenizer: &tokenizer,
+                template_filename: &None,
+                generation_config: None,
+                config: "{}".to_string(),
+                processor_filename: &None,
+                preprocessor_filename: &None,
+                shard_size_mb: None,
+            },
+            Arc::new(MultiProgress::new()),
+        )?;
+
+        assert_eq!(
+            model.quant_manifest(),
+            vec![
+                (
+                    "0".to_string(),
+                    QuantInfo {
+                        method: "gguf",
+                        bits: Some(4),
+                        group_size: Some(256),
+                    }
+                ),
+                (
+                    "1".to_string(),
+                    QuantInfo {
+                        method: "hqq",
+                        bits: Some(4),
+                        group_size: Some(64),
+                    }
+                ),
+                (
+                    "2".to_string(),
+                    QuantInfo {
+                        method: "unquant-linear",
+                        bits: Some(32),
+                        group_size: None,
+                    }
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn isq_overrides_take_precedence_over_topology() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let hidden = 256;
+        let layers = (0..4)
+            .map(|_| {
+                Ok(ReplicatedLayer::from_linear(candle_nn::Linear::new(
+                    Tensor::randn(0f32, 1., (hidden, hidden), &dev)?,
+                    None,
+                ))?)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut model = StackModel {
+            layers,
+            mapper: DeviceMapSetting::dummy().into_mapper(4, &dev, None)?,
+        };
+
+        // The topology only covers the first 3 layers, the last one falls back to the global type.
+        let topology = Topology(vec![
+            Some(LayerTopology {
+                isq: Some(IsqType::Q4K),
+                device: None,
+                kv_cache: None,
+            });
+            3
+        ]);
+        let overrides = [
+            (Regex::new("^1$")?, IsqType::Q8_0),
+            (Regex::new("^[12]$")?, IsqType::HQQ4),
+        ];
+        let tokenizer = Tokenizer::new(WordLevel::default());
+        model.quantize(
+            Some(IsqType::HQQ8),
+            dev.clone(),
+            Some(&topology),
+            &overrides,
+            true,
+            None,
+            IsqOrganization::Default,
+            None,
+            UqffFullSer {
+                tokenizer: &tokenizer,
+                template_filename: &None,
+                generation_config: None,
+                config: "{}".to_string(),
+                processor_filename: &None,
+                preprocessor_filename: &None,
+                shard_size_mb: None,
+            },
+            Arc::new(MultiProgress::new()),
+        )?;
+
+        let quantized = model
+            .quant_manifest()
+            .into_iter()
+            .map(|(_, info)| (info.method, info.bits))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            quantized,
+            vec![
+                ("gguf", Some(4)),
+                ("gguf", Some(8)),
+                ("hqq", Some(4)),
+                ("hqq", Some(8)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn isq_override_matching_no_layer_is_an_error() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let layers = (0..2)
+            .map(|_| {
+                Ok(ReplicatedLayer::from_linear(candle_nn::Linear::new(
+                    Tensor::randn(0f32, 1., (64, 64), &dev)?,
+                    None,
+                ))?)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut model = StackModel {
+            layers,
+            mapper: DeviceMapSetting::dummy().into_mapper(2, &dev, None)?,
+        };
+
+        // Without imatrix names the layers are named `0` and `1`.
+        let overrides = [
+            (Regex::new("^1$")?, IsqType::Q8_0),
+            (Regex::new(r"^blk\.1\.")?, IsqType::Q8_0),
+        ];
+        let tokenizer = Tokenizer::new(WordLevel::default());
+        let err = model
+            .quantize(
+                None,
+                dev.clone(),
+                None,
+                &overrides,
+                true,
+                None,
+                IsqOrganization::Default,
+                None,
+                UqffFullSer {
+                    tokenizer: &tokenizer,
+                    template_filename: &None,
+                    generation_config: None,
+                    config: "{}".to_string(),
+                    processor_filename: &None,
+                    preprocessor_filename: &None,
+                    shard_size_mb: None,
+                },
+                Arc::new(MultiProgress::new()),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains(r"`^blk\.1\.` matches no layer"));
+        Ok(())
+    }
+
+    #[test]
+    fn uqff_round_trips_through_shards() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let hidden = 64;
+        let layers = (0..2)
+            .map(|_| {
+                Ok(ReplicatedLayer::from_linear(candle_nn::Linear::new(
+                    Tensor::randn(0f32, 1., (hidden, hidden), &dev)?,
+                    None,
+                ))?)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut model = StackModel {
+            layers,
+            mapper: DeviceMapSetting::dummy().into_mapper(2, &dev, None)?,
+        };
+        let topology = Topology(vec![
+            Some(LayerTopology {
+                isq: Some(IsqType::Q8_0),
+                device: None,
+                kv_cache: None,
+            });
+            2
+        ]);
+        let tokenizer = Tokenizer::new(WordLevel::default());
+        model.quantize(
+            None,
+            dev.clone(),
+            Some(&topology),
+            &[],
+            true,
+            None,
+            IsqOrganization::Default,
+            None,
+            UqffFullSer {
+                tokenizer: &tokenizer,
+                template_filename: &None,
+                generation_config: None,
+                config: "{}".to_string(),
+                processor_filename: &None,
+                preprocessor_filename: &None,
+                shard_size_mb: None,
+            },
+            Arc::new(MultiProgress::new()),
+        )?;
+
+        let serialized = model
+            .layers
+            .iter()
+            .map(|layer| layer.serialize().map(Cow::into_owned))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let values = serialized
+            .iter()
+            .enumerate()
+            .map(|(i, data)| Ok((i.to_string(), Tensor::new(data.as_slice(), &dev)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // Each layer fills a shard on its own.
+        let dir =
+            std::env::temp_dir().join(format!("mistralrs-uqff-shards-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let files = write_uqff_shards(values, &dir.join("model.uqff"), serialized[0].len())?;
+        assert_eq!(
+            files,
+            vec![dir.join("model-0.uqff"), dir.join("model-1.uqff")]
+        );
+
+        let names = std::fs::read_dir(&dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(
+            expand_uqff_pattern("model-*.uqff", names.into_iter())?,
+            vec!["model-0.uqff", "model-1.uqff"]
+        );
+
+        let mut reloaded = StackModel {
+            layers: vec![Arc::new(DummyLayer), Arc::new(DummyLayer)],
+            mapper: DeviceMapSetting::dummy().into_mapper(2, &dev, None)?,
+        };
+        reloaded.load_from_artifacts(dev.clone(), None, true, &files)?;
+        for (layer, data) in reloaded.layers.iter().zip(&serialized) {
+            assert_eq!(layer.serialize()?.as_ref(), data.as_slice());
+        }
+
+        // The same tensor in two shards is rejected rather than silently shadowed.
+        let err = reloaded
+            .load_from_artifacts(
+                dev.clone(),
+                None,
+                true,
+                &[files[0].clone(), files[0].clone()],
+            )
+            .unwrap_err()
+            .to_string();
+        std::fs::remove_dir_all(&dir)?;
+        assert!(err.contains("UQFF tensor `0` is in both"), "{err}");
+        Ok(())
+    }
+}
@@ -10,6 +10,8 @@ use tokenizers::Tokenizer;
 
 use crate::Constraint;
 
+use super::schema_regex::json_schema_to_regex;
+
 pub fn build_tok_env(tokenizer: Tokenizer) -> TokEnv {
     let bt = toktrie_hf_tokenizers::ByteTokenizer::from_tokenizer(tokenizer)
         .expect("Failed to create ByteTokenizer from Tokenizer");
@@ -23,6 +25,9 @@ pub fn llg_grammar_from_constraint(constraint: &Constraint) -> Result<Option<Top
         Constraint::Regex(regex) => TopLevelGrammar::from_regex(regex),
         Constraint::Lark(lark) => TopLevelGrammar::from_lark(lark.clone()),
         Constraint::JsonSchema(value) => TopLevelGrammar::from_json_schema(value.clone()),
+        Constraint::JsonSchemaRegex(value) => {
+            TopLevelGrammar::from_regex(&json_schema_to_regex(value)?)
+        }
         Constraint::Llguidance(value) => value.clone(),
         Constraint::None => return Ok(None),
     };
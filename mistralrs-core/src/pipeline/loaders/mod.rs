@@ -3,6 +3,7 @@ mod normal_loaders;
 mod vision_loaders;
 
 use std::{
+    collections::HashMap,
     fmt::{self, Debug, Display},
     path::PathBuf,
     str::FromStr,
@@ -11,15 +12,20 @@ use std::{
 
 use anyhow::{Context, Result};
 use as_any::AsAny;
-use candle_core::{DType, Device};
+use candle_core::{DType, Device, Tensor};
 use itertools::Itertools;
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{IsqType, ShardedSafeTensors, ShardedVarBuilder};
 use tokio::sync::Mutex;
 
+pub(crate) use normal_loaders::{
+    override_activation, override_max_cached_rope_positions, override_num_experts_per_tok,
+    validate_weight_shapes,
+};
 pub use normal_loaders::{
-    AutoLoader, DeepSeekV2Loader, DeepSeekV3Loader, Gemma2Loader, GemmaLoader, LlamaLoader,
-    MistralLoader, MixtralLoader, NormalLoaderType, NormalLoadingMetadata, NormalModel,
-    NormalModelLoader, Phi2Loader, Phi3Loader, Phi3_5MoELoader, Qwen2Loader, Starcoder2Loader,
+    AutoLoader, CohereLoader, DeepSeekV2Loader, DeepSeekV3Loader, Gemma2Loader, GemmaLoader,
+    LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType, NormalLoadingMetadata,
+    NormalModel, NormalModelLoader, Phi2Loader, Phi3Loader, Phi3SmallLoader, Phi3_5MoELoader,
+    Qwen2Loader, SharedEmbeddings, Starcoder2Loader,
 };
 
 use tracing::{info, warn};
@@ -36,9 +42,12 @@ pub use diffusion_loaders::{
 
 use crate::{
     paged_attention::{
-        calculate_cache_config, ModelConfigLike, DEFAULT_PAGED_ATTENTION_BLOCK_SIZE,
+        calculate_cache_config, ModelConfigLike, PagedCacheType, DEFAULT_PAGED_ATTENTION_BLOCK_SIZE,
+    },
+    utils::{
+        debug::DeviceRepr,
+        varbuilder_utils::{from_mmaped_safetensors, DeviceForLoadTensor},
     },
-    utils::debug::DeviceRepr,
     DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, MemoryUsage, PagedAttentionConfig,
     TryIntoDType,
 };
@@ -153,6 +162,85 @@ impl ModelPaths for LocalModelPaths<PathBuf> {
     }
 }
 
+/// Where the weights come from when loading a model from in-memory parts via
+/// [`Loader::load_model_from_parts`].
+pub enum WeightSource {
+    /// Paths to `.safetensors` files.
+    Safetensors(Vec<PathBuf>),
+    /// In-memory buffers, each holding the contents of one `.safetensors` file.
+    SafetensorsBuffers(Vec<Vec<u8>>),
+    /// Already materialized tensors, keyed by their checkpoint name.
+    Tensors(HashMap<String, Tensor>),
+}
+
+impl WeightSource {
+    /// Build a `VarBuilder` over these weights. All tensors are placed on `device`.
+    pub(crate) fn into_var_builder(
+        self,
+        dtype: DType,
+        device: &Device,
+        silent: bool,
+    ) -> candle_core::Result<ShardedVarBuilder> {
+        match self {
+            Self::Safetensors(paths) => from_mmaped_safetensors(
+                paths,
+                Vec::new(),
+                Some(dtype),
+                device,
+                vec![None],
+                silent,
+                None,
+                |_| true,
+                Arc::new(|_| DeviceForLoadTensor::Base),
+            ),
+            Self::SafetensorsBuffers(buffers) => {
+                let mut tensors = HashMap::new();
+                for buffer in buffers {
+                    tensors.extend(candle_core::safetensors::load_buffer(&buffer, device)?);
+                }
+                Ok(ShardedSafeTensors::wrap(
+                    Box::new(tensors),
+                    dtype,
+                    device.clone(),
+                ))
+            }
+            Self::Tensors(tensors) => Ok(ShardedSafeTensors::wrap(
+                Box::new(tensors),
+                dtype,
+                device.clone(),
+            )),
+        }
+    }
+
+    /// Check the shapes of these weights against `expected`, see [`validate_weight_shapes`]. Only
+    /// the headers are read, no tensor data is loaded.
+    pub(crate) fn validate_shapes(&self, expected: &[(String, Vec<usize>)]) -> Result<()> {
+        match self {
+            Self::Safetensors(paths) => {
+                let weights = unsafe { candle_core::safetensors::MmapedSafetensors::multi(paths)? };
+                validate_weight_shapes(expected, |name| {
+                    weights.get(name).ok().map(|view| view.shape().to_vec())
+                })
+            }
+            Self::SafetensorsBuffers(buffers) => {
+                let weights = buffers
+                    .iter()
+                    .map(|buffer| safetensors::SafeTensors::deserialize(buffer))
+                    .collect::<Result<Vec<_>, _>>()?;
+                validate_weight_shapes(expected, |name| {
+                    weights
+                        .iter()
+                        .find_map(|weights| weights.tensor(name).ok())
+                        .map(|view| view.shape().to_vec())
+                })
+            }
+            Self::Tensors(tensors) => validate_weight_shapes(expected, |name| {
+                tensors.get(name).map(|tensor| tensor.dims().to_vec())
+            }),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 /// The source of the HF token.
 pub enum TokenSource {
@@ -511,6 +599,9 @@ pub trait DeviceMappedModelLoader {
                             .block_size
                             .unwrap_or(DEFAULT_PAGED_ATTENTION_BLOCK_SIZE),
                     ),
+                    // The int8 cache never needs more memory than the unquantized one.
+                    PagedCacheType::Auto,
+                    &[],
                     dtype,
                     &*model_cfg,
                     &devices[0],
@@ -765,6 +856,1889 @@ pub trait Loader: Send + Sync {
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>>;
 
+    /// Load a model from a config JSON string, the bytes of a serialized `tokenizer.json` and a
+    /// [`WeightSource`], bypassing the [`ModelPaths`] machinery entirely. The model is loaded on a
+    /// single device. Not all loaders support this.
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_parts(
+        &self,
+        _config: &str,
+        _tokenizer: &[u8],
+        _weights: WeightSource,
+        _dtype: &dyn TryIntoDType,
+        _device: &Device,
+        _silent: bool,
+        _in_situ_quant: Option<IsqType>,
+        _paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        anyhow::bail!(
+            "Loader for `{}` does not support loading from in-memory parts.",
+            self.get_id()
+        )
+    }
+
+    /// Estimate what loading this model would take from its config and safetensors index alone,
+    /// without fetching or loading the weights. Not all loaders support this.
+    fn dry_run(
+        &self,
+        _revision: Option<String>,
+        _token_source: TokenSource,
+        _dtype: &dyn TryIntoDType,
+        _device: &Device,
+    ) -> Result<ResourceEstimate> {
+        anyhow::bail!("Loader for `{}` does not support dry runs.", self.get_id())
+    }
+
+    /// Check that a model config is loadable before downloading or loading anything. Failures are a
+    /// [`ConfigValidationError`] naming the offending field. Not all loaders support this.
+    fn validate_config(&self, _config: &str) -> Result<()> {
+        anyhow::bail!(
+            "Loader for `{}` does not support config validation.",
+            self.get_id()
+        )
+    }
+
+    /// Parse a model config into a [`NormalizedConfig`], which names the same concepts the same way
+    /// across model families. Not all loaders support this.
+    fn normalized_config(&self, _config: &str) -> Result<NormalizedConfig> {
+        anyhow::bail!(
+            "Loader for `{}` does not support normalizing configs.",
+            self.get_id()
+        )
+    }
+
+    /// Make subsequent loads reference `shared` instead of loading their own input embedding and LM
+    /// head, for a speculative decoding draft model which shares them with its target. Not all
+    /// loaders support this.
+    fn set_shared_embeddings(&self, _shared: SharedEmbeddings) -> Result<()> {
+        anyhow::bail!(
+            "Loader for `{}` does not support shared embeddings.",
+            self.get_id()
+        )
+    }
+
     fn get_id(&self) -> String;
     fn get_kind(&self) -> ModelKind;
 }
+
+/// The result of [`Loader::dry_run`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResourceEstimate {
+    /// Number of repeating (device-mappable) layers.
+    pub num_layers: usize,
+    /// The dtype the weights would be loaded in.
+    pub dtype: DType,
+    /// Parameters estimated from the config.
+    pub num_parameters: usize,
+    /// Memory the weights would take at `dtype`.
+    pub weight_size_in_bytes: usize,
+    /// `metadata.total_size` of the safetensors index, in the checkpoint's own dtype(s), if the
+    /// model has an index.
+    pub checkpoint_size_in_bytes: Option<usize>,
+    /// Memory available on the target device, if it can be queried.
+    pub device_available_bytes: Option<usize>,
+}
+
+impl ResourceEstimate {
+    /// Whether the weights fit in the memory available on the target device. The KV cache and
+    /// activations need room on top of this.
+    pub fn fits_device(&self) -> Option<bool> {
+        self.device_available_bytes
+            .map(|available| self.weight_size_in_bytes <= available)
+    }
+}
+
+/// A model config with its key names unified across model families, from [`Loader::normalized_config`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct NormalizedConfig {
+    pub hidden_size: usize,
+    pub num_layers: usize,
+    pub num_heads: usize,
+    pub num_kv_heads: usize,
+    pub head_dim: usize,
+    pub vocab_size: usize,
+    /// The maximum sequence length, including the default of families where it is optional.
+    pub max_position: usize,
+    /// Not set for models without RoPE or which leave it to the default of their family.
+    pub rope_theta: Option<f64>,
+    pub intermediate_size: usize,
+    /// The MLP activation, as named in the config.
+    pub activation: Option<String>,
+}
+
+impl NormalizedConfig {
+    /// Take the attention shape from the loader's `model_config` and the other fields from the first of
+    /// their known key names present in the raw `config`.
+    pub(crate) fn new(model_config: &dyn ModelConfigLike, config: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(config)?;
+        let field = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|key| value.get(key).filter(|x| !x.is_null()))
+        };
+        let usize_field = |keys: &[&str]| -> Result<usize> {
+            field(keys)
+                .and_then(serde_json::Value::as_u64)
+                .map(|x| x as usize)
+                .ok_or_else(|| {
+                    ConfigValidationError::new(keys[0], "Missing or not an integer.").into()
+                })
+        };
+        Ok(Self {
+            hidden_size: model_config.hidden_size(),
+            num_layers: model_config.num_layers(),
+            num_heads: model_config.num_attn_heads(),
+            num_kv_heads: model_config.num_kv_heads(),
+            head_dim: model_config.k_head_dim(),
+            vocab_size: usize_field(&["vocab_size", "padded_vocab_size"])?,
+            max_position: model_config.max_seq_len(),
+            rope_theta: field(&["rope_theta", "rotary_emb_base"])
+                .and_then(serde_json::Value::as_f64),
+            intermediate_size: usize_field(&["intermediate_size", "ffn_hidden_size", "n_inner"])?,
+            activation: field(&["hidden_activation", "hidden_act", "activation_function"])
+                .and_then(serde_json::Value::as_str)
+                .map(ToString::to_string),
+        })
+    }
+}
+
+/// A model config field which prevents the model from loading, from [`Loader::validate_config`]. It is
+/// returned inside the [`anyhow::Error`], so callers can `downcast_ref` it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigValidationError {
+    /// The offending config field, or `config` if it cannot be narrowed down.
+    pub field: String,
+    pub reason: String,
+}
+
+impl ConfigValidationError {
+    pub(crate) fn new(field: impl ToString, reason: impl ToString) -> Self {
+        Self {
+            field: field.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Name the field a config deserialization error is about, falling back to `config` where serde
+    /// does not say (e.g. for type errors).
+    pub(crate) fn from_serde(err: &serde_json::Error) -> Self {
+        let reason = err.to_string();
+        let field = ["missing field `", "unknown field `", "duplicate field `"]
+            .iter()
+            .find_map(|prefix| {
+                let rest = &reason[reason.find(prefix)? + prefix.len()..];
+                Some(rest[..rest.find('`')?].to_string())
+            })
+            .unwrap_or("config".to_string());
+        Self { field, reason }
+    }
+}
+
+impl Display for ConfigValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid model config field `{}`: {}",
+            self.field, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ConfigValidationError {}
+
+/// The dtype of a config's `torch_dtype`, for the dtypes safetensors weights can be loaded from.
+pub(crate) fn dtype_from_torch_name(name: &str) -> Option<DType> {
+    match name {
+        "float16" | "half" => Some(DType::F16),
+        "bfloat16" => Some(DType::BF16),
+        "float32" | "float" => Some(DType::F32),
+        "float64" | "double" => Some(DType::F64),
+        "float8_e4m3fn" => Some(DType::F8E4M3),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{RwLock, RwLockReadGuard};
+
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{
+        override_activation, override_num_experts_per_tok, validate_weight_shapes, LlamaLoader,
+        NormalModel, NormalModelLoader, WeightSource,
+    };
+
+    // Strict determinism, the matmul precision and the CUDA GEMM precision are process wide, so tests
+    // running a model must not overlap with a test that changes them.
+    static NUMERICS_LOCK: RwLock<()> = RwLock::new(());
+
+    fn shared_numerics() -> RwLockReadGuard<'static, ()> {
+        NUMERICS_LOCK.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn weight_source_from_safetensors_buffers() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let weight = Tensor::arange(0f32, 6f32, &dev)?.reshape((2, 3))?;
+        let buffer = safetensors::tensor::serialize([("proj.weight", &weight)], &None)
+            .map_err(candle_core::Error::msg)?;
+
+        let vb = WeightSource::SafetensorsBuffers(vec![buffer]).into_var_builder(
+            DType::F32,
+            &dev,
+            true,
+        )?;
+        let loaded = vb.pp("proj").get((2, 3), "weight")?;
+        assert_eq!(loaded.to_vec2::<f32>()?, weight.to_vec2::<f32>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn num_experts_per_tok_override() -> anyhow::Result<()> {
+        let config = r#"{"num_local_experts": 8, "num_experts_per_tok": 2}"#;
+        let patched: serde_json::Value =
+            serde_json::from_str(&override_num_experts_per_tok(config, 1)?)?;
+        assert_eq!(patched["num_experts_per_tok"], 1);
+        assert_eq!(patched["num_local_experts"], 8);
+
+        assert!(override_num_experts_per_tok(config, 0).is_err());
+        assert!(override_num_experts_per_tok(config, 9).is_err());
+        assert!(override_num_experts_per_tok(r#"{"hidden_size": 8}"#, 1).is_err());
+        Ok(())
+    }
+
+    #[cfg(not(all(feature = "cuda", feature = "nccl")))]
+    #[test]
+    fn activation_override_replaces_mlp_activation() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use std::sync::Arc;
+
+        use mistralrs_quant::{Comm, Id};
+
+        use crate::{
+            amoe::MlpLayer,
+            layers::{Activation, Mlp},
+            models::llama::Config,
+        };
+
+        let config = r#"{"hidden_act": "silu", "hidden_size": 8, "intermediate_size": 16,
+            "vocab_size": 32, "num_hidden_layers": 1, "num_attention_heads": 2,
+            "num_key_value_heads": 2, "rms_norm_eps": 1e-5, "max_position_embeddings": 16}"#;
+        let default: Config = serde_json::from_str(config)?;
+        let overridden: Config =
+            serde_json::from_str(&override_activation(config, Activation::Gelu)?)?;
+        assert_eq!(default.hidden_act, Activation::Silu);
+        assert_eq!(overridden.hidden_act, Activation::Gelu);
+        assert!(override_activation(r#"{"hidden_size": 8}"#, Activation::Gelu).is_err());
+
+        let dev = Device::Cpu;
+        let weights = [
+            (
+                "mlp.gate_proj.weight",
+                Tensor::randn(0f32, 1., (16, 8), &dev)?,
+            ),
+            (
+                "mlp.up_proj.weight",
+                Tensor::randn(0f32, 1., (16, 8), &dev)?,
+            ),
+            (
+                "mlp.down_proj.weight",
+                Tensor::randn(0f32, 1., (8, 16), &dev)?,
+            ),
+        ];
+        let buffer = safetensors::tensor::serialize(weights.iter().map(|(n, t)| (*n, t)), &None)?;
+        let comm = Arc::new(Comm::from_device(Id::new(), &dev, 0, 1)?);
+        let mlp = |cfg: &Config| -> anyhow::Result<Mlp> {
+            let vb = WeightSource::SafetensorsBuffers(vec![buffer.clone()]).into_var_builder(
+                DType::F32,
+                &dev,
+                true,
+            )?;
+            Ok(Mlp::new(
+                vb.pp("mlp"),
+                cfg.hidden_size,
+                cfg.intermediate_size,
+                &None,
+                cfg.hidden_act,
+                &comm,
+            )?)
+        };
+
+        let xs = Tensor::randn(0f32, 1., (1, 3, 8), &dev)?;
+        let default_out = mlp(&default)?.forward(&xs)?;
+        let overridden_out = mlp(&overridden)?.forward(&xs)?;
+        let diff = (default_out - overridden_out)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff > 1e-3);
+        Ok(())
+    }
+
+    #[test]
+    fn auto_loader_lists_supported_architectures() -> anyhow::Result<()> {
+        use super::AutoLoader;
+
+        assert!(AutoLoader.is_gptx(r#"{"architectures": ["LlamaForCausalLM"]}"#)?);
+        let err = AutoLoader
+            .is_gptx(r#"{"architectures": ["MambaForCausalLM"]}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`MambaForCausalLM`"), "{err}");
+        assert!(err.contains("`LlamaForCausalLM`"), "{err}");
+        assert!(err.contains("`CohereForCausalLM`"), "{err}");
+
+        let err = AutoLoader
+            .is_gptx(r#"{"architectures": []}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("one name"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_config_is_reported_before_loading() -> anyhow::Result<()> {
+        let config = |num_attention_heads: usize, vocab_size: usize| {
+            format!(
+                r#"{{"hidden_act": "silu", "hidden_size": 64, "intermediate_size": 128,
+                "vocab_size": {vocab_size}, "num_hidden_layers": 2,
+                "num_attention_heads": {num_attention_heads}, "num_key_value_heads": 2,
+                "rms_norm_eps": 1e-5, "max_position_embeddings": 128}}"#
+            )
+        };
+        // The checkpoint was produced with 4 heads of 16 dims and a vocab of 100.
+        let checkpoint = LlamaLoader
+            .expected_weight_shapes(&config(4, 100))?
+            .into_iter()
+            .collect::<std::collections::HashMap<_, _>>();
+        let shape_of = |name: &str| checkpoint.get(name).cloned();
+
+        validate_weight_shapes(
+            &LlamaLoader.expected_weight_shapes(&config(4, 100))?,
+            shape_of,
+        )?;
+
+        // 8 heads of 8 dims changes the kv projections, and the vocab changes the embedding and head.
+        let err = validate_weight_shapes(
+            &LlamaLoader.expected_weight_shapes(&config(8, 120))?,
+            shape_of,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("`model.embed_tokens.weight`: expected [120, 64]"),
+            "{err}"
+        );
+        assert!(err.contains("`lm_head.weight`"), "{err}");
+        for layer in 0..2 {
+            for proj in ["k_proj", "v_proj"] {
+                assert!(
+                    err.contains(&format!("`model.layers.{layer}.self_attn.{proj}.weight`: expected [16, 64] from the config, found [32, 64]")),
+                    "{err}"
+                );
+            }
+        }
+        // Weights which match are not reported.
+        assert!(!err.contains("q_proj"), "{err}");
+        assert!(!err.contains("mlp"), "{err}");
+        Ok(())
+    }
+
+    const TINY_LLAMA: &str = r#"{"hidden_act": "silu", "hidden_size": 16, "intermediate_size": 32,
+        "vocab_size": 40, "num_hidden_layers": 3, "num_attention_heads": 2,
+        "num_key_value_heads": 2, "rms_norm_eps": 1e-5, "max_position_embeddings": 32}"#;
+
+    /// Random weights for [`TINY_LLAMA`] with unit norms.
+    fn tiny_llama_weights(dev: &Device) -> anyhow::Result<Vec<(String, Tensor)>> {
+        let mut weights = Vec::new();
+        for (name, shape) in LlamaLoader.expected_weight_shapes(TINY_LLAMA)? {
+            weights.push((name, Tensor::randn(0f32, 1., shape, dev)?));
+        }
+        let mut norms = vec!["model.norm.weight".to_string()];
+        for i in 0..3 {
+            norms.push(format!("model.layers.{i}.input_layernorm.weight"));
+            norms.push(format!("model.layers.{i}.post_attention_layernorm.weight"));
+        }
+        for name in norms {
+            weights.push((name, Tensor::ones(16, DType::F32, dev)?));
+        }
+        Ok(weights)
+    }
+
+    fn var_builder(
+        weights: &[(String, Tensor)],
+        dev: &Device,
+    ) -> anyhow::Result<mistralrs_quant::ShardedVarBuilder> {
+        let buffer = safetensors::tensor::serialize(weights.iter().map(|(n, t)| (n, t)), &None)?;
+        Ok(
+            WeightSource::SafetensorsBuffers(vec![buffer]).into_var_builder(
+                DType::F32,
+                dev,
+                true,
+            )?,
+        )
+    }
+
+    fn loading_metadata(dev: &Device) -> anyhow::Result<crate::pipeline::NormalLoadingMetadata> {
+        Ok(crate::pipeline::NormalLoadingMetadata {
+            mapper: crate::DeviceMapSetting::dummy().into_mapper(3, dev, None)?,
+            loading_isq: false,
+            real_device: dev.clone(),
+            multi_progress: std::sync::Arc::new(indicatif::MultiProgress::new()),
+        })
+    }
+
+    #[test]
+    fn final_layer_logit_lens_matches_next_token_prediction() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk,
+        };
+
+        let dev = Device::Cpu;
+        let model = LlamaLoader.load(
+            TINY_LLAMA,
+            false,
+            var_builder(&tiny_llama_weights(&dev)?, &dev)?,
+            loading_metadata(&dev)?,
+            AttentionImplementation::Eager,
+        )?;
+
+        let prompt = vec![3u32, 14, 15, 9, 26];
+        let inputs =
+            make_prompt_chunk(0, vec![prompt.clone()], &[0], &dev, None, true, None, None)?;
+        let lens = model
+            .output_hidden_states(&inputs.input, &inputs.positions, &inputs.flash_meta)?
+            .iter()
+            .map(|hidden| model.logit_lens(hidden)?.squeeze(0)?.to_vec1::<u32>())
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        assert_eq!(lens.len(), 3);
+        assert!(lens.iter().all(|layer| layer.len() == prompt.len()));
+
+        for layer in &mut *model.cache().normal().0 {
+            layer.reset();
+        }
+        let next_tokens = model
+            .forward(
+                &inputs.input,
+                &inputs.positions,
+                inputs.context_lens,
+                inputs.position_ids,
+                None,
+                &inputs.flash_meta,
+            )?
+            .argmax(candle_core::D::Minus1)?
+            .squeeze(0)?
+            .to_vec1::<u32>()?;
+        assert_eq!(lens[2], next_tokens);
+        Ok(())
+    }
+
+    #[test]
+    fn op_trace_records_llama_forward_in_order() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use mistralrs_quant::op_trace;
+
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk,
+        };
+
+        let dev = Device::Cpu;
+        let model = LlamaLoader.load(
+            TINY_LLAMA,
+            false,
+            var_builder(&tiny_llama_weights(&dev)?, &dev)?,
+            loading_metadata(&dev)?,
+            AttentionImplementation::Eager,
+        )?;
+        let prompt = vec![3u32, 14, 15, 9, 26];
+        let inputs = make_prompt_chunk(0, vec![prompt], &[0], &dev, None, true, None, None)?;
+
+        let (logits, ops) = op_trace::trace_ops(|| {
+            model.forward(
+                &inputs.input,
+                &inputs.positions,
+                inputs.context_lens.clone(),
+                inputs.position_ids.clone(),
+                None,
+                &inputs.flash_meta,
+            )
+        });
+        logits?;
+
+        // q/k/v, RoPE and attention, o, then the MLP's gate, up and down. The matmuls inside
+        // attention belong to it and are not recorded on their own.
+        let layer = [
+            "rms_norm",
+            "qmatmul",
+            "qmatmul",
+            "qmatmul",
+            "rope",
+            "attention",
+            "qmatmul",
+            "rms_norm",
+            "qmatmul",
+            "qmatmul",
+            "qmatmul",
+        ];
+        let mut expected = layer.repeat(3);
+        expected.extend(["rms_norm", "qmatmul"]);
+        assert_eq!(ops.iter().map(|op| op.op).collect::<Vec<_>>(), expected);
+
+        let out_dims = |i: usize| ops[i].outputs[0].shape.clone();
+        // q, k and v project the hidden size of 16 to 2 heads of 8, o projects back.
+        for i in [1, 2, 3, 6] {
+            assert_eq!(out_dims(i), [1, 5, 16]);
+        }
+        // The MLP runs through the intermediate size of 32.
+        assert_eq!(out_dims(8), [1, 5, 32]);
+        assert_eq!(out_dims(9), [1, 5, 32]);
+        assert_eq!(out_dims(10), [1, 5, 16]);
+        // The LM head projects the final hidden states to the vocab of 40.
+        assert_eq!(out_dims(ops.len() - 1), [1, 5, 40]);
+        assert!(ops.iter().all(|op| op.outputs[0].dtype == DType::F32));
+        Ok(())
+    }
+
+    #[test]
+    fn f32_embedding_and_lm_head_on_f16_model() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk,
+        };
+
+        let dev = Device::Cpu;
+        let weights = tiny_llama_weights(&dev)?;
+        let load = |dtype: DType| -> anyhow::Result<_> {
+            let buffer =
+                safetensors::tensor::serialize(weights.iter().map(|(n, t)| (n, t)), &None)?;
+            Ok(LlamaLoader.load(
+                TINY_LLAMA,
+                false,
+                WeightSource::SafetensorsBuffers(vec![buffer])
+                    .into_var_builder(dtype, &dev, true)?,
+                loading_metadata(&dev)?,
+                AttentionImplementation::Eager,
+            )?)
+        };
+        let prompt = vec![3u32, 14, 15, 9, 26];
+        let logits = |model: &dyn NormalModel| -> anyhow::Result<Tensor> {
+            let inputs =
+                make_prompt_chunk(0, vec![prompt.clone()], &[0], &dev, None, true, None, None)?;
+            Ok(model.forward(
+                &inputs.input,
+                &inputs.positions,
+                inputs.context_lens,
+                inputs.position_ids,
+                None,
+                &inputs.flash_meta,
+            )?)
+        };
+
+        let reference = logits(&*load(DType::F32)?)?;
+        let half = logits(&*load(DType::F16)?)?;
+        let mut mixed = load(DType::F16)?;
+        mixed.set_boundary_dtypes(Some(DType::F32), Some(DType::F32))?;
+
+        // Only the decoder layers run in f16.
+        let inputs =
+            make_prompt_chunk(0, vec![prompt.clone()], &[0], &dev, None, true, None, None)?;
+        assert_eq!(mixed.input_embeddings(&inputs.input)?.dtype(), DType::F32);
+        let hidden =
+            mixed.final_hidden_states(&inputs.input, &inputs.positions, &inputs.flash_meta)?;
+        assert_eq!(hidden.dtype(), DType::F16);
+        for layer in &mut *mixed.cache().normal().0 {
+            layer.reset();
+        }
+        let mixed = logits(&*mixed)?;
+        assert_eq!(half.dtype(), DType::F16);
+        assert_eq!(mixed.dtype(), DType::F32);
+
+        // The f32 logits are finer than f16 can represent, and still close to the f32 model.
+        let rounded = mixed.to_dtype(DType::F16)?.to_dtype(DType::F32)?;
+        let max_diff = |a: &Tensor, b: &Tensor| -> anyhow::Result<f32> {
+            Ok((a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?)
+        };
+        assert!(max_diff(&mixed, &rounded)? > 0.);
+        assert!(max_diff(&mixed, &reference)? < 0.1);
+        Ok(())
+    }
+
+    #[cfg(any(feature = "cuda", feature = "metal"))]
+    #[test]
+    fn strict_determinism_matches_cpu_and_gpu_logits() -> anyhow::Result<()> {
+        let _numerics = NUMERICS_LOCK.write().unwrap_or_else(|e| e.into_inner());
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk,
+        };
+
+        let gpu = if cfg!(feature = "cuda") {
+            Device::new_cuda(0)?
+        } else {
+            Device::new_metal(0)?
+        };
+        let weights = tiny_llama_weights(&Device::Cpu)?;
+        let prompt = vec![3u32, 14, 15, 9, 26];
+        let logits = |dev: &Device| -> anyhow::Result<Tensor> {
+            let model = LlamaLoader.load(
+                TINY_LLAMA,
+                false,
+                var_builder(&weights, dev)?,
+                loading_metadata(dev)?,
+                AttentionImplementation::Eager,
+            )?;
+            let inputs =
+                make_prompt_chunk(0, vec![prompt.clone()], &[0], dev, None, true, None, None)?;
+            Ok(model
+                .forward(
+                    &inputs.input,
+                    &inputs.positions,
+                    inputs.context_lens,
+                    inputs.position_ids,
+                    None,
+                    &inputs.flash_meta,
+                )?
+                .to_device(&Device::Cpu)?)
+        };
+        let cpu_gpu_diff = || -> anyhow::Result<f32> {
+            Ok((logits(&Device::Cpu)? - logits(&gpu)?)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?)
+        };
+
+        // Route the GEMMs of both devices through f16 for the loose baseline, then check that strict
+        // determinism overrides it.
+        let precision = mistralrs_quant::get_matmul_precision();
+        mistralrs_quant::set_matmul_precision(mistralrs_quant::MatMulPrecision::F16);
+        let loose = cpu_gpu_diff();
+        mistralrs_quant::set_strict_determinism(true);
+        let strict = cpu_gpu_diff();
+        mistralrs_quant::set_strict_determinism(false);
+        mistralrs_quant::set_matmul_precision(precision);
+        let (loose, strict) = (loose?, strict?);
+        assert!(strict < 1e-4, "strict CPU/GPU logits differ by {strict}");
+        assert!(loose > strict);
+        Ok(())
+    }
+
+    #[test]
+    fn input_embedding_gradient_matches_finite_difference() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use candle_core::IndexOp;
+
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::{
+                input_gradients::{input_embedding_gradients, GradientTarget},
+                text_models_inputs_processor::make_prompt_chunk,
+            },
+        };
+
+        let dev = Device::Cpu;
+        let model = LlamaLoader.load(
+            TINY_LLAMA,
+            false,
+            var_builder(&tiny_llama_weights(&dev)?, &dev)?,
+            loading_metadata(&dev)?,
+            AttentionImplementation::Eager,
+        )?;
+
+        let prompt = vec![3u32, 14, 15, 9, 26];
+        let inputs =
+            make_prompt_chunk(0, vec![prompt.clone()], &[0], &dev, None, true, None, None)?;
+        let target = |input_embeds: &Tensor| -> candle_core::Result<Tensor> {
+            for layer in &mut *model.cache().normal().0 {
+                layer.reset();
+            }
+            model.forward_input_embeds(
+                &inputs.input,
+                input_embeds,
+                &inputs.positions,
+                &inputs.flash_meta,
+            )
+        };
+        let embeds = model.input_embeddings(&inputs.input)?;
+        let grad = input_embedding_gradients(
+            &embeds,
+            &prompt,
+            GradientTarget::Logit {
+                position: 4,
+                token: 7,
+            },
+            target,
+        )?;
+        assert_eq!(grad.dims(), &[5, 16]);
+
+        // The directional derivative along a random direction matches a central difference.
+        let direction = Tensor::randn(0f32, 1., embeds.shape(), &dev)?;
+        let analytic = (grad.unsqueeze(0)? * &direction)?
+            .sum_all()?
+            .to_scalar::<f32>()?;
+        let eps = 5e-2;
+        let logit = |sign: f64| -> anyhow::Result<f32> {
+            let shifted = (&embeds + (&direction * (sign * eps))?)?;
+            Ok(target(&shifted)?.i((0, 4, 7))?.to_scalar::<f32>()?)
+        };
+        let numeric = (logit(1.)? - logit(-1.)?) / (2. * eps as f32);
+        assert!(
+            (analytic - numeric).abs() <= 0.1 * analytic.abs().max(1.),
+            "analytic {analytic}, numeric {numeric}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn captured_qkv_reproduce_the_layer_output() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk, CapturedQkv,
+        };
+
+        let dev = Device::Cpu;
+        let weights = tiny_llama_weights(&dev)?;
+        let model = LlamaLoader.load(
+            TINY_LLAMA,
+            false,
+            var_builder(&weights, &dev)?,
+            loading_metadata(&dev)?,
+            AttentionImplementation::Eager,
+        )?;
+        let capture = model.qkv_capture().expect("Llama supports QKV capture");
+        capture.enable(1, 0);
+
+        let prompt = vec![3u32, 14, 15, 9, 26];
+        let inputs = make_prompt_chunk(0, vec![prompt], &[0], &dev, None, true, None, None)?;
+        let hidden =
+            model.output_hidden_states(&inputs.input, &inputs.positions, &inputs.flash_meta)?;
+        let CapturedQkv { q, k, v } = capture.take().expect("Layer 1 should be captured");
+        for x in [&q, &k, &v] {
+            assert_eq!(x.dims(), &[2, 5, 8]);
+        }
+
+        // Redo layer 1 from the captured tensors: causal attention, the output projection and the MLP.
+        let weight = |name: &str| {
+            let name = format!("model.layers.1.{name}.weight");
+            weights.iter().find(|(n, _)| *n == name).unwrap().1.t()
+        };
+        let mask = ((Tensor::tril2(5, DType::F32, &dev)? - 1.)? * 1e9)?;
+        let scores = (q.matmul(&k.t()?)? / 8f64.sqrt())?.broadcast_add(&mask)?;
+        let attn = candle_nn::ops::softmax_last_dim(&scores)?
+            .matmul(&v)?
+            .transpose(0, 1)?
+            .reshape((5, 16))?;
+        let x = (hidden[0].squeeze(0)? + attn.matmul(&weight("self_attn.o_proj")?)?)?;
+        let normed = candle_nn::ops::rms_norm(&x, &Tensor::ones(16, DType::F32, &dev)?, 1e-5)?;
+        let gate = candle_nn::ops::silu(&normed.matmul(&weight("mlp.gate_proj")?)?)?;
+        let up = normed.matmul(&weight("mlp.up_proj")?)?;
+        let mlp = (gate * up)?.matmul(&weight("mlp.down_proj")?)?;
+        let diff = ((x + mlp)? - hidden[1].squeeze(0)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "{diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn shared_embedding_draft_matches_target() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            models::llama::{Config, Llama},
+            paged_attention::AttentionImplementation,
+            pipeline::{text_models_inputs_processor::make_prompt_chunk, NormalModel},
+        };
+
+        let dev = Device::Cpu;
+        let cfg: Config = serde_json::from_str(TINY_LLAMA)?;
+        let weights = tiny_llama_weights(&dev)?;
+        let target = Llama::new(
+            &cfg,
+            var_builder(&weights, &dev)?,
+            false,
+            loading_metadata(&dev)?,
+            AttentionImplementation::Eager,
+        )?;
+        let shared = target.shared_embeddings().unwrap();
+
+        // The draft's checkpoint has no embedding or LM head, so they can only come from the target.
+        let layers = weights
+            .into_iter()
+            .filter(|(name, _)| name.starts_with("model.layers.") || name == "model.norm.weight")
+            .collect::<Vec<_>>();
+        let draft = Llama::new_with_shared_embeddings(
+            &cfg,
+            var_builder(&layers, &dev)?,
+            false,
+            loading_metadata(&dev)?,
+            AttentionImplementation::Eager,
+            shared.clone(),
+        )?;
+
+        let prompt = vec![3u32, 14, 15, 9, 26];
+        let inputs =
+            make_prompt_chunk(0, vec![prompt.clone()], &[0], &dev, None, true, None, None)?;
+        let diff = (target.get_input_embeddings(&inputs.input)?
+            - draft.get_input_embeddings(&inputs.input)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+        assert_eq!(diff, 0.);
+
+        // With the same layers, every token proposed by the draft is accepted by the target.
+        let next_tokens = |model: &Llama| -> anyhow::Result<Vec<u32>> {
+            Ok(model
+                .forward(
+                    &inputs.input,
+                    &inputs.positions,
+                    inputs.context_lens.clone(),
+                    None,
+                    &inputs.flash_meta,
+                )?
+                .argmax(candle_core::D::Minus1)?
+                .squeeze(0)?
+                .to_vec1::<u32>()?)
+        };
+        assert_eq!(next_tokens(&draft)?, next_tokens(&target)?);
+
+        let mismatched = Config {
+            vocab_size: 41,
+            ..cfg.clone()
+        };
+        assert!(Llama::new_with_shared_embeddings(
+            &mismatched,
+            var_builder(&layers, &dev)?,
+            false,
+            loading_metadata(&dev)?,
+            AttentionImplementation::Eager,
+            shared,
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn draft_loader_references_shared_embeddings() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use std::sync::Arc;
+
+        use crate::{Loader, NormalLoaderBuilder, NormalSpecificConfig};
+
+        use super::NormalLoaderType;
+
+        const TOKENIZER: &str = r#"{
+            "version": "1.0",
+            "pre_tokenizer": {"type": "Whitespace"},
+            "model": {"type": "WordLevel", "vocab": {"<s>": 0, "</s>": 1, "<unk>": 2}, "unk_token": "<unk>"}
+        }"#;
+
+        let dev = Device::Cpu;
+        let weights = tiny_llama_weights(&dev)?;
+        let load = |loader: &dyn Loader, weights: &[(String, Tensor)]| {
+            let buffer =
+                safetensors::tensor::serialize(weights.iter().map(|(n, t)| (n, t)), &None)?;
+            loader.load_model_from_parts(
+                TINY_LLAMA,
+                TOKENIZER.as_bytes(),
+                WeightSource::SafetensorsBuffers(vec![buffer]),
+                &DType::F32,
+                &dev,
+                true,
+                None,
+                None,
+            )
+        };
+        let loader = |model_id: &str| {
+            NormalLoaderBuilder::new(
+                NormalSpecificConfig::default(),
+                None,
+                None,
+                Some(model_id.to_string()),
+                false,
+                None,
+            )
+            .build(Some(NormalLoaderType::Llama))
+        };
+
+        let target = load(&*loader("target")?, &weights)?;
+        let shared = target
+            .blocking_lock()
+            .shared_embeddings()
+            .expect("Llama shares its embeddings");
+
+        // The draft's checkpoint has no embedding or LM head, so it only loads if they are shared.
+        let layers = weights
+            .into_iter()
+            .filter(|(name, _)| name.starts_with("model.layers.") || name == "model.norm.weight")
+            .collect::<Vec<_>>();
+        let draft_loader = loader("draft")?;
+        assert!(load(&*draft_loader, &layers).is_err());
+        draft_loader.set_shared_embeddings(shared.clone())?;
+        let draft = load(&*draft_loader, &layers)?;
+
+        let draft_shared = draft.blocking_lock().shared_embeddings().unwrap();
+        assert!(Arc::ptr_eq(&draft_shared.lm_head, &shared.lm_head));
+        assert_eq!(
+            draft_shared.embed_tokens.embeddings().to_vec2::<f32>()?,
+            shared.embed_tokens.embeddings().to_vec2::<f32>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cohere_config_parsing_and_logit_scale() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::{text_models_inputs_processor::make_prompt_chunk, DeviceMappedModelLoader},
+        };
+
+        use super::{AutoLoader, CohereLoader, NormalLoaderType};
+
+        // Omits `logit_scale`, `layer_norm_eps`, `rope_theta` and `tie_word_embeddings` like some Command-R
+        // configs, so their defaults are used.
+        let config = r#"{"architectures": ["CohereForCausalLM"], "hidden_act": "silu",
+            "hidden_size": 16, "intermediate_size": 32, "vocab_size": 40, "num_hidden_layers": 2,
+            "num_attention_heads": 2, "num_key_value_heads": 1, "max_position_embeddings": 32"#;
+        let default_scale = format!("{config}}}");
+        let double_scale = format!(r#"{config}, "logit_scale": 0.125}}"#);
+
+        assert_eq!(
+            NormalLoaderType::from_causal_lm_name("CohereForCausalLM")?,
+            NormalLoaderType::CohereCommandR
+        );
+        assert_eq!(
+            "cohere".parse::<NormalLoaderType>(),
+            Ok(NormalLoaderType::CohereCommandR)
+        );
+        assert_eq!(NormalLoaderType::CohereCommandR.to_string(), "cohere");
+        assert!(!CohereLoader.is_gptx(&default_scale)?);
+        assert_eq!(AutoLoader.num_layers(&default_scale)?, 2);
+
+        // The embeddings are tied by default, so there is no LM head.
+        let shapes = AutoLoader.expected_weight_shapes(&default_scale)?;
+        assert_eq!(shapes, CohereLoader.expected_weight_shapes(&default_scale)?);
+        assert!(shapes.iter().all(|(name, _)| name != "lm_head.weight"));
+        assert!(shapes.contains(&(
+            "model.layers.1.self_attn.k_proj.weight".to_string(),
+            vec![8, 16]
+        )));
+
+        let dev = Device::Cpu;
+        let mut weights = Vec::new();
+        for (name, shape) in shapes {
+            weights.push((name, Tensor::randn(0f32, 1., shape, &dev)?));
+        }
+        // The layer norms have a weight but no bias.
+        for name in [
+            "model.norm",
+            "model.layers.0.input_layernorm",
+            "model.layers.1.input_layernorm",
+        ] {
+            weights.push((
+                format!("{name}.weight"),
+                Tensor::ones(16, DType::F32, &dev)?,
+            ));
+        }
+
+        let prompt = vec![3u32, 14, 15, 9, 26];
+        let inputs = make_prompt_chunk(0, vec![prompt], &[0], &dev, None, true, None, None)?;
+        let logits = |config: &str| -> anyhow::Result<Tensor> {
+            let model = AutoLoader.load(
+                config,
+                false,
+                var_builder(&weights, &dev)?,
+                loading_metadata(&dev)?,
+                AttentionImplementation::Eager,
+            )?;
+            Ok(model.forward(
+                &inputs.input,
+                &inputs.positions,
+                inputs.context_lens.clone(),
+                inputs.position_ids.clone(),
+                None,
+                &inputs.flash_meta,
+            )?)
+        };
+        let default_logits = logits(&default_scale)?;
+        assert_eq!(default_logits.dims(), &[1, 1, 40]);
+        // The logits are scaled by `logit_scale`, which defaults to 1/16.
+        let diff = (logits(&double_scale)? - (default_logits * 2.)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "{diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn phi3_loader_caps_cached_rope_positions() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk,
+        };
+
+        use super::{override_max_cached_rope_positions, Phi3Loader};
+
+        // The prompt is longer than both the cap and the original context, so the tables are extended
+        // and the LongRope factors switch from short to long.
+        let config = r#"{"vocab_size": 40, "hidden_act": "silu", "hidden_size": 16,
+            "intermediate_size": 8, "num_hidden_layers": 3, "num_attention_heads": 2,
+            "num_key_value_heads": 2, "rms_norm_eps": 1e-5, "rope_theta": 10000.0,
+            "max_position_embeddings": 64, "original_max_position_embeddings": 4,
+            "rope_scaling": {"type": "longrope", "short_factor": [1.0, 1.5, 2.0, 2.5],
+            "long_factor": [3.0, 4.0, 5.0, 6.0]}}"#;
+        let capped = override_max_cached_rope_positions(config, 2)?;
+        assert!(format!("{:?}", Phi3Loader.get_config_repr(&capped, false)?)
+            .contains("max_cached_rope_positions: Some(2)"));
+        assert!(override_max_cached_rope_positions(TINY_LLAMA, 2).is_err());
+
+        let dev = Device::Cpu;
+        let mut weights = vec![
+            ("model.embed_tokens.weight".to_string(), vec![40, 16]),
+            ("model.norm.weight".to_string(), vec![16]),
+            ("lm_head.weight".to_string(), vec![40, 16]),
+        ];
+        for i in 0..3 {
+            for (name, shape) in [
+                ("self_attn.qkv_proj.weight", vec![48, 16]),
+                ("self_attn.o_proj.weight", vec![16, 16]),
+                ("mlp.gate_up_proj.weight", vec![16, 16]),
+                ("mlp.down_proj.weight", vec![16, 8]),
+                ("input_layernorm.weight", vec![16]),
+                ("post_attention_layernorm.weight", vec![16]),
+            ] {
+                weights.push((format!("model.layers.{i}.{name}"), shape));
+            }
+        }
+        let weights = weights
+            .into_iter()
+            .map(|(name, shape)| Ok((name, Tensor::randn(0f32, 1., shape, &dev)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let prompt = vec![3u32, 14, 15, 9, 26, 5];
+        let logits = |config: &str| -> anyhow::Result<Tensor> {
+            let model = Phi3Loader.load(
+                config,
+                false,
+                var_builder(&weights, &dev)?,
+                loading_metadata(&dev)?,
+                AttentionImplementation::Eager,
+            )?;
+            let inputs =
+                make_prompt_chunk(0, vec![prompt.clone()], &[0], &dev, None, true, None, None)?;
+            Ok(model.forward(
+                &inputs.input,
+                &inputs.positions,
+                inputs.context_lens.clone(),
+                inputs.position_ids.clone(),
+                None,
+                &inputs.flash_meta,
+            )?)
+        };
+        let diff = (logits(config)? - logits(&capped)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "{diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn phi3small_uses_blocksparse_mask_for_prefill_and_decode() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk,
+        };
+
+        use super::{AutoLoader, NormalLoaderType, Phi3SmallLoader};
+
+        // Blocks of 2 positions with a single local block, so early positions fall out of the pattern.
+        let config = r#"{"architectures": ["Phi3SmallForCausalLM"], "hidden_size": 16,
+            "ff_intermediate_size": 8, "vocab_size": 40, "num_hidden_layers": 2,
+            "num_attention_heads": 4, "num_key_value_heads": 2, "max_position_embeddings": 64,
+            "blocksparse_block_size": 2, "blocksparse_vert_stride": 4"#;
+        let sparse = format!(r#"{config}, "blocksparse_num_local_blocks": 1}}"#);
+        let local = format!(r#"{config}, "blocksparse_num_local_blocks": 8}}"#);
+        let dense = format!(r#"{config}, "dense_attention_every_n_layers": 1}}"#);
+
+        assert_eq!(
+            NormalLoaderType::from_causal_lm_name("Phi3SmallForCausalLM")?,
+            NormalLoaderType::Phi3Small
+        );
+        assert_eq!(
+            "phi3small".parse::<NormalLoaderType>(),
+            Ok(NormalLoaderType::Phi3Small)
+        );
+        assert!(!AutoLoader.supports_paged_attention(&sparse)?);
+
+        let dev = Device::Cpu;
+        let mut weights = Vec::new();
+        for (name, shape) in Phi3SmallLoader.expected_weight_shapes(&sparse)? {
+            weights.push((name, Tensor::randn(0f32, 1., shape, &dev)?));
+        }
+
+        let prompt = vec![3u32, 14, 15, 9, 26, 5, 35, 8];
+        // Runs the prompt in chunks starting at the given positions and returns the logits of every position.
+        let logits = |config: &str, starts: &[usize]| -> anyhow::Result<Tensor> {
+            let model = AutoLoader.load(
+                config,
+                false,
+                var_builder(&weights, &dev)?,
+                loading_metadata(&dev)?,
+                AttentionImplementation::Eager,
+            )?;
+            let mut logits = Vec::new();
+            for (i, start) in starts.iter().enumerate() {
+                let end = starts.get(i + 1).copied().unwrap_or(prompt.len());
+                let inputs = make_prompt_chunk(
+                    *start,
+                    vec![prompt[*start..end].to_vec()],
+                    &[0],
+                    &dev,
+                    None,
+                    true,
+                    None,
+                    None,
+                )?;
+                logits.push(model.forward(
+                    &inputs.input,
+                    &inputs.positions,
+                    inputs.context_lens.clone(),
+                    inputs.position_ids.clone(),
+                    None,
+                    &inputs.flash_meta,
+                )?);
+            }
+            Ok(Tensor::cat(&logits, 1)?)
+        };
+        let max_diff = |a: &Tensor, b: &Tensor| -> anyhow::Result<f32> {
+            Ok((a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?)
+        };
+
+        let sparse_logits = logits(&sparse, &[0])?;
+        assert_eq!(sparse_logits.dims(), &[1, prompt.len(), 40]);
+        // The first layer is blocksparse, so dropping positions from its pattern changes the logits.
+        let dense_logits = logits(&dense, &[0])?;
+        assert!(max_diff(&sparse_logits, &dense_logits)? > 1e-3);
+        assert!(max_diff(&logits(&local, &[0])?, &dense_logits)? < 1e-4);
+
+        // A prompt chunk after a cached prefix and a single decoded token see the same pattern.
+        let chunked = logits(&sparse, &[0, 5, 7])?;
+        assert!(max_diff(&chunked, &sparse_logits)? < 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn offloaded_prefill_matches_resident_prefill() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            models::llama::{Config, Llama},
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk,
+        };
+
+        // Offloading is a no-op for a model on the CPU, so run on a GPU when there is one.
+        let dev = Device::cuda_if_available(0)?;
+        let cfg: Config = serde_json::from_str(TINY_LLAMA)?;
+        let weights = tiny_llama_weights(&dev)?;
+        let prompt = (0..30u32).map(|i| (i * 7 + 3) % 40).collect::<Vec<_>>();
+        let inputs = make_prompt_chunk(0, vec![prompt], &[0], &dev, None, true, None, None)?;
+
+        let prefill_logits = |offload: bool| -> anyhow::Result<Vec<f32>> {
+            let mut model = Llama::new(
+                &cfg,
+                var_builder(&weights, &dev)?,
+                false,
+                loading_metadata(&dev)?,
+                AttentionImplementation::Eager,
+            )?;
+            model.set_offload_activations(offload)?;
+            Ok(model
+                .forward(
+                    &inputs.input,
+                    &inputs.positions,
+                    inputs.context_lens.clone(),
+                    None,
+                    &inputs.flash_meta,
+                )?
+                .flatten_all()?
+                .to_vec1::<f32>()?)
+        };
+        assert_eq!(prefill_logits(true)?, prefill_logits(false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn in_memory_weights_are_checked_against_the_config() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{Loader, NormalLoaderBuilder, NormalSpecificConfig};
+
+        use super::{NormalLoaderType, WeightSource};
+
+        const TOKENIZER: &str = r#"{
+            "version": "1.0",
+            "pre_tokenizer": {"type": "Whitespace"},
+            "model": {"type": "WordLevel", "vocab": {"<s>": 0, "</s>": 1, "<unk>": 2}, "unk_token": "<unk>"}
+        }"#;
+
+        let dev = Device::Cpu;
+        let mut weights = tiny_llama_weights(&dev)?
+            .into_iter()
+            .collect::<std::collections::HashMap<_, _>>();
+        weights.insert(
+            "model.layers.1.self_attn.k_proj.weight".to_string(),
+            Tensor::zeros((8, 16), DType::F32, &dev)?,
+        );
+        let buffer = safetensors::tensor::serialize(weights.iter(), &None)?;
+        for source in [
+            WeightSource::Tensors(weights.clone()),
+            WeightSource::SafetensorsBuffers(vec![buffer]),
+        ] {
+            let err = NormalLoaderBuilder::new(
+                NormalSpecificConfig::default(),
+                None,
+                None,
+                Some("shapes".to_string()),
+                false,
+                None,
+            )
+            .build(Some(NormalLoaderType::Llama))?
+            .load_model_from_parts(
+                TINY_LLAMA,
+                TOKENIZER.as_bytes(),
+                source,
+                &DType::F32,
+                &dev,
+                true,
+                None,
+                None,
+            )
+            .err()
+            .unwrap();
+            assert!(
+                format!("{err:#}").contains("`model.layers.1.self_attn.k_proj.weight`: expected [16, 16] from the config, found [8, 16]"),
+                "{err:#}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn offload_activations_is_applied_by_the_loader() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{Loader, NormalLoaderBuilder, NormalSpecificConfig};
+
+        use super::{NormalLoaderType, WeightSource};
+
+        const TOKENIZER: &str = r#"{
+            "version": "1.0",
+            "pre_tokenizer": {"type": "Whitespace"},
+            "model": {"type": "WordLevel", "vocab": {"<s>": 0, "</s>": 1, "<unk>": 2}, "unk_token": "<unk>"}
+        }"#;
+
+        let dev = Device::Cpu;
+        let weights = tiny_llama_weights(&dev)?;
+        let buffer = safetensors::tensor::serialize(weights.iter().map(|(n, t)| (n, t)), &None)?;
+        let config = TINY_LLAMA.replace('}', r#", "rope_theta": 10000.0}"#);
+        let load = |arch: NormalLoaderType| {
+            NormalLoaderBuilder::new(
+                NormalSpecificConfig {
+                    offload_activations: true,
+                    ..Default::default()
+                },
+                None,
+                None,
+                Some("offload".to_string()),
+                false,
+                None,
+            )
+            .build(Some(arch))?
+            .load_model_from_parts(
+                &config,
+                TOKENIZER.as_bytes(),
+                WeightSource::SafetensorsBuffers(vec![buffer.clone()]),
+                &DType::F32,
+                &dev,
+                true,
+                None,
+                None,
+            )
+        };
+        assert!(load(NormalLoaderType::Llama).is_ok());
+        // Mistral has the same weights as Llama but does not support offloading.
+        let err = load(NormalLoaderType::Mistral).err().unwrap();
+        assert!(
+            format!("{err:#}").contains("offloading activations"),
+            "{err:#}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cacheless_forward_matches_a_fresh_cache_and_keeps_the_cache() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            pipeline::text_models_inputs_processor::{make_prompt_chunk, ModelInputs},
+            Loader, NormalLoaderBuilder, NormalSpecificConfig,
+        };
+
+        use super::{NormalLoaderType, WeightSource};
+
+        const TOKENIZER: &str = r#"{
+            "version": "1.0",
+            "pre_tokenizer": {"type": "Whitespace"},
+            "model": {"type": "WordLevel", "vocab": {"<s>": 0, "</s>": 1, "<unk>": 2}, "unk_token": "<unk>"}
+        }"#;
+
+        let dev = Device::Cpu;
+        let weights = tiny_llama_weights(&dev)?;
+        let buffer = safetensors::tensor::serialize(weights.iter().map(|(n, t)| (n, t)), &None)?;
+        let load = || {
+            NormalLoaderBuilder::new(
+                NormalSpecificConfig::default(),
+                None,
+                None,
+                Some("use_cache".to_string()),
+                false,
+                None,
+            )
+            .build(Some(NormalLoaderType::Llama))?
+            .load_model_from_parts(
+                TINY_LLAMA,
+                TOKENIZER.as_bytes(),
+                WeightSource::SafetensorsBuffers(vec![buffer.clone()]),
+                &DType::F32,
+                &dev,
+                true,
+                None,
+                None,
+            )
+        };
+        let inputs = |toks: &[u32], use_cache: bool| -> anyhow::Result<Box<ModelInputs>> {
+            let meta =
+                make_prompt_chunk(0, vec![toks.to_vec()], &[0], &dev, None, false, None, None)?;
+            Ok(Box::new(ModelInputs {
+                input_ids: meta.input,
+                input_ids_full: None,
+                seqlen_offsets: meta.positions,
+                seqlen_offsets_full: None,
+                context_lens: meta.context_lens,
+                position_ids: meta.position_ids,
+                paged_attn_meta: None,
+                flash_meta: meta.flash_meta,
+                flash_meta_full: None,
+                use_cache,
+            }))
+        };
+        let logits = |result: crate::pipeline::ForwardInputsResult| -> anyhow::Result<Vec<f32>> {
+            let crate::pipeline::ForwardInputsResult::CausalGeneration { logits } = result else {
+                anyhow::bail!("Expected causal generation logits.");
+            };
+            Ok(logits.flatten_all()?.to_vec1::<f32>()?)
+        };
+        let prompt = (0..12u32).map(|i| (i * 7 + 3) % 40).collect::<Vec<_>>();
+
+        // Fill the cache with another prompt, then run the whole prompt without it.
+        let pipeline = load()?;
+        let mut pipeline = pipeline.blocking_lock();
+        pipeline.forward_inputs(inputs(&[5, 9, 13], true)?, false, None)?;
+        let cached_k = |pipeline: &dyn crate::Pipeline| -> anyhow::Result<Vec<f32>> {
+            let k = pipeline.cache().normal().0[0]
+                .k()?
+                .expect("the cache was filled");
+            Ok(k.flatten_all()?.to_vec1::<f32>()?)
+        };
+        let before = cached_k(&*pipeline)?;
+        let cacheless = logits(pipeline.forward_inputs(inputs(&prompt, false)?, false, None)?)?;
+        assert_eq!(cached_k(&*pipeline)?, before);
+
+        // A fresh pipeline runs the same prompt on an empty cache.
+        let fresh = load()?;
+        let fresh = logits(fresh.blocking_lock().forward_inputs(
+            inputs(&prompt, true)?,
+            false,
+            None,
+        )?)?;
+        assert_eq!(cacheless, fresh);
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_matches_loaded_weights() -> anyhow::Result<()> {
+        use std::collections::HashMap;
+
+        use crate::{ModelDType, NormalLoaderBuilder, NormalSpecificConfig};
+
+        use super::{NormalLoaderType, TokenSource};
+
+        let dev = Device::Cpu;
+        // These weights load into a Llama model in the other tests, so they are what a real load
+        // would allocate. The checkpoint size is the data section of their serialized file.
+        let weights = tiny_llama_weights(&dev)?;
+        let buffer = safetensors::tensor::serialize(weights.iter().map(|(n, t)| (n, t)), &None)?;
+        let (header_len, metadata) = safetensors::SafeTensors::read_metadata(&buffer)?;
+        let checkpoint_bytes = buffer.len() - 8 - header_len;
+        let loaded_bytes = metadata
+            .tensors()
+            .values()
+            .map(|info| info.data_offsets.1 - info.data_offsets.0)
+            .sum::<usize>();
+        // Embedding and LM head (40 x 16 each), the final norm, and per layer four 16 x 16
+        // attention projections, three 16 x 32 MLP projections and two norms.
+        let expected_params = 2 * 40 * 16 + 16 + 3 * (4 * 16 * 16 + 3 * 16 * 32 + 2 * 16);
+        assert_eq!(loaded_bytes, expected_params * 4);
+
+        let dir = std::env::temp_dir().join(format!("mistralrs-dry-run-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("config.json"), TINY_LLAMA)?;
+        std::fs::write(
+            dir.join("model.safetensors.index.json"),
+            serde_json::json!({
+                "metadata": {"total_size": checkpoint_bytes},
+                "weight_map": weights
+                    .iter()
+                    .map(|(name, _)| (name.clone(), "model.safetensors"))
+                    .collect::<HashMap<_, _>>(),
+            })
+            .to_string(),
+        )?;
+
+        let loader = NormalLoaderBuilder::new(
+            NormalSpecificConfig::default(),
+            None,
+            None,
+            Some(dir.display().to_string()),
+            false,
+            None,
+        )
+        .build(Some(NormalLoaderType::Llama))?;
+        // No weights were written, so this would fail if the dry run tried to load them.
+        let estimate = loader.dry_run(None, TokenSource::None, &ModelDType::F32, &dev);
+        std::fs::remove_dir_all(&dir)?;
+        let estimate = estimate?;
+
+        assert_eq!(estimate.num_layers, 3);
+        assert_eq!(estimate.dtype, DType::F32);
+        assert_eq!(estimate.weight_size_in_bytes, loaded_bytes);
+        assert_eq!(estimate.num_parameters, expected_params);
+        assert_eq!(estimate.checkpoint_size_in_bytes, Some(checkpoint_bytes));
+        Ok(())
+    }
+
+    #[test]
+    fn load_from_parts_honours_chat_template_options() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            pipeline::{MetadataMixin, PreProcessingMixin},
+            NormalLoaderBuilder, NormalSpecificConfig,
+        };
+
+        use super::NormalLoaderType;
+
+        const TOKENIZER: &str = r#"{
+            "version": "1.0",
+            "pre_tokenizer": {"type": "Whitespace"},
+            "model": {"type": "WordLevel", "vocab": {"<s>": 0, "</s>": 1, "hello": 2, "<unk>": 3}, "unk_token": "<unk>"}
+        }"#;
+
+        let dev = Device::Cpu;
+        let weights = tiny_llama_weights(&dev)?;
+        let buffer = safetensors::tensor::serialize(weights.iter().map(|(n, t)| (n, t)), &None)?;
+
+        let tokenizer_config = std::env::temp_dir().join(format!(
+            "mistralrs-parts-tokenizer-config-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &tokenizer_config,
+            r#"{"bos_token": "<s>", "eos_token": "</s>", "chat_template": "{{ bos_token }}"}"#,
+        )?;
+
+        let loader = NormalLoaderBuilder::new(
+            NormalSpecificConfig::default(),
+            Some(tokenizer_config.display().to_string()),
+            None,
+            None,
+            false,
+            None,
+        )
+        .with_chat_template_string("{{ messages[0]['content'] }}".to_string())
+        .build(Some(NormalLoaderType::Llama))?;
+        let pipeline = loader.load_model_from_parts(
+            TINY_LLAMA,
+            TOKENIZER.as_bytes(),
+            WeightSource::SafetensorsBuffers(vec![buffer]),
+            &DType::F32,
+            &dev,
+            true,
+            None,
+            None,
+        );
+        std::fs::remove_file(&tokenizer_config)?;
+        let pipeline = pipeline?;
+
+        let pipeline = pipeline.blocking_lock();
+        let template = pipeline
+            .get_chat_template()
+            .expect("normal pipelines have a chat template");
+        // The special tokens come from the file, the template from the string.
+        assert_eq!(template.bos_tok().as_deref(), Some("<s>"));
+        assert_eq!(template.eos_tok().as_deref(), Some("</s>"));
+        assert_eq!(
+            template
+                .chat_template
+                .as_ref()
+                .and_then(|t| t.0.as_ref().left())
+                .map(String::as_str),
+            Some("{{ messages[0]['content'] }}")
+        );
+        assert_eq!(pipeline.get_metadata().eos_tok, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_config_names_the_offending_field() -> anyhow::Result<()> {
+        use crate::{
+            IsqType, LayerTopology, Loader, NormalLoaderBuilder, NormalSpecificConfig, Topology,
+        };
+
+        use super::ConfigValidationError;
+
+        let auto_loader = |topology: Option<Topology>| {
+            NormalLoaderBuilder::new(
+                NormalSpecificConfig {
+                    topology,
+                    ..Default::default()
+                },
+                None,
+                None,
+                Some("unused".to_string()),
+                false,
+                None,
+            )
+            .build(None)
+        };
+        let failed_field = |loader: &dyn Loader, config: &str| -> anyhow::Result<String> {
+            let err = loader.validate_config(config).unwrap_err();
+            Ok(err
+                .downcast_ref::<ConfigValidationError>()
+                .ok_or(anyhow::anyhow!("Unstructured error: {err}"))?
+                .field
+                .clone())
+        };
+        let with = |extra: &str| format!("{{{extra}, {}", &TINY_LLAMA[1..]);
+
+        let loader = auto_loader(None)?;
+        let llama = with(r#""architectures": ["LlamaForCausalLM"], "torch_dtype": "bfloat16""#);
+        loader.validate_config(&llama)?;
+
+        assert_eq!(failed_field(&*loader, "{")?, "config");
+        let mamba = with(r#""architectures": ["MambaForCausalLM"]"#);
+        assert_eq!(failed_field(&*loader, &mamba)?, "architectures");
+        let no_hidden_size = llama.replace(r#""hidden_size": 16,"#, "");
+        assert_eq!(failed_field(&*loader, &no_hidden_size)?, "hidden_size");
+        let int3 = llama.replace("bfloat16", "int3");
+        assert_eq!(failed_field(&*loader, &int3)?, "torch_dtype");
+
+        // A topology may leave the last layers unmapped, but not map layers the model lacks.
+        let layer = Some(LayerTopology {
+            isq: Some(IsqType::Q8_0),
+            device: None,
+            kv_cache: None,
+        });
+        auto_loader(Some(Topology(vec![layer.clone(); 2])))?.validate_config(&llama)?;
+        let loader = auto_loader(Some(Topology(vec![layer; 4])))?;
+        assert_eq!(failed_field(&*loader, &llama)?, "num_hidden_layers");
+        Ok(())
+    }
+    #[test]
+    fn normalized_config_unifies_model_families() -> anyhow::Result<()> {
+        use crate::{Loader, NormalLoaderBuilder, NormalizedConfig};
+
+        let loader = NormalLoaderBuilder::new(
+            Default::default(),
+            None,
+            None,
+            Some("unused".to_string()),
+            false,
+            None,
+        )
+        .build(None)?;
+
+        // Llama derives the head dim and defaults the RoPE theta.
+        let llama = format!(
+            r#"{{"architectures": ["LlamaForCausalLM"], {}"#,
+            &TINY_LLAMA[1..]
+        );
+        assert_eq!(
+            loader.normalized_config(&llama)?,
+            NormalizedConfig {
+                hidden_size: 16,
+                num_layers: 3,
+                num_heads: 2,
+                num_kv_heads: 2,
+                head_dim: 8,
+                vocab_size: 40,
+                max_position: 32,
+                rope_theta: None,
+                intermediate_size: 32,
+                activation: Some("silu".to_string()),
+            }
+        );
+
+        // Gemma has an explicit head dim, a default max position, and names its activation with
+        // `hidden_activation`.
+        let gemma = r#"{"architectures": ["GemmaForCausalLM"], "attention_bias": false,
+            "head_dim": 12, "hidden_act": null, "hidden_activation": "gelu_pytorch_tanh",
+            "hidden_size": 16, "intermediate_size": 48, "num_attention_heads": 4,
+            "num_hidden_layers": 2, "num_key_value_heads": 1, "rms_norm_eps": 1e-6,
+            "rope_theta": 10000.0, "vocab_size": 64}"#;
+        assert_eq!(
+            loader.normalized_config(gemma)?,
+            NormalizedConfig {
+                hidden_size: 16,
+                num_layers: 2,
+                num_heads: 4,
+                num_kv_heads: 1,
+                head_dim: 12,
+                vocab_size: 64,
+                max_position: 4096,
+                rope_theta: Some(10000.),
+                intermediate_size: 48,
+                activation: Some("gelu_pytorch_tanh".to_string()),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pruned_heads_match_zeroed_head_outputs() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use std::collections::HashMap;
+
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk,
+        };
+
+        let dev = Device::Cpu;
+        let weights = tiny_llama_weights(&dev)?;
+        let load = |weights: &[(String, Tensor)]| -> anyhow::Result<_> {
+            Ok(LlamaLoader.load(
+                TINY_LLAMA,
+                false,
+                var_builder(weights, &dev)?,
+                loading_metadata(&dev)?,
+                AttentionImplementation::Eager,
+            )?)
+        };
+        let prompt = vec![3u32, 14, 15, 9, 26];
+        let logits = |model: &dyn NormalModel| -> anyhow::Result<Tensor> {
+            let inputs =
+                make_prompt_chunk(0, vec![prompt.clone()], &[0], &dev, None, true, None, None)?;
+            Ok(model.forward(
+                &inputs.input,
+                &inputs.positions,
+                inputs.context_lens,
+                inputs.position_ids,
+                None,
+                &inputs.flash_meta,
+            )?)
+        };
+
+        // Prune head 1 of layer 0 and head 0 of layer 2. Each head spans 8 of the 16 hidden dims.
+        let pruned_heads = HashMap::from([(0, vec![1]), (2, vec![0])]);
+        let mut pruned = load(&weights)?;
+        pruned.prune_attention_heads(&pruned_heads)?;
+
+        // The reference drops the same heads by zeroing their columns of the output projection.
+        let zeroed_weights = weights
+            .iter()
+            .map(|(name, w)| -> anyhow::Result<_> {
+                let head = match name.as_str() {
+                    "model.layers.0.self_attn.o_proj.weight" => 1,
+                    "model.layers.2.self_attn.o_proj.weight" => 0,
+                    _ => return Ok((name.clone(), w.clone())),
+                };
+                let mask = (0..16)
+                    .map(|i| if i / 8 == head { 0f32 } else { 1. })
+                    .collect::<Vec<_>>();
+                let w = w.broadcast_mul(&Tensor::new(mask.as_slice(), &dev)?.unsqueeze(0)?)?;
+                Ok((name.clone(), w))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let zeroed = load(&zeroed_weights)?;
+
+        let max_diff = (logits(&*pruned)? - logits(&*zeroed)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(max_diff < 1e-4, "{max_diff}");
+        // The pruned heads change the output.
+        let full_diff = (logits(&*load(&weights)?)? - logits(&*zeroed)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(full_diff > 1e-2, "{full_diff}");
+
+        // Layer 0 has a single head left, which cannot be pruned.
+        assert!(pruned
+            .prune_attention_heads(&HashMap::from([(0, vec![0])]))
+            .is_err());
+        assert!(load(&weights)?
+            .prune_attention_heads(&HashMap::from([(1, vec![2])]))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn zero_attention_head_scale_matches_zeroed_head_outputs() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use std::collections::HashMap;
+
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk,
+        };
+
+        let dev = Device::Cpu;
+        let weights = tiny_llama_weights(&dev)?;
+        let load = |weights: &[(String, Tensor)]| -> anyhow::Result<_> {
+            Ok(LlamaLoader.load(
+                TINY_LLAMA,
+                false,
+                var_builder(weights, &dev)?,
+                loading_metadata(&dev)?,
+                AttentionImplementation::Eager,
+            )?)
+        };
+        let prompt = vec![3u32, 14, 15, 9];
+        let logits = |model: &dyn NormalModel| -> anyhow::Result<Tensor> {
+            let inputs =
+                make_prompt_chunk(0, vec![prompt.clone()], &[0], &dev, None, true, None, None)?;
+            Ok(model.forward(
+                &inputs.input,
+                &inputs.positions,
+                inputs.context_lens,
+                inputs.position_ids,
+                None,
+                &inputs.flash_meta,
+            )?)
+        };
+
+        // Silence head 1 of layer 0, which spans the last 8 of the 16 hidden dims.
+        let mut scaled = load(&weights)?;
+        scaled.set_attention_head_scales(&HashMap::from([(0, vec![1., 0.])]))?;
+
+        let zeroed_weights = weights
+            .iter()
+            .map(|(name, w)| -> anyhow::Result<_> {
+                if name != "model.layers.0.self_attn.o_proj.weight" {
+                    return Ok((name.clone(), w.clone()));
+                }
+                let mask = (0..16)
+                    .map(|i| if i < 8 { 1f32 } else { 0. })
+                    .collect::<Vec<_>>();
+                let w = w.broadcast_mul(&Tensor::new(mask.as_slice(), &dev)?.unsqueeze(0)?)?;
+                Ok((name.clone(), w))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let max_diff = (logits(&*scaled)? - logits(&*load(&zeroed_weights)?)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(max_diff < 1e-4, "{max_diff}");
+
+        // One scale is needed per head.
+        assert!(load(&weights)?
+            .set_attention_head_scales(&HashMap::from([(0, vec![1.])]))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn padded_head_dim_matches_unpadded() -> anyhow::Result<()> {
+        let _numerics = shared_numerics();
+        use crate::{
+            paged_attention::AttentionImplementation,
+            pipeline::text_models_inputs_processor::make_prompt_chunk,
+        };
+
+        // Two heads of 80 dims, padded to 128.
+        const CONFIG: &str = r#"{"hidden_act": "silu", "hidden_size": 160, "intermediate_size": 32,
+            "vocab_size": 40, "num_hidden_layers": 2, "num_attention_heads": 2,
+            "num_key_value_heads": 2, "rms_norm_eps": 1e-5, "max_position_embeddings": 32}"#;
+
+        let dev = Device::Cpu;
+        let mut weights = Vec::new();
+        for (name, shape) in LlamaLoader.expected_weight_shapes(CONFIG)? {
+            weights.push((name, (Tensor::randn(0f32, 1., shape, &dev)? * 0.1)?));
+        }
+        let mut norms = vec!["model.norm.weight".to_string()];
+        for i in 0..2 {
+            norms.push(format!("model.layers.{i}.input_layernorm.weight"));
+            norms.push(format!("model.layers.{i}.post_attention_layernorm.weight"));
+        }
+        for name in norms {
+            weights.push((name, Tensor::ones(160, DType::F32, &dev)?));
+        }
+        let load = || -> anyhow::Result<_> {
+            Ok(LlamaLoader.load(
+                CONFIG,
+                false,
+                var_builder(&weights, &dev)?,
+                crate::pipeline::NormalLoadingMetadata {
+                    mapper: crate::DeviceMapSetting::dummy().into_mapper(2, &dev, None)?,
+                    loading_isq: false,
+                    real_device: dev.clone(),
+                    multi_progress: std::sync::Arc::new(indicatif::MultiProgress::new()),
+                },
+                AttentionImplementation::Eager,
+            )?)
+        };
+        let prompt = vec![3u32, 14, 15, 9, 26];
+        let logits = |model: &dyn NormalModel| -> anyhow::Result<Tensor> {
+            let inputs =
+                make_prompt_chunk(0, vec![prompt.clone()], &[0], &dev, None, true, None, None)?;
+            Ok(model.forward(
+                &inputs.input,
+                &inputs.positions,
+                inputs.context_lens,
+                inputs.position_ids,
+                None,
+                &inputs.flash_meta,
+            )?)
+        };
+
+        let mut padded = load()?;
+        padded.pad_head_dim(64)?;
+        assert_eq!(padded.config().k_head_dim, 128);
+        assert_eq!(padded.config().v_head_dim, 128);
+
+        let max_diff = (logits(&*padded)? - logits(&*load()?)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(max_diff < 1e-4, "{max_diff}");
+        Ok(())
+    }
+}
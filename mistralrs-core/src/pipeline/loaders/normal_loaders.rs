@@ -8,7 +8,8 @@ use std::{
 use crate::{
     amoe::AnyMoeBaseModelMixin,
     device_map::DeviceMapper,
-    layers::{Activation, Llama3RopeConfig, PhiRopeScalingConfig},
+    expert_counts::ExpertCounter,
+    layers::{Activation, Llama3RopeConfig, PhiRopeScalingConfig, RopeScalingConfig},
     lora::{LoraConfig, Ordering},
     paged_attention::{AttentionImplementation, ModelConfigLike, ModelConfigMetadata},
     pipeline::{
@@ -16,6 +17,7 @@ use crate::{
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
         EitherCache, IsqModel,
     },
+    qkv_capture::QkvCapture,
     serde_default_fn,
     utils::{log::once_log_info, varbuilder_utils::DeviceForLoadTensor},
     xlora_models::NonGranularState,
@@ -24,7 +26,7 @@ use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
 
 use indicatif::MultiProgress;
-use mistralrs_quant::{QuantizedConfig, ShardedVarBuilder};
+use mistralrs_quant::{QuantMethod, QuantizedConfig, ShardedVarBuilder};
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
 
@@ -36,7 +38,7 @@ use crate::{
     xlora_models::{self, XLoraConfig},
 };
 
-use super::{AutoDeviceMapParams, DeviceMappedModelLoader};
+use super::{AutoDeviceMapParams, ConfigValidationError, DeviceMappedModelLoader};
 
 pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
     #[allow(clippy::too_many_arguments)]
@@ -69,6 +71,270 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
     fn cache_mut(&mut self) -> &mut EitherCache;
     fn max_seq_len(&self) -> usize;
     fn config(&self) -> &ModelConfigMetadata;
+    /// The hidden states after the final norm, before the LM head, with shape `(bs, seq_len, hidden_size)`.
+    fn final_hidden_states(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _flash_params: &FlashParams,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("This model does not expose its final hidden states.")
+    }
+    /// The output of each decoder layer, before the final norm, each with shape `(bs, seq_len, hidden_size)`.
+    fn output_hidden_states(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _flash_params: &FlashParams,
+    ) -> candle_core::Result<Vec<Tensor>> {
+        candle_core::bail!("This model does not expose its per-layer hidden states.")
+    }
+    /// Apply the final norm and the LM head to a layer's hidden states, returning the most likely token at
+    /// each position with shape `(bs, seq_len)`.
+    fn logit_lens(&self, _hidden_states: &Tensor) -> candle_core::Result<Tensor> {
+        candle_core::bail!("This model does not support the logit lens.")
+    }
+    /// The input embeddings of `input_ids`, with shape `(bs, seq_len, hidden_size)`.
+    fn input_embeddings(&self, _input_ids: &Tensor) -> candle_core::Result<Tensor> {
+        candle_core::bail!("This model does not expose its input embeddings.")
+    }
+    /// The logits at every position with shape `(bs, seq_len, vocab_size)`, running the model on
+    /// `input_embeds` instead of the embeddings of `input_ids`.
+    fn forward_input_embeds(
+        &self,
+        _input_ids: &Tensor,
+        _input_embeds: &Tensor,
+        _seqlen_offsets: &[usize],
+        _flash_params: &FlashParams,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("This model does not support running on input embeddings.")
+    }
+    /// Per-expert routing counts, for MoE models which support them.
+    fn expert_counter(&self) -> Option<&ExpertCounter> {
+        None
+    }
+    /// Capture of the query, key and value tensors of one attention layer, for models which support it.
+    fn qkv_capture(&self) -> Option<&QkvCapture> {
+        None
+    }
+    /// The input embedding and LM head, for models which can share them with a draft model.
+    fn shared_embeddings(&self) -> Option<SharedEmbeddings> {
+        None
+    }
+    /// Run the embedding lookup and the LM head in these dtypes instead of the model dtype, casting
+    /// at their boundaries with the decoder layers. The LM head must not be quantized. Only Llama
+    /// implements this.
+    fn set_boundary_dtypes(
+        &mut self,
+        _embedding_dtype: Option<DType>,
+        _lm_head_dtype: Option<DType>,
+    ) -> candle_core::Result<()> {
+        candle_core::bail!(
+            "This model does not support a separate embedding or LM head dtype, only Llama models do."
+        )
+    }
+    /// Remove attention heads, given as the query head indices to prune keyed by layer index. The
+    /// attention projections must be unquantized.
+    fn prune_attention_heads(
+        &mut self,
+        _pruned_heads: &HashMap<usize, Vec<usize>>,
+    ) -> candle_core::Result<()> {
+        candle_core::bail!("This model does not support pruning attention heads.")
+    }
+    /// Scale the output of each attention head, given as one scale per query head keyed by layer
+    /// index.
+    fn set_attention_head_scales(
+        &mut self,
+        _head_scales: &HashMap<usize, Vec<f32>>,
+    ) -> candle_core::Result<()> {
+        candle_core::bail!("This model does not support attention head scales.")
+    }
+    /// Zero-pad the attention heads up to a multiple of `alignment` dims, for kernels which are
+    /// faster on aligned sizes. The projections are padded once, so attention and the KV cache run
+    /// on the padded heads while RoPE only rotates the real dims. The attention projections must be
+    /// unquantized.
+    fn pad_head_dim(&mut self, _alignment: usize) -> candle_core::Result<()> {
+        candle_core::bail!("This model does not support padding the head dim.")
+    }
+    /// Hold the residual stream on the CPU while each decoder layer's attention and MLP run, trading
+    /// host transfers for lower peak device memory during long prefill. The outputs are unchanged.
+    fn set_offload_activations(&mut self, _offload: bool) -> candle_core::Result<()> {
+        candle_core::bail!("This model does not support offloading activations.")
+    }
+}
+
+/// A model's input embedding and LM head, referenced by a draft model for speculative decoding instead of
+/// loading its own copies.
+#[derive(Clone)]
+pub struct SharedEmbeddings {
+    pub embed_tokens: candle_nn::Embedding,
+    pub lm_head: Arc<dyn QuantMethod>,
+}
+
+impl SharedEmbeddings {
+    /// Check that the embedding has shape `(vocab_size, hidden_size)`, and the LM head too if it is not
+    /// quantized.
+    pub fn validate(&self, vocab_size: usize, hidden_size: usize) -> candle_core::Result<()> {
+        let mut shapes = vec![("embedding", self.embed_tokens.embeddings().dims().to_vec())];
+        if let Some((weight, _)) = self.lm_head.unquant_weight_bias() {
+            shapes.push(("LM head", weight.dims().to_vec()));
+        }
+        for (name, shape) in shapes {
+            if shape != [vocab_size, hidden_size] {
+                candle_core::bail!(
+                    "The shared {name} has shape {shape:?}, but the draft model expects [{vocab_size}, {hidden_size}]."
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the shapes of the weights in a checkpoint against `expected`, reporting every mismatch at once.
+/// Weights for which `shape_of` returns `None` are skipped, as they may be fused or stored differently.
+pub(crate) fn validate_weight_shapes(
+    expected: &[(String, Vec<usize>)],
+    shape_of: impl Fn(&str) -> Option<Vec<usize>>,
+) -> Result<()> {
+    let mismatches = expected
+        .iter()
+        .filter_map(|(name, expected)| {
+            let actual = shape_of(name)?;
+            (actual != *expected).then(|| {
+                format!("  `{name}`: expected {expected:?} from the config, found {actual:?}")
+            })
+        })
+        .collect::<Vec<_>>();
+    if !mismatches.is_empty() {
+        anyhow::bail!(
+            "The model weights do not match the model config:\n{}",
+            mismatches.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Expected weight shapes of a decoder using the Llama tensor names.
+#[allow(clippy::too_many_arguments)]
+fn llama_style_weight_shapes(
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    head_dim: usize,
+    tie_word_embeddings: bool,
+) -> Vec<(String, Vec<usize>)> {
+    let mut shapes = vec![(
+        "model.embed_tokens.weight".to_string(),
+        vec![vocab_size, hidden_size],
+    )];
+    if !tie_word_embeddings {
+        shapes.push(("lm_head.weight".to_string(), vec![vocab_size, hidden_size]));
+    }
+    let (size_q, size_kv) = (
+        num_attention_heads * head_dim,
+        num_key_value_heads * head_dim,
+    );
+    for i in 0..num_hidden_layers {
+        let attn = format!("model.layers.{i}.self_attn");
+        let mlp = format!("model.layers.{i}.mlp");
+        shapes.extend([
+            (format!("{attn}.q_proj.weight"), vec![size_q, hidden_size]),
+            (format!("{attn}.k_proj.weight"), vec![size_kv, hidden_size]),
+            (format!("{attn}.v_proj.weight"), vec![size_kv, hidden_size]),
+            (format!("{attn}.o_proj.weight"), vec![hidden_size, size_q]),
+            (
+                format!("{mlp}.gate_proj.weight"),
+                vec![intermediate_size, hidden_size],
+            ),
+            (
+                format!("{mlp}.up_proj.weight"),
+                vec![intermediate_size, hidden_size],
+            ),
+            (
+                format!("{mlp}.down_proj.weight"),
+                vec![hidden_size, intermediate_size],
+            ),
+        ]);
+    }
+    shapes
+}
+
+/// Override `num_experts_per_tok` in a MoE model config, validating it against the total number of experts.
+pub(crate) fn override_num_experts_per_tok(
+    config: &str,
+    num_experts_per_tok: usize,
+) -> Result<String> {
+    let mut config: serde_json::Value = serde_json::from_str(config)?;
+    let Some(fields) = config.as_object_mut() else {
+        anyhow::bail!("Expected the model config to be a JSON object.");
+    };
+    if !fields.contains_key("num_experts_per_tok") {
+        anyhow::bail!(
+            "Cannot override the number of experts per token for a model without MoE routing."
+        );
+    }
+    let Some(num_experts) = ["num_local_experts", "n_routed_experts", "num_experts"]
+        .iter()
+        .find_map(|key| fields.get(*key).and_then(serde_json::Value::as_u64))
+    else {
+        anyhow::bail!("Model config does not specify the total number of experts.");
+    };
+    if num_experts_per_tok == 0 || num_experts_per_tok as u64 > num_experts {
+        anyhow::bail!(
+            "Number of experts per token must be between 1 and the total number of experts ({num_experts}), got {num_experts_per_tok}."
+        );
+    }
+    fields.insert(
+        "num_experts_per_tok".to_string(),
+        serde_json::Value::from(num_experts_per_tok),
+    );
+    Ok(serde_json::to_string(&config)?)
+}
+
+/// Replace the MLP activation named in a model config with `activation`.
+pub(crate) fn override_activation(config: &str, activation: Activation) -> Result<String> {
+    let mut config: serde_json::Value = serde_json::from_str(config)?;
+    let Some(fields) = config.as_object_mut() else {
+        anyhow::bail!("Expected the model config to be a JSON object.");
+    };
+    let keys = ["hidden_act", "hidden_activation"]
+        .into_iter()
+        .filter(|key| fields.contains_key(*key))
+        .collect::<Vec<_>>();
+    if keys.is_empty() {
+        anyhow::bail!("Model config does not specify an activation to override.");
+    }
+    for key in keys {
+        fields.insert(key.to_string(), serde_json::to_value(activation)?);
+    }
+    Ok(serde_json::to_string(&config)?)
+}
+
+/// Set `max_cached_rope_positions` in a model config, which the Phi 3 and Phi 3.5 MoE loaders read to
+/// cap their precomputed RoPE tables. It is not an HF config field, so it is checked against the LongRope
+/// `original_max_position_embeddings` which only those configs have at the top level.
+pub(crate) fn override_max_cached_rope_positions(
+    config: &str,
+    max_cached_rope_positions: usize,
+) -> Result<String> {
+    let mut config: serde_json::Value = serde_json::from_str(config)?;
+    let Some(fields) = config.as_object_mut() else {
+        anyhow::bail!("Expected the model config to be a JSON object.");
+    };
+    if !fields.contains_key("original_max_position_embeddings") {
+        anyhow::bail!("Capping the cached RoPE positions is only supported for Phi 3 models.");
+    }
+    if max_cached_rope_positions == 0 {
+        anyhow::bail!("The number of cached RoPE positions must be at least 1.");
+    }
+    fields.insert(
+        "max_cached_rope_positions".to_string(),
+        max_cached_rope_positions.into(),
+    );
+    Ok(serde_json::to_string(&config)?)
 }
 
 /// Metadata for loading a model with ISQ or device mapping.
@@ -92,6 +358,19 @@ pub trait NormalModelLoader: IsqModelLoader + Send + Sync + DeviceMappedModelLoa
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
     ) -> Result<Box<dyn NormalModel + Send + Sync>>;
+    /// Load a draft model which references `shared`, its target model's input embedding and LM head,
+    /// instead of loading its own.
+    fn load_with_shared_embeddings(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: ShardedVarBuilder,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _attention_mechanism: AttentionImplementation,
+        _shared: SharedEmbeddings,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("This model does not support sharing the target model's embeddings.")
+    }
     #[allow(clippy::too_many_arguments)]
     fn load_xlora(
         &self,
@@ -109,6 +388,11 @@ pub trait NormalModelLoader: IsqModelLoader + Send + Sync + DeviceMappedModelLoa
         Ok(true)
     }
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>>;
+    /// The `(name, shape)` of key unquantized weights, as implied by the config. These are checked
+    /// against the checkpoint before loading so that mismatches are reported up front.
+    fn expected_weight_shapes(&self, _config: &str) -> Result<Vec<(String, Vec<usize>)>> {
+        Ok(Vec::new())
+    }
     fn get_device_for_tensor(
         &self,
         config: &str,
@@ -166,27 +450,45 @@ pub enum NormalLoaderType {
     DeepSeekV2,
     #[serde(rename = "deepseekv3")]
     DeepSeekV3,
+    #[serde(rename = "cohere")]
+    CohereCommandR,
+    #[serde(rename = "phi3small")]
+    Phi3Small,
 }
 
 // https://github.com/huggingface/transformers/blob/cff06aac6fad28019930be03f5d467055bf62177/src/transformers/models/auto/modeling_auto.py#L448
 
+/// The Huggging Face Transformers -CausalLM model classes which can be loaded automatically.
+const CAUSAL_LM_NAMES: &[(&str, NormalLoaderType)] = &[
+    ("MistralForCausalLM", NormalLoaderType::Mistral),
+    ("MixtralForCausalLM", NormalLoaderType::Mixtral),
+    ("GemmaForCausalLM", NormalLoaderType::Gemma),
+    ("Gemma2ForCausalLM", NormalLoaderType::Gemma2),
+    ("PhiForCausalLM", NormalLoaderType::Phi2),
+    ("Phi3ForCausalLM", NormalLoaderType::Phi3),
+    ("LlamaForCausalLM", NormalLoaderType::Llama),
+    // Granite is Llama with extra scaling multipliers.
+    ("GraniteForCausalLM", NormalLoaderType::Llama),
+    ("Qwen2ForCausalLM", NormalLoaderType::Qwen2),
+    ("Starcoder2ForCausalLM", NormalLoaderType::Starcoder2),
+    ("PhiMoEForCausalLM", NormalLoaderType::Phi3_5MoE),
+    ("DeepseekV2ForCausalLM", NormalLoaderType::DeepSeekV2),
+    ("DeepseekV3ForCausalLM", NormalLoaderType::DeepSeekV3),
+    ("CohereForCausalLM", NormalLoaderType::CohereCommandR),
+    ("Phi3SmallForCausalLM", NormalLoaderType::Phi3Small),
+];
+
 impl NormalLoaderType {
     pub fn from_causal_lm_name(name: &str) -> Result<Self> {
-        match name {
-            "MistralForCausalLM" => Ok(Self::Mistral),
-            "MixtralForCausalLM" => Ok(Self::Mixtral),
-            "GemmaForCausalLM" => Ok(Self::Gemma),
-            "Gemma2ForCausalLM" => Ok(Self::Gemma2),
-            "PhiForCausalLM" => Ok(Self::Phi2),
-            "Phi3ForCausalLM" => Ok(Self::Phi3),
-            "LlamaForCausalLM" => Ok(Self::Llama),
-            "Qwen2ForCausalLM" => Ok(Self::Qwen2),
-            "Starcoder2ForCausalLM" => Ok(Self::Starcoder2),
-            "PhiMoEForCausalLM" => Ok(Self::Phi3_5MoE),
-            "DeepseekV2ForCausalLM" => Ok(Self::DeepSeekV2),
-            "DeepseekV3ForCausalLM" => Ok(Self::DeepSeekV3),
-            other => anyhow::bail!(
-                "Unsupported Huggging Face Transformers -CausalLM model class `{other}`. Please raise an issue."
+        match CAUSAL_LM_NAMES.iter().find(|(class, _)| *class == name) {
+            Some((_, tp)) => Ok(tp.clone()),
+            None => anyhow::bail!(
+                "Unsupported Huggging Face Transformers -CausalLM model class `{name}`. Supported model classes: {}. Please raise an issue.",
+                CAUSAL_LM_NAMES
+                    .iter()
+                    .map(|(class, _)| format!("`{class}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
             ),
         }
     }
@@ -208,7 +510,9 @@ impl FromStr for NormalLoaderType {
             "phi3.5moe" => Ok(Self::Phi3_5MoE),
             "deepseekv2" => Ok(Self::DeepSeekV2),
             "deepseekv3" => Ok(Self::DeepSeekV3),
-            a => Err(format!("Unknown architecture `{a}`. Possible architectures: `mistral`, `gemma`, `mixtral`, `llama`, `phi2`, `phi3`, `qwen2`, `gemma2`, `starcoder2`, `phi3.5moe`, `deepseekv2`, `deepseekv3`.")),
+            "cohere" => Ok(Self::CohereCommandR),
+            "phi3small" => Ok(Self::Phi3Small),
+            a => Err(format!("Unknown architecture `{a}`. Possible architectures: `mistral`, `gemma`, `mixtral`, `llama`, `phi2`, `phi3`, `qwen2`, `gemma2`, `starcoder2`, `phi3.5moe`, `deepseekv2`, `deepseekv3`, `cohere`, `phi3small`.")),
         }
     }
 }
@@ -228,6 +532,8 @@ impl Display for NormalLoaderType {
             Self::Starcoder2 => write!(f, "starcoder2"),
             Self::DeepSeekV2 => write!(f, "deepseekv2"),
             Self::DeepSeekV3 => write!(f, "deepseekv3"),
+            Self::CohereCommandR => write!(f, "cohere"),
+            Self::Phi3Small => write!(f, "phi3small"),
         }
     }
 }
@@ -254,14 +560,24 @@ impl AutoLoader {
     fn get_loader(config: &str) -> Result<Box<dyn NormalModelLoader>> {
         let auto_cfg: AutoLoaderConfig = serde_json::from_str(config)?;
         if auto_cfg.architectures.len() != 1 {
-            anyhow::bail!("Expected to have one name for `architectures` config field.")
+            return Err(ConfigValidationError::new(
+                "architectures",
+                format!(
+                    "Expected to have one name for `architectures` config field, got {:?}.",
+                    auto_cfg.architectures
+                ),
+            )
+            .into());
         }
 
         let name = &auto_cfg.architectures[0];
 
-        let tp = NormalLoaderType::from_causal_lm_name(name)?;
+        let tp = NormalLoaderType::from_causal_lm_name(name)
+            .map_err(|e| ConfigValidationError::new("architectures", e))?;
 
-        once_log_info(format!("Automatic loader type determined to be `{tp}`"));
+        once_log_info(format!(
+            "Automatic loader read architecture `{name}`, using the `{tp}` loader."
+        ));
 
         match tp {
             NormalLoaderType::Mistral => Ok(Box::new(MistralLoader)),
@@ -276,6 +592,8 @@ impl AutoLoader {
             NormalLoaderType::Phi3_5MoE => Ok(Box::new(Phi3_5MoELoader)),
             NormalLoaderType::DeepSeekV2 => Ok(Box::new(DeepSeekV2Loader)),
             NormalLoaderType::DeepSeekV3 => Ok(Box::new(DeepSeekV3Loader)),
+            NormalLoaderType::CohereCommandR => Ok(Box::new(CohereLoader)),
+            NormalLoaderType::Phi3Small => Ok(Box::new(Phi3SmallLoader)),
         }
     }
 }
@@ -297,6 +615,24 @@ impl NormalModelLoader for AutoLoader {
             attention_mechanism,
         )
     }
+    fn load_with_shared_embeddings(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: ShardedVarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+        shared: SharedEmbeddings,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Self::get_loader(config)?.load_with_shared_embeddings(
+            config,
+            use_flash_attn,
+            vb,
+            normal_loading_metadata,
+            attention_mechanism,
+            shared,
+        )
+    }
     fn load_xlora(
         &self,
         config: &str,
@@ -322,6 +658,9 @@ impl NormalModelLoader for AutoLoader {
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
         Self::get_loader(config)?.get_config_repr(config, use_flash_attn)
     }
+    fn expected_weight_shapes(&self, config: &str) -> Result<Vec<(String, Vec<usize>)>> {
+        Self::get_loader(config)?.expected_weight_shapes(config)
+    }
     fn supports_paged_attention(&self, config: &str) -> Result<bool> {
         Self::get_loader(config)?.supports_paged_attention(config)
     }
@@ -472,6 +811,22 @@ impl NormalModelLoader for MistralLoader {
             use_flash_attn,
         )?))
     }
+    fn expected_weight_shapes(&self, config: &str) -> Result<Vec<(String, Vec<usize>)>> {
+        let cfg = MistralBasicConfig::deserialize(config, false)?;
+        if cfg.quantization_config.is_some() {
+            return Ok(Vec::new());
+        }
+        Ok(llama_style_weight_shapes(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            cfg.intermediate_size,
+            cfg.num_hidden_layers,
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
+            cfg.head_dim(),
+            cfg.tie_word_embeddings,
+        ))
+    }
 }
 
 impl IsqModelLoader for MistralLoader {
@@ -861,6 +1216,14 @@ struct LlamaBasicConfig {
     quantization_config: Option<QuantizedConfig>,
     #[serde(default = "word_emb_default")]
     tie_word_embeddings: bool,
+    #[serde(default)]
+    attention_multiplier: Option<f64>,
+    #[serde(default)]
+    embedding_multiplier: Option<f64>,
+    #[serde(default)]
+    residual_multiplier: Option<f64>,
+    #[serde(default)]
+    logits_scaling: Option<f64>,
 }
 
 fn default_rope() -> f32 {
@@ -887,6 +1250,10 @@ impl LlamaBasicConfig {
             quantization_config: basic_config.quantization_config,
             tie_word_embeddings: basic_config.tie_word_embeddings,
             hidden_act: basic_config.hidden_act,
+            attention_multiplier: basic_config.attention_multiplier,
+            embedding_multiplier: basic_config.embedding_multiplier,
+            residual_multiplier: basic_config.residual_multiplier,
+            logits_scaling: basic_config.logits_scaling,
         })
     }
 }
@@ -913,6 +1280,24 @@ impl NormalModelLoader for LlamaLoader {
             attention_mechanism,
         )?))
     }
+    fn load_with_shared_embeddings(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: ShardedVarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+        shared: SharedEmbeddings,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::llama::Llama::new_with_shared_embeddings(
+            &LlamaBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(config)?,
+            normal_loading_metadata,
+            attention_mechanism,
+            shared,
+        )?))
+    }
     fn load_xlora(
         &self,
         config: &str,
@@ -944,6 +1329,22 @@ impl NormalModelLoader for LlamaLoader {
             use_flash_attn,
         )?))
     }
+    fn expected_weight_shapes(&self, config: &str) -> Result<Vec<(String, Vec<usize>)>> {
+        let cfg = LlamaBasicConfig::deserialize(config, false)?;
+        if cfg.quantization_config.is_some() {
+            return Ok(Vec::new());
+        }
+        Ok(llama_style_weight_shapes(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            cfg.intermediate_size,
+            cfg.num_hidden_layers,
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
+            cfg.hidden_size / cfg.num_attention_heads,
+            cfg.tie_word_embeddings,
+        ))
+    }
 }
 
 impl IsqModelLoader for LlamaLoader {
@@ -1547,6 +1948,7 @@ struct Phi3BasicConfig {
     #[serde(default = "word_emb_default")]
     tie_word_embeddings: bool,
     partial_rotary_factor: Option<f64>,
+    max_cached_rope_positions: Option<usize>,
 }
 
 impl Phi3BasicConfig {
@@ -1572,6 +1974,7 @@ impl Phi3BasicConfig {
             quantization_config: basic_config.quantization_config,
             tie_word_embeddings: basic_config.tie_word_embeddings,
             partial_rotary_factor: basic_config.partial_rotary_factor,
+            max_cached_rope_positions: basic_config.max_cached_rope_positions,
         })
     }
 }
@@ -1764,11 +2167,16 @@ struct Qwen2BasicConfig {
     num_key_value_heads: usize,
     max_position_embeddings: usize,
     sliding_window: Option<usize>,
+    #[serde(default)]
+    use_sliding_window: bool,
+    #[serde(default)]
+    max_window_layers: Option<usize>,
     rope_theta: f64,
     rms_norm_eps: f64,
     hidden_act: Activation,
     quantization_config: Option<QuantizedConfig>,
     tie_word_embeddings: bool,
+    rope_scaling: Option<RopeScalingConfig>,
 }
 
 impl Qwen2BasicConfig {
@@ -1786,9 +2194,12 @@ impl Qwen2BasicConfig {
             rope_theta: basic_config.rope_theta,
             rms_norm_eps: basic_config.rms_norm_eps,
             sliding_window: basic_config.sliding_window,
+            use_sliding_window: basic_config.use_sliding_window,
+            max_window_layers: basic_config.max_window_layers,
             use_flash_attn,
             quantization_config: basic_config.quantization_config,
             tie_word_embeddings: basic_config.tie_word_embeddings,
+            rope_scaling: basic_config.rope_scaling,
         })
     }
 }
@@ -1958,7 +2369,7 @@ impl DeviceMappedModelLoader for Qwen2Loader {
             hidden_size: cfg.hidden_size,
             num_kv_heads: cfg.num_key_value_heads,
             num_attn_heads: cfg.num_attention_heads,
-            sliding_window: cfg.sliding_window,
+            sliding_window: cfg.layer_sliding_window(0),
             k_head_dim: cfg.hidden_size / cfg.num_attention_heads,
             v_head_dim: cfg.hidden_size / cfg.num_attention_heads,
         };
@@ -2455,11 +2866,16 @@ struct Phi3_5MoEBasicConfig {
     lm_head_bias: bool,
     attention_bias: bool,
     num_local_experts: usize,
+    #[serde(default = "phi3_5_moe_num_experts_per_tok")]
+    num_experts_per_tok: usize,
     router_jitter_noise: f64,
     #[serde(default = "word_emb_default")]
     tie_word_embeddings: bool,
+    max_cached_rope_positions: Option<usize>,
 }
 
+serde_default_fn!(usize, phi3_5_moe_num_experts_per_tok, 2);
+
 impl Phi3_5MoEBasicConfig {
     fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::phi3_5_moe::Config> {
         let basic_config: Self = serde_json::from_str(slice)?;
@@ -2482,8 +2898,10 @@ impl Phi3_5MoEBasicConfig {
             lm_head_bias: basic_config.lm_head_bias,
             attention_bias: basic_config.attention_bias,
             num_local_experts: basic_config.num_local_experts,
+            num_experts_per_tok: basic_config.num_experts_per_tok,
             router_jitter_noise: basic_config.router_jitter_noise,
             tie_word_embeddings: basic_config.tie_word_embeddings,
+            max_cached_rope_positions: basic_config.max_cached_rope_positions,
         })
     }
 }
@@ -3331,3 +3749,569 @@ impl DeviceMappedModelLoader for DeepSeekV3Loader {
         Ok(Box::new(cfg))
     }
 }
+
+// ======================== Cohere loader
+
+serde_default_fn!(f64, cohere_logit_scale, 0.0625);
+serde_default_fn!(f64, cohere_layer_norm_eps, 1e-5);
+serde_default_fn!(f64, cohere_rope_theta, 10000.0);
+serde_default_fn!(bool, cohere_tie_word_embeddings, true);
+
+#[derive(Deserialize, Debug)]
+struct CohereBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    hidden_act: Activation,
+    max_position_embeddings: usize,
+    #[serde(default = "cohere_rope_theta")]
+    rope_theta: f64,
+    #[serde(default = "cohere_layer_norm_eps")]
+    layer_norm_eps: f64,
+    #[serde(default = "cohere_logit_scale")]
+    logit_scale: f64,
+    #[serde(default)]
+    attention_bias: bool,
+    #[serde(default)]
+    use_qk_norm: bool,
+    quantization_config: Option<QuantizedConfig>,
+    #[serde(default = "cohere_tie_word_embeddings")]
+    tie_word_embeddings: bool,
+}
+
+impl CohereBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::cohere::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        Ok(models::cohere::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: basic_config.num_key_value_heads,
+            hidden_act: basic_config.hidden_act,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            rope_theta: basic_config.rope_theta,
+            layer_norm_eps: basic_config.layer_norm_eps,
+            logit_scale: basic_config.logit_scale,
+            attention_bias: basic_config.attention_bias,
+            use_qk_norm: basic_config.use_qk_norm,
+            use_flash_attn,
+            quantization_config: basic_config.quantization_config,
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+        })
+    }
+}
+
+/// [`NormalLoader`] for a Cohere Command-R model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct CohereLoader;
+
+impl NormalModelLoader for CohereLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: ShardedVarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::cohere::Model::new(
+            &CohereBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(config)?,
+            normal_loading_metadata,
+            attention_mechanism,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: ShardedVarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (ShardedVarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA is not supported for Cohere models.")
+    }
+    fn is_gptx(&self, _: &str) -> Result<bool> {
+        // Cohere rotates interleaved pairs of the head dimension.
+        Ok(false)
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(CohereBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+    fn expected_weight_shapes(&self, config: &str) -> Result<Vec<(String, Vec<usize>)>> {
+        let cfg = CohereBasicConfig::deserialize(config, false)?;
+        if cfg.quantization_config.is_some() {
+            return Ok(Vec::new());
+        }
+        Ok(llama_style_weight_shapes(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            cfg.intermediate_size,
+            cfg.num_hidden_layers,
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
+            cfg.hidden_size / cfg.num_attention_heads,
+            cfg.tie_word_embeddings,
+        ))
+    }
+}
+
+impl IsqModelLoader for CohereLoader {
+    fn isq_layer_regexes(&self, _config: &str) -> Result<Vec<Regex>> {
+        Ok(vec![
+            Regex::new(r"lm_head\.(weight|bias)$")?,
+            // Attention
+            Regex::new(r"layers\.(\d+)\.self_attn\.q_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.k_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.v_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.o_proj\.(weight|bias)$")?,
+            // MLP
+            Regex::new(r"layers\.(\d+)\.mlp\.gate_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.down_proj\.(weight|bias)$")?,
+        ])
+    }
+}
+
+impl DeviceMappedModelLoader for CohereLoader {
+    fn mapped_max_act_size_elems(
+        &self,
+        config: &str,
+        params: &AutoDeviceMapParams,
+        prompt_chunksize: usize,
+    ) -> Result<usize> {
+        let AutoDeviceMapParams::Text {
+            max_seq_len: _,
+            max_batch_size,
+        } = params
+        else {
+            anyhow::bail!("Expected text AutoDeviceMapParams for this model!")
+        };
+
+        let cfg = CohereBasicConfig::deserialize(config, false)?;
+
+        Ok(max_batch_size * cfg.num_attention_heads * prompt_chunksize * prompt_chunksize)
+    }
+    fn non_mapped_max_act_size_elems(
+        &self,
+        _config: &str,
+        _params: &AutoDeviceMapParams,
+    ) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn non_mapped_size_in_bytes(
+        &self,
+        config: &str,
+        dtype: DType,
+        weight_pack_factor: usize,
+    ) -> Result<usize> {
+        let cfg = CohereBasicConfig::deserialize(config, false)?;
+        let elems = {
+            let embed_tokens = cfg.hidden_size * cfg.vocab_size / weight_pack_factor;
+            let lm_head = if !cfg.tie_word_embeddings {
+                cfg.hidden_size * cfg.vocab_size
+            } else {
+                0
+            };
+            let norm = cfg.hidden_size;
+            embed_tokens + lm_head + norm
+        };
+        Ok(elems * dtype.size_in_bytes())
+    }
+
+    fn layer_sizes_in_bytes(
+        &self,
+        config: &str,
+        dtype: DType,
+        weight_pack_factor: usize,
+    ) -> Result<Vec<usize>> {
+        let cfg = CohereBasicConfig::deserialize(config, false)?;
+        let per_layer_elems = {
+            let input_layernorm = cfg.hidden_size;
+
+            let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+            let size_in = cfg.hidden_size;
+            let size_q = head_dim * cfg.num_attention_heads;
+            let size_kv = head_dim * cfg.num_key_value_heads;
+            let q_proj =
+                size_in * size_q / weight_pack_factor + bias_if!(cfg.attention_bias, size_q);
+            let k_proj =
+                size_in * size_kv / weight_pack_factor + bias_if!(cfg.attention_bias, size_kv);
+            let v_proj =
+                size_in * size_kv / weight_pack_factor + bias_if!(cfg.attention_bias, size_kv);
+            let o_proj =
+                size_q * size_in / weight_pack_factor + bias_if!(cfg.attention_bias, size_in);
+            let qk_norm = bias_if!(cfg.use_qk_norm, size_q + size_kv);
+
+            let h_size = cfg.hidden_size;
+            let i_size = cfg.intermediate_size;
+            let gate_proj = h_size * i_size / weight_pack_factor;
+            let up_proj = h_size * i_size / weight_pack_factor;
+            let down_proj = i_size * h_size / weight_pack_factor;
+
+            input_layernorm
+                + q_proj
+                + k_proj
+                + v_proj
+                + o_proj
+                + qk_norm
+                + gate_proj
+                + up_proj
+                + down_proj
+        };
+        Ok(vec![
+            per_layer_elems * dtype.size_in_bytes();
+            cfg.num_hidden_layers
+        ])
+    }
+
+    fn num_layers(&self, config: &str) -> Result<usize> {
+        let cfg = CohereBasicConfig::deserialize(config, false)?;
+        Ok(cfg.num_hidden_layers)
+    }
+
+    fn model_config(&self, config: &str) -> Result<Box<dyn ModelConfigLike>> {
+        let cfg = CohereBasicConfig::deserialize(config, false)?;
+
+        let cfg = ModelConfigMetadata {
+            max_seq_len: cfg.max_position_embeddings,
+            num_layers: cfg.num_hidden_layers,
+            hidden_size: cfg.hidden_size,
+            num_kv_heads: cfg.num_key_value_heads,
+            num_attn_heads: cfg.num_attention_heads,
+            sliding_window: None,
+            k_head_dim: cfg.hidden_size / cfg.num_attention_heads,
+            v_head_dim: cfg.hidden_size / cfg.num_attention_heads,
+        };
+
+        Ok(Box::new(cfg))
+    }
+}
+
+// ======================== Phi-3-small loader
+
+serde_default_fn!(f64, phi3small_layer_norm_epsilon, 1e-5);
+serde_default_fn!(f64, phi3small_rope_embedding_base, 1e6);
+serde_default_fn!(f64, phi3small_gegelu_limit, 20.0);
+serde_default_fn!(f64, phi3small_mup_attn_multiplier, 1.0);
+serde_default_fn!(f64, phi3small_mup_embedding_multiplier, 10.0);
+serde_default_fn!(bool, phi3small_mup_use_scaling, true);
+serde_default_fn!(f64, phi3small_mup_width_multiplier, 8.0);
+serde_default_fn!(usize, phi3small_blocksparse_block_size, 64);
+serde_default_fn!(usize, phi3small_blocksparse_num_local_blocks, 16);
+serde_default_fn!(usize, phi3small_blocksparse_vert_stride, 8);
+serde_default_fn!(
+    Option<usize>,
+    phi3small_dense_attention_every_n_layers,
+    Some(2)
+);
+serde_default_fn!(bool, phi3small_tie_word_embeddings, true);
+
+#[derive(Deserialize, Debug)]
+struct Phi3SmallBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    ff_intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    #[serde(default = "phi3small_layer_norm_epsilon")]
+    layer_norm_epsilon: f64,
+    #[serde(default = "phi3small_rope_embedding_base")]
+    rope_embedding_base: f64,
+    /// Kept as JSON because the long-context configs nest `original_max_position_embeddings` here.
+    rope_scaling: Option<serde_json::Value>,
+    max_position_embeddings: usize,
+    original_max_position_embeddings: Option<usize>,
+    #[serde(default = "phi3small_gegelu_limit")]
+    gegelu_limit: f64,
+    #[serde(default = "phi3small_mup_attn_multiplier")]
+    mup_attn_multiplier: f64,
+    #[serde(default = "phi3small_mup_embedding_multiplier")]
+    mup_embedding_multiplier: f64,
+    #[serde(default = "phi3small_mup_use_scaling")]
+    mup_use_scaling: bool,
+    #[serde(default = "phi3small_mup_width_multiplier")]
+    mup_width_multiplier: f64,
+    #[serde(default = "phi3small_blocksparse_block_size")]
+    blocksparse_block_size: usize,
+    #[serde(default = "phi3small_blocksparse_num_local_blocks")]
+    blocksparse_num_local_blocks: usize,
+    #[serde(default = "phi3small_blocksparse_vert_stride")]
+    blocksparse_vert_stride: usize,
+    #[serde(default)]
+    blocksparse_homo_head_pattern: bool,
+    #[serde(default = "phi3small_dense_attention_every_n_layers")]
+    dense_attention_every_n_layers: Option<usize>,
+    quantization_config: Option<QuantizedConfig>,
+    #[serde(default = "phi3small_tie_word_embeddings")]
+    tie_word_embeddings: bool,
+}
+
+impl Phi3SmallBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::phi3small::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        let original_max_position_embeddings = basic_config
+            .original_max_position_embeddings
+            .or_else(|| {
+                basic_config
+                    .rope_scaling
+                    .as_ref()?
+                    .get("original_max_position_embeddings")?
+                    .as_u64()
+                    .map(|x| x as usize)
+            })
+            .unwrap_or(basic_config.max_position_embeddings);
+        let rope_scaling = basic_config
+            .rope_scaling
+            .map(serde_json::from_value::<PhiRopeScalingConfig>)
+            .transpose()?;
+        Ok(models::phi3small::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            ff_intermediate_size: basic_config.ff_intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: basic_config.num_key_value_heads,
+            layer_norm_epsilon: basic_config.layer_norm_epsilon,
+            rope_embedding_base: basic_config.rope_embedding_base,
+            rope_scaling,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            original_max_position_embeddings,
+            gegelu_limit: basic_config.gegelu_limit,
+            mup_attn_multiplier: basic_config.mup_attn_multiplier,
+            mup_embedding_multiplier: basic_config.mup_embedding_multiplier,
+            mup_use_scaling: basic_config.mup_use_scaling,
+            mup_width_multiplier: basic_config.mup_width_multiplier,
+            blocksparse_block_size: basic_config.blocksparse_block_size,
+            blocksparse_num_local_blocks: basic_config.blocksparse_num_local_blocks,
+            blocksparse_vert_stride: basic_config.blocksparse_vert_stride,
+            blocksparse_homo_head_pattern: basic_config.blocksparse_homo_head_pattern,
+            dense_attention_every_n_layers: basic_config.dense_attention_every_n_layers,
+            use_flash_attn,
+            quantization_config: basic_config.quantization_config,
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+        })
+    }
+}
+
+/// [`NormalLoader`] for a Phi-3-small model.
+///
+/// Phi-3-small ships a tiktoken tokenizer, so a `tokenizer.json` must be provided.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct Phi3SmallLoader;
+
+impl NormalModelLoader for Phi3SmallLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: ShardedVarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::phi3small::Model::new(
+            &Phi3SmallBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(config)?,
+            normal_loading_metadata,
+            attention_mechanism,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: ShardedVarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (ShardedVarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA is not supported for Phi-3-small models.")
+    }
+    fn is_gptx(&self, _: &str) -> Result<bool> {
+        Ok(true)
+    }
+    fn supports_paged_attention(&self, _config: &str) -> Result<bool> {
+        // The blocksparse mask is not applied by the PagedAttention kernels.
+        Ok(false)
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(Phi3SmallBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+    fn expected_weight_shapes(&self, config: &str) -> Result<Vec<(String, Vec<usize>)>> {
+        let cfg = Phi3SmallBasicConfig::deserialize(config, false)?;
+        if cfg.quantization_config.is_some() {
+            return Ok(Vec::new());
+        }
+        let (h_size, i_size) = (cfg.hidden_size, cfg.ff_intermediate_size);
+        let qkv_size = (cfg.num_attention_heads + 2 * cfg.num_key_value_heads) * cfg.head_dim();
+        let mut shapes = vec![
+            (
+                "model.embed_tokens.weight".to_string(),
+                vec![cfg.vocab_size, h_size],
+            ),
+            ("model.final_layernorm.weight".to_string(), vec![h_size]),
+            ("model.final_layernorm.bias".to_string(), vec![h_size]),
+        ];
+        if !cfg.tie_word_embeddings {
+            shapes.push(("lm_head.weight".to_string(), vec![cfg.vocab_size, h_size]));
+        }
+        for i in 0..cfg.num_hidden_layers {
+            let layer = |name: &str, shape: Vec<usize>| (format!("model.layers.{i}.{name}"), shape);
+            shapes.extend([
+                layer("input_layernorm.weight", vec![h_size]),
+                layer("input_layernorm.bias", vec![h_size]),
+                layer("self_attn.query_key_value.weight", vec![qkv_size, h_size]),
+                layer("self_attn.query_key_value.bias", vec![qkv_size]),
+                layer("self_attn.dense.weight", vec![h_size, h_size]),
+                layer("self_attn.dense.bias", vec![h_size]),
+                layer("post_attention_layernorm.weight", vec![h_size]),
+                layer("post_attention_layernorm.bias", vec![h_size]),
+                layer("mlp.up_proj.weight", vec![2 * i_size, h_size]),
+                layer("mlp.up_proj.bias", vec![2 * i_size]),
+                layer("mlp.down_proj.weight", vec![h_size, i_size]),
+                layer("mlp.down_proj.bias", vec![h_size]),
+            ]);
+        }
+        Ok(shapes)
+    }
+}
+
+impl IsqModelLoader for Phi3SmallLoader {
+    fn isq_layer_regexes(&self, _config: &str) -> Result<Vec<Regex>> {
+        Ok(vec![
+            Regex::new(r"lm_head\.(weight|bias)$")?,
+            // Attention
+            Regex::new(r"layers\.(\d+)\.self_attn\.query_key_value\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.dense\.(weight|bias)$")?,
+            // MLP
+            Regex::new(r"layers\.(\d+)\.mlp\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.down_proj\.(weight|bias)$")?,
+        ])
+    }
+}
+
+impl DeviceMappedModelLoader for Phi3SmallLoader {
+    fn mapped_max_act_size_elems(
+        &self,
+        config: &str,
+        params: &AutoDeviceMapParams,
+        prompt_chunksize: usize,
+    ) -> Result<usize> {
+        let AutoDeviceMapParams::Text {
+            max_seq_len: _,
+            max_batch_size,
+        } = params
+        else {
+            anyhow::bail!("Expected text AutoDeviceMapParams for this model!")
+        };
+
+        let cfg = Phi3SmallBasicConfig::deserialize(config, false)?;
+
+        Ok(max_batch_size * cfg.num_attention_heads * prompt_chunksize * prompt_chunksize)
+    }
+    fn non_mapped_max_act_size_elems(
+        &self,
+        _config: &str,
+        _params: &AutoDeviceMapParams,
+    ) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn non_mapped_size_in_bytes(
+        &self,
+        config: &str,
+        dtype: DType,
+        weight_pack_factor: usize,
+    ) -> Result<usize> {
+        let cfg = Phi3SmallBasicConfig::deserialize(config, false)?;
+        let elems = {
+            let embed_tokens = cfg.hidden_size * cfg.vocab_size / weight_pack_factor;
+            let lm_head = if !cfg.tie_word_embeddings {
+                cfg.hidden_size * cfg.vocab_size
+            } else {
+                0
+            };
+            let final_layernorm = 2 * cfg.hidden_size;
+            embed_tokens + lm_head + final_layernorm
+        };
+        Ok(elems * dtype.size_in_bytes())
+    }
+
+    fn layer_sizes_in_bytes(
+        &self,
+        config: &str,
+        dtype: DType,
+        weight_pack_factor: usize,
+    ) -> Result<Vec<usize>> {
+        let cfg = Phi3SmallBasicConfig::deserialize(config, false)?;
+        let per_layer_elems = {
+            let input_layernorm = 2 * cfg.hidden_size;
+            let post_attention_layernorm = 2 * cfg.hidden_size;
+
+            let size_in = cfg.hidden_size;
+            let size_qkv = (cfg.num_attention_heads + 2 * cfg.num_key_value_heads) * cfg.head_dim();
+            let query_key_value = size_in * size_qkv / weight_pack_factor + size_qkv;
+            let dense = size_in * size_in / weight_pack_factor + size_in;
+
+            let h_size = cfg.hidden_size;
+            let i_size = cfg.ff_intermediate_size;
+            let up_proj = h_size * 2 * i_size / weight_pack_factor + 2 * i_size;
+            let down_proj = i_size * h_size / weight_pack_factor + h_size;
+
+            input_layernorm
+                + post_attention_layernorm
+                + query_key_value
+                + dense
+                + up_proj
+                + down_proj
+        };
+        Ok(vec![
+            per_layer_elems * dtype.size_in_bytes();
+            cfg.num_hidden_layers
+        ])
+    }
+
+    fn num_layers(&self, config: &str) -> Result<usize> {
+        let cfg = Phi3SmallBasicConfig::deserialize(config, false)?;
+        Ok(cfg.num_hidden_layers)
+    }
+
+    fn model_config(&self, config: &str) -> Result<Box<dyn ModelConfigLike>> {
+        let cfg = Phi3SmallBasicConfig::deserialize(config, false)?;
+
+        let cfg = ModelConfigMetadata {
+            max_seq_len: cfg.max_position_embeddings,
+            num_layers: cfg.num_hidden_layers,
+            hidden_size: cfg.hidden_size,
+            num_kv_heads: cfg.num_key_value_heads,
+            num_attn_heads: cfg.num_attention_heads,
+            sliding_window: None,
+            k_head_dim: cfg.head_dim(),
+            v_head_dim: cfg.head_dim(),
+        };
+
+        Ok(Box::new(cfg))
+    }
+}
@@ -0,0 +1,129 @@
+use candle_core::{DType, Result, Tensor, D};
+
+/// Score each continuation by its summed log-likelihood given a shared `prefix`.
+///
+/// The prefix is forwarded once. Each continuation then only forwards its own tokens on top of the
+/// cached prefix, after which the cache is truncated back to the prefix length with `truncate`.
+///
+/// - `forward(tokens, seqlen_offset)` runs the model over `tokens` starting at `seqlen_offset`, appending
+///   them to the cache, and returns the logits for every position with shape `(1, tokens.len(), vocab)`.
+/// - `truncate(len)` rolls the cache back to `len` tokens.
+pub(crate) fn score_continuations(
+    prefix: &[u32],
+    continuations: &[Vec<u32>],
+    mut forward: impl FnMut(&[u32], usize) -> Result<Tensor>,
+    mut truncate: impl FnMut(usize) -> Result<()>,
+) -> Result<Vec<f32>> {
+    if prefix.is_empty() {
+        candle_core::bail!("Scoring continuations requires a non-empty prefix.");
+    }
+
+    // Only the last prefix position is needed: it predicts the first token of every continuation.
+    let prefix_logits = forward(prefix, 0)?;
+    let prefix_last = prefix_logits.narrow(1, prefix_logits.dim(1)? - 1, 1)?;
+
+    let mut scores = Vec::with_capacity(continuations.len());
+    for continuation in continuations {
+        if continuation.is_empty() {
+            scores.push(0.);
+            continue;
+        }
+
+        // The last continuation token is never used to predict anything, so it is not forwarded.
+        let logits = if continuation.len() > 1 {
+            let continuation_logits =
+                forward(&continuation[..continuation.len() - 1], prefix.len())?;
+            truncate(prefix.len())?;
+            Tensor::cat(&[&prefix_last, &continuation_logits], 1)?
+        } else {
+            prefix_last.clone()
+        };
+
+        let log_probs =
+            candle_nn::ops::log_softmax(&logits.squeeze(0)?.to_dtype(DType::F32)?, D::Minus1)?;
+        let targets = Tensor::new(continuation.as_slice(), log_probs.device())?.unsqueeze(1)?;
+        let score = log_probs
+            .gather(&targets, 1)?
+            .sum_all()?
+            .to_scalar::<f32>()?;
+        scores.push(score);
+    }
+
+    Ok(scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Result, Tensor};
+
+    use super::score_continuations;
+
+    const VOCAB: usize = 7;
+
+    /// A causal "model" whose logits at each position depend on every token seen so far, with a
+    /// cache of the tokens that have been forwarded.
+    struct MockModel {
+        cache: Vec<u32>,
+        forwarded: usize,
+    }
+
+    impl MockModel {
+        fn forward(&mut self, tokens: &[u32], seqlen_offset: usize) -> Result<Tensor> {
+            assert_eq!(seqlen_offset, self.cache.len());
+            self.forwarded += tokens.len();
+            let mut logits = Vec::new();
+            for tok in tokens {
+                self.cache.push(*tok);
+                let state = self
+                    .cache
+                    .iter()
+                    .enumerate()
+                    .map(|(i, t)| (i + 1) * (*t as usize + 3))
+                    .sum::<usize>();
+                logits.extend((0..VOCAB).map(|v| ((state * (v + 1)) % 11) as f32 / 3.));
+            }
+            Tensor::from_vec(logits, (1, tokens.len(), VOCAB), &Device::Cpu)
+        }
+    }
+
+    fn full_sequence_score(prefix: &[u32], continuation: &[u32]) -> Result<f32> {
+        let mut model = MockModel {
+            cache: Vec::new(),
+            forwarded: 0,
+        };
+        let toks = [prefix, continuation].concat();
+        let log_probs = candle_nn::ops::log_softmax(&model.forward(&toks, 0)?.squeeze(0)?, 1)?
+            .to_vec2::<f32>()?;
+        Ok((0..continuation.len())
+            .map(|i| log_probs[prefix.len() + i - 1][continuation[i] as usize])
+            .sum())
+    }
+
+    #[test]
+    fn shared_prefix_matches_full_sequence_scoring() -> Result<()> {
+        let prefix = [1, 4, 2, 6];
+        let continuations = vec![vec![3, 5, 0], vec![2, 2]];
+
+        let model = std::cell::RefCell::new(MockModel {
+            cache: Vec::new(),
+            forwarded: 0,
+        });
+        let scores = score_continuations(
+            &prefix,
+            &continuations,
+            |toks, offset| model.borrow_mut().forward(toks, offset),
+            |len| {
+                model.borrow_mut().cache.truncate(len);
+                Ok(())
+            },
+        )?;
+
+        for (score, continuation) in scores.iter().zip(&continuations) {
+            let expected = full_sequence_score(&prefix, continuation)?;
+            assert!((score - expected).abs() < 1e-5, "{score} != {expected}");
+        }
+        // The prefix is only forwarded once.
+        assert_eq!(model.borrow().forwarded, prefix.len() + 2 + 1);
+        Ok(())
+    }
+}
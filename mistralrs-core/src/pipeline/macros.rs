@@ -64,7 +64,8 @@ macro_rules! get_paths {
         $quantized_model_id:expr,
         $quantized_filename:expr,
         $silent:expr,
-        $loading_uqff:expr
+        $loading_uqff:expr,
+        $shard_download_retries:expr
     ) => {{
         let api = {
             use $crate::GLOBAL_HF_CACHE;
@@ -101,6 +102,7 @@ macro_rules! get_paths {
             &api,
             &model_id,
             $loading_uqff,
+            $shard_download_retries,
         )?;
         let adapter_paths = get_xlora_paths(
             $this.model_id.clone(),
@@ -221,7 +223,17 @@ macro_rules! get_uqff_paths {
         for file in $from_uqff {
             let file = file.display().to_string();
 
-            files.push(api_get_file!(api, &file, Path::new(&$this.model_id)));
+            let names = if file.contains('*') {
+                $crate::pipeline::expand_uqff_pattern(
+                    &file,
+                    $crate::api_dir_list!(api, Path::new(&$this.model_id)),
+                )?
+            } else {
+                vec![file]
+            };
+            for file in names {
+                files.push(api_get_file!(api, &file, Path::new(&$this.model_id)));
+            }
         }
         files
     }};
@@ -288,6 +300,7 @@ macro_rules! get_paths_gguf {
             &api,
             &model_id,
             false, // Never loading UQFF
+            0,
         )?;
 
         let adapter_paths = get_xlora_paths(
@@ -395,6 +408,7 @@ macro_rules! normal_model_loader {
         $attention_mechanism:expr,
         $is_moqe:expr,
         $multi_progress:expr,
+        $shared_embeddings:expr,
     ) => {{
         let regexes = if $loading_isq && $loading_uqff {
             // Dummy weights for the layers which will be overwritten...
@@ -421,18 +435,29 @@ macro_rules! normal_model_loader {
             get_device_for_tensor,
         )?;
 
-        $loader.load(
-            &$config,
-            $use_flash_attn,
-            vb,
-            $crate::pipeline::NormalLoadingMetadata {
-                mapper: $mapper,
-                loading_isq: $loading_isq,
-                real_device: $real_device,
-                multi_progress: $multi_progress,
-            },
-            $attention_mechanism,
-        )?
+        let normal_loading_metadata = $crate::pipeline::NormalLoadingMetadata {
+            mapper: $mapper,
+            loading_isq: $loading_isq,
+            real_device: $real_device,
+            multi_progress: $multi_progress,
+        };
+        match $shared_embeddings {
+            Some(shared) => $loader.load_with_shared_embeddings(
+                &$config,
+                $use_flash_attn,
+                vb,
+                normal_loading_metadata,
+                $attention_mechanism,
+                shared,
+            )?,
+            None => $loader.load(
+                &$config,
+                $use_flash_attn,
+                vb,
+                normal_loading_metadata,
+                $attention_mechanism,
+            )?,
+        }
     }};
 }
 
@@ -449,19 +474,31 @@ macro_rules! normal_model_loader_sharded {
         $real_device:expr,
         $attention_mechanism:expr,
         $multi_progress:expr,
+        $shared_embeddings:expr,
     ) => {{
-        $loader.load(
-            &$config,
-            $use_flash_attn,
-            $vb,
-            $crate::pipeline::NormalLoadingMetadata {
-                mapper: $mapper,
-                loading_isq: $loading_isq,
-                real_device: $real_device,
-                multi_progress: $multi_progress,
-            },
-            $attention_mechanism,
-        )?
+        let normal_loading_metadata = $crate::pipeline::NormalLoadingMetadata {
+            mapper: $mapper,
+            loading_isq: $loading_isq,
+            real_device: $real_device,
+            multi_progress: $multi_progress,
+        };
+        match $shared_embeddings {
+            Some(shared) => $loader.load_with_shared_embeddings(
+                &$config,
+                $use_flash_attn,
+                $vb,
+                normal_loading_metadata,
+                $attention_mechanism,
+                shared,
+            )?,
+            None => $loader.load(
+                &$config,
+                $use_flash_attn,
+                $vb,
+                normal_loading_metadata,
+                $attention_mechanism,
+            )?,
+        }
     }};
 }
 
@@ -4,53 +4,64 @@ pub mod chat_template;
 mod diffusion;
 mod ggml;
 mod gguf;
+mod input_gradients;
 mod inputs_processor;
 mod isq;
 pub(crate) mod llg;
 mod loaders;
+mod loglikelihood;
 mod macros;
 mod normal;
 mod paths;
 mod processing;
+mod prompt_lookup;
 mod response;
 mod sampling;
+pub(crate) mod schema_regex;
 mod speculative;
+mod value_head;
 mod vision;
 
 pub use super::diffusion_models::DiffusionGenerationParams;
 use crate::amoe::{AnyMoeConfig, AnyMoeExpertType, AnyMoeTrainingInputs, AnyMoeTrainingResult};
 use crate::device_map::DeviceMapper;
-use crate::paged_attention::{CacheConfig, CacheEngine, ModelConfigLike};
+use crate::expert_counts::ExpertCounter;
+use crate::paged_attention::{CacheConfig, CacheEngine, ModelConfigLike, PagedCacheStats};
 use crate::prefix_cacher::PrefixCacheManagerV2;
+use crate::qkv_capture::QkvCapture;
+use crate::sampler::{SamplingParams, SamplingRng};
 pub use amoe::{AnyMoeLoader, AnyMoePipeline};
-use chat_template::ChatTemplate;
+use chat_template::{ChatTemplate, GenerationSamplingConfig};
 pub use diffusion::{DiffusionLoader, DiffusionLoaderBuilder, DiffusionSpecificConfig};
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
 pub use gguf::{GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig};
 use image::DynamicImage;
+pub use input_gradients::GradientTarget;
 pub use inputs_processor::InputProcessorOutput;
-pub(crate) use isq::IsqModelLoader;
+pub(crate) use isq::{expand_uqff_pattern, IsqModelLoader};
 pub use isq::{parse_isq_value, IsqModel, IsqOrganization, UQFF_MULTI_FILE_DELIMITER};
 pub use loaders::{
-    AdapterKind, AutoDeviceMapParams, AutoLoader, DeepSeekV2Loader, DeepSeekV3Loader,
-    DeviceMappedModelLoader, DiffusionLoaderType, DiffusionModel, DiffusionModelLoader, FluxLoader,
-    Gemma2Loader, Gemma3Loader, GemmaLoader, Idefics2Loader, Idefics3Loader, LLaVALoader,
-    LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths, MiniCpmOLoader, Mistral3Loader,
-    MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoaderType, NormalLoadingMetadata,
-    NormalModel, NormalModelLoader, Phi2Loader, Phi3Loader, Phi3VLoader, Phi3_5MoELoader,
+    AdapterKind, AutoDeviceMapParams, AutoLoader, CohereLoader, ConfigValidationError,
+    DeepSeekV2Loader, DeepSeekV3Loader, DeviceMappedModelLoader, DiffusionLoaderType,
+    DiffusionModel, DiffusionModelLoader, FluxLoader, Gemma2Loader, Gemma3Loader, GemmaLoader,
+    Idefics2Loader, Idefics3Loader, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader,
+    LocalModelPaths, MiniCpmOLoader, Mistral3Loader, MistralLoader, MixtralLoader, ModelKind,
+    ModelPaths, NormalLoaderType, NormalLoadingMetadata, NormalModel, NormalModelLoader,
+    NormalizedConfig, Phi2Loader, Phi3Loader, Phi3SmallLoader, Phi3VLoader, Phi3_5MoELoader,
     Phi4MMLoader, PrettyName, QuantizationKind, Qwen2Loader, Qwen2VLLoader, Qwen2_5VLLoader,
-    Starcoder2Loader, TokenSource, VLlama4Loader, VLlamaLoader, VisionLoaderType, VisionModel,
-    VisionModelLoader,
+    ResourceEstimate, SharedEmbeddings, Starcoder2Loader, TokenSource, VLlama4Loader, VLlamaLoader,
+    VisionLoaderType, VisionModel, VisionModelLoader, WeightSource,
 };
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{IsqType, QuantInfo};
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
 pub(crate) use paths::{
-    get_chat_template, get_model_paths, get_xlora_paths, AdapterPaths, LoraAdapterPaths,
+    get_chat_template, get_chat_template_with_string, get_model_paths, get_xlora_paths,
+    AdapterPaths, LoraAdapterPaths,
 };
 pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
-use rand_isaac::Isaac64Rng;
+pub use prompt_lookup::{PromptLookupConfig, PromptLookupLoader, PromptLookupPipeline};
 pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
 use std::any::Any;
 use std::collections::HashMap;
@@ -65,12 +76,13 @@ use candle_core::{DType, Device, IndexOp, Tensor, Var};
 
 use crate::sequence::Sequence;
 
+use self::cache_manager::NormalCacheManager;
 pub use self::cache_manager::{
     Cache, CacheManager, EitherCache, KvCache, LayerCaches, NormalCache, NormalCacheType,
     RotatingCache, SingleCache,
 };
 pub use self::inputs_processor::{
-    text_models_inputs_processor, InputsProcessor, InputsProcessorType,
+    text_models_inputs_processor, InputsProcessor, InputsProcessorType, PromptChunksize,
 };
 use self::text_models_inputs_processor::PagedAttentionMeta;
 
@@ -92,6 +104,8 @@ pub struct GeneralMetadata {
     pub cache_engine: Option<CacheEngine>,
     pub prompt_chunksize: Option<NonZeroUsize>,
     pub model_metadata: Option<Arc<dyn ModelConfigLike + Send + Sync>>,
+    /// Sampling fields of `generation_config.json`, if any.
+    pub generation_sampling: GenerationSamplingConfig,
 }
 
 pub enum CacheInstruction {
@@ -112,6 +126,26 @@ pub trait PreProcessingMixin: MetadataMixin {
     /// Only None if it doesnt make sense for the model
     fn get_chat_template(&self) -> Option<Arc<ChatTemplate>>;
     fn get_input_processor_config(&self) -> Option<Arc<dyn Any>>;
+    /// Tokenize `text`, with `add_special_tokens` controlling whether tokens such as BOS are added.
+    fn tokenize(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        let tokenizer = self
+            .tokenizer()
+            .ok_or_else(|| anyhow::anyhow!("Model `{}` has no tokenizer.", self.name()))?;
+        Ok(tokenizer
+            .encode_fast(text, add_special_tokens)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec())
+    }
+    /// Detokenize `tokens`, with `skip_special_tokens` controlling whether special tokens are omitted.
+    fn detokenize(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+        let tokenizer = self
+            .tokenizer()
+            .ok_or_else(|| anyhow::anyhow!("Model `{}` has no tokenizer.", self.name()))?;
+        tokenizer
+            .decode(tokens, skip_special_tokens)
+            .map_err(anyhow::Error::msg)
+    }
 }
 
 pub trait IsqPipelineMixin {
@@ -149,6 +183,31 @@ pub trait MetadataMixin {
     fn reset_non_granular_state(&self);
     fn get_metadata(&self) -> Arc<GeneralMetadata>;
     fn device_mapper(&self) -> Option<&dyn DeviceMapper>;
+    /// The quantization method, bit width and group size of each quantizable weight. This is useful
+    /// to check that a topology was applied. Empty if the model has no quantizable layers.
+    fn quant_manifest(&self) -> Vec<(String, QuantInfo)> {
+        Vec::new()
+    }
+    /// Total, used and free PagedAttention KV cache blocks of the GPU and CPU pools. None if
+    /// PagedAttention is not used.
+    fn paged_cache_stats(&self) -> Option<PagedCacheStats> {
+        self.get_metadata()
+            .cache_config
+            .as_ref()
+            .map(CacheConfig::stats)
+    }
+    /// The sampling params suggested by the model's `generation_config.json`, for a frontend to
+    /// pre-populate. See [`GenerationSamplingConfig::recommended_sampling`] for the fallbacks.
+    fn recommended_sampling(&self) -> SamplingParams {
+        self.get_metadata()
+            .generation_sampling
+            .recommended_sampling()
+    }
+    /// The model's input embedding and LM head, for models which can share them with a speculative
+    /// decoding draft model.
+    fn shared_embeddings(&self) -> Option<SharedEmbeddings> {
+        None
+    }
 }
 
 /// Implemented by the base model of an AnyMoe.
@@ -251,17 +310,51 @@ pub enum CacheBackendMetadata<'a> {
 
 #[derive(Clone, Debug)]
 pub enum ForwardInputsResult {
-    RawLogits { logits: Tensor },
-    CausalGeneration { logits: Tensor },
-    Image { images: Vec<DynamicImage> },
+    RawLogits {
+        logits: Tensor,
+    },
+    CausalGeneration {
+        logits: Tensor,
+    },
+    /// Causal generation logits along with the `k` most likely tokens and their logprobs for each
+    /// sequence of the batch, most likely first.
+    CausalGenerationWithLogprobs {
+        logits: Tensor,
+        top_logprobs: Vec<Vec<(u32, f32)>>,
+    },
+    Image {
+        images: Vec<DynamicImage>,
+    },
 }
 
 impl ForwardInputsResult {
+    /// Attach the top `k` logprobs of the last position to causal generation logits. Other results,
+    /// and `k` of `None`, are returned as is.
+    pub(crate) fn with_top_logprobs(self, k: Option<usize>) -> candle_core::Result<Self> {
+        match (self, k) {
+            (Self::CausalGeneration { logits }, Some(k)) => {
+                let top_logprobs = top_k_logprobs(&logits, k)?;
+                Ok(Self::CausalGenerationWithLogprobs {
+                    logits,
+                    top_logprobs,
+                })
+            }
+            (this, _) => Ok(this),
+        }
+    }
+
     fn index_bs(&self, bs_idx: usize) -> candle_core::Result<Self> {
         match self {
             Self::CausalGeneration { logits } => Ok(Self::CausalGeneration {
                 logits: logits.i(bs_idx)?,
             }),
+            Self::CausalGenerationWithLogprobs {
+                logits,
+                top_logprobs,
+            } => Ok(Self::CausalGenerationWithLogprobs {
+                logits: logits.i(bs_idx)?,
+                top_logprobs: vec![top_logprobs[bs_idx].clone()],
+            }),
             Self::RawLogits { logits } => Ok(Self::RawLogits {
                 logits: logits.i(bs_idx)?,
             }),
@@ -276,6 +369,13 @@ impl ForwardInputsResult {
             Self::CausalGeneration { logits } => Ok(Self::CausalGeneration {
                 logits: logits.to_device(device)?,
             }),
+            Self::CausalGenerationWithLogprobs {
+                logits,
+                top_logprobs,
+            } => Ok(Self::CausalGenerationWithLogprobs {
+                logits: logits.to_device(device)?,
+                top_logprobs: top_logprobs.clone(),
+            }),
             Self::RawLogits { logits } => Ok(Self::RawLogits {
                 logits: logits.to_device(device)?,
             }),
@@ -284,6 +384,34 @@ impl ForwardInputsResult {
     }
 }
 
+/// The `k` most likely tokens and their logprobs at the last position of each sequence of `logits`,
+/// which is either `(bs, vocab)` or `(bs, seq_len, vocab)`, most likely first. The softmax runs on the
+/// logits' device, but the top `k` are partitioned out on the CPU: sorting a whole vocabulary on device
+/// is slow, and fails on CUDA once it no longer fits in shared memory.
+fn top_k_logprobs(logits: &Tensor, k: usize) -> candle_core::Result<Vec<Vec<(u32, f32)>>> {
+    let logits = match logits.rank() {
+        2 => logits.clone(),
+        3 => logits.i((.., logits.dim(1)? - 1))?,
+        rank => candle_core::bail!("Expected logits of rank 2 or 3, got rank {rank}."),
+    };
+    let logprobs = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, 1)?;
+    let most_likely_first =
+        |a: &(u32, f32), b: &(u32, f32)| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0));
+    Ok(logprobs
+        .to_vec2::<f32>()?
+        .into_iter()
+        .map(|logprobs| {
+            let mut top = (0..).zip(logprobs).collect::<Vec<(u32, f32)>>();
+            if 0 < k && k < top.len() {
+                top.select_nth_unstable_by(k - 1, most_likely_first);
+            }
+            top.truncate(k);
+            top.sort_unstable_by(most_likely_first);
+            top
+        })
+        .collect())
+}
+
 #[async_trait::async_trait]
 pub trait Pipeline:
     Send
@@ -294,10 +422,16 @@ pub trait Pipeline:
     + MetadataMixin
     + AnyMoePipelineMixin
 {
+    /// Run the model on `inputs`. With `return_raw_logits`, the logits of every position are
+    /// returned as [`ForwardInputsResult::RawLogits`] and `return_logprobs` is ignored. Otherwise,
+    /// `Some(k)` computes the top `k` logprobs of the last position, returning
+    /// [`ForwardInputsResult::CausalGenerationWithLogprobs`]. Pipelines which cannot return logprobs
+    /// error on `Some(k)`.
     fn forward_inputs(
         &mut self,
         inputs: Box<dyn Any>,
         return_raw_logits: bool,
+        return_logprobs: Option<usize>,
     ) -> Result<ForwardInputsResult, candle_core::Error>;
 
     /// Returns the total of model execution time.
@@ -309,7 +443,7 @@ pub trait Pipeline:
         return_raw_logits: bool,
         prefix_cacher: &mut PrefixCacheManagerV2,
         disable_eos_stop: bool,
-        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
         backend_metadata: CacheBackendMetadata<'_>,
     ) -> Result<Duration, candle_core::Error> {
         match backend_metadata {
@@ -366,7 +500,7 @@ pub trait Pipeline:
                     }
 
                     let start = Instant::now();
-                    let raw_logits = self.forward_inputs(inputs, return_raw_logits)?;
+                    let raw_logits = self.forward_inputs(inputs, return_raw_logits, None)?;
                     let end = Instant::now();
                     exec_duration += end.duration_since(start);
 
@@ -395,6 +529,39 @@ pub trait Pipeline:
                     _ => unreachable!("Unreachable POST cache op."),
                 }
 
+                // The limits apply to the sequence caches, so a sequence past its limit fails alone
+                // and the rest of the batch is still sampled.
+                let mut failed = NormalCacheManager
+                    .enforce_limits(self, input_seqs)
+                    .into_iter()
+                    .peekable();
+                let mut live_seqs = Vec::with_capacity(input_seqs.len());
+                let mut live_logits = Vec::with_capacity(input_seqs.len());
+                let mut live_raw_out_logits = Vec::with_capacity(input_seqs.len());
+                for (i, ((seq, logits), raw)) in input_seqs
+                    .iter_mut()
+                    .zip(logits)
+                    .zip(raw_out_logits)
+                    .enumerate()
+                {
+                    if let Some((_, e)) = failed.next_if(|(failed_idx, _)| *failed_idx == i) {
+                        seq.responder()
+                            .send(crate::Response::InternalError(e.into()))
+                            .await
+                            .expect("Expected receiver.");
+                        seq.set_state(crate::sequence::SequenceState::Error);
+                    } else {
+                        live_seqs.push(&mut **seq);
+                        live_logits.push(logits);
+                        live_raw_out_logits.push(raw);
+                    }
+                }
+                if live_seqs.is_empty() {
+                    return Ok(exec_duration);
+                }
+                let input_seqs = &mut live_seqs[..];
+                let (logits, raw_out_logits) = (live_logits, live_raw_out_logits);
+
                 if raw_out_logits[0][0].is_some() {
                     let start = Instant::now();
                     response::send_raw_responses(
@@ -422,14 +589,18 @@ pub trait Pipeline:
                 let start = Instant::now();
                 match &logits[0] {
                     ForwardInputsResult::RawLogits { .. } => unreachable!(),
-                    ForwardInputsResult::CausalGeneration { .. } => {
+                    ForwardInputsResult::CausalGeneration { .. }
+                    | ForwardInputsResult::CausalGenerationWithLogprobs { .. } => {
                         self.sample_causal_gen(
                             input_seqs,
                             logits
                                 .into_iter()
                                 .map(|r| {
-                                    #[allow(irrefutable_let_patterns)]
-                                    let ForwardInputsResult::CausalGeneration { logits } = r
+                                    let (ForwardInputsResult::CausalGeneration { logits }
+                                    | ForwardInputsResult::CausalGenerationWithLogprobs {
+                                        logits,
+                                        ..
+                                    }) = r
                                     else {
                                         unreachable!(
                                             "All results must have same type, `CausalGeneration`"
@@ -525,7 +696,7 @@ pub trait Pipeline:
                     } = inputs.map_err(candle_core::Error::msg)?;
 
                     let start = Instant::now();
-                    let raw_logits = self.forward_inputs(inputs, return_raw_logits)?;
+                    let raw_logits = self.forward_inputs(inputs, return_raw_logits, None)?;
                     let end = Instant::now();
                     exec_duration += end.duration_since(start);
 
@@ -566,14 +737,18 @@ pub trait Pipeline:
                 let start = Instant::now();
                 match &logits[0] {
                     ForwardInputsResult::RawLogits { .. } => unreachable!(),
-                    ForwardInputsResult::CausalGeneration { .. } => {
+                    ForwardInputsResult::CausalGeneration { .. }
+                    | ForwardInputsResult::CausalGenerationWithLogprobs { .. } => {
                         self.sample_causal_gen(
                             input_seqs,
                             logits
                                 .into_iter()
                                 .map(|r| {
-                                    #[allow(irrefutable_let_patterns)]
-                                    let ForwardInputsResult::CausalGeneration { logits } = r
+                                    let (ForwardInputsResult::CausalGeneration { logits }
+                                    | ForwardInputsResult::CausalGenerationWithLogprobs {
+                                        logits,
+                                        ..
+                                    }) = r
                                     else {
                                         unreachable!("All results must have same type")
                                     };
@@ -623,10 +798,104 @@ pub trait Pipeline:
         logits: Vec<Tensor>,
         prefix_cacher: &mut PrefixCacheManagerV2,
         disable_eos_stop: bool,
-        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
     ) -> Result<(), candle_core::Error>;
 
     fn category(&self) -> ModelCategory;
+
+    /// Compute the summed log-likelihood of each of `continuations` given a shared `prefix`, for example to
+    /// score the choices of a multiple-choice eval. The prefix is only prefilled once and the KV cache is
+    /// truncated back to it after each continuation.
+    fn continuation_loglikelihoods(
+        &mut self,
+        _prefix: &[u32],
+        _continuations: &[Vec<u32>],
+    ) -> Result<Vec<f32>, candle_core::Error> {
+        candle_core::bail!(
+            "Pipeline `{}` does not support scoring continuations.",
+            self.name()
+        )
+    }
+
+    /// Run the backbone over `tokens` and apply the loaded value head to the final hidden state of the
+    /// last token, as done by reward models.
+    fn score_reward(&mut self, _tokens: &[u32]) -> Result<f32, candle_core::Error> {
+        candle_core::bail!(
+            "Pipeline `{}` does not support reward scoring.",
+            self.name()
+        )
+    }
+
+    /// Project the output of every decoder layer through the final norm and the LM head, returning the
+    /// most likely token for each layer (outer) and each position of `tokens` (inner).
+    fn logit_lens(&mut self, _tokens: &[u32]) -> Result<Vec<Vec<u32>>, candle_core::Error> {
+        candle_core::bail!(
+            "Pipeline `{}` does not support the logit lens.",
+            self.name()
+        )
+    }
+
+    /// Run `tokens` through the model with differentiable ops and return the gradient of `target` with
+    /// respect to the input embeddings, with shape `(tokens.len(), hidden_size)`.
+    fn input_embedding_gradients(
+        &mut self,
+        _tokens: &[u32],
+        _target: GradientTarget,
+    ) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!(
+            "Pipeline `{}` does not support input embedding gradients.",
+            self.name()
+        )
+    }
+
+    /// Prefill each of `prompts`, such as known system prompts, and store its KV cache in `prefix_cacher`
+    /// so that requests starting with one of them skip that part of the prefill. Caches beyond the
+    /// prefix cacher's on-device bound are evicted to the CPU.
+    fn prime_prefix_cache(
+        &mut self,
+        _prompts: Vec<Vec<u32>>,
+        _prefix_cacher: &mut PrefixCacheManagerV2,
+    ) -> Result<(), candle_core::Error> {
+        candle_core::bail!(
+            "Pipeline `{}` does not support priming the prefix cache.",
+            self.name()
+        )
+    }
+
+    /// The per-expert routing counts of an MoE model. Enable the counter, run `forward_inputs` and then
+    /// take the counts to see how many tokens each expert received.
+    fn expert_counter(&self) -> Option<ExpertCounter> {
+        None
+    }
+
+    /// The capture of the query, key and value tensors of one attention layer. Enable it for a layer and a
+    /// sequence, run `forward_inputs` and then take the captured tensors.
+    fn qkv_capture(&self) -> Option<QkvCapture> {
+        None
+    }
+}
+
+impl dyn Pipeline {
+    /// Finish every sequence in `seqs` with its partial output and
+    /// [`StopReason::Shutdown`](crate::StopReason::Shutdown), without running the model. Streaming
+    /// sequences get their unsent output and the finish reason as a last chunk. Sequences which have
+    /// already finished are left alone, and a sequence which cannot be finished does not stop the
+    /// others.
+    pub async fn drain(
+        &self,
+        seqs: &mut [&mut Sequence],
+        prefix_cacher: &mut PrefixCacheManagerV2,
+    ) {
+        for seq in seqs.iter_mut() {
+            if seq.is_finished_paged_attn() {
+                continue;
+            }
+            if let Err(e) = sampling::finish_seq_on_shutdown(self, prefix_cacher, seq).await {
+                tracing::warn!("Failed to finish sequence {} on shutdown: {e}", seq.id());
+            }
+        }
+        self.reset_non_granular_state();
+    }
 }
 
 pub(crate) fn extract_logits(
@@ -640,6 +909,26 @@ pub(crate) fn extract_logits(
     Tensor::cat(&toks, 0)
 }
 
+/// Pair the pipeline's PagedAttention cache engine with the step's input metadata. Exactly one
+/// being present is a misconfiguration, reported as a [`crate::MistralRsError`] wrapped in the candle error.
+pub(crate) fn pair_paged_attn_meta<'a, E, M>(
+    cache_engine: Option<&'a E>,
+    meta: Option<&'a M>,
+) -> candle_core::Result<Option<(&'a E, &'a M)>> {
+    match (cache_engine, meta) {
+        (Some(cache_engine), Some(meta)) => Ok(Some((cache_engine, meta))),
+        // This can happen if Rust-side user code is wrong
+        (Some(_), None) => Err(candle_core::Error::wrap(
+            crate::MistralRsError::PagedAttentionMetadataMissing,
+        )),
+        // This should never happen but we handle it anyway
+        (None, Some(_)) => Err(candle_core::Error::wrap(
+            crate::MistralRsError::PagedAttentionCacheEngineMissing,
+        )),
+        (None, None) => Ok(None),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::MessageContent;
@@ -885,4 +1174,507 @@ mod tests {
 
         test_with_inputs(&templates, &expected_outputs, inputs);
     }
+
+    #[test]
+    fn paged_attn_meta_mismatch_is_typed() {
+        use super::pair_paged_attn_meta;
+        use crate::MistralRsError;
+
+        fn typed(err: candle_core::Error) -> Option<MistralRsError> {
+            match err {
+                candle_core::Error::Wrapped(err) => {
+                    err.downcast::<MistralRsError>().ok().map(|e| *e)
+                }
+                candle_core::Error::WithBacktrace { inner, .. } => typed(*inner),
+                _ => None,
+            }
+        }
+
+        let err = pair_paged_attn_meta(Some(&()), None::<&()>).unwrap_err();
+        assert!(matches!(
+            typed(err),
+            Some(MistralRsError::PagedAttentionMetadataMissing)
+        ));
+        let err = pair_paged_attn_meta(None::<&()>, Some(&())).unwrap_err();
+        assert!(matches!(
+            typed(err),
+            Some(MistralRsError::PagedAttentionCacheEngineMissing)
+        ));
+        assert!(pair_paged_attn_meta(None::<&()>, None::<&()>)
+            .unwrap()
+            .is_none());
+        assert!(pair_paged_attn_meta(Some(&1), Some(&2)).unwrap().is_some());
+    }
+
+    #[test]
+    fn top_logprobs_of_the_last_position() {
+        use super::ForwardInputsResult;
+        use candle_core::{Device, Tensor};
+
+        // Two sequences of two positions, only the last one is ranked.
+        let logits = Tensor::new(
+            &[
+                [[9f32, 0., 0.], [1., 3., 2.]],
+                [[0., 0., 9.], [2f32.ln(), 0., 3f32.ln()]],
+            ],
+            &Device::Cpu,
+        )
+        .unwrap();
+        let result = ForwardInputsResult::CausalGeneration {
+            logits: logits.clone(),
+        }
+        .with_top_logprobs(Some(2))
+        .unwrap();
+        let ForwardInputsResult::CausalGenerationWithLogprobs { top_logprobs, .. } = &result else {
+            panic!("Expected logprobs, got {result:?}");
+        };
+        let norm = (1f32.exp() + 3f32.exp() + 2f32.exp()).ln();
+        let expected = [
+            vec![(1, 3. - norm), (2, 2. - norm)],
+            vec![(2, 0.5f32.ln()), (0, (1. / 3f32).ln())],
+        ];
+        for (got, expected) in top_logprobs.iter().zip(expected) {
+            assert_eq!(got.len(), expected.len());
+            for ((tok, lp), (expected_tok, expected_lp)) in got.iter().zip(expected) {
+                assert_eq!(*tok, expected_tok);
+                assert!((lp - expected_lp).abs() < 1e-5);
+            }
+        }
+        let ForwardInputsResult::CausalGenerationWithLogprobs { top_logprobs, .. } =
+            result.index_bs(1).unwrap()
+        else {
+            unreachable!()
+        };
+        assert_eq!(top_logprobs.len(), 1);
+        assert_eq!(top_logprobs[0][0].0, 2);
+
+        // Without `k`, and for raw logits, nothing is computed.
+        assert!(matches!(
+            ForwardInputsResult::CausalGeneration {
+                logits: logits.clone()
+            }
+            .with_top_logprobs(None)
+            .unwrap(),
+            ForwardInputsResult::CausalGeneration { .. }
+        ));
+        assert!(matches!(
+            ForwardInputsResult::RawLogits { logits }
+                .with_top_logprobs(Some(2))
+                .unwrap(),
+            ForwardInputsResult::RawLogits { .. }
+        ));
+    }
+
+    #[test]
+    fn recommended_sampling_from_generation_config() {
+        use super::chat_template::GenerationConfig;
+
+        let conf: GenerationConfig = serde_json::from_str(
+            r#"{"bos_token_id": 1, "eos_token_id": [2, 3], "do_sample": true, "temperature": 0.6,
+                "top_p": 0.95, "top_k": 20, "repetition_penalty": 1.05}"#,
+        )
+        .unwrap();
+        let params = conf.sampling().recommended_sampling();
+        assert_eq!(params.temperature, Some(0.6));
+        assert_eq!(params.top_p, Some(0.95));
+        assert_eq!(params.top_k, Some(20));
+        assert_eq!(params.n_choices, 1);
+        assert_eq!(conf.sampling().repetition_penalty, Some(1.05));
+        assert!(params.max_len.is_none());
+
+        // Absent fields fall back to the request defaults.
+        let conf: GenerationConfig =
+            serde_json::from_str(r#"{"bos_token_id": 1, "eos_token_id": 2}"#).unwrap();
+        let params = conf.sampling().recommended_sampling();
+        assert!(params.temperature.is_none());
+        assert!(params.top_p.is_none());
+        assert!(params.top_k.is_none());
+
+        // Without sampling, greedy decoding is recommended.
+        let conf: GenerationConfig = serde_json::from_str(
+            r#"{"bos_token_id": 1, "eos_token_id": 2, "do_sample": false, "temperature": 0.6}"#,
+        )
+        .unwrap();
+        let params = conf.sampling().recommended_sampling();
+        assert!(params.temperature.is_none());
+        assert_eq!(params.top_k, Some(1));
+    }
+
+    #[test]
+    fn reasoning_end_tok_from_config_or_template() -> anyhow::Result<()> {
+        use std::str::FromStr;
+
+        use tokenizers::Tokenizer;
+
+        use super::chat_template::ChatTemplate;
+
+        let tokenizer = Tokenizer::from_str(
+            r#"{
+                "version": "1.0",
+                "added_tokens": [
+                    {"id": 0, "content": "</s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true},
+                    {"id": 1, "content": "<think>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": false},
+                    {"id": 2, "content": "</think>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": false},
+                    {"id": 3, "content": "</answer>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": false}
+                ],
+                "model": {"type": "WordLevel", "vocab": {"</s>": 0, "<think>": 1, "</think>": 2, "</answer>": 3, "<unk>": 4}, "unk_token": "<unk>"}
+            }"#,
+        )
+        .map_err(anyhow::Error::msg)?;
+
+        // `<s>`/`</s>` are also opened and closed by the template, but `</s>` is a special token.
+        let template = r#""{% for m in messages %}<s>{{ m.content.split('</think>')[-1] }}</s>{% endfor %}<think>""#;
+        let from_template: ChatTemplate =
+            serde_json::from_str(&format!(r#"{{"chat_template": {template}}}"#))?;
+        assert_eq!(
+            from_template.reasoning_end_tok(&tokenizer).as_deref(),
+            Some("</think>")
+        );
+
+        let from_config: ChatTemplate = serde_json::from_str(&format!(
+            r#"{{"chat_template": {template}, "reasoning_end_token": "</answer>"}}"#
+        ))?;
+        assert_eq!(
+            from_config.reasoning_end_tok(&tokenizer).as_deref(),
+            Some("</answer>")
+        );
+
+        let no_reasoning: ChatTemplate = serde_json::from_str(
+            r#"{"chat_template": "{% for m in messages %}{{ m.content }}</s>{% endfor %}"}"#,
+        )?;
+        assert_eq!(no_reasoning.reasoning_end_tok(&tokenizer), None);
+        Ok(())
+    }
+
+    mod tokenize {
+        use std::{any::Any, str::FromStr, sync::Arc};
+
+        use candle_core::{DType, Device};
+        use tokenizers::Tokenizer;
+
+        use crate::{
+            device_map::DeviceMapper,
+            pipeline::{
+                chat_template::ChatTemplate, GeneralMetadata, MetadataMixin, ModelKind,
+                PreProcessingMixin,
+            },
+        };
+
+        const TOKENIZER: &str = r#"{
+            "version": "1.0",
+            "added_tokens": [
+                {"id": 0, "content": "<s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}
+            ],
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": {
+                "type": "TemplateProcessing",
+                "single": [{"SpecialToken": {"id": "<s>", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}}],
+                "pair": [{"SpecialToken": {"id": "<s>", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}}, {"Sequence": {"id": "B", "type_id": 1}}],
+                "special_tokens": {"<s>": {"id": "<s>", "ids": [0], "tokens": ["<s>"]}}
+            },
+            "model": {"type": "WordLevel", "vocab": {"<s>": 0, "hello": 1, "world": 2, "<unk>": 3}, "unk_token": "<unk>"}
+        }"#;
+
+        struct TokenizerOnly(Arc<Tokenizer>);
+
+        impl MetadataMixin for TokenizerOnly {
+            fn device(&self) -> Device {
+                Device::Cpu
+            }
+            fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+                Some(self.0.clone())
+            }
+            fn name(&self) -> String {
+                "tokenizer-only".to_string()
+            }
+            fn reset_non_granular_state(&self) {}
+            fn get_metadata(&self) -> Arc<GeneralMetadata> {
+                Arc::new(GeneralMetadata {
+                    max_seq_len: 16,
+                    tok_env: None,
+                    no_kv_cache: true,
+                    no_prefix_cache: true,
+                    num_hidden_layers: 0,
+                    eos_tok: vec![],
+                    kind: ModelKind::Normal,
+                    is_xlora: false,
+                    activation_dtype: DType::F32,
+                    sliding_window: None,
+                    cache_config: None,
+                    cache_engine: None,
+                    prompt_chunksize: None,
+                    model_metadata: None,
+                    generation_sampling: Default::default(),
+                })
+            }
+            fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
+                None
+            }
+        }
+
+        impl PreProcessingMixin for TokenizerOnly {
+            fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+                None
+            }
+            fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+                None
+            }
+        }
+
+        #[test]
+        fn tokenize_controls_special_tokens() -> anyhow::Result<()> {
+            let pipeline = TokenizerOnly(Arc::new(
+                Tokenizer::from_str(TOKENIZER).map_err(anyhow::Error::msg)?,
+            ));
+
+            assert_eq!(pipeline.tokenize("hello world", true)?, vec![0, 1, 2]);
+            assert_eq!(pipeline.tokenize("hello world", false)?, vec![1, 2]);
+
+            assert_eq!(pipeline.detokenize(&[0, 1, 2], true)?, "hello world");
+            assert_eq!(pipeline.detokenize(&[0, 1, 2], false)?, "<s> hello world");
+            Ok(())
+        }
+    }
+
+    mod drain {
+        use std::{any::Any, sync::Arc};
+
+        use candle_core::{Device, Tensor};
+        use mistralrs_quant::IsqType;
+        use tokenizers::Tokenizer;
+        use tokio::sync::mpsc::{channel, Receiver};
+
+        use crate::{
+            device_map::DeviceMapper,
+            pipeline::{
+                chat_template::ChatTemplate, AnyMoePipelineMixin, CacheManagerMixin, EitherCache,
+                ForwardInputsResult, GeneralMetadata, IsqPipelineMixin, MetadataMixin,
+                ModelCategory, Pipeline, PreProcessingMixin,
+            },
+            prefix_cacher::PrefixCacheManagerV2,
+            sampler::{Logprobs, Sampler, SamplingRng},
+            sequence::{
+                SeqStepType, Sequence, SequenceGroup, SequenceRecognizer, SequenceState, StopReason,
+            },
+            Response,
+        };
+
+        /// A pipeline without a model. Draining never runs the model or touches the cache.
+        struct NoModel;
+
+        impl MetadataMixin for NoModel {
+            fn device(&self) -> Device {
+                Device::Cpu
+            }
+            fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+                None
+            }
+            fn name(&self) -> String {
+                "no-model".to_string()
+            }
+            fn reset_non_granular_state(&self) {}
+            fn get_metadata(&self) -> Arc<GeneralMetadata> {
+                unreachable!()
+            }
+            fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
+                None
+            }
+        }
+
+        impl PreProcessingMixin for NoModel {
+            fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+                None
+            }
+            fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+                None
+            }
+        }
+
+        impl IsqPipelineMixin for NoModel {
+            fn re_isq_model(&mut self, _dtype: IsqType) -> anyhow::Result<()> {
+                unreachable!()
+            }
+        }
+
+        impl CacheManagerMixin for NoModel {
+            fn clone_in_cache(&self, _seqs: &mut [&mut Sequence]) {
+                unreachable!()
+            }
+            fn clone_out_cache(&self, _seqs: &mut [&mut Sequence]) {
+                unreachable!()
+            }
+            fn set_none_cache(
+                &self,
+                _seqs: &mut [&mut Sequence],
+                _reset_non_granular: bool,
+                _modify_draft_cache: bool,
+                _load_preallocated_cache: bool,
+            ) {
+                unreachable!()
+            }
+            fn cache(&self) -> &EitherCache {
+                unreachable!()
+            }
+        }
+
+        impl AnyMoePipelineMixin for NoModel {}
+
+        #[async_trait::async_trait]
+        impl Pipeline for NoModel {
+            fn forward_inputs(
+                &mut self,
+                _inputs: Box<dyn Any>,
+                _return_raw_logits: bool,
+                _return_logprobs: Option<usize>,
+            ) -> candle_core::Result<ForwardInputsResult> {
+                unreachable!()
+            }
+            async fn sample_causal_gen(
+                &self,
+                _seqs: &mut [&mut Sequence],
+                _logits: Vec<Tensor>,
+                _prefix_cacher: &mut PrefixCacheManagerV2,
+                _disable_eos_stop: bool,
+                _rng: SamplingRng,
+            ) -> candle_core::Result<()> {
+                unreachable!()
+            }
+            fn category(&self) -> ModelCategory {
+                ModelCategory::Text
+            }
+        }
+
+        /// A completion sequence which has generated `pieces`, and the receiver of its responses.
+        fn generating_seq(
+            id: usize,
+            is_streaming: bool,
+            pieces: &[&str],
+        ) -> (Sequence, Receiver<Response>) {
+            let (tx, rx) = channel(4);
+            let sampler = Sampler::new(
+                None,
+                0,
+                None,
+                None,
+                None,
+                Default::default(),
+                None,
+                -1,
+                0.0,
+                0.0,
+                vec![],
+            )
+            .unwrap();
+            let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+                1,
+                is_streaming,
+                false,
+                None,
+            )));
+            let mut seq = Sequence::new_waiting(
+                vec![1, 2],
+                "prompt".to_string(),
+                id,
+                0,
+                1,
+                tx,
+                sampler,
+                vec![],
+                vec![],
+                None,
+                false,
+                false,
+                group,
+                0,
+                0,
+                SequenceRecognizer::None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                SeqStepType::PromptAndDecode,
+                None,
+                None,
+                false,
+                vec![],
+                None,
+                None,
+            );
+            seq.set_state(SequenceState::RunningCompletion);
+            for (tok, piece) in pieces.iter().enumerate() {
+                seq.add_token(
+                    Logprobs {
+                        token: tok as u32,
+                        logprob: 0.,
+                        bytes: None,
+                        top_logprobs: None,
+                        top_k_logprobs: None,
+                        entropy: None,
+                    },
+                    piece.as_bytes().to_vec(),
+                    &None,
+                );
+            }
+            (seq, rx)
+        }
+
+        #[test]
+        fn drain_finishes_seqs_with_partial_output() {
+            let (mut streaming, mut streaming_rx) = generating_seq(0, true, &[" Hello"]);
+            // The first token was already streamed.
+            assert_eq!(streaming.get_delta().unwrap().as_deref(), Some("Hello"));
+            for (tok, piece) in [",", " wor"].iter().enumerate() {
+                streaming.add_token(
+                    Logprobs {
+                        token: tok as u32,
+                        logprob: 0.,
+                        bytes: None,
+                        top_logprobs: None,
+                        top_k_logprobs: None,
+                        entropy: None,
+                    },
+                    piece.as_bytes().to_vec(),
+                    &None,
+                );
+            }
+            let (mut blocking, mut blocking_rx) = generating_seq(1, false, &[" Good", "bye"]);
+            let (mut finished, mut finished_rx) = generating_seq(2, false, &[" Done"]);
+            finished.set_state(SequenceState::Done(StopReason::Eos));
+
+            let pipeline: &dyn Pipeline = &NoModel;
+            let mut prefix_cacher = PrefixCacheManagerV2::new(0, true);
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(pipeline.drain(
+                    &mut [&mut streaming, &mut blocking, &mut finished],
+                    &mut prefix_cacher,
+                ));
+
+            // The streaming client gets what it has not seen yet in a final chunk.
+            let Ok(Response::CompletionChunk(chunk)) = streaming_rx.try_recv() else {
+                panic!("Expected a completion chunk.");
+            };
+            assert_eq!(chunk.choices[0].text, ", wor");
+            assert_eq!(chunk.choices[0].finish_reason.as_deref(), Some("shutdown"));
+            assert!(streaming_rx.try_recv().is_err());
+
+            // The other client gets the whole partial completion.
+            let Ok(Response::CompletionDone(done)) = blocking_rx.try_recv() else {
+                panic!("Expected a completion response.");
+            };
+            assert_eq!(done.choices[0].text, "Goodbye");
+            assert_eq!(done.choices[0].finish_reason, "shutdown");
+
+            // A sequence which already finished was answered before and is left alone.
+            assert!(finished_rx.try_recv().is_err());
+            for seq in [&streaming, &blocking] {
+                assert!(matches!(
+                    seq.getstate(),
+                    SequenceState::Done(StopReason::Shutdown)
+                ));
+            }
+        }
+    }
 }
@@ -1,34 +1,54 @@
 use super::cache_manager::{FullCacheManager, NormalCacheManager};
-use super::inputs_processor::DEFAULT_PROMPT_CHUNK_SIZE;
+use super::input_gradients;
+use super::inputs_processor::{auto_prompt_chunksize, PromptChunksize, DEFAULT_PROMPT_CHUNK_SIZE};
 use super::isq::ImatrixDataSource;
 use super::llg::build_tok_env;
+use super::loaders::{
+    dtype_from_torch_name, override_activation, override_max_cached_rope_positions,
+    override_num_experts_per_tok,
+};
+use super::loglikelihood;
+use super::pair_paged_attn_meta;
+use super::value_head::ValueHead;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
-    TokenSource,
+    AutoDeviceMapParams, CacheManager, ConfigValidationError, GeneralMetadata, Loader, ModelKind,
+    ModelPaths, NormalLoadingMetadata, NormalModel, NormalModelLoader, NormalizedConfig,
+    ResourceEstimate, SharedEmbeddings, TokenSource, WeightSource,
 };
 use super::{
-    AnyMoePipelineMixin, CacheManagerMixin, EitherCache, ForwardInputsResult, IsqOrganization,
-    IsqPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
+    AnyMoePipelineMixin, CacheManagerMixin, EitherCache, ForwardInputsResult, GradientTarget,
+    IsqOrganization, IsqPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
 };
 use super::{
-    AutoLoader, DeepSeekV2Loader, DeepSeekV3Loader, Gemma2Loader, GemmaLoader, LlamaLoader,
-    MistralLoader, MixtralLoader, NormalLoaderType, Phi2Loader, Phi3Loader, Phi3_5MoELoader,
-    Qwen2Loader, Starcoder2Loader,
+    AutoLoader, CohereLoader, DeepSeekV2Loader, DeepSeekV3Loader, Gemma2Loader, GemmaLoader,
+    LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType, Phi2Loader, Phi3Loader,
+    Phi3SmallLoader, Phi3_5MoELoader, Qwen2Loader, Starcoder2Loader,
 };
 use crate::amoe::AnyMoeExpertType;
+use crate::attention::SplitKvAccumulation;
 use crate::device_map::{self, DeviceMapper};
 use crate::distributed::{self, WorkerTransferData};
+use crate::expert_counts::ExpertCounter;
+use crate::layers::{
+    with_f32_norm_weights, with_lazy_rope_tables, with_shared_rope_tables, Activation,
+};
 use crate::lora::Ordering;
 use crate::paged_attention::{calculate_cache_config, AttentionImplementation, CacheEngine};
-use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
-use crate::pipeline::get_chat_template;
+use crate::pipeline::chat_template::{
+    apply_special_token_overrides, calculate_eos_tokens, validate_chat_template, ChatTemplateValue,
+    GenerationConfig,
+};
+use crate::pipeline::get_chat_template_with_string;
 use crate::pipeline::isq::UqffFullSer;
 use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::text_models_inputs_processor::make_prompt_chunk;
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManagerV2;
+use crate::qkv_capture::QkvCapture;
+use crate::sampler::SamplingRng;
 use crate::sequence::Sequence;
+use crate::utils::debug::DeviceRepr;
 use crate::utils::tokenizer::get_tokenizer;
 use crate::utils::varbuilder_utils::DeviceForLoadTensor;
 use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
@@ -36,19 +56,20 @@ use crate::xlora_models::NonGranularState;
 use crate::{
     api_dir_list, api_get_file, get_mut_arcmutex, get_paths, get_uqff_paths, lora_model_loader,
     normal_model_loader, normal_model_loader_sharded, xlora_model_loader, DeviceMapSetting,
-    PagedAttentionConfig, Pipeline, Topology, TryIntoDType, GLOBAL_HF_CACHE,
+    MemoryUsage, PagedAttentionConfig, Pipeline, Topology, TryIntoDType, GLOBAL_HF_CACHE,
 };
 use anyhow::Result;
-use candle_core::{Device, Tensor, Var};
+use candle_core::{DType, Device, IndexOp, Tensor, Var};
+use either::Either;
 use hf_hub::Cache;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use indicatif::MultiProgress;
-use mistralrs_quant::{AfqLayer, GgufMatMul, HqqLayer, IsqType, QuantizedSerdeType};
-use rand_isaac::Isaac64Rng;
+use mistralrs_quant::{AfqLayer, GgufMatMul, HqqLayer, IsqType, QuantInfo, QuantizedSerdeType};
 use regex_automata::meta::Regex;
 use std::any::Any;
 use std::borrow::Cow;
-use std::num::{NonZero, NonZeroUsize};
+use std::collections::HashMap;
+use std::num::NonZero;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
@@ -67,6 +88,7 @@ pub struct NormalPipeline {
     model_id: String,
     metadata: Arc<GeneralMetadata>,
     topology: Option<Topology>,
+    isq_overrides: Vec<(regex::Regex, IsqType)>,
     silent: bool,
     organization: IsqOrganization,
     // For full UQFF serialization
@@ -75,6 +97,22 @@ pub struct NormalPipeline {
     config: String,
     imatrix: Option<PathBuf>,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
+    quant_manifest: Vec<(String, QuantInfo)>,
+    value_head: Option<ValueHead>,
+    prefill_dtype: Option<DType>,
+    split_kv_segment_len: Option<usize>,
+    split_kv_accumulation: SplitKvAccumulation,
+}
+
+/// The checkpoint a [`NormalLoader`] builds its pipeline from.
+enum NormalModelSource<'a> {
+    /// Files resolved from the Hugging Face Hub or a local directory.
+    Paths(&'a Box<dyn ModelPaths>),
+    /// A tokenizer and weights passed to [`Loader::load_model_from_parts`].
+    Parts {
+        tokenizer: &'a [u8],
+        weights: WeightSource,
+    },
 }
 
 /// A loader for a "normal" (non-quantized) model.
@@ -93,8 +131,14 @@ pub struct NormalLoader {
     token_source: RwLock<Option<TokenSource>>,
     revision: RwLock<Option<String>>,
     from_uqff: RwLock<Option<Vec<PathBuf>>>,
+    shared_embeddings: RwLock<Option<SharedEmbeddings>>,
     jinja_explicit: Option<String>,
     hf_cache_path: Option<PathBuf>,
+    num_experts_per_tok: Option<usize>,
+    bos_tok_override: Option<u32>,
+    eos_toks_override: Option<Vec<u32>>,
+    value_head: Option<PathBuf>,
+    chat_template_string: Option<String>,
 }
 
 #[derive(Default)]
@@ -112,20 +156,87 @@ pub struct NormalLoaderBuilder {
     tgt_non_granular_index: Option<usize>,
     jinja_explicit: Option<String>,
     hf_cache_path: Option<PathBuf>,
+    num_experts_per_tok: Option<usize>,
+    bos_tok_override: Option<u32>,
+    eos_toks_override: Option<Vec<u32>>,
+    value_head: Option<PathBuf>,
+    chat_template_string: Option<String>,
 }
 
 #[derive(Clone, Default)]
 /// Config specific to loading a normal model.
 pub struct NormalSpecificConfig {
     pub use_flash_attn: bool,
-    pub prompt_chunksize: Option<NonZeroUsize>,
+    /// Defaults to a fixed size of [`DEFAULT_PROMPT_CHUNK_SIZE`] tokens.
+    pub prompt_chunksize: Option<PromptChunksize>,
     pub topology: Option<Topology>,
     pub organization: IsqOrganization,
     pub write_uqff: Option<PathBuf>,
+    /// Maximum size in MB of each UQFF shard written to `write_uqff`. Defaults to 10 GB.
+    pub uqff_shard_size_mb: Option<usize>,
     pub from_uqff: Option<Vec<PathBuf>>,
     pub imatrix: Option<PathBuf>,
     pub calibration_file: Option<PathBuf>,
     pub hf_cache_path: Option<PathBuf>,
+    /// Replace the MLP activation of every layer, ignoring the one in the model config. This is a
+    /// porting aid for models whose config names the wrong activation.
+    pub activation_override: Option<Activation>,
+    /// Run attention in this dtype during prefill, then switch back to the model dtype for decode.
+    /// Setting `DType::F32` trades prefill speed for a more accurate first token on long prompts.
+    pub prefill_dtype: Option<DType>,
+    /// Process the KV in segments of this many tokens in the eager attention path, combining them with
+    /// an online softmax. This bounds the attention scores held at once to one segment, for long
+    /// contexts without flash attention.
+    pub split_kv_segment_len: Option<usize>,
+    /// How split-KV attention accumulates across the segments. Defaults to f32, with cheaper
+    /// accumulation in the model dtype available through `Native` and `Kahan`.
+    pub split_kv_accumulation: SplitKvAccumulation,
+    /// Look up the input embeddings in this dtype, casting them to the model dtype for the decoder layers.
+    /// Only Llama models support this, and it is only exposed through the Rust API.
+    pub embedding_dtype: Option<DType>,
+    /// Run the LM head in this dtype, casting the final hidden states to it. The LM head must not be
+    /// quantized. Only Llama models support this, and it is only exposed through the Rust API.
+    pub lm_head_dtype: Option<DType>,
+    /// Load the RmsNorm weights in f32 even when the model is f16/bf16. The norms then run in f32,
+    /// casting their output back to the input dtype.
+    pub f32_norm_weights: bool,
+    /// ISQ types for the layers whose name matches a regex, the first match winning. Layers are named
+    /// as in the quantization manifest: by their GGUF tensor name if the model has one
+    /// (`blk.0.attn_q.weight`), otherwise by their index. An override takes precedence over the `isq`
+    /// of the layer's topology, which in turn takes precedence over the global ISQ type. With the MoQE
+    /// organization layers are always named by index. Loading fails if a regex matches no layer.
+    /// Ignored when loading from UQFF.
+    pub isq_overrides: Vec<(regex::Regex, IsqType)>,
+    /// Attention heads to remove, as query head indices keyed by layer index. The QKV and output
+    /// projections are sliced to the remaining heads before ISQ. Query heads sharing a key/value
+    /// head must be pruned together. Not supported with UQFF, and disables PagedAttention.
+    pub pruned_heads: HashMap<usize, Vec<usize>>,
+    /// Scales for the output of each attention head keyed by layer index, for example to silence
+    /// or amplify attention sink heads. Each vector has one entry per query head of the layer,
+    /// after any pruning.
+    pub attention_head_scales: HashMap<usize, Vec<f32>>,
+    /// Zero-pad the attention head dim up to a multiple of this many elements, purely as a
+    /// performance measure for kernels which prefer aligned sizes (e.g. 80 is padded to 128 with
+    /// 64). This is done once at load time, before ISQ, and does not change the outputs. Not
+    /// supported with UQFF.
+    pub head_dim_alignment: Option<usize>,
+    /// Only precompute the RoPE sin/cos tables for this many positions instead of the full context
+    /// length, extending them when a longer sequence is seen. This saves device memory for long
+    /// context models used with short sequences. Only supported for Phi 3 and Phi 3.5 MoE models.
+    pub max_cached_rope_positions: Option<usize>,
+    /// Share the rotary tables with other models loaded with this set and an identical RoPE config,
+    /// such as the members of an ensemble, rather than building them per model. Shared tables are
+    /// kept for the lifetime of the process.
+    pub share_rope_tables: bool,
+    /// Build the rotary tables on the first forward, only up to the longest position seen so far,
+    /// rather than for the full context length at load.
+    pub lazy_rope_tables: bool,
+    /// Hold the residual stream on the CPU while each decoder layer's attention and MLP run, trading
+    /// host transfers for lower peak device memory during long prefill. The outputs are unchanged.
+    pub offload_activations: bool,
+    /// Download a safetensors shard from the Hugging Face Hub again, up to this many times, if the
+    /// cached copy is corrupt or truncated.
+    pub shard_download_retries: usize,
 }
 
 impl NormalLoaderBuilder {
@@ -203,9 +314,40 @@ impl NormalLoaderBuilder {
         self
     }
 
+    /// Override the number of experts selected per token by the MoE router. Defaults to the config value.
+    pub fn with_num_experts_per_tok(mut self, num_experts_per_tok: usize) -> Self {
+        self.num_experts_per_tok = Some(num_experts_per_tok);
+        self
+    }
+
+    /// Override the BOS token id and the set of EOS token ids used to stop generation, instead of
+    /// those resolved from the model's tokenizer and generation config.
+    pub fn with_special_tokens(mut self, bos: Option<u32>, eos: Option<Vec<u32>>) -> Self {
+        self.bos_tok_override = bos;
+        self.eos_toks_override = eos;
+        self
+    }
+
+    /// Load a linear value head from this safetensors file, used by [`Pipeline::score_reward`].
+    pub fn with_value_head(mut self, path: PathBuf) -> Self {
+        self.value_head = Some(path);
+        self
+    }
+
+    /// Use this Jinja chat template instead of one resolved from a file. It takes precedence over
+    /// `chat_template` and `jinja_explicit`, and must compile when the loader is built.
+    pub fn with_chat_template_string(mut self, template: String) -> Self {
+        self.chat_template_string = Some(template);
+        self
+    }
+
     /// If the loader type is not specified, loader type is automatically determined from the
     /// `architectures` array in the config.
     pub fn build(self, loader_tp: Option<NormalLoaderType>) -> anyhow::Result<Box<dyn Loader>> {
+        if let Some(template) = &self.chat_template_string {
+            validate_chat_template(template)
+                .map_err(|e| anyhow::anyhow!("Chat template string does not compile: {e}"))?;
+        }
         let loader: Box<dyn NormalModelLoader> = match loader_tp {
             Some(NormalLoaderType::Mistral) => Box::new(MistralLoader),
             Some(NormalLoaderType::Gemma) => Box::new(GemmaLoader),
@@ -219,6 +361,8 @@ impl NormalLoaderBuilder {
             Some(NormalLoaderType::Phi3_5MoE) => Box::new(Phi3_5MoELoader),
             Some(NormalLoaderType::DeepSeekV2) => Box::new(DeepSeekV2Loader),
             Some(NormalLoaderType::DeepSeekV3) => Box::new(DeepSeekV3Loader),
+            Some(NormalLoaderType::CohereCommandR) => Box::new(CohereLoader),
+            Some(NormalLoaderType::Phi3Small) => Box::new(Phi3SmallLoader),
             None => Box::new(AutoLoader),
         };
         Ok(Box::new(NormalLoader {
@@ -237,64 +381,293 @@ impl NormalLoaderBuilder {
             token_source: RwLock::new(None),
             revision: RwLock::new(None),
             from_uqff: RwLock::new(None),
+            shared_embeddings: RwLock::new(None),
             hf_cache_path: self.hf_cache_path,
+            num_experts_per_tok: self.num_experts_per_tok,
+            bos_tok_override: self.bos_tok_override,
+            eos_toks_override: self.eos_toks_override,
+            value_head: self.value_head,
+            chat_template_string: self.chat_template_string,
         }))
     }
 }
 
-impl Loader for NormalLoader {
-    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
-    fn load_model_from_hf(
+impl NormalLoader {
+    /// Apply any config overrides requested on the builder.
+    fn apply_config_overrides(&self, config: String) -> Result<String> {
+        let config = match self.num_experts_per_tok {
+            Some(num_experts_per_tok) => {
+                info!("Overriding number of experts per token to {num_experts_per_tok}.");
+                override_num_experts_per_tok(&config, num_experts_per_tok)?
+            }
+            None => config,
+        };
+        let config = match self.config.activation_override {
+            Some(activation) => {
+                info!("Overriding the MLP activation to {activation:?}.");
+                override_activation(&config, activation)?
+            }
+            None => config,
+        };
+        match self.config.max_cached_rope_positions {
+            Some(positions) => {
+                info!("Precomputing RoPE tables for the first {positions} positions.");
+                override_max_cached_rope_positions(&config, positions)
+            }
+            None => Ok(config),
+        }
+    }
+
+    /// Move the embedding and LM head to their configured dtypes. This runs after ISQ, so the LM head
+    /// can only be given a dtype if it was left unquantized.
+    fn apply_boundary_dtypes(&self, model: &mut (dyn NormalModel + Send + Sync)) -> Result<()> {
+        let (embedding, lm_head) = (self.config.embedding_dtype, self.config.lm_head_dtype);
+        if embedding.is_none() && lm_head.is_none() {
+            return Ok(());
+        }
+        if let Some(dtype) = embedding {
+            info!("Running the embedding lookup in {dtype:?}.");
+        }
+        if let Some(dtype) = lm_head {
+            info!("Running the LM head in {dtype:?}.");
+        }
+        Ok(model.set_boundary_dtypes(embedding, lm_head)?)
+    }
+
+    /// Check that head pruning can be applied, turning off PagedAttention: its KV cache assumes the
+    /// same number of heads in every layer.
+    fn check_head_pruning(
         &self,
-        revision: Option<String>,
-        token_source: TokenSource,
+        paged_attn_config: &mut Option<PagedAttentionConfig>,
+    ) -> Result<()> {
+        if self.config.pruned_heads.is_empty() {
+            return Ok(());
+        }
+        if self.config.from_uqff.is_some() || self.config.write_uqff.is_some() {
+            anyhow::bail!("Pruning attention heads is not supported with UQFF.");
+        }
+        if paged_attn_config.take().is_some() {
+            warn!(
+                "Pruned attention heads do not currently support PagedAttention, running without"
+            );
+        }
+        Ok(())
+    }
+
+    /// Remove the configured attention heads. This runs before ISQ, while the attention projections
+    /// are unquantized.
+    fn apply_head_pruning(&self, model: &mut (dyn NormalModel + Send + Sync)) -> Result<()> {
+        let pruned_heads = &self.config.pruned_heads;
+        if pruned_heads.is_empty() {
+            return Ok(());
+        }
+        let n_heads = pruned_heads.values().map(Vec::len).sum::<usize>();
+        info!(
+            "Pruning {n_heads} attention heads in {} layers.",
+            pruned_heads.len()
+        );
+        Ok(model.prune_attention_heads(pruned_heads)?)
+    }
+
+    /// Apply the configured attention head scales, after any head pruning.
+    fn apply_attention_head_scales(
+        &self,
+        model: &mut (dyn NormalModel + Send + Sync),
+    ) -> Result<()> {
+        let head_scales = &self.config.attention_head_scales;
+        if head_scales.is_empty() {
+            return Ok(());
+        }
+        info!("Scaling attention heads in {} layers.", head_scales.len());
+        Ok(model.set_attention_head_scales(head_scales)?)
+    }
+
+    /// Pad the attention head dim to the configured alignment. Like head pruning, this runs before
+    /// ISQ while the attention projections are unquantized.
+    fn apply_head_dim_padding(&self, model: &mut (dyn NormalModel + Send + Sync)) -> Result<()> {
+        let Some(alignment) = self.config.head_dim_alignment else {
+            return Ok(());
+        };
+        if alignment == 0 {
+            anyhow::bail!("The head dim alignment must be positive.");
+        }
+        if self.config.from_uqff.is_some() || self.config.write_uqff.is_some() {
+            anyhow::bail!("Padding the head dim is not supported with UQFF.");
+        }
+        info!("Padding the attention head dim to a multiple of {alignment}.");
+        Ok(model.pad_head_dim(alignment)?)
+    }
+
+    /// Resolve the prompt chunk size. For [`PromptChunksize::Auto`], this is chosen so the activations of
+    /// a chunk fit in half of the memory left after the weights on every device the model is mapped to,
+    /// leaving the rest for the KV cache. If the available memory cannot be queried, the default chunk
+    /// size is used.
+    #[allow(clippy::too_many_arguments)]
+    fn prompt_chunksize(
+        &self,
+        config: &str,
         dtype: &dyn TryIntoDType,
         device: &Device,
-        silent: bool,
-        mapper: DeviceMapSetting,
+        mapper: &DeviceMapSetting,
+        available_devices: &[Device],
         in_situ_quant: Option<IsqType>,
-        paged_attn_config: Option<PagedAttentionConfig>,
-    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
-        let cache = self
-            .hf_cache_path
-            .clone()
-            .map(Cache::new)
-            .unwrap_or_default();
-        GLOBAL_HF_CACHE.get_or_init(|| cache);
+    ) -> Result<usize> {
+        let chunksize = match self.config.prompt_chunksize {
+            None => DEFAULT_PROMPT_CHUNK_SIZE,
+            Some(PromptChunksize::Fixed(chunksize)) => chunksize.get(),
+            Some(PromptChunksize::Auto) => {
+                let dtype = dtype.try_into_dtype(&available_devices.iter().collect::<Vec<_>>())?;
+                match self.auto_prompt_chunksize_budget(
+                    config,
+                    dtype,
+                    device,
+                    mapper,
+                    available_devices,
+                    in_situ_quant,
+                )? {
+                    Some(budget_in_bytes) => {
+                        let params = match mapper {
+                            DeviceMapSetting::Auto(params) => params.clone(),
+                            _ => AutoDeviceMapParams::default_text(),
+                        };
+                        let chunksize = auto_prompt_chunksize(
+                            budget_in_bytes,
+                            params.max_seq_len(),
+                            |chunksize| {
+                                Ok(self
+                                    .inner
+                                    .mapped_max_act_size_elems(config, &params, chunksize)?
+                                    * dtype.size_in_bytes())
+                            },
+                        )?;
+                        info!(
+                            "Automatically chose a prompt chunk size of {chunksize} for {}MB of activation memory per device.",
+                            budget_in_bytes / (1024 * 1024)
+                        );
+                        chunksize
+                    }
+                    None => DEFAULT_PROMPT_CHUNK_SIZE,
+                }
+            }
+        };
+        info!("Prompt chunk size is {chunksize}.",);
+        Ok(chunksize)
+    }
 
-        let paths: anyhow::Result<Box<dyn ModelPaths>> = get_paths!(
-            LocalModelPaths,
-            &token_source,
-            revision.clone(),
-            self,
-            None,
-            None,
-            silent,
-            self.config.from_uqff.is_some()
-        );
-        if let Some(from_uqff) = self.config.from_uqff.clone() {
-            *self.from_uqff.write().unwrap() = Some(get_uqff_paths!(&from_uqff, self, silent));
+    /// The activation memory of a prompt chunk on each device: half of the memory the tightest device has
+    /// left after its share of the weights. With automatic device mapping the weights are not placed yet,
+    /// so the memory left after all of them is split evenly between the available devices. Returns
+    /// `None` if the available memory of a device cannot be queried.
+    fn auto_prompt_chunksize_budget(
+        &self,
+        config: &str,
+        dtype: DType,
+        device: &Device,
+        mapper: &DeviceMapSetting,
+        available_devices: &[Device],
+        in_situ_quant: Option<IsqType>,
+    ) -> Result<Option<usize>> {
+        let weight_pack_factor = in_situ_quant.map_or(1, |isq| isq.pack_factor(dtype));
+        let layer_sizes_in_bytes =
+            self.inner
+                .layer_sizes_in_bytes(config, dtype, weight_pack_factor)?;
+        let non_mapped_size_in_bytes =
+            self.inner
+                .non_mapped_size_in_bytes(config, dtype, weight_pack_factor)?;
+
+        // The devices holding the weights, and how much of the weights each holds.
+        let weights_in_bytes = if let DeviceMapSetting::Auto(_) = mapper {
+            let total = layer_sizes_in_bytes.iter().sum::<usize>() + non_mapped_size_in_bytes;
+            available_devices
+                .iter()
+                .map(|dev| (dev.clone(), total / available_devices.len()))
+                .collect::<Vec<_>>()
+        } else {
+            let layer_mapper = mapper.into_mapper(
+                layer_sizes_in_bytes.len(),
+                device,
+                self.config.topology.as_ref(),
+            )?;
+            let mut weights_in_bytes = vec![(device.clone(), non_mapped_size_in_bytes)];
+            for (layer, size) in layer_sizes_in_bytes.iter().enumerate() {
+                let dev = layer_mapper.device_for(layer, false).unwrap_or(device);
+                match weights_in_bytes
+                    .iter_mut()
+                    .find(|(d, _)| d.location() == dev.location())
+                {
+                    Some((_, weights)) => *weights += size,
+                    None => weights_in_bytes.push((dev.clone(), *size)),
+                }
+            }
+            weights_in_bytes
+        };
+
+        let mut budget_in_bytes = usize::MAX;
+        for (dev, weights) in weights_in_bytes {
+            let available = match MemoryUsage.get_memory_available(&dev) {
+                Ok(available) => available,
+                Err(e) => {
+                    warn!(
+                        "Could not query the memory of {}, using the default prompt chunk size of {DEFAULT_PROMPT_CHUNK_SIZE}: {e}",
+                        dev.device_pretty_repr()
+                    );
+                    return Ok(None);
+                }
+            };
+            budget_in_bytes = budget_in_bytes.min(available.saturating_sub(weights) / 2);
         }
-        *self
-            .token_source
-            .write()
-            .expect("Failed to write to token source") = Some(token_source);
-        *self.revision.write().expect("Failed to write to revision") = revision;
-        self.load_model_from_path(
-            &paths?,
-            dtype,
-            device,
-            silent,
-            mapper,
-            in_situ_quant,
-            paged_attn_config,
-        )
+        Ok(Some(budget_in_bytes))
     }
 
-    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
-    fn load_model_from_path(
+    fn load_value_head(&self, model: &dyn NormalModel) -> Result<Option<ValueHead>> {
+        let Some(path) = &self.value_head else {
+            return Ok(None);
+        };
+        info!("Loading value head from `{}`.", path.display());
+        Ok(Some(ValueHead::load(
+            path,
+            model.config().hidden_size,
+            model.device(),
+        )?))
+    }
+
+    /// Build the chat template when there are no model paths to read it from. The same
+    /// precedence as [`get_chat_template_with_string`] applies: a `.json` `chat_template` file
+    /// supplies the template and special tokens, `jinja_explicit` overrides the template and
+    /// `chat_template_string` overrides both.
+    fn chat_template_from_options(&self) -> Result<ChatTemplate> {
+        let mut template = match &self.chat_template {
+            Some(file) if file.ends_with(".json") => {
+                serde_json::from_str(&fs::read_to_string(file)?)?
+            }
+            Some(file) => {
+                anyhow::bail!("Chat template file `{file}` must end with `.json`.")
+            }
+            None => ChatTemplate::default(),
+        };
+        if let Some(jinja_explicit) = &self.jinja_explicit {
+            if !jinja_explicit.ends_with(".jinja") {
+                anyhow::bail!("jinja_explicit must end with .jinja!");
+            }
+            template.chat_template = Some(ChatTemplateValue(Either::Left(fs::read_to_string(
+                jinja_explicit,
+            )?)));
+        }
+        if let Some(chat_template_string) = &self.chat_template_string {
+            template.chat_template = Some(ChatTemplateValue(Either::Left(
+                chat_template_string.clone(),
+            )));
+        }
+        Ok(template)
+    }
+
+    /// Build the pipeline from a config and a checkpoint. This is shared by
+    /// [`Loader::load_model_from_path`] and [`Loader::load_model_from_parts`].
+    #[allow(clippy::too_many_arguments)]
+    fn load_pipeline(
         &self,
-        paths: &Box<dyn ModelPaths>,
+        config: String,
+        source: NormalModelSource<'_>,
         dtype: &dyn TryIntoDType,
         device: &Device,
         silent: bool,
@@ -302,24 +675,48 @@ impl Loader for NormalLoader {
         in_situ_quant: Option<IsqType>,
         mut paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
-        let config = std::fs::read_to_string(paths.get_config_filename())?;
+        let config = self.apply_config_overrides(config)?;
+        let paths = match &source {
+            NormalModelSource::Paths(paths) => Some(*paths),
+            NormalModelSource::Parts { .. } => None,
+        };
 
         if !self.inner.supports_paged_attention(&config)? {
             paged_attn_config = None;
         }
+        self.check_head_pruning(&mut paged_attn_config)?;
+
+        // Report config and checkpoint mismatches before they surface deep in the forward pass.
+        let expected_shapes = self.inner.expected_weight_shapes(&config)?;
+        if !expected_shapes.is_empty() {
+            match &source {
+                NormalModelSource::Paths(paths) => {
+                    let safetensors = paths
+                        .get_weight_filenames()
+                        .iter()
+                        .filter(|path| path.extension().is_some_and(|ext| ext == "safetensors"))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    if !safetensors.is_empty() {
+                        WeightSource::Safetensors(safetensors).validate_shapes(&expected_shapes)?;
+                    }
+                }
+                NormalModelSource::Parts { weights, .. } => {
+                    weights.validate_shapes(&expected_shapes)?
+                }
+            }
+        }
 
-        // Apply default prompt size here
-        let prompt_chunksize = self
-            .config
-            .prompt_chunksize
-            .unwrap_or(DEFAULT_PROMPT_CHUNK_SIZE.try_into().unwrap())
-            .get();
-
-        info!("Prompt chunk size is {prompt_chunksize}.",);
+        if let Some(prefill_dtype) = self.config.prefill_dtype {
+            info!("Running prefill attention in {prefill_dtype:?}.");
+        }
 
-        let use_nccl = mistralrs_quant::distributed::use_nccl();
+        // In-memory parts are loaded onto the given device only.
+        let use_nccl = paths.is_some() && mistralrs_quant::distributed::use_nccl();
 
-        let available_devices = if let Ok(payload) = env::var(distributed::IS_DAEMON_FLAG) {
+        let available_devices = if paths.is_none() {
+            vec![device.clone()]
+        } else if let Ok(payload) = env::var(distributed::IS_DAEMON_FLAG) {
             let payload: WorkerTransferData = serde_json::from_str(&payload)?;
             let WorkerTransferData::Init { id: _, worker_rank } = payload;
             vec![candle_core::Device::new_cuda(worker_rank + 1)?]
@@ -334,6 +731,15 @@ impl Loader for NormalLoader {
             device.clone()
         };
 
+        let prompt_chunksize = self.prompt_chunksize(
+            &config,
+            dtype,
+            &device,
+            &mapper,
+            &available_devices,
+            in_situ_quant,
+        )?;
+
         // If auto, convert to Map if not using nccl
         if use_nccl {
             mapper = DeviceMapSetting::DummyNccl {
@@ -434,18 +840,13 @@ impl Loader for NormalLoader {
             mapper = DeviceMapSetting::Map(new);
         }
 
-        let pipeline_mapper = mapper.into_mapper(
-            self.inner.num_layers(&config)?,
-            &device,
-            self.config.topology.as_ref(),
-        )?;
-        let mapper = mapper.into_mapper(
-            self.inner.num_layers(&config)?,
-            &device,
-            self.config.topology.as_ref(),
-        )?;
+        // The model and the pipeline each own a mapper for the resolved device map.
+        let num_layers = self.inner.num_layers(&config)?;
+        let into_mapper = || mapper.into_mapper(num_layers, &device, self.config.topology.as_ref());
+        let pipeline_mapper = into_mapper()?;
+        let mapper = into_mapper()?;
         let mut layer_devices = Vec::new();
-        for layer in 0..self.inner.num_layers(&config)? {
+        for layer in 0..num_layers {
             let device = mapper.device_for(layer, false).cloned();
             layer_devices.push(device);
         }
@@ -465,7 +866,9 @@ impl Loader for NormalLoader {
                 .get_config_repr(&config, self.config.use_flash_attn)?
         );
 
-        let mut loading_isq = in_situ_quant.is_some() || self.config.from_uqff.is_some();
+        let mut loading_isq = in_situ_quant.is_some()
+            || !self.config.isq_overrides.is_empty()
+            || self.config.from_uqff.is_some();
         if let Some(ref topology) = self.config.topology {
             loading_isq |= topology
                 .0
@@ -497,145 +900,203 @@ impl Loader for NormalLoader {
 
         let multi_progress = Arc::new(MultiProgress::new());
 
-        let mut model = if use_nccl {
-            let (mapper, sharded_vb) = distributed::prepare_distributed_mapper(
-                dtype,
-                &device,
-                &available_devices,
-                silent,
-                &config,
-                loading_isq,
-                self.config.from_uqff.is_some(),
-                self.config.organization,
-                &*self.inner,
-                paths.as_ref(),
-            )?;
+        let shared_embeddings = self.shared_embeddings.read().unwrap().clone();
 
-            // Special case for where things can be more optimially loaded.
-            match self.kind {
-                ModelKind::Normal => normal_model_loader_sharded!(
-                    sharded_vb,
-                    config,
-                    self.inner,
-                    self.config.use_flash_attn,
-                    mapper,
-                    loading_isq,
-                    device.clone(),
-                    attention_mechanism,
-                    multi_progress.clone(),
-                ),
-                ModelKind::Adapter {
-                    adapter: AdapterKind::XLora,
-                } => xlora_model_loader!(
-                    paths,
-                    Some(dtype),
-                    &load_device,
-                    layer_devices.clone(),
-                    config,
-                    self.inner,
-                    self.config.use_flash_attn,
-                    silent,
-                    mapper,
-                    loading_isq,
-                    device.clone(),
-                    multi_progress.clone(),
-                ),
-                ModelKind::Adapter {
-                    adapter: AdapterKind::Lora,
-                } => lora_model_loader!(
-                    paths,
-                    Some(dtype),
-                    &load_device,
-                    layer_devices.clone(),
-                    config,
-                    self.inner,
-                    self.config.use_flash_attn,
-                    silent,
-                    mapper,
-                    loading_isq,
-                    self.config.from_uqff.is_some(),
-                    device.clone(),
-                    attention_mechanism,
-                    matches!(self.config.organization, IsqOrganization::MoeExpertsOnly),
-                    multi_progress.clone(),
-                ),
-                _ => unreachable!(),
-            }
-        } else {
-            match self.kind {
-                ModelKind::Normal => normal_model_loader!(
+        let (tokenizer, gen_conf, mut chat_template) = match &source {
+            NormalModelSource::Paths(paths) => {
+                let tokenizer = get_tokenizer(paths.get_tokenizer_filename(), None)?;
+                let gen_conf: Option<GenerationConfig> = paths.get_gen_conf_filename().map(|f| {
+                    serde_json::from_str(&fs::read_to_string(f).unwrap())
+                        .expect("bos_token_id/eos_token_id missing in generation_config.json")
+                });
+                let chat_template = get_chat_template_with_string(
                     paths,
-                    Some(dtype),
-                    &load_device,
-                    layer_devices.clone(),
-                    config,
-                    self.inner,
-                    self.config.use_flash_attn,
-                    silent,
-                    mapper,
-                    loading_isq,
-                    self.config.from_uqff.is_some(),
-                    device.clone(),
-                    attention_mechanism,
-                    matches!(self.config.organization, IsqOrganization::MoeExpertsOnly),
-                    multi_progress.clone(),
-                ),
-                ModelKind::Adapter {
-                    adapter: AdapterKind::XLora,
-                } => xlora_model_loader!(
-                    paths,
-                    Some(dtype),
-                    &load_device,
-                    layer_devices.clone(),
-                    config,
-                    self.inner,
-                    self.config.use_flash_attn,
-                    silent,
-                    mapper,
-                    loading_isq,
-                    device.clone(),
-                    multi_progress.clone(),
-                ),
-                ModelKind::Adapter {
-                    adapter: AdapterKind::Lora,
-                } => lora_model_loader!(
-                    paths,
-                    Some(dtype),
-                    &load_device,
-                    layer_devices.clone(),
-                    config,
-                    self.inner,
-                    self.config.use_flash_attn,
-                    silent,
-                    mapper,
-                    loading_isq,
-                    self.config.from_uqff.is_some(),
-                    device.clone(),
-                    attention_mechanism,
-                    matches!(self.config.organization, IsqOrganization::MoeExpertsOnly),
-                    multi_progress.clone(),
-                ),
-                _ => unreachable!(),
+                    &self.jinja_explicit,
+                    &paths
+                        .get_chat_template_explicit()
+                        .as_ref()
+                        .map(|x| x.to_string_lossy().to_string())
+                        .clone(),
+                    &self.chat_template,
+                    &self.chat_template_string,
+                );
+                (tokenizer, gen_conf, chat_template)
             }
+            // There is no `tokenizer_config.json` to read from, so the chat template only comes
+            // from the loader's chat template options. Without any, only prompts are accepted.
+            NormalModelSource::Parts { tokenizer, .. } => (
+                Tokenizer::from_bytes(*tokenizer).map_err(anyhow::Error::msg)?,
+                None,
+                self.chat_template_from_options()?,
+            ),
         };
-
-        let tokenizer = get_tokenizer(paths.get_tokenizer_filename(), None)?;
-        let gen_conf: Option<GenerationConfig> = paths.get_gen_conf_filename().map(|f| {
-            serde_json::from_str(&fs::read_to_string(f).unwrap())
-                .expect("bos_token_id/eos_token_id missing in generation_config.json")
-        });
-
-        let chat_template = get_chat_template(
-            paths,
-            &self.jinja_explicit,
-            &paths
-                .get_chat_template_explicit()
-                .as_ref()
-                .map(|x| x.to_string_lossy().to_string())
-                .clone(),
-            &self.chat_template,
-            None,
-        );
+        let template_filename = paths.and_then(|paths| paths.get_template_filename().clone());
+        let gen_conf_filename = paths.and_then(|paths| paths.get_gen_conf_filename().cloned());
+
+        let load_model = || -> Result<_> {
+            Ok(match source {
+                NormalModelSource::Parts { weights, .. } => {
+                    let vb = weights.into_var_builder(dtype, &load_device, silent)?;
+                    let normal_loading_metadata = NormalLoadingMetadata {
+                        mapper,
+                        loading_isq,
+                        real_device: device.clone(),
+                        multi_progress: multi_progress.clone(),
+                    };
+                    match shared_embeddings {
+                        Some(shared) => self.inner.load_with_shared_embeddings(
+                            &config,
+                            self.config.use_flash_attn,
+                            vb,
+                            normal_loading_metadata,
+                            attention_mechanism,
+                            shared,
+                        )?,
+                        None => self.inner.load(
+                            &config,
+                            self.config.use_flash_attn,
+                            vb,
+                            normal_loading_metadata,
+                            attention_mechanism,
+                        )?,
+                    }
+                }
+                NormalModelSource::Paths(paths) if use_nccl => {
+                    let (mapper, sharded_vb) = distributed::prepare_distributed_mapper(
+                        dtype,
+                        &device,
+                        &available_devices,
+                        silent,
+                        &config,
+                        loading_isq,
+                        self.config.from_uqff.is_some(),
+                        self.config.organization,
+                        &*self.inner,
+                        paths.as_ref(),
+                    )?;
+
+                    // Special case for where things can be more optimially loaded.
+                    match self.kind {
+                        ModelKind::Normal => normal_model_loader_sharded!(
+                            sharded_vb,
+                            config,
+                            self.inner,
+                            self.config.use_flash_attn,
+                            mapper,
+                            loading_isq,
+                            device.clone(),
+                            attention_mechanism,
+                            multi_progress.clone(),
+                            shared_embeddings,
+                        ),
+                        ModelKind::Adapter {
+                            adapter: AdapterKind::XLora,
+                        } => xlora_model_loader!(
+                            paths,
+                            Some(dtype),
+                            &load_device,
+                            layer_devices.clone(),
+                            config,
+                            self.inner,
+                            self.config.use_flash_attn,
+                            silent,
+                            mapper,
+                            loading_isq,
+                            device.clone(),
+                            multi_progress.clone(),
+                        ),
+                        ModelKind::Adapter {
+                            adapter: AdapterKind::Lora,
+                        } => lora_model_loader!(
+                            paths,
+                            Some(dtype),
+                            &load_device,
+                            layer_devices.clone(),
+                            config,
+                            self.inner,
+                            self.config.use_flash_attn,
+                            silent,
+                            mapper,
+                            loading_isq,
+                            self.config.from_uqff.is_some(),
+                            device.clone(),
+                            attention_mechanism,
+                            matches!(self.config.organization, IsqOrganization::MoeExpertsOnly),
+                            multi_progress.clone(),
+                        ),
+                        _ => unreachable!(),
+                    }
+                }
+                NormalModelSource::Paths(paths) => match self.kind {
+                    ModelKind::Normal => normal_model_loader!(
+                        paths,
+                        Some(dtype),
+                        &load_device,
+                        layer_devices.clone(),
+                        config,
+                        self.inner,
+                        self.config.use_flash_attn,
+                        silent,
+                        mapper,
+                        loading_isq,
+                        self.config.from_uqff.is_some(),
+                        device.clone(),
+                        attention_mechanism,
+                        matches!(self.config.organization, IsqOrganization::MoeExpertsOnly),
+                        multi_progress.clone(),
+                        shared_embeddings,
+                    ),
+                    ModelKind::Adapter {
+                        adapter: AdapterKind::XLora,
+                    } => xlora_model_loader!(
+                        paths,
+                        Some(dtype),
+                        &load_device,
+                        layer_devices.clone(),
+                        config,
+                        self.inner,
+                        self.config.use_flash_attn,
+                        silent,
+                        mapper,
+                        loading_isq,
+                        device.clone(),
+                        multi_progress.clone(),
+                    ),
+                    ModelKind::Adapter {
+                        adapter: AdapterKind::Lora,
+                    } => lora_model_loader!(
+                        paths,
+                        Some(dtype),
+                        &load_device,
+                        layer_devices.clone(),
+                        config,
+                        self.inner,
+                        self.config.use_flash_attn,
+                        silent,
+                        mapper,
+                        loading_isq,
+                        self.config.from_uqff.is_some(),
+                        device.clone(),
+                        attention_mechanism,
+                        matches!(self.config.organization, IsqOrganization::MoeExpertsOnly),
+                        multi_progress.clone(),
+                    ),
+                    _ => unreachable!(),
+                },
+            })
+        };
+        let mut model = with_shared_rope_tables(self.config.share_rope_tables, || {
+            with_lazy_rope_tables(self.config.lazy_rope_tables, || {
+                with_f32_norm_weights(self.config.f32_norm_weights, load_model)
+            })
+        })?;
+        self.apply_head_pruning(&mut *model)?;
+        self.apply_head_dim_padding(&mut *model)?;
+        self.apply_attention_head_scales(&mut *model)?;
+        if self.config.offload_activations {
+            info!("Offloading the residual stream to the CPU between decoder layers.");
+            model.set_offload_activations(true)?;
+        }
 
         if let Some(calibration_file) = &self.config.calibration_file {
             let calibration_data = std::fs::read_to_string(calibration_file)?;
@@ -716,7 +1177,9 @@ impl Loader for NormalLoader {
             );
         }
 
-        if (in_situ_quant.is_some() || self.config.topology.is_some())
+        if (in_situ_quant.is_some()
+            || self.config.topology.is_some()
+            || !self.config.isq_overrides.is_empty())
             && self.config.from_uqff.is_none()
         {
             let imatrix_source = match (
@@ -737,17 +1200,19 @@ impl Loader for NormalLoader {
                 in_situ_quant,
                 model.device().clone(),
                 self.config.topology.as_ref(),
+                &self.config.isq_overrides,
                 silent,
                 imatrix_source,
                 self.config.organization,
                 self.config.write_uqff.as_ref(),
                 UqffFullSer {
                     tokenizer: &tokenizer,
-                    template_filename: paths.get_template_filename(),
-                    generation_config: paths.get_gen_conf_filename(),
+                    template_filename: &template_filename,
+                    generation_config: gen_conf_filename.as_ref(),
                     config: config.clone(),
                     processor_filename: &None,
                     preprocessor_filename: &None,
+                    shard_size_mb: self.config.uqff_shard_size_mb,
                 },
                 multi_progress.clone(),
             )?;
@@ -759,6 +1224,7 @@ impl Loader for NormalLoader {
                 from_uqff,
             )?;
         }
+        self.apply_boundary_dtypes(&mut *model)?;
 
         let paged_attn_config = if matches!(
             self.kind,
@@ -779,6 +1245,13 @@ impl Loader for NormalLoader {
                 paged_attn_config.mem_gpu,
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
+                paged_attn_config.cache_type,
+                &self
+                    .config
+                    .topology
+                    .as_ref()
+                    .map(Topology::kv_cache_types)
+                    .unwrap_or_default(),
                 dtype,
                 model.config(),
                 &device,
@@ -791,7 +1264,7 @@ impl Loader for NormalLoader {
             )?;
 
             let mut layer_devices = Vec::new();
-            for layer in 0..self.inner.num_layers(&config)? {
+            for layer in 0..num_layers {
                 let device = model.get_layers().1.device_for(layer, false).cloned();
                 layer_devices.push(device);
             }
@@ -814,9 +1287,22 @@ impl Loader for NormalLoader {
             EitherCache::Full(full) => full.lock().len(),
             EitherCache::Normal(normal) => normal.lock().unwrap().0.len(),
         };
-        let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let generation_sampling = gen_conf
+            .as_ref()
+            .map(|gen_conf| gen_conf.sampling().clone())
+            .unwrap_or_default();
+        let mut eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        apply_special_token_overrides(
+            &mut chat_template,
+            &mut eos,
+            self.bos_tok_override,
+            self.eos_toks_override.as_deref(),
+            &tokenizer,
+        )?;
         let sliding_window = model.config().sliding_window;
         let model_metadata = Arc::new(model.config().clone());
+        let quant_manifest = model.quant_manifest();
+        let value_head = self.load_value_head(&*model)?;
 
         Ok(Arc::new(Mutex::new(NormalPipeline {
             model,
@@ -845,17 +1331,251 @@ impl Loader for NormalLoader {
                 cache_engine,
                 prompt_chunksize: Some(NonZero::new(prompt_chunksize).unwrap()),
                 model_metadata: Some(model_metadata),
+                generation_sampling,
             }),
             topology: self.config.topology.clone(),
+            isq_overrides: self.config.isq_overrides.clone(),
             silent,
             organization: self.config.organization,
-            template_filename: paths.get_template_filename().clone(),
-            generation_config: paths.get_gen_conf_filename().cloned(),
+            template_filename,
+            generation_config: gen_conf_filename,
             config,
             imatrix: self.config.imatrix.clone(),
             mapper: pipeline_mapper,
+            quant_manifest,
+            value_head,
+            prefill_dtype: self.config.prefill_dtype,
+            split_kv_segment_len: self.config.split_kv_segment_len,
+            split_kv_accumulation: self.config.split_kv_accumulation,
         })))
     }
+}
+
+impl Loader for NormalLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        let cache = self
+            .hf_cache_path
+            .clone()
+            .map(Cache::new)
+            .unwrap_or_default();
+        GLOBAL_HF_CACHE.get_or_init(|| cache);
+
+        let paths: anyhow::Result<Box<dyn ModelPaths>> = get_paths!(
+            LocalModelPaths,
+            &token_source,
+            revision.clone(),
+            self,
+            None,
+            None,
+            silent,
+            self.config.from_uqff.is_some(),
+            self.config.shard_download_retries
+        );
+        if let Some(from_uqff) = self.config.from_uqff.clone() {
+            *self.from_uqff.write().unwrap() = Some(get_uqff_paths!(&from_uqff, self, silent));
+        }
+        *self
+            .token_source
+            .write()
+            .expect("Failed to write to token source") = Some(token_source);
+        *self.revision.write().expect("Failed to write to revision") = revision;
+        self.load_model_from_path(
+            &paths?,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            paged_attn_config,
+        )
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        let config = std::fs::read_to_string(paths.get_config_filename())?;
+        self.load_pipeline(
+            config,
+            NormalModelSource::Paths(paths),
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            paged_attn_config,
+        )
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_parts(
+        &self,
+        config: &str,
+        tokenizer: &[u8],
+        weights: WeightSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        if !matches!(self.kind, ModelKind::Normal) {
+            anyhow::bail!("Loading from in-memory parts is only supported for plain models, not adapter models.");
+        }
+        if self.config.from_uqff.is_some() {
+            anyhow::bail!("Loading from in-memory parts does not support UQFF artifacts.");
+        }
+        self.load_pipeline(
+            config.to_string(),
+            NormalModelSource::Parts { tokenizer, weights },
+            dtype,
+            device,
+            silent,
+            DeviceMapSetting::dummy(),
+            in_situ_quant,
+            paged_attn_config,
+        )
+    }
+
+    fn dry_run(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+    ) -> Result<ResourceEstimate> {
+        let api = {
+            let cache = GLOBAL_HF_CACHE.get().cloned().unwrap_or_else(|| {
+                self.hf_cache_path
+                    .clone()
+                    .map(Cache::new)
+                    .unwrap_or_default()
+            });
+            let mut api = ApiBuilder::from_cache(cache)
+                .with_progress(false)
+                .with_token(get_token(&token_source)?);
+            if let Ok(x) = std::env::var("HF_HUB_CACHE") {
+                api = api.with_cache_dir(x.into());
+            }
+            api.build()?
+        };
+        let api = api.repo(Repo::with_revision(
+            self.model_id.clone(),
+            RepoType::Model,
+            revision.unwrap_or("main".to_string()),
+        ));
+        let model_id = Path::new(&self.model_id);
+
+        let config = fs::read_to_string(api_get_file!(api, "config.json", model_id))?;
+        let config = self.apply_config_overrides(config)?;
+        let checkpoint_size_in_bytes =
+            if api_dir_list!(api, model_id).any(|file| file == "model.safetensors.index.json") {
+                let index: serde_json::Value = serde_json::from_str(&fs::read_to_string(
+                    api_get_file!(api, "model.safetensors.index.json", model_id),
+                )?)?;
+                index["metadata"]["total_size"]
+                    .as_u64()
+                    .map(|size| size as usize)
+            } else {
+                None
+            };
+
+        let dtype = dtype.try_into_dtype(&[device])?;
+        let weight_size_in_bytes = self
+            .inner
+            .layer_sizes_in_bytes(&config, dtype, 1)?
+            .iter()
+            .sum::<usize>()
+            + self.inner.non_mapped_size_in_bytes(&config, dtype, 1)?;
+        Ok(ResourceEstimate {
+            num_layers: self.inner.num_layers(&config)?,
+            dtype,
+            num_parameters: weight_size_in_bytes / dtype.size_in_bytes(),
+            weight_size_in_bytes,
+            checkpoint_size_in_bytes,
+            device_available_bytes: MemoryUsage.get_memory_available(device).ok(),
+        })
+    }
+
+    fn validate_config(&self, config: &str) -> Result<()> {
+        let value: serde_json::Value =
+            serde_json::from_str(config).map_err(|e| ConfigValidationError::from_serde(&e))?;
+        let config = self.apply_config_overrides(config.to_string())?;
+
+        // This also resolves the architecture for the automatic loader.
+        if let Err(e) = self
+            .inner
+            .get_config_repr(&config, self.config.use_flash_attn)
+        {
+            return Err(match e.downcast::<serde_json::Error>() {
+                Ok(e) => ConfigValidationError::from_serde(&e).into(),
+                Err(e) if e.is::<ConfigValidationError>() => e,
+                Err(e) => ConfigValidationError::new("config", e).into(),
+            });
+        }
+
+        if let Some(torch_dtype) = value.get("torch_dtype") {
+            if torch_dtype
+                .as_str()
+                .and_then(dtype_from_torch_name)
+                .is_none()
+            {
+                return Err(ConfigValidationError::new(
+                    "torch_dtype",
+                    format!("{torch_dtype} is not a dtype the weights can be loaded from."),
+                )
+                .into());
+            }
+        }
+
+        // A topology maps the layers one by one. Layers past its end keep the defaults, but it may
+        // not map layers the model does not have.
+        let num_layers = self.inner.num_layers(&config)?;
+        if let Some(topology) = &self.config.topology {
+            if topology.0.len() > num_layers {
+                return Err(ConfigValidationError::new(
+                    "num_hidden_layers",
+                    format!(
+                        "The model has {num_layers} layers, but the topology maps {}.",
+                        topology.0.len()
+                    ),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    fn normalized_config(&self, config: &str) -> Result<NormalizedConfig> {
+        let config = self.apply_config_overrides(config.to_string())?;
+        NormalizedConfig::new(&*self.inner.model_config(&config)?, &config)
+    }
+
+    fn set_shared_embeddings(&self, shared: SharedEmbeddings) -> Result<()> {
+        if !matches!(self.kind, ModelKind::Normal) {
+            anyhow::bail!("Shared embeddings are not supported for adapter models.");
+        }
+        *self.shared_embeddings.write().unwrap() = Some(shared);
+        Ok(())
+    }
 
     fn get_id(&self) -> String {
         self.model_id.clone()
@@ -866,6 +1586,24 @@ impl Loader for NormalLoader {
     }
 }
 
+impl NormalPipeline {
+    /// Run `f` with this pipeline's attention options, the prefill dtype and the split-KV settings.
+    fn with_attention_options<T>(&self, f: impl FnOnce() -> T) -> T {
+        crate::attention::with_prefill_dtype(self.prefill_dtype, || {
+            crate::attention::with_split_kv(
+                self.split_kv_segment_len,
+                self.split_kv_accumulation,
+                f,
+            )
+        })
+    }
+
+    /// Run `f` on an empty KV cache, restoring the previous cache afterwards.
+    fn with_empty_cache<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.model.cache().with_empty_cache(f)
+    }
+}
+
 impl PreProcessingMixin for NormalPipeline {
     fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
         Some(self.chat_template.clone())
@@ -883,6 +1621,7 @@ impl IsqPipelineMixin for NormalPipeline {
             Some(dtype),
             device.clone(),
             self.topology.as_ref(),
+            &self.isq_overrides,
             self.silent,
             self.imatrix.as_ref().map(ImatrixDataSource::File),
             self.organization,
@@ -894,9 +1633,11 @@ impl IsqPipelineMixin for NormalPipeline {
                 config: self.config.clone(),
                 processor_filename: &None,
                 preprocessor_filename: &None,
+                shard_size_mb: None,
             },
             multi_progress.clone(),
         )?;
+        self.quant_manifest = self.model.quant_manifest();
         Ok(())
     }
 }
@@ -964,6 +1705,12 @@ impl MetadataMixin for NormalPipeline {
     fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
         Some(&*self.mapper)
     }
+    fn quant_manifest(&self) -> Vec<(String, QuantInfo)> {
+        self.quant_manifest.clone()
+    }
+    fn shared_embeddings(&self) -> Option<SharedEmbeddings> {
+        self.model.shared_embeddings()
+    }
 }
 
 #[async_trait::async_trait]
@@ -972,7 +1719,10 @@ impl Pipeline for NormalPipeline {
         &mut self,
         inputs: Box<dyn Any>,
         return_raw_logits: bool,
+        return_logprobs: Option<usize>,
     ) -> Result<ForwardInputsResult, candle_core::Error> {
+        let inputs: ModelInputs = *inputs.downcast().expect("Downcast failed.");
+        inputs.check_consistency()?;
         let ModelInputs {
             input_ids,
             input_ids_full,
@@ -983,22 +1733,18 @@ impl Pipeline for NormalPipeline {
             paged_attn_meta,
             flash_meta,
             flash_meta_full,
-        } = *inputs.downcast().expect("Downcast failed.");
+            use_cache,
+        } = inputs;
         let metadata = self.get_metadata();
-        let paged_attn_meta = match (&metadata.cache_engine, &paged_attn_meta) {
-            (Some(cache_engine), Some(meta)) => Some((cache_engine, meta)),
-            (Some(_), None) => {
-                // This can happen if Rust-side user code is wrong
-                candle_core::bail!("Forward step expected a PagedAttention input metadata. This was not provided, please ensure that the scheduler config is correctly configured for PagedAttention.")
-            }
-            (None, Some(_)) => {
-                // This should never happen but we handle it anyway
-                candle_core::bail!("Forward step got a PagedAttention input metadata but there is no cache engine. Please raise an issue.")
-            }
-            (None, None) => None,
-        };
-        #[cfg(feature = "metal")]
-        let logits = objc::rc::autoreleasepool(|| -> candle_core::Result<Tensor> {
+        if !use_cache && paged_attn_meta.is_some() {
+            candle_core::bail!(
+                "A forward pass without the KV cache is not supported with PagedAttention."
+            );
+        }
+        let paged_attn_meta =
+            pair_paged_attn_meta(metadata.cache_engine.as_ref(), paged_attn_meta.as_ref())?;
+        let no_kv_cache = self.no_kv_cache || !use_cache;
+        let forward = || -> candle_core::Result<Tensor> {
             match self.model.is_xlora() {
                 false => {
                     let paged_attn_meta = paged_attn_meta
@@ -1019,7 +1765,7 @@ impl Pipeline for NormalPipeline {
                     input_ids_full.as_ref().unwrap_or(&input_ids),
                     &seqlen_offsets,
                     seqlen_offsets_full.as_ref().unwrap_or(&seqlen_offsets),
-                    self.no_kv_cache,
+                    no_kv_cache,
                     &self.non_granular_state,
                     context_lens,
                     position_ids,
@@ -1027,40 +1773,20 @@ impl Pipeline for NormalPipeline {
                     flash_meta_full.as_ref().unwrap_or(&flash_meta),
                 ),
             }
-        })?;
-        #[cfg(not(feature = "metal"))]
-        let logits = match self.model.is_xlora() {
-            false => {
-                let paged_attn_meta = paged_attn_meta
-                    .as_ref()
-                    .map(|meta| (meta.0.get_kv_cache().clone(), meta.1.clone()));
-
-                self.model.forward(
-                    &input_ids,
-                    &seqlen_offsets,
-                    context_lens,
-                    position_ids,
-                    paged_attn_meta.as_ref().map(|(a, b)| (a.clone(), b)),
-                    &flash_meta,
-                )?
-            }
-            true => self.model.xlora_forward(
-                &input_ids,
-                input_ids_full.as_ref().unwrap_or(&input_ids),
-                &seqlen_offsets,
-                seqlen_offsets_full.as_ref().unwrap_or(&seqlen_offsets),
-                self.no_kv_cache,
-                &self.non_granular_state,
-                context_lens,
-                position_ids,
-                &flash_meta,
-                flash_meta_full.as_ref().unwrap_or(&flash_meta),
-            )?,
         };
+        #[cfg(feature = "metal")]
+        let forward = || objc::rc::autoreleasepool(forward);
+        let logits = self.with_attention_options(|| {
+            if use_cache || self.no_kv_cache {
+                forward()
+            } else {
+                self.with_empty_cache(forward)
+            }
+        })?;
         if return_raw_logits {
             Ok(ForwardInputsResult::RawLogits { logits })
         } else {
-            Ok(ForwardInputsResult::CausalGeneration { logits })
+            ForwardInputsResult::CausalGeneration { logits }.with_top_logprobs(return_logprobs)
         }
     }
     async fn sample_causal_gen(
@@ -1069,13 +1795,239 @@ impl Pipeline for NormalPipeline {
         logits: Vec<Tensor>,
         prefix_cacher: &mut PrefixCacheManagerV2,
         disable_eos_stop: bool,
-        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
     ) -> Result<(), candle_core::Error> {
         sample_and_add_toks(self, seqs, logits, prefix_cacher, disable_eos_stop, rng).await
     }
     fn category(&self) -> ModelCategory {
         ModelCategory::Text
     }
+    fn continuation_loglikelihoods(
+        &mut self,
+        prefix: &[u32],
+        continuations: &[Vec<u32>],
+    ) -> Result<Vec<f32>, candle_core::Error> {
+        if self.model.is_xlora() {
+            candle_core::bail!("Scoring continuations is not supported for X-LoRA models.");
+        }
+        if self.get_metadata().cache_engine.is_some() {
+            candle_core::bail!("Scoring continuations is not supported with PagedAttention.");
+        }
+
+        let model = &self.model;
+        let mapper = self.mapper.as_ref();
+        let score = || {
+            loglikelihood::score_continuations(
+                prefix,
+                continuations,
+                |toks, seqlen_offset| {
+                    let inputs = make_prompt_chunk(
+                        seqlen_offset,
+                        vec![toks.to_vec()],
+                        &[0],
+                        model.device(),
+                        None,
+                        true,
+                        None,
+                        Some(mapper),
+                    )
+                    .map_err(candle_core::Error::msg)?;
+                    model.forward(
+                        &inputs.input,
+                        &inputs.positions,
+                        inputs.context_lens,
+                        inputs.position_ids,
+                        None,
+                        &inputs.flash_meta,
+                    )
+                },
+                |len| {
+                    match model.cache() {
+                        EitherCache::Full(full) => {
+                            for (k, v) in full.lock().iter_mut().flatten() {
+                                *k = k.narrow(2, 0, len)?;
+                                *v = v.narrow(2, 0, len)?;
+                            }
+                        }
+                        EitherCache::Normal(normal) => {
+                            for layer in &mut *normal.lock().unwrap().0 {
+                                layer.set_len(len)?;
+                            }
+                        }
+                    }
+                    Ok(())
+                },
+            )
+        };
+        self.with_attention_options(|| self.with_empty_cache(score))
+    }
+    fn score_reward(&mut self, tokens: &[u32]) -> Result<f32, candle_core::Error> {
+        let Some(value_head) = &self.value_head else {
+            candle_core::bail!("Reward scoring requires a value head, see `with_value_head`.");
+        };
+        if tokens.is_empty() {
+            candle_core::bail!("Reward scoring requires a non-empty sequence.");
+        }
+        if self.model.is_xlora() {
+            candle_core::bail!("Reward scoring is not supported for X-LoRA models.");
+        }
+        if self.get_metadata().cache_engine.is_some() {
+            candle_core::bail!("Reward scoring is not supported with PagedAttention.");
+        }
+
+        let model = &self.model;
+        let mapper = self.mapper.as_ref();
+        self.with_empty_cache(|| {
+            let inputs = make_prompt_chunk(
+                0,
+                vec![tokens.to_vec()],
+                &[0],
+                model.device(),
+                None,
+                true,
+                None,
+                Some(mapper),
+            )
+            .map_err(candle_core::Error::msg)?;
+            let hidden =
+                model.final_hidden_states(&inputs.input, &inputs.positions, &inputs.flash_meta)?;
+            value_head.forward(&hidden.i((0, hidden.dim(1)? - 1))?)
+        })
+    }
+    fn logit_lens(&mut self, tokens: &[u32]) -> Result<Vec<Vec<u32>>, candle_core::Error> {
+        if tokens.is_empty() {
+            candle_core::bail!("The logit lens requires a non-empty sequence.");
+        }
+        if self.model.is_xlora() {
+            candle_core::bail!("The logit lens is not supported for X-LoRA models.");
+        }
+        if self.get_metadata().cache_engine.is_some() {
+            candle_core::bail!("The logit lens is not supported with PagedAttention.");
+        }
+
+        let model = &self.model;
+        let mapper = self.mapper.as_ref();
+        self.with_empty_cache(|| {
+            let inputs = make_prompt_chunk(
+                0,
+                vec![tokens.to_vec()],
+                &[0],
+                model.device(),
+                None,
+                true,
+                None,
+                Some(mapper),
+            )
+            .map_err(candle_core::Error::msg)?;
+            model
+                .output_hidden_states(&inputs.input, &inputs.positions, &inputs.flash_meta)?
+                .iter()
+                .map(|hidden| model.logit_lens(hidden)?.i(0)?.to_vec1::<u32>())
+                .collect()
+        })
+    }
+    fn input_embedding_gradients(
+        &mut self,
+        tokens: &[u32],
+        target: GradientTarget,
+    ) -> Result<Tensor, candle_core::Error> {
+        if tokens.is_empty() {
+            candle_core::bail!("Input embedding gradients require a non-empty sequence.");
+        }
+        if self.model.is_xlora() {
+            candle_core::bail!("Input embedding gradients are not supported for X-LoRA models.");
+        }
+        if self.get_metadata().cache_engine.is_some() {
+            candle_core::bail!("Input embedding gradients are not supported with PagedAttention.");
+        }
+
+        let model = &self.model;
+        let mapper = self.mapper.as_ref();
+        self.with_empty_cache(|| {
+            let inputs = make_prompt_chunk(
+                0,
+                vec![tokens.to_vec()],
+                &[0],
+                model.device(),
+                None,
+                true,
+                None,
+                Some(mapper),
+            )
+            .map_err(candle_core::Error::msg)?;
+            input_gradients::input_embedding_gradients(
+                &model.input_embeddings(&inputs.input)?,
+                tokens,
+                target,
+                |input_embeds| {
+                    model.forward_input_embeds(
+                        &inputs.input,
+                        input_embeds,
+                        &inputs.positions,
+                        &inputs.flash_meta,
+                    )
+                },
+            )
+        })
+    }
+    fn prime_prefix_cache(
+        &mut self,
+        prompts: Vec<Vec<u32>>,
+        prefix_cacher: &mut PrefixCacheManagerV2,
+    ) -> Result<(), candle_core::Error> {
+        if self.model.is_xlora() {
+            candle_core::bail!("Priming the prefix cache is not supported for X-LoRA models.");
+        }
+        if self.get_metadata().cache_engine.is_some() {
+            candle_core::bail!("Priming the prefix cache is not supported with PagedAttention.");
+        }
+        let EitherCache::Normal(normal) = self.model.cache() else {
+            candle_core::bail!("Priming the prefix cache requires a model with a normal KV cache.");
+        };
+
+        let model = &self.model;
+        let mapper = self.mapper.as_ref();
+        prefix_cacher.prime(prompts, |prompt| {
+            self.with_empty_cache(|| -> Result<_, candle_core::Error> {
+                let inputs = make_prompt_chunk(
+                    0,
+                    vec![prompt.to_vec()],
+                    &[0],
+                    model.device(),
+                    None,
+                    true,
+                    None,
+                    Some(mapper),
+                )
+                .map_err(candle_core::Error::msg)?;
+                // Prime with the same attention dtype as a regular prefill of this prompt.
+                self.with_attention_options(|| {
+                    model.forward(
+                        &inputs.input,
+                        &inputs.positions,
+                        inputs.context_lens,
+                        inputs.position_ids,
+                        None,
+                        &inputs.flash_meta,
+                    )
+                })?;
+                Ok(normal
+                    .lock()
+                    .unwrap()
+                    .0
+                    .iter()
+                    .cloned()
+                    .map(Some)
+                    .collect::<Vec<_>>())
+            })
+        })
+    }
+    fn expert_counter(&self) -> Option<ExpertCounter> {
+        self.model.expert_counter().cloned()
+    }
+    fn qkv_capture(&self) -> Option<QkvCapture> {
+        self.model.qkv_capture().cloned()
+    }
 }
 
 impl AnyMoePipelineMixin for NormalPipeline {
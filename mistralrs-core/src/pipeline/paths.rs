@@ -21,7 +21,7 @@ use crate::{
         chat_template::{ChatTemplate, ChatTemplateValue},
         isq::UQFF_RESIDUAL_SAFETENSORS,
     },
-    utils::tokens::get_token,
+    utils::{tokens::get_token, varbuilder_utils::open_safetensors_shard},
     xlora_models::XLoraConfig,
     ModelPaths, Ordering, TokenSource, GLOBAL_HF_CACHE,
 };
@@ -314,6 +314,7 @@ pub fn get_model_paths(
     api: &ApiRepo,
     model_id: &Path,
     loading_from_uqff: bool,
+    shard_download_retries: usize,
 ) -> Result<Vec<PathBuf>> {
     match &quantized_filename {
         Some(names) => {
@@ -385,25 +386,115 @@ pub fn get_model_paths(
                     .collect::<Vec<_>>()
             );
             for rfilename in files {
-                filenames.push(api_get_file!(api, &rfilename, model_id));
+                let path = api_get_file!(api, &rfilename, model_id);
+                let path = if model_id.exists() || !rfilename.ends_with(".safetensors") {
+                    path
+                } else {
+                    redownload_corrupt_shard(api, &rfilename, path, shard_download_retries)?
+                };
+                filenames.push(path);
             }
             Ok(filenames)
         }
     }
 }
 
+/// Check that the cached shard `path` for `rfilename` can be opened, downloading it again up to
+/// `retries` times if it is corrupt or truncated.
+fn redownload_corrupt_shard(
+    api: &ApiRepo,
+    rfilename: &str,
+    mut path: PathBuf,
+    retries: usize,
+) -> Result<PathBuf> {
+    let mut attempt = 0;
+    loop {
+        match open_safetensors_shard(&path) {
+            Ok(_) => return Ok(path),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!("{e}. Downloading `{rfilename}` again (attempt {attempt}/{retries}).");
+                remove_cached_file(&path)?;
+                path = api.download(rfilename)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Remove the cached file `path`. In the Hugging Face cache, `path` is a symlink into the `blobs`
+/// directory, and the blob is removed too so that the file is really downloaded again.
+fn remove_cached_file(path: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(path)?.file_type().is_symlink() {
+        fs::remove_file(fs::canonicalize(path)?)?;
+    }
+    fs::remove_file(path)
+}
+
 /// Find and parse the appropriate [`ChatTemplate`], and ensure is has a valid [`ChatTemplate.chat_template`].
-/// If the provided `tokenizer_config.json` from [`ModelPaths.get_template_filename`] does not
-/// have a `chat_template`, use the provided one.
 ///
-/// - Uses `chat_template_fallback` if `paths` does not contain a chat template file. This may be a literal or .json file.
-/// - `chat_template_ovrd` (GGUF chat template content) causes the usage of that string chat template initially.
-///   Falls back to `chat_template_file` if it is invalid. *The user must add the bos/unk/eos tokens manually if this
-///   is used.*
+/// The special tokens always come from the `tokenizer_config.json` from [`ModelPaths.get_template_filename`]
+/// (or `chat_template_fallback` if there is none). The chat template itself is taken from the first of the
+/// following sources which provides one:
+///
+/// 1. `jinja_explicit`: a `.jinja` file explicitly specified by the user.
+/// 2. `chat_template_ovrd`: a literal chat template (GGUF chat template content). *The user must add the
+///    bos/unk/eos tokens manually if this is used.*
+/// 3. The `chat_template` field of the processor config (vision models).
+/// 4. The `chat_template` field of `tokenizer_config.json`. This may be a string or a list of named templates
+///    (`[{"name": "default", "template": ...}, ...]`), in which case the template is selected at render time.
+/// 5. `chat_template_explicit`: the model's `chat_template.json` (a json with one field: "chat_template") or
+///    `.jinja` file.
+/// 6. `chat_template_fallback`, a `.json` file with a `chat_template` field and optional bos/eos/unk tokens.
 ///
-/// THE FOLLOWING IS IGNORED:
-/// After this, if the `chat_template_explicit` filename is specified (a json with one field: "chat_template" OR a jinja file),
-///  the chat template is overwritten with this chat template.
+/// If none of these provide a chat template, only prompts will be accepted.
+/// Like [`get_chat_template`], but a Jinja `chat_template_string` given at runtime takes precedence over every
+/// file-based source. The special tokens still come from `tokenizer_config.json` if there is one.
+#[allow(clippy::borrowed_box)]
+pub(crate) fn get_chat_template_with_string(
+    paths: &Box<dyn ModelPaths>,
+    jinja_explicit: &Option<String>,
+    chat_template_explicit: &Option<String>,
+    chat_template_fallback: &Option<String>,
+    chat_template_string: &Option<String>,
+) -> ChatTemplate {
+    let Some(chat_template_string) = chat_template_string else {
+        return get_chat_template(
+            paths,
+            jinja_explicit,
+            chat_template_explicit,
+            chat_template_fallback,
+            None,
+        );
+    };
+    if paths.get_template_filename().is_none()
+        && !chat_template_fallback
+            .as_ref()
+            .is_some_and(|f| f.ends_with(".json"))
+    {
+        // There is nothing to take the special tokens from, so use it as a literal template.
+        return get_chat_template(
+            paths,
+            &None,
+            &None,
+            &None,
+            Some(chat_template_string.clone()),
+        );
+    }
+    info!("Using chat template string.");
+    let mut template = get_chat_template(
+        paths,
+        jinja_explicit,
+        chat_template_explicit,
+        chat_template_fallback,
+        None,
+    );
+    template.chat_template = Some(ChatTemplateValue(Either::Left(
+        chat_template_string.clone(),
+    )));
+    template
+}
+
 #[allow(clippy::borrowed_box)]
 pub(crate) fn get_chat_template(
     paths: &Box<dyn ModelPaths>,
@@ -439,6 +530,7 @@ pub(crate) fn get_chat_template(
     } else {
         panic!("Expected chat template file to end with .json, or you can specify a tokenizer model ID to load the chat template there. If you are running a GGUF model, it probably does not contain a chat template.");
     };
+    let literal_template = chat_template_ovrd.is_some();
     let mut template: ChatTemplate = match chat_template_ovrd {
         Some(chat_template) => {
             // In this case the override chat template is being used. The user must add the bos/eos/unk toks themselves.
@@ -449,7 +541,7 @@ pub(crate) fn get_chat_template(
         }
         None => serde_json::from_str(&template_content.as_ref().unwrap().clone()).unwrap(),
     };
-    // Overwrite to use any present `chat_template.json`, only if there is not one present already.
+    // Use any present `chat_template.json`, only if `tokenizer_config.json` does not have a chat template.
     if template.chat_template.is_none() {
         if let Some(chat_template_explicit) = chat_template_explicit {
             let ct =
@@ -470,22 +562,12 @@ pub(crate) fn get_chat_template(
         }
     }
 
-    // JINJA explicit
-    if let Some(jinja_explicit) = jinja_explicit {
-        if !jinja_explicit.ends_with(".jinja") {
-            panic!("jinja_explicit must end with .jinja!");
-        }
-
-        let ct = fs::read_to_string(jinja_explicit).expect("Loading chat template failed.");
-
-        template.chat_template = Some(ChatTemplateValue(Either::Left(ct)));
-    }
-
     let processor_conf: Option<crate::vision_models::processor_config::ProcessorConfig> = paths
         .get_processor_config()
         .as_ref()
         .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
-    if let Some(processor_conf) = processor_conf {
+    // A literal chat template is not overridden by the processor config.
+    if let Some(processor_conf) = processor_conf.filter(|_| !literal_template) {
         if processor_conf.chat_template.is_some() {
             template.chat_template = processor_conf
                 .chat_template
@@ -493,6 +575,17 @@ pub(crate) fn get_chat_template(
         }
     }
 
+    // JINJA explicit, this takes precedence over every other source.
+    if let Some(jinja_explicit) = jinja_explicit {
+        if !jinja_explicit.ends_with(".jinja") {
+            panic!("jinja_explicit must end with .jinja!");
+        }
+
+        let ct = fs::read_to_string(jinja_explicit).expect("Loading chat template failed.");
+
+        template.chat_template = Some(ChatTemplateValue(Either::Left(ct)));
+    }
+
     #[derive(Debug, serde::Deserialize)]
     struct SpecifiedTemplate {
         chat_template: String,
@@ -607,4 +700,159 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn chat_template_from_tokenizer_config() -> anyhow::Result<()> {
+        use either::Either;
+        use indexmap::IndexMap;
+
+        use super::get_chat_template;
+        use crate::{
+            pipeline::{chat_template::apply_chat_template_to, AdapterPaths},
+            LocalModelPaths, MessageContent, ModelPaths,
+        };
+
+        let dir = std::env::temp_dir().join(format!(
+            "mistralrs-tokenizer-config-template-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir)?;
+
+        let configs = [
+            serde_json::json!({
+                "bos_token": "<s>",
+                "eos_token": "</s>",
+                "chat_template": "{{ bos_token }}{% for message in messages %}[{{ message['role'] }}] {{ message['content'] }}{% endfor %}",
+            }),
+            serde_json::json!({
+                "bos_token": "<s>",
+                "eos_token": "</s>",
+                "chat_template": [
+                    {"name": "tool_use", "template": "unused"},
+                    {"name": "default", "template": "{{ bos_token }}{% for message in messages %}[{{ message['role'] }}] {{ message['content'] }}{% endfor %}"},
+                ],
+            }),
+        ];
+        // Lower precedence than `tokenizer_config.json`, so it must be ignored.
+        let chat_template_json = dir.join("chat_template.json");
+        std::fs::write(
+            &chat_template_json,
+            serde_json::json!({"chat_template": "ignored"}).to_string(),
+        )?;
+
+        for (i, config) in configs.iter().enumerate() {
+            let tokenizer_config = dir.join(format!("tokenizer_config_{i}.json"));
+            std::fs::write(&tokenizer_config, config.to_string())?;
+
+            let paths: Box<dyn ModelPaths> = Box::new(LocalModelPaths::new(
+                dir.join("tokenizer.json"),
+                dir.join("config.json"),
+                tokenizer_config,
+                vec![],
+                AdapterPaths::None,
+                None,
+                None,
+                None,
+                Some(chat_template_json.clone()),
+            ));
+            let template = get_chat_template(
+                &paths,
+                &None,
+                &Some(chat_template_json.to_string_lossy().to_string()),
+                &None,
+                None,
+            );
+            assert!(template.has_chat_template());
+
+            let mut message: IndexMap<String, MessageContent> = IndexMap::new();
+            message.insert("role".to_string(), Either::Left("user".to_string()));
+            message.insert("content".to_string(), Either::Left("Hello".to_string()));
+            let rendered = apply_chat_template_to(
+                vec![message],
+                true,
+                template.chat_template.as_ref().unwrap(),
+                template.bos_tok(),
+                template.eos_tok(),
+                template.unk_tok(),
+                Vec::new(),
+            )?;
+            assert_eq!(rendered, "<s>[user] Hello");
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn chat_template_string_takes_precedence() -> anyhow::Result<()> {
+        use either::Either;
+        use indexmap::IndexMap;
+
+        use super::get_chat_template_with_string;
+        use crate::{
+            pipeline::{chat_template::apply_chat_template_to, AdapterPaths},
+            LocalModelPaths, MessageContent, ModelPaths, NormalLoaderBuilder, NormalSpecificConfig,
+        };
+
+        let inline = "{{ bos_token }}{% for message in messages %}<{{ message['role'] }}>{{ message['content'] }}{% endfor %}".to_string();
+        let build = |template: String| {
+            NormalLoaderBuilder::new(
+                NormalSpecificConfig::default(),
+                None,
+                None,
+                Some("unused".to_string()),
+                false,
+                None,
+            )
+            .with_chat_template_string(template)
+            .build(None)
+        };
+        assert!(build(inline.clone()).is_ok());
+        assert!(build("{% for message in messages %}".to_string()).is_err());
+
+        let dir = std::env::temp_dir().join(format!(
+            "mistralrs-chat-template-string-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let tokenizer_config = dir.join("tokenizer_config.json");
+        std::fs::write(
+            &tokenizer_config,
+            serde_json::json!({
+                "bos_token": "<s>",
+                "eos_token": "</s>",
+                "chat_template": "ignored",
+            })
+            .to_string(),
+        )?;
+        let paths: Box<dyn ModelPaths> = Box::new(LocalModelPaths::new(
+            dir.join("tokenizer.json"),
+            dir.join("config.json"),
+            tokenizer_config,
+            vec![],
+            AdapterPaths::None,
+            None,
+            None,
+            None,
+            None,
+        ));
+        let template = get_chat_template_with_string(&paths, &None, &None, &None, &Some(inline));
+        std::fs::remove_dir_all(&dir)?;
+
+        let mut message: IndexMap<String, MessageContent> = IndexMap::new();
+        message.insert("role".to_string(), Either::Left("user".to_string()));
+        message.insert("content".to_string(), Either::Left("Hello".to_string()));
+        let rendered = apply_chat_template_to(
+            vec![message],
+            true,
+            template.chat_template.as_ref().unwrap(),
+            template.bos_tok(),
+            template.eos_tok(),
+            template.unk_tok(),
+            Vec::new(),
+        )?;
+        // The special tokens still come from `tokenizer_config.json`.
+        assert_eq!(rendered, "<s><user>Hello");
+        Ok(())
+    }
 }
@@ -0,0 +1,543 @@
+use std::{
+    any::Any,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result as anyhowResult;
+use candle_core::{Device, IndexOp, Result, Tensor};
+use mistralrs_quant::{IsqType, QuantInfo};
+use tokenizers::Tokenizer;
+
+use crate::{
+    device_map::DeviceMapper,
+    get_mut_arcmutex,
+    pipeline::sampling::{finish_or_add_toks_to_seq, sample_target_sequence_speculative},
+    prefix_cacher::PrefixCacheManagerV2,
+    sampler::SamplingRng,
+    sequence::{Sequence, SequenceRecognizer},
+    DeviceMapSetting, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource, TryIntoDType,
+};
+
+use super::{
+    chat_template::ChatTemplate, AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction,
+    CacheManagerMixin, EitherCache, ForwardInputsResult, GeneralMetadata, IsqPipelineMixin,
+    MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin, Processor,
+};
+
+/// A loader for a prompt lookup pipeline wrapping a target [`Loader`].
+pub struct PromptLookupLoader {
+    pub target: Box<dyn Loader>,
+    pub config: PromptLookupConfig,
+}
+
+impl Loader for PromptLookupLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        let target = self.target.load_model_from_hf(
+            revision,
+            token_source,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            paged_attn_config,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            PromptLookupPipeline::new(target, self.config)?,
+        )))
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        let target = self.target.load_model_from_path(
+            paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            paged_attn_config,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            PromptLookupPipeline::new(target, self.config)?,
+        )))
+    }
+    fn get_id(&self) -> String {
+        format!(
+            "Prompt lookup: tgt = `{}`, max_ngram_size = `{}`, num_draft_tokens = `{}`",
+            self.target.get_id(),
+            self.config.max_ngram_size,
+            self.config.num_draft_tokens,
+        )
+    }
+    fn get_kind(&self) -> ModelKind {
+        self.target.get_kind()
+    }
+}
+
+#[derive(Copy, Clone)]
+/// Metadata for a prompt lookup pipeline
+pub struct PromptLookupConfig {
+    /// Longest n-gram at the end of the sequence to search for in the earlier tokens
+    pub max_ngram_size: usize,
+    /// Maximum number of tokens to draft from a match
+    pub num_draft_tokens: usize,
+}
+
+/// Draft up to `num_draft_tokens` tokens by matching the last n tokens of `toks` against an earlier
+/// occurrence in `toks`, for n from `max_ngram_size` down to 1. The tokens which followed the most
+/// recent match are the draft. Returns an empty draft if there is no match.
+pub(crate) fn prompt_lookup_draft(
+    toks: &[u32],
+    max_ngram_size: usize,
+    num_draft_tokens: usize,
+) -> Vec<u32> {
+    for n in (1..=max_ngram_size.min(toks.len().saturating_sub(1))).rev() {
+        let ngram = &toks[toks.len() - n..];
+        if let Some(start) = (0..toks.len() - n)
+            .rev()
+            .find(|&i| &toks[i..i + n] == ngram)
+        {
+            let draft_start = start + n;
+            let draft_end = (draft_start + num_draft_tokens).min(toks.len());
+            return toks[draft_start..draft_end].to_vec();
+        }
+    }
+    Vec::new()
+}
+
+/// Number of `target` tokens to accept given the `draft` they verify, where `target[i]` is the target
+/// model's token after the sequence extended by `draft[..i]`. Tokens are accepted up to and including
+/// the first mismatch, so at least one token is always accepted.
+pub(crate) fn accepted_draft_len(draft: &[u32], target: &[u32]) -> usize {
+    draft
+        .iter()
+        .zip(target)
+        .position(|(d, t)| d != t)
+        .map(|mismatch| mismatch + 1)
+        .unwrap_or(target.len())
+}
+
+/// Prompt lookup decoding pipeline: <https://github.com/apoorvumang/prompt-lookup-decoding>
+///
+/// # Algorithm
+/// Tokens are drafted without a draft model by finding the most recent earlier occurrence of the
+/// trailing n-gram of the sequence and proposing the tokens which followed it.
+///
+/// - Run the target model once over the last token and all drafted tokens
+/// - Accept drafted tokens while they match the target's samples, then take the target's token at the first
+///   mismatch (or after the last drafted token)
+/// - Truncate the target's KV cache to drop the rejected tokens
+///
+/// Prompts, batches of more than one sequence and steps without a match fall back to the target's plain step.
+pub struct PromptLookupPipeline {
+    target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    config: PromptLookupConfig,
+    metadata: Arc<GeneralMetadata>,
+    category: ModelCategory,
+}
+
+impl PromptLookupPipeline {
+    pub fn new(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        config: PromptLookupConfig,
+    ) -> Result<Self> {
+        if config.max_ngram_size == 0 || config.num_draft_tokens == 0 {
+            candle_core::bail!(
+                "Prompt lookup requires `max_ngram_size` and `num_draft_tokens` to be at least 1."
+            );
+        }
+        if get_mut_arcmutex!(target)
+            .get_metadata()
+            .cache_engine
+            .is_some()
+        {
+            candle_core::bail!("Prompt lookup decoding does not support PagedAttention.");
+        }
+        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        let category = get_mut_arcmutex!(target).category();
+        Ok(Self {
+            target,
+            config,
+            metadata,
+            category,
+        })
+    }
+}
+
+impl PreProcessingMixin for PromptLookupPipeline {
+    fn get_processor(&self) -> Arc<dyn Processor> {
+        get_mut_arcmutex!(self.target).get_processor()
+    }
+    fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+        get_mut_arcmutex!(self.target).get_chat_template()
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        get_mut_arcmutex!(self.target).get_input_processor_config()
+    }
+}
+
+impl IsqPipelineMixin for PromptLookupPipeline {
+    fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).re_isq_model(dtype)
+    }
+}
+
+impl CacheManagerMixin for PromptLookupPipeline {
+    fn clone_in_cache(&self, seqs: &mut [&mut Sequence]) {
+        get_mut_arcmutex!(self.target).clone_in_cache(seqs)
+    }
+    fn clone_out_cache(&self, seqs: &mut [&mut Sequence]) {
+        get_mut_arcmutex!(self.target).clone_out_cache(seqs)
+    }
+    fn set_none_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        reset_non_granular: bool,
+        modify_draft_cache: bool,
+        load_preallocated_cache: bool,
+    ) {
+        get_mut_arcmutex!(self.target).set_none_cache(
+            seqs,
+            reset_non_granular,
+            modify_draft_cache,
+            load_preallocated_cache,
+        )
+    }
+    fn cache(&self) -> &EitherCache {
+        unreachable!()
+    }
+    fn do_preallocated_cache(&self) -> bool {
+        // The KV cache is truncated after verification
+        false
+    }
+}
+
+impl MetadataMixin for PromptLookupPipeline {
+    fn device(&self) -> Device {
+        get_mut_arcmutex!(self.target).device()
+    }
+    fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        get_mut_arcmutex!(self.target).tokenizer()
+    }
+    fn name(&self) -> String {
+        format!(
+            "Prompt lookup: tgt = `{}`, max_ngram_size = `{}`, num_draft_tokens = `{}`",
+            get_mut_arcmutex!(self.target).name(),
+            self.config.max_ngram_size,
+            self.config.num_draft_tokens,
+        )
+    }
+    fn reset_non_granular_state(&self) {
+        get_mut_arcmutex!(self.target).reset_non_granular_state();
+    }
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+    fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
+        None
+    }
+    fn quant_manifest(&self) -> Vec<(String, QuantInfo)> {
+        get_mut_arcmutex!(self.target).quant_manifest()
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for PromptLookupPipeline {
+    fn forward_inputs(
+        &mut self,
+        inputs: Box<dyn Any>,
+        return_raw_logits: bool,
+        return_logprobs: Option<usize>,
+    ) -> Result<ForwardInputsResult> {
+        get_mut_arcmutex!(self.target).forward_inputs(inputs, return_raw_logits, return_logprobs)
+    }
+    async fn sample_causal_gen(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _logits: Vec<Tensor>,
+        _prefix_cacher: &mut PrefixCacheManagerV2,
+        _disable_eos_stop: bool,
+        _rng: SamplingRng,
+    ) -> Result<()> {
+        unreachable!()
+    }
+    async fn step(
+        &mut self,
+        input_seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+        return_raw_logits: bool,
+        prefix_cacher: &mut PrefixCacheManagerV2,
+        disable_eos_stop: bool,
+        rng: SamplingRng,
+        backend_metadata: CacheBackendMetadata<'_>,
+    ) -> Result<Duration> {
+        let draft = if is_prompt || return_raw_logits || input_seqs.len() != 1 {
+            Vec::new()
+        } else {
+            prompt_lookup_draft(
+                input_seqs[0].get_toks(),
+                self.config.max_ngram_size,
+                self.config.num_draft_tokens,
+            )
+        };
+        if draft.is_empty() {
+            return get_mut_arcmutex!(self.target)
+                .step(
+                    input_seqs,
+                    is_prompt,
+                    return_raw_logits,
+                    prefix_cacher,
+                    disable_eos_stop,
+                    rng,
+                    backend_metadata,
+                )
+                .await;
+        }
+
+        match backend_metadata {
+            CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
+                match pre_op {
+                    CacheInstruction::In => self.clone_in_cache(input_seqs),
+                    CacheInstruction::Nothing => (),
+                    CacheInstruction::Reset {
+                        reset_non_granular,
+                        load_preallocated_cache,
+                    } => self.set_none_cache(
+                        input_seqs,
+                        reset_non_granular,
+                        false,
+                        load_preallocated_cache,
+                    ),
+                    _ => unreachable!("Unreachable PRE cache op."),
+                }
+
+                let start = Instant::now();
+                let seq = &mut input_seqs[0];
+
+                // ======================= Run the target model over the last token and the draft. ============================
+                let n_toks = draft.len() + 1;
+                let mut prefill_tokens = vec![*seq.get_toks().last().unwrap()];
+                prefill_tokens.extend(&draft);
+                seq.set_prefill_toks(prefill_tokens);
+
+                let initial_cache_len = match get_mut_arcmutex!(self.target).cache() {
+                    EitherCache::Full(full) => full.lock()[0]
+                        .as_ref()
+                        .map(|(k, _)| k.dims()[2])
+                        .unwrap_or(0),
+                    EitherCache::Normal(normal) => normal.lock().unwrap().0[0].current_seq_len(),
+                };
+
+                let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
+                let device = get_mut_arcmutex!(self.target).device();
+                let no_kv_cache = get_mut_arcmutex!(self.target).get_metadata().no_kv_cache;
+                let inputs = self
+                    .get_processor()
+                    .inputs_processor()
+                    .process_inputs(
+                        self.tokenizer(),
+                        &mut [seq],
+                        true, // use the "prefill" tokens
+                        is_xlora,
+                        &device,
+                        no_kv_cache,
+                        Some((n_toks, initial_cache_len)), // Get the logits of all prefill tokens
+                        false,
+                        None,
+                        None,
+                        None,
+                        get_mut_arcmutex!(self.target).device_mapper(),
+                    )
+                    .nth(0)
+                    .unwrap()
+                    .unwrap()
+                    .inputs;
+
+                let logits = get_mut_arcmutex!(self.target).forward_inputs(inputs, false, None)?;
+                #[allow(irrefutable_let_patterns)]
+                let ForwardInputsResult::CausalGeneration { logits } = logits
+                else {
+                    candle_core::bail!(
+                        "Prompt lookup decoding requires `CausalGeneration` forward results"
+                    );
+                };
+
+                // Reset the prefill tokens
+                seq.reset_prefill_toks();
+
+                // ======================= Verify the draft. ============================
+                let samples = sample_target_sequence_speculative(
+                    logits,
+                    seq,
+                    seq.return_logprobs(),
+                    rng.clone(),
+                    n_toks,
+                )
+                .await?;
+                let target_toks = samples.iter().map(|s| s.sample.token).collect::<Vec<_>>();
+                let n_accepted = accepted_draft_len(&draft, &target_toks);
+
+                // ======================= Narrow the cache to account for rejections ============================
+                let n_not_accepted = n_toks - n_accepted;
+                match get_mut_arcmutex!(self.target).cache() {
+                    EitherCache::Full(full) => {
+                        for (k, v) in full.lock().iter_mut().flatten() {
+                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                        }
+                        if is_xlora {
+                            for (k, v) in full.xlora_lock().iter_mut().flatten() {
+                                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                            }
+                        }
+                    }
+                    EitherCache::Normal(normal) => {
+                        for cache in &mut *normal.lock().unwrap().0 {
+                            cache
+                                .set_len(cache.current_seq_len() - n_not_accepted)
+                                .map_err(|_| candle_core::Error::msg("KV cache set_len failed."))?;
+                        }
+                    }
+                }
+
+                let eos_owned = get_mut_arcmutex!(self.target)
+                    .get_metadata()
+                    .eos_tok
+                    .clone();
+                let eos_tok = if disable_eos_stop {
+                    None
+                } else {
+                    Some(&eos_owned[..])
+                };
+                // Add the tokens to the seq and the trie
+                for accepted in samples.into_iter().take(n_accepted) {
+                    // Do not use the prefix cacher
+                    finish_or_add_toks_to_seq(
+                        self,
+                        prefix_cacher,
+                        seq,
+                        accepted.sample.clone(),
+                        eos_tok,
+                        false,
+                    )
+                    .await?;
+                    match seq.recognizer {
+                        SequenceRecognizer::Llguidance(ref mut llg) => {
+                            llg.commit_token(Some(accepted.sample.token))
+                                .map_err(candle_core::Error::msg)?;
+                        }
+                        SequenceRecognizer::None => {}
+                    }
+                    if !seq.is_running() {
+                        break;
+                    }
+                }
+
+                let end = Instant::now();
+                let exec_duration = end.duration_since(start);
+
+                match post_op {
+                    CacheInstruction::Out => {
+                        self.clone_out_cache(input_seqs);
+                    }
+                    CacheInstruction::Nothing => (),
+                    CacheInstruction::Reset {
+                        reset_non_granular,
+                        load_preallocated_cache,
+                    } => self.set_none_cache(
+                        input_seqs,
+                        reset_non_granular,
+                        false,
+                        load_preallocated_cache,
+                    ),
+                    _ => unreachable!("Unreachable post cache op."),
+                }
+
+                Ok(exec_duration)
+            }
+            CacheBackendMetadata::PagedAttention { .. } => unreachable!(),
+        }
+    }
+    fn category(&self) -> ModelCategory {
+        self.category.clone()
+    }
+}
+
+impl AnyMoePipelineMixin for PromptLookupPipeline {}
+
+#[cfg(test)]
+mod tests {
+    use super::{accepted_draft_len, prompt_lookup_draft};
+
+    /// A deterministic "target model" which continues a repeating pattern after a short preamble.
+    fn next_token(toks: &[u32]) -> u32 {
+        const PATTERN: [u32; 5] = [10, 11, 12, 13, 14];
+        if toks.len() < 3 {
+            return 1 + toks.len() as u32;
+        }
+        PATTERN[(toks.len() - 3) % PATTERN.len()]
+    }
+
+    #[test]
+    fn drafts_from_most_recent_match() {
+        let toks = [1, 2, 3, 9, 1, 2, 4, 1, 2];
+        assert_eq!(prompt_lookup_draft(&toks, 2, 3), vec![4, 1, 2]);
+        assert_eq!(prompt_lookup_draft(&toks, 2, 1), vec![4]);
+        assert!(prompt_lookup_draft(&[1, 2, 3], 2, 3).is_empty());
+        assert!(prompt_lookup_draft(&[], 2, 3).is_empty());
+    }
+
+    #[test]
+    fn prompt_lookup_matches_plain_decoding() {
+        let prompt = vec![1, 2, 3];
+        let n_new = 30;
+
+        let mut plain = prompt.clone();
+        for _ in 0..n_new {
+            plain.push(next_token(&plain));
+        }
+
+        let mut toks = prompt.clone();
+        let mut n_steps = 0;
+        let mut n_accepted_draft = 0;
+        while toks.len() < prompt.len() + n_new {
+            let draft = prompt_lookup_draft(&toks, 3, 4);
+            // One target forward verifies the whole draft
+            let target = (0..=draft.len())
+                .map(|i| next_token(&[&toks[..], &draft[..i]].concat()))
+                .collect::<Vec<_>>();
+            let n_accepted = accepted_draft_len(&draft, &target);
+            n_accepted_draft += n_accepted - 1;
+            toks.extend(&target[..n_accepted]);
+            n_steps += 1;
+        }
+        toks.truncate(prompt.len() + n_new);
+
+        assert_eq!(toks, plain);
+        assert!(n_accepted_draft > 0);
+        assert!(n_steps < n_new);
+    }
+}
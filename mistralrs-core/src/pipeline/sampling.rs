@@ -1,16 +1,19 @@
 use std::sync::Arc;
 
 use candle_core::{DType, Device, Result, Tensor};
-use rand_isaac::Isaac64Rng;
 
 use crate::{
     prefix_cacher::PrefixCacheManagerV2,
-    sampler::Logprobs,
+    sampler::{Logprobs, SamplingRng},
     sequence::{Sequence, SequenceRecognizer, SequenceState, StopReason},
-    tools::parse_text_tools,
+    tools::{parse_text_tools, ToolCallEvent, ToolCallResponse},
+    Constraint,
 };
 
-use super::Pipeline;
+use super::{
+    llg::{constraint_from_llg_grammar, llg_grammar_from_constraint},
+    Pipeline,
+};
 
 macro_rules! fixup_sentencepiece {
     ($txt:expr) => {
@@ -33,19 +36,46 @@ pub(crate) async fn finish_or_add_toks_to_seq(
     use_prefix_cacher: bool,
 ) -> Result<()> {
     let mut is_done = seq.is_done(logprobs.token, eos_tok, this.get_metadata().max_seq_len);
-    seq.add_token(
-        logprobs.clone(),
-        this.get_metadata()
-            .tok_env
-            .as_ref()
-            .ok_or(candle_core::Error::Msg(
-                "`finish_or_add_toks_to_seq` requires the pipeline to have a token trie"
-                    .to_string(),
-            ))?
-            .tok_trie()
-            .decode(&[logprobs.token]),
-        &is_done,
-    );
+    let tok_env = this
+        .get_metadata()
+        .tok_env
+        .clone()
+        .ok_or(candle_core::Error::Msg(
+            "`finish_or_add_toks_to_seq` requires the pipeline to have a token trie".to_string(),
+        ))?;
+    let token_bytes = seq.strip_healed_prefix(tok_env.tok_trie().decode(&[logprobs.token]));
+    // Filter before the token is added so that text which trips the filter is never streamed.
+    if is_done.is_none() {
+        is_done = seq.check_content_filter(&token_bytes);
+    }
+    seq.add_token(logprobs.clone(), token_bytes.clone(), &is_done);
+
+    // Watch for a configured tool call trigger. Once it is seen, the arguments are constrained to the
+    // schema and the sequence stops as soon as they are complete.
+    if let Some(detector) = seq.tool_call_detector.as_mut() {
+        match detector
+            .observe(logprobs.token, &token_bytes)
+            .map_err(candle_core::Error::msg)?
+        {
+            ToolCallEvent::Triggered => {
+                let constraint =
+                    Constraint::JsonSchema(detector.trigger().arguments_schema.clone());
+                let grm = llg_grammar_from_constraint(&constraint)
+                    .map_err(candle_core::Error::msg)?
+                    .ok_or_else(|| {
+                        candle_core::Error::msg("Tool call arguments schema produced no grammar.")
+                    })?;
+                let llg = constraint_from_llg_grammar(tok_env.clone(), grm)
+                    .map_err(candle_core::Error::msg)?;
+                seq.recognizer = SequenceRecognizer::Llguidance(Box::new(llg));
+            }
+            ToolCallEvent::Completed => {
+                seq.set_state(SequenceState::Done(StopReason::Eos));
+                is_done = Some(StopReason::Eos);
+            }
+            ToolCallEvent::None => {}
+        }
+    }
 
     // If we can have a tool and we got a tool, stop the sequence early.
     // Doesn't conflict with the logic below because it does the same thing anyway.
@@ -76,59 +106,71 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     t.prefix_could_be_tool(this, d.as_str())?;
             }
         };
+        // Hold back the arguments of a detected tool call until they are complete.
+        if seq.tool_call_detector.as_ref().is_some_and(|d| d.in_call()) {
+            tool_use_still_possible = true;
+        }
 
-        let send = seq.get_toks().len() % 2 == 0 || is_done.is_some();
         if !tool_use_still_possible || tool_use_is_done {
-            if send {
-                if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
-                    if seq.get_mut_group().is_chat {
-                        let (text_new, tool_calls) =
-                            parse_text_tools(this, delta.as_str(), seq.tools.clone())
-                                .map_err(candle_core::Error::msg)?;
-
-                        if !tool_calls.is_empty() && is_done.is_none() {
-                            is_done = Some(StopReason::Eos);
-                        };
-                        seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
-                            delta: crate::Delta {
-                                content: fixup_sentencepiece!(
-                                    Option text_new.map(ToString::to_string)
-                                ),
-                                role: "assistant".to_string(),
-                                tool_calls: Some(tool_calls).filter(|v| !v.is_empty()),
-                            },
+            let delta = if seq.get_mut_group().stream_raw_bytes {
+                let send = seq.get_toks().len() % 2 == 0 || is_done.is_some();
+                send.then(|| seq.get_delta_bytes())
+                    .flatten()
+                    .map(|bytes| (String::from_utf8_lossy(&bytes).to_string(), Some(bytes)))
+            } else {
+                let policy = seq.get_mut_group().stream_flush_policy;
+                crate::handle_seq_error_ok!(
+                    seq.get_flushed_delta(policy, is_done.is_some()),
+                    seq.responder()
+                )
+                .map(|delta| (delta, None))
+            };
+            if let Some((delta, delta_bytes)) = delta {
+                if seq.get_mut_group().is_chat {
+                    let (text_new, tool_calls) = match detected_tool_call(seq) {
+                        Some(call) => (None, vec![call]),
+                        None => parse_text_tools(this, delta.as_str(), seq.tools.clone())
+                            .map_err(candle_core::Error::msg)?,
+                    };
+
+                    if !tool_calls.is_empty() && is_done.is_none() {
+                        is_done = Some(StopReason::Eos);
+                    };
+                    seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
+                        delta: crate::Delta {
+                            content: fixup_sentencepiece!(
+                                Option text_new.map(ToString::to_string)
+                            ),
+                            role: "assistant".to_string(),
+                            tool_calls: Some(tool_calls).filter(|v| !v.is_empty()),
+                            content_bytes: delta_bytes,
+                        },
+                        index: seq.get_response_index(),
+                        finish_reason: is_done.map(|x| x.to_string()),
+                        top_k_logprobs: seq.take_top_k_logprobs(),
+                        entropy: seq.take_entropies(),
+                        logprobs: if seq.return_logprobs() {
+                            seq.take_chunk_logprob(delta)
+                        } else {
+                            None
+                        },
+                    });
+                } else {
+                    seq.add_streaming_completion_chunk_choice_to_group(
+                        crate::CompletionChunkChoice {
+                            text: fixup_sentencepiece!(delta),
                             index: seq.get_response_index(),
                             finish_reason: is_done.map(|x| x.to_string()),
                             logprobs: if seq.return_logprobs() {
-                                Some(crate::ResponseLogprob {
-                                    token: delta,
-                                    bytes: logprobs.bytes.clone().map(|b| b.into_bytes()),
-                                    logprob: logprobs.logprob,
-                                    top_logprobs: logprobs.top_logprobs.unwrap().clone(),
-                                })
+                                seq.take_chunk_logprob(delta)
                             } else {
                                 None
                             },
-                        });
-                    } else {
-                        seq.add_streaming_completion_chunk_choice_to_group(
-                            crate::CompletionChunkChoice {
-                                text: fixup_sentencepiece!(delta),
-                                index: seq.get_response_index(),
-                                finish_reason: is_done.map(|x| x.to_string()),
-                                logprobs: if seq.return_logprobs() {
-                                    Some(crate::ResponseLogprob {
-                                        token: delta,
-                                        bytes: logprobs.bytes.clone().map(|b| b.into_bytes()),
-                                        logprob: logprobs.logprob,
-                                        top_logprobs: logprobs.top_logprobs.unwrap().clone(),
-                                    })
-                                } else {
-                                    None
-                                },
-                            },
-                        );
-                    }
+                            text_bytes: delta_bytes,
+                            top_k_logprobs: seq.take_top_k_logprobs(),
+                            entropy: seq.take_entropies(),
+                        },
+                    );
                 }
             }
 
@@ -170,138 +212,214 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         Finish the sequence now
         ***********************
         */
-        {
-            seq.set_state(crate::sequence::SequenceState::Done(reason));
-            let (tokenizer, pipeline_name) = {
-                let pipeline_name = this.name();
-                let tokenizer = this.tokenizer();
-                (tokenizer, pipeline_name)
-            };
+        finish_seq(this, prefix_cacher, seq, reason, use_prefix_cacher).await?;
+        this.reset_non_granular_state();
+    }
+
+    Ok(())
+}
+
+/// Finish `seq` with `reason` and send the final response for its group, using its completion so far.
+pub(crate) async fn finish_seq(
+    this: &dyn Pipeline,
+    prefix_cacher: &mut PrefixCacheManagerV2,
+    seq: &mut Sequence,
+    reason: StopReason,
+    use_prefix_cacher: bool,
+) -> Result<()> {
+    seq.set_state(crate::sequence::SequenceState::Done(reason));
+    let (tokenizer, pipeline_name) = {
+        let pipeline_name = this.name();
+        let tokenizer = this.tokenizer();
+        (tokenizer, pipeline_name)
+    };
 
-            let logprobs = if seq.return_logprobs() {
-                let mut logprobs = Vec::new();
-                for logprob in seq.logprobs() {
-                    let resp_logprob = crate::ResponseLogprob {
-                        token: crate::handle_seq_error_ok!(
-                        tokenizer
+    let logprobs = if seq.return_logprobs() {
+        let mut logprobs = Vec::new();
+        for logprob in seq.logprobs() {
+            let resp_logprob = crate::ResponseLogprob {
+                token: crate::handle_seq_error_ok!(
+                    tokenizer
                         .as_ref()
                         .ok_or(candle_core::Error::Msg(
                             "`finish_or_add_toks_to_seq` requires the pipeline to have a tokenizer"
                                 .to_string(),
-                        ))?.decode(&[logprob.token], false),
-                        seq.responder()
-                    ),
-                        bytes: logprob.bytes.clone().map(|b| b.into_bytes()),
-                        logprob: logprob.logprob,
-                        top_logprobs: logprob.top_logprobs.clone().unwrap(),
-                    };
-                    logprobs.push(resp_logprob);
-                }
-                Some(logprobs)
-            } else {
-                None
+                        ))?
+                        .decode(&[logprob.token], false),
+                    seq.responder()
+                ),
+                bytes: logprob.bytes.clone().map(|b| b.into_bytes()),
+                logprob: logprob.logprob,
+                top_logprobs: logprob.top_logprobs.clone().unwrap(),
             };
+            logprobs.push(resp_logprob);
+        }
+        Some(logprobs)
+    } else {
+        None
+    };
 
-            let text = match reason {
-                crate::sequence::StopReason::Length(_)
-                | crate::sequence::StopReason::ModelLength(_)
-                | crate::sequence::StopReason::Eos
-                | crate::sequence::StopReason::StopTok(_)
-                | crate::sequence::StopReason::Canceled => {
-                    String::from_utf8_lossy(seq.completion_bytes())
-                        .trim_start()
-                        .to_string()
-                }
-                crate::sequence::StopReason::StopString {
-                    completion_bytes_pos,
-                    ..
-                } => {
-                    let txt = String::from_utf8_lossy(seq.completion_bytes());
-                    txt[..completion_bytes_pos].trim_start().to_string()
-                }
-                crate::sequence::StopReason::GeneratedImage => {
-                    candle_core::bail!("Stop reason was `GeneratedImage`.")
-                }
-            };
+    let text = match reason {
+        crate::sequence::StopReason::Length(_)
+        | crate::sequence::StopReason::ModelLength(_)
+        | crate::sequence::StopReason::Eos
+        | crate::sequence::StopReason::StopTok(_)
+        | crate::sequence::StopReason::Canceled
+        | crate::sequence::StopReason::Shutdown
+        | crate::sequence::StopReason::ContentFiltered { .. }
+        | crate::sequence::StopReason::RepetitionLoop => {
+            String::from_utf8_lossy(seq.completion_bytes())
+                .trim_start()
+                .to_string()
+        }
+        crate::sequence::StopReason::StopString {
+            completion_bytes_pos,
+            ..
+        } => {
+            let txt = String::from_utf8_lossy(seq.completion_bytes());
+            txt[..completion_bytes_pos].trim_start().to_string()
+        }
+        crate::sequence::StopReason::GeneratedImage => {
+            candle_core::bail!("Stop reason was `GeneratedImage`.")
+        }
+    };
 
-            if seq.get_mut_group().is_chat {
-                let (text_new, tool_calls) =
-                    parse_text_tools(this, text.as_str(), seq.tools.clone())
-                        .map_err(candle_core::Error::msg)?;
-                let choice = crate::Choice {
-                    finish_reason: fixup_sentencepiece!(reason),
-                    index: seq.get_response_index(),
-                    message: crate::ResponseMessage {
-                        content: text_new.map(ToString::to_string),
-                        role: "assistant".to_string(),
-                        tool_calls: Some(tool_calls).filter(|v| !v.is_empty()),
-                    },
-                    logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
-                };
-                seq.add_choice_to_group(choice);
-            } else {
-                let choice = crate::CompletionChoice {
-                    finish_reason: fixup_sentencepiece!(reason),
-                    index: seq.get_response_index(),
-                    text,
-                    logprobs: None,
-                };
-                seq.add_completion_choice_to_group(choice);
-            }
+    if seq.get_mut_group().is_chat {
+        let (text_new, tool_calls) = match detected_tool_call(seq) {
+            Some(call) => (None, vec![call]),
+            None => parse_text_tools(this, text.as_str(), seq.tools.clone())
+                .map_err(candle_core::Error::msg)?,
+        };
+        let choice = crate::Choice {
+            finish_reason: fixup_sentencepiece!(reason),
+            index: seq.get_response_index(),
+            message: crate::ResponseMessage {
+                content: text_new.map(ToString::to_string),
+                role: "assistant".to_string(),
+                tool_calls: Some(tool_calls).filter(|v| !v.is_empty()),
+            },
+            logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+        };
+        seq.add_choice_to_group(choice);
+    } else {
+        let choice = crate::CompletionChoice {
+            finish_reason: fixup_sentencepiece!(reason),
+            index: seq.get_response_index(),
+            text,
+            logprobs: None,
+        };
+        seq.add_completion_choice_to_group(choice);
+    }
 
-            if use_prefix_cacher {
-                prefix_cacher.add_sequence(seq);
-                prefix_cacher.evict_to_cpu()?;
-            }
+    if use_prefix_cacher {
+        prefix_cacher.add_sequence(seq);
+        prefix_cacher.evict_to_cpu()?;
+    }
 
-            let group = seq.get_mut_group();
-            if group.is_chat {
-                group
-                    .maybe_send_chat_done_response(
-                        crate::ChatCompletionResponse {
-                            id: seq.id().to_string(),
-                            choices: group.get_choices().to_vec(),
-                            created: seq.creation_time(),
-                            model: pipeline_name,
-                            system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
-                            object: "chat.completion".to_string(),
-                            usage: group.get_usage(),
-                        },
-                        seq.responder(),
-                    )
-                    .await
-                    .map_err(candle_core::Error::msg)?;
-            } else {
-                group
-                    .maybe_send_completion_done_response(
-                        crate::CompletionResponse {
-                            id: seq.id().to_string(),
-                            choices: group.get_completion_choices().to_vec(),
-                            created: seq.creation_time(),
-                            model: pipeline_name,
-                            system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
-                            object: "text_completion".to_string(),
-                            usage: group.get_usage(),
-                        },
-                        seq.responder(),
-                    )
-                    .await
-                    .map_err(candle_core::Error::msg)?;
-            }
-        }
-        this.reset_non_granular_state();
+    let group = seq.get_mut_group();
+    if group.is_chat {
+        group
+            .maybe_send_chat_done_response(
+                crate::ChatCompletionResponse {
+                    id: seq.id().to_string(),
+                    choices: group.get_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model: pipeline_name,
+                    system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                    object: "chat.completion".to_string(),
+                    usage: group.get_usage(),
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
+    } else {
+        group
+            .maybe_send_completion_done_response(
+                crate::CompletionResponse {
+                    id: seq.id().to_string(),
+                    choices: group.get_completion_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model: pipeline_name,
+                    system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                    object: "text_completion".to_string(),
+                    usage: group.get_usage(),
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
+    }
+    Ok(())
+}
+
+/// Finish a sequence which is still in flight with its partial output and [`StopReason::Shutdown`],
+/// without generating any more tokens. The caller resets the non-granular state afterwards.
+pub(crate) async fn finish_seq_on_shutdown(
+    this: &dyn Pipeline,
+    prefix_cacher: &mut PrefixCacheManagerV2,
+    seq: &mut Sequence,
+) -> Result<()> {
+    let reason = StopReason::Shutdown;
+    if !seq.get_mut_group().is_streaming {
+        return finish_seq(this, prefix_cacher, seq, reason, false).await;
     }
 
+    seq.set_state(SequenceState::Done(reason));
+    // Flush whatever has not been streamed yet along with the finish reason.
+    let delta = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()).unwrap_or_default();
+    if seq.get_mut_group().is_chat {
+        seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
+            delta: crate::Delta {
+                content: Some(fixup_sentencepiece!(delta)),
+                role: "assistant".to_string(),
+                tool_calls: None,
+                content_bytes: None,
+            },
+            index: seq.get_response_index(),
+            finish_reason: Some(reason.to_string()),
+            logprobs: None,
+            top_k_logprobs: seq.take_top_k_logprobs(),
+            entropy: seq.take_entropies(),
+        });
+    } else {
+        seq.add_streaming_completion_chunk_choice_to_group(crate::CompletionChunkChoice {
+            text: fixup_sentencepiece!(delta),
+            index: seq.get_response_index(),
+            finish_reason: Some(reason.to_string()),
+            logprobs: None,
+            text_bytes: None,
+            top_k_logprobs: seq.take_top_k_logprobs(),
+            entropy: seq.take_entropies(),
+        });
+    }
+
+    let usage = seq.get_mut_group().get_usage();
+    seq.get_mut_group().total_prompt_toks = 0;
+    seq.get_mut_group().total_toks = 0;
+    // The client may already be gone, in which case there is nobody to send the partial output to.
+    let _ = seq
+        .get_mut_group()
+        .maybe_send_streaming_response(seq, this.name(), Some(usage))
+        .await;
     Ok(())
 }
 
+/// The tool call found by the sequence's [`crate::tools::ToolCallDetector`], if it is complete.
+fn detected_tool_call(seq: &Sequence) -> Option<ToolCallResponse> {
+    seq.tool_call_detector
+        .as_ref()
+        .and_then(|d| d.call())
+        .cloned()
+}
+
 pub async fn sample_and_add_toks(
     this: &dyn Pipeline,
     seqs: &mut [&mut Sequence],
     logits_seq: Vec<Tensor>,
     prefix_cacher: &mut PrefixCacheManagerV2,
     disable_eos_stop: bool,
-    rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    rng: SamplingRng,
 ) -> Result<()> {
     let seqs_len = seqs.len();
     debug_assert_eq!(logits_seq.len(), seqs_len);
@@ -346,7 +464,7 @@ pub async fn sample_sequence(
     logits: Tensor,
     seq: &mut Sequence,
     return_logprobs: bool,
-    rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    rng: SamplingRng,
     use_async_pool: bool,
     add_to_trie: bool,
     sample_speculative: bool,
@@ -355,6 +473,7 @@ pub async fn sample_sequence(
 
     let sampler = seq.sampler();
     let ctx_clone = seq.get_toks().to_vec();
+    let prompt_len = seq.prompt_tokens();
     let rng_clone = rng.clone();
     let logits_clone = logits.clone();
     let first_lobprobs_response = if use_async_pool {
@@ -362,6 +481,7 @@ pub async fn sample_sequence(
             sampler.sample(
                 logits_clone,
                 &ctx_clone,
+                prompt_len,
                 return_logprobs,
                 rng_clone,
                 sample_speculative,
@@ -372,6 +492,7 @@ pub async fn sample_sequence(
         sampler.sample(
             logits_clone,
             &ctx_clone,
+            prompt_len,
             return_logprobs,
             rng_clone,
             sample_speculative,
@@ -418,6 +539,7 @@ pub async fn sample_sequence(
                     sampler.sample(
                         new_logits,
                         &ctx_clone,
+                        prompt_len,
                         return_logprobs,
                         rng_clone,
                         sample_speculative,
@@ -428,6 +550,7 @@ pub async fn sample_sequence(
                 sampler.sample(
                     new_logits,
                     &ctx_clone,
+                    prompt_len,
                     return_logprobs,
                     rng_clone,
                     sample_speculative,
@@ -459,7 +582,7 @@ pub async fn sample_target_sequence_speculative(
     logits: Tensor,
     seq: &mut Sequence,
     return_logprobs: bool,
-    rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    rng: SamplingRng,
     n_toks: usize,
 ) -> Result<Vec<SpeculativeSample>> {
     let mut sampled = Vec::new();
@@ -0,0 +1,333 @@
+//! Compile a subset of JSON schema to a regex which matches only schema-valid, compact JSON.
+//!
+//! Supported: objects (required properties are emitted first, in `required` order), strings
+//! (`pattern`, `minLength`, `maxLength`), numbers, integers, booleans, null, arrays, `enum`,
+//! `const`, `anyOf`/`oneOf` and type unions. `$ref` and `additionalProperties` are not supported.
+
+use anyhow::Result;
+use serde_json::Value;
+
+/// Optional single space allowed between tokens.
+const WS: &str = "[ ]?";
+/// One JSON string character, including escapes.
+const STRING_CHAR: &str = r#"(?:[^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+const INTEGER: &str = "-?(?:0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(?:0|[1-9][0-9]*)(?:\.[0-9]+)?(?:[eE][+-]?[0-9]+)?";
+const BOOLEAN: &str = "(?:true|false)";
+const NULL: &str = "null";
+
+/// Compile a JSON schema to a regex.
+pub fn json_schema_to_regex(schema: &Value) -> Result<String> {
+    match schema {
+        Value::Bool(true) => Ok(value_regex()),
+        Value::Bool(false) => anyhow::bail!("Schema `false` matches no values."),
+        Value::Object(schema) => {
+            if schema.contains_key("$ref") {
+                anyhow::bail!("`$ref` is not supported when compiling a JSON schema to a regex.");
+            }
+            if let Some(value) = schema.get("const") {
+                return Ok(literal(value));
+            }
+            if let Some(values) = schema.get("enum") {
+                let Some(values) = values.as_array() else {
+                    anyhow::bail!("Expected `enum` to be an array.");
+                };
+                return Ok(alternatives(values.iter().map(literal).collect()));
+            }
+            if let Some(options) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+                let Some(options) = options.as_array() else {
+                    anyhow::bail!("Expected `anyOf`/`oneOf` to be an array.");
+                };
+                return Ok(alternatives(
+                    options
+                        .iter()
+                        .map(json_schema_to_regex)
+                        .collect::<Result<_>>()?,
+                ));
+            }
+            match schema.get("type") {
+                Some(Value::String(ty)) => type_regex(ty, schema),
+                Some(Value::Array(tys)) => Ok(alternatives(
+                    tys.iter()
+                        .map(|ty| match ty.as_str() {
+                            Some(ty) => type_regex(ty, schema),
+                            None => anyhow::bail!("Expected `type` entries to be strings."),
+                        })
+                        .collect::<Result<_>>()?,
+                )),
+                Some(_) => anyhow::bail!("Expected `type` to be a string or array of strings."),
+                None if schema.contains_key("properties") => type_regex("object", schema),
+                None => Ok(value_regex()),
+            }
+        }
+        _ => anyhow::bail!("Expected the JSON schema to be an object or boolean."),
+    }
+}
+
+fn type_regex(ty: &str, schema: &serde_json::Map<String, Value>) -> Result<String> {
+    match ty {
+        "object" => object_regex(schema),
+        "array" => {
+            let item = match schema.get("items") {
+                Some(items) => json_schema_to_regex(items)?,
+                None => value_regex(),
+            };
+            Ok(format!(r"\[{WS}(?:{item}(?:{WS},{WS}{item})*)?{WS}\]"))
+        }
+        "string" => string_regex(schema),
+        "integer" => Ok(INTEGER.to_string()),
+        "number" => Ok(NUMBER.to_string()),
+        "boolean" => Ok(BOOLEAN.to_string()),
+        "null" => Ok(NULL.to_string()),
+        other => anyhow::bail!("Unsupported JSON schema type `{other}`."),
+    }
+}
+
+fn string_regex(schema: &serde_json::Map<String, Value>) -> Result<String> {
+    if let Some(pattern) = schema.get("pattern") {
+        let Some(pattern) = pattern.as_str() else {
+            anyhow::bail!("Expected `pattern` to be a string.");
+        };
+        let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+        let pattern = pattern.strip_suffix('$').unwrap_or(pattern);
+        return Ok(format!("\"(?:{pattern})\""));
+    }
+    let min = schema.get("minLength").and_then(Value::as_u64).unwrap_or(0);
+    let repeat = match schema.get("maxLength").and_then(Value::as_u64) {
+        Some(max) => format!("{{{min},{max}}}"),
+        None if min == 0 => "*".to_string(),
+        None => format!("{{{min},}}"),
+    };
+    Ok(format!("\"{STRING_CHAR}{repeat}\""))
+}
+
+/// Required properties are emitted first, in `required` order, followed by optional properties.
+fn object_regex(schema: &serde_json::Map<String, Value>) -> Result<String> {
+    let Some(properties) = schema.get("properties") else {
+        return Ok(format!(r"\{{{WS}\}}"));
+    };
+    let Some(properties) = properties.as_object() else {
+        anyhow::bail!("Expected `properties` to be an object.");
+    };
+    let required = match schema.get("required") {
+        Some(Value::Array(required)) => required
+            .iter()
+            .map(|key| {
+                key.as_str()
+                    .ok_or_else(|| anyhow::anyhow!("Expected `required` entries to be strings."))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Some(_) => anyhow::bail!("Expected `required` to be an array."),
+        None => Vec::new(),
+    };
+    for key in &required {
+        if !properties.contains_key(*key) {
+            anyhow::bail!("Required property `{key}` is not in `properties`.");
+        }
+    }
+
+    let prop_regex = |key: &str, value: &Value| -> Result<String> {
+        Ok(format!(
+            "{}{WS}:{WS}{}",
+            literal(&Value::String(key.to_string())),
+            json_schema_to_regex(value)?
+        ))
+    };
+    let required_props = required
+        .iter()
+        .map(|key| prop_regex(key, &properties[*key]))
+        .collect::<Result<Vec<_>>>()?;
+    let optional_props = properties
+        .iter()
+        .filter(|(key, _)| !required.contains(&key.as_str()))
+        .map(|(key, value)| prop_regex(key, value))
+        .collect::<Result<Vec<_>>>()?;
+
+    let sep = format!("{WS},{WS}");
+    let body = if required_props.is_empty() {
+        // Any optional property may come first, followed by any of the later ones.
+        let options = (0..optional_props.len())
+            .map(|i| {
+                let mut option = optional_props[i].clone();
+                for prop in &optional_props[i + 1..] {
+                    option.push_str(&format!("(?:{sep}{prop})?"));
+                }
+                option
+            })
+            .collect::<Vec<_>>();
+        if options.is_empty() {
+            String::new()
+        } else {
+            format!("{}?", alternatives(options))
+        }
+    } else {
+        let mut body = required_props.join(&sep);
+        for prop in &optional_props {
+            body.push_str(&format!("(?:{sep}{prop})?"));
+        }
+        body
+    };
+    Ok(format!(r"\{{{WS}{body}{WS}\}}"))
+}
+
+/// Any JSON value, with nesting limited to keep the regex finite.
+fn value_regex() -> String {
+    let string = format!("\"{STRING_CHAR}*\"");
+    let scalar = format!("(?:{string}|{NUMBER}|{BOOLEAN}|{NULL})");
+    let array = format!(r"\[{WS}(?:{scalar}(?:{WS},{WS}{scalar})*)?{WS}\]");
+    let member = format!("{string}{WS}:{WS}{scalar}");
+    let object = format!(r"\{{{WS}(?:{member}(?:{WS},{WS}{member})*)?{WS}\}}");
+    format!("(?:{scalar}|{array}|{object})")
+}
+
+fn literal(value: &Value) -> String {
+    regex::escape(&value.to_string())
+}
+
+fn alternatives(options: Vec<String>) -> String {
+    format!("(?:{})", options.join("|"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::{json, Value};
+    use tokenizers::Tokenizer;
+
+    use super::json_schema_to_regex;
+    use crate::{
+        pipeline::llg::{build_tok_env, constraint_from_llg_grammar, llg_grammar_from_constraint},
+        Constraint,
+    };
+
+    const EOS: u32 = 256;
+
+    /// GPT-2 byte-level alphabet, indexed by byte.
+    fn byte_level_chars() -> Vec<char> {
+        let mut bytes: Vec<u32> = (u32::from(b'!')..=u32::from(b'~'))
+            .chain(0xA1..=0xAC)
+            .chain(0xAE..=0xFF)
+            .collect();
+        let mut chars = bytes.clone();
+        let mut n = 0;
+        for b in 0..256 {
+            if !bytes.contains(&b) {
+                bytes.push(b);
+                chars.push(256 + n);
+                n += 1;
+            }
+        }
+        let mut table = vec![' '; 256];
+        for (b, c) in bytes.into_iter().zip(chars) {
+            table[b as usize] = char::from_u32(c).unwrap();
+        }
+        table
+    }
+
+    /// A byte-level tokenizer where token `i < 256` is byte `i` and token 256 is EOS.
+    fn byte_tokenizer() -> Tokenizer {
+        let vocab = byte_level_chars()
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| (c.to_string(), json!(i)))
+            .chain([("</s>".to_string(), json!(EOS))])
+            .collect::<serde_json::Map<_, _>>();
+        let byte_level = json!({
+            "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true
+        });
+        let tokenizer = json!({
+            "version": "1.0",
+            "added_tokens": [{
+                "id": EOS, "content": "</s>", "single_word": false, "lstrip": false,
+                "rstrip": false, "normalized": false, "special": true
+            }],
+            "pre_tokenizer": byte_level,
+            "decoder": byte_level,
+            "model": {"type": "BPE", "vocab": vocab, "merges": []},
+        });
+        Tokenizer::from_str(&tokenizer.to_string()).unwrap()
+    }
+
+    #[test]
+    fn schema_regex_matches_conforming_json() -> anyhow::Result<()> {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "kind": {"enum": ["cat", "dog"]},
+                "tags": {"type": "array", "items": {"type": "string"}},
+            },
+            "required": ["name", "kind"],
+        });
+        let re = regex::Regex::new(&format!("^{}$", json_schema_to_regex(&schema)?))?;
+
+        assert!(re.is_match(r#"{"name": "Tom \"T\"", "kind": "cat"}"#));
+        assert!(re.is_match(r#"{"name":"Rex","kind":"dog","age":3,"tags":["a", "b"]}"#));
+        assert!(!re.is_match(r#"{"kind": "cat", "name": "Tom"}"#));
+        assert!(!re.is_match(r#"{"name": "Tom", "kind": "cow"}"#));
+        assert!(!re.is_match(r#"{"name": "Tom"}"#));
+        assert!(!re.is_match(r#"{"name": "Tom", "kind": "cat", "age": 1.5}"#));
+        Ok(())
+    }
+
+    #[test]
+    fn schema_constrains_sampling() -> anyhow::Result<()> {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "city": {"type": "string"},
+            },
+            "required": ["name", "city"],
+        });
+        let tok_env = build_tok_env(byte_tokenizer());
+        let grm = llg_grammar_from_constraint(&Constraint::JsonSchemaRegex(schema))?.unwrap();
+        let mut llg = constraint_from_llg_grammar(tok_env, grm)?;
+
+        // The "model" wants to emit the keys out of order with an extra key. When its token is
+        // masked out, the sampler falls back to the first allowed token in a fixed preference.
+        let proposed = br#"{"city": "Paris", "name": "Alice", "extra": 1}"#;
+        let fallback = b"\"},: ";
+        let mut output = Vec::new();
+        for step in 0..256 {
+            let step_res = llg.compute_mask().map_err(anyhow::Error::msg)?;
+            if step_res.is_stop() {
+                break;
+            }
+            let mask = step_res.sample_mask.expect("expected a sampling mask");
+            let tok = proposed
+                .get(step)
+                .map(|&b| u32::from(b))
+                .filter(|&tok| mask.is_allowed(tok))
+                .or_else(|| {
+                    fallback
+                        .iter()
+                        .map(|&b| u32::from(b))
+                        .find(|&tok| mask.is_allowed(tok))
+                })
+                .or_else(|| (0..=EOS).find(|&tok| mask.is_allowed(tok)))
+                .expect("no allowed token");
+            if tok == EOS {
+                break;
+            }
+            llg.commit_token(Some(tok)).map_err(anyhow::Error::msg)?;
+            output.push(tok as u8);
+            if serde_json::from_slice::<Value>(&output).is_ok() {
+                break;
+            }
+        }
+
+        let text = String::from_utf8(output)?;
+        let value: Value = serde_json::from_str(&text)?;
+        let fields = value.as_object().expect("expected an object");
+        assert_eq!(fields.len(), 2, "{text}");
+        assert!(
+            fields["name"].is_string() && fields["city"].is_string(),
+            "{text}"
+        );
+        assert!(text.find("\"name\"") < text.find("\"city\""), "{text}");
+        Ok(())
+    }
+}
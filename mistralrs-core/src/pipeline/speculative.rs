@@ -1,14 +1,13 @@
 use std::{
     any::Any,
     iter::zip,
-    sync::{Arc, Mutex},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use anyhow::Result as anyhowResult;
 use candle_core::{Device, IndexOp, Result, Tensor};
-use mistralrs_quant::IsqType;
-use rand_isaac::Isaac64Rng;
+use mistralrs_quant::{IsqType, QuantInfo};
 use tokenizers::Tokenizer;
 use tracing::warn;
 
@@ -19,6 +18,7 @@ use crate::{
         finish_or_add_toks_to_seq, sample_sequence, sample_target_sequence_speculative,
     },
     prefix_cacher::PrefixCacheManagerV2,
+    sampler::SamplingRng,
     sequence::{Sequence, SequenceRecognizer},
     DeviceMapSetting, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource, TryIntoDType,
 };
@@ -37,6 +37,27 @@ pub struct SpeculativeLoader {
     pub config: SpeculativeConfig,
 }
 
+impl SpeculativeLoader {
+    /// If configured, make the draft loader reference the loaded target's embeddings.
+    fn share_embeddings(
+        &self,
+        target: &Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>,
+    ) -> anyhowResult<()> {
+        if !self.config.share_embeddings {
+            return Ok(());
+        }
+        let shared = get_mut_arcmutex!(target)
+            .shared_embeddings()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Target model `{}` cannot share its embeddings with the draft model.",
+                    self.target.get_id()
+                )
+            })?;
+        self.draft.set_shared_embeddings(shared)
+    }
+}
+
 impl Loader for SpeculativeLoader {
     #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     fn load_model_from_hf(
@@ -69,6 +90,7 @@ impl Loader for SpeculativeLoader {
             in_situ_quant,
             paged_attn_config,
         )?;
+        self.share_embeddings(&target)?;
         let draft = self.draft.load_model_from_hf(
             revision,
             token_source,
@@ -115,6 +137,7 @@ impl Loader for SpeculativeLoader {
             in_situ_quant,
             paged_attn_config,
         )?;
+        self.share_embeddings(&target)?;
         let draft = self.draft.load_model_from_path(
             paths,
             dtype,
@@ -170,6 +193,9 @@ pub struct SpeculativePipeline {
 pub struct SpeculativeConfig {
     /// γ completions to run of the draft model
     pub gamma: usize,
+    /// Have the draft model reference the target model's input embedding and LM head instead of
+    /// loading its own. The two must have the same vocab and hidden sizes.
+    pub share_embeddings: bool,
 }
 
 impl SpeculativePipeline {
@@ -306,6 +332,9 @@ impl MetadataMixin for SpeculativePipeline {
     fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
         None
     }
+    fn quant_manifest(&self) -> Vec<(String, QuantInfo)> {
+        get_mut_arcmutex!(self.target).quant_manifest()
+    }
 }
 
 #[async_trait::async_trait]
@@ -314,7 +343,11 @@ impl Pipeline for SpeculativePipeline {
         &mut self,
         _inputs: Box<dyn Any>,
         _return_raw_logits: bool,
+        return_logprobs: Option<usize>,
     ) -> Result<ForwardInputsResult> {
+        if return_logprobs.is_some() {
+            candle_core::bail!("Speculative decoding does not support returning logprobs.");
+        }
         unreachable!()
     }
     async fn sample_causal_gen(
@@ -323,7 +356,7 @@ impl Pipeline for SpeculativePipeline {
         _logits: Vec<Tensor>,
         _prefix_cacher: &mut PrefixCacheManagerV2,
         _disable_eos_stop: bool,
-        _rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        _rng: SamplingRng,
     ) -> Result<()> {
         unreachable!()
     }
@@ -334,7 +367,7 @@ impl Pipeline for SpeculativePipeline {
         _return_raw_logits: bool,
         prefix_cacher: &mut PrefixCacheManagerV2,
         disable_eos_stop: bool,
-        rng: Arc<Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
         backend_metadata: CacheBackendMetadata<'_>,
     ) -> Result<Duration> {
         match backend_metadata {
@@ -387,7 +420,8 @@ impl Pipeline for SpeculativePipeline {
                         .unwrap()
                         .unwrap()
                         .inputs;
-                    let logits = get_mut_arcmutex!(self.draft).forward_inputs(inputs, false)?;
+                    let logits =
+                        get_mut_arcmutex!(self.draft).forward_inputs(inputs, false, None)?;
                     #[allow(irrefutable_let_patterns)]
                     let ForwardInputsResult::CausalGeneration { logits } = logits
                     else {
@@ -461,7 +495,7 @@ impl Pipeline for SpeculativePipeline {
                     .unwrap()
                     .inputs;
 
-                let logits = get_mut_arcmutex!(self.target).forward_inputs(inputs, false)?;
+                let logits = get_mut_arcmutex!(self.target).forward_inputs(inputs, false, None)?;
                 #[allow(irrefutable_let_patterns)]
                 let ForwardInputsResult::CausalGeneration { logits } = logits
                 else {
@@ -0,0 +1,114 @@
+use std::{collections::HashMap, path::Path};
+
+use candle_core::{DType, Device, Result, Tensor};
+
+/// A linear head mapping the final hidden state to a scalar, such as the value head of a reward model.
+///
+/// It is loaded from a safetensors file with a single `*weight` tensor of shape `(hidden_size,)` or
+/// `(1, hidden_size)`, and optionally a `*bias` tensor with one element.
+pub(crate) struct ValueHead {
+    weight: Tensor,
+    bias: Option<f32>,
+}
+
+impl ValueHead {
+    pub(crate) fn load(path: &Path, hidden_size: usize, device: &Device) -> Result<Self> {
+        Self::from_tensors(candle_core::safetensors::load(path, device)?, hidden_size)
+    }
+
+    fn from_tensors(tensors: HashMap<String, Tensor>, hidden_size: usize) -> Result<Self> {
+        let find = |suffix: &str| -> Result<Option<&Tensor>> {
+            let mut matches = tensors.iter().filter(|(name, _)| name.ends_with(suffix));
+            match (matches.next(), matches.next()) {
+                (Some((_, tensor)), None) => Ok(Some(tensor)),
+                (None, _) => Ok(None),
+                (Some(_), Some(_)) => {
+                    candle_core::bail!("Expected a single value head `{suffix}` tensor.")
+                }
+            }
+        };
+
+        let Some(weight) = find("weight")? else {
+            candle_core::bail!("Value head has no weight tensor.");
+        };
+        let in_dim = match weight.dims() {
+            [in_dim] | [1, in_dim] => *in_dim,
+            dims => candle_core::bail!(
+                "Value head weight must have shape (hidden_size,) or (1, hidden_size), got {dims:?}."
+            ),
+        };
+        if in_dim != hidden_size {
+            candle_core::bail!(
+                "Value head input dimension {in_dim} does not match the model hidden size {hidden_size}."
+            );
+        }
+        let bias = match find("bias")? {
+            Some(bias) if bias.elem_count() == 1 => {
+                Some(bias.flatten_all()?.to_dtype(DType::F32)?.to_vec1::<f32>()?[0])
+            }
+            Some(bias) => candle_core::bail!(
+                "Value head bias must have a single element, got shape {:?}.",
+                bias.dims()
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            weight: weight.flatten_all()?.to_dtype(DType::F32)?,
+            bias,
+        })
+    }
+
+    /// Score a single hidden state of shape `(hidden_size,)`.
+    pub(crate) fn forward(&self, hidden: &Tensor) -> Result<f32> {
+        let hidden = hidden
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_device(self.weight.device())?;
+        let score = (hidden * &self.weight)?.sum_all()?.to_scalar::<f32>()?;
+        Ok(score + self.bias.unwrap_or(0.))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{Device, Result, Tensor};
+
+    use super::ValueHead;
+
+    #[test]
+    fn value_head_matches_manual_projection() -> Result<()> {
+        let dev = Device::Cpu;
+        let hidden_size = 16;
+        let weight = Tensor::randn(0f32, 1., (1, hidden_size), &dev)?;
+        let bias = Tensor::new(&[0.25f32], &dev)?;
+        let path =
+            std::env::temp_dir().join(format!("value_head_{}.safetensors", std::process::id()));
+        candle_core::safetensors::save(
+            &HashMap::from([
+                ("v_head.weight".to_string(), weight.clone()),
+                ("v_head.bias".to_string(), bias),
+            ]),
+            &path,
+        )?;
+        let head = ValueHead::load(&path, hidden_size, &dev);
+        let wrong_dim = ValueHead::load(&path, hidden_size + 1, &dev);
+        std::fs::remove_file(&path)?;
+        let head = head?;
+        assert!(wrong_dim.is_err());
+
+        let hidden = Tensor::randn(0f32, 1., hidden_size, &dev)?;
+        let expected = hidden
+            .to_vec1::<f32>()?
+            .iter()
+            .zip(weight.flatten_all()?.to_vec1::<f32>()?)
+            .map(|(h, w)| h * w)
+            .sum::<f32>()
+            + 0.25;
+        let score = head.forward(&hidden)?;
+        assert!((score - expected).abs() < 1e-5, "{score} != {expected}");
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use super::cache_manager::{FullCacheManager, NormalCacheManager};
 use super::isq::ImatrixDataSource;
 use super::isq::UqffFullSer;
+use super::pair_paged_attn_meta;
 use super::{
     get_model_paths, get_xlora_paths, AdapterKind, AnyMoePipelineMixin, CacheManager,
     CacheManagerMixin, EitherCache, ForwardInputsResult, Gemma3Loader, GeneralMetadata,
@@ -21,6 +22,7 @@ use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::text_models_inputs_processor::make_prompt_chunk;
 use crate::pipeline::{get_chat_template, ChatTemplate, IsqOrganization, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManagerV2;
+use crate::sampler::SamplingRng;
 use crate::sequence::Sequence;
 use crate::utils::tokenizer::get_tokenizer;
 use crate::utils::varbuilder_utils::DeviceForLoadTensor;
@@ -38,8 +40,7 @@ use candle_core::{Device, Tensor, Var};
 use hf_hub::Cache;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use indicatif::MultiProgress;
-use mistralrs_quant::{AfqLayer, GgufMatMul, HqqLayer, IsqType, QuantizedSerdeType};
-use rand_isaac::Isaac64Rng;
+use mistralrs_quant::{AfqLayer, GgufMatMul, HqqLayer, IsqType, QuantInfo, QuantizedSerdeType};
 use regex_automata::meta::Regex;
 use std::any::Any;
 use std::borrow::Cow;
@@ -65,6 +66,7 @@ pub struct VisionPipeline {
     silent: bool,
     prefixer: Arc<dyn VisionPromptPrefixer>,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
+    quant_manifest: Vec<(String, QuantInfo)>,
 
     // For full UQFF serialization
     template_filename: Option<PathBuf>,
@@ -113,11 +115,16 @@ pub struct VisionSpecificConfig {
     pub prompt_chunksize: Option<NonZeroUsize>,
     pub topology: Option<Topology>,
     pub write_uqff: Option<PathBuf>,
+    /// Maximum size in MB of each UQFF shard written to `write_uqff`. Defaults to 10 GB.
+    pub uqff_shard_size_mb: Option<usize>,
     pub from_uqff: Option<Vec<PathBuf>>,
     pub max_edge: Option<u32>,
     pub imatrix: Option<PathBuf>,
     pub calibration_file: Option<PathBuf>,
     pub hf_cache_path: Option<PathBuf>,
+    /// Download a safetensors shard from the Hugging Face Hub again, up to this many times, if the
+    /// cached copy is corrupt or truncated.
+    pub shard_download_retries: usize,
 }
 
 impl VisionLoaderBuilder {
@@ -216,7 +223,8 @@ impl Loader for VisionLoader {
             None,
             None,
             silent,
-            self.config.from_uqff.is_some()
+            self.config.from_uqff.is_some(),
+            self.config.shard_download_retries
         );
         if let Some(from_uqff) = self.config.from_uqff.clone() {
             *self.from_uqff.write().unwrap() = Some(get_uqff_paths!(&from_uqff, self, silent));
@@ -609,6 +617,7 @@ impl Loader for VisionLoader {
                 in_situ_quant,
                 device.clone(),
                 self.config.topology.as_ref(),
+                &[],
                 silent,
                 imatrix_source,
                 IsqOrganization::Default,
@@ -620,6 +629,7 @@ impl Loader for VisionLoader {
                     config: config.clone(),
                     processor_filename: paths.get_processor_config(),
                     preprocessor_filename: paths.get_preprocessor_config(),
+                    shard_size_mb: self.config.uqff_shard_size_mb,
                 },
                 Arc::new(MultiProgress::new()),
             )?;
@@ -641,6 +651,13 @@ impl Loader for VisionLoader {
                 paged_attn_config.mem_gpu,
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
+                paged_attn_config.cache_type,
+                &self
+                    .config
+                    .topology
+                    .as_ref()
+                    .map(Topology::kv_cache_types)
+                    .unwrap_or_default(),
                 dtype,
                 model.config(),
                 &device,
@@ -660,9 +677,14 @@ impl Loader for VisionLoader {
             EitherCache::Full(full) => full.lock().len(),
             EitherCache::Normal(normal) => normal.lock().unwrap().0.len(),
         };
+        let generation_sampling = gen_conf
+            .as_ref()
+            .map(|gen_conf| gen_conf.sampling().clone())
+            .unwrap_or_default();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
         let sliding_window = model.config().sliding_window;
         let model_metadata = Arc::new(model.config().clone());
+        let quant_manifest = model.quant_manifest();
         Ok(Arc::new(Mutex::new(VisionPipeline {
             model,
             tokenizer: tokenizer.into(),
@@ -683,6 +705,7 @@ impl Loader for VisionLoader {
                 cache_engine,
                 prompt_chunksize: self.config.prompt_chunksize,
                 model_metadata: Some(model_metadata),
+                generation_sampling,
             }),
             processor,
             prefixer: self.inner.prefixer(),
@@ -695,6 +718,7 @@ impl Loader for VisionLoader {
             processor_filename: paths.get_processor_config().clone(),
             preprocessor_filename: paths.get_preprocessor_config().clone(),
             mapper: pipeline_mapper,
+            quant_manifest,
             imatrix: self.config.imatrix.clone(),
         })))
     }
@@ -728,6 +752,7 @@ impl IsqPipelineMixin for VisionPipeline {
                 Some(dtype),
                 device,
                 self.topology.as_ref(),
+                &[],
                 self.silent,
                 self.imatrix.as_ref().map(ImatrixDataSource::File),
                 IsqOrganization::Default,
@@ -739,10 +764,13 @@ impl IsqPipelineMixin for VisionPipeline {
                     config: self.config.clone(),
                     processor_filename: &self.processor_filename,
                     preprocessor_filename: &self.preprocessor_filename,
+                    shard_size_mb: None,
                 },
                 Arc::new(MultiProgress::new()),
             )
-            .map_err(anyhow::Error::msg)
+            .map_err(anyhow::Error::msg)?;
+        self.quant_manifest = self.model.quant_manifest();
+        Ok(())
     }
 }
 
@@ -805,6 +833,9 @@ impl MetadataMixin for VisionPipeline {
     fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
         Some(&*self.mapper)
     }
+    fn quant_manifest(&self) -> Vec<(String, QuantInfo)> {
+        self.quant_manifest.clone()
+    }
 }
 
 #[async_trait::async_trait]
@@ -813,6 +844,7 @@ impl Pipeline for VisionPipeline {
         &mut self,
         inputs: Box<dyn Any>,
         return_raw_logits: bool,
+        return_logprobs: Option<usize>,
     ) -> candle_core::Result<ForwardInputsResult> {
         let ModelInputs {
             input_ids,
@@ -823,22 +855,18 @@ impl Pipeline for VisionPipeline {
             model_specific_args,
             paged_attn_meta,
             flash_meta,
+            use_cache,
         } = *inputs.downcast::<ModelInputs>().expect("Downcast failed.");
         let metadata = self.get_metadata();
-        let paged_attn_meta = match (&metadata.cache_engine, &paged_attn_meta) {
-            (Some(engine), Some(meta)) => Some((engine.get_kv_cache().clone(), meta)),
-            (Some(_), None) => {
-                // This can happen if Rust-side user code is wrong
-                candle_core::bail!("Forward step expected a PagedAttention input metadata. This was not provided, please ensure that the scheduler config is correctly configured for PagedAttention.")
-            }
-            (None, Some(_)) => {
-                // This should never happen but we handle it anyway
-                candle_core::bail!("Forward step got a PagedAttention input metadata but there is no cache engine. Please raise an issue.")
-            }
-            (None, None) => None,
-        };
-        #[cfg(feature = "metal")]
-        let logits = objc::rc::autoreleasepool(|| {
+        if !use_cache && paged_attn_meta.is_some() {
+            candle_core::bail!(
+                "A forward pass without the KV cache is not supported with PagedAttention."
+            );
+        }
+        let paged_attn_meta =
+            pair_paged_attn_meta(metadata.cache_engine.as_ref(), paged_attn_meta.as_ref())?
+                .map(|(engine, meta)| (engine.get_kv_cache().clone(), meta));
+        let forward = || {
             self.model.forward(
                 &input_ids,
                 pixel_values,
@@ -849,22 +877,18 @@ impl Pipeline for VisionPipeline {
                 paged_attn_meta,
                 &flash_meta,
             )
-        })?;
-        #[cfg(not(feature = "metal"))]
-        let logits = self.model.forward(
-            &input_ids,
-            pixel_values,
-            &seqlen_offsets,
-            context_lens,
-            position_ids,
-            model_specific_args,
-            paged_attn_meta,
-            &flash_meta,
-        )?;
+        };
+        #[cfg(feature = "metal")]
+        let forward = || objc::rc::autoreleasepool(forward);
+        let logits = if use_cache {
+            forward()?
+        } else {
+            self.model.cache().with_empty_cache(forward)?
+        };
         if return_raw_logits {
             Ok(ForwardInputsResult::RawLogits { logits })
         } else {
-            Ok(ForwardInputsResult::CausalGeneration { logits })
+            ForwardInputsResult::CausalGeneration { logits }.with_top_logprobs(return_logprobs)
         }
     }
     async fn sample_causal_gen(
@@ -873,7 +897,7 @@ impl Pipeline for VisionPipeline {
         logits: Vec<Tensor>,
         prefix_cacher: &mut PrefixCacheManagerV2,
         disable_eos_stop: bool,
-        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
     ) -> Result<(), candle_core::Error> {
         sample_and_add_toks(self, seqs, logits, prefix_cacher, disable_eos_stop, rng).await
     }
@@ -42,6 +42,19 @@ pub struct PrefixCacheManagerV2 {
     caches: HashMap<Tokens, CacheElement>,
     n_on_device: usize,
     no_prefix_cache: bool,
+    stats: PrefixCacheStats,
+}
+
+/// Counters for the prefix cache since it was created or [`PrefixCacheManagerV2::reset_stats`]
+/// was last called.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrefixCacheStats {
+    /// Lookups which reused a cached prefix.
+    pub hits: usize,
+    /// Lookups which found no usable cached prefix.
+    pub misses: usize,
+    /// Caches moved from the device to the CPU.
+    pub evictions: usize,
 }
 
 #[derive(Clone)]
@@ -60,23 +73,55 @@ impl PrefixCacheManagerV2 {
             caches: HashMap::new(),
             n_on_device,
             no_prefix_cache,
+            stats: PrefixCacheStats::default(),
         }
     }
 
+    /// Hit, miss and eviction counts. Lookups skipped because the prefix cache is disabled or the
+    /// sequence has images are not counted.
+    pub fn stats(&self) -> PrefixCacheStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = PrefixCacheStats::default();
+    }
+
     /// This always keeps the cache on the device.
     pub fn add_sequence(&mut self, seq: &mut Sequence) {
-        if self.no_prefix_cache || seq.has_images() {
+        // An evicted cache no longer holds every token of the sequence.
+        if self.no_prefix_cache || seq.has_images() || seq.kv_cache_evicted_toks() > 0 {
+            return;
+        }
+        self.add_cache(seq.get_toks().to_vec(), seq.normal_cache().to_vec());
+    }
+
+    /// Store the KV cache of `toks`, which must hold exactly those tokens. This keeps the cache on the device.
+    pub fn add_cache(&mut self, toks: Vec<u32>, cache: Vec<Option<KvCache>>) {
+        if self.no_prefix_cache {
             return;
         }
-        let cache = seq.normal_cache().to_vec();
         let devices = cache
             .iter()
             .map(|x| x.as_ref().map(|x| x.k().unwrap().unwrap().device().clone()))
             .collect::<Vec<_>>();
-        self.caches.insert(
-            seq.get_toks().to_vec().into(),
-            CacheElement { cache, devices },
-        );
+        self.caches
+            .insert(toks.into(), CacheElement { cache, devices });
+    }
+
+    /// Store the KV cache which `prefill` computes for each non-empty prompt, then evict caches
+    /// beyond the on-device bound to the CPU. Prompts primed before an error are kept.
+    pub fn prime(
+        &mut self,
+        prompts: Vec<Vec<u32>>,
+        mut prefill: impl FnMut(&[u32]) -> Result<Vec<Option<KvCache>>>,
+    ) -> Result<()> {
+        for prompt in prompts.into_iter().filter(|prompt| !prompt.is_empty()) {
+            let cache = prefill(&prompt)?;
+            self.add_cache(prompt, cache);
+        }
+        self.evict_to_cpu()?;
+        Ok(())
     }
 
     fn cache_to(
@@ -185,6 +230,7 @@ impl PrefixCacheManagerV2 {
                 n_evicted += 1;
             }
         }
+        self.stats.evictions += n_evicted;
         Ok(self.caches.len().saturating_sub(self.n_on_device))
     }
 
@@ -211,6 +257,7 @@ impl PrefixCacheManagerV2 {
 
             if !matches!(cache_device, Device::Cpu) {
                 Self::cache_to(&mut cache.cache, Either::Left(&Device::Cpu))?;
+                self.stats.evictions += 1;
             }
         }
         Ok(self.caches.len())
@@ -243,16 +290,100 @@ impl PrefixCacheManagerV2 {
             for layer in cache.cache.iter_mut().flatten() {
                 match layer.set_len(match_len) {
                     Ok(_) => (),
-                    Err(_) => return Ok(None),
+                    Err(_) => {
+                        self.stats.misses += 1;
+                        return Ok(None);
+                    }
                 }
             }
+            self.stats.hits += 1;
             Ok(Some(MatchingCache {
                 normal: cache.cache,
                 toks: toks.0[match_len..].to_vec(),
                 offset: match_len,
             }))
         } else {
+            self.stats.misses += 1;
             Ok(None)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Result, Tensor};
+
+    use super::{PrefixCacheManagerV2, PrefixCacheStats};
+    use crate::pipeline::KvCache;
+
+    /// A single-layer cache whose keys and values at position `i` are `i + 1000 * id`.
+    fn fake_cache(id: usize, len: usize) -> Result<Vec<Option<KvCache>>> {
+        let data = Tensor::arange(0f32, len as f32, &Device::Cpu)?
+            .affine(1., 1000. * id as f64)?
+            .reshape((1, 1, len, 1))?;
+        let mut cache = KvCache::new_normal(2, 64, 16);
+        cache.append(&data, &data)?;
+        Ok(vec![Some(cache)])
+    }
+
+    #[test]
+    fn primed_prefixes_are_reused() -> Result<()> {
+        let mut cacher = PrefixCacheManagerV2::new(16, false);
+        let system_a = vec![1, 2, 3, 4, 5];
+        let system_b = vec![1, 2, 9, 9];
+        cacher.add_cache(system_a.clone(), fake_cache(0, system_a.len())?);
+        cacher.add_cache(system_b.clone(), fake_cache(1, system_b.len())?);
+
+        for (id, system) in [system_a, system_b].into_iter().enumerate() {
+            let request = [system.clone(), vec![42, 43]].concat();
+            let matching = cacher
+                .search_for_matching_cache(&request, false)?
+                .expect("primed prefix should match");
+            // Only the last prefix token is run again, to produce the logits for the new tokens.
+            assert_eq!(matching.offset, system.len() - 1);
+            assert_eq!(matching.toks, request[system.len() - 1..]);
+
+            let layer = matching.normal[0].as_ref().unwrap();
+            assert_eq!(layer.current_seq_len(), system.len() - 1);
+            let k = layer.k()?.unwrap().flatten_all()?.to_vec1::<f32>()?;
+            let expected = (0..system.len() - 1)
+                .map(|i| (i + 1000 * id) as f32)
+                .collect::<Vec<_>>();
+            assert_eq!(k, expected);
+        }
+
+        assert!(cacher.search_for_matching_cache(&[7, 8], false)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn stats_count_hits_and_misses() -> Result<()> {
+        let mut cacher = PrefixCacheManagerV2::new(16, false);
+        let system = vec![1, 2, 3, 4];
+        cacher.add_cache(system.clone(), fake_cache(0, system.len())?);
+
+        assert!(cacher
+            .search_for_matching_cache(&[1, 2, 3, 4, 5], false)?
+            .is_some());
+        assert!(cacher.search_for_matching_cache(&[7, 8], false)?.is_none());
+        assert!(cacher
+            .search_for_matching_cache(&[1, 2, 3], false)?
+            .is_some());
+        // Lookups for sequences with images skip the prefix cache entirely.
+        assert!(cacher
+            .search_for_matching_cache(&[1, 2, 3], true)?
+            .is_none());
+        assert_eq!(
+            cacher.stats(),
+            PrefixCacheStats {
+                hits: 2,
+                misses: 1,
+                evictions: 0,
+            }
+        );
+
+        cacher.reset_stats();
+        assert_eq!(cacher.stats(), PrefixCacheStats::default());
+        Ok(())
+    }
+}
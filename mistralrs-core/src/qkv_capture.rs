@@ -0,0 +1,72 @@
+//! Opt-in capture of the query, key and value tensors of one attention layer, for interpretability.
+
+use std::sync::{Arc, Mutex};
+
+use candle_core::{IndexOp, Result, Tensor};
+
+/// The query, key and value tensors of one sequence at one attention layer, after the projections and
+/// RoPE. Each has shape `(heads, seq_len, head_dim)`, where the key and value have the KV heads.
+#[derive(Clone, Debug)]
+pub struct CapturedQkv {
+    pub q: Tensor,
+    pub k: Tensor,
+    pub v: Tensor,
+}
+
+/// Captures the query, key and value tensors of one layer for one sequence of the batch. Clones share the
+/// capture.
+///
+/// Capturing is off until [`QkvCapture::enable`] is called. Only the designated layer is kept, and each
+/// forward pass replaces the previous capture, so the memory used is bounded by one layer of one sequence.
+/// The key and value are those of the tokens in the forward pass, without any cached tokens.
+#[derive(Clone, Default)]
+pub struct QkvCapture {
+    /// The layer and the index in the batch of the sequence to capture.
+    target: Arc<Mutex<Option<(usize, usize)>>>,
+    captured: Arc<Mutex<Option<CapturedQkv>>>,
+}
+
+impl QkvCapture {
+    /// Capture layer `layer_idx` for the sequence at `seq_idx` in the batch.
+    pub fn enable(&self, layer_idx: usize, seq_idx: usize) {
+        *self.target.lock().unwrap() = Some((layer_idx, seq_idx));
+    }
+
+    pub fn disable(&self) {
+        *self.target.lock().unwrap() = None;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.target.lock().unwrap().is_some()
+    }
+
+    /// Record the `(bs, heads, seq_len, head_dim)` tensors of `layer_idx` if it is the designated layer.
+    /// Forward passes whose batch does not contain the designated sequence are not recorded.
+    pub(crate) fn record(
+        &self,
+        layer_idx: usize,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+    ) -> Result<()> {
+        let Some((target_layer, seq_idx)) = *self.target.lock().unwrap() else {
+            return Ok(());
+        };
+        if layer_idx != target_layer || seq_idx >= q.dim(0)? {
+            return Ok(());
+        }
+        // Copy out the sequence so the capture does not keep the whole batch alive.
+        let select = |x: &Tensor| x.i(seq_idx)?.copy().map(|x| x.detach());
+        *self.captured.lock().unwrap() = Some(CapturedQkv {
+            q: select(q)?,
+            k: select(k)?,
+            v: select(v)?,
+        });
+        Ok(())
+    }
+
+    /// Take the tensors captured by the last forward pass, if any.
+    pub fn take(&self) -> Option<CapturedQkv> {
+        self.captured.lock().unwrap().take()
+    }
+}
@@ -7,10 +7,10 @@ use serde_json::Value;
 use crate::{
     response::Response,
     sampler::SamplingParams,
-    tools::{Tool, ToolChoice},
+    tools::{Tool, ToolCallTrigger, ToolChoice},
     CustomLogitsProcessor, DiffusionGenerationParams,
 };
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, str::FromStr, sync::Arc};
 use tokio::sync::mpsc::Sender;
 
 pub type LlguidanceGrammar = llguidance::api::TopLevelGrammar;
@@ -21,6 +21,8 @@ pub enum Constraint {
     Regex(String),
     Lark(String),
     JsonSchema(serde_json::Value),
+    /// A JSON schema compiled to a regex, supporting objects, strings, numbers, enums and arrays.
+    JsonSchemaRegex(serde_json::Value),
     Llguidance(LlguidanceGrammar),
     None,
 }
@@ -57,6 +59,78 @@ pub enum RequestMessage {
     },
 }
 
+/// Limits on the raw input of a request, checked before it is tokenized so that oversized inputs
+/// are rejected cheaply. The tokenized prompt is still checked against the model maximum length.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InputLimits {
+    pub max_input_chars: Option<usize>,
+    pub max_input_bytes: Option<usize>,
+}
+
+impl InputLimits {
+    /// Check the raw text of `messages`. Prompts given as token ids are not checked.
+    pub(crate) fn check(&self, messages: &RequestMessage) -> Result<(), String> {
+        if self.max_input_chars.is_none() && self.max_input_bytes.is_none() {
+            return Ok(());
+        }
+        let mut texts: Vec<&str> = Vec::new();
+        match messages {
+            RequestMessage::Chat(messages) | RequestMessage::VisionChat { messages, .. } => {
+                for content in messages.iter().flat_map(|message| message.values()) {
+                    match content {
+                        Either::Left(text) => texts.push(text),
+                        Either::Right(parts) => texts.extend(
+                            parts
+                                .iter()
+                                .flat_map(|part| part.values())
+                                .filter_map(Value::as_str),
+                        ),
+                    }
+                }
+            }
+            RequestMessage::Completion { text, .. } => texts.push(text),
+            RequestMessage::ImageGeneration { prompt, .. } => texts.push(prompt),
+            RequestMessage::CompletionTokens(_) => (),
+        }
+
+        if let Some(max_bytes) = self.max_input_bytes {
+            let bytes = texts.iter().map(|text| text.len()).sum::<usize>();
+            if bytes > max_bytes {
+                return Err(format!(
+                    "Input is {bytes} bytes long, which is more than the maximum of {max_bytes} bytes."
+                ));
+            }
+        }
+        if let Some(max_chars) = self.max_input_chars {
+            let chars = texts.iter().map(|text| text.chars().count()).sum::<usize>();
+            if chars > max_chars {
+                return Err(format!(
+                    "Input is {chars} characters long, which is more than the maximum of {max_chars} characters."
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check that every prompt token id can be looked up in an embedding of `vocab_size` rows.
+pub(crate) fn check_token_ids(tokens: &[u32], vocab_size: usize) -> Result<(), String> {
+    // The max is cheap; only search for the position once something is out of range.
+    match tokens.iter().max() {
+        Some(&max) if max as usize >= vocab_size => {
+            let position = tokens
+                .iter()
+                .position(|&tok| tok as usize >= vocab_size)
+                .expect("max is out of range");
+            Err(format!(
+                "Token id {} at position {position} is out of range for the vocabulary of size {vocab_size}. Was the prompt tokenized with a different tokenizer?",
+                tokens[position]
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
 fn default_responder<T>() -> Sender<T> {
     let (sender, _) = tokio::sync::mpsc::channel(1);
     sender
@@ -135,6 +209,20 @@ pub struct NormalRequest {
     pub logits_processors: Option<Vec<Arc<dyn CustomLogitsProcessor>>>,
     pub return_raw_logits: bool,
     pub web_search_options: Option<WebSearchOptions>,
+    /// Detect a tool call starting at a trigger token and constrain its arguments to a schema.
+    #[serde(default)]
+    pub tool_call_trigger: Option<ToolCallTrigger>,
+    /// When streaming, also return the raw bytes of each delta so that partial UTF-8 sequences from
+    /// byte-fallback tokens can be assembled by the caller.
+    #[serde(default)]
+    pub stream_raw_bytes: bool,
+    /// When streaming, where the generated text is cut into chunks. Raw byte deltas
+    /// (`stream_raw_bytes`) are always cut every two tokens.
+    #[serde(default)]
+    pub stream_flush_policy: StreamFlushPolicy,
+    /// Bound the number of tokens held in the KV cache of this request, for long streaming sessions.
+    #[serde(default)]
+    pub kv_cache_limit: Option<KvCacheLimit>,
 }
 
 impl NormalRequest {
@@ -160,6 +248,109 @@ impl NormalRequest {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            tool_call_trigger: None,
+            stream_raw_bytes: false,
+            stream_flush_policy: StreamFlushPolicy::default(),
+            kv_cache_limit: None,
+        }
+    }
+}
+
+/// A bound on the number of tokens a sequence keeps in its KV cache. This only applies to the
+/// default KV cache: PagedAttention and X-LoRA caches are not limited, and sliding-window layers are
+/// already bounded by their window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KvCacheLimit {
+    pub max_tokens: usize,
+    pub overflow: KvCacheOverflowPolicy,
+}
+
+/// What happens once a KV cache grows past its [`KvCacheLimit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KvCacheOverflowPolicy {
+    /// Fail the step which grew the cache past the limit.
+    #[serde(rename = "error")]
+    Error,
+    /// Keep the first `sink_tokens` tokens (the attention sinks) and the most recent ones, evicting
+    /// the tokens in between. Positions are not rebased, so the model still sees the absolute
+    /// position of each token.
+    #[serde(rename = "evict_sink_window")]
+    EvictSinkWindow { sink_tokens: usize },
+}
+
+/// When a streaming response sends the text generated so far. Text held back by a policy is always
+/// sent with the final chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamFlushPolicy {
+    /// Send after every token.
+    #[serde(rename = "token")]
+    Token,
+    /// Send text up to the last word boundary, so every chunk but the last ends on a whole word.
+    #[serde(rename = "word")]
+    Word,
+    /// Send text up to the last sentence end (`.`, `!` or `?` followed by whitespace, or a newline).
+    #[serde(rename = "sentence")]
+    Sentence,
+    /// Send once this many tokens have been generated since the last chunk.
+    #[serde(rename = "tokens")]
+    Tokens(usize),
+}
+
+impl Default for StreamFlushPolicy {
+    fn default() -> Self {
+        Self::Tokens(2)
+    }
+}
+
+impl FromStr for StreamFlushPolicy {
+    type Err = String;
+    /// `token`, `word`, `sentence`, or a number of tokens.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "token" => Ok(Self::Token),
+            "word" => Ok(Self::Word),
+            "sentence" => Ok(Self::Sentence),
+            other => match other.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(Self::Tokens(n)),
+                _ => Err(format!(
+                    "Stream flush policy `{other}` is not `token`, `word`, `sentence` or a positive number of tokens."
+                )),
+            },
+        }
+    }
+}
+
+impl StreamFlushPolicy {
+    /// How many bytes at the start of `pending` may be sent, given `pending_toks` tokens generated
+    /// since the last chunk.
+    pub(crate) fn flush_len(&self, pending: &str, pending_toks: usize) -> usize {
+        match self {
+            Self::Token => pending.len(),
+            Self::Tokens(n) => {
+                if pending_toks >= (*n).max(1) {
+                    pending.len()
+                } else {
+                    0
+                }
+            }
+            Self::Word => pending.rfind(char::is_whitespace).unwrap_or(0),
+            Self::Sentence => {
+                let mut chars = pending.char_indices().peekable();
+                let mut len = 0;
+                while let Some((i, c)) = chars.next() {
+                    let ends_sentence = match c {
+                        '\n' => true,
+                        '.' | '!' | '?' => {
+                            chars.peek().is_some_and(|(_, next)| next.is_whitespace())
+                        }
+                        _ => false,
+                    };
+                    if ends_sentence {
+                        len = i + c.len_utf8();
+                    }
+                }
+                len
+            }
         }
     }
 }
@@ -199,6 +390,9 @@ pub enum Request {
     // and then Engine will be dropped.
     Terminate,
     TerminateAllSeqsNextStep,
+    /// Stop accepting new requests and finish every in-flight sequence with its partial output and
+    /// [`crate::StopReason::Shutdown`].
+    Drain,
 }
 
 impl Debug for Request {
@@ -227,6 +421,85 @@ impl Debug for Request {
             }
             Request::Terminate => write!(f, "Termination Request"),
             Request::TerminateAllSeqsNextStep => write!(f, "Terminate All Seqs Next Step"),
+            Request::Drain => write!(f, "Drain Request"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use either::Either;
+    use indexmap::IndexMap;
+
+    use super::{check_token_ids, InputLimits, RequestMessage, StreamFlushPolicy};
+
+    #[test]
+    fn out_of_vocab_token_is_rejected() {
+        assert!(check_token_ids(&[0, 5, 9], 10).is_ok());
+        assert!(check_token_ids(&[], 10).is_ok());
+        let err = check_token_ids(&[1, 2, 12, 3, 15], 10).unwrap_err();
+        assert!(err.contains("Token id 12 at position 2"), "{err}");
+        assert!(err.contains("size 10"), "{err}");
+    }
+
+    #[test]
+    fn flush_policy_boundaries() {
+        assert_eq!(
+            StreamFlushPolicy::Word.flush_len("Hello wor", 1),
+            "Hello".len()
+        );
+        assert_eq!(StreamFlushPolicy::Word.flush_len(" wor", 1), 0);
+        assert_eq!(
+            StreamFlushPolicy::Sentence.flush_len("Hi there. Pi is 3.14", 1),
+            "Hi there.".len()
+        );
+        assert_eq!(StreamFlushPolicy::Sentence.flush_len("Done.", 1), 0);
+        assert_eq!(StreamFlushPolicy::Tokens(3).flush_len("abc", 2), 0);
+        assert_eq!(StreamFlushPolicy::Tokens(3).flush_len("abc", 3), 3);
+    }
+
+    #[test]
+    fn flush_policy_from_str() {
+        assert_eq!("Word".parse(), Ok(StreamFlushPolicy::Word));
+        assert_eq!("sentence".parse(), Ok(StreamFlushPolicy::Sentence));
+        assert_eq!("4".parse(), Ok(StreamFlushPolicy::Tokens(4)));
+        assert!("0".parse::<StreamFlushPolicy>().is_err());
+        assert!("line".parse::<StreamFlushPolicy>().is_err());
+    }
+
+    #[test]
+    fn over_length_input_is_rejected_before_tokenization() {
+        let limits = InputLimits {
+            max_input_chars: Some(8),
+            max_input_bytes: Some(16),
+        };
+
+        // No tokenizer is involved: the check only looks at the raw text.
+        let completion = |text: &str| RequestMessage::Completion {
+            text: text.to_string(),
+            echo_prompt: false,
+            best_of: None,
+        };
+        assert!(limits.check(&completion("hello")).is_ok());
+        let err = limits.check(&completion("hello world")).unwrap_err();
+        assert!(err.contains("11 characters"), "{err}");
+        // 6 characters but 18 bytes.
+        let err = limits.check(&completion("日本語日本語")).unwrap_err();
+        assert!(err.contains("18 bytes"), "{err}");
+
+        let mut message = IndexMap::new();
+        message.insert("role".to_string(), Either::Left("user".to_string()));
+        message.insert("content".to_string(), Either::Left("hi there".to_string()));
+        assert!(limits
+            .check(&RequestMessage::Chat(vec![message]))
+            .unwrap_err()
+            .contains("12 characters"));
+
+        assert!(limits
+            .check(&RequestMessage::CompletionTokens(vec![0; 1024]))
+            .is_ok());
+        assert!(InputLimits::default()
+            .check(&completion(&"a".repeat(1 << 20)))
+            .is_ok());
+    }
+}
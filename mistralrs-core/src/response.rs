@@ -44,6 +44,10 @@ pub struct Delta {
     pub content: Option<String>,
     pub role: String,
     pub tool_calls: Option<Vec<ToolCallResponse>>,
+    /// Raw bytes of the delta, which may end in an incomplete UTF-8 sequence. Only set when raw byte
+    /// streaming was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_bytes: Option<Vec<u8>>,
 }
 
 generate_repr!(Delta);
@@ -93,6 +97,14 @@ pub struct ChunkChoice {
     pub index: usize,
     pub delta: Delta,
     pub logprobs: Option<ResponseLogprob>,
+    /// Per token in this chunk, `(token, logprob)` of the most likely candidates. Only set when
+    /// `stream_top_k_logprobs` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k_logprobs: Option<Vec<Vec<(u32, f32)>>>,
+    /// Per token in this chunk, `(entropy, varentropy)` in nats. Only set when `return_entropy` was
+    /// requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entropy: Option<Vec<(f32, f32)>>,
 }
 
 generate_repr!(ChunkChoice);
@@ -106,6 +118,18 @@ pub struct CompletionChunkChoice {
     pub index: usize,
     pub logprobs: Option<ResponseLogprob>,
     pub finish_reason: Option<String>,
+    /// Raw bytes of the delta, which may end in an incomplete UTF-8 sequence. Only set when raw byte
+    /// streaming was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text_bytes: Option<Vec<u8>>,
+    /// Per token in this chunk, `(token, logprob)` of the most likely candidates. Only set when
+    /// `stream_top_k_logprobs` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k_logprobs: Option<Vec<Vec<(u32, f32)>>>,
+    /// Per token in this chunk, `(entropy, varentropy)` in nats. Only set when `return_entropy` was
+    /// requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entropy: Option<Vec<(f32, f32)>>,
 }
 
 generate_repr!(CompletionChunkChoice);
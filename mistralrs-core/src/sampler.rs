@@ -10,9 +10,12 @@ use candle_core::{Device, Error, Result, Tensor, D};
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
 
+use llguidance::toktrie::TokTrie;
 use once_cell::sync::Lazy;
-use rand::distr::{weighted::WeightedIndex, Distribution};
-use rand_isaac::Isaac64Rng;
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    RngCore,
+};
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
@@ -27,6 +30,54 @@ pub enum StopTokens {
     Ids(Vec<u32>),
 }
 
+/// Stop a sequence caught in a loop: once its output ends with the same run of at most
+/// `max_pattern_len` tokens repeated `min_repeats` times in a row.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepetitionLoopParams {
+    pub max_pattern_len: usize,
+    pub min_repeats: usize,
+}
+
+impl RepetitionLoopParams {
+    /// Whether `toks` followed by `next` ends with a loop.
+    pub(crate) fn ends_in_loop(&self, toks: &[u32], next: u32) -> bool {
+        let len = toks.len() + 1;
+        let at = |i: usize| if i == toks.len() { next } else { toks[i] };
+        (1..=self.max_pattern_len).any(|pattern_len| {
+            let span = pattern_len * self.min_repeats;
+            span <= len && (len - span..len - pattern_len).all(|i| at(i) == at(i + pattern_len))
+        })
+    }
+}
+
+/// Random number generator used for sampling, shared between the sampling threads.
+///
+/// Any `RngCore + Send` implementation can be supplied with
+/// [`MistralRsBuilder::with_sampling_rng`](crate::MistralRsBuilder::with_sampling_rng). The default
+/// is an `Isaac64Rng` seeded with 0.
+pub type SamplingRng = Arc<Mutex<dyn RngCore + Send>>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// Which tokens of the sequence the frequency, presence and DRY penalties are computed over.
+pub enum PenaltyScope {
+    /// Both the prompt and the generated tokens.
+    #[default]
+    PromptAndGenerated,
+    /// Only the generated tokens, so words from the prompt are not discouraged.
+    GeneratedOnly,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// Where the temperature is applied in the logits processing chain, relative to the frequency, presence
+/// and DRY penalties.
+pub enum TemperatureOrder {
+    /// Penalize the raw logits and apply the temperature when sampling.
+    #[default]
+    AfterPenalties,
+    /// Apply the temperature first, so that the penalties are not scaled by it.
+    BeforePenalties,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 /// Sampling params are used to control sampling.
 pub struct SamplingParams {
@@ -37,11 +88,42 @@ pub struct SamplingParams {
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    #[serde(default)]
+    pub penalty_scope: PenaltyScope,
     pub stop_toks: Option<StopTokens>,
     pub max_len: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
     pub dry_params: Option<DrySamplingParams>,
+    /// Stream the logprobs of the `k` most likely tokens at each step. Off by default.
+    #[serde(default)]
+    pub stream_top_k_logprobs: Option<usize>,
+    /// Force the model's reasoning end token, such as `</think>`, once this many tokens were generated
+    /// without it, closing the reasoning span. See [`crate::ChatTemplate::reasoning_end_tok`].
+    #[serde(default)]
+    pub reasoning_budget: Option<usize>,
+    /// Back the prompt up by its last token and constrain the first generated token to start with that
+    /// token's text. This avoids the tokenization boundary bias of prompts which end mid-word.
+    #[serde(default)]
+    pub token_healing: bool,
+    /// Report the entropy and varentropy of the processed distribution at each step. Off by default.
+    #[serde(default)]
+    pub return_entropy: bool,
+    /// Where the temperature is applied relative to the penalties.
+    #[serde(default)]
+    pub temperature_order: TemperatureOrder,
+    /// Weight each occurrence of a token in the frequency and presence penalties by `decay^distance`,
+    /// where the distance is the number of tokens since that occurrence, so that recent repeats are
+    /// penalized more. Should be in `(0, 1]`; off by default.
+    #[serde(default)]
+    pub penalty_decay: Option<f32>,
+    /// Treat an EOS token within the prompt as a document separator: the penalties only count the
+    /// tokens after the last one. Off by default.
+    #[serde(default)]
+    pub eos_separates_documents: bool,
+    /// Stop with the `repetition_loop` reason when the output falls into a loop. Off by default.
+    #[serde(default)]
+    pub repetition_loop: Option<RepetitionLoopParams>,
 }
 
 impl SamplingParams {
@@ -58,11 +140,20 @@ impl SamplingParams {
             top_n_logprobs: 0,
             frequency_penalty: None,
             presence_penalty: None,
+            penalty_scope: PenaltyScope::default(),
             stop_toks: None,
             max_len: None,
             logits_bias: None,
             n_choices: 1,
             dry_params: None,
+            stream_top_k_logprobs: None,
+            reasoning_budget: None,
+            token_healing: false,
+            return_entropy: false,
+            temperature_order: TemperatureOrder::default(),
+            penalty_decay: None,
+            eos_separates_documents: false,
+            repetition_loop: None,
         }
     }
 }
@@ -177,6 +268,25 @@ impl<T: Fn(&Tensor, &[u32]) -> Result<Tensor> + Send + Sync> CustomLogitsProcess
     }
 }
 
+/// Divides the logits by the temperature. The [`Sampler`] places it in the chain according to
+/// [`TemperatureOrder`].
+#[derive(Clone, Copy, Debug)]
+pub struct TemperatureProcessor {
+    temperature: f64,
+}
+
+impl TemperatureProcessor {
+    pub fn new(temperature: f64) -> Self {
+        Self { temperature }
+    }
+}
+
+impl CustomLogitsProcessor for TemperatureProcessor {
+    fn apply(&self, logits: &Tensor, _context: &[u32]) -> Result<Tensor> {
+        logits / self.temperature
+    }
+}
+
 /// Sampler for sampling.
 #[derive(Clone)]
 pub struct Sampler {
@@ -185,11 +295,22 @@ pub struct Sampler {
     tokenizer: Option<Arc<Tokenizer>>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    penalty_scope: PenaltyScope,
     dry_params: Option<DrySamplingParamsInner>,
     top_k: i64,
     top_p: f64,
     min_p: f64,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+    stream_top_k_logprobs: Option<usize>,
+    /// Number of generated tokens after which the reasoning close token is forced, and that token.
+    reasoning_budget: Option<(usize, u32)>,
+    /// Tokens allowed as the first generated token when healing the prompt boundary.
+    token_healing: Option<Vec<u32>>,
+    return_entropy: bool,
+    temperature_order: TemperatureOrder,
+    penalty_decay: Option<f32>,
+    /// Prompt tokens after which the penalty context starts anew.
+    document_separators: Vec<u32>,
 }
 
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
@@ -208,12 +329,72 @@ pub struct Logprobs {
     pub logprob: f32,
     pub bytes: Option<String>,
     pub top_logprobs: Option<Vec<TopLogprob>>,
+    /// `(token, logprob)` of the most likely tokens under the processed logits, most likely first.
+    /// Only set when [`SamplingParams::stream_top_k_logprobs`] is.
+    #[serde(default)]
+    pub top_k_logprobs: Option<Vec<(u32, f32)>>,
+    /// `(entropy, varentropy)` of the processed distribution, in nats. Only set when
+    /// [`SamplingParams::return_entropy`] is.
+    #[serde(default)]
+    pub entropy: Option<(f32, f32)>,
 }
 
 fn argmax_sample_last_dim(logits: &Tensor) -> Result<Tensor> {
     logits.argmax(D::Minus1)
 }
 
+/// The ids of the tokens in `vocab` whose bytes start with `prefix`.
+fn healing_tokens<'a>(prefix: &[u8], vocab: impl Iterator<Item = &'a [u8]>) -> Vec<u32> {
+    vocab
+        .enumerate()
+        .filter(|(_, bytes)| bytes.starts_with(prefix))
+        .map(|(tok, _)| tok as u32)
+        .collect()
+}
+
+/// Set every logit except those of `allowed` to -inf.
+fn mask_all_but(logits: &Tensor, allowed: &[u32]) -> Result<Tensor> {
+    let mut mask = vec![f32::NEG_INFINITY; logits.dim(0)?];
+    for tok in allowed {
+        if let Some(allowed) = mask.get_mut(*tok as usize) {
+            *allowed = 0.;
+        }
+    }
+    logits + Tensor::from_vec(mask, logits.dim(0)?, logits.device())?
+}
+
+/// Map a logit to an integer with the same order, so that logits can be compared as integers.
+fn ordered_key(x: f32) -> u32 {
+    let bits = x.to_bits();
+    if bits >> 31 == 1 {
+        !bits
+    } else {
+        bits | (1 << 31)
+    }
+}
+
+/// The most likely of the `top_k` largest logits, preferring the lowest index on ties. The top-k are
+/// partitioned out in linear time, without sorting or a softmax over the vocabulary.
+fn top_k_argmax(logits: &[f32], top_k: usize) -> u32 {
+    top_k_argmax_by(logits, top_k, |a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)))
+}
+
+/// [`top_k_argmax`] with `order` sorting `(key, index)` pairs from most to least likely.
+fn top_k_argmax_by(
+    logits: &[f32],
+    top_k: usize,
+    mut order: impl FnMut(&(u32, u32), &(u32, u32)) -> std::cmp::Ordering,
+) -> u32 {
+    let mut keys = logits
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (ordered_key(*x), i as u32))
+        .collect::<Vec<_>>();
+    let k = top_k.clamp(1, keys.len());
+    keys.select_nth_unstable_by(k - 1, &mut order);
+    keys[..k].iter().min_by(|a, b| order(a, b)).unwrap().1
+}
+
 impl Sampler {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -222,6 +403,7 @@ impl Sampler {
         tokenizer: Option<Arc<Tokenizer>>,
         frequency_penalty: Option<f32>,
         presence_penalty: Option<f32>,
+        penalty_scope: PenaltyScope,
         dry_params: Option<DrySamplingParams>,
         top_k: i64,
         top_p: f64,
@@ -248,14 +430,141 @@ impl Sampler {
             tokenizer,
             frequency_penalty,
             presence_penalty,
+            penalty_scope,
             dry_params,
             top_k,
             top_p,
             min_p,
             logits_processors,
+            stream_top_k_logprobs: None,
+            reasoning_budget: None,
+            token_healing: None,
+            return_entropy: false,
+            temperature_order: TemperatureOrder::default(),
+            penalty_decay: None,
+            document_separators: Vec::new(),
         })
     }
 
+    /// Also return the logprobs of the `k` most likely tokens at each step.
+    pub fn with_stream_top_k_logprobs(mut self, k: Option<usize>) -> Self {
+        self.stream_top_k_logprobs = k;
+        self
+    }
+
+    /// Also return the entropy and varentropy of the processed distribution at each step.
+    pub fn with_entropy(mut self, return_entropy: bool) -> Self {
+        self.return_entropy = return_entropy;
+        self
+    }
+
+    /// Apply the temperature before or after the penalties.
+    pub fn with_temperature_order(mut self, temperature_order: TemperatureOrder) -> Self {
+        self.temperature_order = temperature_order;
+        self
+    }
+
+    /// Weight each occurrence of a token in the frequency and presence penalties by `decay` raised to
+    /// its distance from the end of the penalty context.
+    pub fn with_penalty_decay(mut self, decay: Option<f32>) -> Self {
+        self.penalty_decay = decay;
+        self
+    }
+
+    /// Only penalize the tokens after the last of `separators` in the prompt, so that a prompt made of
+    /// several documents is penalized as the last one.
+    pub fn with_document_separators(mut self, separators: Vec<u32>) -> Self {
+        self.document_separators = separators;
+        self
+    }
+
+    /// The temperature stage of the chain, if it runs at `order`.
+    fn temperature_at(&self, order: TemperatureOrder) -> Option<TemperatureProcessor> {
+        self.temperature
+            .filter(|_| self.temperature_order == order)
+            .map(TemperatureProcessor::new)
+    }
+
+    /// Apply the temperature to the processed logits, unless it was applied before the penalties.
+    fn apply_temperature(&self, logits: &Tensor) -> Result<Tensor> {
+        match self.temperature_at(TemperatureOrder::AfterPenalties) {
+            Some(temperature) => temperature.apply(logits, &[]),
+            None => Ok(logits.clone()),
+        }
+    }
+
+    /// Force `end_tok`, the model's reasoning end token, once `budget` tokens were generated without it.
+    pub fn with_reasoning_budget(
+        mut self,
+        budget: Option<usize>,
+        end_tok: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let Some(budget) = budget else {
+            return Ok(self);
+        };
+        let Some(tokenizer) = &self.tokenizer else {
+            anyhow::bail!("A reasoning budget requires a tokenizer.");
+        };
+        let Some(end_tok) = end_tok else {
+            anyhow::bail!(
+                "A reasoning budget was set but the model has no reasoning end token. Set \
+                 `reasoning_end_token` in the chat template config."
+            );
+        };
+        let Some(close_tok) = tokenizer.token_to_id(end_tok) else {
+            anyhow::bail!("A reasoning budget was set but the tokenizer has no `{end_tok}` token.");
+        };
+        self.reasoning_budget = Some((budget, close_tok));
+        Ok(self)
+    }
+
+    /// Constrain the first generated token to start with the text of `removed_tok`, the last prompt token
+    /// which was dropped to heal the prompt boundary.
+    pub fn with_token_healing(mut self, tok_trie: &TokTrie, removed_tok: u32) -> Self {
+        let vocab = (0..tok_trie.vocab_size() as u32).map(|tok| tok_trie.token(tok));
+        self.token_healing = Some(healing_tokens(tok_trie.token(removed_tok), vocab));
+        self
+    }
+
+    /// The reasoning close token, if the budget is spent and the token was not generated yet.
+    fn forced_reasoning_close(&self, context: &[u32], prompt_len: usize) -> Option<u32> {
+        let (budget, close_tok) = self.reasoning_budget?;
+        let generated = context.get(prompt_len..).unwrap_or_default();
+        (generated.len() >= budget && !generated.contains(&close_tok)).then_some(close_tok)
+    }
+
+    /// The `k` most likely tokens under the processed logits, including the temperature. The logprobs
+    /// are base 10, like [`Logprobs::logprob`].
+    fn top_k_logprobs(&self, logits: &Tensor, k: usize) -> Result<Vec<(u32, f32)>> {
+        let logits = self.apply_temperature(logits)?;
+        let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?;
+        let mut indices = (0..probs.len() as u32).collect::<Vec<_>>();
+        indices.sort_by(|a, b| probs[*b as usize].total_cmp(&probs[*a as usize]));
+        Ok(indices
+            .into_iter()
+            .take(k)
+            .map(|tok| (tok, probs[tok as usize].log(10.0)))
+            .collect())
+    }
+
+    /// `(entropy, varentropy)` in nats of the processed distribution, including the temperature:
+    /// `-sum(p ln p)` and the variance of `-ln p` under `p`.
+    fn entropy(&self, logits: &Tensor) -> Result<(f32, f32)> {
+        let logits = self.apply_temperature(logits)?;
+        let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?;
+        let surprisals = probs
+            .iter()
+            .filter(|p| **p > 0.)
+            .map(|p| (*p, -p.ln()))
+            .collect::<Vec<_>>();
+        let entropy = surprisals.iter().map(|(p, s)| p * s).sum::<f32>();
+        let varentropy = surprisals
+            .iter()
+            .map(|(p, s)| p * (s - entropy).powi(2))
+            .sum::<f32>();
+        Ok((entropy, varentropy))
+    }
+
     fn get_top_logprobs(&self, probs: &[f32], argsort_indices: &[u32]) -> Result<Vec<TopLogprob>> {
         let mut argsort_indices_sorted = argsort_indices.to_vec();
         // Sort by descending prob
@@ -334,6 +643,34 @@ impl Sampler {
             logprob,
             top_logprobs,
             bytes,
+            top_k_logprobs: None,
+            entropy: None,
+        })
+    }
+
+    /// Greedy sampling restricted to the top-k logits.
+    fn sample_greedy_top_k(&self, logits: Tensor, top_k: usize) -> Result<Logprobs> {
+        let logits: Vec<f32> = logits.to_vec1()?;
+        let next_token = top_k_argmax(&logits, top_k);
+        let logprob = logits[next_token as usize].log(10.0);
+
+        let bytes = if let Some(tokenizer) = &self.tokenizer {
+            Some(
+                tokenizer
+                    .decode(&[next_token], false)
+                    .map_err(|x| Error::Msg(x.to_string()))?,
+            )
+        } else {
+            None
+        };
+
+        Ok(Logprobs {
+            token: next_token,
+            logprob,
+            top_logprobs: None,
+            bytes,
+            top_k_logprobs: None,
+            entropy: None,
         })
     }
 
@@ -414,6 +751,8 @@ impl Sampler {
             logprob,
             top_logprobs,
             bytes,
+            top_k_logprobs: None,
+            entropy: None,
         })
     }
 
@@ -422,7 +761,7 @@ impl Sampler {
         probs: &mut Vec<f32>,
         argsort_indices: Vec<u32>,
         return_logprobs: bool,
-        rng: Arc<Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
     ) -> Result<Logprobs> {
         let distr = WeightedIndex::new(&*probs).map_err(Error::wrap)?;
 
@@ -451,6 +790,8 @@ impl Sampler {
             logprob,
             top_logprobs,
             bytes,
+            top_k_logprobs: None,
+            entropy: None,
         })
     }
 
@@ -463,7 +804,7 @@ impl Sampler {
         top_p: f32,
         min_p: f32,
         return_logprobs: bool,
-        rng: Arc<Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
     ) -> Result<Logprobs> {
         let argsort_indices: Vec<u32> = logits.arg_sort_last_dim(false)?.to_vec1()?;
 
@@ -518,16 +859,36 @@ impl Sampler {
         self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
     }
 
-    fn apply_penalties(&self, mut logits: Vec<f32>, context: &[u32]) -> Result<Tensor> {
+    fn apply_penalties(
+        &self,
+        mut logits: Vec<f32>,
+        context: &[u32],
+        prompt_len: usize,
+    ) -> Result<Tensor> {
         if context.is_empty() {
             candle_core::bail!("Penalty context is empty, this should not happen.");
         }
 
-        // Dry penalty
-        self.apply_dry_penalty(&mut logits, context)?;
+        let prompt_len = prompt_len.min(context.len());
+        let (context, prompt_len) = match context[..prompt_len]
+            .iter()
+            .rposition(|tok| self.document_separators.contains(tok))
+        {
+            Some(pos) => (&context[pos + 1..], prompt_len - pos - 1),
+            None => (context, prompt_len),
+        };
+        let context = match self.penalty_scope {
+            PenaltyScope::PromptAndGenerated => context,
+            PenaltyScope::GeneratedOnly => &context[prompt_len..],
+        };
+        // Nothing has been generated yet.
+        if !context.is_empty() {
+            // Dry penalty
+            self.apply_dry_penalty(&mut logits, context)?;
 
-        // Frequency and Presence penalty
-        self.apply_freq_presc_penalty(&mut logits, context)?;
+            // Frequency and Presence penalty
+            self.apply_freq_presc_penalty(&mut logits, context)?;
+        }
 
         let vocab_size = logits.len();
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
@@ -540,20 +901,26 @@ impl Sampler {
 
             //mu[j] -> mu[j] - c[j] * alpha_frequency - float(c[j] > 0) * alpha_presence
 
+            // With a decay, each occurrence counts for less the further back it is, and the presence
+            // of a token is weighted as its most recent occurrence.
             let mut counts = vec![0.0f32; logits.len()];
-            for ctx in context.iter() {
+            let mut presence = vec![0.0f32; logits.len()];
+            for (distance, ctx) in context.iter().rev().enumerate() {
                 // Llama 3.2 uses a hack triggering this error... we wouldn't want a weight on it anyway
                 if *ctx as usize >= logits.len() {
                     continue;
                 }
-                counts[*ctx as usize] += 1.0;
+                let weight = self
+                    .penalty_decay
+                    .map_or(1., |decay| decay.powi(distance as i32));
+                counts[*ctx as usize] += weight;
+                presence[*ctx as usize] = presence[*ctx as usize].max(weight);
             }
 
             for (token_id, logit) in logits.iter_mut().enumerate() {
-                let count = counts[token_id];
                 *logit = *logit
-                    - count * frequency_penalty
-                    - if count > 0.0 { 1. } else { 0. } * presence_penalty;
+                    - counts[token_id] * frequency_penalty
+                    - presence[token_id] * presence_penalty;
             }
         }
         Ok(())
@@ -635,20 +1002,46 @@ impl Sampler {
     ///
     /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
     /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
+    ///
+    /// The first `prompt_len` tokens of `context` are the prompt.
+    #[allow(clippy::too_many_arguments)]
     pub fn sample(
         &self,
         logits: Tensor,
         context: &[u32],
+        prompt_len: usize,
         return_logprobs: bool,
-        rng: Arc<Mutex<Isaac64Rng>>,
+        rng: SamplingRng,
         sample_speculative: bool,
     ) -> Result<Logprobs> {
-        let logits = logits.to_vec1()?;
-        let mut logits = self.apply_penalties(logits, context)?;
+        let logits = match self.temperature_at(TemperatureOrder::BeforePenalties) {
+            Some(temperature) => temperature.apply(&logits, context)?,
+            None => logits,
+        };
+        let mut logits = self.apply_penalties(logits.to_vec1()?, context, prompt_len)?;
         for processor in &self.logits_processors {
             logits = processor.apply(&logits, context)?;
         }
-        let next_token = if sample_speculative {
+        if let Some(allowed) = &self.token_healing {
+            if context.len() == prompt_len {
+                logits = mask_all_but(&logits, allowed)?;
+            }
+        }
+        if let Some(close_tok) = self.forced_reasoning_close(context, prompt_len) {
+            logits = mask_all_but(&logits, &[close_tok])?;
+        }
+        let top_k_logprobs = self
+            .stream_top_k_logprobs
+            .map(|k| self.top_k_logprobs(&logits, k))
+            .transpose()?;
+        let entropy = self
+            .return_entropy
+            .then(|| self.entropy(&logits))
+            .transpose()?;
+        let mut next_token = if self.temperature.is_none() && self.top_k > 0 && !return_logprobs {
+            // Greedy with top-k only needs the largest of the top-k logits.
+            self.sample_greedy_top_k(logits, self.top_k as usize)?
+        } else if sample_speculative {
             match self.temperature {
                 None => self.sample_speculative_top_kp_min_p(
                     logits,
@@ -657,8 +1050,8 @@ impl Sampler {
                     self.top_p as f32,
                     self.min_p as f32,
                 )?,
-                Some(temperature) => {
-                    let logits = (&logits / temperature)?;
+                Some(_) => {
+                    let logits = self.apply_temperature(&logits)?;
                     let probs = candle_nn::ops::softmax_last_dim(&logits)?;
 
                     self.sample_speculative_top_kp_min_p(
@@ -673,8 +1066,8 @@ impl Sampler {
         } else {
             match self.temperature {
                 None => self.sample_argmax(logits, return_logprobs)?,
-                Some(temperature) => {
-                    let logits = (&logits / temperature)?;
+                Some(_) => {
+                    let logits = self.apply_temperature(&logits)?;
                     let logits = candle_nn::ops::softmax_last_dim(&logits)?;
                     let mut probs: Vec<f32> = logits.to_vec1()?;
 
@@ -690,6 +1083,8 @@ impl Sampler {
                 }
             }
         };
+        next_token.top_k_logprobs = top_k_logprobs;
+        next_token.entropy = entropy;
         Ok(next_token)
     }
 }
@@ -704,18 +1099,192 @@ mod tests {
         use std::sync::Arc;
         use std::sync::Mutex;
 
-        let sampler =
-            Sampler::new(None, 10, None, None, None, None, 32, 0.1, 0.05, vec![]).unwrap();
+        let sampler = Sampler::new(
+            None,
+            10,
+            None,
+            None,
+            None,
+            Default::default(),
+            None,
+            32,
+            0.1,
+            0.05,
+            vec![],
+        )
+        .unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
-            .sample(logits, &(0..1024).collect::<Vec<_>>(), false, rng, false)
+            .sample(
+                logits,
+                &(0..1024).collect::<Vec<_>>(),
+                1024,
+                false,
+                rng,
+                false,
+            )
             .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
 
+    #[test]
+    fn reasoning_close_token_is_forced_at_budget() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        const CLOSE: u32 = 3;
+        let sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            Default::default(),
+            None,
+            -1,
+            1.0,
+            0.0,
+            vec![],
+        )
+        .unwrap();
+        let sampler = Sampler {
+            reasoning_budget: Some((4, CLOSE)),
+            ..sampler
+        };
+        // The model strongly prefers the last token and would never emit the close token.
+        let logits = Tensor::arange(0f32, 16f32, &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let prompt = [1u32, 2];
+        let sample = |generated: &[u32]| {
+            let context = [&prompt[..], generated].concat();
+            sampler
+                .sample(
+                    logits.clone(),
+                    &context,
+                    prompt.len(),
+                    false,
+                    rng.clone(),
+                    false,
+                )
+                .unwrap()
+                .token
+        };
+
+        assert_eq!(sample(&[15, 15, 15]), 15);
+        assert_eq!(sample(&[15, 15, 15, 15]), CLOSE);
+        // Once the reasoning span was closed, nothing is forced anymore.
+        assert_eq!(sample(&[15, 15, 15, 15, CLOSE]), 15);
+        assert_eq!(sample(&[15, CLOSE, 15, 15, 15]), 15);
+    }
+
+    #[test]
+    fn token_healing_completes_the_partial_word() {
+        use super::{healing_tokens, Sampler};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let vocab: [&[u8]; 6] = [b"def", b" hel", b"(", b" hello", b" help", b"lo"];
+        let sampler = || {
+            Sampler::new(
+                None,
+                0,
+                None,
+                None,
+                None,
+                Default::default(),
+                None,
+                -1,
+                1.0,
+                0.0,
+                vec![],
+            )
+            .unwrap()
+        };
+        // "def hel" tokenizes as `def`, ` hel`. Healing drops ` hel` and only allows tokens extending it.
+        let allowed = healing_tokens(vocab[1], vocab.into_iter());
+        assert_eq!(allowed, vec![1, 3, 4]);
+        let healed = Sampler {
+            token_healing: Some(allowed),
+            ..sampler()
+        };
+
+        // After `def`, the model prefers `(`, but the user already typed ` hel`, where ` hello` fits best.
+        let logits = Tensor::new(&[0f32, 1., 5., 4., 2., 0.], &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let sample = |sampler: &Sampler, context: &[u32]| {
+            sampler
+                .sample(logits.clone(), context, 1, false, rng.clone(), false)
+                .unwrap()
+                .token
+        };
+        let text = |toks: &[u32]| {
+            toks.iter()
+                .flat_map(|tok| vocab[*tok as usize].to_vec())
+                .collect::<Vec<_>>()
+        };
+
+        let healed_tok = sample(&healed, &[0]);
+        assert_eq!(healed_tok, 3);
+        assert!(text(&[0, healed_tok]).starts_with(b"def hel"));
+        let unhealed_tok = sample(&sampler(), &[0]);
+        assert!(!text(&[0, unhealed_tok]).starts_with(b"def hel"));
+        // Only the first generated token is constrained.
+        assert_eq!(sample(&healed, &[0, 3]), 2);
+    }
+
+    #[test]
+    fn greedy_top_k_matches_softmax_argmax() {
+        use super::{top_k_argmax, top_k_argmax_by};
+        use candle_core::{Device, Tensor};
+        use rand::{Rng, SeedableRng};
+        use rand_isaac::Isaac64Rng;
+        use std::cell::Cell;
+
+        let dev = Device::Cpu;
+        for vocab in [7, 1024, 32000] {
+            let logits = Tensor::randn(0f32, 4., vocab, &dev).unwrap();
+            let probs = candle_nn::ops::softmax_last_dim(&logits).unwrap();
+            let expected = probs.argmax(0).unwrap().to_scalar::<u32>().unwrap();
+            let logits = logits.to_vec1::<f32>().unwrap();
+            for top_k in [1, 5, 40, vocab] {
+                assert_eq!(top_k_argmax(&logits, top_k), expected);
+            }
+        }
+        // Ties and negative logits.
+        assert_eq!(top_k_argmax(&[-3., -1., -1., -2.], 2), 1);
+        assert_eq!(top_k_argmax(&[f32::NEG_INFINITY, -5., 3.], 1), 2);
+
+        // Partitioning out the top-k takes a linear number of comparisons, fewer than any sort of the
+        // vocabulary could, which needs about `log2(vocab!)` of them.
+        let vocab = 256 * 1024;
+        let mut rng = Isaac64Rng::seed_from_u64(42);
+        let logits = (0..vocab)
+            .map(|_| rng.random_range(-16f32..16.))
+            .collect::<Vec<_>>();
+        let comparisons = Cell::new(0usize);
+        let next_token = top_k_argmax_by(&logits, 40, |a, b| {
+            comparisons.set(comparisons.get() + 1);
+            b.0.cmp(&a.0).then(a.1.cmp(&b.1))
+        });
+        assert_eq!(next_token, top_k_argmax(&logits, 1));
+        let sort_comparisons = (vocab as f64) * (vocab as f64).log2() - 1.443 * vocab as f64;
+        assert!(
+            (comparisons.get() as f64) < sort_comparisons / 2.,
+            "{} comparisons, a sort needs about {sort_comparisons}",
+            comparisons.get()
+        );
+    }
+
     #[test]
     fn test_gumbel_speculative() {
         use super::Sampler;
@@ -725,15 +1294,422 @@ mod tests {
         use std::sync::Arc;
         use std::sync::Mutex;
 
-        let sampler =
-            Sampler::new(None, 10, None, None, None, None, 32, 0.1, 0.05, vec![]).unwrap();
+        let sampler = Sampler::new(
+            None,
+            10,
+            None,
+            None,
+            None,
+            Default::default(),
+            None,
+            32,
+            0.1,
+            0.05,
+            vec![],
+        )
+        .unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
-            .sample(logits, &(0..1024).collect::<Vec<_>>(), false, rng, true)
+            .sample(
+                logits,
+                &(0..1024).collect::<Vec<_>>(),
+                1024,
+                false,
+                rng,
+                true,
+            )
             .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn penalty_scope_excludes_prompt() {
+        use super::{PenaltyScope, Sampler};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // Token 5 is the most likely but only appears in the prompt, token 3 is the runner up.
+        let mut logits = vec![0f32; 8];
+        logits[5] = 10.;
+        logits[3] = 5.;
+        let context = [5, 1, 2];
+        let prompt_len = 2;
+
+        let sample = |penalty_scope| {
+            let sampler = Sampler::new(
+                None,
+                0,
+                None,
+                None,
+                Some(100.),
+                penalty_scope,
+                None,
+                1,
+                0.0,
+                0.0,
+                vec![],
+            )
+            .unwrap();
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+            sampler
+                .sample(
+                    Tensor::new(logits.as_slice(), &Device::Cpu).unwrap(),
+                    &context,
+                    prompt_len,
+                    false,
+                    rng,
+                    false,
+                )
+                .unwrap()
+                .token
+        };
+
+        assert_eq!(sample(PenaltyScope::PromptAndGenerated), 3);
+        assert_eq!(sample(PenaltyScope::GeneratedOnly), 5);
+    }
+
+    #[test]
+    fn custom_rng_is_reproducible() {
+        use super::{Sampler, SamplingRng};
+        use candle_core::{Device, Tensor};
+        use rand::RngCore;
+        use std::sync::{Arc, Mutex};
+
+        /// Deterministic counter-based generator: each output is a mix of an incrementing counter.
+        struct CounterRng(u64);
+
+        impl RngCore for CounterRng {
+            fn next_u32(&mut self) -> u32 {
+                (self.next_u64() >> 32) as u32
+            }
+            fn next_u64(&mut self) -> u64 {
+                self.0 += 1;
+                let mut z = self.0.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                z ^ (z >> 31)
+            }
+            fn fill_bytes(&mut self, dest: &mut [u8]) {
+                for chunk in dest.chunks_mut(8) {
+                    let bytes = self.next_u64().to_le_bytes();
+                    chunk.copy_from_slice(&bytes[..chunk.len()]);
+                }
+            }
+        }
+
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            Default::default(),
+            None,
+            -1,
+            0.0,
+            0.0,
+            vec![],
+        )
+        .unwrap();
+        let logits = (Tensor::arange(0f32, 16f32, &Device::Cpu).unwrap() / 4.).unwrap();
+
+        let draw = || {
+            let counter = Arc::new(Mutex::new(CounterRng(0)));
+            let rng: SamplingRng = counter.clone();
+            let tokens = (0..8)
+                .map(|_| {
+                    sampler
+                        .sample(logits.clone(), &[0], 1, false, rng.clone(), false)
+                        .unwrap()
+                        .token
+                })
+                .collect::<Vec<_>>();
+            let steps = counter.lock().unwrap().0;
+            (tokens, steps)
+        };
+
+        let (first, first_steps) = draw();
+        let (second, second_steps) = draw();
+        assert_eq!(first, second);
+        // The injected generator is the one being used.
+        assert!(first_steps > 0);
+        assert_eq!(first_steps, second_steps);
+    }
+
+    #[test]
+    fn streamed_top_k_includes_sampled_token() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let sampler = Sampler::new(
+            Some(0.7),
+            0,
+            None,
+            None,
+            None,
+            Default::default(),
+            None,
+            -1,
+            0.0,
+            0.0,
+            vec![],
+        )
+        .unwrap()
+        .with_stream_top_k_logprobs(Some(4));
+        // Only a few tokens are likely, so the sampled token is always within the top 4.
+        let mut logits = vec![-20f32; 32];
+        logits[3] = 2.;
+        logits[17] = 1.5;
+        logits[9] = 1.;
+        let logits = Tensor::new(logits.as_slice(), &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+
+        for _ in 0..16 {
+            let res = sampler
+                .sample(logits.clone(), &[3], 1, false, rng.clone(), false)
+                .unwrap();
+            let top_k = res.top_k_logprobs.unwrap();
+            assert_eq!(top_k.len(), 4);
+            assert_eq!(
+                top_k[..3].iter().map(|(tok, _)| *tok).collect::<Vec<_>>(),
+                vec![3, 17, 9]
+            );
+            assert!(top_k.windows(2).all(|w| w[0].1 >= w[1].1));
+            let (_, logprob) = top_k
+                .iter()
+                .find(|(tok, _)| *tok == res.token)
+                .expect("sampled token is in the top k");
+            assert!((logprob - res.logprob).abs() < 1e-5);
+        }
+
+        // Off by default.
+        let sampler = Sampler::new(
+            Some(0.7),
+            0,
+            None,
+            None,
+            None,
+            Default::default(),
+            None,
+            -1,
+            0.0,
+            0.0,
+            vec![],
+        )
+        .unwrap();
+        let res = sampler.sample(logits, &[3], 1, false, rng, false).unwrap();
+        assert!(res.top_k_logprobs.is_none());
+    }
+
+    #[test]
+    fn entropy_matches_manual_computation() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // A repetition penalty changes the distribution, so the entropy must be of the processed one.
+        let sampler = Sampler::new(
+            Some(0.7),
+            0,
+            None,
+            Some(0.5),
+            None,
+            Default::default(),
+            None,
+            -1,
+            0.0,
+            0.0,
+            vec![],
+        )
+        .unwrap()
+        .with_entropy(true);
+        let raw = [2f32, 1.5, 1., 0.5, -1., -3.];
+        let context = [1u32, 1];
+        let logits = Tensor::new(raw.as_slice(), &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let res = sampler
+            .sample(logits.clone(), &context, 0, false, rng.clone(), false)
+            .unwrap();
+        let (entropy, varentropy) = res.entropy.unwrap();
+
+        let processed = sampler
+            .apply_penalties(raw.to_vec(), &context, 0)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_ne!(processed, raw);
+        let scaled = processed.iter().map(|x| x / 0.7).collect::<Vec<_>>();
+        let max = scaled.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum = scaled.iter().map(|x| (x - max).exp()).sum::<f32>();
+        let probs = scaled
+            .iter()
+            .map(|x| (x - max).exp() / sum)
+            .collect::<Vec<_>>();
+        let expected = -probs.iter().map(|p| p * p.ln()).sum::<f32>();
+        let expected_var = probs
+            .iter()
+            .map(|p| p * (-p.ln() - expected).powi(2))
+            .sum::<f32>();
+        assert!((entropy - expected).abs() < 1e-5, "{entropy} != {expected}");
+        assert!((varentropy - expected_var).abs() < 1e-5);
+
+        // Off by default.
+        let sampler = sampler.with_entropy(false);
+        let res = sampler
+            .sample(logits, &context, 0, false, rng, false)
+            .unwrap();
+        assert!(res.entropy.is_none());
+    }
+
+    #[test]
+    fn temperature_order_changes_penalized_distribution() {
+        use super::{Sampler, TemperatureOrder};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let sampler = Sampler::new(
+            Some(0.7),
+            0,
+            None,
+            Some(0.5),
+            None,
+            Default::default(),
+            None,
+            -1,
+            0.0,
+            0.0,
+            vec![],
+        )
+        .unwrap()
+        .with_stream_top_k_logprobs(Some(6));
+        let raw = [2f32, 1.5, 1., 0.5, -1., -3.];
+        let context = [1u32, 1];
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        // Base 10 logprobs by token id, as reported by the sampler.
+        let distribution = |sampler: &Sampler| {
+            let logits = Tensor::new(raw.as_slice(), &Device::Cpu).unwrap();
+            let mut top_k = sampler
+                .sample(logits, &context, 0, false, rng.clone(), false)
+                .unwrap()
+                .top_k_logprobs
+                .unwrap();
+            top_k.sort_by_key(|(tok, _)| *tok);
+            top_k.into_iter().map(|(_, lp)| lp).collect::<Vec<_>>()
+        };
+        let log10_softmax = |xs: Vec<f32>| {
+            let max = xs.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let lse = xs.iter().map(|x| (x - max).exp()).sum::<f32>().ln() + max;
+            xs.iter()
+                .map(|x| (x - lse) / 10f32.ln())
+                .collect::<Vec<_>>()
+        };
+        let assert_close = |a: &[f32], b: &[f32]| {
+            for (a, b) in a.iter().zip(b) {
+                assert!((a - b).abs() < 1e-5, "{a} != {b}");
+            }
+        };
+
+        // The default penalizes the raw logits, then applies the temperature.
+        let after = distribution(&sampler);
+        let penalized = [2f32, 0.5, 1., 0.5, -1., -3.];
+        assert_close(
+            &after,
+            &log10_softmax(penalized.iter().map(|x| x / 0.7).collect()),
+        );
+        let explicit = sampler
+            .clone()
+            .with_temperature_order(TemperatureOrder::AfterPenalties);
+        assert_eq!(distribution(&explicit), after);
+
+        // Applying it first leaves the penalty unscaled, so the penalized token loses less mass.
+        let before =
+            distribution(&sampler.with_temperature_order(TemperatureOrder::BeforePenalties));
+        let expected = raw
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x / 0.7 - if i == 1 { 1. } else { 0. })
+            .collect();
+        assert_close(&before, &log10_softmax(expected));
+        assert!(before[1] > after[1]);
+    }
+
+    #[test]
+    fn penalty_decay_favors_recent_repeats() {
+        use super::Sampler;
+
+        let sampler = Sampler::new(
+            Some(0.7),
+            0,
+            None,
+            Some(1.),
+            Some(1.),
+            Default::default(),
+            None,
+            -1,
+            0.0,
+            0.0,
+            vec![],
+        )
+        .unwrap()
+        .with_penalty_decay(Some(0.5));
+        let raw = vec![4f32, 4., 4., 4.];
+        // Token 1 is the most recent and was also seen 2 tokens ago, token 2 1 token ago and token 0 3
+        // tokens ago.
+        let penalized = sampler
+            .apply_penalties(raw, &[0, 1, 2, 1], 0)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(penalized, vec![4. - 0.25, 4. - 2.25, 4. - 1., 4.]);
+        assert!(penalized[2] < penalized[0]);
+    }
+
+    #[test]
+    fn prompt_eos_separates_penalty_context() {
+        use super::Sampler;
+
+        let sampler = Sampler::new(
+            Some(0.7),
+            0,
+            None,
+            Some(1.),
+            None,
+            Default::default(),
+            None,
+            -1,
+            0.0,
+            0.0,
+            vec![],
+        )
+        .unwrap();
+        let raw = vec![0f32; 6];
+        // Two documents separated by EOS (2), then one generated token.
+        let context = [4u32, 4, 2, 3, 5];
+        let penalties = |sampler: &Sampler| {
+            sampler
+                .apply_penalties(raw.clone(), &context, 4)
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+        };
+        assert_eq!(penalties(&sampler), vec![0., 0., -1., -1., -2., -1.]);
+        let sampler = sampler.with_document_separators(vec![2]);
+        assert_eq!(penalties(&sampler), vec![0., 0., 0., -1., 0., -1.]);
+    }
 }
@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+
+/// What happens to a request whose sequences would exceed `max_concurrent_seqs`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConcurrencyPolicy {
+    /// Hold the request until enough live sequences finish.
+    #[default]
+    Queue,
+    /// Reject the request with a validation error.
+    RejectWithError,
+}
+
+/// Bound on the number of live sequences, each of which holds its own KV cache. This is distinct from
+/// the batch size of the scheduler, which only bounds how many live sequences are stepped at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConcurrencyLimit {
+    pub max_concurrent_seqs: usize,
+    pub policy: ConcurrencyPolicy,
+}
+
+/// Admits the sequences of each request to the scheduler as a group, so that a request with `n` choices
+/// accounts for `n` caches.
+pub(crate) struct SeqAdmission<T> {
+    limit: Option<ConcurrencyLimit>,
+    queued: VecDeque<Vec<T>>,
+}
+
+impl<T> SeqAdmission<T> {
+    pub(crate) fn new(limit: Option<ConcurrencyLimit>) -> Self {
+        Self {
+            limit,
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Check whether a request with `n_seqs` sequences may be accepted when `live` sequences are
+    /// already scheduled, returning the reason it is rejected otherwise.
+    pub(crate) fn check(&self, live: usize, n_seqs: usize) -> Result<(), String> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        if n_seqs > limit.max_concurrent_seqs {
+            return Err(format!(
+                "The request has {n_seqs} sequences, more than the maximum of {} concurrent sequences.",
+                limit.max_concurrent_seqs
+            ));
+        }
+        if limit.policy == ConcurrencyPolicy::RejectWithError
+            && live + self.queued_len() + n_seqs > limit.max_concurrent_seqs
+        {
+            return Err(format!(
+                "The maximum of {} concurrent sequences has been reached.",
+                limit.max_concurrent_seqs
+            ));
+        }
+        Ok(())
+    }
+
+    /// Queue the sequences of an accepted request and return every group which can now be scheduled.
+    pub(crate) fn submit(&mut self, live: usize, seqs: Vec<T>) -> Vec<T> {
+        self.queued.push_back(seqs);
+        self.release(live)
+    }
+
+    /// Return the queued groups, in order, which fit next to `live` scheduled sequences.
+    pub(crate) fn release(&mut self, mut live: usize) -> Vec<T> {
+        let max = self
+            .limit
+            .map_or(usize::MAX, |limit| limit.max_concurrent_seqs);
+        let mut admitted = Vec::new();
+        while let Some(group) = self.queued.front() {
+            if live + group.len() > max {
+                break;
+            }
+            live += group.len();
+            admitted.extend(self.queued.pop_front().unwrap());
+        }
+        admitted
+    }
+
+    /// Remove and return every queued sequence.
+    pub(crate) fn drain(&mut self) -> Vec<T> {
+        self.queued.drain(..).flatten().collect()
+    }
+
+    fn queued_len(&self) -> usize {
+        self.queued.iter().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConcurrencyLimit, ConcurrencyPolicy, SeqAdmission};
+
+    /// Submit `requests` one after the other, finishing the oldest live sequence whenever nothing
+    /// can be admitted, and return the order in which they completed and the rejected ones.
+    fn run(policy: ConcurrencyPolicy, requests: &[Vec<usize>]) -> (Vec<usize>, Vec<usize>) {
+        let mut admission = SeqAdmission::new(Some(ConcurrencyLimit {
+            max_concurrent_seqs: 2,
+            policy,
+        }));
+        let mut live = Vec::new();
+        let mut completed = Vec::new();
+        let mut rejected = Vec::new();
+        for (i, seqs) in requests.iter().enumerate() {
+            if admission.check(live.len(), seqs.len()).is_err() {
+                rejected.push(i);
+                continue;
+            }
+            live.extend(admission.submit(live.len(), seqs.clone()));
+            assert!(live.len() <= 2);
+        }
+        while !live.is_empty() {
+            completed.push(live.remove(0));
+            live.extend(admission.release(live.len()));
+            assert!(live.len() <= 2);
+        }
+        assert!(admission.drain().is_empty());
+        (completed, rejected)
+    }
+
+    #[test]
+    fn queued_sequences_all_complete_in_order() {
+        let (completed, rejected) = run(ConcurrencyPolicy::Queue, &[vec![0], vec![1], vec![2, 3]]);
+        assert_eq!(completed, vec![0, 1, 2, 3]);
+        assert!(rejected.is_empty());
+    }
+
+    #[test]
+    fn excess_sequences_are_rejected() {
+        let (completed, rejected) = run(
+            ConcurrencyPolicy::RejectWithError,
+            &[vec![0], vec![1], vec![2], vec![3, 4, 5]],
+        );
+        assert_eq!(completed, vec![0, 1]);
+        assert_eq!(rejected, vec![2, 3]);
+    }
+}
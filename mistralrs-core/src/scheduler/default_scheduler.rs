@@ -1,7 +1,7 @@
 use std::{
     collections::{HashMap, VecDeque},
     num::NonZeroUsize,
-    sync::atomic::Ordering,
+    sync::{atomic::Ordering, Arc, Mutex},
 };
 
 use crate::{
@@ -88,7 +88,9 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
         let mut seq_buckets: HashMap<BucketKey, Vec<Sequence>> = HashMap::new();
         let mut seq_priorities: HashMap<BucketKey, f64> = HashMap::new();
         for seq in running {
-            let len = seq.len();
+            // Sequences which evicted tokens from their KV cache hold fewer tokens than they have
+            // seen, and a batch must share one cache length.
+            let len = seq.len() - seq.kv_cache_evicted_toks();
             match seq_buckets.get_mut(&(
                 len,
                 seq.images().is_some() && seq.is_prompt(),
@@ -325,6 +327,13 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
         None
     }
     fn free_finished_sequence_groups(&mut self) {}
+    fn drain(&mut self) -> Vec<Arc<Mutex<Sequence>>> {
+        let mut seqs = std::mem::take(&mut self.running);
+        seqs.extend(std::mem::take(&mut self.waiting).into_iter());
+        seqs.into_iter()
+            .map(|seq| Arc::new(Mutex::new(seq)))
+            .collect()
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         None
     }
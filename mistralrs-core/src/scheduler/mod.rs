@@ -1,7 +1,10 @@
+mod admission;
 mod default_scheduler;
 
 use std::sync::Arc;
 
+pub(crate) use admission::SeqAdmission;
+pub use admission::{ConcurrencyLimit, ConcurrencyPolicy};
 pub use default_scheduler::{DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput};
 use tokio::sync::Mutex;
 
@@ -57,6 +60,9 @@ pub trait Scheduler: Send + Sync {
     fn add_seq(&mut self, seq: Sequence);
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
+    /// Remove and return every waiting, running and swapped out sequence, releasing any cache
+    /// blocks they hold, for example to finish them on shutdown.
+    fn drain(&mut self) -> Vec<Arc<std::sync::Mutex<Sequence>>>;
 
     // PagedAttention metadata
     fn block_tables(&self) -> Option<&BlockTables>;
@@ -1,15 +1,19 @@
 use crate::{
     get_mut_group,
     pipeline::{text_models_inputs_processor::PagedAttentionMeta, LayerCaches},
-    response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
-    sampler::{Logprobs, Sampler},
+    request::{KvCacheLimit, StreamFlushPolicy},
+    response::{
+        ChatCompletionChunkResponse, Choice, ChunkChoice, Response, ResponseLogprob,
+        SYSTEM_FINGERPRINT,
+    },
+    sampler::{Logprobs, RepetitionLoopParams, Sampler},
     ChatCompletionResponse, Usage,
 };
 use crate::{
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
     pipeline::{DiffusionGenerationParams, KvCache},
     response::CompletionChoice,
-    tools::ToolCallingMatcher,
+    tools::{ToolCallDetector, ToolCallingMatcher},
     CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, ImageChoice,
     ImageGenerationResponse, ImageGenerationResponseFormat,
 };
@@ -24,6 +28,10 @@ use tokio::sync::{
     Mutex, MutexGuard,
 };
 
+/// Called with the decoded completion so far. Returning `true` stops the sequence with
+/// [`StopReason::ContentFiltered`].
+pub type ContentFilter = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum StopReason {
     Eos,
@@ -35,7 +43,15 @@ pub enum StopReason {
         completion_bytes_pos: usize,
     },
     Canceled,
+    /// The engine was drained while the sequence was in flight. The completion is the partial output.
+    Shutdown,
     GeneratedImage,
+    /// The content filter tripped. The completion is trimmed to `completion_bytes_pos`.
+    ContentFiltered {
+        completion_bytes_pos: usize,
+    },
+    /// The output fell into a loop, see [`RepetitionLoopParams`].
+    RepetitionLoop,
 }
 
 impl Display for StopReason {
@@ -45,7 +61,10 @@ impl Display for StopReason {
             StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
             StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
+            StopReason::Shutdown => write!(f, "shutdown"),
             StopReason::GeneratedImage => write!(f, "generated-image"),
+            StopReason::ContentFiltered { .. } => write!(f, "content_filter"),
+            StopReason::RepetitionLoop => write!(f, "repetition_loop"),
         }
     }
 }
@@ -202,6 +221,10 @@ pub struct Sequence {
     last_is_done: Option<StopReason>,
     completion_bytes: Vec<u8>,
     stream_idx: usize,
+    stream_flushed_toks: usize,
+    top_k_logprobs_stream_idx: usize,
+    entropy_stream_idx: usize,
+    logprob_stream_idx: usize,
     pub recognizer: SequenceRecognizer,
     scheduling_urgency: usize, // The number of passes since scheduling
     input_images: Option<Vec<image::DynamicImage>>,
@@ -220,6 +243,20 @@ pub struct Sequence {
 
     // Tool calls
     pub tools: Option<Arc<ToolCallingMatcher>>,
+    pub tool_call_detector: Option<ToolCallDetector>,
+
+    // Content filtering
+    content_filter: Option<ContentFilter>,
+
+    // KV cache limit
+    kv_cache_limit: Option<KvCacheLimit>,
+    kv_cache_evicted_toks: usize,
+
+    // Loop detection
+    repetition_loop: Option<RepetitionLoopParams>,
+
+    // Token healing
+    healed_prefix: Vec<u8>,
 }
 
 impl BlockEngineSequence for Sequence {
@@ -284,6 +321,8 @@ impl Sequence {
         //
         return_raw_logits: bool,
         eos_tokens: Vec<u32>,
+        content_filter: Option<ContentFilter>,
+        tool_call_detector: Option<ToolCallDetector>,
     ) -> Self {
         let prompt_len = tokens.len();
         let mut custom_metadata = if let Some(block_size) = block_size {
@@ -333,6 +372,10 @@ impl Sequence {
             cumulative_logprob: 0.,
             completion_bytes: Vec::new(),
             stream_idx: 0,
+            stream_flushed_toks: 0,
+            top_k_logprobs_stream_idx: 0,
+            entropy_stream_idx: 0,
+            logprob_stream_idx: 0,
             last_completion_bytes_len: 0,
             last_logprob: 0.0,
             last_is_done: None,
@@ -350,6 +393,12 @@ impl Sequence {
             return_raw_logits,
             token_offset: 0,
             eos_tokens,
+            content_filter,
+            kv_cache_limit: None,
+            kv_cache_evicted_toks: 0,
+            tool_call_detector,
+            repetition_loop: None,
+            healed_prefix: Vec::new(),
         }
     }
 
@@ -397,6 +446,46 @@ impl Sequence {
         self
     }
 
+    pub fn with_kv_cache_limit(mut self, limit: Option<KvCacheLimit>) -> Self {
+        self.kv_cache_limit = limit;
+        self
+    }
+
+    pub fn with_repetition_loop(mut self, repetition_loop: Option<RepetitionLoopParams>) -> Self {
+        self.repetition_loop = repetition_loop;
+        self
+    }
+
+    /// The bytes of the prompt token dropped by token healing. The first generated token starts with
+    /// them, and they are removed from its text as the prompt already contains them.
+    pub fn with_healed_prefix(mut self, healed_prefix: Vec<u8>) -> Self {
+        self.healed_prefix = healed_prefix;
+        self
+    }
+
+    /// Remove the healed prefix from the bytes of the first generated token, see
+    /// [`Self::with_healed_prefix`]. Later tokens are returned unchanged.
+    pub(crate) fn strip_healed_prefix(&mut self, token_bytes: Vec<u8>) -> Vec<u8> {
+        let healed_prefix = std::mem::take(&mut self.healed_prefix);
+        match token_bytes.strip_prefix(healed_prefix.as_slice()) {
+            Some(rest) => rest.to_vec(),
+            None => token_bytes,
+        }
+    }
+
+    pub fn kv_cache_limit(&self) -> Option<KvCacheLimit> {
+        self.kv_cache_limit
+    }
+
+    /// The number of tokens evicted from the KV cache to keep it within the [`KvCacheLimit`].
+    pub fn kv_cache_evicted_toks(&self) -> usize {
+        self.kv_cache_evicted_toks
+    }
+
+    pub(crate) fn add_kv_cache_evicted_toks(&mut self, n: usize) {
+        self.kv_cache_evicted_toks += n;
+    }
+
     /// This is the number of tokens. If the KV cache is Some, then it will use that.
     pub fn len(&self) -> usize {
         if let Some(toks) = &self.prefill_prompt_toks {
@@ -581,7 +670,14 @@ impl Sequence {
             is_done,
             Some(StopReason::Eos) | Some(StopReason::StopTok(_))
         );
-        if !stopped_by_token {
+        if let Some(StopReason::ContentFiltered {
+            completion_bytes_pos,
+        }) = is_done
+        {
+            // Drop the token which tripped the filter.
+            self.completion_bytes.truncate(*completion_bytes_pos);
+            self.stream_idx = self.stream_idx.min(*completion_bytes_pos);
+        } else if !stopped_by_token {
             // Completion bytes is used to check for stop strings, and as the response buffer.
             // We don't need to add stop tokens to the completion bytes to check for stop strings.
             // And by not adding it here, we can avoid having to delete these tokens from the output.
@@ -645,6 +741,10 @@ impl Sequence {
             Some(StopReason::Length(self.max_len.unwrap()))
         } else if self.tokens.len().saturating_sub(self.prompt_len) == max_model_len {
             Some(StopReason::ModelLength(max_model_len))
+        } else if self.repetition_loop.is_some_and(|params| {
+            params.ends_in_loop(self.tokens.get(self.prompt_len..).unwrap_or_default(), tok)
+        }) {
+            Some(StopReason::RepetitionLoop)
         } else {
             if !self.stop_strings.is_empty() {
                 for (idx, s) in self.stop_strings.iter().enumerate() {
@@ -661,6 +761,19 @@ impl Sequence {
         }
     }
 
+    /// Run the content filter over the completion extended by the bytes of the next token, before
+    /// that token is added and streamed. If the filter trips, the token is dropped.
+    pub fn check_content_filter(&self, token_bytes: &[u8]) -> Option<StopReason> {
+        let content_filter = self.content_filter.as_ref()?;
+        let mut candidate = self.completion_bytes.clone();
+        candidate.extend_from_slice(token_bytes);
+        content_filter(&String::from_utf8_lossy(&candidate)).then_some(
+            StopReason::ContentFiltered {
+                completion_bytes_pos: self.completion_bytes.len(),
+            },
+        )
+    }
+
     pub fn logprobs(&self) -> &[Logprobs] {
         &self.logprobs
     }
@@ -688,6 +801,96 @@ impl Sequence {
         new_decoded
     }
 
+    /// Like [`Self::get_delta`], but only returns the text up to the last boundary of `policy` and
+    /// keeps the rest pending. With `flush_all`, everything pending is returned.
+    pub fn get_flushed_delta(
+        &mut self,
+        policy: StreamFlushPolicy,
+        flush_all: bool,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(mut delta) = self.peek_delta()? else {
+            return Ok(None);
+        };
+        let pending_bytes = self.completion_bytes.len() - self.stream_idx;
+        // Lossy decoding may change the byte length, so only cut deltas which decoded cleanly.
+        let len = if flush_all || delta.contains('�') {
+            delta.len()
+        } else {
+            policy.flush_len(&delta, self.logprobs.len() - self.stream_flushed_toks)
+        };
+        if len == 0 && !flush_all {
+            return Ok(None);
+        }
+        // The first delta has its leading whitespace trimmed.
+        let trimmed = pending_bytes.saturating_sub(delta.len());
+        self.stream_idx = if len == delta.len() {
+            self.completion_bytes.len()
+        } else {
+            self.stream_idx + trimmed + len
+        };
+        self.stream_flushed_toks = self.logprobs.len();
+        delta.truncate(len);
+        Ok(Some(delta))
+    }
+
+    /// Returns the raw completion bytes since the last delta, advancing the stream index.
+    ///
+    /// Unlike [`Self::get_delta`], the bytes are returned even if they end in an incomplete UTF-8
+    /// sequence, so the caller must assemble valid UTF-8 across deltas.
+    pub fn get_delta_bytes(&mut self) -> Option<Vec<u8>> {
+        if self.stream_idx >= self.completion_bytes.len() {
+            return None;
+        }
+        let mut delta = &self.completion_bytes[self.stream_idx..];
+        // Match the string deltas, which drop the leading space of the first token.
+        if self.stream_idx == 0 {
+            delta = delta.trim_ascii_start();
+        }
+        let delta = delta.to_vec();
+        self.stream_idx = self.completion_bytes.len();
+        Some(delta)
+    }
+
+    /// Returns the top-k logprobs of each token generated since the last call, if they were requested.
+    pub fn take_top_k_logprobs(&mut self) -> Option<Vec<Vec<(u32, f32)>>> {
+        let start = self.top_k_logprobs_stream_idx.min(self.logprobs.len());
+        self.top_k_logprobs_stream_idx = self.logprobs.len();
+        let top_k = self.logprobs[start..]
+            .iter()
+            .filter_map(|logprobs| logprobs.top_k_logprobs.clone())
+            .collect::<Vec<_>>();
+        (!top_k.is_empty()).then_some(top_k)
+    }
+
+    /// Returns the `(entropy, varentropy)` of each token generated since the last call, if they were
+    /// requested.
+    pub fn take_entropies(&mut self) -> Option<Vec<(f32, f32)>> {
+        let start = self.entropy_stream_idx.min(self.logprobs.len());
+        self.entropy_stream_idx = self.logprobs.len();
+        let entropies = self.logprobs[start..]
+            .iter()
+            .filter_map(|logprobs| logprobs.entropy)
+            .collect::<Vec<_>>();
+        (!entropies.is_empty()).then_some(entropies)
+    }
+
+    /// Merges the logprobs of the tokens generated since the last call into one entry for a streamed
+    /// chunk with text `token`, as a flush policy may send several tokens at once. The logprob is the
+    /// sum over those tokens and the top logprobs are those of the last one. A policy which holds back
+    /// the end of a token's text still counts that token in this chunk, so each token is counted once.
+    pub fn take_chunk_logprob(&mut self, token: String) -> Option<ResponseLogprob> {
+        let start = self.logprob_stream_idx.min(self.logprobs.len());
+        self.logprob_stream_idx = self.logprobs.len();
+        let chunk = &self.logprobs[start..];
+        let last = chunk.last()?;
+        Some(ResponseLogprob {
+            bytes: Some(token.as_bytes().to_vec()),
+            token,
+            logprob: chunk.iter().map(|logprobs| logprobs.logprob).sum(),
+            top_logprobs: last.top_logprobs.clone().unwrap_or_default(),
+        })
+    }
+
     /// Peeks at the delta between the last two decoded sequences, but does not advance the stream index.
     pub fn peek_delta(&self) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let is_first = self.stream_idx == 0;
@@ -830,6 +1033,10 @@ pub struct SequenceGroup {
     pub completion_streaming_chunks: Vec<CompletionChunkChoice>,
     pub is_streaming: bool,
     pub is_chat: bool,
+    /// Streaming chunks also carry the raw bytes of each delta.
+    pub stream_raw_bytes: bool,
+    /// Where streamed text is cut into chunks.
+    pub stream_flush_policy: StreamFlushPolicy,
 }
 
 impl SequenceGroup {
@@ -855,6 +1062,8 @@ impl SequenceGroup {
             is_streaming,
             is_chat,
             best_of,
+            stream_raw_bytes: false,
+            stream_flush_policy: StreamFlushPolicy::default(),
         }
     }
 
@@ -1001,3 +1210,414 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+
+    use super::{
+        ContentFilter, SeqStepType, Sequence, SequenceGroup, SequenceRecognizer, StopReason,
+    };
+    use crate::sampler::{Logprobs, RepetitionLoopParams, Sampler};
+
+    fn new_seq(content_filter: ContentFilter) -> Sequence {
+        new_seq_with(0, None, Some(content_filter))
+    }
+
+    fn new_seq_with(
+        id: usize,
+        block_size: Option<usize>,
+        content_filter: Option<ContentFilter>,
+    ) -> Sequence {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            Default::default(),
+            None,
+            -1,
+            0.0,
+            0.0,
+            vec![],
+        )
+        .unwrap();
+        let group = Arc::new(Mutex::new(SequenceGroup::new(1, false, false, None)));
+        Sequence::new_waiting(
+            vec![1, 2],
+            "prompt".to_string(),
+            id,
+            0,
+            1,
+            tx,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            false,
+            false,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            block_size,
+            None,
+            None,
+            SeqStepType::PromptAndDecode,
+            None,
+            None,
+            false,
+            vec![],
+            content_filter,
+            None,
+        )
+    }
+
+    #[test]
+    fn prompt_eos_does_not_stop_generation() {
+        // The prompt `[1, 2]` contains the EOS token 2, which only stops the sequence when generated.
+        let seq = new_seq(Arc::new(|_: &str| false));
+        assert!(seq.get_toks().contains(&2));
+        assert_eq!(seq.is_done(3, Some(&[2][..]), 4096), None);
+        assert_eq!(seq.is_done(2, Some(&[2][..]), 4096), Some(StopReason::Eos));
+    }
+
+    #[test]
+    fn word_flush_policy_chunks_on_word_boundaries() {
+        use crate::StreamFlushPolicy;
+
+        let mut seq = new_seq(Arc::new(|_: &str| false));
+        let pieces = [" Hel", "lo", " wor", "ld", ",", " how", " are", " y", "ou"];
+        let mut chunks = Vec::new();
+        for (tok, piece) in pieces.iter().enumerate() {
+            seq.add_token(
+                Logprobs {
+                    token: tok as u32,
+                    logprob: 0.,
+                    bytes: None,
+                    top_logprobs: None,
+                    top_k_logprobs: None,
+                    entropy: None,
+                },
+                piece.as_bytes().to_vec(),
+                &None,
+            );
+            let is_last = tok == pieces.len() - 1;
+            if let Some(chunk) = seq
+                .get_flushed_delta(StreamFlushPolicy::Word, is_last)
+                .unwrap()
+            {
+                chunks.push(chunk);
+            }
+        }
+
+        assert_eq!(chunks, ["Hello", " world,", " how", " are", " you"]);
+        assert_eq!(chunks.concat(), "Hello world, how are you");
+    }
+
+    #[test]
+    fn chunk_logprob_merges_flushed_tokens() {
+        use crate::{StreamFlushPolicy, TopLogprob};
+
+        let mut seq = new_seq(Arc::new(|_: &str| false));
+        let mut chunks = Vec::new();
+        for (tok, (piece, logprob)) in [(" Hel", -0.5), ("lo", -0.25), (" wor", -1.)]
+            .into_iter()
+            .enumerate()
+        {
+            seq.add_token(
+                Logprobs {
+                    token: tok as u32,
+                    logprob,
+                    bytes: None,
+                    top_logprobs: Some(vec![TopLogprob {
+                        token: tok as u32,
+                        logprob,
+                        bytes: None,
+                    }]),
+                    top_k_logprobs: None,
+                    entropy: None,
+                },
+                piece.as_bytes().to_vec(),
+                &None,
+            );
+            if let Some(chunk) = seq
+                .get_flushed_delta(StreamFlushPolicy::Word, false)
+                .unwrap()
+            {
+                chunks.push(seq.take_chunk_logprob(chunk).unwrap());
+            }
+        }
+
+        // The first chunk is sent once ` wor` starts a new word and covers all three tokens.
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].token, "Hello");
+        assert_eq!(chunks[0].logprob, -1.75);
+        assert_eq!(chunks[0].bytes.as_deref(), Some(&b"Hello"[..]));
+        assert_eq!(chunks[0].top_logprobs[0].token, 2);
+        assert!(seq.take_chunk_logprob(String::new()).is_none());
+    }
+
+    #[test]
+    fn healed_prefix_is_not_repeated() {
+        // The prompt ended in ` hel`, which token healing dropped. The model then generates ` hello`.
+        let mut seq = new_seq(Arc::new(|_: &str| false)).with_healed_prefix(b" hel".to_vec());
+        let mut streamed = Vec::new();
+        for (tok, piece) in [" hello", " hel", "p"].iter().enumerate() {
+            let token_bytes = seq.strip_healed_prefix(piece.as_bytes().to_vec());
+            seq.add_token(
+                Logprobs {
+                    token: tok as u32,
+                    logprob: 0.,
+                    bytes: None,
+                    top_logprobs: None,
+                    top_k_logprobs: None,
+                    entropy: None,
+                },
+                token_bytes,
+                &None,
+            );
+            streamed.push(seq.get_delta().unwrap().unwrap());
+        }
+
+        // Only the first token overlaps the prompt.
+        assert_eq!(streamed, ["lo", " hel", "p"]);
+        assert_eq!(seq.completion_bytes(), b"lo help");
+    }
+
+    #[test]
+    fn content_filter_stops_and_trims() {
+        let mut seq = new_seq(Arc::new(|text: &str| text.contains("forbidden")));
+
+        let mut stop_reason = None;
+        let mut streamed = String::new();
+        for (tok, piece) in [" The", " forbidden", " word", " here"].iter().enumerate() {
+            let is_done = seq
+                .is_done(tok as u32, None, 4096)
+                .or_else(|| seq.check_content_filter(piece.as_bytes()));
+            seq.add_token(
+                Logprobs {
+                    token: tok as u32,
+                    logprob: 0.,
+                    bytes: None,
+                    top_logprobs: None,
+                    top_k_logprobs: None,
+                    entropy: None,
+                },
+                piece.as_bytes().to_vec(),
+                &is_done,
+            );
+            if let Some(delta) = seq.get_delta().unwrap() {
+                streamed.push_str(&delta);
+            }
+            if is_done.is_some() {
+                stop_reason = is_done;
+                break;
+            }
+        }
+
+        assert_eq!(
+            stop_reason,
+            Some(StopReason::ContentFiltered {
+                completion_bytes_pos: 4
+            })
+        );
+        assert_eq!(stop_reason.unwrap().to_string(), "content_filter");
+        assert_eq!(seq.completion_bytes(), b" The");
+        // The offending token was never streamed.
+        assert_eq!(streamed, "The");
+    }
+
+    #[test]
+    fn repetition_loop_stops_looping_output_only() {
+        let params = RepetitionLoopParams {
+            max_pattern_len: 4,
+            min_repeats: 3,
+        };
+        let generate = |toks: &[u32]| {
+            let mut seq = new_seq(Arc::new(|_: &str| false)).with_repetition_loop(Some(params));
+            for (i, &tok) in toks.iter().enumerate() {
+                let is_done = seq.is_done(tok, None, 4096);
+                if is_done.is_some() {
+                    return (i + 1, is_done);
+                }
+                seq.add_token(
+                    Logprobs {
+                        token: tok,
+                        logprob: 0.,
+                        bytes: None,
+                        top_logprobs: None,
+                        top_k_logprobs: None,
+                        entropy: None,
+                    },
+                    vec![],
+                    &is_done,
+                );
+            }
+            (toks.len(), None)
+        };
+
+        // `7 8 9` loops after a preamble, and is caught on the token completing the third repeat.
+        let looping = [3, 4, 5, 7, 8, 9, 7, 8, 9, 7, 8, 9, 7, 8, 9];
+        assert_eq!(generate(&looping), (12, Some(StopReason::RepetitionLoop)));
+        assert_eq!(StopReason::RepetitionLoop.to_string(), "repetition_loop");
+
+        // Too few repeats or too long a pattern is not a loop. Only the output counts, so the `1 2`
+        // of the prompt does not make a third repeat.
+        let not_looping = [
+            1, 2, 1, 2, 5, 5, 6, 7, 6, 7, 3, 1, 2, 3, 4, 5, 1, 2, 3, 4, 5, 1, 2, 3,
+        ];
+        assert_eq!(generate(&not_looping), (not_looping.len(), None));
+    }
+
+    #[test]
+    fn paged_attn_drain_returns_every_seq_and_frees_its_blocks() {
+        use crate::{
+            paged_attention::{
+                BlockUsage, CacheConfig, PagedAttentionScheduler, PagedAttentionSchedulerConfig,
+            },
+            scheduler::Scheduler,
+            PagedCacheType,
+        };
+
+        let cache_config = CacheConfig {
+            block_size: 16,
+            num_gpu_blocks: 8,
+            num_cpu_blocks: 0,
+            cache_type: PagedCacheType::Auto,
+            layer_cache_types: Vec::new(),
+            usage: BlockUsage::default(),
+        };
+        let mut scheduler = PagedAttentionScheduler::new(
+            PagedAttentionSchedulerConfig { max_num_seqs: 2 },
+            cache_config.clone(),
+        );
+        let mut running = new_seq_with(0, Some(16), None);
+        running.add_token(
+            Logprobs {
+                token: 3,
+                logprob: 0.,
+                bytes: None,
+                top_logprobs: None,
+                top_k_logprobs: None,
+                entropy: None,
+            },
+            b" Hello".to_vec(),
+            &None,
+        );
+        scheduler.add_seq(running);
+        scheduler.add_seq(new_seq_with(1, Some(16), None));
+
+        // Only one sequence fits next to `max_num_seqs`, so the other one keeps waiting. The output
+        // of the last step still shares the running sequence.
+        let output = scheduler.schedule();
+        assert_eq!(output.scheduled.len(), 1);
+        assert_eq!((scheduler.running_len(), scheduler.waiting_len()), (1, 1));
+        assert_eq!(cache_config.stats().used_gpu_blocks, 1);
+
+        let drained = scheduler.drain();
+        assert_eq!((scheduler.running_len(), scheduler.waiting_len()), (0, 0));
+        assert_eq!(cache_config.stats().used_gpu_blocks, 0);
+        let mut ids = drained
+            .iter()
+            .map(|seq| *seq.lock().unwrap().id())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![0, 1]);
+        // The running sequence keeps the output it had generated so far.
+        let running = drained.iter().find(|seq| *seq.lock().unwrap().id() == 0);
+        assert_eq!(
+            running.unwrap().lock().unwrap().completion_bytes(),
+            b" Hello"
+        );
+        drop(output);
+    }
+
+    #[test]
+    fn eos_override_replaces_stop_tokens() -> anyhow::Result<()> {
+        use std::str::FromStr;
+
+        use tokenizers::Tokenizer;
+
+        use crate::pipeline::chat_template::{
+            apply_special_token_overrides, calculate_eos_tokens, ChatTemplate,
+        };
+
+        let tokenizer = Tokenizer::from_str(
+            r#"{
+                "version": "1.0",
+                "pre_tokenizer": {"type": "Whitespace"},
+                "model": {"type": "WordLevel", "vocab": {"<s>": 0, "</s>": 1, "<eot>": 2, "hello": 3, "<unk>": 4}, "unk_token": "<unk>"}
+            }"#,
+        )
+        .map_err(anyhow::Error::msg)?;
+        let mut chat_template: ChatTemplate =
+            serde_json::from_str(r#"{"bos_token": "<s>", "eos_token": "</s>"}"#)?;
+
+        let mut eos = calculate_eos_tokens(&chat_template, None, &tokenizer);
+        assert_eq!(eos, vec![1]);
+
+        assert!(apply_special_token_overrides(
+            &mut chat_template,
+            &mut eos,
+            None,
+            Some(&[5]),
+            &tokenizer
+        )
+        .is_err());
+
+        apply_special_token_overrides(
+            &mut chat_template,
+            &mut eos,
+            Some(3),
+            Some(&[2]),
+            &tokenizer,
+        )?;
+        assert_eq!(eos, vec![2]);
+        assert_eq!(chat_template.bos_tok().as_deref(), Some("hello"));
+
+        let seq = new_seq(Arc::new(|_: &str| false));
+        assert_eq!(seq.is_done(1, Some(&eos), 4096), None);
+        assert_eq!(seq.is_done(2, Some(&eos), 4096), Some(StopReason::Eos));
+        Ok(())
+    }
+
+    #[test]
+    fn byte_deltas_assemble_partial_utf8() {
+        let mut seq = new_seq(Arc::new(|_: &str| false));
+
+        // "é€!" where both multi-byte characters are split across tokens.
+        let pieces: [&[u8]; 5] = [b" \xC3", b"\xA9\xE2", b"\x82", b"\xAC", b"!"];
+        let mut assembled = Vec::new();
+        let mut lossy = String::new();
+        for (tok, piece) in pieces.iter().enumerate() {
+            seq.add_token(
+                Logprobs {
+                    token: tok as u32,
+                    logprob: 0.,
+                    bytes: None,
+                    top_logprobs: None,
+                    top_k_logprobs: None,
+                    entropy: None,
+                },
+                piece.to_vec(),
+                &None,
+            );
+            let delta = seq.get_delta_bytes().unwrap();
+            lossy.push_str(&String::from_utf8_lossy(&delta));
+            assembled.extend(delta);
+        }
+
+        assert_eq!(String::from_utf8(assembled).unwrap(), "é€!");
+        // Decoding each delta on its own would have produced replacement characters.
+        assert!(lossy.contains('\u{FFFD}'));
+        assert!(seq.get_delta_bytes().is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fs::File, num::NonZeroUsize, path::PathBuf, str::FromStr};
+use std::{fs::File, path::PathBuf, str::FromStr};
 
 use mistralrs_quant::MULTI_LORA_DELIMITER;
 use serde::Deserialize;
@@ -6,9 +6,9 @@ use serde::Deserialize;
 use crate::{
     amoe::AnyMoeConfig, pipeline::IsqOrganization, AnyMoeLoader, AutoDeviceMapParams,
     GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader,
-    ModelDType, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
-    SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
-    GGUF_MULTI_FILE_DELIMITER, UQFF_MULTI_FILE_DELIMITER,
+    ModelDType, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, PromptChunksize,
+    SpeculativeConfig, SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType,
+    VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER, UQFF_MULTI_FILE_DELIMITER,
 };
 
 fn default_one() -> usize {
@@ -63,6 +63,9 @@ pub enum TomlModelSelected {
         /// UQFF path to write to.
         write_uqff: Option<PathBuf>,
 
+        /// Maximum size in MB of each UQFF shard written with `write_uqff`. Defaults to 10 GB.
+        uqff_shard_size_mb: Option<usize>,
+
         /// UQFF path to load from. If provided, this takes precedence over applying ISQ.
         from_uqff: Option<String>,
 
@@ -114,6 +117,9 @@ pub enum TomlModelSelected {
         /// UQFF path to write to.
         write_uqff: Option<PathBuf>,
 
+        /// Maximum size in MB of each UQFF shard written with `write_uqff`. Defaults to 10 GB.
+        uqff_shard_size_mb: Option<usize>,
+
         /// UQFF path to load from. If provided, this takes precedence over applying ISQ.
         from_uqff: Option<String>,
 
@@ -150,6 +156,9 @@ pub enum TomlModelSelected {
         /// UQFF path to write to.
         write_uqff: Option<PathBuf>,
 
+        /// Maximum size in MB of each UQFF shard written with `write_uqff`. Defaults to 10 GB.
+        uqff_shard_size_mb: Option<usize>,
+
         /// UQFF path to load from. If provided, this takes precedence over applying ISQ.
         from_uqff: Option<String>,
 
@@ -406,6 +415,9 @@ pub enum TomlModelSelected {
         /// UQFF path to write to.
         write_uqff: Option<PathBuf>,
 
+        /// Maximum size in MB of each UQFF shard written with `write_uqff`. Defaults to 10 GB.
+        uqff_shard_size_mb: Option<usize>,
+
         /// UQFF path to load from. If provided, this takes precedence over applying ISQ.
         from_uqff: Option<String>,
 
@@ -446,6 +458,10 @@ pub struct SpeculativeTomlModelSelected {
     /// Gamma value for the model
     gamma: usize,
 
+    /// Have the draft model reference the target model's embeddings instead of loading its own
+    #[serde(default)]
+    share_embeddings: bool,
+
     /// Base model
     draft_model: TomlModelSelected,
 }
@@ -493,7 +509,7 @@ struct TomlLoaderInnerParams {
     chat_template: Option<String>,
     no_kv_cache: bool,
     tokenizer_json: Option<String>,
-    prompt_chunksize: Option<NonZeroUsize>,
+    prompt_chunksize: Option<PromptChunksize>,
     jinja_explicit: Option<String>,
 }
 
@@ -501,7 +517,7 @@ pub struct TomlLoaderArgs {
     pub use_flash_attn: bool,
     pub chat_template: Option<String>,
     pub no_kv_cache: bool,
-    pub prompt_chunksize: Option<NonZeroUsize>,
+    pub prompt_chunksize: Option<PromptChunksize>,
     pub jinja_explicit: Option<String>,
 }
 
@@ -600,6 +616,7 @@ fn loader_from_selected(
             topology,
             organization,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             imatrix,
             calibration_file,
@@ -613,6 +630,7 @@ fn loader_from_selected(
                 topology: Topology::from_option_path(topology)?,
                 organization: organization.unwrap_or_default(),
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.split(UQFF_MULTI_FILE_DELIMITER)
                         .map(PathBuf::from_str)
@@ -622,6 +640,22 @@ fn loader_from_selected(
                 imatrix,
                 calibration_file,
                 hf_cache_path,
+                activation_override: None,
+                prefill_dtype: None,
+                split_kv_segment_len: None,
+                split_kv_accumulation: Default::default(),
+                embedding_dtype: None,
+                lm_head_dtype: None,
+                f32_norm_weights: false,
+                isq_overrides: Vec::new(),
+                pruned_heads: Default::default(),
+                attention_head_scales: Default::default(),
+                head_dim_alignment: None,
+                max_cached_rope_positions: None,
+                offload_activations: false,
+                share_rope_tables: false,
+                lazy_rope_tables: false,
+                shard_download_retries: 0,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -639,6 +673,7 @@ fn loader_from_selected(
             dtype: _,
             topology,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             max_seq_len: _,
             max_batch_size: _,
@@ -650,6 +685,7 @@ fn loader_from_selected(
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.split(UQFF_MULTI_FILE_DELIMITER)
                         .map(PathBuf::from_str)
@@ -659,6 +695,22 @@ fn loader_from_selected(
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                activation_override: None,
+                prefill_dtype: None,
+                split_kv_segment_len: None,
+                split_kv_accumulation: Default::default(),
+                embedding_dtype: None,
+                lm_head_dtype: None,
+                f32_norm_weights: false,
+                isq_overrides: Vec::new(),
+                pruned_heads: Default::default(),
+                attention_head_scales: Default::default(),
+                head_dim_alignment: None,
+                max_cached_rope_positions: None,
+                offload_activations: false,
+                share_rope_tables: false,
+                lazy_rope_tables: false,
+                shard_download_retries: 0,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -683,6 +735,7 @@ fn loader_from_selected(
             dtype: _,
             topology,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             max_seq_len: _,
             max_batch_size: _,
@@ -694,6 +747,7 @@ fn loader_from_selected(
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.split(UQFF_MULTI_FILE_DELIMITER)
                         .map(PathBuf::from_str)
@@ -703,6 +757,22 @@ fn loader_from_selected(
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                activation_override: None,
+                prefill_dtype: None,
+                split_kv_segment_len: None,
+                split_kv_accumulation: Default::default(),
+                embedding_dtype: None,
+                lm_head_dtype: None,
+                f32_norm_weights: false,
+                isq_overrides: Vec::new(),
+                pruned_heads: Default::default(),
+                attention_head_scales: Default::default(),
+                head_dim_alignment: None,
+                max_cached_rope_positions: None,
+                offload_activations: false,
+                share_rope_tables: false,
+                lazy_rope_tables: false,
+                shard_download_retries: 0,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -734,7 +804,7 @@ fn loader_from_selected(
                 .map(ToOwned::to_owned)
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.no_kv_cache,
@@ -761,7 +831,7 @@ fn loader_from_selected(
                 .map(ToOwned::to_owned)
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.no_kv_cache,
@@ -794,7 +864,7 @@ fn loader_from_selected(
                 .map(ToOwned::to_owned)
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.no_kv_cache,
@@ -820,7 +890,7 @@ fn loader_from_selected(
         } => GGMLLoaderBuilder::new(
             GGMLSpecificConfig {
                 gqa,
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -847,7 +917,7 @@ fn loader_from_selected(
         } => GGMLLoaderBuilder::new(
             GGMLSpecificConfig {
                 gqa,
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -882,7 +952,7 @@ fn loader_from_selected(
         } => GGMLLoaderBuilder::new(
             GGMLSpecificConfig {
                 gqa,
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -907,6 +977,7 @@ fn loader_from_selected(
             dtype: _,
             topology,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             max_edge,
             calibration_file,
@@ -919,9 +990,10 @@ fn loader_from_selected(
         } => VisionLoaderBuilder::new(
             VisionSpecificConfig {
                 use_flash_attn,
-                prompt_chunksize: args.prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(args.prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.split(UQFF_MULTI_FILE_DELIMITER)
                         .map(PathBuf::from_str)
@@ -932,6 +1004,7 @@ fn loader_from_selected(
                 calibration_file,
                 imatrix,
                 hf_cache_path,
+                shard_download_retries: 0,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -963,6 +1036,7 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
                 draft: draft_loader,
                 config: SpeculativeConfig {
                     gamma: speculative.gamma,
+                    share_embeddings: speculative.share_embeddings,
                 },
             })
         } else {
@@ -0,0 +1,153 @@
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::{CalledFunction, ToolCallResponse, ToolCallType};
+
+/// Configures the detection of a tool call in the generated output.
+///
+/// Once `token` is sampled, the following tokens are constrained to `arguments_schema` and the call
+/// is emitted as soon as the arguments form a complete JSON value.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ToolCallTrigger {
+    /// Token which starts a tool call, for example the id of `<tool_call>`.
+    pub token: u32,
+    /// Name of the called function.
+    pub name: String,
+    /// JSON schema of the function arguments.
+    pub arguments_schema: Value,
+}
+
+/// What happened after a token was observed by a [`ToolCallDetector`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToolCallEvent {
+    None,
+    /// The trigger was sampled, so the following tokens should be constrained to the arguments schema.
+    Triggered,
+    /// The arguments are complete and the call is available from [`ToolCallDetector::call`].
+    Completed,
+}
+
+enum DetectorState {
+    Watching,
+    Arguments(Vec<u8>),
+    Done(ToolCallResponse),
+}
+
+/// Watches the incremental output of a sequence for a [`ToolCallTrigger`] and parses the arguments
+/// which follow it.
+pub struct ToolCallDetector {
+    trigger: ToolCallTrigger,
+    state: DetectorState,
+}
+
+impl ToolCallDetector {
+    pub fn new(trigger: ToolCallTrigger) -> Self {
+        Self {
+            trigger,
+            state: DetectorState::Watching,
+        }
+    }
+
+    pub fn trigger(&self) -> &ToolCallTrigger {
+        &self.trigger
+    }
+
+    /// Whether the trigger has been seen and the arguments are still being generated.
+    pub fn in_call(&self) -> bool {
+        matches!(self.state, DetectorState::Arguments(_))
+    }
+
+    /// The detected call, once the arguments are complete.
+    pub fn call(&self) -> Option<&ToolCallResponse> {
+        match &self.state {
+            DetectorState::Done(call) => Some(call),
+            _ => None,
+        }
+    }
+
+    /// Observe a newly sampled token and its decoded bytes.
+    pub fn observe(&mut self, token: u32, bytes: &[u8]) -> anyhow::Result<ToolCallEvent> {
+        if matches!(self.state, DetectorState::Watching) && token == self.trigger.token {
+            self.state = DetectorState::Arguments(Vec::new());
+            return Ok(ToolCallEvent::Triggered);
+        }
+        let DetectorState::Arguments(buf) = &mut self.state else {
+            return Ok(ToolCallEvent::None);
+        };
+
+        buf.extend_from_slice(bytes);
+        let arguments = match serde_json::Deserializer::from_slice(buf)
+            .into_iter::<Value>()
+            .next()
+        {
+            Some(Ok(arguments)) => arguments,
+            // EOF means the arguments are valid JSON so far, but not yet complete.
+            Some(Err(e)) if e.is_eof() => return Ok(ToolCallEvent::None),
+            None => return Ok(ToolCallEvent::None),
+            Some(Err(e)) => anyhow::bail!("Tool call arguments are not valid JSON: {e}"),
+        };
+
+        self.state = DetectorState::Done(ToolCallResponse {
+            id: format!("call-{}", Uuid::new_v4()),
+            tp: ToolCallType::Function,
+            function: CalledFunction {
+                name: self.trigger.name.clone(),
+                arguments: serde_json::to_string(&arguments)?,
+            },
+        });
+        Ok(ToolCallEvent::Completed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{ToolCallDetector, ToolCallEvent, ToolCallTrigger};
+
+    #[test]
+    fn trigger_then_arguments_emits_call() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "city": { "type": "string" },
+                "days": { "type": "integer" }
+            },
+            "required": ["city", "days"]
+        });
+        let mut detector = ToolCallDetector::new(ToolCallTrigger {
+            token: 7,
+            name: "get_weather".to_string(),
+            arguments_schema: schema.clone(),
+        });
+
+        let steps: [(u32, &str, ToolCallEvent); 6] = [
+            (1, "Sure", ToolCallEvent::None),
+            (7, "<tool_call>", ToolCallEvent::Triggered),
+            (20, "{\"city\"", ToolCallEvent::None),
+            (21, ": \"Par", ToolCallEvent::None),
+            (22, "is\", \"days\": 3", ToolCallEvent::None),
+            (23, "}", ToolCallEvent::Completed),
+        ];
+        for (token, text, expected) in steps {
+            assert_eq!(detector.observe(token, text.as_bytes()).unwrap(), expected);
+            assert_eq!(
+                detector.in_call(),
+                token != 1 && expected != ToolCallEvent::Completed
+            );
+        }
+
+        let call = detector.call().expect("call should be detected");
+        assert_eq!(call.function.name, "get_weather");
+        let arguments: Value = serde_json::from_str(&call.function.arguments).unwrap();
+        assert_eq!(arguments, json!({ "city": "Paris", "days": 3 }));
+        for key in schema["required"].as_array().unwrap() {
+            assert!(arguments.get(key.as_str().unwrap()).is_some());
+        }
+        assert!(arguments["city"].is_string());
+        assert!(arguments["days"].is_i64());
+
+        // Only one call is detected.
+        assert_eq!(detector.observe(7, b"").unwrap(), ToolCallEvent::None);
+    }
+}
@@ -1,7 +1,9 @@
+mod detector;
 mod request;
 mod response;
 
 use candle_core::Result;
+pub use detector::*;
 use regex::Regex;
 pub use request::*;
 pub use response::*;
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fs, io::Read, ops::Range, path::Path};
+use std::{collections::HashMap, fs, io::Read, ops::Range, path::Path, str::FromStr};
 
 use candle_core::Device;
 use itertools::Itertools;
@@ -6,7 +6,7 @@ use mistralrs_quant::IsqType;
 use regex::Regex;
 use serde::Deserialize;
 
-use crate::parse_isq_value;
+use crate::{parse_isq_value, PagedCacheType};
 
 const DEVICE_PATTERN: &str = r"^(cpu|cuda\[(\d+)\]|metal\[(\d+)\])$";
 
@@ -14,6 +14,7 @@ const DEVICE_PATTERN: &str = r"^(cpu|cuda\[(\d+)\]|metal\[(\d+)\])$";
 pub struct DeserLayerTopology {
     isq: Option<String>,
     device: Option<String>,
+    kv_cache: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -23,6 +24,9 @@ pub struct DeserTopology(HashMap<String, DeserLayerTopology>);
 pub struct LayerTopology {
     pub isq: Option<IsqType>,
     pub device: Option<Device>,
+    /// Storage type of this layer's PagedAttention KV cache, overriding the one set on the
+    /// [`crate::PagedAttentionConfig`].
+    pub kv_cache: Option<PagedCacheType>,
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -72,6 +76,14 @@ impl Topology {
             .all(|l| l.is_none() || l.as_ref().is_some_and(|l| l.device.is_none()))
     }
 
+    /// The per-layer KV cache storage type overrides, indexed by layer.
+    pub fn kv_cache_types(&self) -> Vec<Option<PagedCacheType>> {
+        self.0
+            .iter()
+            .map(|l| l.as_ref().and_then(|l| l.kv_cache))
+            .collect()
+    }
+
     pub fn with_range(mut self, range: Range<usize>, layer: LayerTopology) -> Self {
         if self.0.len() < range.end {
             self.0.extend(vec![None; range.end - self.0.len()]);
@@ -88,7 +100,15 @@ impl Topology {
         let device_regex = Regex::new(DEVICE_PATTERN)?;
 
         let mut layers = Vec::new();
-        for (range, DeserLayerTopology { isq, device }) in deser.0 {
+        for (
+            range,
+            DeserLayerTopology {
+                isq,
+                device,
+                kv_cache,
+            },
+        ) in deser.0
+        {
             // Parse isq
             let (start, end) = if range.contains('-') {
                 // Range (inclusive, exclusive)
@@ -134,7 +154,15 @@ impl Topology {
                 None
             };
 
-            let layer_topo = LayerTopology { isq, device };
+            let kv_cache = kv_cache
+                .map(|kv_cache| PagedCacheType::from_str(&kv_cache).map_err(anyhow::Error::msg))
+                .transpose()?;
+
+            let layer_topo = LayerTopology {
+                isq,
+                device,
+                kv_cache,
+            };
             layers.push((range, layer_topo));
         }
         // Sort so that we increase in end points
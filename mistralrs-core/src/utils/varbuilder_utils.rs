@@ -2,7 +2,7 @@
 
 use std::{
     collections::HashMap,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
     thread::{self, JoinHandle},
 };
@@ -51,6 +51,17 @@ impl TensorLoaderBackend for PickleBackend {
     }
 }
 
+/// Open a safetensors shard, failing with an error naming the file if its header or data is corrupt or
+/// truncated, such as after an interrupted download.
+pub(crate) fn open_safetensors_shard(path: &Path) -> Result<MmapedSafetensors> {
+    unsafe { MmapedSafetensors::new(path) }.map_err(|e| {
+        candle_core::Error::Msg(format!(
+            "Safetensors shard `{}` is corrupt or truncated, try deleting and downloading it again: {e}",
+            path.display()
+        ))
+    })
+}
+
 pub enum DeviceForLoadTensor {
     Base,
     Idx(usize),
@@ -86,86 +97,112 @@ pub(crate) fn from_mmaped_safetensors(
     // }
 
     #[allow(clippy::type_complexity)]
-    let mut handles: Vec<JoinHandle<Result<HashMap<String, Tensor>>>> = Vec::new();
+    let mut handles: Vec<(PathBuf, JoinHandle<Result<HashMap<String, Tensor>>>)> = Vec::new();
 
     for path in paths {
+        let shard = path.clone();
         let base_device = base_device.clone();
         let layer_devices = layer_devices.clone();
         let get_device_for_tensor = get_device_for_tensor.clone();
         if let Some(regexes) = make_dummy_regexes.clone() {
             let predicate = predicate.clone();
-            handles.push(thread::spawn(Box::new(move || {
-                let loader = Common::new();
-                loader.load_tensors_from_path(
-                    &path,
-                    &base_device,
-                    layer_devices,
-                    get_device_for_tensor,
-                    dtype,
-                    silent,
-                    predicate,
-                    |key| regexes.iter().any(|r| r.is_match(key)),
-                )
-            })));
+            handles.push((
+                shard,
+                thread::spawn(Box::new(move || {
+                    let loader = Common::new();
+                    loader.load_tensors_from_path(
+                        &path,
+                        &base_device,
+                        layer_devices,
+                        get_device_for_tensor,
+                        dtype,
+                        silent,
+                        predicate,
+                        |key| regexes.iter().any(|r| r.is_match(key)),
+                    )
+                })),
+            ));
         } else {
             let predicate = predicate.clone();
-            handles.push(thread::spawn(Box::new(move || {
-                let loader = Common::new();
-                loader.load_tensors_from_path(
-                    &path,
-                    &base_device,
-                    layer_devices,
-                    get_device_for_tensor,
-                    dtype,
-                    silent,
-                    predicate,
-                    |_| false,
-                )
-            })));
+            handles.push((
+                shard,
+                thread::spawn(Box::new(move || {
+                    let loader = Common::new();
+                    loader.load_tensors_from_path(
+                        &path,
+                        &base_device,
+                        layer_devices,
+                        get_device_for_tensor,
+                        dtype,
+                        silent,
+                        predicate,
+                        |_| false,
+                    )
+                })),
+            ));
         }
     }
     for (i, path) in xlora_paths.into_iter().enumerate() {
+        let shard = path.clone();
         let base_device = base_device.clone();
         let layer_devices = layer_devices.clone();
         let get_device_for_tensor = get_device_for_tensor.clone();
         if let Some(regexes) = make_dummy_regexes.clone() {
             let predicate = predicate.clone();
-            handles.push(thread::spawn(Box::new(move || {
-                let loader = XLora::new(i + 1);
-                loader.load_tensors_from_path(
-                    &path,
-                    &base_device,
-                    layer_devices,
-                    get_device_for_tensor,
-                    dtype,
-                    silent,
-                    predicate,
-                    |key| regexes.iter().any(|r| r.is_match(key)),
-                )
-            })));
+            handles.push((
+                shard,
+                thread::spawn(Box::new(move || {
+                    let loader = XLora::new(i + 1);
+                    loader.load_tensors_from_path(
+                        &path,
+                        &base_device,
+                        layer_devices,
+                        get_device_for_tensor,
+                        dtype,
+                        silent,
+                        predicate,
+                        |key| regexes.iter().any(|r| r.is_match(key)),
+                    )
+                })),
+            ));
         } else {
             let predicate = predicate.clone();
-            handles.push(thread::spawn(Box::new(move || {
-                let loader = XLora::new(i + 1);
-                loader.load_tensors_from_path(
-                    &path,
-                    &base_device,
-                    layer_devices,
-                    get_device_for_tensor,
-                    dtype,
-                    silent,
-                    predicate,
-                    |_| false,
-                )
-            })));
+            handles.push((
+                shard,
+                thread::spawn(Box::new(move || {
+                    let loader = XLora::new(i + 1);
+                    loader.load_tensors_from_path(
+                        &path,
+                        &base_device,
+                        layer_devices,
+                        get_device_for_tensor,
+                        dtype,
+                        silent,
+                        predicate,
+                        |_| false,
+                    )
+                })),
+            ));
         }
     }
 
     let mut ws = HashMap::new();
+    // Tracks which shard each tensor came from, to report duplicates.
+    let mut sources: HashMap<String, PathBuf> = HashMap::new();
     // Wait until all spawned threads have finished loading tensors:
-    while !handles.iter().all(|h| h.is_finished()) {}
-    for h in handles {
-        ws.extend(h.join().unwrap()?);
+    while !handles.iter().all(|(_, h)| h.is_finished()) {}
+    for (shard, h) in handles {
+        for (name, tensor) in h.join().unwrap()? {
+            if let Some(first) = sources.get(&name) {
+                candle_core::bail!(
+                    "Tensor `{name}` is defined in multiple shards: `{}` and `{}`.",
+                    first.display(),
+                    shard.display()
+                );
+            }
+            sources.insert(name.clone(), shard.clone());
+            ws.insert(name, tensor);
+        }
     }
 
     let backend = Box::new(ws);
@@ -234,9 +271,7 @@ trait LoadTensors {
             .to_str()
             .expect("Expected to convert")
         {
-            "safetensors" => Box::new(SafetensorBackend(unsafe {
-                MmapedSafetensors::new(path)?
-            })),
+            "safetensors" => Box::new(SafetensorBackend(open_safetensors_shard(path)?)),
             "pth" | "pt" | "bin" => Box::new(PickleBackend(
                 candle_core::pickle::PthTensors::new(path, None)?
             )),
@@ -263,7 +298,12 @@ trait LoadTensors {
                             .unwrap_or(base_device),
                     };
                     // If making a dummy, don't add the tensor. `mistralrs_quant` handles this!
-                    let tensor = tensors.load_name(&load_name, dev, dtype)?;
+                    let tensor = tensors.load_name(&load_name, dev, dtype).map_err(|e| {
+                        candle_core::Error::Msg(format!(
+                            "Failed to load tensor `{load_name}` from `{}`: {e}",
+                            path.display()
+                        ))
+                    })?;
 
                     loaded_tensors.insert(key_name, tensor);
                 }
@@ -314,3 +354,94 @@ impl LoadTensors for XLora {
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{from_mmaped_safetensors, DeviceForLoadTensor};
+
+    #[test]
+    fn duplicate_tensor_across_shards_errors() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let dir = std::env::temp_dir().join(format!("mistralrs-dup-shards-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+
+        let weight = Tensor::zeros((2, 2), DType::F32, &dev)?;
+        let shard_a = dir.join("model-00001-of-00002.safetensors");
+        let shard_b = dir.join("model-00002-of-00002.safetensors");
+        candle_core::safetensors::save(
+            &HashMap::from([
+                ("proj.weight", weight.clone()),
+                ("a.weight", weight.clone()),
+            ]),
+            &shard_a,
+        )?;
+        candle_core::safetensors::save(
+            &HashMap::from([("proj.weight", weight.clone()), ("b.weight", weight)]),
+            &shard_b,
+        )?;
+
+        let res = from_mmaped_safetensors(
+            vec![shard_a, shard_b],
+            vec![],
+            Some(DType::F32),
+            &dev,
+            vec![None],
+            true,
+            None,
+            |_| true,
+            Arc::new(|_| DeviceForLoadTensor::Base),
+        );
+        std::fs::remove_dir_all(&dir)?;
+
+        let err = res
+            .err()
+            .expect("duplicate tensor should be an error")
+            .to_string();
+        assert!(err.contains("proj.weight"), "{err}");
+        assert!(err.contains("model-00001-of-00002.safetensors"), "{err}");
+        assert!(err.contains("model-00002-of-00002.safetensors"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_shard_error_names_the_file() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let dir =
+            std::env::temp_dir().join(format!("mistralrs-truncated-shard-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+
+        let shard = dir.join("model-00001-of-00001.safetensors");
+        candle_core::safetensors::save(
+            &HashMap::from([("proj.weight", Tensor::zeros((4, 4), DType::F32, &dev)?)]),
+            &shard,
+        )?;
+        // Drop the end of the tensor data, as an interrupted download would.
+        let bytes = std::fs::read(&shard)?;
+        std::fs::write(&shard, &bytes[..bytes.len() - 8])?;
+
+        let res = from_mmaped_safetensors(
+            vec![shard],
+            vec![],
+            Some(DType::F32),
+            &dev,
+            vec![None],
+            true,
+            None,
+            |_| true,
+            Arc::new(|_| DeviceForLoadTensor::Base),
+        );
+        std::fs::remove_dir_all(&dir)?;
+
+        let err = res
+            .err()
+            .expect("truncated shard should be an error")
+            .to_string();
+        assert!(err.contains("corrupt or truncated"), "{err}");
+        assert!(err.contains("model-00001-of-00001.safetensors"), "{err}");
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use candle_core::Result;
 use mistralrs_quant::QuantizedConfig;
 
 use crate::{
@@ -59,6 +60,53 @@ pub struct Gemma3TextConfig {
     #[serde(default = "sliding_window_pattern")]
     pub sliding_window_pattern: usize,
     pub rope_scaling: Option<Gemma3RopeScalingConfig>,
+    /// Attention type of each layer. When absent, every `sliding_window_pattern`-th layer is global.
+    pub layer_types: Option<Vec<Gemma3LayerType>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gemma3LayerType {
+    SlidingAttention,
+    FullAttention,
+}
+
+impl Gemma3TextConfig {
+    /// Check that the sliding-window/full-attention layer pattern is usable.
+    pub fn validate_layer_pattern(&self) -> Result<()> {
+        match &self.layer_types {
+            Some(layer_types) if layer_types.len() != self.num_hidden_layers => {
+                candle_core::bail!(
+                    "Gemma 3 config has {} `layer_types` but {} layers.",
+                    layer_types.len(),
+                    self.num_hidden_layers
+                )
+            }
+            Some(_) => Ok(()),
+            None if self.sliding_window_pattern == 0 => {
+                candle_core::bail!("Gemma 3 `sliding_window_pattern` must be at least 1.")
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Whether this layer uses sliding-window (local) attention, and so the local RoPE base.
+    pub fn is_sliding(&self, layer_idx: usize) -> bool {
+        match &self.layer_types {
+            Some(layer_types) => layer_types[layer_idx] == Gemma3LayerType::SlidingAttention,
+            None => (layer_idx + 1) % self.sliding_window_pattern != 0,
+        }
+    }
+
+    /// The RoPE base frequency of this layer: `rope_local_base_freq` for local layers and `rope_theta`
+    /// for global layers.
+    pub fn rope_base_for_layer(&self, layer_idx: usize) -> f64 {
+        if self.is_sliding(layer_idx) {
+            self.rope_local_base_freq
+        } else {
+            self.rope_theta
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -73,3 +121,60 @@ pub enum Gemma3Config {
     #[serde(untagged)]
     Text(Gemma3TextConfig),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Gemma3TextConfig;
+
+    fn config(extra: &str) -> Gemma3TextConfig {
+        serde_json::from_str(&format!(
+            r#"{{
+                "hidden_size": 64,
+                "intermediate_size": 128,
+                "num_hidden_layers": 12,
+                "sliding_window": 512,
+                "attn_logit_softcapping": null,
+                "final_logit_softcapping": null,
+                "quantization_config": null,
+                "rope_scaling": null,
+                "rope_theta": 1000000.0,
+                "rope_local_base_freq": 10000.0
+                {extra}
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn local_layers_use_local_rope_base() {
+        let cfg = config(r#", "sliding_window_pattern": 6"#);
+        cfg.validate_layer_pattern().unwrap();
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let is_global = layer_idx == 5 || layer_idx == 11;
+            assert_eq!(cfg.is_sliding(layer_idx), !is_global, "layer {layer_idx}");
+            let expected = if is_global { 1000000. } else { 10000. };
+            assert_eq!(cfg.rope_base_for_layer(layer_idx), expected);
+        }
+
+        let layer_types = (0..12)
+            .map(|i| match i % 3 {
+                0 => r#""full_attention""#,
+                _ => r#""sliding_attention""#,
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let cfg = config(&format!(r#", "layer_types": [{layer_types}]"#));
+        cfg.validate_layer_pattern().unwrap();
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let expected = if layer_idx % 3 == 0 { 1000000. } else { 10000. };
+            assert_eq!(cfg.rope_base_for_layer(layer_idx), expected);
+        }
+
+        assert!(config(r#", "sliding_window_pattern": 0"#)
+            .validate_layer_pattern()
+            .is_err());
+        assert!(config(r#", "layer_types": ["full_attention"]"#)
+            .validate_layer_pattern()
+            .is_err());
+    }
+}
@@ -252,6 +252,7 @@ impl InputsProcessor for Gemma3ImageProcessor {
             model_specific_args: Box::new(Gemma3SpecificArgs),
             paged_attn_meta,
             flash_meta,
+            use_cache: !no_kv_cache,
         });
         Box::new(std::iter::once(Ok(InputProcessorOutput {
             inputs,
@@ -26,12 +26,6 @@ use crate::{
 
 use super::config::Gemma3TextConfig;
 
-macro_rules! is_sliding {
-    ($layer_idx:expr, $cfg:expr) => {
-        ($layer_idx + 1) % $cfg.sliding_window_pattern != 0
-    };
-}
-
 struct Attention {
     q_proj: Arc<dyn QuantMethod>,
     k_proj: Arc<dyn QuantMethod>,
@@ -105,7 +99,7 @@ impl Attention {
             comm,
             vb.pp("o_proj"),
         )?;
-        let sliding_window = if is_sliding!(layer_idx, cfg) {
+        let sliding_window = if cfg.is_sliding(layer_idx) {
             Some(cfg.sliding_window)
         } else {
             None
@@ -143,6 +137,7 @@ impl Attention {
                 softcap: cfg.attn_logit_softcapping.map(|x| x as f32),
                 softmax_scale: 1.0 / (cfg.query_pre_attn_scalar as f32).sqrt(),
                 sliding_window,
+                head_scales: None,
             },
             q_norm,
             k_norm,
@@ -391,6 +386,7 @@ impl TextModel {
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
     ) -> Result<Self> {
+        cfg.validate_layer_pattern()?;
         if let Some(ref quant_cfg) = &cfg.quantization_config {
             tracing::info!(
                 "Using {} quantization: {}.",
@@ -508,7 +504,7 @@ impl TextModel {
         };
         let cache_types = (0..cfg.num_hidden_layers)
             .map(|layer_idx| {
-                is_sliding!(layer_idx, cfg)
+                cfg.is_sliding(layer_idx)
                     .then(|| NormalCacheType::SlidingWindow {
                         window: cfg.sliding_window,
                     })
@@ -268,6 +268,7 @@ impl InputsProcessor for Idefics3ImageProcessor {
             model_specific_args: Box::new(pixel_attention_mask),
             paged_attn_meta,
             flash_meta,
+            use_cache: !no_kv_cache,
         });
         Box::new(std::iter::once(Ok(InputProcessorOutput {
             inputs,
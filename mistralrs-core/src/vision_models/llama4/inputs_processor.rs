@@ -339,6 +339,7 @@ impl InputsProcessor for Llama4ImageProcessor {
             model_specific_args: Box::new(Llama4ModelSpecificArgs),
             paged_attn_meta,
             flash_meta,
+            use_cache: !no_kv_cache,
         });
         Box::new(std::iter::once(Ok(InputProcessorOutput {
             inputs,
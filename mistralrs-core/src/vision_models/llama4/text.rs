@@ -132,6 +132,7 @@ impl CausalSelfAttention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: None,
+                head_scales: None,
             },
             norm,
             use_rope,
@@ -154,6 +154,7 @@ impl Llama4VisionAttention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: None,
+                head_scales: None,
             },
             head_dim,
             freqs,
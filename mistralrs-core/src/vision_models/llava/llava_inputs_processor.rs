@@ -189,6 +189,7 @@ impl InputsProcessor for LLaVAInputProcessor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: _,
+                            use_cache,
                         } = *inputs
                             .downcast::<text_models_inputs_processor::ModelInputs>()
                             .expect("Downcast failed.");
@@ -202,6 +203,7 @@ impl InputsProcessor for LLaVAInputProcessor {
                             model_specific_args: Box::new(LLaVAVisionSpecificArgs {}),
                             paged_attn_meta,
                             flash_meta,
+                            use_cache,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -319,6 +321,7 @@ impl InputsProcessor for LLaVAInputProcessor {
                 model_specific_args: Box::new(LLaVAVisionSpecificArgs {}),
                 paged_attn_meta,
                 flash_meta,
+                use_cache: !no_kv_cache,
             });
             Ok(InputProcessorOutput {
                 inputs,
@@ -215,6 +215,7 @@ impl InputsProcessor for LLaVANextInputProcessor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: _,
+                            use_cache,
                         } = *inputs
                             .downcast::<text_models_inputs_processor::ModelInputs>()
                             .expect("Downcast failed.");
@@ -232,6 +233,7 @@ impl InputsProcessor for LLaVANextInputProcessor {
                             }),
                             paged_attn_meta,
                             flash_meta,
+                            use_cache,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -367,6 +369,7 @@ impl InputsProcessor for LLaVANextInputProcessor {
                 }),
                 paged_attn_meta,
                 flash_meta,
+                use_cache: !no_kv_cache,
             });
             Ok(InputProcessorOutput {
                 inputs,
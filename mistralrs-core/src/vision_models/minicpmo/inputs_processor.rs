@@ -388,6 +388,7 @@ impl InputsProcessor for MiniCpmOImageProcessor {
             model_specific_args: Box::new(args),
             paged_attn_meta,
             flash_meta,
+            use_cache: !no_kv_cache,
         });
         Box::new(std::iter::once(Ok(InputProcessorOutput {
             inputs,
@@ -268,6 +268,7 @@ impl InputsProcessor for Mistral3ImageProcessor {
             model_specific_args: Box::new(Mistral3SpecificArgs { image_sizes }),
             paged_attn_meta,
             flash_meta,
+            use_cache: !no_kv_cache,
         });
         Box::new(std::iter::once(Ok(InputProcessorOutput {
             inputs,
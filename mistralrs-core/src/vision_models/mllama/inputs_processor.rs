@@ -438,6 +438,7 @@ impl InputsProcessor for MLlamaImageProcessor {
             }),
             paged_attn_meta,
             flash_meta,
+            use_cache: !no_kv_cache,
         });
         Box::new(std::iter::once(Ok(InputProcessorOutput {
             inputs,
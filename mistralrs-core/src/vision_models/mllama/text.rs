@@ -143,6 +143,7 @@ impl MLlamaTextSelfAttention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: None,
+                head_scales: None,
             },
             rope,
             num_heads: cfg.num_attention_heads / comm.world_size(),
@@ -365,6 +366,7 @@ impl MLlamaTextCrossAttention {
                 softcap: None,
                 softmax_scale: 1.0 / (cfg.head_dim() as f32).sqrt(),
                 sliding_window: None,
+                head_scales: None,
             },
         })
     }
@@ -183,6 +183,7 @@ impl MLlamaVisionAttention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: None,
+                head_scales: None,
             },
             num_heads: cfg.num_attention_heads,
             head_dim,
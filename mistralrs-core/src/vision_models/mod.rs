@@ -38,4 +38,7 @@ pub struct ModelInputs {
     pub model_specific_args: Box<dyn Any>,
     pub paged_attn_meta: Option<PagedAttentionInputMetadata>,
     pub flash_meta: FlashParams,
+    /// When false, the forward pass runs on an empty KV cache and leaves the pipeline's cache
+    /// untouched. The inputs must then cover the whole sequence.
+    pub use_cache: bool,
 }
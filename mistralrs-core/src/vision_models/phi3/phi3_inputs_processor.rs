@@ -191,6 +191,7 @@ impl InputsProcessor for Phi3InputsProcessor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: _,
+                            use_cache,
                         } = *inputs
                             .downcast::<text_models_inputs_processor::ModelInputs>()
                             .expect("Downcast failed.");
@@ -206,6 +207,7 @@ impl InputsProcessor for Phi3InputsProcessor {
                             }),
                             paged_attn_meta,
                             flash_meta,
+                            use_cache,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -358,6 +360,7 @@ impl InputsProcessor for Phi3InputsProcessor {
                 }),
                 paged_attn_meta,
                 flash_meta,
+                use_cache: !no_kv_cache,
             });
             Ok(InputProcessorOutput {
                 inputs,
@@ -192,6 +192,7 @@ impl InputsProcessor for Phi4MMInputsProcessor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: _,
+                            use_cache,
                         } = *inputs
                             .downcast::<text_models_inputs_processor::ModelInputs>()
                             .expect("Downcast failed.");
@@ -209,6 +210,7 @@ impl InputsProcessor for Phi4MMInputsProcessor {
                             }),
                             paged_attn_meta,
                             flash_meta,
+                            use_cache,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -324,6 +326,7 @@ impl InputsProcessor for Phi4MMInputsProcessor {
                 }),
                 paged_attn_meta,
                 flash_meta,
+                use_cache: !no_kv_cache,
             });
             Ok(InputProcessorOutput {
                 inputs,
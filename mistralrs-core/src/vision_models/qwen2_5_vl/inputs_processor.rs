@@ -495,6 +495,7 @@ impl InputsProcessor for Qwen2_5VLImageProcessor {
             }),
             paged_attn_meta,
             flash_meta,
+            use_cache: !no_kv_cache,
         });
         Box::new(std::iter::once(Ok(InputProcessorOutput {
             inputs,
@@ -294,6 +294,7 @@ impl Attention {
                 sliding_window: None,
                 softcap: None,
                 softmax_scale: self.scale,
+                head_scales: None,
             },
         )?;
 
@@ -24,7 +24,7 @@ use tracing::info;
 use crate::{
     device_map::DeviceMapper,
     layers::{CausalMasker, RmsNorm},
-    models::mixtral::Config,
+    models::mixtral::{select_experts, Config},
     pipeline::{extract_logits, Cache, NormalModel},
 };
 
@@ -117,6 +117,7 @@ impl Attention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: cfg.sliding_window,
+                head_scales: None,
             },
         })
     }
@@ -408,19 +409,9 @@ impl SparseMoeBlock {
         let mut top_x = vec![vec![]; self.experts.len()];
         let mut selected_rws = vec![vec![]; self.experts.len()];
         for (row_idx, rw) in routing_weights.iter().enumerate() {
-            let mut dst = (0..rw.len() as u32).collect::<Vec<u32>>();
-            dst.sort_by(|&i, &j| rw[j as usize].total_cmp(&rw[i as usize]));
-            let mut sum_routing_weights = 0f32;
-            for &expert_idx in dst.iter().take(self.num_experts_per_tok) {
-                let expert_idx = expert_idx as usize;
-                let routing_weight = rw[expert_idx];
-                sum_routing_weights += routing_weight;
+            for (expert_idx, routing_weight) in select_experts(rw, self.num_experts_per_tok) {
                 top_x[expert_idx].push(row_idx as u32);
-            }
-            for &expert_idx in dst.iter().take(self.num_experts_per_tok) {
-                let expert_idx = expert_idx as usize;
-                let routing_weight = rw[expert_idx];
-                selected_rws[expert_idx].push(routing_weight / sum_routing_weights)
+                selected_rws[expert_idx].push(routing_weight);
             }
         }
 
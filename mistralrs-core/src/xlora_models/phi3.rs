@@ -94,6 +94,7 @@ impl Attention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: cfg.sliding_window,
+                head_scales: None,
             },
         })
     }
@@ -429,7 +430,12 @@ impl Model {
                 .unwrap_or(&normal_loading_metadata.real_device);
             ropes.insert(
                 device.location(),
-                Arc::new(PhiRotaryEmbedding::new(vb.dtype(), cfg.clone(), device)?),
+                Arc::new(PhiRotaryEmbedding::new_with_max_cached_positions(
+                    vb.dtype(),
+                    cfg.clone(),
+                    cfg.max_cached_rope_positions,
+                    device,
+                )?),
             );
         }
         let mut count = 0;
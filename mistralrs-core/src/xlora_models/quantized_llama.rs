@@ -399,6 +399,7 @@ impl ModelConfig::FromAdapterGGML for ModelWeights {
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                     sliding_window: None,
+                    head_scales: None,
                 },
                 dtype,
             })
@@ -701,6 +702,7 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                     sliding_window: None,
+                    head_scales: None,
                 },
                 dtype,
             })
@@ -86,8 +86,13 @@ pub fn copy_blocks(
                 let ptr_value = *slice_value.slice(0..).device_ptr();
                 (ptr_key, ptr_value)
             }
+            (CudaStorageSlice::U8(slice_key), CudaStorageSlice::U8(slice_value)) => {
+                let ptr_key = *slice_key.slice(0..).device_ptr();
+                let ptr_value = *slice_value.slice(0..).device_ptr();
+                (ptr_key, ptr_value)
+            }
             _ => {
-                candle_core::bail!("only f32, f16 and bf16 input data type supported!",);
+                candle_core::bail!("only f32, f16, bf16 and u8 input data type supported!",);
             }
         };
         key_cache_ptrs.push(key_ptr + key_offset);
@@ -199,8 +204,13 @@ pub unsafe fn swap_blocks(
                     let ptr_dst = *slice_dst.slice(dst_layout.start_offset()..).device_ptr();
                     (ptr_src, ptr_dst)
                 }
+                (CudaStorageSlice::U8(slice_src), CudaStorageSlice::U8(slice_dst)) => {
+                    let ptr_src = *slice_src.slice(src_layout.start_offset()..).device_ptr();
+                    let ptr_dst = *slice_dst.slice(dst_layout.start_offset()..).device_ptr();
+                    (ptr_src, ptr_dst)
+                }
                 _ => {
-                    candle_core::bail!("only f32, f16 and bf16 input data type supported!")
+                    candle_core::bail!("only f32, f16, bf16 and u8 input data type supported!")
                 }
             };
 
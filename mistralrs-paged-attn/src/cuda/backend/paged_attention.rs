@@ -332,6 +332,7 @@ fn update_cache<
         DType::F16 => 0,
         DType::BF16 => 1,
         DType::F32 => 2,
+        DType::U8 => 3,
         dtype => candle::bail!("dtype {dtype:?} is not supported"),
     };
 
@@ -474,6 +475,8 @@ fn update_cache<
 ///   with `x` being the size of an element in bytes.
 /// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`.
 /// * `slot_mapping` - Mapping associating a slot to each token of shape `(num_tokens)`.
+///
+/// `u8` tensors are copied verbatim, which allows storing already quantized keys and values.
 pub fn reshape_and_cache(
     key: &Tensor,
     value: &Tensor,
@@ -485,8 +488,9 @@ pub fn reshape_and_cache(
         DType::F16 => update_cache::<f16>(key, value, key_cache, value_cache, slot_mapping),
         DType::BF16 => update_cache::<bf16>(key, value, key_cache, value_cache, slot_mapping),
         DType::F32 => update_cache::<f32>(key, value, key_cache, value_cache, slot_mapping),
+        DType::U8 => update_cache::<u8>(key, value, key_cache, value_cache, slot_mapping),
         dt => {
-            candle::bail!("reshape_and_cache is only supported for f32, f16 and bf16 ({dt:?})")
+            candle::bail!("reshape_and_cache is only supported for f32, f16, bf16 and u8 ({dt:?})")
         }
     }
 }
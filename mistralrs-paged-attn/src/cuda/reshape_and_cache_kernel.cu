@@ -86,7 +86,7 @@ extern "C" void reshape_and_cache(
     int32_t block_size, int32_t x, int32_t key_stride, int32_t value_stride,
     cudaStream_t stream,
 
-    uint32_t dtype // 0 => f16; 1 => bf16; 2 => f32
+    uint32_t dtype // 0 => f16; 1 => bf16; 2 => f32; 3 => u8
 ) {
   dim3 grid(num_tokens);
   dim3 block(std::min(num_heads * head_size, 512));
@@ -97,6 +97,8 @@ extern "C" void reshape_and_cache(
     CALL_RESHAPE_AND_CACHE(__nv_bfloat16);
   } else if (dtype == 2) {
     CALL_RESHAPE_AND_CACHE(float);
+  } else if (dtype == 3) {
+    CALL_RESHAPE_AND_CACHE(uint8_t);
   }
   CUDA_CHECK(cudaGetLastError());
 }
\ No newline at end of file
@@ -220,7 +220,16 @@ pub unsafe fn swap_blocks(
                     block_size_in_bytes,
                     block_mapping,
                 )?,
-                _ => candle_core::bail!("expected bf16, f16, or f32 for cpu<>gpu swap-blocks"),
+                CpuStorage::U8(s) => swap_thunk(
+                    s,
+                    src_layout,
+                    dst_storage,
+                    dst_layout,
+                    dev,
+                    block_size_in_bytes,
+                    block_mapping,
+                )?,
+                _ => candle_core::bail!("expected bf16, f16, f32 or u8 for cpu<>gpu swap-blocks"),
             }
         }
         (src, dst) => {
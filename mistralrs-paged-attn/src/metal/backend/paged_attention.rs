@@ -309,6 +309,8 @@ pub fn paged_attention(
 ///   with `x` being the size of an element in bytes.
 /// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`.
 /// * `slot_mapping` - Mapping associating a slot to each token of shape `(num_tokens)`.
+///
+/// `u8` tensors are copied verbatim, which allows storing already quantized keys and values.
 pub fn reshape_and_cache(
     key: &Tensor,
     value: &Tensor,
@@ -322,6 +324,7 @@ pub fn reshape_and_cache(
         DType::F16 => PagedAttentionDType::F16,
         DType::BF16 => PagedAttentionDType::BF16,
         DType::F32 => PagedAttentionDType::F32,
+        DType::U8 => PagedAttentionDType::U8,
         dtype => candle_core::bail!("dtype {dtype:?} is not supported"),
     };
 
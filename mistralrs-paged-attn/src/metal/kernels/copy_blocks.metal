@@ -289,3 +289,4 @@ template <typename T>
 
 instantiate_copy_blocks(float) instantiate_copy_blocks(bfloat16_t)
     instantiate_copy_blocks(half)
+        instantiate_copy_blocks(uint8_t)
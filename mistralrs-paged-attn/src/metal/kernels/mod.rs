@@ -175,9 +175,10 @@ pub fn call_copy_blocks(
         DType::F32 => "copy_blocks_float",
         DType::BF16 => "copy_blocks_bfloat16_t",
         DType::F16 => "copy_blocks_half",
+        DType::U8 => "copy_blocks_uint8_t",
         other => {
             return Err(MetalKernelError::DTypeMismatch {
-                expected: vec![DType::F32, DType::F16, DType::BF16],
+                expected: vec![DType::F32, DType::F16, DType::BF16, DType::U8],
                 got: other,
             })
         }
@@ -216,6 +217,7 @@ pub enum PagedAttentionDType {
     F16 = 0,
     BF16 = 1,
     F32 = 2,
+    U8 = 3,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -246,6 +248,7 @@ pub fn call_reshape_and_cache(
         PagedAttentionDType::F32 => "reshape_and_cache_float",
         PagedAttentionDType::BF16 => "reshape_and_cache_bfloat16_t",
         PagedAttentionDType::F16 => "reshape_and_cache_half",
+        PagedAttentionDType::U8 => "reshape_and_cache_uint8_t",
     };
     let pipeline = kernels.load_pipeline(device, Source::ReshapeAndCache, name.to_string())?;
     let encoder = ep.encoder();
@@ -371,6 +374,12 @@ pub fn call_paged_attention_v1(
         PagedAttentionDType::F32 => "paged_attention_float",
         PagedAttentionDType::BF16 => "paged_attention_bfloat16_t",
         PagedAttentionDType::F16 => "paged_attention_half",
+        PagedAttentionDType::U8 => {
+            return Err(MetalKernelError::DTypeMismatch {
+                expected: vec![DType::F32, DType::F16, DType::BF16],
+                got: DType::U8,
+            })
+        }
     };
     let mut name = name.to_string();
     name.push_str(&format!("_hs{head_size}"));
@@ -508,6 +517,12 @@ pub fn call_paged_attention_v2(
             PagedAttentionDType::F32 => "paged_attention_float",
             PagedAttentionDType::BF16 => "paged_attention_bfloat16_t",
             PagedAttentionDType::F16 => "paged_attention_half",
+            PagedAttentionDType::U8 => {
+                return Err(MetalKernelError::DTypeMismatch {
+                    expected: vec![DType::F32, DType::F16, DType::BF16],
+                    got: DType::U8,
+                })
+            }
         };
         let mut name = name.to_string();
         name.push_str(&format!("_hs{head_size}"));
@@ -614,6 +629,12 @@ pub fn call_paged_attention_v2(
             PagedAttentionDType::F32 => "paged_attention_v2_reduce_float",
             PagedAttentionDType::BF16 => "paged_attention_v2_reduce_bfloat16_t",
             PagedAttentionDType::F16 => "paged_attention_v2_reduce_half",
+            PagedAttentionDType::U8 => {
+                return Err(MetalKernelError::DTypeMismatch {
+                    expected: vec![DType::F32, DType::F16, DType::BF16],
+                    got: DType::U8,
+                })
+            }
         };
         let mut name = name.to_string();
         name.push_str(&format!("_hs{head_size}"));
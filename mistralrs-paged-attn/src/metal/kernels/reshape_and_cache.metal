@@ -313,3 +313,4 @@ template <typename T>
 
 instantiate_reshape_and_cache(float) instantiate_reshape_and_cache(bfloat16_t)
     instantiate_reshape_and_cache(half)
+        instantiate_reshape_and_cache(uint8_t)
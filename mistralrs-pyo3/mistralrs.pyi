@@ -61,6 +61,9 @@ class ChatCompletionRequest:
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
     web_search_options: WebSearchOptions | None = None
+    stream_flush_policy: str | None = None
+    kv_cache_limit: int | None = None
+    kv_cache_sink_tokens: int | None = None
 
 @dataclass
 class CompletionRequest:
@@ -88,6 +91,8 @@ class CompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    kv_cache_limit: int | None = None
+    kv_cache_sink_tokens: int | None = None
 
 @dataclass
 class Architecture(Enum):
@@ -102,6 +107,8 @@ class Architecture(Enum):
     Phi3_5MoE = "phi3.5moe"
     DeepseekV2 = "deepseekv2"
     DeepseekV3 = "deepseekv3"
+    Cohere = "cohere"
+    Phi3Small = "phi3small"
 
 @dataclass
 class VisionArchitecture(Enum):
@@ -180,6 +187,7 @@ class Which(Enum):
         organization: str | None = None
         from_uqff: str | list[str] | None = None
         write_uqff: str | None = None
+        uqff_shard_size_mb: int | None = None
         dtype: ModelDType = ModelDType.Auto
         auto_map_params: TextAutoMapParams | None = (None,)
         calibration_file: str | None = None
@@ -197,6 +205,7 @@ class Which(Enum):
         topology: str | None = None
         from_uqff: str | list[str] | None = None
         write_uqff: str | None = None
+        uqff_shard_size_mb: int | None = None
         dtype: ModelDType = ModelDType.Auto
         auto_map_params: TextAutoMapParams | None = (None,)
         hf_cache_path: str | None = None
@@ -210,6 +219,7 @@ class Which(Enum):
         topology: str | None = None
         from_uqff: str | list[str] | None = None
         write_uqff: str | None = None
+        uqff_shard_size_mb: int | None = None
         dtype: ModelDType = ModelDType.Auto
         auto_map_params: TextAutoMapParams | None = (None,)
         hf_cache_path: str | None = None
@@ -291,6 +301,7 @@ class Which(Enum):
         topology: str | None = None
         from_uqff: str | list[str] | None = None
         write_uqff: str | None = None
+        uqff_shard_size_mb: int | None = None
         dtype: ModelDType = ModelDType.Auto
         max_edge: int | None = None
         auto_map_params: VisionAutoMapParams | None = (None,)
@@ -323,10 +334,11 @@ class Runner:
         pa_blk_size: int | None = None,
         no_paged_attn: bool = False,
         paged_attn: bool = False,
-        prompt_batchsize: int | None = None,
+        prompt_batchsize: int | str | None = None,
         seed: int | None = None,
         search_bert_model: str | None = None,
         no_bert_model: bool = False,
+        speculative_share_embeddings: bool = False,
     ) -> None:
         """
         Load a model.
@@ -366,9 +378,12 @@ class Runner:
         - `no_paged_attn` disables PagedAttention on CUDA. Because PagedAttention is already disabled on Metal, this is only applicable on CUDA.
         - `paged_attn` enables PagedAttention on Metal. Because PagedAttention is already enabled on CUDA, this is only applicable on Metal.
         - `prompt_batchsize` Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
+            Pass `"auto"` to choose it from the free device memory at load time. This is only supported for plain models.
         - `seed`, used to ensure reproducible random number generation.
         - `enable_search`: Enable searching compatible with the OpenAI `web_search_options` setting. This uses the BERT model specified below or the default.
         - `search_bert_model`: specify a Hugging Face model ID for a BERT model to assist web searching. Defaults to Snowflake Arctic Embed L.
+        - `speculative_share_embeddings` makes the draft model reference the target model's input embedding and LM head instead of loading
+            its own. The two models must have the same vocab and hidden sizes. If `which_draft` is not specified, this is ignored.
         """
         ...
 
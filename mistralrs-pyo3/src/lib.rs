@@ -9,7 +9,6 @@ use requests::{ChatCompletionRequest, CompletionRequest, ToolChoice};
 use serde_json::Value;
 use std::{
     cell::RefCell,
-    num::NonZeroUsize,
     path::PathBuf,
     str::FromStr,
     sync::{Arc, Mutex, OnceLock},
@@ -25,10 +24,11 @@ use mistralrs_core::{
     DefaultSchedulerMethod, DetokenizationRequest, DeviceLayerMapMetadata, DeviceMapMetadata,
     DeviceMapSetting, DiffusionGenerationParams, DiffusionLoaderBuilder, DiffusionSpecificConfig,
     DrySamplingParams, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder,
-    GGUFSpecificConfig, ImageGenerationResponse, ImageGenerationResponseFormat, LlguidanceGrammar,
-    Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder, NormalLoaderBuilder, NormalRequest,
-    NormalSpecificConfig, PagedAttentionConfig, Request as _Request, RequestMessage, Response,
-    ResponseOk, SamplingParams, SchedulerConfig, SpeculativeConfig, SpeculativeLoader, StopTokens,
+    GGUFSpecificConfig, ImageGenerationResponse, ImageGenerationResponseFormat, KvCacheLimit,
+    KvCacheOverflowPolicy, LlguidanceGrammar, Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder,
+    NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, PagedAttentionConfig,
+    PromptChunksize, Request as _Request, RequestMessage, Response, ResponseOk, SamplingParams,
+    SchedulerConfig, SpeculativeConfig, SpeculativeLoader, StopTokens, StreamFlushPolicy,
     TokenSource, TokenizationRequest, Tool, Topology, VisionLoaderBuilder, VisionSpecificConfig,
 };
 use pyo3::prelude::*;
@@ -80,7 +80,7 @@ fn parse_which(
     which: Which,
     no_kv_cache: bool,
     chat_template: Option<String>,
-    prompt_chunksize: Option<NonZeroUsize>,
+    prompt_chunksize: Option<PromptChunksize>,
     jinja_explicit: Option<String>,
 ) -> PyApiResult<Box<dyn Loader>> {
     let use_flash_attn = mistralrs_core::using_flash_attn();
@@ -93,6 +93,7 @@ fn parse_which(
             topology,
             organization,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             dtype: _,
             imatrix,
@@ -106,6 +107,7 @@ fn parse_which(
                 topology: Topology::from_option_path(topology)?,
                 organization: organization.map(Into::into).unwrap_or(Default::default()),
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.right_or_else(|l| vec![l])
                         .iter()
@@ -115,6 +117,22 @@ fn parse_which(
                 imatrix,
                 calibration_file,
                 hf_cache_path,
+                activation_override: None,
+                prefill_dtype: None,
+                split_kv_segment_len: None,
+                split_kv_accumulation: Default::default(),
+                embedding_dtype: None,
+                lm_head_dtype: None,
+                f32_norm_weights: false,
+                isq_overrides: Vec::new(),
+                pruned_heads: Default::default(),
+                attention_head_scales: Default::default(),
+                head_dim_alignment: None,
+                max_cached_rope_positions: None,
+                offload_activations: false,
+                share_rope_tables: false,
+                lazy_rope_tables: false,
+                shard_download_retries: 0,
             },
             chat_template,
             tokenizer_json,
@@ -132,6 +150,7 @@ fn parse_which(
             arch,
             topology,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             dtype: _,
             auto_map_params: _,
@@ -143,6 +162,7 @@ fn parse_which(
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.right_or_else(|l| vec![l])
                         .iter()
@@ -152,6 +172,22 @@ fn parse_which(
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                activation_override: None,
+                prefill_dtype: None,
+                split_kv_segment_len: None,
+                split_kv_accumulation: Default::default(),
+                embedding_dtype: None,
+                lm_head_dtype: None,
+                f32_norm_weights: false,
+                isq_overrides: Vec::new(),
+                pruned_heads: Default::default(),
+                attention_head_scales: Default::default(),
+                head_dim_alignment: None,
+                max_cached_rope_positions: None,
+                offload_activations: false,
+                share_rope_tables: false,
+                lazy_rope_tables: false,
+                shard_download_retries: 0,
             },
             chat_template,
             tokenizer_json,
@@ -176,6 +212,7 @@ fn parse_which(
             arch,
             topology,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             dtype: _,
             auto_map_params: _,
@@ -187,6 +224,7 @@ fn parse_which(
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.right_or_else(|l| vec![l])
                         .iter()
@@ -196,6 +234,22 @@ fn parse_which(
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                activation_override: None,
+                prefill_dtype: None,
+                split_kv_segment_len: None,
+                split_kv_accumulation: Default::default(),
+                embedding_dtype: None,
+                lm_head_dtype: None,
+                f32_norm_weights: false,
+                isq_overrides: Vec::new(),
+                pruned_heads: Default::default(),
+                attention_head_scales: Default::default(),
+                head_dim_alignment: None,
+                max_cached_rope_positions: None,
+                offload_activations: false,
+                share_rope_tables: false,
+                lazy_rope_tables: false,
+                shard_download_retries: 0,
             },
             chat_template,
             tokenizer_json,
@@ -218,7 +272,7 @@ fn parse_which(
             quantized_model_id,
             quantized_filename.map_left(|f| vec![f]).into_inner(),
             GGUFSpecificConfig {
-                prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             no_kv_cache,
@@ -241,7 +295,7 @@ fn parse_which(
             quantized_model_id,
             quantized_filename.map_left(|f| vec![f]).into_inner(),
             GGUFSpecificConfig {
-                prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             no_kv_cache,
@@ -272,7 +326,7 @@ fn parse_which(
             quantized_model_id,
             quantized_filename.map_left(|f| vec![f]).into_inner(),
             GGUFSpecificConfig {
-                prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             no_kv_cache,
@@ -298,7 +352,7 @@ fn parse_which(
         } => GGMLLoaderBuilder::new(
             GGMLSpecificConfig {
                 gqa,
-                prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             chat_template,
@@ -325,7 +379,7 @@ fn parse_which(
         } => GGMLLoaderBuilder::new(
             GGMLSpecificConfig {
                 gqa,
-                prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             chat_template,
@@ -360,7 +414,7 @@ fn parse_which(
         } => GGMLLoaderBuilder::new(
             GGMLSpecificConfig {
                 gqa,
-                prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
             },
             chat_template,
@@ -385,6 +439,7 @@ fn parse_which(
             arch,
             topology,
             write_uqff,
+            uqff_shard_size_mb,
             from_uqff,
             dtype: _,
             max_edge,
@@ -395,9 +450,10 @@ fn parse_which(
         } => VisionLoaderBuilder::new(
             VisionSpecificConfig {
                 use_flash_attn,
-                prompt_chunksize,
+                prompt_chunksize: PromptChunksize::fixed_only(prompt_chunksize)?,
                 topology: Topology::from_option_path(topology)?,
                 write_uqff,
+                uqff_shard_size_mb,
                 from_uqff: from_uqff.map(|x| {
                     x.right_or_else(|l| vec![l])
                         .iter()
@@ -408,6 +464,7 @@ fn parse_which(
                 calibration_file,
                 imatrix,
                 hf_cache_path,
+                shard_download_retries: 0,
             },
             chat_template,
             tokenizer_json,
@@ -460,6 +517,32 @@ fn build_constraint(grammar: Option<&str>, grammar_type: Option<&str>) -> PyApiR
 
     Ok(constraint)
 }
+
+/// Bound the KV cache of a request to `max_tokens`. With `sink_tokens`, the tokens past the limit
+/// are evicted, keeping the first `sink_tokens` tokens and the most recent ones; otherwise the
+/// request fails once it goes past the limit.
+fn build_kv_cache_limit(
+    max_tokens: Option<usize>,
+    sink_tokens: Option<usize>,
+) -> PyApiResult<Option<KvCacheLimit>> {
+    let Some(max_tokens) = max_tokens else {
+        if sink_tokens.is_some() {
+            return Err(PyApiErr::from(
+                "`kv_cache_sink_tokens` is specified but not `kv_cache_limit`",
+            ));
+        }
+        return Ok(None);
+    };
+    let overflow = match sink_tokens {
+        Some(sink_tokens) => KvCacheOverflowPolicy::EvictSinkWindow { sink_tokens },
+        None => KvCacheOverflowPolicy::Error,
+    };
+    Ok(Some(KvCacheLimit {
+        max_tokens,
+        overflow,
+    }))
+}
+
 #[pymethods]
 impl Runner {
     #[new]
@@ -486,6 +569,7 @@ impl Runner {
         seed = None,
         enable_search = false,
         search_bert_model = None,
+        speculative_share_embeddings = false,
     ))]
     fn new(
         which: Which,
@@ -506,10 +590,11 @@ impl Runner {
         pa_blk_size: Option<usize>,
         no_paged_attn: bool,
         paged_attn: bool,
-        prompt_chunksize: Option<usize>,
+        prompt_chunksize: Option<Either<usize, String>>,
         seed: Option<u64>,
         enable_search: bool,
         search_bert_model: Option<String>,
+        speculative_share_embeddings: bool,
     ) -> PyApiResult<Self> {
         let tgt_non_granular_index = match which {
             Which::Plain { .. }
@@ -603,12 +688,8 @@ impl Runner {
         };
 
         let prompt_chunksize = match prompt_chunksize {
-            Some(0) => {
-                return Err(PyApiErr::from(
-                    "`prompt_chunksize` must be a strictly positive integer, got 0.",
-                ))
-            }
-            Some(x) => Some(NonZeroUsize::new(x).unwrap()),
+            Some(Either::Left(x)) => Some(PromptChunksize::from_str(&x.to_string())?),
+            Some(Either::Right(x)) => Some(PromptChunksize::from_str(&x)?),
             None => None,
         };
 
@@ -632,6 +713,7 @@ impl Runner {
                 draft,
                 config: SpeculativeConfig {
                     gamma: speculative_gamma,
+                    share_embeddings: speculative_share_embeddings,
                 },
             })
         } else {
@@ -821,6 +903,13 @@ impl Runner {
                 .map(|x| StopTokens::Seqs(x.to_vec()));
             let constraint =
                 build_constraint(request.grammar.as_deref(), request.grammar_type.as_deref())?;
+            let stream_flush_policy = request
+                .stream_flush_policy
+                .as_deref()
+                .map(str::parse::<StreamFlushPolicy>)
+                .transpose()
+                .map_err(PyApiErr::from)?
+                .unwrap_or_default();
 
             let dry_params = if let Some(dry_multiplier) = request.dry_multiplier {
                 Some(DrySamplingParams::new_with_defaults(
@@ -1020,6 +1109,15 @@ impl Runner {
                     top_n_logprobs: request.top_logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    penalty_scope: Default::default(),
+                    stream_top_k_logprobs: None,
+                    reasoning_budget: None,
+                    token_healing: false,
+                    return_entropy: false,
+                    temperature_order: Default::default(),
+                    penalty_decay: None,
+                    eos_separates_documents: false,
+                    repetition_loop: None,
                     max_len: request.max_tokens,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
@@ -1037,6 +1135,13 @@ impl Runner {
                 logits_processors: None,
                 return_raw_logits: false,
                 web_search_options: request.web_search_options.clone(),
+                tool_call_trigger: None,
+                stream_raw_bytes: false,
+                stream_flush_policy,
+                kv_cache_limit: build_kv_cache_limit(
+                    request.kv_cache_limit,
+                    request.kv_cache_sink_tokens,
+                )?,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1126,6 +1231,15 @@ impl Runner {
                     top_n_logprobs: 1,
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    penalty_scope: Default::default(),
+                    stream_top_k_logprobs: None,
+                    reasoning_budget: None,
+                    token_healing: false,
+                    return_entropy: false,
+                    temperature_order: Default::default(),
+                    penalty_decay: None,
+                    eos_separates_documents: false,
+                    repetition_loop: None,
                     max_len: request.max_tokens,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
@@ -1143,6 +1257,13 @@ impl Runner {
                 logits_processors: None,
                 return_raw_logits: false,
                 web_search_options: None,
+                tool_call_trigger: None,
+                stream_raw_bytes: false,
+                stream_flush_policy: Default::default(),
+                kv_cache_limit: build_kv_cache_limit(
+                    request.kv_cache_limit,
+                    request.kv_cache_sink_tokens,
+                )?,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1200,6 +1321,10 @@ impl Runner {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            tool_call_trigger: None,
+            stream_raw_bytes: false,
+            stream_flush_policy: Default::default(),
+            kv_cache_limit: None,
         });
 
         let sender = self.runner.get_sender()?;
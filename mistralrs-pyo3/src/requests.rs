@@ -43,6 +43,8 @@ pub struct CompletionRequest {
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) kv_cache_limit: Option<usize>,
+    pub(crate) kv_cache_sink_tokens: Option<usize>,
 }
 
 #[pymethods]
@@ -72,6 +74,8 @@ impl CompletionRequest {
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        kv_cache_limit=None,
+        kv_cache_sink_tokens=None,
     ))]
     fn new(
         prompt: String,
@@ -97,6 +101,8 @@ impl CompletionRequest {
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        kv_cache_limit: Option<usize>,
+        kv_cache_sink_tokens: Option<usize>,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -122,6 +128,8 @@ impl CompletionRequest {
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            kv_cache_limit,
+            kv_cache_sink_tokens,
         })
     }
 }
@@ -163,6 +171,9 @@ pub struct ChatCompletionRequest {
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
     pub(crate) web_search_options: Option<WebSearchOptions>,
+    pub(crate) stream_flush_policy: Option<String>,
+    pub(crate) kv_cache_limit: Option<usize>,
+    pub(crate) kv_cache_sink_tokens: Option<usize>,
 }
 
 #[pymethods]
@@ -193,6 +204,9 @@ impl ChatCompletionRequest {
         dry_allowed_length=None,
         dry_sequence_breakers=None,
         web_search_options=None,
+        stream_flush_policy=None,
+        kv_cache_limit=None,
+        kv_cache_sink_tokens=None,
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -219,6 +233,9 @@ impl ChatCompletionRequest {
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
         web_search_options: Option<WebSearchOptions>,
+        stream_flush_policy: Option<String>,
+        kv_cache_limit: Option<usize>,
+        kv_cache_sink_tokens: Option<usize>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -293,6 +310,9 @@ impl ChatCompletionRequest {
             dry_base,
             dry_sequence_breakers,
             web_search_options,
+            stream_flush_policy,
+            kv_cache_limit,
+            kv_cache_sink_tokens,
         })
     }
 }
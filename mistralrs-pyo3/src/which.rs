@@ -21,6 +21,8 @@ pub enum Architecture {
     Phi3_5MoE,
     DeepseekV2,
     DeepseekV3,
+    Cohere,
+    Phi3Small,
 }
 
 impl From<Architecture> for NormalLoaderType {
@@ -38,6 +40,8 @@ impl From<Architecture> for NormalLoaderType {
             Architecture::Phi3_5MoE => Self::Phi3_5MoE,
             Architecture::DeepseekV2 => Self::DeepSeekV2,
             Architecture::DeepseekV3 => Self::DeepSeekV3,
+            Architecture::Cohere => Self::CohereCommandR,
+            Architecture::Phi3Small => Self::Phi3Small,
         }
     }
 }
@@ -179,6 +183,7 @@ pub enum Which {
         topology = None,
         organization = None,
         write_uqff = None,
+        uqff_shard_size_mb = None,
         from_uqff = None,
         dtype = ModelDType::Auto,
         imatrix = None,
@@ -193,6 +198,7 @@ pub enum Which {
         topology: Option<String>,
         organization: Option<IsqOrganization>,
         write_uqff: Option<PathBuf>,
+        uqff_shard_size_mb: Option<usize>,
         from_uqff: Option<Either<String, Vec<String>>>,
         dtype: ModelDType,
         imatrix: Option<PathBuf>,
@@ -210,6 +216,7 @@ pub enum Which {
         tgt_non_granular_index = None,
         topology = None,
         write_uqff = None,
+        uqff_shard_size_mb = None,
         from_uqff = None,
         dtype = ModelDType::Auto,
         auto_map_params = None,
@@ -224,6 +231,7 @@ pub enum Which {
         tgt_non_granular_index: Option<usize>,
         topology: Option<String>,
         write_uqff: Option<PathBuf>,
+        uqff_shard_size_mb: Option<usize>,
         from_uqff: Option<Either<String, Vec<String>>>,
         dtype: ModelDType,
         auto_map_params: Option<TextAutoMapParams>,
@@ -237,6 +245,7 @@ pub enum Which {
         tokenizer_json = None,
         topology = None,
         write_uqff = None,
+        uqff_shard_size_mb = None,
         from_uqff = None,
         dtype = ModelDType::Auto,
         auto_map_params = None,
@@ -249,6 +258,7 @@ pub enum Which {
         tokenizer_json: Option<String>,
         topology: Option<String>,
         write_uqff: Option<PathBuf>,
+        uqff_shard_size_mb: Option<usize>,
         from_uqff: Option<Either<String, Vec<String>>>,
         dtype: ModelDType,
         auto_map_params: Option<TextAutoMapParams>,
@@ -397,6 +407,7 @@ pub enum Which {
         tokenizer_json = None,
         topology = None,
         write_uqff = None,
+        uqff_shard_size_mb = None,
         from_uqff = None,
         dtype = ModelDType::Auto,
         max_edge = None,
@@ -411,6 +422,7 @@ pub enum Which {
         tokenizer_json: Option<String>,
         topology: Option<String>,
         write_uqff: Option<PathBuf>,
+        uqff_shard_size_mb: Option<usize>,
         from_uqff: Option<Either<String, Vec<String>>>,
         dtype: ModelDType,
         max_edge: Option<u32>,
@@ -86,6 +86,8 @@ fn main() {
             "kernels/ops/ops.cu",
             "kernels/bitsandbytes/dequant.cu",
             "kernels/rotary/rotary.cu",
+            "kernels/rms_norm/fused_add_rms_norm.cu",
+            "kernels/int8/int8_gemm.cu",
         ];
         if cc_over_800 {
             lib_files.push("kernels/marlin/marlin_kernel.cu");
@@ -466,4 +466,68 @@ extern "C" void dequantize_3bit_32_kernel_bf16(unsigned char *Wq_packed,
                                                uint16_t *W_r, int h, int w) {
   assert(false);
 }
-#endif
\ No newline at end of file
+#endif
+
+/*******************************************************************************************************************************************/
+/************* 5-bit *************/
+/*******************************************************************************************************************************************/
+
+// Six 5-bit values per int32, the first in bits 29..25 and the last in bits
+// 4..0.
+template <typename T>
+__global__ void dequantize_5bit_32_kernel(int32_t *Wq_packed, T *scale, T *zero,
+                                          T *W_r, int h, int w) {
+  int i = blockIdx.x * blockDim.x + threadIdx.x;
+  int n = h * w;
+  if (i >= n)
+    return;
+
+  int j = i % w;
+#pragma unroll
+  for (int p = 0; p < 6; p++) {
+    W_r[i + n * p] =
+        ((T)((Wq_packed[i] >> (25 - 5 * p)) & 0x1F) - zero[j]) * scale[j];
+  }
+}
+
+extern "C" void dequantize_5bit_32_kernel_f32(int32_t *Wq_packed, float *scale,
+                                              float *zero, float *W_r, int h,
+                                              int w) {
+  int blocks = cdiv(h * w, BLOCK_SIZE);
+  dequantize_5bit_32_kernel<<<blocks, BLOCK_SIZE>>>(Wq_packed, scale, zero, W_r,
+                                                    h, w);
+}
+
+#if __CUDA_ARCH__ >= 530
+extern "C" void dequantize_5bit_32_kernel_f16(int32_t *Wq_packed, __half *scale,
+                                              __half *zero, __half *W_r, int h,
+                                              int w) {
+  int blocks = cdiv(h * w, BLOCK_SIZE);
+  dequantize_5bit_32_kernel<<<blocks, BLOCK_SIZE>>>(Wq_packed, scale, zero, W_r,
+                                                    h, w);
+}
+#else
+extern "C" void dequantize_5bit_32_kernel_f16(int32_t *Wq_packed,
+                                              uint16_t *scale, uint16_t *zero,
+                                              uint16_t *W_r, int h, int w) {
+  assert(false);
+}
+#endif
+
+#if __CUDA_ARCH__ >= 800
+extern "C" void dequantize_5bit_32_kernel_bf16(int32_t *Wq_packed,
+                                               __nv_bfloat16 *scale,
+                                               __nv_bfloat16 *zero,
+                                               __nv_bfloat16 *W_r, int h,
+                                               int w) {
+  int blocks = cdiv(h * w, BLOCK_SIZE);
+  dequantize_5bit_32_kernel<<<blocks, BLOCK_SIZE>>>(Wq_packed, scale, zero, W_r,
+                                                    h, w);
+}
+#else
+extern "C" void dequantize_5bit_32_kernel_bf16(int32_t *Wq_packed,
+                                               uint16_t *scale, uint16_t *zero,
+                                               uint16_t *W_r, int h, int w) {
+  assert(false);
+}
+#endif
@@ -0,0 +1,43 @@
+#include <cuda_bf16.h>
+#include <cuda_fp16.h>
+#include <stdint.h>
+
+// Symmetric int8 quantization: `round(x / scale)` clamped to [-127, 127].
+// Halfway cases round away from zero, as `f32::round` does.
+template <typename T>
+__global__ void quantize_i8_kernel(const T *x, int8_t *out, const float scale,
+                                   const int64_t n) {
+  const int64_t i = (int64_t)blockIdx.x * blockDim.x + threadIdx.x;
+  if (i < n) {
+    const float q = roundf((float)x[i] / scale);
+    out[i] = (int8_t)fminf(fmaxf(q, -127.f), 127.f);
+  }
+}
+
+// Scale the i32 accumulator of an int8 GEMM back to `T`.
+template <typename T>
+__global__ void dequantize_i32_kernel(const int32_t *acc, T *out,
+                                      const float scale, const int64_t n) {
+  const int64_t i = (int64_t)blockIdx.x * blockDim.x + threadIdx.x;
+  if (i < n) {
+    out[i] = (T)((float)acc[i] * scale);
+  }
+}
+
+#define INT8_GEMM_OPS(TYPENAME, RUST_NAME)                                     \
+  extern "C" void mq_quantize_i8_##RUST_NAME(const TYPENAME *x, int8_t *out,   \
+                                             float scale, int64_t n) {         \
+    const int nthreads = 256;                                                  \
+    const int64_t nblocks = (n + nthreads - 1) / nthreads;                     \
+    quantize_i8_kernel<<<nblocks, nthreads>>>(x, out, scale, n);               \
+  }                                                                            \
+  extern "C" void mq_dequantize_i32_##RUST_NAME(                               \
+      const int32_t *acc, TYPENAME *out, float scale, int64_t n) {             \
+    const int nthreads = 256;                                                  \
+    const int64_t nblocks = (n + nthreads - 1) / nthreads;                     \
+    dequantize_i32_kernel<<<nblocks, nthreads>>>(acc, out, scale, n);          \
+  }
+
+INT8_GEMM_OPS(float, f32)
+INT8_GEMM_OPS(__half, f16)
+INT8_GEMM_OPS(__nv_bfloat16, bf16)
@@ -0,0 +1,63 @@
+#include <cuda_bf16.h>
+#include <cuda_fp16.h>
+#include <stdint.h>
+
+// Sum of `val` over the block, returned to every thread. The block size must
+// be a multiple of 32.
+__device__ float block_sum(float val) {
+  __shared__ float partial[32];
+  const int lane = threadIdx.x % 32;
+  const int warp = threadIdx.x / 32;
+  for (int offset = 16; offset > 0; offset /= 2) {
+    val += __shfl_xor_sync(0xffffffff, val, offset);
+  }
+  if (lane == 0) {
+    partial[warp] = val;
+  }
+  __syncthreads();
+  const int n_warps = blockDim.x / 32;
+  val = lane < n_warps ? partial[lane] : 0.f;
+  for (int offset = 16; offset > 0; offset /= 2) {
+    val += __shfl_xor_sync(0xffffffff, val, offset);
+  }
+  return val;
+}
+
+// One block per row: `sum = x + residual` and `normed = sum / rms(sum) * weight`.
+// The sum is rounded to `T` before it is normalized, as in an add followed by
+// an RmsNorm, and the norm itself is computed in f32.
+template <typename T>
+__global__ void fused_add_rms_norm_kernel(const T *x, const T *residual,
+                                          const T *weight, T *sum, T *normed,
+                                          const int32_t hidden,
+                                          const float eps) {
+  const int64_t offset = (int64_t)blockIdx.x * hidden;
+  float sum_sq = 0.f;
+  for (int i = threadIdx.x; i < hidden; i += blockDim.x) {
+    const T s = (T)((float)x[offset + i] + (float)residual[offset + i]);
+    sum[offset + i] = s;
+    sum_sq += (float)s * (float)s;
+  }
+  const float scale = rsqrtf(block_sum(sum_sq) / hidden + eps);
+  for (int i = threadIdx.x; i < hidden; i += blockDim.x) {
+    normed[offset + i] =
+        (T)((float)sum[offset + i] * scale * (float)weight[i]);
+  }
+}
+
+#define FUSED_ADD_RMS_NORM_OP(TYPENAME, RUST_NAME)                             \
+  extern "C" void mq_fused_add_rms_norm_##RUST_NAME(                           \
+      const TYPENAME *x, const TYPENAME *residual, const TYPENAME *weight,     \
+      TYPENAME *sum, TYPENAME *normed, int32_t rows, int32_t hidden,           \
+      float eps) {                                                             \
+    int nthreads = (hidden + 31) / 32 * 32;                                    \
+    if (nthreads > 1024) {                                                     \
+      nthreads = 1024;                                                         \
+    }                                                                          \
+    fused_add_rms_norm_kernel<<<rows, nthreads>>>(x, residual, weight, sum,    \
+                                                  normed, hidden, eps);        \
+  }
+
+FUSED_ADD_RMS_NORM_OP(float, f32)
+FUSED_ADD_RMS_NORM_OP(__half, f16)
+FUSED_ADD_RMS_NORM_OP(__nv_bfloat16, bf16)
@@ -12,7 +12,7 @@ use crate::{
         deserialize_tensor, fake_deserialize_tensor, serialize_tensor, version_is_compatible,
         UQFF_VERSION,
     },
-    Comm, IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedConfig,
+    Comm, IsqType, QuantInfo, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedConfig,
     QuantizedSerde, QuantizedSerdeType, ShardedVarBuilder,
 };
 
@@ -100,6 +100,7 @@ impl QuantMethod for AfqLayer {
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
+            | QuantMethodConfig::Exl2 { .. }
             | QuantMethodConfig::Unquantized(_) => unreachable!(),
             QuantMethodConfig::Afq {
                 weight,
@@ -187,6 +188,14 @@ impl QuantMethod for AfqLayer {
     ) -> Result<Arc<dyn QuantMethod>> {
         todo!()
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        QuantInfo {
+            method: self.name(),
+            bits: Some(self.bits as usize),
+            group_size: Some(self.group_size as usize),
+        }
+    }
 }
 
 impl AfqLayer {
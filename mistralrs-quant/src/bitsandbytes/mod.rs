@@ -7,7 +7,8 @@ use candle_core::{Context, DType, Device, Result, Shape, Tensor};
 use serde::Deserialize;
 
 use crate::{
-    IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde, ShardedVarBuilder,
+    IsqType, QuantInfo, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde,
+    ShardedVarBuilder,
 };
 
 #[cfg(feature = "cuda")]
@@ -212,7 +213,8 @@ impl QuantMethod for BnbLinear {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
-            | QuantMethodConfig::Afq { .. } => unreachable!(),
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Exl2 { .. } => unreachable!(),
             QuantMethodConfig::Bnb {
                 weight,
                 bias,
@@ -265,6 +267,18 @@ impl QuantMethod for BnbLinear {
     ) -> Result<Arc<dyn QuantMethod>> {
         todo!()
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        let bits = match self.quant_ty {
+            BnbQuantType::Int8 => 8,
+            BnbQuantType::Fp4 | BnbQuantType::Nf4 => 4,
+        };
+        QuantInfo {
+            method: self.name(),
+            bits: Some(bits),
+            group_size: Some(self.params.blocksize),
+        }
+    }
 }
 
 impl QuantizedSerde for BnbLinear {
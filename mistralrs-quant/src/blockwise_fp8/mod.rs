@@ -12,7 +12,7 @@ use crate::{
     generate_isq, generate_isq_imatrix,
     hqq::{ISQ_HQQ_DEFAULT_OPT_STEPS, ISQ_HQQ_GROUP_SIZE},
     AfqBits, AfqGroupSize, AfqLayer, DummyLayer, FP8Linear, GgufMatMul, HqqAxis, HqqBits,
-    HqqConfig, HqqLayer, IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard,
+    HqqConfig, HqqLayer, IsqType, QuantInfo, QuantMethod, QuantMethodConfig, QuantizeOntoGuard,
     QuantizedConfig, QuantizedSerde, Shard, ShardedVarBuilder, UnquantLinear,
 };
 
@@ -38,7 +38,8 @@ impl QuantMethod for BlockwiseFP8Linear {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::FP8 { .. }
-            | QuantMethodConfig::Afq { .. } => unreachable!(),
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Exl2 { .. } => unreachable!(),
             QuantMethodConfig::BlockwiseFP8 {
                 weight,
                 weight_scale_inv,
@@ -223,6 +224,14 @@ impl QuantMethod for BlockwiseFP8Linear {
             }
         }
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        QuantInfo {
+            method: self.name(),
+            bits: Some(8),
+            group_size: Some(self.weight_block_size.iter().product()),
+        }
+    }
 }
 
 // Serialization structure:
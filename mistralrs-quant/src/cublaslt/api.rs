@@ -1,4 +1,4 @@
-use candle_core::cuda::cudarc::driver::{DevicePtr, DeviceRepr};
+use candle_core::cuda::cudarc::driver::{CudaSlice, CudaView, DevicePtr, DevicePtrMut, DeviceRepr};
 use candle_core::cuda::CudaDType;
 use float8::F8E4M3;
 use std::ffi::c_int;
@@ -10,7 +10,7 @@ use half::{bf16, f16};
 use std::sync::Arc;
 
 use super::matmul::{Activation, CublasLTDType, CudaBlasLT, Matmul, MatmulConfig, OutSlice};
-use super::F8MatmulOutType;
+use super::{ffi, F8MatmulOutType};
 
 #[derive(Debug, Clone)]
 pub struct CublasLt(Arc<CudaBlasLT>);
@@ -580,3 +580,161 @@ pub fn fused_batch_matmul(
         a.apply_op2(b, op)
     }
 }
+
+pub struct CublasLTMatmulI8 {
+    pub cublaslt: Arc<CudaBlasLT>,
+    pub a_scale: f32,
+    pub b_scale: f32,
+}
+
+impl CublasLTMatmulI8 {
+    pub fn fwd<T: CublasLTDType>(
+        &self,
+        a: &candle_core::CudaStorage,
+        a_l: &Layout,
+        b: &candle_core::CudaStorage,
+        b_l: &Layout,
+    ) -> Result<(candle_core::CudaStorage, Shape)> {
+        use std::ffi::c_void;
+
+        let dev = a.device();
+        let dtype = a.dtype();
+
+        // Assume TN
+        let (m, k) = a_l.shape().dims2()?;
+        let (n, b_1) = b_l.shape().dims2()?;
+        if b_1 != k {
+            candle_core::bail!("This layer only supports TN layout");
+        }
+        if !a_l.is_contiguous() || !b_l.is_contiguous() {
+            candle_core::bail!("`a` and `b` must be contiguous");
+        }
+        if k % 4 != 0 || m % 4 != 0 {
+            candle_core::bail!("Int8 cuBLASlt matmul needs `k` and `m` to be multiples of 4");
+        }
+
+        let a = a.as_cuda_slice::<T>()?.slice(a_l.start_offset()..);
+        let b = b.as_cuda_slice::<T>()?.slice(b_l.start_offset()..);
+
+        let quantize = |x: &CudaView<T>, len: usize, scale: f32| -> Result<CudaSlice<i8>> {
+            let mut out = unsafe { dev.alloc::<i8>(len).w()? };
+            let x = *x.device_ptr() as *const c_void;
+            let out_ptr = *out.device_ptr_mut() as *mut i8;
+            let len = len as i64;
+            unsafe {
+                match dtype {
+                    DType::F32 => ffi::mq_quantize_i8_f32(x, out_ptr, scale, len),
+                    DType::F16 => ffi::mq_quantize_i8_f16(x, out_ptr, scale, len),
+                    DType::BF16 => ffi::mq_quantize_i8_bf16(x, out_ptr, scale, len),
+                    _ => unreachable!(),
+                }
+            }
+            Ok(out)
+        };
+        let a_q = quantize(&a, m * k, self.a_scale)?;
+        let b_q = quantize(&b, n * k, self.b_scale)?;
+
+        let mut acc = unsafe { dev.alloc::<i32>(n * m).w()? };
+        let config = MatmulConfig {
+            transa: true,
+            transb: false,
+            m: m as u64,
+            n: n as u64,
+            k: k as u64,
+            alpha: 1.0,
+            lda: k as i64,
+            ldb: k as i64,
+            beta: 0.0,
+            ldc: m as i64,
+            stride_a: None,
+            stride_b: None,
+            stride_c: None,
+            stride_bias: None,
+            batch_size: None,
+        };
+        unsafe {
+            self.cublaslt
+                .matmul_i8(config, &a_q, &b_q, &mut acc)
+                .map_err(|e| candle_core::Error::Cuda(Box::new(e)))?;
+        }
+
+        let mut out = unsafe { dev.alloc::<T>(n * m).w()? };
+        let acc_ptr = *acc.device_ptr() as *const i32;
+        let out_ptr = *out.device_ptr_mut() as *mut c_void;
+        let scale = self.a_scale * self.b_scale;
+        let len = (n * m) as i64;
+        unsafe {
+            match dtype {
+                DType::F32 => ffi::mq_dequantize_i32_f32(acc_ptr, out_ptr, scale, len),
+                DType::F16 => ffi::mq_dequantize_i32_f16(acc_ptr, out_ptr, scale, len),
+                DType::BF16 => ffi::mq_dequantize_i32_bf16(acc_ptr, out_ptr, scale, len),
+                _ => unreachable!(),
+            }
+        }
+
+        let out = candle_core::CudaStorage::wrap_cuda_slice(out, dev.clone());
+        Ok((out, Shape::from((n, m))))
+    }
+}
+
+impl candle_core::CustomOp2 for CublasLTMatmulI8 {
+    fn name(&self) -> &'static str {
+        "cublaslt-matmul-i8"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle_core::bail!("no cpu support for cublaslt-matmul-i8")
+    }
+
+    fn cuda_fwd(
+        &self,
+        a: &candle_core::CudaStorage,
+        a_l: &Layout,
+        b: &candle_core::CudaStorage,
+        b_l: &Layout,
+    ) -> Result<(candle_core::CudaStorage, Shape)> {
+        match a.dtype() {
+            candle_core::DType::F16 => self.fwd::<f16>(a, a_l, b, b_l),
+            candle_core::DType::BF16 => self.fwd::<bf16>(a, a_l, b, b_l),
+            candle_core::DType::F32 => self.fwd::<f32>(a, a_l, b, b_l),
+            dt => {
+                candle_core::bail!("cublaslt-matmul-i8 is only supported for f16/bf16/f32 ({dt:?})")
+            }
+        }
+    }
+}
+
+/// Int8 matmul with an i32 accumulator using CublasLt. Both inputs are quantized to the
+/// symmetric int8 grid with their scale and the accumulator is scaled back to their dtype.
+///
+/// # Arguments
+///
+/// * `a` - Input tensor of size MxK, M must be a multiple of 4
+/// * `b` - Input tensor of size NxK
+/// * `a_scale` - Quantization scale of `a`
+/// * `b_scale` - Quantization scale of `b`
+/// * `cublaslt` - CublasLt handle
+///
+/// K must be a multiple of 4. The resulting tensor is of shape NxM
+pub fn matmul_i8(
+    a: &Tensor,
+    b: &Tensor,
+    a_scale: f32,
+    b_scale: f32,
+    cublaslt: CublasLt,
+) -> Result<Tensor> {
+    a.apply_op2_no_bwd(
+        b,
+        &CublasLTMatmulI8 {
+            cublaslt: cublaslt.0,
+            a_scale,
+            b_scale,
+        },
+    )
+}
@@ -0,0 +1,11 @@
+use std::ffi::c_void;
+
+extern "C" {
+    pub(crate) fn mq_quantize_i8_f32(x: *const c_void, out: *mut i8, scale: f32, n: i64);
+    pub(crate) fn mq_quantize_i8_f16(x: *const c_void, out: *mut i8, scale: f32, n: i64);
+    pub(crate) fn mq_quantize_i8_bf16(x: *const c_void, out: *mut i8, scale: f32, n: i64);
+
+    pub(crate) fn mq_dequantize_i32_f32(acc: *const i32, out: *mut c_void, scale: f32, n: i64);
+    pub(crate) fn mq_dequantize_i32_f16(acc: *const i32, out: *mut c_void, scale: f32, n: i64);
+    pub(crate) fn mq_dequantize_i32_bf16(acc: *const i32, out: *mut c_void, scale: f32, n: i64);
+}
@@ -45,6 +45,78 @@ impl CudaBlasLT {
     }
 }
 
+impl CudaBlasLT {
+    /// Int8 matrix multiplication with an i32 accumulator. See
+    /// [nvidia docs](https://docs.nvidia.com/cuda/cublas/#cublasltmatmul)
+    ///
+    /// There are a few requirements:
+    /// - Compute and scale types are i32, `alpha` and `beta` are taken as 1 and 0 (upheld)
+    /// - `transa && !transb` (upheld)
+    /// - A and B must be i8 and C must be i32 (upheld)
+    /// - The leading dimensions must be multiples of 4
+    ///
+    /// # Safety
+    /// This is unsafe because improper arguments may lead to invalid
+    /// memory accesses.
+    pub unsafe fn matmul_i8<I: DevicePtr<i8>, O: DevicePtrMut<i32>>(
+        &self,
+        cfg: MatmulConfig,
+        a: &I,
+        b: &I,
+        c: &mut O,
+    ) -> Result<(), CublasError> {
+        assert!(cfg.transa);
+        assert!(!cfg.transb);
+
+        let a_layout = MatrixLayout::new(sys::cudaDataType_t::CUDA_R_8I, cfg.k, cfg.m, cfg.lda)?;
+        let b_layout = MatrixLayout::new(sys::cudaDataType_t::CUDA_R_8I, cfg.k, cfg.n, cfg.ldb)?;
+        let c_layout = MatrixLayout::new(sys::cudaDataType_t::CUDA_R_32I, cfg.m, cfg.n, cfg.ldc)?;
+
+        // Matmul description
+        let matmul_desc = MatmulDesc::new(
+            sys::cublasComputeType_t::CUBLAS_COMPUTE_32I,
+            sys::cudaDataType_t::CUDA_R_32I,
+        )?;
+        matmul_desc.set_transpose(cfg.transa, Matrix::A)?;
+        matmul_desc.set_transpose(cfg.transb, Matrix::B)?;
+
+        // Create matmul heuristic search preferences
+        let matmul_pref = MatmulPref::new()?;
+        matmul_pref.set_workspace_size(self.workspace().size)?;
+
+        let heuristic = result::get_matmul_algo_heuristic(
+            *self.handle(),
+            matmul_desc.handle,
+            a_layout.handle,
+            b_layout.handle,
+            c_layout.handle,
+            c_layout.handle,
+            matmul_pref.handle,
+        )?;
+
+        let alpha = 1i32;
+        let beta = 0i32;
+        result::matmul(
+            *self.handle(),
+            matmul_desc.handle,
+            (&alpha) as *const _ as *const _,
+            (&beta) as *const _ as *const _,
+            *a.device_ptr() as *const _,
+            a_layout.handle,
+            *b.device_ptr() as *const _,
+            b_layout.handle,
+            *c.device_ptr_mut() as *const _,
+            c_layout.handle,
+            *c.device_ptr_mut() as *mut _,
+            c_layout.handle,
+            (&heuristic.algo) as *const _,
+            *self.workspace().buffer.device_ptr() as *const CUdeviceptr as *mut _,
+            self.workspace().size,
+            *self.stream() as *mut _,
+        )
+    }
+}
+
 impl Drop for CudaBlasLT {
     fn drop(&mut self) {
         let handle = mem::replace(&mut self.handle, std::ptr::null_mut());
@@ -10,13 +10,15 @@ use std::sync::{Mutex, Once};
 #[cfg(feature = "cuda")]
 mod api;
 #[cfg(feature = "cuda")]
+mod ffi;
+#[cfg(feature = "cuda")]
 mod matmul;
 #[cfg(test)]
 #[cfg(feature = "cuda")]
 mod tests;
 
 #[cfg(feature = "cuda")]
-pub use api::{fused_batch_matmul, fused_batch_matmul_f8, CublasLt};
+pub use api::{fused_batch_matmul, fused_batch_matmul_f8, matmul_i8, CublasLt};
 
 pub enum F8MatmulOutType {
     F8,
@@ -127,6 +129,27 @@ impl CublasLtWrapper {
         }
     }
 
+    /// Int8 matmul with an i32 accumulator using CublasLt.
+    ///
+    /// # Arguments
+    ///
+    /// * `a` - Input tensor of size MxK, M must be a multiple of 4
+    /// * `b` - Input tensor of size NxK
+    /// * `a_scale` - Quantization scale of `a`
+    /// * `b_scale` - Quantization scale of `b`
+    ///
+    /// K must be a multiple of 4. The resulting tensor is of shape NxM
+    pub fn matmul_i8(&self, a: &Tensor, b: &Tensor, a_scale: f32, b_scale: f32) -> Result<Tensor> {
+        #[cfg(feature = "cuda")]
+        {
+            matmul_i8(a, b, a_scale, b_scale, self.cublaslt.clone())
+        }
+        #[cfg(not(feature = "cuda"))]
+        {
+            candle_core::bail!("`cuda` feature is not enabled")
+        }
+    }
+
     /// Fused batch matmul + add + Relu/Gelu activation using CublasLt.
     ///
     /// # Arguments
@@ -107,3 +107,22 @@ fn test_fused_batch_matmul_f8e4m3_out_bf16() -> Result<()> {
         .all(|x| x.iter().all(|y| y.iter().all(|x| *x <= range))));
     Ok(())
 }
+
+#[test]
+fn test_matmul_i8_is_exact_on_the_int8_grid() -> Result<()> {
+    let device = Device::new_cuda(0)?;
+
+    // Integer values in [-8, 8) scaled onto the grid, so the i32 accumulator is exact.
+    let grid = |shape: (usize, usize), scale: f64| -> Result<Tensor> {
+        (Tensor::rand(0f32, 16., shape, &device)?.floor()? - 8.)?.affine(scale, 0.)
+    };
+    let a = grid((32, 64), 0.25)?;
+    let b = grid((8, 64), 0.5)?;
+
+    let cublaslt = CublasLt::new(&device)?;
+    let res = matmul_i8(&a, &b, 0.25, 0.5, cublaslt)?;
+    let expected = b.matmul(&a.t()?)?;
+
+    assert_eq!(res.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+    Ok(())
+}
@@ -5,9 +5,9 @@ use candle_nn::Linear;
 
 use crate::{
     blockwise_fp8::blockwise_fp8_linear_b, distributed, gptq::gptq_linear,
-    lora::merge_lora_weights, AfqLayer, BnbLinear, DistributedKind, DummyLayer, FP8Linear,
-    GgufMatMul, HqqLayer, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedConfig,
-    QuantizedSerde, QuantizedSerdeType, Shard, ShardedVarBuilder, UnquantLinear,
+    lora::merge_lora_weights, AfqLayer, BnbLinear, DistributedKind, DummyLayer, Exl2Layer,
+    FP8Linear, GgufMatMul, HqqLayer, QuantInfo, QuantMethod, QuantMethodConfig, QuantizeOntoGuard,
+    QuantizedConfig, QuantizedSerde, QuantizedSerdeType, Shard, ShardedVarBuilder, UnquantLinear,
 };
 
 use super::{Comm, SumAllReduce};
@@ -50,10 +50,11 @@ impl RowParallelLayer {
                 QuantizedConfig::Gptq { .. }
                     | QuantizedConfig::Bitsandbytes { .. }
                     | QuantizedConfig::Afq { .. }
+                    | QuantizedConfig::Exl2 { .. }
             ) && comm.world_size() != 1
             {
                 candle_core::bail!(
-                    "GPTQ and BNB and AFQ quantization types to not support tensor parallelism, but got a world size of {}",
+                    "GPTQ, BNB, AFQ and EXL2 quantization types to not support tensor parallelism, but got a world size of {}",
                     comm.world_size()
                 );
             }
@@ -72,6 +73,9 @@ impl RowParallelLayer {
                 QuantizedConfig::Afq { .. } => {
                     AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
                 }
+                QuantizedConfig::Exl2 { .. } => {
+                    Exl2Layer::exl2_linear_b(in_dim, out_dim, bias, vb.clone())?
+                }
             }
         } else {
             // Handle the case where the layer is dummy (no tensors)
@@ -185,6 +189,10 @@ impl QuantMethod for RowParallelLayer {
     fn is_distributed(&self) -> Option<DistributedKind> {
         Some(DistributedKind::RowParallel)
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        self.weight.quant_info()
+    }
 }
 
 impl QuantizedSerde for RowParallelLayer {
@@ -251,10 +259,11 @@ impl ColumnParallelLayer {
                 QuantizedConfig::Gptq { .. }
                     | QuantizedConfig::Bitsandbytes { .. }
                     | QuantizedConfig::Afq { .. }
+                    | QuantizedConfig::Exl2 { .. }
             ) && comm.world_size() != 1
             {
                 candle_core::bail!(
-                    "GPTQ and BNB and AFQ quantization types to not support tensor parallelism, but got a world size of {}",
+                    "GPTQ, BNB, AFQ and EXL2 quantization types to not support tensor parallelism, but got a world size of {}",
                     comm.world_size()
                 );
             }
@@ -273,6 +282,9 @@ impl ColumnParallelLayer {
                 QuantizedConfig::Afq { .. } => {
                     AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
                 }
+                QuantizedConfig::Exl2 { .. } => {
+                    Exl2Layer::exl2_linear_b(in_dim, out_dim, bias, vb.clone())?
+                }
             }
         } else {
             // Handle the case where the layer is dummy (no tensors)
@@ -392,6 +404,10 @@ impl QuantMethod for ColumnParallelLayer {
     fn is_distributed(&self) -> Option<DistributedKind> {
         Some(DistributedKind::ColumnParallel)
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        self.weight.quant_info()
+    }
 }
 
 impl QuantizedSerde for ColumnParallelLayer {
@@ -464,6 +480,9 @@ impl ReplicatedLayer {
                 QuantizedConfig::Afq { .. } => {
                     AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
                 }
+                QuantizedConfig::Exl2 { .. } => {
+                    Exl2Layer::exl2_linear_b(in_dim, out_dim, bias, vb.clone())?
+                }
             }
         } else {
             // Handle the case where the layer is dummy (no tensors)
@@ -548,6 +567,10 @@ impl QuantMethod for ReplicatedLayer {
     fn is_distributed(&self) -> Option<DistributedKind> {
         Some(DistributedKind::Replicated)
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        self.0.quant_info()
+    }
 }
 
 impl QuantizedSerde for ReplicatedLayer {
@@ -813,3 +836,248 @@ pub fn compute_n_kv_groups(
         num_attention_heads / total_num_kv_heads
     }
 }
+
+/// The `(offset, len)` of the rows of a column parallel weight with `out_dim` rows which belong to `shard`.
+fn column_shard_range(out_dim: usize, shard: Shard) -> Result<(usize, usize)> {
+    let (offset, len) = match shard {
+        Shard::Simple {
+            dim: 0,
+            rank,
+            world_size,
+        } => {
+            if out_dim % world_size != 0 {
+                candle_core::bail!(
+                    "Cannot split {out_dim} output features over a world size of {world_size}"
+                );
+            }
+            let len = out_dim / world_size;
+            (rank * len, len)
+        }
+        Shard::Offset {
+            dim: 0,
+            offset,
+            len,
+        } => (offset, len),
+        _ => candle_core::bail!("Column parallel weights must be sharded on dimension 0"),
+    };
+    if offset + len > out_dim {
+        candle_core::bail!(
+            "Shard {offset}..{} is out of range for {out_dim} output features",
+            offset + len
+        );
+    }
+    Ok((offset, len))
+}
+
+/// Load the q, k and v projections of an attention layer, sharded like [`ColumnParallelLayer`].
+///
+/// Checkpoints usually have separate `q_proj`, `k_proj` and `v_proj` weights, but if a fused `qkv_proj`
+/// weight is present instead it is partitioned into the three projections using the head dims.
+#[allow(clippy::too_many_arguments)]
+pub fn qkv_column_parallel_layers(
+    in_dim: usize,
+    head_dim: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    config: &Option<QuantizedConfig>,
+    bias: bool,
+    comm: &Arc<crate::Comm>,
+    vb: ShardedVarBuilder,
+) -> Result<[Arc<dyn QuantMethod>; 3]> {
+    let q_dim = head_dim * num_attention_heads;
+    let kv_dim = head_dim * num_key_value_heads;
+    let kv_shard = compute_kv_shard(num_key_value_heads, head_dim, comm);
+
+    if !vb.contains_tensor("qkv_proj.weight") {
+        let q_proj = ColumnParallelLayer::new(in_dim, q_dim, config, bias, comm, vb.pp("q_proj"))?;
+        let k_proj = ColumnParallelLayer::new_with_shard(
+            in_dim,
+            kv_dim,
+            config,
+            bias,
+            comm,
+            kv_shard,
+            vb.pp("k_proj"),
+        )?;
+        let v_proj = ColumnParallelLayer::new_with_shard(
+            in_dim,
+            kv_dim,
+            config,
+            bias,
+            comm,
+            kv_shard,
+            vb.pp("v_proj"),
+        )?;
+        return Ok([q_proj, k_proj, v_proj]);
+    }
+
+    if config.is_some() {
+        candle_core::bail!("Loading a fused `qkv_proj` is only supported for unquantized weights");
+    }
+    let vb = vb.pp("qkv_proj");
+    let fused_dim = q_dim + 2 * kv_dim;
+    // The shape check validates the partition: the fused rows must be exactly q, then k, then v.
+    let weight = vb.get_with_hints((fused_dim, in_dim), "weight", Default::default())?;
+    let bias = if bias && vb.contains_tensor("bias") {
+        Some(vb.get_with_hints((fused_dim,), "bias", Default::default())?)
+    } else {
+        None
+    };
+
+    let partitions = [
+        (0, q_dim, shard(0, comm.rank(), comm.world_size())),
+        (q_dim, kv_dim, kv_shard),
+        (q_dim + kv_dim, kv_dim, kv_shard),
+    ];
+    let mut layers = Vec::with_capacity(partitions.len());
+    for (start, out_dim, shard) in partitions {
+        let (offset, len) = column_shard_range(out_dim, shard)?;
+        let weight = weight.narrow(0, start + offset, len)?.contiguous()?;
+        let bias = bias
+            .as_ref()
+            .map(|b| b.narrow(0, start + offset, len)?.contiguous())
+            .transpose()?;
+        let layer = <UnquantLinear as QuantMethod>::new(QuantMethodConfig::Unquantized(
+            Linear::new(weight, bias),
+        ))?;
+        layers.push(Arc::new(layer) as Arc<dyn QuantMethod>);
+    }
+    let [q_proj, k_proj, v_proj]: [Arc<dyn QuantMethod>; 3] = layers
+        .try_into()
+        .map_err(|_| candle_core::Error::Msg("Expected 3 qkv partitions".to_string()))?;
+    Ok([q_proj, k_proj, v_proj])
+}
+
+/// Load a fused qkv projection with `q_dim + 2 * kv_dim` output features and no bias.
+///
+/// This is the counterpart of [`qkv_column_parallel_layers`]: checkpoints usually have a fused `qkv_proj`
+/// weight, but if separate `q_proj`, `k_proj` and `v_proj` weights are present instead they are concatenated.
+pub fn fused_qkv_linear_no_bias(
+    in_dim: usize,
+    q_dim: usize,
+    kv_dim: usize,
+    config: &Option<QuantizedConfig>,
+    vb: ShardedVarBuilder,
+) -> Result<Arc<dyn QuantMethod>> {
+    if vb.contains_tensor("qkv_proj.weight") || !vb.contains_tensor("q_proj.weight") {
+        return crate::linear_no_bias(in_dim, q_dim + 2 * kv_dim, config, vb.pp("qkv_proj"));
+    }
+
+    if config.is_some() {
+        candle_core::bail!(
+            "Loading separate `q_proj`, `k_proj` and `v_proj` weights is only supported for unquantized weights"
+        );
+    }
+    let q = vb.get_with_hints((q_dim, in_dim), "q_proj.weight", Default::default())?;
+    let k = vb.get_with_hints((kv_dim, in_dim), "k_proj.weight", Default::default())?;
+    let v = vb.get_with_hints((kv_dim, in_dim), "v_proj.weight", Default::default())?;
+    let weight = Tensor::cat(&[q, k, v], 0)?;
+    let layer = <UnquantLinear as QuantMethod>::new(QuantMethodConfig::Unquantized(Linear::new(
+        weight, None,
+    )))?;
+    Ok(Arc::new(layer))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use super::{fused_qkv_linear_no_bias, qkv_column_parallel_layers};
+    use crate::{Comm, Id, QuantMethod, ShardedSafeTensors};
+
+    #[cfg(not(all(feature = "cuda", feature = "nccl")))]
+    #[test]
+    fn fused_and_split_qkv_load_identically() -> Result<()> {
+        let dev = Device::Cpu;
+        let (hidden, head_dim, heads, kv_heads) = (16, 4, 4, 2);
+        let q = Tensor::randn(0f32, 1., (heads * head_dim, hidden), &dev)?;
+        let k = Tensor::randn(0f32, 1., (kv_heads * head_dim, hidden), &dev)?;
+        let v = Tensor::randn(0f32, 1., (kv_heads * head_dim, hidden), &dev)?;
+        let fused = Tensor::cat(&[&q, &k, &v], 0)?;
+
+        let split_vb = ShardedSafeTensors::wrap(
+            Box::new(HashMap::from([
+                ("attn.q_proj.weight".to_string(), q),
+                ("attn.k_proj.weight".to_string(), k),
+                ("attn.v_proj.weight".to_string(), v),
+            ])),
+            DType::F32,
+            dev.clone(),
+        );
+        let fused_vb = ShardedSafeTensors::wrap(
+            Box::new(HashMap::from([("attn.qkv_proj.weight".to_string(), fused)])),
+            DType::F32,
+            dev.clone(),
+        );
+        let comm = Arc::new(Comm::from_device(Id::new(), &dev, 0, 1)?);
+        let xs = Tensor::randn(0f32, 1., (1, 3, hidden), &dev)?;
+
+        let split = qkv_column_parallel_layers(
+            hidden,
+            head_dim,
+            heads,
+            kv_heads,
+            &None,
+            false,
+            &comm,
+            split_vb.pp("attn"),
+        )?;
+        let from_fused = qkv_column_parallel_layers(
+            hidden,
+            head_dim,
+            heads,
+            kv_heads,
+            &None,
+            false,
+            &comm,
+            fused_vb.pp("attn"),
+        )?;
+        let mut split_outputs = Vec::new();
+        for (a, b) in split.iter().zip(&from_fused) {
+            let (a, b) = (a.forward(&xs)?, b.forward(&xs)?);
+            let diff = (&a - &b)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-6, "{diff}");
+            split_outputs.push(a);
+        }
+
+        // The fused layer built from separate weights matches the concatenated split outputs.
+        let (q_dim, kv_dim) = (heads * head_dim, kv_heads * head_dim);
+        for vb in [split_vb, fused_vb] {
+            let qkv = fused_qkv_linear_no_bias(hidden, q_dim, kv_dim, &None, vb.pp("attn"))?;
+            let diff = (qkv.forward(&xs)? - Tensor::cat(&split_outputs, 2)?)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-6, "{diff}");
+        }
+
+        // A fused weight whose size does not match the head dims is rejected.
+        assert!(qkv_column_parallel_layers(
+            hidden,
+            head_dim,
+            heads,
+            heads,
+            &None,
+            false,
+            &comm,
+            ShardedSafeTensors::wrap(
+                Box::new(HashMap::from([(
+                    "attn.qkv_proj.weight".to_string(),
+                    Tensor::zeros((q_dim + 2 * kv_dim, hidden), DType::F32, &dev)?,
+                )])),
+                DType::F32,
+                dev.clone(),
+            )
+            .pp("attn"),
+        )
+        .is_err());
+        Ok(())
+    }
+}
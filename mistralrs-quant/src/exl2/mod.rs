@@ -0,0 +1,409 @@
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::Linear;
+
+use crate::{
+    DummyLayer, IsqType, QuantInfo, QuantMethod, QuantMethodConfig, QuantizeOntoGuard,
+    QuantizedSerde, ShardedVarBuilder, UnquantLinear,
+};
+
+/// Bit widths EXL2 quantizes groups with.
+const EXL2_BITS: [usize; 6] = [2, 3, 4, 5, 6, 8];
+
+/// A linear layer with EXL2 (exllamav2) weights.
+///
+/// EXL2 quantizes each group of input rows with its own bit width. The rows are reordered, each
+/// group is packed column-wise into `u32` words, and each column of a group has a 4-bit scale
+/// relative to the group's maximum scale. There is no EXL2 kernel, so the weight is dequantized
+/// once when the layer is built and the forward pass is an unquantized matmul.
+#[derive(Debug)]
+pub struct Exl2Layer {
+    dequantized: UnquantLinear,
+    group_size: usize,
+}
+
+impl QuantMethod for Exl2Layer {
+    fn new(method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        match method {
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Gptq { .. }
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Dummy
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Bnb { .. }
+            | QuantMethodConfig::BlockwiseFP8 { .. }
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Unquantized(_) => unreachable!(),
+            QuantMethodConfig::Exl2 {
+                q_weight,
+                q_scale,
+                q_scale_max,
+                q_groups,
+                q_invperm,
+                bias,
+            } => {
+                let w = exl2_dequantize(&q_weight, &q_scale, &q_scale_max, &q_groups, &q_invperm)?
+                    .to_dtype(q_scale_max.dtype())?;
+                Ok(Self {
+                    dequantized: UnquantLinear::new(QuantMethodConfig::Unquantized(Linear::new(
+                        w, bias,
+                    )))?,
+                    group_size: exl2_group_size(q_invperm.dim(0)?, q_scale_max.dim(0)?),
+                })
+            }
+        }
+    }
+
+    fn dequantize_w(&self) -> Result<Tensor> {
+        self.dequantized.dequantize_w()
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.dequantized.forward(xs)
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        None
+    }
+
+    fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        candle_core::bail!("EXL2 quantization does not support adding weight delta.")
+    }
+
+    fn dtype_and_device(&self) -> (DType, Device) {
+        self.dequantized.dtype_and_device()
+    }
+
+    fn apply_isq(
+        self: Arc<Self>,
+        dtype: Option<IsqType>,
+        device: Device,
+        n_quantized: &AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
+        guard: QuantizeOntoGuard,
+    ) -> Result<Arc<dyn QuantMethod>> {
+        // Requantize from the dequantized weight.
+        let (w, bias) = self
+            .dequantized
+            .unquant_weight_bias()
+            .expect("An unquantized layer has its weight");
+        let unquant = UnquantLinear::new(QuantMethodConfig::Unquantized(Linear::new(w, bias)))?;
+        Arc::new(unquant).apply_isq(dtype, device, n_quantized, imatrix_weight, guard)
+    }
+
+    fn quant_info(&self) -> QuantInfo {
+        // The bit width varies between groups.
+        QuantInfo {
+            method: self.name(),
+            bits: None,
+            group_size: Some(self.group_size),
+        }
+    }
+}
+
+impl QuantizedSerde for Exl2Layer {
+    fn name(&self) -> &'static str {
+        "exl2"
+    }
+}
+
+impl Exl2Layer {
+    pub fn exl2_linear_b(
+        in_dim: usize,
+        out_dim: usize,
+        bias: bool,
+        vb: ShardedVarBuilder,
+    ) -> Result<Arc<dyn QuantMethod>> {
+        // Handle the case where the layer is dummy (no tensors)
+        if !(vb.contains_tensor("q_weight")
+            && vb.contains_tensor("q_scale")
+            && vb.contains_tensor("q_scale_max")
+            && vb.contains_tensor("q_groups")
+            && vb.contains_tensor("q_invperm"))
+        {
+            let layer = <DummyLayer as QuantMethod>::new(QuantMethodConfig::Dummy)?;
+            return Ok(Arc::new(layer) as Arc<dyn QuantMethod>);
+        }
+
+        let q_weight = vb.get_unchecked_dtype("q_weight", DType::I32)?;
+        let q_scale = vb.get_unchecked_dtype("q_scale", DType::I32)?;
+        let q_scale_max = vb.get_unchecked_dtype("q_scale_max", DType::F16)?;
+        let q_groups = vb.get_unchecked_dtype("q_groups", DType::I16)?;
+        let q_invperm =
+            vb.get_with_hints_dtype((in_dim,), "q_invperm", Default::default(), DType::I32)?;
+
+        if q_weight.rank() != 2 || q_weight.dim(1)? != out_dim {
+            candle_core::bail!(
+                "Expected EXL2 `q_weight` with {out_dim} columns, got shape {:?}.",
+                q_weight.dims()
+            );
+        }
+
+        let bias = if bias {
+            Some(vb.get_with_hints_dtype((out_dim,), "bias", Default::default(), DType::F16)?)
+        } else {
+            None
+        };
+
+        Ok(Arc::new(Self::new(QuantMethodConfig::Exl2 {
+            q_weight,
+            q_scale,
+            q_scale_max,
+            q_groups,
+            q_invperm,
+            bias,
+        })?))
+    }
+}
+
+/// Number of input rows in each EXL2 group: the smallest power of two covering all rows.
+fn exl2_group_size(in_dim: usize, groups: usize) -> usize {
+    let mut group_size = 1;
+    while group_size * groups < in_dim {
+        group_size *= 2;
+    }
+    group_size
+}
+
+/// Dequantize EXL2 tensors into an f32 weight of shape `(out_dim, in_dim)`, on the device of the inputs.
+///
+/// Within a group, each column holds its rows as a little-endian stream of `bits`-wide values, starting
+/// at the group's first packed row. A value `q` dequantizes to `(q - 2^(bits - 1)) * scale`, where the
+/// column's scale is `(qs + 1)^2 * scale_max / 256` for its 4-bit quantized scale `qs`.
+pub(crate) fn exl2_dequantize(
+    q_weight: &Tensor,
+    q_scale: &Tensor,
+    q_scale_max: &Tensor,
+    q_groups: &Tensor,
+    q_invperm: &Tensor,
+) -> Result<Tensor> {
+    let (packed_rows, out_dim) = q_weight.dims2()?;
+    let in_dim = q_invperm.dim(0)?;
+    let groups = q_scale_max.dim(0)?;
+    let group_size = exl2_group_size(in_dim, groups);
+
+    let q_weight = q_weight.flatten_all()?.to_vec1::<i32>()?;
+    let q_scale = q_scale.to_vec2::<i32>()?;
+    let scale_max = q_scale_max.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    let q_groups = q_groups.to_vec1::<i16>()?;
+    if q_groups.len() != 2 * groups {
+        candle_core::bail!(
+            "Expected {} EXL2 `q_groups` entries for {groups} groups, got {}.",
+            2 * groups,
+            q_groups.len()
+        );
+    }
+
+    let mut w = vec![0f32; in_dim * out_dim];
+    for g in 0..groups {
+        let bits = q_groups[2 * g] as usize;
+        let first_packed_row = q_groups[2 * g + 1] as u16 as usize;
+        if !EXL2_BITS.contains(&bits) {
+            candle_core::bail!("Unsupported EXL2 bit width {bits}, expected one of {EXL2_BITS:?}.");
+        }
+        let row_start = g * group_size;
+        let row_end = (row_start + group_size).min(in_dim);
+        if first_packed_row + ((row_end - row_start) * bits).div_ceil(32) > packed_rows {
+            candle_core::bail!("EXL2 group {g} reads past the end of `q_weight`.");
+        }
+
+        let zero = 1i32 << (bits - 1);
+        let mask = (1u64 << bits) - 1;
+        let max_scale = scale_max[g] / 256.;
+        for col in 0..out_dim {
+            let qs = (q_scale[g][col / 8] as u32 >> ((col % 8) * 4)) & 0xf;
+            let scale = ((qs + 1) * (qs + 1)) as f32 * max_scale;
+
+            let mut buffer = 0u64;
+            let mut buffered_bits = 0;
+            let mut packed_row = first_packed_row;
+            for row in row_start..row_end {
+                if buffered_bits < bits {
+                    buffer |= (q_weight[packed_row * out_dim + col] as u32 as u64) << buffered_bits;
+                    buffered_bits += 32;
+                    packed_row += 1;
+                }
+                let q = (buffer & mask) as i32;
+                buffer >>= bits;
+                buffered_bits -= bits;
+                w[row * out_dim + col] = (q - zero) as f32 * scale;
+            }
+        }
+    }
+
+    // Undo the row permutation, rows of the original weight are `invperm` rows of the packed one.
+    let device = q_invperm.device();
+    let w = Tensor::from_vec(w, (in_dim, out_dim), &Device::Cpu)?;
+    let invperm = q_invperm
+        .to_vec1::<i32>()?
+        .into_iter()
+        .map(|i| i as u32)
+        .collect::<Vec<_>>();
+    let invperm = Tensor::from_vec(invperm, in_dim, &Device::Cpu)?;
+    w.index_select(&invperm, 0)?.t()?.to_device(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use super::{exl2_dequantize, exl2_group_size};
+
+    /// Pack the rows of one column as a little-endian stream of `bits`-wide values.
+    fn pack_column(values: &[u32], bits: usize) -> Vec<u32> {
+        let mut packed = Vec::new();
+        let (mut buffer, mut buffered_bits) = (0u64, 0);
+        for v in values {
+            buffer |= (*v as u64) << buffered_bits;
+            buffered_bits += bits;
+            if buffered_bits >= 32 {
+                packed.push(buffer as u32);
+                buffer >>= 32;
+                buffered_bits -= 32;
+            }
+        }
+        if buffered_bits > 0 {
+            packed.push(buffer as u32);
+        }
+        packed
+    }
+
+    /// A 4-bit and a 3-bit group of 32 rows, packed as exllamav2's `pack_columns` writes them:
+    /// every 32 rows of a column become `bits` words, with row `i` at bit `i * bits` of their
+    /// little-endian concatenation, so 3-bit rows 10 and 21 straddle two words.
+    #[test]
+    fn dequantize_exllamav2_layout() -> Result<()> {
+        let dev = Device::Cpu;
+        let column = [
+            // 4-bit: rows 0..16 are 0..16, rows 16..32 are 7..0 and 15..8.
+            0x7654_3210u32,
+            0xfedc_ba98,
+            0x0123_4567,
+            0x89ab_cdef,
+            // 3-bit: row i is 7 - i % 8.
+            0x7705_3977,
+            0x3977_0539,
+            0x0539_7705,
+        ];
+        let (in_dim, out_dim) = (64, 8);
+        let q_weight = column
+            .iter()
+            .flat_map(|word| [*word as i32; 8])
+            .collect::<Vec<_>>();
+
+        // Scales of `(0 + 1)^2 * 256 / 256 = 1` and the identity permutation.
+        let w = exl2_dequantize(
+            &Tensor::from_vec(q_weight, (column.len(), out_dim), &dev)?,
+            &Tensor::zeros((2, 1), DType::I32, &dev)?,
+            &Tensor::new(&[256f32, 256.], &dev)?,
+            &Tensor::new(&[4i16, 0, 3, 4], &dev)?,
+            &Tensor::arange(0i32, in_dim as i32, &dev)?,
+        )?;
+
+        let four_bit = (0..16).chain((0..8).rev()).chain((8..16).rev());
+        let three_bit = (0..32).map(|i| 7 - i % 8);
+        let expected = four_bit
+            .map(|q| q as f32 - 8.)
+            .chain(three_bit.map(|q| q as f32 - 4.))
+            .collect::<Vec<_>>();
+        for row in w.to_vec2::<f32>()? {
+            assert_eq!(row, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dequantize_matches_reference() -> Result<()> {
+        let dev = Device::Cpu;
+        // xorshift, returns a value in `0..n`
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = |n: u32| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as u32
+        };
+        let (in_dim, out_dim) = (96, 16);
+        let group_bits = [8usize, 5, 3];
+        let groups = group_bits.len();
+        let group_size = exl2_group_size(in_dim, groups);
+        assert_eq!(group_size, 32);
+
+        // Packed row order -> original row.
+        let mut perm = (0..in_dim as u32).collect::<Vec<_>>();
+        for i in (1..in_dim).rev() {
+            perm.swap(i, next(i as u32 + 1) as usize);
+        }
+        let mut invperm = vec![0i32; in_dim];
+        for (i, p) in perm.iter().enumerate() {
+            invperm[*p as usize] = i as i32;
+        }
+
+        let scale_max = [0.5f32, 0.25, 1.0];
+        let q_scale_vals = (0..groups)
+            .map(|_| (0..out_dim).map(|_| next(16)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let mut reference = vec![0f32; out_dim * in_dim];
+        let mut packed_groups = Vec::new();
+        let mut q_groups = Vec::new();
+        let mut packed_row = 0;
+        for (g, bits) in group_bits.iter().enumerate() {
+            q_groups.extend([*bits as i16, packed_row as i16]);
+            let mut columns = Vec::new();
+            for col in 0..out_dim {
+                let qs = q_scale_vals[g][col];
+                let scale = ((qs + 1) * (qs + 1)) as f32 * scale_max[g] / 256.;
+                let values = (0..group_size)
+                    .map(|r| {
+                        let q = next(1 << bits);
+                        let row = perm[g * group_size + r] as usize;
+                        reference[col * in_dim + row] =
+                            (q as i32 - (1 << (bits - 1))) as f32 * scale;
+                        q
+                    })
+                    .collect::<Vec<_>>();
+                columns.push(pack_column(&values, *bits));
+            }
+            packed_row += columns[0].len();
+            packed_groups.push(columns);
+        }
+
+        // Lay the packed words out as (packed rows, out_dim).
+        let mut q_weight = Vec::new();
+        for columns in &packed_groups {
+            for word in 0..columns[0].len() {
+                q_weight.extend(columns.iter().map(|c| c[word] as i32));
+            }
+        }
+        let q_scale = q_scale_vals
+            .iter()
+            .flat_map(|g| {
+                g.chunks(8).map(|c| {
+                    c.iter()
+                        .enumerate()
+                        .fold(0u32, |acc, (i, qs)| acc | (qs << (i * 4))) as i32
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let w = exl2_dequantize(
+            &Tensor::from_vec(q_weight, (packed_row, out_dim), &dev)?,
+            &Tensor::from_vec(q_scale, (groups, out_dim / 8), &dev)?,
+            &Tensor::new(&scale_max, &dev)?,
+            &Tensor::new(q_groups.as_slice(), &dev)?,
+            &Tensor::new(invperm.as_slice(), &dev)?,
+        )?;
+        assert_eq!(w.dims(), &[out_dim, in_dim]);
+
+        let w = w.flatten_all()?.to_vec1::<f32>()?;
+        for (x, y) in w.iter().zip(&reference) {
+            assert!((x - y).abs() < 1e-6, "{x} != {y}");
+        }
+        Ok(())
+    }
+}
@@ -17,7 +17,8 @@ use crate::{
         deserialize_tensor, read_dtype, serialize_tensor, version_is_compatible, write_dtype,
         UQFF_VERSION,
     },
-    IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde, QuantizedSerdeType,
+    IsqType, QuantInfo, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde,
+    QuantizedSerdeType,
 };
 
 #[derive(Debug)]
@@ -43,7 +44,8 @@ impl QuantMethod for FP8Linear {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
-            | QuantMethodConfig::Afq { .. } => unreachable!(),
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Exl2 { .. } => unreachable!(),
             QuantMethodConfig::FP8 { lin, dtype } => {
                 let QuantizationResult {
                     qw,
@@ -157,6 +159,15 @@ impl QuantMethod for FP8Linear {
     ) -> Result<Arc<dyn QuantMethod>> {
         todo!()
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        // A single scale for the whole tensor.
+        QuantInfo {
+            method: self.name(),
+            bits: Some(8),
+            group_size: None,
+        }
+    }
 }
 
 // Serialization structure:
@@ -14,8 +14,8 @@ use candle_nn::{Linear, Module};
 use crate::{
     generate_isq, generate_isq_imatrix,
     utils::{deserialize_tensor, serialize_tensor, version_is_compatible, UQFF_VERSION},
-    IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde, QuantizedSerdeType,
-    UnquantLinear,
+    IsqType, QuantInfo, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde,
+    QuantizedSerdeType, UnquantLinear,
 };
 
 #[derive(Debug)]
@@ -41,7 +41,8 @@ impl QuantMethod for GgufMatMul {
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
-            | QuantMethodConfig::Afq { .. } => unreachable!(),
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Exl2 { .. } => unreachable!(),
         }
     }
 
@@ -154,6 +155,31 @@ impl QuantMethod for GgufMatMul {
             Ok(Arc::new(GgufMatMul { w, b }))
         }
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        let (bits, group_size) = match &self.w {
+            QMatMul::QTensor(q) => {
+                let bits = match q.dtype() {
+                    GgmlDType::Q2K => 2,
+                    GgmlDType::Q3K => 3,
+                    GgmlDType::Q4_0 | GgmlDType::Q4_1 | GgmlDType::Q4K => 4,
+                    GgmlDType::Q5_0 | GgmlDType::Q5_1 | GgmlDType::Q5K => 5,
+                    GgmlDType::Q6K => 6,
+                    GgmlDType::Q8_0 | GgmlDType::Q8_1 | GgmlDType::Q8K => 8,
+                    GgmlDType::F16 | GgmlDType::BF16 => 16,
+                    GgmlDType::F32 => 32,
+                };
+                let block_size = q.dtype().block_size();
+                (bits, (block_size > 1).then_some(block_size))
+            }
+            QMatMul::Tensor(w) | QMatMul::TensorF16(w) => (w.dtype().size_in_bytes() * 8, None),
+        };
+        QuantInfo {
+            method: self.name(),
+            bits: Some(bits),
+            group_size,
+        }
+    }
 }
 
 // Serialization structure:
@@ -19,8 +19,8 @@ use lazy_static::lazy_static;
 use crate::{
     gptq::marlin_backend::{gptq_marlin_matmul, gptq_weight_repack},
     utils::{get_cuda_device, get_cuda_slice},
-    DummyLayer, IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedConfig,
-    QuantizedSerde, ShardedVarBuilder,
+    DummyLayer, IsqType, QuantInfo, QuantMethod, QuantMethodConfig, QuantizeOntoGuard,
+    QuantizedConfig, QuantizedSerde, ShardedVarBuilder,
 };
 
 use super::{
@@ -263,7 +263,8 @@ impl QuantMethod for GptqLayer {
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
-            | QuantMethodConfig::Afq { .. } => {
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Exl2 { .. } => {
                 unreachable!()
             }
         }
@@ -341,6 +342,14 @@ impl QuantMethod for GptqLayer {
     ) -> Result<Arc<dyn QuantMethod>> {
         candle_core::bail!("GPTQ quantization does not support ISQ.")
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        QuantInfo {
+            method: self.name(),
+            bits: Some(self.bits as usize),
+            group_size: None,
+        }
+    }
 }
 
 impl QuantizedSerde for GptqLayer {
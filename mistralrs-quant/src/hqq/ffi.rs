@@ -49,6 +49,18 @@ pub mod three_bit {
     }
 }
 
+pub mod five_bit {
+    use half::{bf16, f16};
+    use paste::paste;
+
+    #[allow(dead_code)]
+    extern "C" {
+        dequant_kernel!(i32, f32, 5bit_32_kernel_f32);
+        dequant_kernel!(i32, f16, 5bit_32_kernel_f16);
+        dequant_kernel!(i32, bf16, 5bit_32_kernel_bf16);
+    }
+}
+
 pub mod two_bit {
     use half::{bf16, f16};
     use paste::paste;
@@ -1,6 +1,200 @@
+use std::num::NonZeroUsize;
+
 #[cfg(feature = "metal")]
-use candle_core::{backend::BackendStorage, DType};
-use candle_core::{CpuStorage, CustomOp3, Layout, Result, Shape, WithDType};
+use candle_core::backend::BackendStorage;
+use candle_core::{CpuStorage, CustomOp3, DType, Layout, Result, Shape, Tensor, WithDType};
+use rayon::{iter::IndexedParallelIterator, iter::ParallelIterator, slice::ParallelSliceMut};
+
+use super::{HqqAxis, HqqBits, HqqConfig, HqqLayer};
+
+/// Launch the HQQ CUDA dequantization kernel matching the dtype of the scales and zeros. The output has shape
+/// `(pack * h, w)`, like the CPU path.
+#[cfg(feature = "cuda")]
+macro_rules! cuda_dequant {
+    (
+        $this:expr,
+        ($w:expr, $l_w:expr),
+        ($s:expr, $l_s:expr),
+        ($z:expr, $l_z:expr),
+        w = $wq_t:ty,
+        pack = $pack:expr,
+        $bits:ident,
+        f32 = $f32_kernel:tt,
+        f16 = $f16_kernel:tt,
+        bf16 = $bf16_kernel:tt $(,)?
+    ) => {{
+        use candle_core::{
+            backend::BackendStorage,
+            cuda::{cudarc::driver::DevicePtr, WrapErr},
+            CudaStorage,
+        };
+        use half::{bf16, f16};
+
+        if $w.dtype() != <$wq_t as WithDType>::DTYPE {
+            candle_core::bail!(
+                "Weight must be {:?}, HQQ dequant {}",
+                <$wq_t as WithDType>::DTYPE,
+                $this.name()
+            );
+        }
+        if !($l_w.is_contiguous() && $l_s.is_contiguous() && $l_z.is_contiguous()) {
+            candle_core::bail!("All inputs must be contiguous");
+        }
+        check_layouts($this.name(), ($this.h, $this.w), $l_w, $l_s, $l_z)?;
+        if $s.dtype() != $z.dtype() {
+            candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16");
+        }
+
+        let dev = $w.device();
+        let out_shape = Shape::from_dims(&[$pack * $this.h, $this.w]);
+        let w_ptr = *$w
+            .as_cuda_slice::<$wq_t>()?
+            .slice($l_w.start_offset()..)
+            .device_ptr() as *const $wq_t;
+
+        macro_rules! launch {
+            ($t:ty, $kernel:tt) => {{
+                let s_ptr = *$s
+                    .as_cuda_slice::<$t>()?
+                    .slice($l_s.start_offset()..)
+                    .device_ptr() as *const $t;
+                let z_ptr = *$z
+                    .as_cuda_slice::<$t>()?
+                    .slice($l_z.start_offset()..)
+                    .device_ptr() as *const $t;
+                let out = unsafe { dev.alloc::<$t>(out_shape.elem_count()).w()? };
+                let out_ptr = *out.device_ptr() as *mut $t;
+                paste::paste! {
+                    unsafe {
+                        crate::hqq::ffi::$bits::[< dequantize_ $kernel >](
+                            w_ptr,
+                            s_ptr,
+                            z_ptr,
+                            out_ptr,
+                            $this.h as i32,
+                            $this.w as i32,
+                        );
+                    }
+                }
+                CudaStorage::wrap_cuda_slice(out, dev.clone())
+            }};
+        }
+
+        let storage = match $s.dtype() {
+            DType::F32 => launch!(f32, $f32_kernel),
+            DType::F16 => launch!(f16, $f16_kernel),
+            DType::BF16 => launch!(bf16, $bf16_kernel),
+            _ => candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16"),
+        };
+        Ok((storage, out_shape))
+    }};
+}
+
+/// Check that the packed weight of an op with shape `(h, w)` has `h * w` elements and that the scales and zeros
+/// have one element per group, so that a mismatched layout (e.g. from a corrupted UQFF file) is an error instead
+/// of silently dequantizing the wrong values.
+fn check_layouts(
+    name: &str,
+    (h, w): (usize, usize),
+    l_w: &Layout,
+    l_s: &Layout,
+    l_z: &Layout,
+) -> Result<()> {
+    if l_w.shape().elem_count() != h * w {
+        candle_core::bail!(
+            "HQQ dequant {name} expected a packed weight of shape ({h}, {w}), got {:?}",
+            l_w.shape()
+        );
+    }
+    for (what, l) in [("scales", l_s), ("zeros", l_z)] {
+        if l.shape().elem_count() != w {
+            candle_core::bail!(
+                "HQQ dequant {name} expected {what} for {w} groups, got shape {:?}",
+                l.shape()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Dequantize packed weights of shape `(h, w)` where each packed value holds `n_planes` values: the `p`-th
+/// value of packed value `i` is written to `out[i + p * h * w]`.
+///
+/// Output rows are dequantized in parallel on the current rayon pool, unless it has a single thread. Every
+/// output element only depends on its own inputs, so the result is bit-identical for any thread count.
+fn dequantize_planes<W: Copy + Sync, T: WithDType + Default>(
+    w: &[W],
+    s: &[T],
+    z: &[T],
+    (h, width): (usize, usize),
+    n_planes: usize,
+    unpack: impl Fn(W, usize) -> f64 + Sync,
+) -> Vec<T> {
+    let plane_len = h * width;
+    let mut out = vec![T::default(); w.len() * n_planes];
+    let dequantize_row = |(row, out_row): (usize, &mut [T])| {
+        let (plane, start) = ((row * width) / plane_len, (row * width) % plane_len);
+        for (j, out) in out_row.iter_mut().enumerate() {
+            *out = (T::from_f64(unpack(w[start + j], plane)) - z[j]) * s[j];
+        }
+    };
+    if rayon::current_num_threads() > 1 {
+        out.par_chunks_mut(width)
+            .enumerate()
+            .for_each(dequantize_row);
+    } else {
+        out.chunks_mut(width).enumerate().for_each(dequantize_row);
+    }
+    out
+}
+
+/// Quantize `weight` with HQQ, returning the packed weight, scales and zero points in the layout consumed by
+/// the `Dequant*Bit` ops: groups of `group_size` are taken along axis 0, and the packed weight is `u8`, or
+/// `i32` for 3 and 5 bits (10 or 6 values per element, with the groups padded to a multiple of that).
+///
+/// Quantization runs on the device of `weight`, and the scales and zero points have its dtype.
+pub fn quantize_hqq(
+    weight: &Tensor,
+    bits: HqqBits,
+    group_size: NonZeroUsize,
+    optimization_steps: Option<usize>,
+) -> Result<(Tensor, Tensor, Tensor)> {
+    if !matches!(weight.dtype(), DType::F32 | DType::F16 | DType::BF16) {
+        candle_core::bail!(
+            "HQQ quantization expects a f32, f16 or bf16 weight, got {:?}",
+            weight.dtype()
+        );
+    }
+    let pack = match bits {
+        HqqBits::Eight | HqqBits::Five | HqqBits::Three => 1,
+        HqqBits::Four => 2,
+        HqqBits::Two => 4,
+        HqqBits::One => 8,
+    };
+    if group_size.get() % pack != 0 {
+        candle_core::bail!(
+            "HQQ {}-bit quantization needs a group size divisible by {pack}, got {group_size}",
+            bits as usize
+        );
+    }
+    let layer = HqqLayer::quantize(
+        weight,
+        weight.device(),
+        HqqConfig {
+            bits,
+            group_size,
+            axis: HqqAxis::Zero,
+            optimization_steps,
+            round_zeros: false,
+            channel_wise: true,
+        },
+    )?;
+    Ok((
+        layer.w_q,
+        layer.scales.to_dtype(weight.dtype())?,
+        layer.zeros.to_dtype(weight.dtype())?,
+    ))
+}
 
 /*
  8 bit
@@ -12,12 +206,7 @@ pub(crate) struct Dequant8Bit {
 
 impl Dequant8Bit {
     fn dequantize<T: WithDType + Default>(&self, w: &[u8], s: &[T], z: &[T]) -> Vec<T> {
-        let mut out = vec![T::default(); w.len()];
-        for (i, w) in w.iter().enumerate() {
-            let j = i % self.w;
-            out[i] = (T::from_f64(*w as f64) - z[j]) * s[j];
-        }
-        out
+        dequantize_planes(w, s, z, (self.h, self.w), 1, |w, _| w as f64)
     }
 }
 
@@ -40,6 +229,7 @@ impl CustomOp3 for Dequant8Bit {
         if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
             candle_core::bail!("All inputs must be contiguous");
         }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
         match (s, z) {
             (CpuStorage::F32(s_slice), CpuStorage::F32(z_slice)) => Ok((
                 CpuStorage::F32(self.dequantize(w_slice, s_slice, z_slice)),
@@ -56,6 +246,29 @@ impl CustomOp3 for Dequant8Bit {
             (_, _) => candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16"),
         }
     }
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        w: &candle_core::CudaStorage,
+        l_w: &Layout,
+        s: &candle_core::CudaStorage,
+        l_s: &Layout,
+        z: &candle_core::CudaStorage,
+        l_z: &Layout,
+    ) -> Result<(candle_core::CudaStorage, Shape)> {
+        cuda_dequant!(
+            self,
+            (w, l_w),
+            (s, l_s),
+            (z, l_z),
+            w = u8,
+            pack = 1,
+            eight_bit,
+            f32 = 8bit_u8_kernel_f32,
+            f16 = 8bit_u8_kernel_f16,
+            bf16 = 8bit_u8_kernel_bf16,
+        )
+    }
     #[cfg(feature = "metal")]
     fn metal_fwd(
         &self,
@@ -72,6 +285,7 @@ impl CustomOp3 for Dequant8Bit {
         if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
             candle_core::bail!("All inputs must be contiguous");
         }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
 
         let command_buffer = w.device().command_buffer()?;
         command_buffer.set_label("dequant-8bit");
@@ -116,15 +330,9 @@ pub(crate) struct Dequant4Bit {
 
 impl Dequant4Bit {
     fn dequantize<T: WithDType + Default>(&self, w: &[u8], s: &[T], z: &[T]) -> Vec<T> {
-        let output_size = w.len() * 2;
-        let mut out = vec![T::default(); output_size];
-        for (i, w) in w.iter().enumerate() {
-            let j = i % self.w;
-            let nrows = self.h * self.w;
-            out[i] = (T::from_f64(((*w & 0xF0) >> 4) as f64) - z[j]) * s[j];
-            out[i + nrows] = (T::from_f64((*w & 0x0F) as f64) - z[j]) * s[j];
-        }
-        out
+        dequantize_planes(w, s, z, (self.h, self.w), 2, |w, plane| {
+            ((w >> (4 - 4 * plane)) & 0x0F) as f64
+        })
     }
 }
 
@@ -149,6 +357,7 @@ impl CustomOp3 for Dequant4Bit {
         if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
             candle_core::bail!("All inputs must be contiguous");
         }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
         match (s, z) {
             (CpuStorage::F32(s_slice), CpuStorage::F32(z_slice)) => Ok((
                 CpuStorage::F32(self.dequantize(w_slice, s_slice, z_slice)),
@@ -165,6 +374,29 @@ impl CustomOp3 for Dequant4Bit {
             (_, _) => candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16"),
         }
     }
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        w: &candle_core::CudaStorage,
+        l_w: &Layout,
+        s: &candle_core::CudaStorage,
+        l_s: &Layout,
+        z: &candle_core::CudaStorage,
+        l_z: &Layout,
+    ) -> Result<(candle_core::CudaStorage, Shape)> {
+        cuda_dequant!(
+            self,
+            (w, l_w),
+            (s, l_s),
+            (z, l_z),
+            w = u8,
+            pack = 2,
+            four_bit,
+            f32 = 4bit_u8_kernel_f32,
+            f16 = 4bit_u8_kernel_f16,
+            bf16 = 4bit_u8_kernel_bf16,
+        )
+    }
     #[cfg(feature = "metal")]
     fn metal_fwd(
         &self,
@@ -183,6 +415,7 @@ impl CustomOp3 for Dequant4Bit {
         if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
             candle_core::bail!("All inputs must be contiguous");
         }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
 
         let command_buffer = w.device().command_buffer()?;
         command_buffer.set_label("dequant-4bit");
@@ -217,6 +450,106 @@ impl CustomOp3 for Dequant4Bit {
     }
 }
 
+/// Fused 4-bit dequantize and matmul, computing `x @ W^T` for the (`o`, `k`) weight described by
+/// `w_q`, `scales` and `zeros` without materializing it. `x` must be a contiguous (m, k) matrix.
+#[cfg(feature = "metal")]
+pub(crate) fn hqq_4bit_matmul_op(
+    x: &candle_core::Tensor,
+    w_q: &candle_core::Tensor,
+    scales: &candle_core::Tensor,
+    zeros: &candle_core::Tensor,
+    o: usize,
+) -> Result<candle_core::Tensor> {
+    use candle_core::{from_storage_no_op, MetalStorage, Storage};
+
+    if w_q.dtype() != DType::U8 {
+        candle_core::bail!("Weight must be u8, HQQ fused matmul 4-bit");
+    }
+    if x.dtype() != scales.dtype() || x.dtype() != zeros.dtype() {
+        candle_core::bail!(
+            "Expected input, scales and zeros to have the same dtype, got {:?}, {:?} and {:?}.",
+            x.dtype(),
+            scales.dtype(),
+            zeros.dtype()
+        );
+    }
+    if !(x.is_contiguous()
+        && w_q.is_contiguous()
+        && scales.is_contiguous()
+        && zeros.is_contiguous())
+    {
+        candle_core::bail!("All inputs must be contiguous");
+    }
+    let (m, k) = x.dims2()?;
+    let (h, w) = w_q.dims2()?;
+    if 2 * h * w != o * k {
+        candle_core::bail!(
+            "HQQ fused matmul got input with {k} features, but the weight has {} elements for {o} outputs.",
+            2 * h * w
+        );
+    }
+
+    let x_s = x.storage_and_layout().0;
+    let Storage::Metal(x_s) = &*x_s else {
+        candle_core::bail!("expected metal")
+    };
+    let wq_s = w_q.storage_and_layout().0;
+    let Storage::Metal(wq_s) = &*wq_s else {
+        candle_core::bail!("expected metal")
+    };
+    let s_s = scales.storage_and_layout().0;
+    let Storage::Metal(s_s) = &*s_s else {
+        candle_core::bail!("expected metal")
+    };
+    let z_s = zeros.storage_and_layout().0;
+    let Storage::Metal(z_s) = &*z_s else {
+        candle_core::bail!("expected metal")
+    };
+
+    let device = x_s.device();
+
+    let command_buffer = device.command_buffer()?;
+    command_buffer.set_label("hqq-4bit-matmul");
+
+    let out_shape = Shape::from_dims(&[m, o]);
+    let output = device.new_buffer(out_shape.elem_count(), x.dtype(), "hqq-4bit-matmul")?;
+
+    assert_eq!(w_q.layout().start_offset(), 0);
+    assert_eq!(scales.layout().start_offset(), 0);
+    assert_eq!(zeros.layout().start_offset(), 0);
+    crate::metal_kernels::call_hqq_4bit_matmul(
+        device.device(),
+        &command_buffer,
+        &crate::metal_kernels::Kernels::new(),
+        x.dtype(),
+        (
+            x_s.buffer(),
+            x.layout().start_offset() * x.dtype().size_in_bytes(),
+        ),
+        wq_s.buffer(),
+        s_s.buffer(),
+        z_s.buffer(),
+        h as u32,
+        w as u32,
+        m as u32,
+        k as u32,
+        o as u32,
+        &output,
+    )
+    .map_err(candle_core::Error::wrap)?;
+
+    Ok(from_storage_no_op(
+        Storage::Metal(MetalStorage::new(
+            output,
+            device.clone(),
+            out_shape.elem_count(),
+            x.dtype(),
+        )),
+        out_shape,
+        false,
+    ))
+}
+
 /*
  2 bit
 */
@@ -227,17 +560,9 @@ pub(crate) struct Dequant2Bit {
 
 impl Dequant2Bit {
     fn dequantize<T: WithDType + Default>(&self, w: &[u8], s: &[T], z: &[T]) -> Vec<T> {
-        let output_size = w.len() * 4;
-        let mut out = vec![T::default(); output_size];
-        for (i, w) in w.iter().enumerate() {
-            let j = i % self.w;
-            let nrows = self.h * self.w;
-            out[i] = (T::from_f64(((*w & 0xC0) >> 6) as f64) - z[j]) * s[j];
-            out[i + nrows] = (T::from_f64(((*w & 0x30) >> 4) as f64) - z[j]) * s[j];
-            out[i + nrows * 2] = (T::from_f64(((*w & 0x0C) >> 2) as f64) - z[j]) * s[j];
-            out[i + nrows * 3] = (T::from_f64((*w & 0x03) as f64) - z[j]) * s[j];
-        }
-        out
+        dequantize_planes(w, s, z, (self.h, self.w), 4, |w, plane| {
+            ((w >> (6 - 2 * plane)) & 0x03) as f64
+        })
     }
 }
 
@@ -262,6 +587,7 @@ impl CustomOp3 for Dequant2Bit {
         if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
             candle_core::bail!("All inputs must be contiguous");
         }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
         match (s, z) {
             (CpuStorage::F32(s_slice), CpuStorage::F32(z_slice)) => Ok((
                 CpuStorage::F32(self.dequantize(w_slice, s_slice, z_slice)),
@@ -278,6 +604,29 @@ impl CustomOp3 for Dequant2Bit {
             (_, _) => candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16"),
         }
     }
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        w: &candle_core::CudaStorage,
+        l_w: &Layout,
+        s: &candle_core::CudaStorage,
+        l_s: &Layout,
+        z: &candle_core::CudaStorage,
+        l_z: &Layout,
+    ) -> Result<(candle_core::CudaStorage, Shape)> {
+        cuda_dequant!(
+            self,
+            (w, l_w),
+            (s, l_s),
+            (z, l_z),
+            w = u8,
+            pack = 4,
+            two_bit,
+            f32 = 2bit_u8_kernel_f32,
+            f16 = 2bit_u8_kernel_f16,
+            bf16 = 2bit_u8_kernel_bf16,
+        )
+    }
     #[cfg(feature = "metal")]
     fn metal_fwd(
         &self,
@@ -296,6 +645,7 @@ impl CustomOp3 for Dequant2Bit {
         if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
             candle_core::bail!("All inputs must be contiguous");
         }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
 
         let command_buffer = w.device().command_buffer()?;
         command_buffer.set_label("dequant-2bit");
@@ -340,21 +690,9 @@ pub(crate) struct Dequant1Bit {
 
 impl Dequant1Bit {
     fn dequantize<T: WithDType + Default>(&self, w: &[u8], s: &[T], z: &[T]) -> Vec<T> {
-        let output_size = w.len() * 8;
-        let mut out = vec![T::default(); output_size];
-        for (i, w) in w.iter().enumerate() {
-            let j = i % self.w;
-            let nrows = self.h * self.w;
-            out[i] = (T::from_f64(((*w & 0x80) >> 7) as f64) - z[j]) * s[j];
-            out[i + nrows] = (T::from_f64(((*w & 0x40) >> 6) as f64) - z[j]) * s[j];
-            out[i + nrows * 2] = (T::from_f64(((*w & 0x20) >> 5) as f64) - z[j]) * s[j];
-            out[i + nrows * 3] = (T::from_f64(((*w & 0x10) >> 4) as f64) - z[j]) * s[j];
-            out[i + nrows * 4] = (T::from_f64(((*w & 0x08) >> 3) as f64) - z[j]) * s[j];
-            out[i + nrows * 5] = (T::from_f64(((*w & 0x04) >> 2) as f64) - z[j]) * s[j];
-            out[i + nrows * 6] = (T::from_f64(((*w & 0x02) >> 1) as f64) - z[j]) * s[j];
-            out[i + nrows * 7] = (T::from_f64((*w & 0x01) as f64) - z[j]) * s[j];
-        }
-        out
+        dequantize_planes(w, s, z, (self.h, self.w), 8, |w, plane| {
+            ((w >> (7 - plane)) & 0x01) as f64
+        })
     }
 }
 
@@ -379,6 +717,7 @@ impl CustomOp3 for Dequant1Bit {
         if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
             candle_core::bail!("All inputs must be contiguous");
         }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
         match (s, z) {
             (CpuStorage::F32(s_slice), CpuStorage::F32(z_slice)) => Ok((
                 CpuStorage::F32(self.dequantize(w_slice, s_slice, z_slice)),
@@ -395,6 +734,29 @@ impl CustomOp3 for Dequant1Bit {
             (_, _) => candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16"),
         }
     }
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        w: &candle_core::CudaStorage,
+        l_w: &Layout,
+        s: &candle_core::CudaStorage,
+        l_s: &Layout,
+        z: &candle_core::CudaStorage,
+        l_z: &Layout,
+    ) -> Result<(candle_core::CudaStorage, Shape)> {
+        cuda_dequant!(
+            self,
+            (w, l_w),
+            (s, l_s),
+            (z, l_z),
+            w = u8,
+            pack = 8,
+            one_bit,
+            f32 = 1bit_u8_kernel_f32,
+            f16 = 1bit_u8_kernel_f16,
+            bf16 = 1bit_u8_kernel_bf16,
+        )
+    }
     #[cfg(feature = "metal")]
     fn metal_fwd(
         &self,
@@ -413,6 +775,7 @@ impl CustomOp3 for Dequant1Bit {
         if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
             candle_core::bail!("All inputs must be contiguous");
         }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
 
         let command_buffer = w.device().command_buffer()?;
         command_buffer.set_label("dequant-1bit");
@@ -457,23 +820,9 @@ pub(crate) struct Dequant3Bit {
 
 impl Dequant3Bit {
     fn dequantize<T: WithDType + Default>(&self, w: &[i32], s: &[T], z: &[T]) -> Vec<T> {
-        let output_size = w.len() * 10;
-        let mut out = vec![T::default(); output_size];
-        for (i, w) in w.iter().enumerate() {
-            let j = i % self.w;
-            let nrows = self.h * self.w;
-            out[i] = (T::from_f64(((*w & 0x38000000) >> 27) as f64) - z[j]) * s[j];
-            out[i + nrows] = (T::from_f64(((*w & 0x07000000) >> 24) as f64) - z[j]) * s[j];
-            out[i + nrows * 2] = (T::from_f64(((*w & 0x00E00000) >> 21) as f64) - z[j]) * s[j];
-            out[i + nrows * 3] = (T::from_f64(((*w & 0x001C0000) >> 18) as f64) - z[j]) * s[j];
-            out[i + nrows * 4] = (T::from_f64(((*w & 0x00038000) >> 15) as f64) - z[j]) * s[j];
-            out[i + nrows * 5] = (T::from_f64(((*w & 0x00007000) >> 12) as f64) - z[j]) * s[j];
-            out[i + nrows * 6] = (T::from_f64(((*w & 0x00000E00) >> 9) as f64) - z[j]) * s[j];
-            out[i + nrows * 7] = (T::from_f64(((*w & 0x000001C0) >> 6) as f64) - z[j]) * s[j];
-            out[i + nrows * 8] = (T::from_f64(((*w & 0x00000038) >> 3) as f64) - z[j]) * s[j];
-            out[i + nrows * 9] = (T::from_f64((*w & 0x00000007) as f64) - z[j]) * s[j];
-        }
-        out
+        dequantize_planes(w, s, z, (self.h, self.w), 10, |w, plane| {
+            ((w >> (27 - 3 * plane)) & 0x07) as f64
+        })
     }
 }
 
@@ -498,6 +847,7 @@ impl CustomOp3 for Dequant3Bit {
         if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
             candle_core::bail!("All inputs must be contiguous");
         }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
         match (s, z) {
             (CpuStorage::F32(s_slice), CpuStorage::F32(z_slice)) => Ok((
                 CpuStorage::F32(self.dequantize(w_slice, s_slice, z_slice)),
@@ -514,6 +864,29 @@ impl CustomOp3 for Dequant3Bit {
             (_, _) => candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16"),
         }
     }
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        w: &candle_core::CudaStorage,
+        l_w: &Layout,
+        s: &candle_core::CudaStorage,
+        l_s: &Layout,
+        z: &candle_core::CudaStorage,
+        l_z: &Layout,
+    ) -> Result<(candle_core::CudaStorage, Shape)> {
+        cuda_dequant!(
+            self,
+            (w, l_w),
+            (s, l_s),
+            (z, l_z),
+            w = i32,
+            pack = 10,
+            three_bit,
+            f32 = 3bit_32_kernel_f32,
+            f16 = 3bit_32_kernel_f16,
+            bf16 = 3bit_32_kernel_bf16,
+        )
+    }
     #[cfg(feature = "metal")]
     fn metal_fwd(
         &self,
@@ -532,6 +905,7 @@ impl CustomOp3 for Dequant3Bit {
         if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
             candle_core::bail!("All inputs must be contiguous");
         }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
 
         let command_buffer = w.device().command_buffer()?;
         command_buffer.set_label("dequant-3bit");
@@ -565,3 +939,404 @@ impl CustomOp3 for Dequant3Bit {
         Ok((newstorage, out_shape))
     }
 }
+
+/*
+ 5 bit
+*/
+/// Dequantize 5-bit HQQ weights packed 6 values per `i32`. For packed weights of shape `(h, w)`, the output
+/// has shape `(6 * h, w)` and value `p` of packed element `i` lands in output row `p * h + i / w`. Value `p` is
+/// stored in bits `25 - 5 * p .. 30 - 5 * p` (the first value in the highest bits), and the top two bits are
+/// unused. Groups whose size is not a multiple of 6 are padded with zero rows before packing.
+pub(crate) struct Dequant5Bit {
+    pub(crate) h: usize,
+    pub(crate) w: usize,
+}
+
+impl Dequant5Bit {
+    fn dequantize<T: WithDType + Default>(&self, w: &[i32], s: &[T], z: &[T]) -> Vec<T> {
+        dequantize_planes(w, s, z, (self.h, self.w), 6, |w, plane| {
+            ((w >> (25 - 5 * plane)) & 0x1F) as f64
+        })
+    }
+}
+
+impl CustomOp3 for Dequant5Bit {
+    fn name(&self) -> &'static str {
+        "dequant-hqq-5bit"
+    }
+    fn cpu_fwd(
+        &self,
+        w: &CpuStorage,
+        l_w: &Layout,
+        s: &CpuStorage,
+        l_s: &Layout,
+        z: &CpuStorage,
+        l_z: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        const PACK_FACTOR: usize = 6;
+
+        let CpuStorage::I32(w_slice) = w else {
+            candle_core::bail!("Weight must be i32, HQQ dequant 5-bit");
+        };
+        if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
+            candle_core::bail!("All inputs must be contiguous");
+        }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
+        match (s, z) {
+            (CpuStorage::F32(s_slice), CpuStorage::F32(z_slice)) => Ok((
+                CpuStorage::F32(self.dequantize(w_slice, s_slice, z_slice)),
+                Shape::from_dims(&[PACK_FACTOR * self.h, self.w]),
+            )),
+            (CpuStorage::F16(s_slice), CpuStorage::F16(z_slice)) => Ok((
+                CpuStorage::F16(self.dequantize(w_slice, s_slice, z_slice)),
+                Shape::from_dims(&[PACK_FACTOR * self.h, self.w]),
+            )),
+            (CpuStorage::BF16(s_slice), CpuStorage::BF16(z_slice)) => Ok((
+                CpuStorage::BF16(self.dequantize(w_slice, s_slice, z_slice)),
+                Shape::from_dims(&[PACK_FACTOR * self.h, self.w]),
+            )),
+            (_, _) => candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16"),
+        }
+    }
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        w: &candle_core::CudaStorage,
+        l_w: &Layout,
+        s: &candle_core::CudaStorage,
+        l_s: &Layout,
+        z: &candle_core::CudaStorage,
+        l_z: &Layout,
+    ) -> Result<(candle_core::CudaStorage, Shape)> {
+        cuda_dequant!(
+            self,
+            (w, l_w),
+            (s, l_s),
+            (z, l_z),
+            w = i32,
+            pack = 6,
+            five_bit,
+            f32 = 5bit_32_kernel_f32,
+            f16 = 5bit_32_kernel_f16,
+            bf16 = 5bit_32_kernel_bf16,
+        )
+    }
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        w: &candle_core::MetalStorage,
+        l_w: &Layout,
+        s: &candle_core::MetalStorage,
+        l_s: &Layout,
+        z: &candle_core::MetalStorage,
+        l_z: &Layout,
+    ) -> Result<(candle_core::MetalStorage, Shape)> {
+        const PACK_FACTOR: usize = 6;
+
+        if w.dtype() != DType::I32 {
+            candle_core::bail!("Weight must be i32, HQQ dequant 5-bit");
+        };
+        if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
+            candle_core::bail!("All inputs must be contiguous");
+        }
+        check_layouts(self.name(), (self.h, self.w), l_w, l_s, l_z)?;
+
+        let command_buffer = w.device().command_buffer()?;
+        command_buffer.set_label("dequant-5bit");
+
+        let device = w.device();
+
+        let out_shape = Shape::from_dims(&[PACK_FACTOR * self.h, self.w]);
+
+        let output = device.new_buffer(out_shape.elem_count(), s.dtype(), "dequant-5bit")?;
+
+        crate::metal_kernels::call_dequant_5bit(
+            device.device(),
+            &command_buffer,
+            &crate::metal_kernels::Kernels::new(),
+            s.dtype(),
+            w.buffer(),
+            s.buffer(),
+            z.buffer(),
+            self.h as u32,
+            self.w as u32,
+            &output,
+        )
+        .map_err(candle_core::Error::wrap)?;
+
+        let newstorage = candle_core::MetalStorage::new(
+            output,
+            device.clone(),
+            out_shape.elem_count(),
+            s.dtype(),
+        );
+        Ok((newstorage, out_shape))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "metal")]
+    use candle_core::{Device, Result, Tensor};
+
+    #[cfg(feature = "metal")]
+    use crate::{HqqAxis, HqqBits, HqqConfig, HqqLayer, QuantMethod};
+
+    #[test]
+    fn parallel_dequant_is_bit_identical_across_thread_counts() {
+        use super::{Dequant1Bit, Dequant2Bit, Dequant3Bit, Dequant4Bit, Dequant5Bit, Dequant8Bit};
+
+        let (h, w) = (37, 24);
+        // A simple LCG, so that the inputs are reproducible and cover every packed bit.
+        let mut state = 0x2545F491u32;
+        let mut next = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            state
+        };
+        let packed_u8 = (0..h * w).map(|_| (next() >> 24) as u8).collect::<Vec<_>>();
+        let packed_i32 = (0..h * w).map(|_| next() as i32).collect::<Vec<_>>();
+        let scales = (0..w)
+            .map(|_| next() as f32 / u32::MAX as f32)
+            .collect::<Vec<_>>();
+        let zeros = (0..w)
+            .map(|_| next() as f32 / u32::MAX as f32 * 4.)
+            .collect::<Vec<_>>();
+
+        let dequant_all = || {
+            vec![
+                Dequant8Bit { h, w }.dequantize(&packed_u8, &scales, &zeros),
+                Dequant5Bit { h, w }.dequantize(&packed_i32, &scales, &zeros),
+                Dequant4Bit { h, w }.dequantize(&packed_u8, &scales, &zeros),
+                Dequant3Bit { h, w }.dequantize(&packed_i32, &scales, &zeros),
+                Dequant2Bit { h, w }.dequantize(&packed_u8, &scales, &zeros),
+                Dequant1Bit { h, w }.dequantize(&packed_u8, &scales, &zeros),
+            ]
+        };
+        let in_pool = |n_threads: usize| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(n_threads)
+                .build()
+                .unwrap()
+                .install(dequant_all)
+        };
+        let to_bits = |outs: Vec<Vec<f32>>| {
+            outs.into_iter()
+                .map(|out| out.into_iter().map(f32::to_bits).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        let serial = to_bits(in_pool(1));
+        // Spot check the serial path against the packing: the last 4-bit plane is the low nibble.
+        assert_eq!(
+            serial[2][h * w + 5],
+            ((f32::from(packed_u8[5] & 0x0F) - zeros[5]) * scales[5]).to_bits()
+        );
+        // The first 5-bit plane is bits 25..30 and the last is bits 0..5.
+        assert_eq!(
+            serial[1][5],
+            ((((packed_i32[5] >> 25) & 0x1F) as f32 - zeros[5]) * scales[5]).to_bits()
+        );
+        assert_eq!(
+            serial[1][5 * h * w + 5],
+            (((packed_i32[5] & 0x1F) as f32 - zeros[5]) * scales[5]).to_bits()
+        );
+        for n_threads in [2, 8] {
+            assert_eq!(to_bits(in_pool(n_threads)), serial, "{n_threads} threads");
+        }
+    }
+
+    #[test]
+    fn mismatched_layouts_are_rejected() -> candle_core::Result<()> {
+        use candle_core::{DType, Device, Tensor};
+
+        use super::Dequant4Bit;
+
+        let dev = Device::Cpu;
+        let (h, w) = (4, 6);
+        let w_q = Tensor::zeros((h, w), DType::U8, &dev)?;
+        let scales = Tensor::ones((1, w), DType::F32, &dev)?;
+        let zeros = Tensor::zeros((1, w), DType::F32, &dev)?;
+        let op = Dequant4Bit { h, w };
+        assert_eq!(
+            w_q.apply_op3_no_bwd(&scales, &zeros, &op)?.dims(),
+            &[2 * h, w]
+        );
+
+        let short_w_q = w_q.narrow(0, 0, h - 1)?.contiguous()?;
+        let err = short_w_q
+            .apply_op3_no_bwd(&scales, &zeros, &op)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("packed weight of shape (4, 6)"), "{err}");
+
+        let short_zeros = zeros.narrow(1, 0, w - 1)?.contiguous()?;
+        let err = w_q
+            .apply_op3_no_bwd(&scales, &short_zeros, &op)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("zeros for 6 groups"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn quantize_dequantize_round_trip() -> candle_core::Result<()> {
+        use candle_core::{CustomOp3, DType, Device, Tensor};
+
+        use super::{
+            quantize_hqq, Dequant1Bit, Dequant2Bit, Dequant3Bit, Dequant4Bit, Dequant5Bit,
+            Dequant8Bit,
+        };
+        use crate::HqqBits;
+
+        let dev = Device::Cpu;
+        let group_size = 64;
+        let weight = Tensor::rand(0f32, 1f32, (32, 48), &dev)?;
+
+        for bits in [
+            HqqBits::Eight,
+            HqqBits::Five,
+            HqqBits::Four,
+            HqqBits::Three,
+            HqqBits::Two,
+            HqqBits::One,
+        ] {
+            for dtype in [DType::F32, DType::F16, DType::BF16] {
+                let (w_q, scales, zeros) =
+                    quantize_hqq(&weight.to_dtype(dtype)?, bits, group_size.try_into()?, None)?;
+                let expected_w_dtype = match bits {
+                    HqqBits::Five | HqqBits::Three => DType::I32,
+                    _ => DType::U8,
+                };
+                assert_eq!(w_q.dtype(), expected_w_dtype);
+                assert_eq!(scales.dtype(), dtype);
+                assert_eq!(zeros.dtype(), dtype);
+
+                let (h, w) = w_q.dims2()?;
+                let dequant = match bits {
+                    HqqBits::Eight => {
+                        w_q.apply_op3_no_bwd(&scales, &zeros, &Dequant8Bit { h, w })?
+                    }
+                    HqqBits::Five => {
+                        w_q.apply_op3_no_bwd(&scales, &zeros, &Dequant5Bit { h, w })?
+                    }
+                    HqqBits::Four => {
+                        w_q.apply_op3_no_bwd(&scales, &zeros, &Dequant4Bit { h, w })?
+                    }
+                    HqqBits::Three => {
+                        w_q.apply_op3_no_bwd(&scales, &zeros, &Dequant3Bit { h, w })?
+                    }
+                    HqqBits::Two => w_q.apply_op3_no_bwd(&scales, &zeros, &Dequant2Bit { h, w })?,
+                    HqqBits::One => w_q.apply_op3_no_bwd(&scales, &zeros, &Dequant1Bit { h, w })?,
+                };
+                let dequant = dequant
+                    .narrow(0, 0, group_size)?
+                    .reshape(weight.shape())?
+                    .to_dtype(DType::F32)?;
+
+                // Rounding to the nearest of `2^bits` levels over each group's range of at most 1.
+                let step = 1. / ((1 << bits as usize) - 1) as f32;
+                let mean_err = (dequant - &weight)?.abs()?.mean_all()?.to_scalar::<f32>()?;
+                assert!(
+                    mean_err <= step / 2. + 1e-2,
+                    "{} bits, {dtype:?}: mean error {mean_err}",
+                    bits as usize
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn cuda_dequant_matches_cpu() -> candle_core::Result<()> {
+        use super::{Dequant1Bit, Dequant2Bit, Dequant3Bit, Dequant4Bit, Dequant5Bit, Dequant8Bit};
+        use candle_core::{CustomOp3, DType, Device, Tensor};
+
+        let (h, w) = (37, 24);
+        let cuda = Device::new_cuda(0)?;
+        let packed_u8 = Tensor::rand(0f32, 256f32, (h, w), &Device::Cpu)?.to_dtype(DType::U8)?;
+        let packed_i32 =
+            Tensor::rand(-1e9f32, 1e9f32, (h, w), &Device::Cpu)?.to_dtype(DType::I32)?;
+        let scales = Tensor::rand(0f32, 1f32, (1, w), &Device::Cpu)?;
+        let zeros = Tensor::rand(0f32, 4f32, (1, w), &Device::Cpu)?;
+
+        fn check<C: CustomOp3>(
+            packed: &Tensor,
+            op: &C,
+            (scales, zeros): (&Tensor, &Tensor),
+            dtype: DType,
+            cuda: &Device,
+        ) -> candle_core::Result<()> {
+            let (s, z) = (scales.to_dtype(dtype)?, zeros.to_dtype(dtype)?);
+            let cpu = packed.apply_op3_no_bwd(&s, &z, op)?;
+            let gpu = packed.to_device(cuda)?.apply_op3_no_bwd(
+                &s.to_device(cuda)?,
+                &z.to_device(cuda)?,
+                op,
+            )?;
+            assert_eq!(gpu.dims(), cpu.dims(), "{} {dtype:?}", op.name());
+            let max_diff = (cpu.to_dtype(DType::F32)?
+                - gpu.to_device(&Device::Cpu)?.to_dtype(DType::F32)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+            // The 8-bit outputs reach ~256, so allow for one unit in the last place of the half types.
+            let tol = match dtype {
+                DType::F32 => 1e-4,
+                DType::F16 => 0.25,
+                _ => 2.,
+            };
+            assert!(max_diff <= tol, "{} {dtype:?}: {max_diff}", op.name());
+            Ok(())
+        }
+
+        let sz = (&scales, &zeros);
+        for dtype in [DType::F32, DType::F16, DType::BF16] {
+            check(&packed_u8, &Dequant8Bit { h, w }, sz, dtype, &cuda)?;
+            check(&packed_i32, &Dequant5Bit { h, w }, sz, dtype, &cuda)?;
+            check(&packed_u8, &Dequant4Bit { h, w }, sz, dtype, &cuda)?;
+            check(&packed_i32, &Dequant3Bit { h, w }, sz, dtype, &cuda)?;
+            check(&packed_u8, &Dequant2Bit { h, w }, sz, dtype, &cuda)?;
+            check(&packed_u8, &Dequant1Bit { h, w }, sz, dtype, &cuda)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "metal")]
+    #[test]
+    fn fused_4bit_matmul_matches_dequant_matmul() -> Result<()> {
+        let dev = Device::new_metal(0)?;
+
+        let weight = Tensor::randn(0f32, 1f32, (96, 128), &dev)?;
+        let hqq = HqqLayer::quantize(
+            &weight,
+            &dev,
+            HqqConfig {
+                bits: HqqBits::Four,
+                group_size: 64.try_into()?,
+                axis: HqqAxis::Zero,
+                optimization_steps: None,
+                round_zeros: false,
+                channel_wise: true,
+            },
+        )?;
+
+        // 6 rows take the fused kernel, 16 rows dequantize the weight first.
+        for (shape, fused) in [((2, 3, 128), true), ((2, 8, 128), false)] {
+            let xs = Tensor::randn(0f32, 1f32, shape, &dev)?;
+            assert_eq!(hqq.can_use_fused_matmul(&xs), fused, "{shape:?}");
+            let out = hqq.forward(&xs)?;
+            let reference = xs.broadcast_matmul(&hqq.dequantize_w()?.t()?)?;
+
+            assert_eq!(out.dims(), &[shape.0, shape.1, 96]);
+            let max_diff = (out - reference)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(max_diff < 1e-3, "{shape:?}: {max_diff}");
+        }
+        Ok(())
+    }
+}
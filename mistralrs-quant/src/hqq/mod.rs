@@ -1,15 +1,7 @@
 use byteorder::{LittleEndian, ReadBytesExt};
 use candle_core::{DType, Device, Result, Shape, Tensor};
 
-#[cfg(feature = "cuda")]
-use candle_core::{
-    cuda::{cudarc::driver::DevicePtr, CudaStorageSlice, WrapErr},
-    from_storage_no_op, CudaStorage, Storage,
-};
-
 use candle_nn::Linear;
-#[cfg(feature = "cuda")]
-use half::{bf16, f16};
 use std::{
     borrow::Cow,
     io::Cursor,
@@ -22,64 +14,25 @@ use crate::{
         deserialize_tensor, fake_deserialize_tensor, serialize_tensor, version_is_compatible,
         BitWiseOp, LeftshiftOp, UQFF_VERSION,
     },
-    IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde, QuantizedSerdeType,
-    UnquantLinear,
+    IsqType, QuantInfo, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde,
+    QuantizedSerdeType, UnquantLinear,
 };
 
-#[cfg(feature = "cuda")]
-use crate::utils::{get_cuda_device, get_cuda_slice};
-
-#[cfg(feature = "cuda")]
-use ffi::{eight_bit, four_bit, one_bit, three_bit, two_bit};
-
 #[cfg(feature = "cuda")]
 mod ffi;
 
-#[cfg(not(feature = "cuda"))]
 mod hqq_op;
 
+pub use hqq_op::quantize_hqq;
+
 mod optimize;
 mod quantize;
 
 pub(crate) const ISQ_HQQ_GROUP_SIZE: usize = 64;
 pub(crate) const ISQ_HQQ_DEFAULT_OPT_STEPS: Option<usize> = Some(10);
 pub(crate) const OPTIMIZER_HQQ_DEFAULT_STEPS: usize = 20;
-
-#[cfg(feature = "cuda")]
-macro_rules! dequant_for_dtype {
-    ($this:expr, w=$wq_t:ty, sz=$scale_t:ty, $dtype:ident, pack=$pack:expr, $dev:expr, $bit_thing:ident, $postfix:tt) => {{
-        paste::paste! {
-            let w_slice = get_cuda_slice::<$wq_t>(&$this.w_q)?;
-            let scale_slice = get_cuda_slice::<$scale_t>(&$this.scales)?;
-            let zero_slice = get_cuda_slice::<$scale_t>(&$this.zeros)?;
-
-            let (h, w) = $this.w_q.dims2()?;
-            let num_packed_elems = $pack;
-            let out_shape = Shape::from_dims(&[num_packed_elems * h, w]);
-
-            let out = unsafe { $dev.alloc::<$scale_t>(out_shape.elem_count()).w()? };
-            let out_ptr = *out.device_ptr() as *mut $scale_t;
-            unsafe {
-                $bit_thing::[< dequantize_ $postfix >](
-                    w_slice,
-                    scale_slice,
-                    zero_slice,
-                    out_ptr,
-                    h as i32,
-                    w as i32,
-                );
-            }
-
-            let storage = CudaStorage {
-                slice: CudaStorageSlice::$dtype(out),
-                device: $dev.clone(),
-            };
-            let storage = Storage::Cuda(storage);
-
-            from_storage_no_op(storage, out_shape, false)
-        }
-    }};
-}
+/// The most rows of the input for which the fused Metal 4-bit matmul is used.
+const FUSED_MATMUL_MAX_ROWS: usize = 8;
 
 #[derive(Debug, Clone, Copy)]
 pub enum HqqAxis {
@@ -101,6 +54,7 @@ impl TryFrom<usize> for HqqAxis {
 #[derive(Debug, Clone, Copy)]
 pub enum HqqBits {
     Eight = 8,
+    Five = 5,
     Four = 4,
     Three = 3,
     Two = 2,
@@ -112,6 +66,7 @@ impl TryFrom<usize> for HqqBits {
     fn try_from(value: usize) -> std::result::Result<Self, Self::Error> {
         match value {
             8 => Ok(Self::Eight),
+            5 => Ok(Self::Five),
             4 => Ok(Self::Four),
             3 => Ok(Self::Three),
             2 => Ok(Self::Two),
@@ -184,6 +139,36 @@ impl HqqBits {
                     .bitwise_or(&i.leftshift(3)?)?
                     .bitwise_or(&j)
             },
+            // Not in the reference implementation: 6 values per i32, the first in bits 25..30 and the last in
+            // bits 0..5. The top two bits are unused.
+            Self::Five => |wq_in: Tensor| {
+                let wq = Tensor::zeros(
+                    (
+                        (6. * (wq_in.dims()[0] as f64 / 6.).ceil()) as usize,
+                        wq_in.dims()[1],
+                    ),
+                    DType::U32,
+                    wq_in.device(),
+                )?;
+                let wq = wq
+                    .slice_assign(&[&(..wq_in.dims()[0]), &..], &wq_in.to_dtype(DType::U32)?)?
+                    .to_dtype(DType::I32)?;
+                let step = (wq.dims()[0] as f64 / 6.) as usize;
+
+                let a = wq.narrow(0, 0, step)?;
+                let b = wq.narrow(0, step, step)?;
+                let c = wq.narrow(0, step * 2, step)?;
+                let d = wq.narrow(0, step * 3, step)?;
+                let e = wq.narrow(0, step * 4, step)?;
+                let f = wq.narrow(0, step * 5, step)?;
+
+                a.leftshift(25)?
+                    .bitwise_or(&b.leftshift(20)?)?
+                    .bitwise_or(&c.leftshift(15)?)?
+                    .bitwise_or(&d.leftshift(10)?)?
+                    .bitwise_or(&e.leftshift(5)?)?
+                    .bitwise_or(&f)
+            },
             Self::One => |wq: Tensor| {
                 let wq = wq.to_dtype(DType::U8)?;
                 let step = (wq.dims()[0] as f64 / 8.) as usize;
@@ -231,10 +216,12 @@ pub struct HqqLayer {
 }
 
 impl HqqLayer {
-    /// Dequantize `self` into a tensor of shape `scales` or `zeros`.
-    #[cfg(not(feature = "cuda"))]
+    /// Dequantize `self` into a tensor of shape `scales` or `zeros`. The dequant ops launch the HQQ
+    /// kernels on CUDA and Metal.
     fn dequantize(&self) -> Result<Tensor> {
-        use crate::hqq::hqq_op::{Dequant1Bit, Dequant2Bit, Dequant3Bit, Dequant4Bit, Dequant8Bit};
+        use crate::hqq::hqq_op::{
+            Dequant1Bit, Dequant2Bit, Dequant3Bit, Dequant4Bit, Dequant5Bit, Dequant8Bit,
+        };
 
         match (self.scales.dtype(), self.zeros.dtype()) {
             (DType::F16, DType::F16) | (DType::BF16, DType::BF16) | (DType::F32, DType::F32) => (),
@@ -248,7 +235,7 @@ impl HqqLayer {
         }
         if self.cfg.axis as usize != 0 {
             candle_core::bail!(
-                "CPU HQQ dequantization requires axis == 0, got {}.",
+                "HQQ dequantization requires axis == 0, got {}.",
                 self.cfg.axis as usize
             );
         }
@@ -259,6 +246,11 @@ impl HqqLayer {
                 .w_q
                 .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant8Bit { h, w })?
                 .reshape(&self.w_shape),
+            5 => self
+                .w_q
+                .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant5Bit { h, w })?
+                .narrow(0, 0, self.cfg.group_size.into())?
+                .reshape(&self.w_shape),
             4 => self
                 .w_q
                 .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant4Bit { h, w })?
@@ -266,6 +258,7 @@ impl HqqLayer {
             3 => self
                 .w_q
                 .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant3Bit { h, w })?
+                .narrow(0, 0, self.cfg.group_size.into())?
                 .reshape(&self.w_shape),
             2 => self
                 .w_q
@@ -279,226 +272,6 @@ impl HqqLayer {
         }
     }
 
-    /// Dequantize `self` into a tensor of shape `scales` or `zeros`.
-    #[cfg(feature = "cuda")]
-    fn dequantize(&self) -> Result<Tensor> {
-        match (self.scales.dtype(), self.zeros.dtype()) {
-            (DType::F16, DType::F16) | (DType::BF16, DType::BF16) | (DType::F32, DType::F32) => (),
-            (a, b) => {
-                candle_core::bail!("Expected all dtypes to be the same, got ({a:?}, {b:?}).")
-            }
-        }
-        if !(self.w_q.is_contiguous() && self.scales.is_contiguous() && self.zeros.is_contiguous())
-        {
-            candle_core::bail!("All tensors must be contiguous!");
-        }
-        if self.cfg.axis as usize != 0 {
-            candle_core::bail!(
-                "CUDA HQQ dequantization requires axis == 0, got {}.",
-                self.cfg.axis as usize
-            );
-        }
-        let dev = get_cuda_device(&self.w_q)?;
-
-        let inner = match (self.cfg.bits as usize, self.scales.dtype()) {
-            // 8 bits
-            (8, DType::F32) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = f32,
-                    F32,
-                    pack = 1,
-                    dev,
-                    eight_bit,
-                    8bit_u8_kernel_f32
-                )
-            }
-            (8, DType::F16) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = f16,
-                    F16,
-                    pack = 1,
-                    dev,
-                    eight_bit,
-                    8bit_u8_kernel_f16
-                )
-            }
-            (8, DType::BF16) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = bf16,
-                    BF16,
-                    pack = 1,
-                    dev,
-                    eight_bit,
-                    8bit_u8_kernel_bf16
-                )
-            }
-
-            // 4 bits
-            (4, DType::F32) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = f32,
-                    F32,
-                    pack = 2,
-                    dev,
-                    four_bit,
-                    4bit_u8_kernel_f32
-                )
-            }
-            (4, DType::F16) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = f16,
-                    F16,
-                    pack = 2,
-                    dev,
-                    four_bit,
-                    4bit_u8_kernel_f16
-                )
-            }
-            (4, DType::BF16) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = bf16,
-                    BF16,
-                    pack = 2,
-                    dev,
-                    four_bit,
-                    4bit_u8_kernel_bf16
-                )
-            }
-
-            // 3 bits
-            // https://github.com/mobiusml/hqq/blob/306e30d9400629523c8e0af70101d8d7073cb3d5/hqq/kernels/hqq_aten_cuda.cpp#L42-L45
-            (3, DType::F32) => {
-                let res = dequant_for_dtype!(
-                    self,
-                    w = i32,
-                    sz = f32,
-                    F32,
-                    pack = 10,
-                    dev,
-                    three_bit,
-                    3bit_32_kernel_f32
-                );
-                res.narrow(self.cfg.axis as usize, 0, self.cfg.group_size.into())?
-            }
-            (3, DType::F16) => {
-                let res = dequant_for_dtype!(
-                    self,
-                    w = i32,
-                    sz = f16,
-                    F16,
-                    pack = 10,
-                    dev,
-                    three_bit,
-                    3bit_32_kernel_f16
-                );
-                res.narrow(self.cfg.axis as usize, 0, self.cfg.group_size.into())?
-            }
-            (3, DType::BF16) => {
-                let res = dequant_for_dtype!(
-                    self,
-                    w = i32,
-                    sz = bf16,
-                    BF16,
-                    pack = 10,
-                    dev,
-                    three_bit,
-                    3bit_32_kernel_bf16
-                );
-                res.narrow(self.cfg.axis as usize, 0, self.cfg.group_size.into())?
-            }
-
-            // 2 bits
-            (2, DType::F32) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = f32,
-                    F32,
-                    pack = 4,
-                    dev,
-                    two_bit,
-                    2bit_u8_kernel_f32
-                )
-            }
-            (2, DType::F16) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = f16,
-                    F16,
-                    pack = 4,
-                    dev,
-                    two_bit,
-                    2bit_u8_kernel_f16
-                )
-            }
-            (2, DType::BF16) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = bf16,
-                    BF16,
-                    pack = 4,
-                    dev,
-                    two_bit,
-                    2bit_u8_kernel_bf16
-                )
-            }
-
-            // 1 bit
-            (1, DType::F32) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = f32,
-                    F32,
-                    pack = 8,
-                    dev,
-                    one_bit,
-                    1bit_u8_kernel_f32
-                )
-            }
-            (1, DType::F16) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = f16,
-                    F16,
-                    pack = 8,
-                    dev,
-                    one_bit,
-                    1bit_u8_kernel_f16
-                )
-            }
-            (1, DType::BF16) => {
-                dequant_for_dtype!(
-                    self,
-                    w = u8,
-                    sz = bf16,
-                    BF16,
-                    pack = 8,
-                    dev,
-                    one_bit,
-                    1bit_u8_kernel_bf16
-                )
-            }
-            (bits, dtype) => candle_core::bail!("Unsupported bit width {bits} and dtype {dtype:?}"),
-        };
-        inner.reshape(&self.w_shape)
-    }
-
     fn dequantize_matmul(&self, xs: &Tensor) -> Result<Tensor> {
         let w = self.dequantize()?;
         // Dispatch to unquant. This uses some cublaslt for bias & on cuda always, so it is better
@@ -509,6 +282,41 @@ impl HqqLayer {
         unquant.forward(xs)
     }
 
+    /// Whether `xs @ W^T` can use the fused 4-bit dequantize-matmul kernel instead of
+    /// dequantizing the full weight first. The kernel reads the packed weight once per row of
+    /// `xs`, so it only pays off for decode-sized inputs of at most [`FUSED_MATMUL_MAX_ROWS`].
+    fn can_use_fused_matmul(&self, xs: &Tensor) -> bool {
+        let k = xs.dims().last().copied().unwrap_or(1).max(1);
+        cfg!(feature = "metal")
+            && xs.device().is_metal()
+            && xs.elem_count() / k <= FUSED_MATMUL_MAX_ROWS
+            && self.cfg.bits as usize == 4
+            && self.cfg.axis as usize == 0
+            && xs.dtype() == self.scales.dtype()
+            && self.zeros.dtype() == self.scales.dtype()
+    }
+
+    #[cfg(feature = "metal")]
+    fn fused_matmul(&self, xs: &Tensor) -> Result<Tensor> {
+        let (o, k) = self.w_shape.dims2()?;
+        let mut out_dims = xs.dims().to_vec();
+        *out_dims.last_mut().unwrap() = o;
+
+        let xs = xs.reshape(((), k))?.contiguous()?;
+        let out =
+            crate::hqq::hqq_op::hqq_4bit_matmul_op(&xs, &self.w_q, &self.scales, &self.zeros, o)?
+                .reshape(out_dims)?;
+        match &self.bias {
+            Some(bias) => out.broadcast_add(bias),
+            None => Ok(out),
+        }
+    }
+
+    #[cfg(not(feature = "metal"))]
+    fn fused_matmul(&self, _xs: &Tensor) -> Result<Tensor> {
+        candle_core::bail!("The HQQ fused matmul is only supported on Metal.")
+    }
+
     pub fn with_bias(mut self, bias: Tensor) -> Self {
         self.bias = Some(bias);
         self
@@ -528,7 +336,8 @@ impl QuantMethod for HqqLayer {
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
-            | QuantMethodConfig::Afq { .. } => {
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Exl2 { .. } => {
                 unreachable!()
             }
             QuantMethodConfig::Hqq {
@@ -571,7 +380,11 @@ impl QuantMethod for HqqLayer {
         } else {
             todo!()
         } */
-        self.dequantize_matmul(a)
+        if self.can_use_fused_matmul(a) {
+            self.fused_matmul(a)
+        } else {
+            self.dequantize_matmul(a)
+        }
     }
 
     fn quantized_act_type(&self) -> Option<DType> {
@@ -628,6 +441,14 @@ impl QuantMethod for HqqLayer {
             Ok(Arc::new(res))
         }
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        QuantInfo {
+            method: self.name(),
+            bits: Some(self.cfg.bits as usize),
+            group_size: Some(self.cfg.group_size.get()),
+        }
+    }
 }
 
 // Serialization structure:
@@ -906,7 +727,7 @@ impl HqqLayer {
         match bits {
             HqqBits::Eight => Ok(IsqType::HQQ8),
             HqqBits::Four => Ok(IsqType::HQQ4),
-            HqqBits::One | HqqBits::Two | HqqBits::Three => {
+            HqqBits::One | HqqBits::Two | HqqBits::Three | HqqBits::Five => {
                 candle_core::bail!("cannot convert hqq bits to isq type")
             }
         }
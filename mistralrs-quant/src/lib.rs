@@ -2,7 +2,10 @@ use std::{
     borrow::Cow,
     fmt::Debug,
     num::NonZeroUsize,
-    sync::{atomic::AtomicUsize, Arc, Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
 use blockwise_fp8::blockwise_fp8_linear_b;
@@ -20,12 +23,15 @@ mod blockwise_fp8;
 pub mod cublaslt;
 pub mod distributed;
 mod dummy;
+mod exl2;
 mod fp8;
 mod gguf;
 mod gptq;
 mod hqq;
 mod imatrix;
 mod lora;
+pub mod op_trace;
+pub mod rms_norm;
 pub mod rotary;
 pub mod safetensors;
 mod unquantized;
@@ -39,17 +45,18 @@ pub use afq::{AfqBits, AfqGroupSize, AfqLayer};
 pub use bitsandbytes::{BnbLinear, BnbQuantParmas, BnbQuantType};
 pub use distributed::{
     layers::{
-        compute_kv_shard, compute_n_kv_groups, ColumnParallelLayer, ReplicatedLayer,
-        RowParallelLayer,
+        compute_kv_shard, compute_n_kv_groups, fused_qkv_linear_no_bias,
+        qkv_column_parallel_layers, ColumnParallelLayer, ReplicatedLayer, RowParallelLayer,
     },
     socket::{Client, Server},
     BarrierLike, Comm, Id, SumAllReduce,
 };
 pub use dummy::DummyLayer;
+pub use exl2::Exl2Layer;
 pub use fp8::FP8Linear;
 pub use gguf::GgufMatMul;
 pub use gptq::GptqLayer;
-pub use hqq::{HqqAxis, HqqBits, HqqConfig, HqqLayer};
+pub use hqq::{quantize_hqq, HqqAxis, HqqBits, HqqConfig, HqqLayer};
 pub use imatrix::{CollectedImatrixData, ImatrixLayerStats};
 pub use lora::{
     linear_no_bias_static_lora, LoraAdapter, LoraConfig, StaticLoraConfig, APPLIED_LORAS,
@@ -79,13 +86,18 @@ pub enum QuantizedConfig {
         bits: usize,
         group_size: usize,
     },
+    Exl2 {
+        /// Average bits per weight, each group has its own bit width.
+        bits: f64,
+    },
 }
 
 // Common fields for all variants
 #[derive(Deserialize)]
 struct RawConfig {
     quant_method: Option<String>,
-    bits: Option<usize>,
+    // EXL2 uses fractional (average) bits.
+    bits: Option<f64>,
     group_size: Option<usize>,
     checkpoint_format: Option<String>,
     weight_block_size: Option<Vec<usize>>,
@@ -99,12 +111,19 @@ impl<'de> Deserialize<'de> for QuantizedConfig {
         D: Deserializer<'de>,
     {
         let raw = RawConfig::deserialize(deserializer)?;
+        let int_bits = || -> std::result::Result<usize, D::Error> {
+            match raw.bits {
+                Some(bits) if bits.fract() == 0. && bits > 0. => Ok(bits as usize),
+                Some(bits) => Err(serde::de::Error::custom(format!(
+                    "Expected an integer number of bits, got {bits}"
+                ))),
+                None => Err(serde::de::Error::missing_field("bits")),
+            }
+        };
 
         match &raw.quant_method {
             Some(m) if m == "gptq" => {
-                let bits = raw
-                    .bits
-                    .ok_or_else(|| serde::de::Error::missing_field("bits"))?;
+                let bits = int_bits()?;
                 let group_size = raw
                     .group_size
                     .ok_or_else(|| serde::de::Error::missing_field("group_size"))?;
@@ -123,19 +142,21 @@ impl<'de> Deserialize<'de> for QuantizedConfig {
             Some(m) if m == "bitsandbytes" => Ok(QuantizedConfig::Bitsandbytes {
                 bnb_4bit_quant_type: raw.bnb_4bit_quant_type,
             }),
-            Some(m) if m == "afq" => {
+            Some(m) if m == "exl2" => {
                 let bits = raw
                     .bits
                     .ok_or_else(|| serde::de::Error::missing_field("bits"))?;
+                Ok(QuantizedConfig::Exl2 { bits })
+            }
+            Some(m) if m == "afq" => {
+                let bits = int_bits()?;
                 let group_size = raw
                     .group_size
                     .ok_or_else(|| serde::de::Error::missing_field("group_size"))?;
                 Ok(QuantizedConfig::Afq { bits, group_size })
             }
             None => {
-                let bits = raw
-                    .bits
-                    .ok_or_else(|| serde::de::Error::missing_field("bits"))?;
+                let bits = int_bits()?;
                 let group_size = raw
                     .group_size
                     .ok_or_else(|| serde::de::Error::missing_field("group_size"))?;
@@ -143,7 +164,7 @@ impl<'de> Deserialize<'de> for QuantizedConfig {
             }
             Some(unknown_method) => {
                 Err(serde::de::Error::custom(format!(
-                    "Unknown quantization method: {}. Expected one of: gptq, fp8, bitsandbytes, afq, or not specified", 
+                    "Unknown quantization method: {}. Expected one of: gptq, fp8, bitsandbytes, afq, exl2, or not specified", 
                     unknown_method
                 )))
             },
@@ -158,6 +179,7 @@ impl QuantizedConfig {
             Self::Fp8 { .. } => "fp8",
             Self::Bitsandbytes { .. } => "bitsandbytes",
             Self::Afq { .. } => "afq",
+            Self::Exl2 { .. } => "exl2",
         }
     }
 
@@ -172,6 +194,7 @@ impl QuantizedConfig {
                 bnb_4bit_quant_type: None,
             } => "8 bits".to_string(),
             Self::Afq { bits, .. } => format!("{bits} bits"),
+            Self::Exl2 { bits } => format!("{bits} average bits"),
         }
     }
 }
@@ -228,15 +251,156 @@ pub enum QuantMethodConfig {
         bits: AfqBits,
         group_size: AfqGroupSize,
     },
+    Exl2 {
+        q_weight: Tensor,
+        q_scale: Tensor,
+        q_scale_max: Tensor,
+        q_groups: Tensor,
+        q_invperm: Tensor,
+        bias: Option<Tensor>,
+    },
+}
+
+/// The dtype [`MatMul`] routes unquantized GEMMs through. The states are mutually exclusive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MatMulPrecision {
+    /// Multiply in the operands' own dtype (f16 on CPU, as before).
+    #[default]
+    Native,
+    /// Cast the operands to f16 for the GEMM.
+    F16,
+    /// Cast the operands to bf16 for the GEMM. Avoids the f16 overflow on large logits.
+    BF16,
+}
+
+impl MatMulPrecision {
+    fn dtype(&self) -> Option<DType> {
+        match self {
+            Self::Native => None,
+            Self::F16 => Some(DType::F16),
+            Self::BF16 => Some(DType::BF16),
+        }
+    }
+}
+
+static MATMUL_PRECISION: AtomicU8 = AtomicU8::new(0);
+
+/// Set the global [`MatMulPrecision`].
+pub fn set_matmul_precision(precision: MatMulPrecision) {
+    let raw = match precision {
+        MatMulPrecision::Native => 0,
+        MatMulPrecision::F16 => 1,
+        MatMulPrecision::BF16 => 2,
+    };
+    MATMUL_PRECISION.store(raw, Ordering::Relaxed);
+}
+
+/// Get the global [`MatMulPrecision`].
+pub fn get_matmul_precision() -> MatMulPrecision {
+    match MATMUL_PRECISION.load(Ordering::Relaxed) {
+        1 => MatMulPrecision::F16,
+        2 => MatMulPrecision::BF16,
+        _ => MatMulPrecision::Native,
+    }
+}
+
+/// Route matmuls through f16. Enabling this disables the bf16 routing.
+pub fn set_use_matmul_via_f16(via_f16: bool) {
+    set_via(MatMulPrecision::F16, via_f16)
+}
+
+pub fn get_use_matmul_via_f16() -> bool {
+    get_matmul_precision() == MatMulPrecision::F16
+}
+
+/// Route matmuls through bf16. Enabling this disables the f16 routing.
+pub fn set_use_matmul_via_bf16(via_bf16: bool) {
+    set_via(MatMulPrecision::BF16, via_bf16)
+}
+
+pub fn get_use_matmul_via_bf16() -> bool {
+    get_matmul_precision() == MatMulPrecision::BF16
+}
+
+fn set_via(precision: MatMulPrecision, enable: bool) {
+    if enable {
+        set_matmul_precision(precision);
+    } else if get_matmul_precision() == precision {
+        set_matmul_precision(MatMulPrecision::Native);
+    }
+}
+
+static STRICT_DETERMINISM: AtomicBool = AtomicBool::new(false);
+/// The CUDA reduced precision GEMM flags for f16, bf16 and f32 from before strict determinism was
+/// enabled, restored when it is disabled.
+#[cfg(feature = "cuda")]
+static GEMM_REDUCED_PRECISION: Mutex<Option<[bool; 3]>> = Mutex::new(None);
+
+/// Make forward passes agree closely across devices, for example to compare a CPU and a GPU run:
+/// - unquantized matmuls run in f32, overriding the [`MatMulPrecision`] and the f16 routing on CPU,
+///   and CUDA GEMMs do not accumulate in reduced precision,
+/// - norms, softmax and attention run in f32 with composed ops instead of fused kernels, and the
+///   RmsNorm and softmax sums add in a fixed pairwise order rather than the backend's.
+///
+/// With f32 weights, CPU and GPU logits then agree to within about `1e-4`. The GEMMs, including those of
+/// attention, still accumulate in a backend specific order and `exp` may differ in the last bit, so the
+/// outputs are close rather than bitwise equal. The cost is roughly 2-4x
+/// slower f16/bf16 inference (f32 GEMMs, extra casts, and no flash attention or fused kernels) and
+/// twice the activation memory.
+///
+/// Disabling it restores the CUDA reduced precision GEMM settings from before it was enabled.
+pub fn set_strict_determinism(strict: bool) {
+    #[cfg(feature = "cuda")]
+    {
+        use candle_core::cuda;
+
+        let mut saved = GEMM_REDUCED_PRECISION
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if strict {
+            saved.get_or_insert([
+                cuda::gemm_reduced_precision_f16(),
+                cuda::gemm_reduced_precision_bf16(),
+                cuda::gemm_reduced_precision_f32(),
+            ]);
+            cuda::set_gemm_reduced_precision_f16(false);
+            cuda::set_gemm_reduced_precision_bf16(false);
+            cuda::set_gemm_reduced_precision_f32(false);
+        } else if let Some([f16, bf16, f32]) = saved.take() {
+            cuda::set_gemm_reduced_precision_f16(f16);
+            cuda::set_gemm_reduced_precision_bf16(bf16);
+            cuda::set_gemm_reduced_precision_f32(f32);
+        }
+    }
+    STRICT_DETERMINISM.store(strict, Ordering::Relaxed);
+}
+
+/// Whether [`set_strict_determinism`] is enabled.
+pub fn get_strict_determinism() -> bool {
+    STRICT_DETERMINISM.load(Ordering::Relaxed)
 }
 
 /// Device/configurable intelligent matrix multiplication
 /// - Handles limitation of `accelerate` which requires f32
+/// - Routes through f16 or bf16 according to the global [`MatMulPrecision`]
+/// - Runs in f32 with [`set_strict_determinism`]
 pub struct MatMul;
 
 impl MatMul {
     /// Compute matrix-matrix product.
     pub fn matmul(&self, a: &Tensor, b: &Tensor) -> Result<Tensor> {
+        let out = op_trace::run_op(|| self.matmul_inner(a, b))?;
+        op_trace::record("matmul", &[a, b], &[&out]);
+        Ok(out)
+    }
+
+    fn matmul_inner(&self, a: &Tensor, b: &Tensor) -> Result<Tensor> {
+        if get_strict_determinism() {
+            return a
+                .to_dtype(DType::F32)?
+                .matmul(&b.to_dtype(DType::F32)?)?
+                .to_dtype(a.dtype());
+        }
         #[cfg(feature = "accelerate")]
         {
             let original_dtype = a.dtype();
@@ -246,13 +410,17 @@ impl MatMul {
         }
         #[cfg(not(feature = "accelerate"))]
         {
-            if a.device().is_cpu() {
-                let original_dtype = a.dtype();
-                a.to_dtype(DType::F16)?
-                    .matmul(&b.to_dtype(DType::F16)?)?
-                    .to_dtype(original_dtype)
-            } else {
-                a.matmul(b)
+            let via = get_matmul_precision()
+                .dtype()
+                .or(a.device().is_cpu().then_some(DType::F16));
+            match via {
+                Some(dtype) if a.dtype() != dtype || b.dtype() != dtype => {
+                    let original_dtype = a.dtype();
+                    a.to_dtype(dtype)?
+                        .matmul(&b.to_dtype(dtype)?)?
+                        .to_dtype(original_dtype)
+                }
+                _ => a.matmul(b),
             }
         }
     }
@@ -260,25 +428,150 @@ impl MatMul {
     /// Compute matrix-matrix product.
     /// The result will be divided by the `scale` parameter in an affine division.
     pub fn matmul_affine_div(&self, a: &Tensor, b: &Tensor, scale: f64) -> Result<Tensor> {
-        // TODO(EricLBuehler): Optimize this by using the gemm parameter?
-        self.matmul(a, b)? / scale
+        self.matmul_scaled(a, b, 1. / scale)
     }
 
     /// Compute matrix-matrix product.
     /// The result will be divided by the `scale` parameter in an affine multiplication.
     pub fn matmul_affine_mul(&self, a: &Tensor, b: &Tensor, scale: f64) -> Result<Tensor> {
-        // TODO(EricLBuehler): Optimize this by using the gemm parameter?
-        self.matmul(a, b)? * scale
+        self.matmul_scaled(a, b, scale)
+    }
+
+    /// Compute `(a @ b) * factor` by scaling the smaller operand before the GEMM, so the
+    /// (usually much larger) output is never touched a second time.
+    ///
+    /// When `factor` amplifies, a scaled f16/bf16 operand could overflow where the product would
+    /// not, so the scale and the GEMM then run in f32 and the output is cast back. This choice
+    /// only looks at shapes, so it never reads values back from the device.
+    fn matmul_scaled(&self, a: &Tensor, b: &Tensor, factor: f64) -> Result<Tensor> {
+        if factor == 1. {
+            return self.matmul(a, b);
+        }
+        let scale_a = a.elem_count() <= b.elem_count();
+        if factor > 1. {
+            let out = op_trace::run_op(|| {
+                let (a_f32, b_f32) = (a.to_dtype(DType::F32)?, b.to_dtype(DType::F32)?);
+                let out = if scale_a {
+                    (a_f32 * factor)?.matmul(&b_f32)?
+                } else {
+                    a_f32.matmul(&(b_f32 * factor)?)?
+                };
+                out.to_dtype(a.dtype())
+            })?;
+            op_trace::record("matmul", &[a, b], &[&out]);
+            return Ok(out);
+        }
+        if scale_a {
+            self.matmul(&(a * factor)?, b)
+        } else {
+            self.matmul(a, &(b * factor)?)
+        }
+    }
+
+    /// Compute an activation-quantized matrix-matrix product.
+    ///
+    /// Both operands are quantized to the symmetric int8 grid (`round(x / scale)` clamped to
+    /// `[-127, 127]`) with the given per-tensor scales and the product is returned in the
+    /// dtype of `a`.
+    ///
+    /// On CUDA this is an int8 cuBLASLt GEMM with an i32 accumulator when `b` is 2D, the dtypes
+    /// are f32, f16 or bf16 and the inner and output dims are multiples of 4. Otherwise, and on
+    /// every other device, the int8 operands are dequantized and multiplied on the regular matmul
+    /// path (f16 on CPU). This fallback rounds like a float GEMM of the grid values, not like an
+    /// i32 accumulator.
+    pub fn matmul_i8(&self, a: &Tensor, b: &Tensor, a_scale: f32, b_scale: f32) -> Result<Tensor> {
+        if a_scale <= 0. || b_scale <= 0. {
+            candle_core::bail!("matmul_i8 scales must be positive, got {a_scale} and {b_scale}");
+        }
+        let fake_quant = |t: &Tensor, scale: f32| -> Result<Tensor> {
+            let scale = scale as f64;
+            (t.to_dtype(DType::F32)? / scale)?
+                .round()?
+                .clamp(-127f32, 127f32)?
+                .affine(scale, 0.)?
+                .to_dtype(a.dtype())
+        };
+        let out = op_trace::run_op(|| {
+            #[cfg(feature = "cuda")]
+            if let Some(out) = Self::cublaslt_matmul_i8(a, b, a_scale, b_scale)? {
+                return Ok(out);
+            }
+            self.matmul_inner(&fake_quant(a, a_scale)?, &fake_quant(b, b_scale)?)
+        })?;
+        op_trace::record("matmul_i8", &[a, b], &[&out]);
+        Ok(out)
+    }
+
+    /// [`Self::matmul_i8`] through the int8 cuBLASLt GEMM, or `None` if it does not apply.
+    #[cfg(feature = "cuda")]
+    fn cublaslt_matmul_i8(
+        a: &Tensor,
+        b: &Tensor,
+        a_scale: f32,
+        b_scale: f32,
+    ) -> Result<Option<Tensor>> {
+        if !a.device().is_cuda()
+            || b.rank() != 2
+            || a.dtype() != b.dtype()
+            || !matches!(a.dtype(), DType::F32 | DType::F16 | DType::BF16)
+        {
+            return Ok(None);
+        }
+        let (k, n) = b.dims2()?;
+        if k % 4 != 0 || n % 4 != 0 || a.dims().last() != Some(&k) {
+            return Ok(None);
+        }
+        cublaslt::maybe_init_cublas_lt_wrapper(a.device().clone());
+        let Some(handle) = *cublaslt::CUBLASLT_HANDLE.lock().unwrap() else {
+            return Ok(None);
+        };
+
+        let mut out_shape = a.dims().to_vec();
+        *out_shape.last_mut().unwrap() = n;
+        // cuBLASLt takes both operands with the inner dim contiguous, `b` as (n, k).
+        let x = a.contiguous()?.reshape(((), k))?;
+        let w = b.t()?.contiguous()?;
+        let out = handle.matmul_i8(&w, &x, b_scale, a_scale)?;
+        Ok(Some(out.reshape(out_shape)?))
     }
 
     /// Compute quantized matrix-matrix product.
+    ///
+    /// Dequantized weights follow the global [`MatMulPrecision`]; quantized kernels keep their
+    /// own activation dtype.
     pub fn qmatmul(&self, x: &Tensor, matmul: &QMatMul) -> Result<Tensor> {
-        matmul.forward(x)
+        let out = op_trace::run_op(|| match (get_matmul_precision().dtype(), matmul) {
+            (Some(dtype), QMatMul::Tensor(w) | QMatMul::TensorF16(w)) => {
+                Self::matmul_via(x, w, dtype)
+            }
+            _ => matmul.forward(x),
+        })?;
+        op_trace::record("qmatmul", &[x], &[&out]);
+        Ok(out)
     }
 
     /// Compute quantized matrix-matrix product.
+    ///
+    /// Unquantized layers follow the global [`MatMulPrecision`]: the input is cast and the layer's
+    /// own `forward` runs in that dtype, so bias, stats tracking and any all-reduce still apply.
+    /// Quantized methods keep their own activation dtype.
     pub fn qmethod_matmul(&self, x: &Tensor, matmul: &dyn QuantMethod) -> Result<Tensor> {
-        matmul.forward(x)
+        let via = get_matmul_precision().dtype().filter(|_| {
+            matmul.quantized_act_type().is_none() && matmul.unquant_weight_bias().is_some()
+        });
+        let out = op_trace::run_op(|| match via {
+            Some(dtype) => matmul.forward(&x.to_dtype(dtype)?)?.to_dtype(x.dtype()),
+            None => matmul.forward(x),
+        })?;
+        op_trace::record("qmatmul", &[x], &[&out]);
+        Ok(out)
+    }
+
+    /// `x @ w.T` computed in `dtype`, returned in the dtype of `x`.
+    fn matmul_via(x: &Tensor, w: &Tensor, dtype: DType) -> Result<Tensor> {
+        x.to_dtype(dtype)?
+            .broadcast_matmul(&w.t()?.to_dtype(dtype)?)?
+            .to_dtype(x.dtype())
     }
 }
 
@@ -550,6 +843,17 @@ pub enum DistributedKind {
     Replicated,
 }
 
+/// The quantization applied to a single weight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantInfo {
+    /// Name of the quantization method, as given by [`QuantizedSerde::name`].
+    pub method: &'static str,
+    /// Bits per weight element, if known.
+    pub bits: Option<usize>,
+    /// Number of weight elements sharing one set of quantization parameters, if the method is grouped.
+    pub group_size: Option<usize>,
+}
+
 /// Quantized method for a quantized matmul.
 pub trait QuantMethod: Send + Sync + Debug + QuantizedSerde {
     fn new(method: QuantMethodConfig) -> Result<Self>
@@ -635,6 +939,15 @@ pub trait QuantMethod: Send + Sync + Debug + QuantizedSerde {
     fn is_distributed(&self) -> Option<DistributedKind> {
         None
     }
+
+    /// The quantization method, bit width and group size of the weight.
+    fn quant_info(&self) -> QuantInfo {
+        QuantInfo {
+            method: self.name(),
+            bits: None,
+            group_size: None,
+        }
+    }
 }
 
 impl Module for dyn QuantMethod {
@@ -661,6 +974,7 @@ pub fn linear_no_bias(
             QuantizedConfig::Afq { .. } => {
                 AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, false, vb)?
             }
+            QuantizedConfig::Exl2 { .. } => Exl2Layer::exl2_linear_b(in_dim, out_dim, false, vb)?,
         }
     } else {
         // Handle the case where the layer is dummy (no tensors)
@@ -698,6 +1012,7 @@ pub fn linear(
             QuantizedConfig::Afq { .. } => {
                 AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, true, vb)?
             }
+            QuantizedConfig::Exl2 { .. } => Exl2Layer::exl2_linear_b(in_dim, out_dim, true, vb)?,
         }
     } else {
         // Handle the case where the layer is dummy (no tensors)
@@ -731,3 +1046,205 @@ pub fn linear_b(
         linear_no_bias(in_dim, out_dim, config, vb)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    // The matmul precision is global, so tests that multiply must not overlap with tests that change it.
+    static PRECISION_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn test_matmul_affine_div_matches_post_division() {
+        let _guard = PRECISION_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        use crate::MatMul;
+        use candle_core::{DType, Device, Tensor};
+        let device = Device::Cpu;
+        let a = Tensor::randn(0f32, 1f32, (2, 8, 16), &device).unwrap();
+        let b = Tensor::randn(0f32, 1f32, (2, 16, 8), &device).unwrap();
+        let reference = (a.matmul(&b).unwrap() / 4.).unwrap();
+        let fused = MatMul.matmul_affine_div(&a, &b, 4.).unwrap();
+        let diff = (reference - fused)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-2, "max abs diff {diff}");
+
+        // A tiny scale amplifies by 1e4: scaling the large operand would leave the f16 range.
+        let big = (Tensor::ones((1, 4, 4), DType::F32, &device).unwrap() * 50.).unwrap();
+        let small = (Tensor::ones((1, 4, 4), DType::F32, &device).unwrap() * 1e-3).unwrap();
+        let out = MatMul
+            .matmul_affine_div(&big, &small, 1e-4)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert!(out.iter().all(|x| x.is_finite() && (x - 2000.).abs() < 5.));
+
+        // The same in f16, where the scaled operand alone would be out of range.
+        let out = MatMul
+            .matmul_affine_div(
+                &big.to_dtype(DType::F16).unwrap(),
+                &small.to_dtype(DType::F16).unwrap(),
+                1e-4,
+            )
+            .unwrap()
+            .to_dtype(DType::F32)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert!(out.iter().all(|x| x.is_finite() && (x - 2000.).abs() < 5.));
+    }
+
+    /// `q @ k.T / sqrt(d)` over a 4096 token attention score matrix, with the scale folded into
+    /// the GEMM and as a separate division of the scores. Run with
+    /// `cargo test --release -p mistralrs-quant matmul_affine_div_attention_scores -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn matmul_affine_div_attention_scores() {
+        let _guard = PRECISION_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        use std::time::Instant;
+
+        use crate::MatMul;
+        use candle_core::{Device, Tensor};
+
+        const RUNS: u32 = 5;
+        let (n_heads, seq_len, head_dim) = (4, 4096, 64);
+        let device = Device::Cpu;
+        let q = Tensor::randn(0f32, 1f32, (n_heads, seq_len, head_dim), &device).unwrap();
+        let k_t = Tensor::randn(0f32, 1f32, (n_heads, head_dim, seq_len), &device).unwrap();
+        let scale = (head_dim as f64).sqrt();
+
+        let fused = || MatMul.matmul_affine_div(&q, &k_t, scale).unwrap();
+        let post_division = || (MatMul.matmul(&q, &k_t).unwrap() / scale).unwrap();
+        for (name, run) in [
+            ("fused", &fused as &dyn Fn() -> Tensor),
+            ("post-division", &post_division),
+        ] {
+            run();
+            let start = Instant::now();
+            for _ in 0..RUNS {
+                run();
+            }
+            println!(
+                "{name}: {:.2}ms per call",
+                start.elapsed().as_secs_f64() * 1000. / f64::from(RUNS),
+            );
+        }
+    }
+
+    #[test]
+    fn test_matmul_precision_is_exclusive() {
+        let _guard = PRECISION_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        use crate::{
+            get_use_matmul_via_bf16, get_use_matmul_via_f16, set_use_matmul_via_bf16,
+            set_use_matmul_via_f16, MatMul,
+        };
+        use candle_core::{DType, Device, Tensor};
+
+        set_use_matmul_via_f16(true);
+        set_use_matmul_via_bf16(true);
+        assert!(get_use_matmul_via_bf16() && !get_use_matmul_via_f16());
+        // Disabling the inactive mode leaves the active one in place.
+        set_use_matmul_via_f16(false);
+        assert!(get_use_matmul_via_bf16());
+
+        let device = Device::Cpu;
+        let a = Tensor::new(&[[1f32, 2.], [3., 4.]], &device).unwrap();
+        let out = MatMul.matmul(&a, &a).unwrap();
+        assert_eq!(out.dtype(), DType::F32);
+        assert_eq!(out.to_vec2::<f32>().unwrap(), [[7., 10.], [15., 22.]]);
+
+        set_use_matmul_via_bf16(false);
+        assert!(!get_use_matmul_via_bf16() && !get_use_matmul_via_f16());
+    }
+
+    #[test]
+    fn test_qmethod_matmul_via_f16_runs_layer_forward() {
+        let _guard = PRECISION_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        use crate::{
+            set_use_matmul_via_f16, MatMul, QuantMethod, QuantMethodConfig, UnquantLinear,
+        };
+        use candle_core::{DType, Device, Tensor};
+        use candle_nn::Linear;
+
+        let device = Device::Cpu;
+        let w = Tensor::new(&[[1f32, 2.], [3., 4.]], &device).unwrap();
+        let b = Tensor::new(&[10f32, 20.], &device).unwrap();
+        let mut layer = <UnquantLinear as QuantMethod>::new(QuantMethodConfig::Unquantized(
+            Linear::new(w, Some(b)),
+        ))
+        .unwrap();
+        layer.begin_track_stats().unwrap();
+        let x = Tensor::new(&[[1f32, 1.]], &device).unwrap();
+
+        set_use_matmul_via_f16(true);
+        let out = MatMul.qmethod_matmul(&x, &layer);
+        set_use_matmul_via_f16(false);
+
+        let out = out.unwrap();
+        assert_eq!(out.dtype(), DType::F32);
+        assert_eq!(out.to_vec2::<f32>().unwrap(), [[13., 27.]]);
+        // The layer's own forward ran, so the imatrix stats saw the input.
+        let imatrix = layer.end_track_stats().unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(imatrix, [0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_unquant_forward_casts_weights_to_each_activation_dtype() {
+        use crate::{QuantMethod, QuantMethodConfig, UnquantLinear};
+        use candle_core::{DType, Device, Tensor};
+        use candle_nn::Linear;
+
+        let device = Device::Cpu;
+        let w = Tensor::new(&[[1f32, 2.], [3., 4.]], &device).unwrap();
+        let b = Tensor::new(&[10f32, 20.], &device).unwrap();
+        let layer = <UnquantLinear as QuantMethod>::new(QuantMethodConfig::Unquantized(
+            Linear::new(w, Some(b)),
+        ))
+        .unwrap();
+        let x = Tensor::new(&[[1f32, 1.]], &device).unwrap();
+
+        // Repeated dtypes reuse the cached cast, and a new dtype replaces it.
+        for dtype in [DType::F16, DType::F16, DType::BF16, DType::F32, DType::F16] {
+            let out = layer.forward(&x.to_dtype(dtype).unwrap()).unwrap();
+            assert_eq!(out.dtype(), dtype);
+            let out = out.to_dtype(DType::F32).unwrap().to_vec2::<f32>().unwrap();
+            assert_eq!(out, [[13., 27.]], "{dtype:?}");
+        }
+    }
+
+    #[test]
+    fn test_matmul_i8_matches_quantized_reference() {
+        let _guard = PRECISION_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        use crate::MatMul;
+        use candle_core::{Device, Tensor};
+        let device = Device::Cpu;
+        // Values already on the int8 grid are reproduced exactly.
+        let a = Tensor::new(&[[0.5f32, -1.0], [0.25, 0.75]], &device).unwrap();
+        let b = Tensor::new(&[[1.0f32, 0.5], [-0.5, 2.0]], &device).unwrap();
+        let out = MatMul
+            .matmul_i8(&a, &b, 0.25, 0.5)
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        assert_eq!(out, [[1.0, -1.75], [-0.125, 1.625]]);
+
+        // Out-of-range values saturate at +-127 * scale.
+        let a = Tensor::new(&[[1000f32]], &device).unwrap();
+        let b = Tensor::new(&[[1f32]], &device).unwrap();
+        let out = MatMul
+            .matmul_i8(&a, &b, 1.0, 1.0)
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        assert_eq!(out, [[127.0]]);
+    }
+}
@@ -197,3 +197,103 @@ instantiate_dequantize_3bit(float)
     instantiate_dequantize_3bit(bfloat)
 #endif
         instantiate_dequantize_3bit(half)
+
+    /*********************************/
+    /************* 5-bit *************/
+    //********************************/
+
+    template <typename T>
+    [[kernel]] void dequantize_5bit(const device int *weight [[buffer(0)]],
+                                    const device T *scale [[buffer(1)]],
+                                    const device T *zero [[buffer(2)]],
+                                    device T *output [[buffer(3)]],
+                                    device const uint &h, device const uint &w,
+                                    uint tid [[thread_position_in_grid]]) {
+  uint n = h * w;
+  uint j = tid % w;
+  output[tid] =
+      ((T)((weight[tid] & 0x3E000000) >> 25) - zero[j]) * scale[j]; // 1st chunk
+  output[tid + n] =
+      ((T)((weight[tid] & 0x01F00000) >> 20) - zero[j]) * scale[j]; // 2nd chunk
+  output[tid + n * 2] =
+      ((T)((weight[tid] & 0x000F8000) >> 15) - zero[j]) * scale[j]; // 3rd chunk
+  output[tid + n * 3] =
+      ((T)((weight[tid] & 0x00007C00) >> 10) - zero[j]) * scale[j]; // 4th chunk
+  output[tid + n * 4] =
+      ((T)((weight[tid] & 0x000003E0) >> 5) - zero[j]) * scale[j]; // 5th chunk
+  output[tid + n * 5] =
+      ((T)((weight[tid] & 0x0000001F)) - zero[j]) * scale[j]; // 6th chunk
+}
+
+#define instantiate_dequantize_5bit(type)                                      \
+  template [[host_name("dequantize_5bit_" #type)]] [[kernel]] void             \
+  dequantize_5bit<type>(const device int *weight [[buffer(0)]],                \
+                        const device type *scale [[buffer(1)]],                \
+                        const device type *zero [[buffer(2)]],                 \
+                        device type *output [[buffer(3)]],                     \
+                        device const uint &h, device const uint &w,            \
+                        uint tid [[thread_position_in_grid]]);
+
+instantiate_dequantize_5bit(float)
+#if defined(__HAVE_BFLOAT__)
+instantiate_dequantize_5bit(bfloat)
+#endif
+instantiate_dequantize_5bit(half)
+
+/*********************************/
+/***** 4-bit fused matmul ********/
+//********************************/
+
+// Computes `output = x @ W^T` for an HQQ 4-bit (axis 0) weight `W` of shape (o, k),
+// dequantizing the weight on the fly. `weight` is the packed (h, w) tensor: the
+// first h rows of the (2 * h, w) dequantized weight are in the high nibbles and
+// the last h rows in the low nibbles. Each threadgroup is a single simdgroup
+// which computes one output element.
+template <typename T>
+[[kernel]] void hqq_4bit_matmul(const device T *x [[buffer(0)]],
+                                const device uchar *weight [[buffer(1)]],
+                                const device T *scale [[buffer(2)]],
+                                const device T *zero [[buffer(3)]],
+                                device T *output [[buffer(4)]],
+                                device const uint &h, device const uint &w,
+                                device const uint &k, device const uint &o,
+                                uint2 tgid [[threadgroup_position_in_grid]],
+                                uint lane [[thread_index_in_simdgroup]]) {
+  const uint col = tgid.x;
+  const uint row = tgid.y;
+  const device T *x_row = x + row * k;
+
+  float acc = 0;
+  for (uint i = lane; i < k; i += 32) {
+    // Position of W[col, i] in the (2 * h, w) dequantized weight
+    const uint idx = col * k + i;
+    const uint r = idx / w;
+    const uint c = idx % w;
+    const uchar q = r < h ? (weight[r * w + c] & 0xF0) >> 4
+                          : (weight[(r - h) * w + c] & 0x0F);
+    const float wv = ((float)q - (float)zero[c]) * (float)scale[c];
+    acc += (float)x_row[i] * wv;
+  }
+  acc = simd_sum(acc);
+  if (lane == 0) {
+    output[row * o + col] = (T)acc;
+  }
+}
+
+#define instantiate_hqq_4bit_matmul(type)                                      \
+  template [[host_name("hqq_4bit_matmul_" #type)]] [[kernel]] void             \
+  hqq_4bit_matmul<type>(const device type *x [[buffer(0)]],                    \
+                        const device uchar *weight [[buffer(1)]],              \
+                        const device type *scale [[buffer(2)]],                \
+                        const device type *zero [[buffer(3)]],                 \
+                        device type *output [[buffer(4)]],                     \
+                        device const uint &h, device const uint &w,            \
+                        device const uint &k, device const uint &o,            \
+                        uint2 tgid [[threadgroup_position_in_grid]],           \
+                        uint lane [[thread_index_in_simdgroup]]);
+
+instantiate_hqq_4bit_matmul(float)
+#if defined(__HAVE_BFLOAT__)
+instantiate_hqq_4bit_matmul(bfloat)
+#endif
+instantiate_hqq_4bit_matmul(half)
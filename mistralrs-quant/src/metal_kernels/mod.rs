@@ -16,6 +16,7 @@ const HQQ_DEQUANTIZE: &str = include_str!("hqq_dequantize.metal");
 const BNB_DEQUANTIZE: &str = include_str!("bnb_dequantize.metal");
 const BITWISE: &str = include_str!("bitwise.metal");
 const QUANTIZED: &str = include_str!("quantized.metal");
+const RMS_NORM: &str = include_str!("rms_norm.metal");
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Source {
@@ -23,6 +24,7 @@ pub enum Source {
     BnbDequant,
     Bitwise,
     Quantized,
+    RmsNorm,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -78,6 +80,7 @@ impl Kernels_ {
             Source::BnbDequant => BNB_DEQUANTIZE,
             Source::Bitwise => BITWISE,
             Source::Quantized => QUANTIZED,
+            Source::RmsNorm => RMS_NORM,
         }
     }
 
@@ -355,6 +358,150 @@ pub fn call_dequant_3bit(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn call_dequant_5bit(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    ty: DType,
+    weight: &Buffer,
+    scale: &Buffer,
+    zero: &Buffer,
+    h: u32,
+    w: u32,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let name = match ty {
+        DType::F32 => "dequantize_5bit_float",
+        DType::BF16 => "dequantize_5bit_bfloat",
+        DType::F16 => "dequantize_5bit_half",
+        other => {
+            return Err(MetalKernelError::DTypeMismatch {
+                expected: vec![DType::F32, DType::F16, DType::BF16],
+                got: other,
+            })
+        }
+    };
+    let pipeline = kernels.load_pipeline(device, Source::HqqDequant, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    let length = h * w;
+
+    set_params!(encoder, (weight, scale, zero, output, h, w));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length as usize);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Fused HQQ 4-bit dequantize and matmul: `output = x @ W^T` where `x` is (m, k) and `W` is (o, k).
+#[allow(clippy::too_many_arguments)]
+pub fn call_hqq_4bit_matmul(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    ty: DType,
+    x: (&Buffer, usize),
+    weight: &Buffer,
+    scale: &Buffer,
+    zero: &Buffer,
+    h: u32,
+    w: u32,
+    m: u32,
+    k: u32,
+    o: u32,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let name = match ty {
+        DType::F32 => "hqq_4bit_matmul_float",
+        DType::BF16 => "hqq_4bit_matmul_bfloat",
+        DType::F16 => "hqq_4bit_matmul_half",
+        other => {
+            return Err(MetalKernelError::DTypeMismatch {
+                expected: vec![DType::F32, DType::F16, DType::BF16],
+                got: other,
+            })
+        }
+    };
+    let pipeline = kernels.load_pipeline(device, Source::HqqDequant, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (x, weight, scale, zero, output, h, w, k, o));
+
+    // One simdgroup per output element
+    let thread_group_count = MTLSize {
+        width: o as u64,
+        height: m as u64,
+        depth: 1,
+    };
+    let thread_group_size = MTLSize {
+        width: 32,
+        height: 1,
+        depth: 1,
+    };
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Fused `sum = x + residual` and `normed = rms_norm(sum) * weight` over `rows` rows of `hidden`
+/// elements, one threadgroup per row.
+#[allow(clippy::too_many_arguments)]
+pub fn call_fused_add_rms_norm(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    ty: DType,
+    x: (&Buffer, usize),
+    residual: (&Buffer, usize),
+    weight: (&Buffer, usize),
+    sum: (&Buffer, usize),
+    normed: (&Buffer, usize),
+    rows: u32,
+    hidden: u32,
+    eps: f32,
+) -> Result<(), MetalKernelError> {
+    let name = match ty {
+        DType::F32 => "fused_add_rms_norm_float",
+        DType::BF16 => "fused_add_rms_norm_bfloat",
+        DType::F16 => "fused_add_rms_norm_half",
+        other => {
+            return Err(MetalKernelError::DTypeMismatch {
+                expected: vec![DType::F32, DType::F16, DType::BF16],
+                got: other,
+            })
+        }
+    };
+    let pipeline = kernels.load_pipeline(device, Source::RmsNorm, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (x, residual, weight, sum, normed, hidden, eps));
+
+    // The kernel reduces over whole simdgroups, so the threadgroup is a multiple of 32 threads.
+    let max_threads = pipeline.max_total_threads_per_threadgroup().min(1024);
+    let width = (u64::from(hidden).div_ceil(32) * 32).min(max_threads);
+    let thread_group_count = MTLSize {
+        width: u64::from(rows),
+        height: 1,
+        depth: 1,
+    };
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_bitwise_or(
     device: &Device,
@@ -0,0 +1,58 @@
+#include <metal_stdlib>
+using namespace metal;
+
+// One threadgroup per row: `sum = x + residual` and
+// `normed = sum / rms(sum) * weight`. The sum is rounded to `T` before it is
+// normalized, as in an add followed by an RmsNorm, and the norm itself is
+// computed in f32.
+template <typename T>
+[[kernel]] void fused_add_rms_norm(
+    const device T *x [[buffer(0)]], const device T *residual [[buffer(1)]],
+    const device T *weight [[buffer(2)]], device T *sum [[buffer(3)]],
+    device T *normed [[buffer(4)]], device const uint &hidden,
+    device const float &eps, uint row [[threadgroup_position_in_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint tg_size [[threads_per_threadgroup]],
+    uint lane [[thread_index_in_simdgroup]],
+    uint simd_id [[simdgroup_index_in_threadgroup]]) {
+  threadgroup float partial[32];
+  const uint offset = row * hidden;
+
+  float sum_sq = 0;
+  for (uint i = tid; i < hidden; i += tg_size) {
+    const T s = (T)((float)x[offset + i] + (float)residual[offset + i]);
+    sum[offset + i] = s;
+    sum_sq += (float)s * (float)s;
+  }
+  sum_sq = simd_sum(sum_sq);
+  if (lane == 0) {
+    partial[simd_id] = sum_sq;
+  }
+  threadgroup_barrier(mem_flags::mem_threadgroup);
+  const uint n_simd = (tg_size + 31) / 32;
+  sum_sq = simd_sum(lane < n_simd ? partial[lane] : 0);
+
+  const float scale = rsqrt(sum_sq / hidden + eps);
+  for (uint i = tid; i < hidden; i += tg_size) {
+    normed[offset + i] = (T)((float)sum[offset + i] * scale * (float)weight[i]);
+  }
+}
+
+#define instantiate_fused_add_rms_norm(type)                                   \
+  template [[host_name("fused_add_rms_norm_" #type)]] [[kernel]] void          \
+  fused_add_rms_norm<type>(                                                    \
+      const device type *x [[buffer(0)]],                                      \
+      const device type *residual [[buffer(1)]],                               \
+      const device type *weight [[buffer(2)]], device type *sum [[buffer(3)]], \
+      device type *normed [[buffer(4)]], device const uint &hidden,            \
+      device const float &eps, uint row [[threadgroup_position_in_grid]],      \
+      uint tid [[thread_position_in_threadgroup]],                             \
+      uint tg_size [[threads_per_threadgroup]],                                \
+      uint lane [[thread_index_in_simdgroup]],                                 \
+      uint simd_id [[simdgroup_index_in_threadgroup]]);
+
+instantiate_fused_add_rms_norm(float)
+#if defined(__HAVE_BFLOAT__)
+instantiate_fused_add_rms_norm(bfloat)
+#endif
+instantiate_fused_add_rms_norm(half)
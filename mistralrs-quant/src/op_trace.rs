@@ -0,0 +1,105 @@
+//! Opt-in trace of the major ops (matmuls, norms, attention, RoPE) run during a forward pass.
+//!
+//! Ops are collected on the current thread while inside [`trace_ops`]. Outside of it, [`record`] only
+//! checks two flags. Ops which run inside another traced op, like the matmuls of attention, are part
+//! of it and are not recorded.
+
+use std::cell::{Cell, RefCell};
+
+use candle_core::{DType, Tensor};
+
+thread_local! {
+    static TRACE: RefCell<Option<Vec<TracedOp>>> = const { RefCell::new(None) };
+    /// Number of traced ops running on this thread.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Shape and dtype of an op input or output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TensorInfo {
+    pub shape: Vec<usize>,
+    pub dtype: DType,
+}
+
+impl TensorInfo {
+    fn of(xs: &Tensor) -> Self {
+        Self {
+            shape: xs.dims().to_vec(),
+            dtype: xs.dtype(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TracedOp {
+    pub op: &'static str,
+    pub inputs: Vec<TensorInfo>,
+    pub outputs: Vec<TensorInfo>,
+}
+
+/// Run `f`, returning the ops it ran on this thread in order.
+pub fn trace_ops<T>(f: impl FnOnce() -> T) -> (T, Vec<TracedOp>) {
+    let outer = TRACE.with(|trace| trace.borrow_mut().replace(Vec::new()));
+    let res = f();
+    let ops = TRACE.with(|trace| std::mem::replace(&mut *trace.borrow_mut(), outer));
+    (res, ops.unwrap_or_default())
+}
+
+/// Decrements [`DEPTH`] when the body of a traced op is done, even if it panicked.
+struct OpBody;
+
+impl Drop for OpBody {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Run `f`, the body of a traced op. Ops recorded inside it belong to that op and are not recorded
+/// themselves. Call [`record`] for the op after `f` returned.
+pub fn run_op<T>(f: impl FnOnce() -> T) -> T {
+    DEPTH.with(|depth| depth.set(depth.get() + 1));
+    let _body = OpBody;
+    f()
+}
+
+/// Record that `op` ran on `inputs`, producing `outputs`.
+pub fn record(op: &'static str, inputs: &[&Tensor], outputs: &[&Tensor]) {
+    if DEPTH.with(Cell::get) > 0 {
+        return;
+    }
+    TRACE.with(|trace| {
+        if let Some(ops) = trace.borrow_mut().as_mut() {
+            ops.push(TracedOp {
+                op,
+                inputs: inputs.iter().map(|xs| TensorInfo::of(xs)).collect(),
+                outputs: outputs.iter().map(|xs| TensorInfo::of(xs)).collect(),
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{record, run_op, trace_ops};
+
+    #[test]
+    fn ops_inside_a_traced_op_are_not_recorded() -> candle_core::Result<()> {
+        let x = Tensor::zeros((2, 3), DType::F32, &Device::Cpu)?;
+        let ((), ops) = trace_ops(|| {
+            let out = run_op(|| {
+                record("matmul", &[&x], &[&x]);
+                run_op(|| record("matmul", &[&x], &[&x]));
+                x.clone()
+            });
+            record("attention", &[&x], &[&out]);
+            record("matmul", &[&x], &[&x]);
+        });
+        assert_eq!(
+            ops.iter().map(|op| op.op).collect::<Vec<_>>(),
+            ["attention", "matmul"]
+        );
+        Ok(())
+    }
+}
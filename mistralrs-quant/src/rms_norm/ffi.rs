@@ -0,0 +1,34 @@
+use std::ffi::c_void;
+
+extern "C" {
+    pub(crate) fn mq_fused_add_rms_norm_f32(
+        x: *const c_void,
+        residual: *const c_void,
+        weight: *const c_void,
+        sum: *mut c_void,
+        normed: *mut c_void,
+        rows: i32,
+        hidden: i32,
+        eps: f32,
+    );
+    pub(crate) fn mq_fused_add_rms_norm_f16(
+        x: *const c_void,
+        residual: *const c_void,
+        weight: *const c_void,
+        sum: *mut c_void,
+        normed: *mut c_void,
+        rows: i32,
+        hidden: i32,
+        eps: f32,
+    );
+    pub(crate) fn mq_fused_add_rms_norm_bf16(
+        x: *const c_void,
+        residual: *const c_void,
+        weight: *const c_void,
+        sum: *mut c_void,
+        normed: *mut c_void,
+        rows: i32,
+        hidden: i32,
+        eps: f32,
+    );
+}
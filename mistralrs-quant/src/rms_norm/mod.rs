@@ -0,0 +1,217 @@
+use candle_core::{CpuStorage, CustomOp3, DType, Device, Layout, Result, Shape, Tensor};
+
+#[cfg(feature = "cuda")]
+mod ffi;
+
+/// `x + residual` and its RmsNorm in one kernel. The output holds the sum followed by the normed
+/// sum, so that both come from a single allocation.
+struct FusedAddRmsNorm {
+    eps: f32,
+}
+
+impl FusedAddRmsNorm {
+    /// The number of rows and the row size, checking that the inputs are contiguous and agree.
+    fn dims(&self, x_l: &Layout, residual_l: &Layout, weight_l: &Layout) -> Result<(usize, usize)> {
+        if !(x_l.is_contiguous() && residual_l.is_contiguous() && weight_l.is_contiguous()) {
+            candle_core::bail!("fused-add-rms-norm expects contiguous inputs");
+        }
+        if x_l.shape() != residual_l.shape() {
+            candle_core::bail!(
+                "fused-add-rms-norm shape mismatch, x {:?} and residual {:?}",
+                x_l.shape(),
+                residual_l.shape()
+            );
+        }
+        let hidden = x_l.dims().last().copied().unwrap_or(1);
+        if weight_l.dims() != [hidden] {
+            candle_core::bail!(
+                "fused-add-rms-norm expects a weight of shape ({hidden},), got {:?}",
+                weight_l.shape()
+            );
+        }
+        Ok((x_l.shape().elem_count() / hidden, hidden))
+    }
+}
+
+impl CustomOp3 for FusedAddRmsNorm {
+    fn name(&self) -> &'static str {
+        "fused-add-rms-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle_core::bail!("fused-add-rms-norm has no CPU kernel, add and normalize instead")
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        x: &candle_core::CudaStorage,
+        x_l: &Layout,
+        residual: &candle_core::CudaStorage,
+        residual_l: &Layout,
+        weight: &candle_core::CudaStorage,
+        weight_l: &Layout,
+    ) -> Result<(candle_core::CudaStorage, Shape)> {
+        use candle_core::backend::BackendStorage;
+        use candle_core::cuda::cudarc::driver::DevicePtr;
+        use candle_core::cuda::{CudaStorage, WrapErr};
+        use half::{bf16, f16};
+        use std::ffi::c_void;
+
+        let (rows, hidden) = self.dims(x_l, residual_l, weight_l)?;
+        let n = rows * hidden;
+        let dev = x.device().clone();
+
+        macro_rules! launch {
+            ($ty:ty, $kernel:ident) => {{
+                let x = x.as_cuda_slice::<$ty>()?.slice(x_l.start_offset()..);
+                let residual = residual
+                    .as_cuda_slice::<$ty>()?
+                    .slice(residual_l.start_offset()..);
+                let weight = weight
+                    .as_cuda_slice::<$ty>()?
+                    .slice(weight_l.start_offset()..);
+                let out = unsafe { dev.alloc::<$ty>(2 * n) }.w()?;
+                let sum_ptr = *out.device_ptr() as *mut c_void;
+                let normed_ptr = *out.slice(n..).device_ptr() as *mut c_void;
+                unsafe {
+                    ffi::$kernel(
+                        *x.device_ptr() as *const c_void,
+                        *residual.device_ptr() as *const c_void,
+                        *weight.device_ptr() as *const c_void,
+                        sum_ptr,
+                        normed_ptr,
+                        i32::try_from(rows)?,
+                        i32::try_from(hidden)?,
+                        self.eps,
+                    )
+                };
+                CudaStorage::wrap_cuda_slice(out, dev)
+            }};
+        }
+
+        let out = match x.dtype() {
+            DType::F32 => launch!(f32, mq_fused_add_rms_norm_f32),
+            DType::F16 => launch!(f16, mq_fused_add_rms_norm_f16),
+            DType::BF16 => launch!(bf16, mq_fused_add_rms_norm_bf16),
+            other => candle_core::bail!("fused-add-rms-norm does not support {other:?}"),
+        };
+        Ok((out, Shape::from_dims(&[2, rows, hidden])))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        x: &candle_core::MetalStorage,
+        x_l: &Layout,
+        residual: &candle_core::MetalStorage,
+        residual_l: &Layout,
+        weight: &candle_core::MetalStorage,
+        weight_l: &Layout,
+    ) -> Result<(candle_core::MetalStorage, Shape)> {
+        use candle_core::backend::BackendStorage;
+
+        let (rows, hidden) = self.dims(x_l, residual_l, weight_l)?;
+        let n = rows * hidden;
+        let dtype = x.dtype();
+        let size = dtype.size_in_bytes();
+        let device = x.device();
+
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label("fused-add-rms-norm");
+
+        let output = device.new_buffer(2 * n, dtype, "fused-add-rms-norm")?;
+        crate::metal_kernels::call_fused_add_rms_norm(
+            device.device(),
+            &command_buffer,
+            &crate::metal_kernels::Kernels::new(),
+            dtype,
+            (x.buffer(), x_l.start_offset() * size),
+            (residual.buffer(), residual_l.start_offset() * size),
+            (weight.buffer(), weight_l.start_offset() * size),
+            (&output, 0),
+            (&output, n * size),
+            u32::try_from(rows)?,
+            u32::try_from(hidden)?,
+            self.eps,
+        )
+        .map_err(candle_core::Error::wrap)?;
+
+        let out = candle_core::MetalStorage::new(output, device.clone(), 2 * n, dtype);
+        Ok((out, Shape::from_dims(&[2, rows, hidden])))
+    }
+}
+
+/// Whether [`fused_add_rms_norm`] has a kernel for `device`.
+pub fn has_fused_add_rms_norm(device: &Device) -> bool {
+    device.is_cuda() || device.is_metal()
+}
+
+/// Compute `x + residual` and its RmsNorm with `weight` over the last dim in one kernel, returning
+/// `(sum, normed)`. The sum is rounded to the input dtype before it is normalized, so the result
+/// matches an add followed by `candle_nn::ops::rms_norm`.
+///
+/// `x`, `residual` and `weight` must share a dtype of f32, f16 or bf16. Only CUDA and Metal have
+/// the kernel, see [`has_fused_add_rms_norm`].
+pub fn fused_add_rms_norm(
+    x: &Tensor,
+    residual: &Tensor,
+    weight: &Tensor,
+    eps: f32,
+) -> Result<(Tensor, Tensor)> {
+    let out = x.contiguous()?.apply_op3_no_bwd(
+        &residual.contiguous()?,
+        &weight.contiguous()?,
+        &FusedAddRmsNorm { eps },
+    )?;
+    let sum = out.get(0)?.reshape(x.shape())?;
+    let normed = out.get(1)?.reshape(x.shape())?;
+    Ok((sum, normed))
+}
+
+#[cfg(all(test, any(feature = "cuda", feature = "metal")))]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    #[test]
+    fn fused_add_rms_norm_matches_add_then_norm() -> candle_core::Result<()> {
+        #[cfg(feature = "cuda")]
+        let dev = Device::new_cuda(0)?;
+        #[cfg(not(feature = "cuda"))]
+        let dev = Device::new_metal(0)?;
+
+        // Row sizes which are not a multiple of 32, one of them larger than a block.
+        for hidden in [48, 2050] {
+            for dtype in [DType::F32, DType::F16, DType::BF16] {
+                let x = Tensor::randn(0f32, 1., (2, 3, hidden), &dev)?.to_dtype(dtype)?;
+                let residual = Tensor::randn(0f32, 1., (2, 3, hidden), &dev)?.to_dtype(dtype)?;
+                let weight = Tensor::randn(0f32, 1., hidden, &dev)?.to_dtype(dtype)?;
+
+                let (sum, normed) = super::fused_add_rms_norm(&x, &residual, &weight, 1e-5)?;
+                let expected_sum = (&x + &residual)?;
+                let expected_normed = candle_nn::ops::rms_norm(&expected_sum, &weight, 1e-5)?;
+                let diff = |a: &Tensor, b: &Tensor| -> candle_core::Result<f32> {
+                    (a - b)?
+                        .abs()?
+                        .to_dtype(DType::F32)?
+                        .flatten_all()?
+                        .max(0)?
+                        .to_scalar::<f32>()
+                };
+                assert_eq!(diff(&sum, &expected_sum)?, 0., "{dtype:?} {hidden}");
+                let tol = if dtype == DType::F32 { 1e-5 } else { 5e-2 };
+                let err = diff(&normed, &expected_normed)?;
+                assert!(err < tol, "{dtype:?} {hidden}: {err}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use std::{
     borrow::Cow,
     io::Cursor,
-    sync::{atomic::AtomicUsize, Arc},
+    sync::{atomic::AtomicUsize, Arc, Mutex},
 };
 
 use byteorder::{LittleEndian, ReadBytesExt};
@@ -10,11 +10,12 @@ use candle_nn::Linear;
 
 use crate::{
     cublaslt::{maybe_init_cublas_lt_wrapper, CUBLASLT_HANDLE},
-    generate_isq, generate_isq_imatrix,
+    generate_isq, generate_isq_imatrix, get_strict_determinism,
     hqq::{HqqAxis, HqqBits, HqqConfig, HqqLayer, ISQ_HQQ_DEFAULT_OPT_STEPS, ISQ_HQQ_GROUP_SIZE},
     utils::{deserialize_tensor, serialize_tensor, version_is_compatible, UQFF_VERSION},
     AfqBits, AfqGroupSize, AfqLayer, FP8Linear, GgufMatMul, ImatrixLayerStats, IsqType, MatMul,
-    QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde, QuantizedSerdeType,
+    QuantInfo, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde,
+    QuantizedSerdeType,
 };
 
 #[derive(Debug)]
@@ -22,6 +23,27 @@ pub struct UnquantLinear {
     w: Tensor,
     b: Option<Tensor>,
     stats: Option<ImatrixLayerStats>,
+    /// `w` and `b` cast to the last activation dtype which differed from `w`.
+    cast: Mutex<Option<(Tensor, Option<Tensor>)>>,
+}
+
+impl UnquantLinear {
+    /// The weight and bias in `dtype`. A global `MatMulPrecision` makes every forward ask for the
+    /// same dtype, so the cast is done once and cached.
+    fn weights_in(&self, dtype: DType) -> Result<(Tensor, Option<Tensor>)> {
+        let cast_b = |b: Option<&Tensor>| b.map(|b| b.to_dtype(dtype)).transpose();
+        if dtype == self.w.dtype() {
+            return Ok((self.w.clone(), cast_b(self.b.as_ref())?));
+        }
+        let mut cast = self.cast.lock().unwrap();
+        if let Some((w, b)) = cast.as_ref().filter(|(w, _)| w.dtype() == dtype) {
+            return Ok((w.clone(), b.clone()));
+        }
+        let w = self.w.to_dtype(dtype)?;
+        let b = cast_b(self.b.as_ref())?;
+        *cast = Some((w.clone(), b.clone()));
+        Ok((w, b))
+    }
 }
 
 impl QuantMethod for UnquantLinear {
@@ -37,11 +59,13 @@ impl QuantMethod for UnquantLinear {
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
-            | QuantMethodConfig::Afq { .. } => unreachable!(),
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Exl2 { .. } => unreachable!(),
             QuantMethodConfig::Unquantized(l) => Ok(Self {
                 w: l.weight().clone(),
                 b: l.bias().cloned(),
                 stats: None,
+                cast: Mutex::new(None),
             }),
         }
     }
@@ -54,17 +78,28 @@ impl QuantMethod for UnquantLinear {
         // Batch matrix multiplication
         maybe_init_cublas_lt_wrapper(a.device().clone());
 
+        // Under a global `MatMulPrecision` the activation may arrive in another dtype.
+        let (w, b) = self.weights_in(a.dtype())?;
         let w = match *a.dims() {
-            [b1, b2, _, _] => self.w.broadcast_left((b1, b2))?,
-            [bsize, _, _] => self.w.broadcast_left(bsize)?,
-            _ => self.w.clone(),
+            [b1, b2, _, _] => w.broadcast_left((b1, b2))?,
+            [bsize, _, _] => w.broadcast_left(bsize)?,
+            _ => w,
         };
 
         if let Some(stats) = &self.stats {
             stats.process(a)?;
         }
 
-        if let Some(b) = self.b.as_ref() {
+        if get_strict_determinism() {
+            // The fused bias GEMMs may accumulate differently, so keep to the f32 `MatMul`.
+            let out = MatMul.matmul(a, &w.t()?)?;
+            return match &b {
+                Some(b) => out.broadcast_add(b),
+                None => Ok(out),
+            };
+        }
+
+        if let Some(b) = b {
             let mut tgt_shape = a.dims().to_vec();
             tgt_shape[a.dims().len() - 1] = w.dim(D::Minus2)?;
             let b = b.broadcast_as(Shape::from_dims(&tgt_shape))?;
@@ -144,6 +179,7 @@ impl QuantMethod for UnquantLinear {
             w: (&self.w + delta)?,
             b: self.b.clone(),
             stats: self.stats.clone(),
+            cast: Mutex::new(None),
         }))
     }
 
@@ -300,6 +336,14 @@ impl QuantMethod for UnquantLinear {
             candle_core::bail!("`{}` does not support tracking stats.", self.name())
         }
     }
+
+    fn quant_info(&self) -> QuantInfo {
+        QuantInfo {
+            method: self.name(),
+            bits: Some(self.w.dtype().size_in_bytes() * 8),
+            group_size: None,
+        }
+    }
 }
 
 // Serialization structure:
@@ -385,7 +429,12 @@ impl QuantizedSerde for UnquantLinear {
             None
         };
 
-        Ok(Arc::new(Self { w, b, stats: None }))
+        Ok(Arc::new(Self {
+            w,
+            b,
+            stats: None,
+            cast: Mutex::new(None),
+        }))
     }
     fn deserialize_ext_bias(
         data: Cow<[u8]>,
@@ -426,6 +475,7 @@ impl QuantizedSerde for UnquantLinear {
                 w,
                 b: None,
                 stats: None,
+                cast: Mutex::new(None),
             }),
             b,
         ))
@@ -386,6 +386,15 @@ async fn parse_request(
                 top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                penalty_scope: Default::default(),
+                stream_top_k_logprobs: None,
+                reasoning_budget: None,
+                token_healing: false,
+                return_entropy: false,
+                temperature_order: Default::default(),
+                penalty_decay: None,
+                eos_separates_documents: false,
+                repetition_loop: None,
                 max_len: oairequest.max_tokens,
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
@@ -402,6 +411,10 @@ async fn parse_request(
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: oairequest.web_search_options,
+            tool_call_trigger: None,
+            stream_raw_bytes: false,
+            stream_flush_policy: oairequest.stream_flush_policy.unwrap_or_default(),
+            kv_cache_limit: oairequest.kv_cache_limit,
         }),
         is_streaming,
     ))
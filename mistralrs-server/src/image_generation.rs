@@ -83,6 +83,10 @@ fn parse_request(
         logits_processors: None,
         return_raw_logits: false,
         web_search_options: None,
+        tool_call_trigger: None,
+        stream_raw_bytes: false,
+        stream_flush_policy: Default::default(),
+        kv_cache_limit: None,
     }))
 }
 
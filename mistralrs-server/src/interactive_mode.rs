@@ -93,6 +93,15 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool, do_s
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        penalty_scope: Default::default(),
+        stream_top_k_logprobs: None,
+        reasoning_budget: None,
+        token_healing: false,
+        return_entropy: false,
+        temperature_order: Default::default(),
+        penalty_decay: None,
+        eos_separates_documents: false,
+        repetition_loop: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
@@ -180,6 +189,10 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool, do_s
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: do_search.then(WebSearchOptions::default),
+            tool_call_trigger: None,
+            stream_raw_bytes: false,
+            stream_flush_policy: Default::default(),
+            kv_cache_limit: None,
         });
         sender.send(req).await.unwrap();
 
@@ -296,6 +309,15 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool, do
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        penalty_scope: Default::default(),
+        stream_top_k_logprobs: None,
+        reasoning_budget: None,
+        token_healing: false,
+        return_entropy: false,
+        temperature_order: Default::default(),
+        penalty_decay: None,
+        eos_separates_documents: false,
+        repetition_loop: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
@@ -412,6 +434,10 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool, do
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: do_search.then(WebSearchOptions::default),
+            tool_call_trigger: None,
+            stream_raw_bytes: false,
+            stream_flush_policy: Default::default(),
+            kv_cache_limit: None,
         });
         sender.send(req).await.unwrap();
 
@@ -546,6 +572,10 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>, do_search: bool)
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: do_search.then(WebSearchOptions::default),
+            tool_call_trigger: None,
+            stream_raw_bytes: false,
+            stream_flush_policy: Default::default(),
+            kv_cache_limit: None,
         });
 
         let start = Instant::now();
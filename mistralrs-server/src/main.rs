@@ -11,15 +11,15 @@ use mistralrs_core::{
     get_auto_device_map_params, get_model_dtype, get_tgt_non_granular_index, initialize_logging,
     paged_attn_supported, parse_isq_value, BertEmbeddingModel, DefaultSchedulerMethod,
     DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, IsqType, Loader, LoaderBuilder,
-    MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected, PagedAttentionConfig, Request,
-    SchedulerConfig, TokenSource,
+    MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected, PagedAttentionConfig,
+    PromptChunksize, Request, SchedulerConfig, TokenSource,
 };
 use openai::{
     ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, Message, ModelObjects,
     StopTokens,
 };
 use serde::{Deserialize, Serialize};
-use std::{num::NonZeroUsize, sync::Arc};
+use std::sync::Arc;
 
 mod chat_completion;
 mod completions;
@@ -74,6 +74,14 @@ struct Args {
     #[clap(long, short, action)]
     truncate_sequence: bool,
 
+    /// Reject requests whose raw input text has more than this many characters, before tokenizing them.
+    #[arg(long)]
+    max_input_chars: Option<usize>,
+
+    /// Reject requests whose raw input text has more than this many bytes, before tokenizing them.
+    #[arg(long)]
+    max_input_bytes: Option<usize>,
+
     /// Model selector
     #[clap(subcommand)]
     model: ModelSelected,
@@ -158,8 +166,9 @@ struct Args {
     throughput_log: bool,
 
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
+    /// Use `auto` to choose it from the free device memory at load time (plain models only).
     #[arg(long = "prompt-batchsize")]
-    prompt_chunksize: Option<usize>,
+    prompt_chunksize: Option<PromptChunksize>,
 
     /// Use CPU only
     #[arg(long)]
@@ -285,21 +294,13 @@ async fn main() -> Result<()> {
         args.max_seqs = 1;
     }
 
-    let prompt_chunksize = match args.prompt_chunksize {
-        Some(0) => {
-            anyhow::bail!("`prompt_chunksize` must be a strictly positive integer, got 0.",)
-        }
-        Some(x) => Some(NonZeroUsize::new(x).unwrap()),
-        None => None,
-    };
-
     let max_seq_len = auto_device_map_params.max_seq_len();
 
     let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
         .with_no_kv_cache(args.no_kv_cache)
         .with_chat_template(args.chat_template)
         .with_use_flash_attn(use_flash_attn)
-        .with_prompt_chunksize(prompt_chunksize)
+        .with_prompt_chunksize(args.prompt_chunksize)
         .with_jinja_explicit(args.jinja_explicit)
         .build()?;
 
@@ -475,7 +476,7 @@ async fn main() -> Result<()> {
         None
     };
     // Throughput logging in the server
-    let mistralrs = MistralRsBuilder::new(
+    let mut builder = MistralRsBuilder::new(
         pipeline,
         scheduler_config,
         !args.interactive_mode,
@@ -484,8 +485,14 @@ async fn main() -> Result<()> {
     .with_opt_log(args.log)
     .with_truncate_sequence(args.truncate_sequence)
     .with_no_kv_cache(args.no_kv_cache)
-    .with_prefix_cache_n(args.prefix_cache_n)
-    .build();
+    .with_prefix_cache_n(args.prefix_cache_n);
+    if let Some(max_input_chars) = args.max_input_chars {
+        builder = builder.with_max_input_chars(max_input_chars);
+    }
+    if let Some(max_input_bytes) = args.max_input_bytes {
+        builder = builder.with_max_input_bytes(max_input_bytes);
+    }
+    let mistralrs = builder.build();
 
     if args.interactive_mode {
         interactive_mode(mistralrs, args.throughput_log, args.interactive_search).await;
@@ -1,6 +1,7 @@
 use either::Either;
 use mistralrs_core::{
-    ImageGenerationResponseFormat, LlguidanceGrammar, Tool, ToolChoice, ToolType, WebSearchOptions,
+    ImageGenerationResponseFormat, KvCacheLimit, LlguidanceGrammar, StreamFlushPolicy, Tool,
+    ToolChoice, ToolType, WebSearchOptions,
 };
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, ops::Deref};
@@ -173,6 +174,14 @@ pub struct ChatCompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    /// When streaming, where the text is cut into chunks: `"token"`, `"word"`, `"sentence"` or
+    /// `{"tokens": n}`. Defaults to every two tokens.
+    #[schema(example = json!(Option::None::<StreamFlushPolicy>))]
+    pub stream_flush_policy: Option<StreamFlushPolicy>,
+    /// Bound the number of tokens held in the KV cache of this request:
+    /// `{"max_tokens": n, "overflow": "error" | {"evict_sink_window": {"sink_tokens": s}}}`.
+    #[schema(example = json!(Option::None::<KvCacheLimit>))]
+    pub kv_cache_limit: Option<KvCacheLimit>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -248,6 +257,14 @@ pub struct CompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    /// When streaming, where the text is cut into chunks: `"token"`, `"word"`, `"sentence"` or
+    /// `{"tokens": n}`. Defaults to every two tokens.
+    #[schema(example = json!(Option::None::<StreamFlushPolicy>))]
+    pub stream_flush_policy: Option<StreamFlushPolicy>,
+    /// Bound the number of tokens held in the KV cache of this request:
+    /// `{"max_tokens": n, "overflow": "error" | {"evict_sink_window": {"sink_tokens": s}}}`.
+    #[schema(example = json!(Option::None::<KvCacheLimit>))]
+    pub kv_cache_limit: Option<KvCacheLimit>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
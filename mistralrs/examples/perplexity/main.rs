@@ -50,6 +50,10 @@ async fn process_chunk(runner: &MistralRs, chunk: Vec<u32>) -> anyhow::Result<(T
         logits_processors: None,
         return_raw_logits: true,
         web_search_options: None,
+        tool_call_trigger: None,
+        stream_raw_bytes: false,
+        stream_flush_policy: Default::default(),
+        kv_cache_limit: None,
     });
 
     runner.get_sender()?.send(request).await?;
@@ -15,6 +15,7 @@ async fn main() -> Result<()> {
                     LayerTopology {
                         isq: Some(IsqType::Q3K),
                         device: None,
+                        kv_cache: None,
                     },
                 )
                 .with_range(
@@ -22,6 +23,7 @@ async fn main() -> Result<()> {
                     LayerTopology {
                         isq: Some(IsqType::Q4K),
                         device: None,
+                        kv_cache: None,
                     },
                 )
                 .with_range(
@@ -29,6 +31,7 @@ async fn main() -> Result<()> {
                     LayerTopology {
                         isq: Some(IsqType::Q6K),
                         device: None,
+                        kv_cache: None,
                     },
                 )
                 .with_range(
@@ -36,6 +39,7 @@ async fn main() -> Result<()> {
                     LayerTopology {
                         isq: Some(IsqType::Q8_0),
                         device: None,
+                        kv_cache: None,
                     },
                 ),
         )
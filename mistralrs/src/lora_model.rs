@@ -29,10 +29,27 @@ impl LoraModelBuilder {
             topology: self.text_model.topology,
             organization: self.text_model.organization,
             write_uqff: self.text_model.write_uqff,
+            uqff_shard_size_mb: self.text_model.uqff_shard_size_mb,
             from_uqff: self.text_model.from_uqff,
             imatrix: None,
             calibration_file: None,
             hf_cache_path: self.text_model.hf_cache_path,
+            activation_override: None,
+            prefill_dtype: None,
+            split_kv_segment_len: None,
+            split_kv_accumulation: Default::default(),
+            embedding_dtype: None,
+            lm_head_dtype: None,
+            f32_norm_weights: false,
+            isq_overrides: Vec::new(),
+            pruned_heads: Default::default(),
+            attention_head_scales: Default::default(),
+            head_dim_alignment: None,
+            max_cached_rope_positions: None,
+            offload_activations: false,
+            share_rope_tables: false,
+            lazy_rope_tables: false,
+            shard_download_retries: self.text_model.shard_download_retries,
         };
 
         if self.text_model.with_logging {
@@ -17,6 +17,8 @@ pub trait RequestLike {
     fn take_tools(&mut self) -> Option<(Vec<Tool>, ToolChoice)>;
     fn take_sampling_params(&mut self) -> SamplingParams;
     fn take_web_search_options(&mut self) -> Option<WebSearchOptions>;
+    fn stream_flush_policy(&self) -> StreamFlushPolicy;
+    fn kv_cache_limit(&self) -> Option<KvCacheLimit>;
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -109,6 +111,12 @@ impl RequestLike for TextMessages {
     fn take_web_search_options(&mut self) -> Option<WebSearchOptions> {
         None
     }
+    fn stream_flush_policy(&self) -> StreamFlushPolicy {
+        StreamFlushPolicy::default()
+    }
+    fn kv_cache_limit(&self) -> Option<KvCacheLimit> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -225,6 +233,12 @@ impl RequestLike for VisionMessages {
     fn take_web_search_options(&mut self) -> Option<WebSearchOptions> {
         None
     }
+    fn stream_flush_policy(&self) -> StreamFlushPolicy {
+        StreamFlushPolicy::default()
+    }
+    fn kv_cache_limit(&self) -> Option<KvCacheLimit> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -247,6 +261,8 @@ pub struct RequestBuilder {
     tool_choice: ToolChoice,
     sampling_params: SamplingParams,
     web_search_options: Option<WebSearchOptions>,
+    stream_flush_policy: StreamFlushPolicy,
+    kv_cache_limit: Option<KvCacheLimit>,
 }
 
 impl Default for RequestBuilder {
@@ -268,6 +284,8 @@ impl From<TextMessages> for RequestBuilder {
             tool_choice: ToolChoice::Auto,
             sampling_params: SamplingParams::deterministic(),
             web_search_options: None,
+            stream_flush_policy: StreamFlushPolicy::default(),
+            kv_cache_limit: None,
         }
     }
 }
@@ -285,6 +303,8 @@ impl From<VisionMessages> for RequestBuilder {
             tool_choice: ToolChoice::Auto,
             sampling_params: SamplingParams::deterministic(),
             web_search_options: None,
+            stream_flush_policy: StreamFlushPolicy::default(),
+            kv_cache_limit: None,
         }
     }
 }
@@ -302,6 +322,8 @@ impl RequestBuilder {
             tool_choice: ToolChoice::Auto,
             sampling_params: SamplingParams::deterministic(),
             web_search_options: None,
+            stream_flush_policy: StreamFlushPolicy::default(),
+            kv_cache_limit: None,
         }
     }
 
@@ -310,6 +332,18 @@ impl RequestBuilder {
         self
     }
 
+    /// When streaming, where the generated text is cut into chunks.
+    pub fn set_stream_flush_policy(mut self, policy: StreamFlushPolicy) -> Self {
+        self.stream_flush_policy = policy;
+        self
+    }
+
+    /// Bound the number of tokens held in the KV cache of this request, for long sessions.
+    pub fn set_kv_cache_limit(mut self, limit: KvCacheLimit) -> Self {
+        self.kv_cache_limit = Some(limit);
+        self
+    }
+
     /// Add a message to the request.
     ///
     /// For messages with tool calls, use [`Self::add_message_with_tool_call`].
@@ -467,6 +501,71 @@ impl RequestBuilder {
         self
     }
 
+    /// Which tokens the frequency, presence and DRY penalties are computed over.
+    pub fn set_sampler_penalty_scope(mut self, penalty_scope: PenaltyScope) -> Self {
+        self.sampling_params.penalty_scope = penalty_scope;
+        self
+    }
+
+    /// Stream the logprobs of the `k` most likely tokens at each step.
+    pub fn set_sampler_stream_top_k_logprobs(mut self, k: usize) -> Self {
+        self.sampling_params.stream_top_k_logprobs = Some(k);
+        self
+    }
+
+    /// Report the entropy and varentropy of the processed distribution at each step.
+    pub fn set_sampler_entropy(mut self, return_entropy: bool) -> Self {
+        self.sampling_params.return_entropy = return_entropy;
+        self
+    }
+
+    /// Apply the temperature before or after the frequency, presence and DRY penalties.
+    pub fn set_sampler_temperature_order(mut self, temperature_order: TemperatureOrder) -> Self {
+        self.sampling_params.temperature_order = temperature_order;
+        self
+    }
+
+    /// Weight each occurrence of a token in the frequency and presence penalties by `decay^distance`.
+    pub fn set_sampler_penalty_decay(mut self, decay: f32) -> Self {
+        self.sampling_params.penalty_decay = Some(decay);
+        self
+    }
+
+    /// Treat an EOS token within the prompt as a document separator, so that the penalties only count
+    /// the tokens after it.
+    pub fn set_sampler_eos_separates_documents(mut self, eos_separates_documents: bool) -> Self {
+        self.sampling_params.eos_separates_documents = eos_separates_documents;
+        self
+    }
+
+    /// Stop with the `repetition_loop` reason once the output ends with the same run of at most
+    /// `max_pattern_len` tokens repeated `min_repeats` times.
+    pub fn set_sampler_repetition_loop(
+        mut self,
+        max_pattern_len: usize,
+        min_repeats: usize,
+    ) -> Self {
+        self.sampling_params.repetition_loop = Some(RepetitionLoopParams {
+            max_pattern_len,
+            min_repeats,
+        });
+        self
+    }
+
+    /// Force the model's reasoning end token, such as `</think>`, once `budget` tokens were generated
+    /// without the model closing its reasoning.
+    pub fn set_sampler_reasoning_budget(mut self, budget: usize) -> Self {
+        self.sampling_params.reasoning_budget = Some(budget);
+        self
+    }
+
+    /// Back the prompt up by its last token and constrain the first generated token to start with that
+    /// token's text, for prompts which end mid-word.
+    pub fn set_sampler_token_healing(mut self, token_healing: bool) -> Self {
+        self.sampling_params.token_healing = token_healing;
+        self
+    }
+
     pub fn set_sampler_stop_toks(mut self, stop_toks: StopTokens) -> Self {
         self.sampling_params.stop_toks = Some(stop_toks);
         self
@@ -568,4 +667,12 @@ impl RequestLike for RequestBuilder {
         std::mem::swap(&mut other, &mut self.web_search_options);
         other
     }
+
+    fn stream_flush_policy(&self) -> StreamFlushPolicy {
+        self.stream_flush_policy
+    }
+
+    fn kv_cache_limit(&self) -> Option<KvCacheLimit> {
+        self.kv_cache_limit
+    }
 }
@@ -89,6 +89,10 @@ impl Model {
             logits_processors: request.take_logits_processors(),
             return_raw_logits: false,
             web_search_options: request.take_web_search_options(),
+            tool_call_trigger: None,
+            stream_raw_bytes: false,
+            stream_flush_policy: request.stream_flush_policy(),
+            kv_cache_limit: request.kv_cache_limit(),
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -124,6 +128,10 @@ impl Model {
             logits_processors: request.take_logits_processors(),
             return_raw_logits: false,
             web_search_options: request.take_web_search_options(),
+            tool_call_trigger: None,
+            stream_raw_bytes: false,
+            stream_flush_policy: request.stream_flush_policy(),
+            kv_cache_limit: request.kv_cache_limit(),
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -168,6 +176,10 @@ impl Model {
             logits_processors: request.take_logits_processors(),
             return_raw_logits: true,
             web_search_options: request.take_web_search_options(),
+            tool_call_trigger: None,
+            stream_raw_bytes: false,
+            stream_flush_policy: request.stream_flush_policy(),
+            kv_cache_limit: request.kv_cache_limit(),
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -213,6 +225,10 @@ impl Model {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            tool_call_trigger: None,
+            stream_raw_bytes: false,
+            stream_flush_policy: Default::default(),
+            kv_cache_limit: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
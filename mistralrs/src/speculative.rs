@@ -45,10 +45,27 @@ impl TextSpeculativeBuilder {
             topology: builder.topology,
             organization: builder.organization,
             write_uqff: builder.write_uqff,
+            uqff_shard_size_mb: builder.uqff_shard_size_mb,
             from_uqff: builder.from_uqff,
             imatrix: builder.imatrix,
             calibration_file: builder.calibration_file,
             hf_cache_path: builder.hf_cache_path,
+            activation_override: None,
+            prefill_dtype: None,
+            split_kv_segment_len: None,
+            split_kv_accumulation: Default::default(),
+            embedding_dtype: None,
+            lm_head_dtype: None,
+            f32_norm_weights: false,
+            isq_overrides: Vec::new(),
+            pruned_heads: Default::default(),
+            attention_head_scales: Default::default(),
+            head_dim_alignment: None,
+            max_cached_rope_positions: None,
+            offload_activations: false,
+            share_rope_tables: false,
+            lazy_rope_tables: false,
+            shard_download_retries: builder.shard_download_retries,
         };
 
         if builder.with_logging {
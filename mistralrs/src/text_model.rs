@@ -1,5 +1,7 @@
+use candle_core::DType;
 use mistralrs_core::*;
 use std::{
+    collections::HashMap,
     num::NonZeroUsize,
     ops::{Deref, DerefMut},
     path::PathBuf,
@@ -15,6 +17,7 @@ pub struct TextModelBuilder {
     pub(crate) token_source: TokenSource,
     pub(crate) hf_revision: Option<String>,
     pub(crate) write_uqff: Option<PathBuf>,
+    pub(crate) uqff_shard_size_mb: Option<usize>,
     pub(crate) from_uqff: Option<Vec<PathBuf>>,
     pub(crate) imatrix: Option<PathBuf>,
     pub(crate) calibration_file: Option<PathBuf>,
@@ -27,7 +30,7 @@ pub struct TextModelBuilder {
 
     // Model running
     pub(crate) use_flash_attn: bool,
-    pub(crate) prompt_chunksize: Option<NonZeroUsize>,
+    pub(crate) prompt_chunksize: Option<PromptChunksize>,
     pub(crate) topology: Option<Topology>,
     pub(crate) organization: IsqOrganization,
     pub(crate) loader_type: Option<NormalLoaderType>,
@@ -35,6 +38,25 @@ pub struct TextModelBuilder {
     pub(crate) force_cpu: bool,
     pub(crate) isq: Option<IsqType>,
     pub(crate) throughput_logging: bool,
+    pub(crate) num_experts_per_tok: Option<usize>,
+    pub(crate) bos_tok_override: Option<u32>,
+    pub(crate) eos_toks_override: Option<Vec<u32>>,
+    pub(crate) activation_override: Option<layers::Activation>,
+    pub(crate) prefill_dtype: Option<DType>,
+    pub(crate) split_kv_segment_len: Option<usize>,
+    pub(crate) split_kv_accumulation: SplitKvAccumulation,
+    pub(crate) embedding_dtype: Option<DType>,
+    pub(crate) lm_head_dtype: Option<DType>,
+    pub(crate) f32_norm_weights: bool,
+    pub(crate) isq_overrides: Vec<(regex::Regex, IsqType)>,
+    pub(crate) pruned_heads: HashMap<usize, Vec<usize>>,
+    pub(crate) attention_head_scales: HashMap<usize, Vec<f32>>,
+    pub(crate) head_dim_alignment: Option<usize>,
+    pub(crate) max_cached_rope_positions: Option<usize>,
+    pub(crate) offload_activations: bool,
+    pub(crate) share_rope_tables: bool,
+    pub(crate) lazy_rope_tables: bool,
+    pub(crate) shard_download_retries: usize,
 
     // Other things
     pub(crate) paged_attn_cfg: Option<PagedAttentionConfig>,
@@ -93,6 +115,7 @@ impl TextModelBuilder {
             topology: None,
             organization: IsqOrganization::Default,
             write_uqff: None,
+            uqff_shard_size_mb: None,
             from_uqff: None,
             chat_template: None,
             tokenizer_json: None,
@@ -112,6 +135,25 @@ impl TextModelBuilder {
             calibration_file: None,
             jinja_explicit: None,
             throughput_logging: false,
+            num_experts_per_tok: None,
+            bos_tok_override: None,
+            eos_toks_override: None,
+            activation_override: None,
+            prefill_dtype: None,
+            split_kv_segment_len: None,
+            split_kv_accumulation: SplitKvAccumulation::F32,
+            embedding_dtype: None,
+            lm_head_dtype: None,
+            f32_norm_weights: false,
+            isq_overrides: Vec::new(),
+            pruned_heads: HashMap::new(),
+            attention_head_scales: HashMap::new(),
+            head_dim_alignment: None,
+            max_cached_rope_positions: None,
+            offload_activations: false,
+            share_rope_tables: false,
+            lazy_rope_tables: false,
+            shard_download_retries: 0,
             hf_cache_path: None,
             search_bert_model: None,
         }
@@ -137,7 +179,13 @@ impl TextModelBuilder {
 
     /// Set the prompt batchsize to use for inference.
     pub fn with_prompt_chunksize(mut self, prompt_chunksize: NonZeroUsize) -> Self {
-        self.prompt_chunksize = Some(prompt_chunksize);
+        self.prompt_chunksize = Some(prompt_chunksize.into());
+        self
+    }
+
+    /// Choose the prompt batchsize at load time from the device memory left after the weights.
+    pub fn with_auto_prompt_chunksize(mut self) -> Self {
+        self.prompt_chunksize = Some(PromptChunksize::Auto);
         self
     }
 
@@ -178,6 +226,19 @@ impl TextModelBuilder {
         self
     }
 
+    /// Override the number of experts selected per token for MoE models. Defaults to the model config.
+    pub fn with_num_experts_per_tok(mut self, num_experts_per_tok: usize) -> Self {
+        self.num_experts_per_tok = Some(num_experts_per_tok);
+        self
+    }
+
+    /// Override the BOS token id and the EOS token ids used to stop generation.
+    pub fn with_special_tokens(mut self, bos: Option<u32>, eos: Option<Vec<u32>>) -> Self {
+        self.bos_tok_override = bos;
+        self.eos_toks_override = eos;
+        self
+    }
+
     /// Force usage of the CPU device. Do not use PagedAttention with this.
     pub fn with_force_cpu(mut self) -> Self {
         self.force_cpu = true;
@@ -279,6 +340,127 @@ impl TextModelBuilder {
         self
     }
 
+    /// Maximum size in MB of each UQFF shard written with [`Self::write_uqff`]. Defaults to 10 GB.
+    pub fn with_uqff_shard_size_mb(mut self, shard_size_mb: usize) -> Self {
+        self.uqff_shard_size_mb = Some(shard_size_mb);
+        self
+    }
+
+    /// Replace the MLP activation of every layer, ignoring the one in the model config. This is
+    /// useful when porting a model whose config names the wrong activation.
+    pub fn with_activation_override(mut self, activation: layers::Activation) -> Self {
+        self.activation_override = Some(activation);
+        self
+    }
+
+    /// Run attention in this dtype during prefill, switching back to the model dtype for decode.
+    /// Use `DType::F32` for a more accurate first token on long prompts, at the cost of prefill speed.
+    pub fn with_prefill_dtype(mut self, dtype: DType) -> Self {
+        self.prefill_dtype = Some(dtype);
+        self
+    }
+
+    /// Without flash attention, process the KV in segments of `segment_len` tokens with an online
+    /// softmax, bounding the attention scores held at once on long contexts.
+    pub fn with_split_kv_segment_len(mut self, segment_len: usize) -> Self {
+        self.split_kv_segment_len = Some(segment_len);
+        self
+    }
+
+    /// How split-KV attention accumulates across the segments, f32 by default. `Native` and `Kahan`
+    /// accumulate in the model dtype, which is cheaper but less accurate on long contexts.
+    pub fn with_split_kv_accumulation(mut self, accumulation: SplitKvAccumulation) -> Self {
+        self.split_kv_accumulation = accumulation;
+        self
+    }
+
+    /// Look up the input embeddings in this dtype, casting them to the model dtype for the decoder
+    /// layers. Keeping the embeddings in `DType::F32` on a half precision model is a common recipe for quality.
+    /// Only Llama models support this; loading any other architecture fails.
+    pub fn with_embedding_dtype(mut self, dtype: DType) -> Self {
+        self.embedding_dtype = Some(dtype);
+        self
+    }
+
+    /// Run the LM head in this dtype, casting the final hidden states to it. Use `DType::F32` for
+    /// finer grained logits on a half precision model. The LM head must not be quantized. Only Llama
+    /// models support this; loading any other architecture fails.
+    pub fn with_lm_head_dtype(mut self, dtype: DType) -> Self {
+        self.lm_head_dtype = Some(dtype);
+        self
+    }
+
+    /// Load the RmsNorm weights in f32 even when the model is f16/bf16, running the norms in f32.
+    pub fn with_f32_norm_weights(mut self) -> Self {
+        self.f32_norm_weights = true;
+        self
+    }
+
+    /// Quantize the layers whose name matches `pattern` to `isq`, in preference to the topology and the
+    /// global ISQ type. Earlier overrides take precedence over later ones. Layers are named by their GGUF
+    /// tensor name (`blk.0.attn_q.weight`) if the model has one, otherwise by their index, and loading
+    /// fails if `pattern` matches no layer.
+    pub fn with_isq_override(mut self, pattern: &str, isq: IsqType) -> anyhow::Result<Self> {
+        self.isq_overrides.push((regex::Regex::new(pattern)?, isq));
+        Ok(self)
+    }
+
+    /// Remove the query heads `heads` from the attention of layer `layer`, slicing the projections to
+    /// the remaining heads. Query heads sharing a key/value head must be pruned together.
+    pub fn with_pruned_heads(mut self, layer: usize, heads: Vec<usize>) -> Self {
+        self.pruned_heads.entry(layer).or_default().extend(heads);
+        self
+    }
+
+    /// Scale the output of each attention head of layer `layer`, with one scale per query head
+    /// remaining after pruning. A scale of zero silences the head.
+    pub fn with_attention_head_scales(mut self, layer: usize, scales: Vec<f32>) -> Self {
+        self.attention_head_scales.insert(layer, scales);
+        self
+    }
+
+    /// Zero-pad the attention head dim up to a multiple of `alignment` at load time, for kernels
+    /// which are faster on aligned sizes. This does not change the outputs.
+    pub fn with_head_dim_alignment(mut self, alignment: usize) -> Self {
+        self.head_dim_alignment = Some(alignment);
+        self
+    }
+
+    /// Only precompute the RoPE tables for the first `positions` positions, extending them when a
+    /// longer sequence is seen. This saves memory for long context Phi 3 models.
+    pub fn with_max_cached_rope_positions(mut self, positions: usize) -> Self {
+        self.max_cached_rope_positions = Some(positions);
+        self
+    }
+
+    /// Hold the residual stream on the CPU between decoder layers, lowering the peak device memory
+    /// of long prefills at the cost of host transfers. The outputs are unchanged.
+    pub fn with_activation_offloading(mut self) -> Self {
+        self.offload_activations = true;
+        self
+    }
+
+    /// Share the rotary tables with other models built with this option and an identical RoPE config,
+    /// such as the members of an ensemble, instead of building them per model.
+    pub fn with_shared_rope_tables(mut self) -> Self {
+        self.share_rope_tables = true;
+        self
+    }
+
+    /// Build the rotary tables on the first forward, only up to the longest position seen so far,
+    /// rather than for the full context length at load.
+    pub fn with_lazy_rope_tables(mut self) -> Self {
+        self.lazy_rope_tables = true;
+        self
+    }
+
+    /// Download a safetensors shard from the Hugging Face Hub again, up to `retries` times, if the
+    /// cached copy is corrupt or truncated.
+    pub fn with_shard_download_retries(mut self, retries: usize) -> Self {
+        self.shard_download_retries = retries;
+        self
+    }
+
     /// Cache path for Hugging Face models downloaded locally
     pub fn from_hf_cache_pathf(mut self, hf_cache_path: PathBuf) -> Self {
         self.hf_cache_path = Some(hf_cache_path);
@@ -292,25 +474,48 @@ impl TextModelBuilder {
             topology: self.topology,
             organization: self.organization,
             write_uqff: self.write_uqff,
+            uqff_shard_size_mb: self.uqff_shard_size_mb,
             from_uqff: self.from_uqff,
             imatrix: self.imatrix,
             calibration_file: self.calibration_file,
             hf_cache_path: self.hf_cache_path,
+            activation_override: self.activation_override,
+            prefill_dtype: self.prefill_dtype,
+            split_kv_segment_len: self.split_kv_segment_len,
+            split_kv_accumulation: self.split_kv_accumulation,
+            embedding_dtype: self.embedding_dtype,
+            lm_head_dtype: self.lm_head_dtype,
+            f32_norm_weights: self.f32_norm_weights,
+            isq_overrides: self.isq_overrides,
+            pruned_heads: self.pruned_heads,
+            attention_head_scales: self.attention_head_scales,
+            head_dim_alignment: self.head_dim_alignment,
+            max_cached_rope_positions: self.max_cached_rope_positions,
+            offload_activations: self.offload_activations,
+            share_rope_tables: self.share_rope_tables,
+            lazy_rope_tables: self.lazy_rope_tables,
+            shard_download_retries: self.shard_download_retries,
         };
 
         if self.with_logging {
             initialize_logging();
         }
 
-        let loader = NormalLoaderBuilder::new(
+        let mut loader = NormalLoaderBuilder::new(
             config,
             self.chat_template,
             self.tokenizer_json,
             Some(self.model_id),
             self.no_kv_cache,
             self.jinja_explicit,
-        )
-        .build(self.loader_type)?;
+        );
+        if let Some(num_experts_per_tok) = self.num_experts_per_tok {
+            loader = loader.with_num_experts_per_tok(num_experts_per_tok);
+        }
+        if self.bos_tok_override.is_some() || self.eos_toks_override.is_some() {
+            loader = loader.with_special_tokens(self.bos_tok_override, self.eos_toks_override);
+        }
+        let loader = loader.build(self.loader_type)?;
 
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
@@ -15,6 +15,7 @@ pub struct VisionModelBuilder {
     pub(crate) token_source: TokenSource,
     pub(crate) hf_revision: Option<String>,
     pub(crate) write_uqff: Option<PathBuf>,
+    pub(crate) uqff_shard_size_mb: Option<usize>,
     pub(crate) from_uqff: Option<Vec<PathBuf>>,
     pub(crate) calibration_file: Option<PathBuf>,
     pub(crate) imatrix: Option<PathBuf>,
@@ -24,6 +25,7 @@ pub struct VisionModelBuilder {
     pub(crate) device_mapping: Option<DeviceMapSetting>,
     pub(crate) max_edge: Option<u32>,
     pub(crate) hf_cache_path: Option<PathBuf>,
+    pub(crate) shard_download_retries: usize,
     pub(crate) search_bert_model: Option<BertEmbeddingModel>,
 
     // Model running
@@ -54,6 +56,7 @@ impl VisionModelBuilder {
             use_flash_attn: cfg!(feature = "flash-attn"),
             topology: None,
             write_uqff: None,
+            uqff_shard_size_mb: None,
             from_uqff: None,
             prompt_chunksize: None,
             chat_template: None,
@@ -74,6 +77,7 @@ impl VisionModelBuilder {
             throughput_logging: false,
             paged_attn_cfg: None,
             hf_cache_path: None,
+            shard_download_retries: 0,
             search_bert_model: None,
         }
     }
@@ -216,23 +220,38 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Maximum size in MB of each UQFF shard written with [`Self::write_uqff`]. Defaults to 10 GB.
+    pub fn with_uqff_shard_size_mb(mut self, shard_size_mb: usize) -> Self {
+        self.uqff_shard_size_mb = Some(shard_size_mb);
+        self
+    }
+
     /// Cache path for Hugging Face models downloaded locally
     pub fn from_hf_cache_pathf(mut self, hf_cache_path: PathBuf) -> Self {
         self.hf_cache_path = Some(hf_cache_path);
         self
     }
 
+    /// Download a safetensors shard from the Hugging Face Hub again, up to `retries` times, if the
+    /// cached copy is corrupt or truncated.
+    pub fn with_shard_download_retries(mut self, retries: usize) -> Self {
+        self.shard_download_retries = retries;
+        self
+    }
+
     pub async fn build(self) -> anyhow::Result<Model> {
         let config = VisionSpecificConfig {
             use_flash_attn: self.use_flash_attn,
             prompt_chunksize: self.prompt_chunksize,
             topology: self.topology,
             write_uqff: self.write_uqff,
+            uqff_shard_size_mb: self.uqff_shard_size_mb,
             from_uqff: self.from_uqff,
             max_edge: self.max_edge,
             calibration_file: self.calibration_file,
             imatrix: self.imatrix,
             hf_cache_path: self.hf_cache_path,
+            shard_download_retries: self.shard_download_retries,
         };
 
         if self.with_logging {